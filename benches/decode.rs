@@ -0,0 +1,152 @@
+#[macro_use]
+extern crate criterion;
+extern crate protobuf;
+extern crate serde_protobuf;
+
+use std::fs;
+
+use criterion::Criterion;
+use protobuf::Message;
+use serde_protobuf::descriptor;
+use serde_protobuf::field_mask;
+use serde_protobuf::value;
+
+#[allow(dead_code)]
+#[path = "../tests/protobuf_unittest/mod.rs"]
+mod protobuf_unittest;
+
+use crate::protobuf_unittest::unittest;
+
+fn load_descriptors() -> descriptor::Descriptors {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    let mut descriptors = descriptor::Descriptors::from_proto(&proto);
+    descriptors.resolve_refs();
+    descriptors
+}
+
+fn decode(c: &mut Criterion, name: &str, message_name: &str, bytes: &[u8]) {
+    let descriptors = load_descriptors();
+    let message = descriptors.message_by_name(message_name).unwrap();
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            let mut m = value::Message::new(message);
+            m.merge_from(&descriptors, message, &mut input).unwrap();
+            m
+        })
+    });
+}
+
+fn decode_short_repeated(c: &mut Criterion) {
+    let mut v = unittest::TestAllTypes::new();
+    for i in 0..3 {
+        v.mut_repeated_int32().push(i);
+        v.mut_repeated_int64().push(i.into());
+        v.mut_repeated_uint32().push(i as u32);
+        v.mut_repeated_double().push(i.into());
+        v.mut_repeated_bool().push(i % 2 == 0);
+        v.mut_repeated_string().push(i.to_string());
+        v.mut_repeated_nested_message().push_default().set_bb(i);
+    }
+    decode(
+        c,
+        "decode_short_repeated",
+        ".protobuf_unittest.TestAllTypes",
+        &v.write_to_bytes().unwrap(),
+    );
+}
+
+fn decode_long_repeated(c: &mut Criterion) {
+    let mut v = unittest::TestAllTypes::new();
+    for i in 0..1000 {
+        v.mut_repeated_int32().push(i);
+        v.mut_repeated_double().push(i.into());
+    }
+    decode(
+        c,
+        "decode_long_repeated",
+        ".protobuf_unittest.TestAllTypes",
+        &v.write_to_bytes().unwrap(),
+    );
+}
+
+fn decode_short_packed(c: &mut Criterion) {
+    let mut v = unittest::TestPackedTypes::new();
+    for i in 0..3 {
+        v.mut_packed_int32().push(i);
+        v.mut_packed_int64().push(i.into());
+        v.mut_packed_uint32().push(i as u32);
+        v.mut_packed_uint64().push(i as u64);
+        v.mut_packed_sint32().push(i);
+        v.mut_packed_sint64().push(i.into());
+        v.mut_packed_fixed32().push(i as u32);
+        v.mut_packed_fixed64().push(i as u64);
+        v.mut_packed_float().push(i as f32);
+        v.mut_packed_double().push(i.into());
+        v.mut_packed_bool().push(i % 2 == 0);
+    }
+    decode(
+        c,
+        "decode_short_packed",
+        ".protobuf_unittest.TestPackedTypes",
+        &v.write_to_bytes().unwrap(),
+    );
+}
+
+fn decode_long_packed(c: &mut Criterion) {
+    let mut v = unittest::TestPackedTypes::new();
+    for i in 0..1000 {
+        v.mut_packed_int32().push(i * 1000);
+        v.mut_packed_int64().push(i64::from(i) << 40);
+        v.mut_packed_sint32().push(-i);
+        v.mut_packed_uint64().push(i as u64);
+        v.mut_packed_bool().push(i % 2 == 0);
+        v.mut_packed_fixed32().push(i as u32);
+    }
+    decode(
+        c,
+        "decode_long_packed",
+        ".protobuf_unittest.TestPackedTypes",
+        &v.write_to_bytes().unwrap(),
+    );
+}
+
+fn decode_masked(c: &mut Criterion) {
+    let descriptors = load_descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mask = field_mask::FieldMask::new(vec!["optional_int32", "optional_nested_message.bb"]);
+    let tree = mask.compile(&descriptors, message).unwrap();
+
+    let mut v = unittest::TestAllTypes::new();
+    v.set_optional_int32(1);
+    v.mut_optional_nested_message().set_bb(2);
+    for i in 0..100 {
+        v.mut_repeated_string().push(i.to_string());
+        v.mut_repeated_nested_message().push_default().set_bb(i);
+    }
+    let bytes = v.write_to_bytes().unwrap();
+
+    c.bench_function("decode_masked", |b| {
+        b.iter(|| {
+            let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+            let mut m = value::Message::new(message);
+            m.merge_from_masked(&descriptors, message, &tree, &mut input)
+                .unwrap();
+            m
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    decode_short_repeated,
+    decode_long_repeated,
+    decode_short_packed,
+    decode_long_packed,
+    decode_masked
+);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! A testee for the protocol buffers conformance test suite.
+//!
+//! Takes the path of a `FileDescriptorSet` that includes the test message types (as produced by
+//! `protoc --include_imports --descriptor_set_out=...`) as its only argument.  Since the test
+//! runner starts the testee without arguments, wrap it in a script, e.g.:
+//!
+//! ```sh
+//! #!/bin/sh
+//! exec target/debug/examples/conformance test_messages.pb
+//! ```
+extern crate protobuf;
+extern crate serde_protobuf;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use serde_protobuf::conformance;
+use serde_protobuf::descriptor::Descriptors;
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: conformance <descriptor set>");
+            process::exit(2);
+        }
+    };
+    let mut file = fs::File::open(&path).expect("failed to open descriptor set");
+    let proto = protobuf::parse_from_reader(&mut file).expect("failed to parse descriptor set");
+    let descriptors = Descriptors::from_proto(&proto);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let count = conformance::serve(&descriptors, &mut stdin.lock(), &mut stdout.lock())
+        .expect("failed to serve conformance requests");
+    eprintln!("conformance: answered {} requests", count);
+}
@@ -0,0 +1,568 @@
+//! Arena-backed representations of runtime Protobuf values.
+//!
+//! The types in this module mirror those in the [`value`](../value/index.html) module, but every
+//! string, byte buffer and nested message is allocated from a caller-provided
+//! [`bumpalo::Bump`](https://docs.rs/bumpalo) arena.  All of the memory used by a decoded message
+//! is then released at once when the arena is reset or dropped, which makes this a good fit for
+//! request-scoped decoding.
+//!
+//! ```
+//! # extern crate bumpalo;
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::arena;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! let bump = bumpalo::Bump::new();
+//! let mut input = protobuf::CodedInputStream::from_bytes(&[8, 42]);
+//! let mut message = arena::Message::new_in(descriptor, &bump);
+//! message.merge_from(&descriptors, descriptor, &mut input).unwrap();
+//!
+//! match message.field(1) {
+//!     Some(&arena::Field::Singular(Some(arena::Value::I32(v)))) => assert_eq!(42, v),
+//!     f => panic!("unexpected field {:?}", f),
+//! }
+//! # }
+//! ```
+use std::str;
+use std::sync::Arc;
+
+use bumpalo;
+use protobuf;
+use protobuf::stream::wire_format;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+type BumpVec<'a, A> = bumpalo::collections::Vec<'a, A>;
+
+/// Any protobuf value, borrowing its data from an arena.
+#[derive(Debug)]
+pub enum Value<'a> {
+    /// A boolean value.
+    Bool(bool),
+    /// A 32-bit signed integer.
+    I32(i32),
+    /// A 64-bit signed integer.
+    I64(i64),
+    /// A 32-bit unsigned integer.
+    U32(u32),
+    /// A 64-bit unsigned integer.
+    U64(u64),
+    /// A 32-bit floating point value.
+    F32(f32),
+    /// A 64-bit floating point value.
+    F64(f64),
+    /// A byte slice.
+    Bytes(&'a [u8]),
+    /// A string.
+    String(&'a str),
+    /// An enum value.
+    Enum(i32),
+    /// A message, including `google.protobuf.FieldMask` messages.
+    Message(Message<'a>),
+}
+
+/// A message value whose storage lives in an arena.
+#[derive(Debug)]
+pub struct Message<'a> {
+    /// Known fields on the message, ordered by field number.
+    pub fields: BumpVec<'a, (i32, Field<'a>)>,
+    /// Unknown fields on the message, in the order they were encountered.
+    pub unknown: BumpVec<'a, (u32, UnknownValue<'a>)>,
+}
+
+/// A message field value whose storage lives in an arena.
+#[derive(Debug)]
+pub enum Field<'a> {
+    /// A field with a single value.
+    Singular(Option<Value<'a>>),
+    /// A field with several (repeated) values.
+    Repeated(BumpVec<'a, Value<'a>>),
+}
+
+/// The raw value of an unknown field.
+#[derive(Clone, Copy, Debug)]
+pub enum UnknownValue<'a> {
+    /// A 32-bit fixed width value.
+    Fixed32(u32),
+    /// A 64-bit fixed width value.
+    Fixed64(u64),
+    /// A variable width integer.
+    Varint(u64),
+    /// A length-delimited value.
+    LengthDelimited(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    /// Copies an owned value into the specified arena.
+    ///
+    /// A `BytesStream` is read into the arena, consuming it; one that can't be read becomes empty
+    /// bytes.  A custom value becomes an empty message, since it can only be converted back with
+    /// its `TypeHandler`, and `Null` becomes an empty message too.
+    pub fn from_value_in(value: &value::Value, bump: &'a bumpalo::Bump) -> Value<'a> {
+        match *value {
+            value::Value::Bool(v) => Value::Bool(v),
+            value::Value::I32(v) => Value::I32(v),
+            value::Value::I64(v) => Value::I64(v),
+            value::Value::U32(v) => Value::U32(v),
+            value::Value::U64(v) => Value::U64(v),
+            value::Value::F32(v) => Value::F32(v),
+            value::Value::F64(v) => Value::F64(v),
+            value::Value::Bytes(ref v) => Value::Bytes(bump.alloc_slice_copy(v)),
+            value::Value::String(ref v) => Value::String(bump.alloc_str(v)),
+            value::Value::BytesStream(ref s) => {
+                Value::Bytes(bump.alloc_slice_copy(&s.read_to_vec().unwrap_or_default()))
+            }
+            value::Value::Enum(v) => Value::Enum(v),
+            value::Value::Message(ref m) => Value::Message(Message::from_message_in(m, bump)),
+            value::Value::FieldMask(ref m) => {
+                let mut paths = BumpVec::with_capacity_in(m.paths.len(), bump);
+                paths.extend(m.paths.iter().map(|p| Value::String(bump.alloc_str(p))));
+                let mut fields = BumpVec::with_capacity_in(1, bump);
+                fields.push((1, Field::Repeated(paths)));
+                Value::Message(Message {
+                    fields,
+                    unknown: BumpVec::new_in(bump),
+                })
+            }
+            value::Value::Custom(_) | value::Value::Null => Value::Message(Message {
+                fields: BumpVec::new_in(bump),
+                unknown: BumpVec::new_in(bump),
+            }),
+        }
+    }
+
+    /// Copies this value out of the arena into an owned value.
+    pub fn to_value(&self) -> value::Value {
+        match *self {
+            Value::Bool(v) => value::Value::Bool(v),
+            Value::I32(v) => value::Value::I32(v),
+            Value::I64(v) => value::Value::I64(v),
+            Value::U32(v) => value::Value::U32(v),
+            Value::U64(v) => value::Value::U64(v),
+            Value::F32(v) => value::Value::F32(v),
+            Value::F64(v) => value::Value::F64(v),
+            Value::Bytes(v) => value::Value::Bytes(v.to_vec()),
+            Value::String(v) => value::Value::String(v.to_owned()),
+            Value::Enum(v) => value::Value::Enum(v),
+            Value::Message(ref m) => value::Value::Message(Arc::new(m.to_message())),
+        }
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Creates a message given a Protobuf descriptor, allocating it in the specified arena.
+    #[inline]
+    pub fn new_in(message: &descriptor::MessageDescriptor, bump: &'a bumpalo::Bump) -> Message<'a> {
+        let mut fields = BumpVec::with_capacity_in(message.fields().len(), bump);
+
+        for field in message.fields() {
+            fields.push((
+                field.number(),
+                if field.is_repeated() {
+                    Field::Repeated(BumpVec::new_in(bump))
+                } else {
+                    Field::Singular(field.default_value().map(|v| Value::from_value_in(v, bump)))
+                },
+            ));
+        }
+        fields.sort_by_key(|&(number, _)| number);
+
+        Message {
+            fields,
+            unknown: BumpVec::new_in(bump),
+        }
+    }
+
+    /// Copies an owned message into the specified arena.
+    pub fn from_message_in(message: &value::Message, bump: &'a bumpalo::Bump) -> Message<'a> {
+        let mut fields = BumpVec::with_capacity_in(message.fields.len(), bump);
+
+        for (&number, field) in &message.fields {
+            let field = match *field {
+                value::Field::Singular(ref v) => {
+                    Field::Singular(v.as_ref().map(|v| Value::from_value_in(v, bump)))
+                }
+                value::Field::Repeated(ref vs) => {
+                    let mut values = BumpVec::with_capacity_in(vs.len(), bump);
+                    values.extend(vs.iter().map(|v| Value::from_value_in(v, bump)));
+                    Field::Repeated(values)
+                }
+                value::Field::RepeatedTyped(ref vs) => {
+                    let mut values = BumpVec::with_capacity_in(vs.len(), bump);
+                    values.extend(vs.iter().map(|v| Value::from_value_in(&v, bump)));
+                    Field::Repeated(values)
+                }
+            };
+            fields.push((number, field));
+        }
+
+        let mut unknown = BumpVec::new_in(bump);
+        for (number, values) in &message.unknown {
+            for v in values {
+                let v = match v {
+                    protobuf::UnknownValueRef::Fixed32(v) => UnknownValue::Fixed32(v),
+                    protobuf::UnknownValueRef::Fixed64(v) => UnknownValue::Fixed64(v),
+                    protobuf::UnknownValueRef::Varint(v) => UnknownValue::Varint(v),
+                    protobuf::UnknownValueRef::LengthDelimited(v) => {
+                        UnknownValue::LengthDelimited(bump.alloc_slice_copy(v))
+                    }
+                };
+                unknown.push((number, v));
+            }
+        }
+
+        Message { fields, unknown }
+    }
+
+    /// Copies this message out of the arena into an owned message.
+    pub fn to_message(&self) -> value::Message {
+        let mut message = value::Message::default();
+
+        for &(number, ref field) in &self.fields {
+            let field = match *field {
+                Field::Singular(ref v) => value::Field::Singular(v.as_ref().map(Value::to_value)),
+                Field::Repeated(ref vs) => {
+                    value::Field::Repeated(vs.iter().map(Value::to_value).collect())
+                }
+            };
+            message.fields.insert(number, field);
+        }
+
+        for &(number, v) in &self.unknown {
+            match v {
+                UnknownValue::Fixed32(v) => message.unknown.add_fixed32(number, v),
+                UnknownValue::Fixed64(v) => message.unknown.add_fixed64(number, v),
+                UnknownValue::Varint(v) => message.unknown.add_varint(number, v),
+                UnknownValue::LengthDelimited(v) => {
+                    message.unknown.add_length_delimited(number, v.to_vec())
+                }
+            }
+        }
+
+        message
+    }
+
+    /// The arena that this message is allocated in.
+    #[inline]
+    pub fn bump(&self) -> &'a bumpalo::Bump {
+        self.fields.bump()
+    }
+
+    /// Finds a field by field number.
+    #[inline]
+    pub fn field(&self, number: i32) -> Option<&Field<'a>> {
+        self.fields
+            .binary_search_by_key(&number, |&(n, _)| n)
+            .ok()
+            .map(|i| &self.fields[i].1)
+    }
+
+    /// Merge data from the given input stream into this message, allocating any decoded data in
+    /// the arena of this message.  Messages nested deeper than
+    /// [`DEFAULT_RECURSION_LIMIT`](../value/constant.DEFAULT_RECURSION_LIMIT.html) fail the
+    /// decode with a `RecursionLimitExceeded` error.
+    pub fn merge_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        self.merge_from_impl(descriptors, message, input, None, 1)
+    }
+
+    /// Merge data from the given buffer into this message.  Decoded strings and byte buffers
+    /// borrow from the buffer instead of being copied into the arena.
+    pub fn merge_from_slice(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'a [u8],
+    ) -> error::Result<()> {
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        self.merge_from_impl(descriptors, message, &mut input, Some(bytes), 1)
+    }
+
+    // `source`, if any, is the buffer that `input` reads from, and `depth` is the nesting depth of
+    // this message, starting at 1
+    fn merge_from_impl(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        source: Option<&'a [u8]>,
+        depth: u32,
+    ) -> error::Result<()> {
+        if depth > value::DEFAULT_RECURSION_LIMIT {
+            return Err(error::Error::RecursionLimitExceeded {
+                limit: value::DEFAULT_RECURSION_LIMIT,
+                position: input.pos(),
+            });
+        }
+        let bump = self.bump();
+        while !input.eof()? {
+            let position = input.pos();
+            let truncated = |e: error::Error| e.truncated_at(position);
+            let (number, wire_type) = value::read_tag(input, false).map_err(truncated)?;
+
+            if let Some(field) = value::decoded_field(descriptors, message, number) {
+                let value = self.ensure_field(field, bump);
+                value
+                    .merge_from_impl(descriptors, field, input, wire_type, bump, source, depth)
+                    .map_err(truncated)?;
+            } else {
+                let value = match wire_type {
+                    wire_format::WireType::WireTypeStartGroup => {
+                        use protobuf::rt::read_unknown_or_skip_group as u;
+                        u(
+                            number,
+                            wire_type,
+                            input,
+                            &mut protobuf::UnknownFields::new(),
+                        )
+                        .map_err(|e| truncated(e.into()))?;
+                        continue;
+                    }
+                    wire_format::WireType::WireTypeLengthDelimited => {
+                        UnknownValue::LengthDelimited(
+                            read_bytes_in(input, bump, source).map_err(truncated)?,
+                        )
+                    }
+                    _ => match input
+                        .read_unknown(wire_type)
+                        .map_err(|e| truncated(e.into()))?
+                    {
+                        protobuf::UnknownValue::Fixed32(v) => UnknownValue::Fixed32(v),
+                        protobuf::UnknownValue::Fixed64(v) => UnknownValue::Fixed64(v),
+                        protobuf::UnknownValue::Varint(v) => UnknownValue::Varint(v),
+                        protobuf::UnknownValue::LengthDelimited(v) => {
+                            UnknownValue::LengthDelimited(bump.alloc_slice_copy(&v))
+                        }
+                    },
+                };
+                self.unknown.push((number, value));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn ensure_field(
+        &mut self,
+        field: &descriptor::FieldDescriptor,
+        bump: &'a bumpalo::Bump,
+    ) -> &mut Field<'a> {
+        let number = field.number();
+        let idx = match self.fields.binary_search_by_key(&number, |&(n, _)| n) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.fields
+                    .insert(idx, (number, Field::new_in(field, bump)));
+                idx
+            }
+        };
+        &mut self.fields[idx].1
+    }
+}
+
+impl<'a> Field<'a> {
+    /// Creates a field given a Protobuf descriptor, allocating it in the specified arena.
+    #[inline]
+    pub fn new_in(field: &descriptor::FieldDescriptor, bump: &'a bumpalo::Bump) -> Field<'a> {
+        if field.is_repeated() {
+            Field::Repeated(BumpVec::new_in(bump))
+        } else {
+            Field::Singular(None)
+        }
+    }
+
+    /// Merge data from the given input stream into this field, allocating any decoded data in the
+    /// specified arena.
+    pub fn merge_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        bump: &'a bumpalo::Bump,
+    ) -> error::Result<()> {
+        self.merge_from_impl(descriptors, field, input, wire_type, bump, None, 1)
+    }
+
+    // `depth` is the nesting depth of the message that contains this field
+    #[allow(clippy::too_many_arguments)]
+    fn merge_from_impl(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        bump: &'a bumpalo::Bump,
+        source: Option<&'a [u8]>,
+        depth: u32,
+    ) -> error::Result<()> {
+        // Make the type dispatch below more compact
+        use crate::descriptor::FieldType::*;
+        use protobuf::stream::wire_format::WireType::*;
+        use protobuf::CodedInputStream as I;
+
+        // Packable scalar
+        macro_rules! ps {
+            ($expected_wire_type:expr, $value_ctor:expr, $reader:expr) => {
+                self.merge_packable_scalar(
+                    input,
+                    wire_type,
+                    $expected_wire_type,
+                    $value_ctor,
+                    $reader,
+                )
+            };
+        }
+
+        match field.field_type(descriptors) {
+            Bool => ps!(WireTypeVarint, Value::Bool, I::read_bool),
+            Int32 => ps!(WireTypeVarint, Value::I32, I::read_int32),
+            Int64 => ps!(WireTypeVarint, Value::I64, I::read_int64),
+            SInt32 => ps!(WireTypeVarint, Value::I32, I::read_sint32),
+            SInt64 => ps!(WireTypeVarint, Value::I64, I::read_sint64),
+            UInt32 => ps!(WireTypeVarint, Value::U32, I::read_uint32),
+            UInt64 => ps!(WireTypeVarint, Value::U64, I::read_uint64),
+            Fixed32 => ps!(WireTypeFixed32, Value::U32, I::read_fixed32),
+            Fixed64 => ps!(WireTypeFixed64, Value::U64, I::read_fixed64),
+            SFixed32 => ps!(WireTypeFixed32, Value::I32, I::read_sfixed32),
+            SFixed64 => ps!(WireTypeFixed64, Value::I64, I::read_sfixed64),
+            Float => ps!(WireTypeFixed32, Value::F32, I::read_float),
+            Double => ps!(WireTypeFixed64, Value::F64, I::read_double),
+            Enum(_) => ps!(WireTypeVarint, Value::Enum, I::read_int32),
+            Bytes => {
+                check_wire_type(input, wire_type, WireTypeLengthDelimited)?;
+                self.put(Value::Bytes(read_bytes_in(input, bump, source)?));
+                Ok(())
+            }
+            String => {
+                check_wire_type(input, wire_type, WireTypeLengthDelimited)?;
+                let bytes = read_bytes_in(input, bump, source)?;
+                let s = str::from_utf8(bytes).map_err(|_| {
+                    protobuf::ProtobufError::WireError(protobuf::error::WireError::Utf8Error)
+                })?;
+                self.put(Value::String(s));
+                Ok(())
+            }
+            Message(m) => {
+                check_wire_type(input, wire_type, WireTypeLengthDelimited)?;
+                let len = input.read_raw_varint64()?;
+                let mut msg = match *self {
+                    Field::Singular(ref mut o) => match o.take() {
+                        Some(Value::Message(msg)) => msg,
+                        _ => self::Message::new_in(m, bump),
+                    },
+                    _ => self::Message::new_in(m, bump),
+                };
+
+                let old_limit = input.push_limit(len)?;
+                msg.merge_from_impl(descriptors, m, input, source, depth + 1)?;
+                input.pop_limit(old_limit);
+
+                self.put(Value::Message(msg));
+                Ok(())
+            }
+            Group => Err(error::Error::BadFieldValue {
+                field: field.name().to_owned(),
+            }),
+            UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
+        }
+    }
+
+    #[inline]
+    fn merge_packable_scalar<'b, A, V, R>(
+        &mut self,
+        input: &mut protobuf::CodedInputStream<'b>,
+        actual_wire_type: wire_format::WireType,
+        expected_wire_type: wire_format::WireType,
+        value_ctor: V,
+        reader: R,
+    ) -> error::Result<()>
+    where
+        V: Fn(A) -> Value<'a>,
+        R: Fn(&mut protobuf::CodedInputStream<'b>) -> protobuf::ProtobufResult<A>,
+    {
+        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            let len = input.read_raw_varint64()?;
+
+            let old_limit = input.push_limit(len)?;
+            while !input.eof()? {
+                self.put(value_ctor(reader(input)?));
+            }
+            input.pop_limit(old_limit);
+
+            Ok(())
+        } else {
+            check_wire_type(input, actual_wire_type, expected_wire_type)?;
+            self.put(value_ctor(reader(input)?));
+            Ok(())
+        }
+    }
+
+    #[inline]
+    fn put(&mut self, value: Value<'a>) {
+        match *self {
+            Field::Singular(ref mut s) => *s = Some(value),
+            Field::Repeated(ref mut r) => r.push(value),
+        }
+    }
+}
+
+#[inline]
+fn check_wire_type(
+    input: &protobuf::CodedInputStream,
+    actual_wire_type: wire_format::WireType,
+    expected_wire_type: wire_format::WireType,
+) -> error::Result<()> {
+    if actual_wire_type == expected_wire_type {
+        Ok(())
+    } else {
+        Err(error::Error::BadWireType {
+            wire_type: actual_wire_type,
+            position: input.pos(),
+        })
+    }
+}
+
+/// Reads a length-delimited value, borrowing it from `source` if `input` reads from it, and
+/// copying it into the arena otherwise.
+#[inline]
+fn read_bytes_in<'a>(
+    input: &mut protobuf::CodedInputStream,
+    bump: &'a bumpalo::Bump,
+    source: Option<&'a [u8]>,
+) -> error::Result<&'a [u8]> {
+    let len = input.read_raw_varint64()?;
+    if len > input.bytes_until_limit() || len > u64::from(u32::MAX) {
+        return Err(error::Error::EndOfStream);
+    }
+    if let Some(source) = source {
+        let start = input.pos() as usize;
+        input.skip_raw_bytes(len as u32)?;
+        return Ok(&source[start..start + len as usize]);
+    }
+    // Large lengths may be bogus, so they are only allocated once the bytes have been read
+    if len > MAX_PREALLOCATED_LEN {
+        return Ok(bump.alloc_slice_copy(&input.read_raw_bytes(len as u32)?));
+    }
+    let buf = bump.alloc_slice_fill_copy(len as usize, 0u8);
+    input.read(buf)?;
+    Ok(buf)
+}
+
+/// The largest length of a value that is allocated in the arena before its bytes are read.
+const MAX_PREALLOCATED_LEN: u64 = 1 << 16;
@@ -0,0 +1,344 @@
+//! Export of dynamic messages as Apache Arrow record batches.
+//!
+//! A [`RecordBatchBuilder`](struct.RecordBatchBuilder.html) flattens messages of a single type into
+//! columns, so that protobuf encoded data can be handed to analytics pipelines without generating
+//! code for its schema.
+//!
+//!   * Singular scalar fields become primitive, `Utf8` or `Binary` columns.  Absent values are null.
+//!   * Enum fields become `Utf8` columns holding the name of the enum value.
+//!   * Repeated scalar fields become `List` columns.
+//!   * Singular message fields are flattened into one column per nested field, named by the dotted
+//!     path to it, e.g. `"author.name"`.  `google.protobuf.FieldMask` fields become lists of paths.
+//!   * Repeated message fields, groups and recursive references to an enclosing message type are
+//!     skipped.
+use std::fmt;
+use std::sync;
+
+use arrow_array::builder;
+use arrow_array::builder::ArrayBuilder;
+use arrow_schema;
+use arrow_schema::DataType;
+
+use crate::descriptor;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// A builder that accumulates messages of one type into Arrow columns.
+pub struct RecordBatchBuilder<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    schema: arrow_schema::SchemaRef,
+    columns: Vec<Column<'a>>,
+    len: usize,
+}
+
+struct Column<'a> {
+    path: Vec<i32>,
+    field: &'a descriptor::FieldDescriptor,
+    data_type: DataType,
+    builder: Box<dyn ArrayBuilder>,
+}
+
+/// Converts the given messages of the specified type into a single record batch.
+pub fn to_record_batch<'m, I>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    messages: I,
+) -> error::Result<arrow_array::RecordBatch>
+where
+    I: IntoIterator<Item = &'m value::Message>,
+{
+    let mut builder = RecordBatchBuilder::new(descriptors, descriptor);
+    for message in messages {
+        builder.append(message)?;
+    }
+    builder.finish()
+}
+
+impl<'a> RecordBatchBuilder<'a> {
+    /// Creates a builder for messages of the specified type.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+    ) -> RecordBatchBuilder<'a> {
+        let mut columns = Vec::new();
+        let mut fields = Vec::new();
+        add_columns(
+            descriptors,
+            descriptor,
+            "",
+            &mut Vec::new(),
+            &mut vec![descriptor.name()],
+            &mut columns,
+            &mut fields,
+        );
+        let schema = sync::Arc::new(arrow_schema::Schema::new(fields));
+
+        RecordBatchBuilder {
+            descriptors,
+            schema,
+            columns,
+            len: 0,
+        }
+    }
+
+    /// The schema of the record batches produced by this builder.
+    pub fn schema(&self) -> arrow_schema::SchemaRef {
+        self.schema.clone()
+    }
+
+    /// The number of messages appended since the last batch was finished.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no messages were appended since the last batch was finished.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends a message as a new row.
+    ///
+    /// On error, the builder is left in an inconsistent state and should be discarded.
+    pub fn append(&mut self, message: &value::Message) -> error::Result<()> {
+        for column in &mut self.columns {
+            column.append(self.descriptors, message)?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Builds a record batch of all of the appended messages, and resets the builder.
+    pub fn finish(&mut self) -> error::Result<arrow_array::RecordBatch> {
+        let arrays = self
+            .columns
+            .iter_mut()
+            .map(|c| c.builder.finish())
+            .collect();
+        let options = arrow_array::RecordBatchOptions::new().with_row_count(Some(self.len));
+        self.len = 0;
+        arrow_array::RecordBatch::try_new_with_options(self.schema.clone(), arrays, &options)
+            .map_err(|e| error::Error::Custom {
+                message: e.to_string(),
+            })
+    }
+}
+
+impl<'a> fmt::Debug for RecordBatchBuilder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordBatchBuilder")
+            .field("schema", &self.schema)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<'a> Column<'a> {
+    fn append(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &value::Message,
+    ) -> error::Result<()> {
+        let enum_descriptor = match self.field.field_type(descriptors) {
+            descriptor::FieldType::Enum(e) => Some(e),
+            _ => None,
+        };
+        let builder = self.builder.as_mut();
+
+        // A cleared field is stored as a null, just like an absent one
+        let field = match lookup(message, &self.path, self.field.number()) {
+            Some(value::Field::Singular(Some(value::Value::Null))) => None,
+            field => field,
+        };
+        let appended =
+            match (field, &self.data_type) {
+                (Some(value::Field::Repeated(values)), DataType::List(item)) => {
+                    append_list(builder, item.data_type(), enum_descriptor, values.iter())
+                }
+                (Some(value::Field::RepeatedTyped(values)), DataType::List(item)) => {
+                    let values: Vec<_> = values.iter().collect();
+                    append_list(builder, item.data_type(), enum_descriptor, values.iter())
+                }
+                (Some(value::Field::Singular(Some(value::Value::FieldMask(m)))), _) => {
+                    let paths: Vec<_> = m.paths.iter().cloned().map(value::Value::String).collect();
+                    append_list(builder, &DataType::Utf8, None, paths.iter())
+                }
+                (Some(value::Field::Singular(None)), DataType::List(_))
+                | (None, DataType::List(_)) => list_builder(builder).map(|l| l.append_null()),
+                (Some(value::Field::Singular(v)), data_type) => {
+                    append_value(builder, data_type, enum_descriptor, v.as_ref())
+                }
+                (None, data_type) => append_value(builder, data_type, None, None),
+                (Some(value::Field::Repeated(_)), _)
+                | (Some(value::Field::RepeatedTyped(_)), _) => None,
+            };
+
+        appended.ok_or_else(|| error::Error::BadFieldValue {
+            field: self.field.name().to_owned(),
+        })
+    }
+}
+
+fn add_columns<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    prefix: &str,
+    path: &mut Vec<i32>,
+    enclosing: &mut Vec<&'a str>,
+    columns: &mut Vec<Column<'a>>,
+    fields: &mut Vec<arrow_schema::Field>,
+) {
+    for field in message.fields() {
+        let name = format!("{}{}", prefix, field.name());
+        let data_type = match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) if m.name() == field_mask::FIELD_MASK => {
+                if field.is_repeated() {
+                    continue;
+                }
+                list_type(DataType::Utf8)
+            }
+            descriptor::FieldType::Message(m) => {
+                if !field.is_repeated() && !enclosing.contains(&m.name()) {
+                    path.push(field.number());
+                    enclosing.push(m.name());
+                    let prefix = format!("{}.", name);
+                    add_columns(descriptors, m, &prefix, path, enclosing, columns, fields);
+                    enclosing.pop();
+                    path.pop();
+                }
+                continue;
+            }
+            field_type => match scalar_type(field_type) {
+                Some(t) if field.is_repeated() => list_type(t),
+                Some(t) => t,
+                None => continue,
+            },
+        };
+
+        columns.push(Column {
+            path: path.clone(),
+            field,
+            builder: builder::make_builder(&data_type, 0),
+            data_type: data_type.clone(),
+        });
+        fields.push(arrow_schema::Field::new(name, data_type, true));
+    }
+}
+
+fn scalar_type(field_type: descriptor::FieldType) -> Option<DataType> {
+    use crate::descriptor::FieldType::*;
+
+    match field_type {
+        Bool => Some(DataType::Boolean),
+        Int32 | SInt32 | SFixed32 => Some(DataType::Int32),
+        Int64 | SInt64 | SFixed64 => Some(DataType::Int64),
+        UInt32 | Fixed32 => Some(DataType::UInt32),
+        UInt64 | Fixed64 => Some(DataType::UInt64),
+        Float => Some(DataType::Float32),
+        Double => Some(DataType::Float64),
+        String | Enum(_) => Some(DataType::Utf8),
+        Bytes => Some(DataType::Binary),
+        Message(_) | Group | UnresolvedEnum(_) | UnresolvedMessage(_) => None,
+    }
+}
+
+fn list_type(item: DataType) -> DataType {
+    DataType::List(sync::Arc::new(arrow_schema::Field::new(
+        "item", item, false,
+    )))
+}
+
+/// Finds the field with the given number in the nested message at the given path, if present.
+fn lookup<'m>(message: &'m value::Message, path: &[i32], number: i32) -> Option<&'m value::Field> {
+    let mut message = message;
+    for n in path {
+        match message.fields.get(n) {
+            Some(&value::Field::Singular(Some(value::Value::Message(ref m)))) => message = m,
+            _ => return None,
+        }
+    }
+    message.fields.get(&number)
+}
+
+fn list_builder(
+    builder: &mut dyn ArrayBuilder,
+) -> Option<&mut builder::ListBuilder<Box<dyn ArrayBuilder>>> {
+    builder.as_any_mut().downcast_mut()
+}
+
+/// Appends a list of values; returns `None` if a value doesn't match the item type.
+fn append_list<'v, I>(
+    builder: &mut dyn ArrayBuilder,
+    item: &DataType,
+    enum_descriptor: Option<&descriptor::EnumDescriptor>,
+    values: I,
+) -> Option<()>
+where
+    I: Iterator<Item = &'v value::Value>,
+{
+    let list = list_builder(builder)?;
+    for value in values {
+        append_value(list.values().as_mut(), item, enum_descriptor, Some(value))?;
+    }
+    list.append(true);
+    Some(())
+}
+
+macro_rules! append_primitive {
+    ($builder:expr, $builder_type:ty, $variant:ident, $value:expr) => {
+        $builder
+            .as_any_mut()
+            .downcast_mut::<$builder_type>()?
+            .append_option(match $value {
+                None => None,
+                Some(&value::Value::$variant(v)) => Some(v),
+                Some(_) => return None,
+            })
+    };
+}
+
+/// Appends a value, or a null; returns `None` if the value doesn't match the type.
+fn append_value(
+    builder: &mut dyn ArrayBuilder,
+    data_type: &DataType,
+    enum_descriptor: Option<&descriptor::EnumDescriptor>,
+    value: Option<&value::Value>,
+) -> Option<()> {
+    match *data_type {
+        DataType::Boolean => append_primitive!(builder, builder::BooleanBuilder, Bool, value),
+        DataType::Int32 => append_primitive!(builder, builder::Int32Builder, I32, value),
+        DataType::Int64 => append_primitive!(builder, builder::Int64Builder, I64, value),
+        DataType::UInt32 => append_primitive!(builder, builder::UInt32Builder, U32, value),
+        DataType::UInt64 => append_primitive!(builder, builder::UInt64Builder, U64, value),
+        DataType::Float32 => append_primitive!(builder, builder::Float32Builder, F32, value),
+        DataType::Float64 => append_primitive!(builder, builder::Float64Builder, F64, value),
+        DataType::Utf8 => {
+            let builder = builder
+                .as_any_mut()
+                .downcast_mut::<builder::StringBuilder>()?;
+            match value {
+                None => builder.append_null(),
+                Some(value::Value::String(s)) => builder.append_value(s),
+                Some(&value::Value::Enum(n)) => {
+                    match enum_descriptor.and_then(|e| e.value_by_number(n)) {
+                        Some(v) => builder.append_value(v.name()),
+                        None => builder.append_value(n.to_string()),
+                    }
+                }
+                Some(_) => return None,
+            }
+        }
+        DataType::Binary => {
+            let builder = builder
+                .as_any_mut()
+                .downcast_mut::<builder::BinaryBuilder>()?;
+            match value {
+                None => builder.append_null(),
+                Some(value::Value::Bytes(b)) => builder.append_value(b),
+                Some(_) => return None,
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
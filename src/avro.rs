@@ -0,0 +1,518 @@
+//! Conversion of messages to and from Apache Avro, for pipelines that bridge protocol buffer
+//! sources into Avro based data lakes.
+//!
+//! `schema` translates a message type to an Avro schema, and `to_datum` and `from_datum` convert
+//! messages to and from Avro binary encoded datums of that schema.
+//!
+//!   * Messages become records, named by the fully qualified name of their type.  Recursive
+//!     references to a message type refer to the record by name.
+//!   * Singular fields are unions of `null` and their type, so that absent fields stay absent.
+//!   * Repeated fields are arrays, and map fields with `string` keys are maps.  Other map fields
+//!     are arrays of their key-value entry records.
+//!   * `int32`, `sint32` and `sfixed32` fields are `int`s; all other integers are `long`s.
+//!     `uint64` and `fixed64` values above `i64::MAX` wrap around to negative numbers.
+//!   * Enums become Avro enums with the names of their values as symbols.  Enum values that the
+//!     enum type doesn't declare can't be converted.
+//!   * Groups and fields of unresolved types are left out.
+//!
+//! This module is only available with the `serde_json` feature.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::avro;
+//! use serde_protobuf::value::{Field, Message, Value};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.ForeignMessage")
+//!     .unwrap();
+//!
+//! let schema = avro::schema(&descriptors, descriptor);
+//! assert_eq!("protobuf_unittest.ForeignMessage", schema["name"]);
+//!
+//! let mut message = Message::new(descriptor);
+//! message.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+//! let datum = avro::to_datum(&descriptors, descriptor, &message).unwrap();
+//! // The union branch of `int`, and zigzag encoded 5
+//! assert_eq!(vec![2, 10], datum);
+//! ```
+use std::borrow::Cow;
+use std::collections;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+
+use serde_json;
+use serde_json::json;
+
+use crate::descriptor;
+use crate::descriptor::FieldType;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// The Avro schema of a message type.
+pub fn schema(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+) -> serde_json::Value {
+    record_schema(descriptors, message, &mut collections::HashSet::new())
+}
+
+/// Encodes a message as an Avro binary encoded datum of the schema of its type.
+pub fn to_datum(
+    descriptors: &descriptor::Descriptors,
+    message_type: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    write_record(descriptors, message_type, message, &mut output)?;
+    Ok(output)
+}
+
+/// Decodes a message from an Avro binary encoded datum of the schema of its type.
+pub fn from_datum(
+    descriptors: &descriptor::Descriptors,
+    message_type: &descriptor::MessageDescriptor,
+    datum: &[u8],
+) -> error::Result<value::Message> {
+    let mut input = datum;
+    read_record(descriptors, message_type, &mut input)
+}
+
+fn avro_name(message: &descriptor::MessageDescriptor) -> &str {
+    message.name().trim_start_matches('.')
+}
+
+/// The fields of a message type that have an Avro representation.
+fn avro_fields<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+) -> impl Iterator<Item = (&'a descriptor::FieldDescriptor, FieldType<'a>)> {
+    message
+        .fields()
+        .iter()
+        .filter_map(move |f| match f.field_type(descriptors) {
+            FieldType::Group | FieldType::UnresolvedEnum(_) | FieldType::UnresolvedMessage(_) => {
+                None
+            }
+            field_type => Some((f, field_type)),
+        })
+}
+
+/// The value field of a map entry type, if the map can be an Avro map.
+fn string_map_value<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType<'a>,
+) -> Option<(&'a descriptor::FieldDescriptor, FieldType<'a>)> {
+    match *field_type {
+        FieldType::Message(entry) if field.is_repeated() && entry.is_map_entry() => {
+            let key = entry.field_by_number(1)?;
+            let value = entry.field_by_number(2)?;
+            match (key.field_type(descriptors), value.field_type(descriptors)) {
+                (FieldType::String, FieldType::Group)
+                | (FieldType::String, FieldType::UnresolvedEnum(_))
+                | (FieldType::String, FieldType::UnresolvedMessage(_)) => None,
+                (FieldType::String, value_type) => Some((value, value_type)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn record_schema(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    defined: &mut collections::HashSet<String>,
+) -> serde_json::Value {
+    let name = avro_name(message);
+    if !defined.insert(name.to_owned()) {
+        return json!(name);
+    }
+
+    let fields = avro_fields(descriptors, message)
+        .map(|(field, field_type)| {
+            let (schema, default) = match string_map_value(descriptors, field, &field_type) {
+                Some((_, value_type)) => (
+                    json!({
+                        "type": "map",
+                        "values": type_schema(descriptors, &value_type, defined),
+                    }),
+                    json!({}),
+                ),
+                None if field.is_repeated() => (
+                    json!({
+                        "type": "array",
+                        "items": type_schema(descriptors, &field_type, defined),
+                    }),
+                    json!([]),
+                ),
+                None => (
+                    json!(["null", type_schema(descriptors, &field_type, defined)]),
+                    serde_json::Value::Null,
+                ),
+            };
+            json!({ "name": field.name(), "type": schema, "default": default })
+        })
+        .collect::<Vec<_>>();
+    json!({ "type": "record", "name": name, "fields": fields })
+}
+
+fn type_schema(
+    descriptors: &descriptor::Descriptors,
+    field_type: &FieldType,
+    defined: &mut collections::HashSet<String>,
+) -> serde_json::Value {
+    match *field_type {
+        FieldType::Bool => json!("boolean"),
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => json!("int"),
+        FieldType::Int64
+        | FieldType::SInt64
+        | FieldType::SFixed64
+        | FieldType::UInt32
+        | FieldType::Fixed32
+        | FieldType::UInt64
+        | FieldType::Fixed64 => json!("long"),
+        FieldType::Float => json!("float"),
+        FieldType::Double => json!("double"),
+        FieldType::String => json!("string"),
+        FieldType::Bytes => json!("bytes"),
+        FieldType::Enum(e) => {
+            let name = e.name().trim_start_matches('.');
+            if !defined.insert(name.to_owned()) {
+                return json!(name);
+            }
+            json!({ "type": "enum", "name": name, "symbols": symbols(e).iter().map(|v| v.name()).collect::<Vec<_>>() })
+        }
+        FieldType::Message(m) => record_schema(descriptors, m, defined),
+        FieldType::Group | FieldType::UnresolvedEnum(_) | FieldType::UnresolvedMessage(_) => {
+            json!("null")
+        }
+    }
+}
+
+/// The distinct names of the values of an enum, in declaration order; aliases are left out.
+fn symbols(enum_type: &descriptor::EnumDescriptor) -> Vec<&descriptor::EnumValueDescriptor> {
+    let mut symbols: Vec<&descriptor::EnumValueDescriptor> = Vec::new();
+    for v in enum_type.values() {
+        if !symbols.iter().any(|s| s.number() == v.number()) {
+            symbols.push(v);
+        }
+    }
+    symbols
+}
+
+fn write_record(
+    descriptors: &descriptor::Descriptors,
+    message_type: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    output: &mut Vec<u8>,
+) -> error::Result<()> {
+    for (field, field_type) in avro_fields(descriptors, message_type) {
+        let values: Vec<Cow<value::Value>> = match message.fields.get(&field.number()) {
+            // A cleared field is written as a null, just like an absent one
+            Some(value::Field::Singular(Some(value::Value::Null))) | None => Vec::new(),
+            Some(value::Field::Singular(v)) => v.iter().map(Cow::Borrowed).collect(),
+            Some(value::Field::Repeated(vs)) => vs.iter().map(Cow::Borrowed).collect(),
+            Some(value::Field::RepeatedTyped(vs)) => vs.iter().map(Cow::Owned).collect(),
+        };
+        if let Some((value_field, value_type)) = string_map_value(descriptors, field, &field_type) {
+            write_long(values.len() as i64, output);
+            for entry in &values {
+                let entry = match **entry {
+                    value::Value::Message(ref m) => m,
+                    _ => return Err(bad_field_value(field)),
+                };
+                match entry.fields.get(&1) {
+                    Some(value::Field::Singular(Some(value::Value::String(k)))) => {
+                        write_bytes(k.as_bytes(), output)
+                    }
+                    _ => write_bytes(b"", output),
+                }
+                match entry.fields.get(&2) {
+                    Some(value::Field::Singular(Some(v))) if !matches!(*v, value::Value::Null) => {
+                        write_value(descriptors, value_field, &value_type, v, output)?
+                    }
+                    _ => write_zero(descriptors, value_field, &value_type, output)?,
+                }
+            }
+            if !values.is_empty() {
+                write_long(0, output);
+            }
+        } else if field.is_repeated() {
+            write_long(values.len() as i64, output);
+            for v in &values {
+                write_value(descriptors, field, &field_type, v, output)?;
+            }
+            if !values.is_empty() {
+                write_long(0, output);
+            }
+        } else {
+            match values.first() {
+                None => write_long(0, output),
+                Some(v) => {
+                    write_long(1, output);
+                    write_value(descriptors, field, &field_type, v, output)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType,
+    value: &value::Value,
+    output: &mut Vec<u8>,
+) -> error::Result<()> {
+    match (field_type, value) {
+        (&FieldType::Bool, &value::Value::Bool(v)) => output.push(u8::from(v)),
+        (_, &value::Value::I32(v)) => write_long(i64::from(v), output),
+        (_, &value::Value::I64(v)) => write_long(v, output),
+        (_, &value::Value::U32(v)) => write_long(i64::from(v), output),
+        (_, &value::Value::U64(v)) => write_long(v as i64, output),
+        (_, &value::Value::F32(v)) => output.extend_from_slice(&v.to_le_bytes()),
+        (_, &value::Value::F64(v)) => output.extend_from_slice(&v.to_le_bytes()),
+        (_, value::Value::String(v)) => write_bytes(v.as_bytes(), output),
+        (_, value::Value::Bytes(v)) => write_bytes(v, output),
+        (_, value::Value::BytesStream(s)) => write_bytes(&s.read_to_vec()?, output),
+        (&FieldType::Enum(e), &value::Value::Enum(n)) => {
+            let index = symbols(e)
+                .iter()
+                .position(|s| s.number() == n)
+                .ok_or(error::Error::UnknownEnumValue { value: n })?;
+            write_long(index as i64, output);
+        }
+        (&FieldType::Message(m), value::Value::Message(v)) => {
+            write_record(descriptors, m, v, output)?
+        }
+        (&FieldType::Message(m), value::Value::FieldMask(v))
+            if m.name() == field_mask::FIELD_MASK =>
+        {
+            write_long(v.paths.len() as i64, output);
+            for path in &v.paths {
+                write_bytes(path.as_bytes(), output);
+            }
+            if !v.paths.is_empty() {
+                write_long(0, output);
+            }
+        }
+        _ => return Err(bad_field_value(field)),
+    }
+    Ok(())
+}
+
+/// Writes the zero value of a type, for map entries without a value.
+fn write_zero(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType,
+    output: &mut Vec<u8>,
+) -> error::Result<()> {
+    match *field_type {
+        FieldType::Float => output.extend_from_slice(&[0; 4]),
+        FieldType::Double => output.extend_from_slice(&[0; 8]),
+        FieldType::Enum(e) => match symbols(e).first() {
+            Some(_) => write_long(0, output),
+            None => return Err(bad_field_value(field)),
+        },
+        FieldType::Message(m) => write_record(descriptors, m, &value::Message::new(m), output)?,
+        // Booleans, numbers, strings and bytes are all a single zero byte
+        _ => output.push(0),
+    }
+    Ok(())
+}
+
+fn write_long(v: i64, output: &mut Vec<u8>) {
+    let mut n = ((v << 1) ^ (v >> 63)) as u64;
+    while n >= 0x80 {
+        output.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    output.push(n as u8);
+}
+
+fn write_bytes(bytes: &[u8], output: &mut Vec<u8>) {
+    write_long(bytes.len() as i64, output);
+    output.extend_from_slice(bytes);
+}
+
+fn read_record(
+    descriptors: &descriptor::Descriptors,
+    message_type: &descriptor::MessageDescriptor,
+    input: &mut &[u8],
+) -> error::Result<value::Message> {
+    let mut message = value::Message::new(message_type);
+    for (field, field_type) in avro_fields(descriptors, message_type) {
+        let value = if let Some((value_field, value_type)) =
+            string_map_value(descriptors, field, &field_type)
+        {
+            let entry_type = match field_type {
+                FieldType::Message(entry) => entry,
+                _ => return Err(bad_field_value(field)),
+            };
+            let mut entries = Vec::new();
+            read_blocks(input, |input| {
+                let key = read_string(input)?;
+                let value = read_value(descriptors, value_field, &value_type, input)?;
+                let mut entry = value::Message::new(entry_type);
+                entry
+                    .fields
+                    .insert(1, value::Field::Singular(Some(value::Value::String(key))));
+                entry.fields.insert(2, value::Field::Singular(Some(value)));
+                entries.push(value::Value::Message(Arc::new(entry)));
+                Ok(())
+            })?;
+            value::Field::Repeated(entries)
+        } else if field.is_repeated() {
+            let mut values = Vec::new();
+            read_blocks(input, |input| {
+                values.push(read_value(descriptors, field, &field_type, input)?);
+                Ok(())
+            })?;
+            value::Field::Repeated(values)
+        } else {
+            match read_long(input)? {
+                0 => value::Field::Singular(None),
+                1 => value::Field::Singular(Some(read_value(
+                    descriptors,
+                    field,
+                    &field_type,
+                    input,
+                )?)),
+                _ => return Err(bad_field_value(field)),
+            }
+        };
+        message.fields.insert(field.number(), value);
+    }
+    Ok(message)
+}
+
+fn read_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType,
+    input: &mut &[u8],
+) -> error::Result<value::Value> {
+    let bad = || bad_field_value(field);
+    let out_of_range = |_| bad_field_value(field);
+    Ok(match *field_type {
+        FieldType::Bool => match read_slice(input, 1)? {
+            [0] => value::Value::Bool(false),
+            [1] => value::Value::Bool(true),
+            _ => return Err(bad()),
+        },
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => {
+            value::Value::I32(read_long(input)?.try_into().map_err(out_of_range)?)
+        }
+        FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => {
+            value::Value::I64(read_long(input)?)
+        }
+        FieldType::UInt32 | FieldType::Fixed32 => {
+            value::Value::U32(read_long(input)?.try_into().map_err(out_of_range)?)
+        }
+        FieldType::UInt64 | FieldType::Fixed64 => value::Value::U64(read_long(input)? as u64),
+        FieldType::Float => {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(read_slice(input, 4)?);
+            value::Value::F32(f32::from_le_bytes(bytes))
+        }
+        FieldType::Double => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(read_slice(input, 8)?);
+            value::Value::F64(f64::from_le_bytes(bytes))
+        }
+        FieldType::String => value::Value::String(read_string(input)?),
+        FieldType::Bytes => {
+            let len = read_len(input)?;
+            value::Value::Bytes(read_slice(input, len)?.to_vec())
+        }
+        FieldType::Enum(e) => {
+            let index = usize::try_from(read_long(input)?).map_err(|_| bad())?;
+            value::Value::Enum(symbols(e).get(index).ok_or_else(bad)?.number())
+        }
+        FieldType::Message(m) if m.name() == field_mask::FIELD_MASK => {
+            let mut paths = Vec::new();
+            read_blocks(input, |input| {
+                paths.push(read_string(input)?);
+                Ok(())
+            })?;
+            value::Value::FieldMask(field_mask::FieldMask::new(paths))
+        }
+        FieldType::Message(m) => {
+            value::Value::Message(Arc::new(read_record(descriptors, m, input)?))
+        }
+        FieldType::Group | FieldType::UnresolvedEnum(_) | FieldType::UnresolvedMessage(_) => {
+            return Err(bad())
+        }
+    })
+}
+
+/// Reads the blocks of an array or map, calling the function for every item.
+fn read_blocks<F>(input: &mut &[u8], mut item: F) -> error::Result<()>
+where
+    F: FnMut(&mut &[u8]) -> error::Result<()>,
+{
+    loop {
+        let count = match read_long(input)? {
+            0 => return Ok(()),
+            n if n < 0 => {
+                // A negative count is followed by the size of the block in bytes
+                read_long(input)?;
+                n.checked_neg().ok_or(error::Error::EndOfStream)?
+            }
+            n => n,
+        };
+        for _ in 0..count {
+            item(input)?;
+        }
+    }
+}
+
+fn read_long(input: &mut &[u8]) -> error::Result<i64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *read_slice(input, 1)?
+            .first()
+            .ok_or(error::Error::EndOfStream)?;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(((n >> 1) as i64) ^ -((n & 1) as i64));
+        }
+    }
+    Err(error::Error::Custom {
+        message: "Avro long is longer than 10 bytes".to_owned(),
+    })
+}
+
+fn read_len(input: &mut &[u8]) -> error::Result<usize> {
+    usize::try_from(read_long(input)?).map_err(|_| error::Error::EndOfStream)
+}
+
+fn read_string(input: &mut &[u8]) -> error::Result<String> {
+    let len = read_len(input)?;
+    String::from_utf8(read_slice(input, len)?.to_vec()).map_err(|e| error::Error::Custom {
+        message: e.to_string(),
+    })
+}
+
+fn read_slice<'a>(input: &mut &'a [u8], len: usize) -> error::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(error::Error::EndOfStream);
+    }
+    let (slice, rest) = input.split_at(len);
+    *input = rest;
+    Ok(slice)
+}
+
+fn bad_field_value(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
@@ -0,0 +1,71 @@
+//! Conversion of messages to and from CBOR, using `ciborium`.
+//!
+//! Messages become CBOR maps keyed by field name, in the same shape that `de::Deserializer`
+//! produces.  `bytes` fields are written as CBOR byte strings rather than arrays of integers,
+//! and 64-bit integers keep their full range.
+//!
+//! This module is only available with the `ciborium` feature.
+use std::io;
+
+use crate::de;
+use crate::descriptor;
+use crate::error;
+use crate::ser;
+use crate::value;
+
+/// Encodes a message of the specified type as CBOR.
+pub fn to_vec(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    to_writer(descriptors, descriptor, message, &mut output)?;
+    Ok(output)
+}
+
+/// Writes a message of the specified type as CBOR.
+pub fn to_writer<W>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    output: W,
+) -> error::Result<()>
+where
+    W: io::Write,
+{
+    let content: ser::Content = message.to_typed(descriptors, descriptor)?;
+    ciborium::into_writer(&content, output).map_err(|e| match e {
+        ciborium::ser::Error::Io(e) => io_error(e),
+        e => custom_error(e),
+    })
+}
+
+/// Reads a message of the specified type from CBOR.
+pub fn from_reader<R>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: R,
+) -> error::Result<value::Message>
+where
+    R: io::Read,
+{
+    let content: ser::Content = ciborium::from_reader(input).map_err(|e| match e {
+        ciborium::de::Error::Io(e) => io_error(e),
+        e => custom_error(e),
+    })?;
+    ser::message_from_content(descriptors, descriptor, &de::Options::new(), content)
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
+
+fn custom_error<E>(e: E) -> error::Error
+where
+    E: std::fmt::Debug,
+{
+    error::Error::Custom {
+        message: format!("{:?}", e),
+    }
+}
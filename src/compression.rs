@@ -0,0 +1,271 @@
+//! Transparent compression of large `bytes` fields.
+//!
+//! `Compression` is a `value::FieldCodec` that compresses designated fields with gzip or zstd as
+//! they are written, and decompresses them as they are read, so that the decoded message holds
+//! the original content.  The size of decompressed content is limited, to protect against
+//! decompression bombs.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use std::sync::Arc;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::compression::{Algorithm, Compression};
+//! use serde_protobuf::value::{DecodeOptions, EncodeOptions, Field, Message, Value};
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//! let compression = Arc::new(
+//!     Compression::new()
+//!         .field(".protobuf_unittest.TestAllTypes", "optional_bytes", Algorithm::Zstd)
+//!         .max_decompressed_size(1 << 20),
+//! );
+//!
+//! let mut message = Message::default();
+//! message.fields.insert(15, Field::Singular(Some(Value::Bytes(vec![0; 10000]))));
+//! let options = EncodeOptions::new().field_codec(compression.clone());
+//! let bytes = message.write_to_bytes_with_options(&descriptors, descriptor, &options).unwrap();
+//! assert!(bytes.len() < 100);
+//!
+//! let mut decoded = Message::new(descriptor);
+//! let options = DecodeOptions::new().field_codec(compression);
+//! let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+//! decoded.merge_from_with_options(&descriptors, descriptor, &options, &mut input).unwrap();
+//! assert!(decoded.semantic_eq(&descriptors, descriptor, &message));
+//! # }
+//! ```
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
+
+use flate2;
+use protobuf;
+use zstd;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// The default limit on the size of decompressed content, 64 MiB.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 64 << 20;
+
+// The number of compressed contents that are kept from computing the encoded size of a message
+// until it is written
+const CACHE_CAPACITY: usize = 64;
+
+/// A compression algorithm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    /// gzip, as described in RFC 1952, at the default compression level.
+    Gzip,
+    /// Zstandard, at the default compression level.
+    Zstd,
+}
+
+/// A field codec that compresses designated fields.
+///
+/// The content of the fields is compressed on every write.  Since a message is encoded once to
+/// compute its size and again to write it, the compressed content of the first pass is kept
+/// until the second one uses it.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    // The fully qualified message name, field name and algorithm of each designated field
+    fields: Vec<(String, String, Algorithm)>,
+    max_decompressed_size: u64,
+    cache: Cache,
+}
+
+/// Compressed contents by the address, length and hash of the original content.
+struct Cache {
+    hasher: RandomState,
+    entries: Mutex<VecDeque<(CacheKey, Vec<u8>)>>,
+}
+
+type CacheKey = (Algorithm, usize, usize, u64);
+
+impl Compression {
+    /// Creates a codec that doesn't compress any fields yet.
+    pub fn new() -> Compression {
+        Compression {
+            fields: Vec::new(),
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            cache: Cache::new(),
+        }
+    }
+
+    /// Compresses the field with the specified name of the message type with the specified fully
+    /// qualified name, e.g. `.example.Blob`, with the specified algorithm.
+    pub fn field<M, F>(mut self, message: M, field: F, algorithm: Algorithm) -> Compression
+    where
+        M: Into<String>,
+        F: Into<String>,
+    {
+        self.fields.push((message.into(), field.into(), algorithm));
+        self
+    }
+
+    /// The maximum size of decompressed content; larger content fails the decode with a
+    /// `DecompressedTooLarge` error.  The default is `DEFAULT_MAX_DECOMPRESSED_SIZE`.
+    pub fn max_decompressed_size(mut self, max_size: u64) -> Compression {
+        self.max_decompressed_size = max_size;
+        self
+    }
+
+    fn algorithm(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) -> Option<Algorithm> {
+        self.fields
+            .iter()
+            .find(|(m, f, _)| m == message.name() && f == field.name())
+            .map(|&(_, _, algorithm)| algorithm)
+    }
+
+    fn read_limited<R>(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        reader: R,
+    ) -> error::Result<Vec<u8>>
+    where
+        R: Read,
+    {
+        let mut decompressed = Vec::new();
+        reader
+            .take(self.max_decompressed_size.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(io_error)?;
+        if decompressed.len() as u64 > self.max_decompressed_size {
+            return Err(error::Error::DecompressedTooLarge {
+                field: field.name().to_owned(),
+                limit: self.max_decompressed_size,
+            });
+        }
+        Ok(decompressed)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl value::FieldCodec for Compression {
+    fn applies_to(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) -> bool {
+        self.algorithm(message, field).is_some()
+    }
+
+    fn encode(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        content: &[u8],
+    ) -> error::Result<Vec<u8>> {
+        let algorithm = match self.algorithm(message, field) {
+            Some(algorithm) => algorithm,
+            None => return Ok(content.to_vec()),
+        };
+        let key = self.cache.key(algorithm, content);
+        if let Some(compressed) = self.cache.take(key) {
+            return Ok(compressed);
+        }
+        let compressed = match algorithm {
+            Algorithm::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(content).map_err(io_error)?;
+                encoder.finish().map_err(io_error)?
+            }
+            Algorithm::Zstd => zstd::stream::encode_all(content, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(io_error)?,
+        };
+        self.cache.put(key, compressed.clone());
+        Ok(compressed)
+    }
+
+    fn decode(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        content: Vec<u8>,
+    ) -> error::Result<Vec<u8>> {
+        match self.algorithm(message, field) {
+            Some(Algorithm::Gzip) => {
+                self.read_limited(field, flate2::read::GzDecoder::new(content.as_slice()))
+            }
+            Some(Algorithm::Zstd) => {
+                let decoder =
+                    zstd::stream::read::Decoder::new(content.as_slice()).map_err(io_error)?;
+                self.read_limited(field, decoder)
+            }
+            None => Ok(content),
+        }
+    }
+}
+
+impl Cache {
+    fn new() -> Cache {
+        Cache {
+            hasher: RandomState::new(),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // The content is identified by its address as well as its hash, so that content that was
+    // modified or freed in between isn't mistaken for the cached one
+    fn key(&self, algorithm: Algorithm, content: &[u8]) -> CacheKey {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(content);
+        (
+            algorithm,
+            content.as_ptr() as usize,
+            content.len(),
+            hasher.finish(),
+        )
+    }
+
+    fn take(&self, key: CacheKey) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let index = entries.iter().position(|&(k, _)| k == key)?;
+        entries.remove(index).map(|(_, compressed)| compressed)
+    }
+
+    fn put(&self, key: CacheKey, compressed: Vec<u8>) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == CACHE_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back((key, compressed));
+    }
+}
+
+// Clones start out with an empty cache
+impl Clone for Cache {
+    fn clone(&self) -> Cache {
+        Cache::new()
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache").finish()
+    }
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
@@ -0,0 +1,267 @@
+//! An adapter for the official protocol buffers conformance test suite.
+//!
+//! The conformance test runner starts a "testee" program and sends it `ConformanceRequest`
+//! messages on standard input, each prefixed with its length as a little-endian `u32`.  The testee
+//! parses the payload of each request as the requested message type, serializes it again in the
+//! requested output format and replies with a `ConformanceResponse` in the same framing.  `serve`
+//! implements that protocol on top of the dynamic messages of this crate; see
+//! `examples/conformance.rs` for a testee program.
+//!
+//! Only the binary format and (with the `serde_json` feature) the JSON format are supported;
+//! requests for other formats are answered as skipped.
+use std::io;
+
+use protobuf;
+use protobuf::stream::wire_format;
+
+use crate::descriptor;
+use crate::error;
+#[cfg(feature = "serde_json")]
+use crate::json;
+use crate::value;
+
+/// The message type that the runner asks for first, to learn about expected failures.
+const FAILURE_SET: &str = "conformance.FailureSet";
+
+/// The `JSON_IGNORE_UNKNOWN_PARSING_TEST` test category.
+#[cfg(feature = "serde_json")]
+const JSON_IGNORE_UNKNOWN_PARSING_TEST: i32 = 3;
+
+/// A request of the conformance test runner.
+#[derive(Clone, Debug, Default)]
+pub struct ConformanceRequest {
+    /// The payload to parse.
+    pub payload: Payload,
+    /// The format that the parsed message should be serialized to.
+    pub requested_output_format: WireFormat,
+    /// The fully qualified name of the message type, without a leading dot.
+    pub message_type: String,
+    /// The `conformance.TestCategory` of the test.
+    pub test_category: i32,
+}
+
+/// The payload of a conformance request.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    /// A binary encoded message.
+    Protobuf(Vec<u8>),
+    /// A JSON encoded message.
+    Json(String),
+    /// A message in a format that isn't supported, like JSPB or the text format.
+    Unsupported,
+}
+
+/// A serialization format of the conformance test protocol.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WireFormat {
+    /// No format was specified.
+    #[default]
+    Unspecified,
+    /// The binary format.
+    Protobuf,
+    /// The JSON format.
+    Json,
+    /// Some other format, with its `conformance.WireFormat` number.
+    Other(i32),
+}
+
+/// A response to the conformance test runner.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConformanceResponse {
+    /// The payload could not be parsed.
+    ParseError(String),
+    /// The parsed message could not be serialized.
+    SerializeError(String),
+    /// Some other error occurred.
+    RuntimeError(String),
+    /// The message serialized in the binary format.
+    Protobuf(Vec<u8>),
+    /// The message serialized in the JSON format.
+    Json(String),
+    /// The test was skipped, for the given reason.
+    Skipped(String),
+}
+
+/// Answers conformance requests read from the input until it ends, returning how many requests
+/// were answered.
+pub fn serve<R, W>(
+    descriptors: &descriptor::Descriptors,
+    input: &mut R,
+    output: &mut W,
+) -> error::Result<usize>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let mut count = 0;
+    loop {
+        let mut len = [0; 4];
+        match input.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(count),
+            Err(e) => return Err(io_error(e)),
+        }
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        input.read_exact(&mut bytes).map_err(io_error)?;
+
+        let response = match ConformanceRequest::parse(&bytes) {
+            Ok(request) => handle(descriptors, &request),
+            Err(e) => ConformanceResponse::RuntimeError(format!("bad request: {}", e)),
+        };
+        let bytes = response.write_to_bytes()?;
+        output
+            .write_all(&(bytes.len() as u32).to_le_bytes())
+            .and_then(|()| output.write_all(&bytes))
+            .and_then(|()| output.flush())
+            .map_err(io_error)?;
+        count += 1;
+    }
+}
+
+/// Answers a single conformance request.
+pub fn handle(
+    descriptors: &descriptor::Descriptors,
+    request: &ConformanceRequest,
+) -> ConformanceResponse {
+    if request.message_type == FAILURE_SET {
+        // No failures are expected up front
+        return ConformanceResponse::Protobuf(Vec::new());
+    }
+    let name = format!(".{}", request.message_type);
+    let descriptor = match descriptors.message_by_name(&name) {
+        Some(descriptor) => descriptor,
+        None => {
+            return ConformanceResponse::RuntimeError(format!("unknown message type: {}", name))
+        }
+    };
+
+    let message = match request.payload {
+        Payload::Protobuf(ref bytes) => {
+            let mut message = value::Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            match message.merge_from(descriptors, descriptor, &mut input) {
+                Ok(()) => message,
+                Err(e) => return ConformanceResponse::ParseError(e.to_string()),
+            }
+        }
+        #[cfg(feature = "serde_json")]
+        Payload::Json(_) if request.test_category == JSON_IGNORE_UNKNOWN_PARSING_TEST => {
+            return ConformanceResponse::Skipped("ignoring unknown JSON fields".to_owned())
+        }
+        #[cfg(feature = "serde_json")]
+        Payload::Json(ref s) => {
+            let parsed = serde_json::from_str(s)
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    json::from_json(descriptors, descriptor, &json).map_err(|e| e.to_string())
+                });
+            match parsed {
+                Ok(message) => message,
+                Err(e) => return ConformanceResponse::ParseError(e),
+            }
+        }
+        _ => return ConformanceResponse::Skipped("unsupported input format".to_owned()),
+    };
+
+    match request.requested_output_format {
+        WireFormat::Protobuf => match message.write_to_bytes(descriptors, descriptor) {
+            Ok(bytes) => ConformanceResponse::Protobuf(bytes),
+            Err(e) => ConformanceResponse::SerializeError(e.to_string()),
+        },
+        #[cfg(feature = "serde_json")]
+        WireFormat::Json => match json::to_json(descriptors, descriptor, &message) {
+            Ok(json) => ConformanceResponse::Json(json.to_string()),
+            Err(e) => ConformanceResponse::SerializeError(e.to_string()),
+        },
+        _ => ConformanceResponse::Skipped("unsupported output format".to_owned()),
+    }
+}
+
+impl ConformanceRequest {
+    /// Parses an encoded `conformance.ConformanceRequest` message.
+    pub fn parse(bytes: &[u8]) -> error::Result<ConformanceRequest> {
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut request = ConformanceRequest::default();
+
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            match (number, wire_type) {
+                (1, wire_format::WireType::WireTypeLengthDelimited) => {
+                    request.payload = Payload::Protobuf(input.read_bytes()?)
+                }
+                (2, wire_format::WireType::WireTypeLengthDelimited) => {
+                    request.payload = Payload::Json(input.read_string()?)
+                }
+                (7, wire_format::WireType::WireTypeLengthDelimited)
+                | (8, wire_format::WireType::WireTypeLengthDelimited) => {
+                    input.read_bytes()?;
+                    request.payload = Payload::Unsupported;
+                }
+                (3, wire_format::WireType::WireTypeVarint) => {
+                    request.requested_output_format = WireFormat::from_number(input.read_int32()?)
+                }
+                (4, wire_format::WireType::WireTypeLengthDelimited) => {
+                    request.message_type = input.read_string()?
+                }
+                (5, wire_format::WireType::WireTypeVarint) => {
+                    request.test_category = input.read_int32()?
+                }
+                _ => skip_field(number, wire_type, &mut input)?,
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Payload {
+        Payload::Protobuf(Vec::new())
+    }
+}
+
+impl WireFormat {
+    /// Converts a `conformance.WireFormat` number.
+    pub fn from_number(number: i32) -> WireFormat {
+        match number {
+            0 => WireFormat::Unspecified,
+            1 => WireFormat::Protobuf,
+            2 => WireFormat::Json,
+            n => WireFormat::Other(n),
+        }
+    }
+}
+
+impl ConformanceResponse {
+    /// Encodes this response as a `conformance.ConformanceResponse` message.
+    pub fn write_to_bytes(&self) -> error::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            match *self {
+                ConformanceResponse::ParseError(ref s) => output.write_string(1, s)?,
+                ConformanceResponse::RuntimeError(ref s) => output.write_string(2, s)?,
+                ConformanceResponse::Protobuf(ref b) => output.write_bytes(3, b)?,
+                ConformanceResponse::Json(ref s) => output.write_string(4, s)?,
+                ConformanceResponse::Skipped(ref s) => output.write_string(5, s)?,
+                ConformanceResponse::SerializeError(ref s) => output.write_string(6, s)?,
+            }
+            output.flush()?;
+        }
+        Ok(bytes)
+    }
+}
+
+fn skip_field(
+    number: u32,
+    wire_type: wire_format::WireType,
+    input: &mut protobuf::CodedInputStream,
+) -> error::Result<()> {
+    let mut unknown = protobuf::UnknownFields::new();
+    protobuf::rt::read_unknown_or_skip_group(number, wire_type, input, &mut unknown)?;
+    Ok(())
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
@@ -0,0 +1,493 @@
+//! Navigation of dynamic messages by field name and index.
+//!
+//! A `Cursor` points at a message, field or value inside of a message, and can move further
+//! down with `field` and `index`.  Reading through fields that are absent is fine and yields
+//! cursors without a value, so getters return `None` instead of failing.  A `CursorMut` can
+//! additionally set and clear values, and creates absent message fields as it moves through them.
+//! With the `bytes` feature, it also clears the source bytes of the messages that it moves through
+//! (see `value::Message::clear_source_bytes`), since they may be modified.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::cursor::{Cursor, CursorMut};
+//! use serde_protobuf::value::{Message, Value};
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//! let mut message = Message::new(descriptor);
+//!
+//! CursorMut::new(&descriptors, descriptor, &mut message)
+//!     .field("optional_nested_message").unwrap()
+//!     .field("bb").unwrap()
+//!     .set(Value::I32(7))
+//!     .unwrap();
+//!
+//! let cursor = Cursor::new(&descriptors, descriptor, &message);
+//! let bb = cursor.field("optional_nested_message").unwrap().field("bb").unwrap();
+//! assert_eq!(Some(7), bb.as_i32());
+//! # }
+//! ```
+use std::sync::Arc;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// A read-only cursor into a message.
+#[derive(Clone, Copy, Debug)]
+pub struct Cursor<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    field: Option<&'a descriptor::FieldDescriptor>,
+    node: Node<'a>,
+}
+
+/// A cursor into a message that can modify the values it points at.
+///
+/// Modifications are recorded as changes of the message that holds the modified field, see
+/// `value::Message::changed_paths`.
+#[derive(Debug)]
+pub struct CursorMut<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    field: Option<&'a descriptor::FieldDescriptor>,
+    node: NodeMut<'a>,
+    // Records changes to the message that holds `field`
+    changes: Option<value::ChangeRecorder<'a>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Node<'a> {
+    Message(&'a descriptor::MessageDescriptor, &'a value::Message),
+    Repeated(&'a [value::Value]),
+    Typed(&'a value::TypedValues),
+    Value(Option<&'a value::Value>),
+    // An element of a field with typed storage, by index
+    TypedElement(&'a value::TypedValues, usize),
+}
+
+#[derive(Debug)]
+enum NodeMut<'a> {
+    Message(&'a descriptor::MessageDescriptor, &'a mut value::Message),
+    Field(&'a mut value::Field),
+    Value(&'a mut value::Value),
+}
+
+macro_rules! getters {
+    ($($(#[$attr:meta])* $name:ident: $ty:ty => $variant:ident;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&self) -> Option<$ty> {
+                match self.element() {
+                    Some(value::Value::$variant(v)) => Some(v),
+                    _ => None,
+                }
+            }
+        )*
+
+        /// The string that the cursor points at, if any.
+        pub fn as_str(&self) -> Option<&str> {
+            match self.value() {
+                Some(value::Value::String(v)) => Some(v),
+                _ => None,
+            }
+        }
+
+        /// The bytes that the cursor points at, if any.
+        pub fn as_bytes(&self) -> Option<&[u8]> {
+            match self.value() {
+                Some(value::Value::Bytes(v)) => Some(v),
+                _ => None,
+            }
+        }
+
+        /// The name of the enum value that the cursor points at, if any.
+        pub fn as_enum_name(&self) -> Option<&'a str> {
+            match (self.value(), self.field?.field_type(self.descriptors)) {
+                (Some(&value::Value::Enum(v)), descriptor::FieldType::Enum(e)) => {
+                    e.value_by_number(v).map(|v| v.name())
+                }
+                _ => None,
+            }
+        }
+
+        /// The message that the cursor points at, if any.
+        pub fn as_message(&self) -> Option<&value::Message> {
+            match self.value() {
+                Some(value::Value::Message(m)) => Some(m),
+                _ => None,
+            }
+        }
+    };
+}
+
+impl<'a> Cursor<'a> {
+    /// Creates a cursor that points at the specified message.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        message: &'a value::Message,
+    ) -> Cursor<'a> {
+        Cursor {
+            descriptors,
+            field: None,
+            node: Node::Message(descriptor, message),
+        }
+    }
+
+    /// Moves to the field with the specified name of the message that the cursor points at.
+    pub fn field(&self, name: &str) -> error::Result<Cursor<'a>> {
+        let (descriptor, message) = match self.node {
+            Node::Message(d, m) => (d, Some(m)),
+            Node::Value(v) => {
+                let d = message_type(self.descriptors, self.field)?;
+                match v {
+                    Some(value::Value::Message(m)) => (d, Some(&**m)),
+                    Some(_) => return Err(bad_field_value(self.field)),
+                    None => (d, None),
+                }
+            }
+            Node::Repeated(_) | Node::Typed(_) | Node::TypedElement(..) => {
+                return Err(bad_field_value(self.field))
+            }
+        };
+        let field = field_by_name(descriptor, name)?;
+
+        let node = match message.and_then(|m| m.fields.get(&field.number())) {
+            Some(value::Field::Singular(v)) => Node::Value(v.as_ref()),
+            Some(value::Field::Repeated(vs)) => Node::Repeated(&vs[..]),
+            Some(value::Field::RepeatedTyped(vs)) => Node::Typed(vs),
+            None if field.is_repeated() => Node::Repeated(&[]),
+            None => Node::Value(None),
+        };
+        Ok(Cursor {
+            descriptors: self.descriptors,
+            field: Some(field),
+            node,
+        })
+    }
+
+    /// Moves to the element at the specified index of the repeated field that the cursor points
+    /// at.
+    pub fn index(&self, index: usize) -> error::Result<Cursor<'a>> {
+        match self.node {
+            Node::Repeated(vs) => match vs.get(index) {
+                Some(v) => Ok(Cursor {
+                    descriptors: self.descriptors,
+                    field: self.field,
+                    node: Node::Value(Some(v)),
+                }),
+                None => Err(out_of_bounds(self.field, index)),
+            },
+            Node::Typed(vs) if index < vs.len() => Ok(Cursor {
+                descriptors: self.descriptors,
+                field: self.field,
+                node: Node::TypedElement(vs, index),
+            }),
+            Node::Typed(_) => Err(out_of_bounds(self.field, index)),
+            _ => Err(bad_field_value(self.field)),
+        }
+    }
+
+    /// The number of elements of the repeated field that the cursor points at, or zero if it
+    /// doesn't point at a repeated field.
+    pub fn len(&self) -> usize {
+        match self.node {
+            Node::Repeated(vs) => vs.len(),
+            Node::Typed(vs) => vs.len(),
+            _ => 0,
+        }
+    }
+
+    /// Whether the cursor points at a repeated field without elements, or at no field at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The descriptor of the field that the cursor points into, or `None` at the root message.
+    pub fn field_descriptor(&self) -> Option<&'a descriptor::FieldDescriptor> {
+        self.field
+    }
+
+    /// The singular value that the cursor points at, if any.
+    ///
+    /// The elements of fields with typed storage aren't held as values, so they are only
+    /// available through the typed getters like `as_i32`.
+    pub fn value(&self) -> Option<&'a value::Value> {
+        match self.node {
+            Node::Value(v) => v,
+            _ => None,
+        }
+    }
+
+    /// The scalar value that the cursor points at, if any.
+    fn element(&self) -> Option<value::Value> {
+        match self.node {
+            Node::TypedElement(vs, index) => vs.get(index),
+            _ => scalar(self.value()),
+        }
+    }
+
+    getters! {
+        /// The `bool` that the cursor points at, if any.
+        as_bool: bool => Bool;
+        /// The `int32`, `sint32` or `sfixed32` that the cursor points at, if any.
+        as_i32: i32 => I32;
+        /// The `int64`, `sint64` or `sfixed64` that the cursor points at, if any.
+        as_i64: i64 => I64;
+        /// The `uint32` or `fixed32` that the cursor points at, if any.
+        as_u32: u32 => U32;
+        /// The `uint64` or `fixed64` that the cursor points at, if any.
+        as_u64: u64 => U64;
+        /// The `float` that the cursor points at, if any.
+        as_f32: f32 => F32;
+        /// The `double` that the cursor points at, if any.
+        as_f64: f64 => F64;
+        /// The number of the enum value that the cursor points at, if any.
+        as_enum: i32 => Enum;
+    }
+}
+
+impl<'a> CursorMut<'a> {
+    /// Creates a cursor that points at the specified message.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        message: &'a mut value::Message,
+    ) -> CursorMut<'a> {
+        CursorMut {
+            descriptors,
+            field: None,
+            node: NodeMut::Message(descriptor, message),
+            changes: None,
+        }
+    }
+
+    /// Moves to the field with the specified name of the message that the cursor points at.
+    ///
+    /// If the cursor points at an absent singular message field, an empty message is created.
+    pub fn field(self, name: &str) -> error::Result<CursorMut<'a>> {
+        let descriptors = self.descriptors;
+        let (descriptor, message) = match self.node {
+            NodeMut::Message(d, m) => (d, m),
+            NodeMut::Field(value::Field::Singular(slot)) => {
+                let d = message_type(descriptors, self.field)?;
+                if slot.is_none() {
+                    if let (Some(mut changes), Some(field)) = (self.changes, self.field) {
+                        changes.mark(field.number());
+                    }
+                }
+                let v = slot
+                    .get_or_insert_with(|| value::Value::Message(Arc::new(value::Message::new(d))));
+                match v {
+                    value::Value::Message(m) => (d, Arc::make_mut(m)),
+                    _ => return Err(bad_field_value(self.field)),
+                }
+            }
+            NodeMut::Value(value::Value::Message(m)) => {
+                (message_type(descriptors, self.field)?, Arc::make_mut(m))
+            }
+            _ => return Err(bad_field_value(self.field)),
+        };
+        let field = field_by_name(descriptor, name)?;
+        // The message may be about to be modified
+        #[cfg(feature = "bytes")]
+        message.clear_source_bytes();
+
+        let (fields, changes) = message.fields_and_changes();
+        let node = fields
+            .entry(field.number())
+            .or_insert_with(|| value::Field::new(field));
+        Ok(CursorMut {
+            descriptors,
+            field: Some(field),
+            node: NodeMut::Field(node),
+            changes: Some(changes),
+        })
+    }
+
+    /// Moves to the element at the specified index of the repeated field that the cursor points
+    /// at.
+    pub fn index(mut self, index: usize) -> error::Result<CursorMut<'a>> {
+        if let NodeMut::Field(ref mut field) = self.node {
+            field.make_untyped();
+        }
+        match self.node {
+            NodeMut::Field(value::Field::Repeated(vs)) => match vs.get_mut(index) {
+                Some(v) => Ok(CursorMut {
+                    descriptors: self.descriptors,
+                    field: self.field,
+                    node: NodeMut::Value(v),
+                    changes: self.changes,
+                }),
+                None => Err(out_of_bounds(self.field, index)),
+            },
+            _ => Err(bad_field_value(self.field)),
+        }
+    }
+
+    /// The number of elements of the repeated field that the cursor points at, or zero if it
+    /// doesn't point at a repeated field.
+    pub fn len(&self) -> usize {
+        match self.node {
+            NodeMut::Field(value::Field::Repeated(ref vs)) => vs.len(),
+            NodeMut::Field(value::Field::RepeatedTyped(ref vs)) => vs.len(),
+            _ => 0,
+        }
+    }
+
+    /// Whether the cursor points at a repeated field without elements, or at no field at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The descriptor of the field that the cursor points into, or `None` at the root message.
+    pub fn field_descriptor(&self) -> Option<&'a descriptor::FieldDescriptor> {
+        self.field
+    }
+
+    /// The singular value that the cursor points at, if any.
+    pub fn value(&self) -> Option<&value::Value> {
+        match self.node {
+            NodeMut::Field(value::Field::Singular(ref v)) => v.as_ref(),
+            NodeMut::Value(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn element(&self) -> Option<value::Value> {
+        scalar(self.value())
+    }
+
+    getters! {
+        /// The `bool` that the cursor points at, if any.
+        as_bool: bool => Bool;
+        /// The `int32`, `sint32` or `sfixed32` that the cursor points at, if any.
+        as_i32: i32 => I32;
+        /// The `int64`, `sint64` or `sfixed64` that the cursor points at, if any.
+        as_i64: i64 => I64;
+        /// The `uint32` or `fixed32` that the cursor points at, if any.
+        as_u32: u32 => U32;
+        /// The `uint64` or `fixed64` that the cursor points at, if any.
+        as_u64: u64 => U64;
+        /// The `float` that the cursor points at, if any.
+        as_f32: f32 => F32;
+        /// The `double` that the cursor points at, if any.
+        as_f64: f64 => F64;
+        /// The number of the enum value that the cursor points at, if any.
+        as_enum: i32 => Enum;
+    }
+
+    /// Sets the singular value that the cursor points at.
+    ///
+    /// Fails if the value doesn't have the representation of the field type, e.g. a
+    /// `Value::I64` for an `int32` field.
+    pub fn set(&mut self, value: value::Value) -> error::Result<()> {
+        let field = self.field.ok_or_else(|| bad_field_value(self.field))?;
+        value::check_value(self.descriptors, field, &value)?;
+        match self.node {
+            NodeMut::Field(value::Field::Singular(ref mut v)) => *v = Some(value),
+            NodeMut::Value(ref mut v) => **v = value,
+            _ => return Err(bad_field_value(self.field)),
+        }
+        self.mark_changed();
+        Ok(())
+    }
+
+    /// Sets the enum value that the cursor points at by name.
+    pub fn set_enum_name(&mut self, name: &str) -> error::Result<()> {
+        let number = match self.field.map(|f| f.field_type(self.descriptors)) {
+            Some(descriptor::FieldType::Enum(e)) => e.value_by_name(name).map(|v| v.number()),
+            _ => None,
+        };
+        match number {
+            Some(number) => self.set(value::Value::Enum(number)),
+            None => Err(bad_field_value(self.field)),
+        }
+    }
+
+    /// Clears the field that the cursor points at, making it absent or empty.
+    pub fn clear(&mut self) -> error::Result<()> {
+        match (self.field, &mut self.node) {
+            (Some(field), NodeMut::Field(f)) => **f = value::Field::new(field),
+            _ => return Err(bad_field_value(self.field)),
+        }
+        self.mark_changed();
+        Ok(())
+    }
+
+    /// Appends a value to the repeated field that the cursor points at.
+    pub fn push(&mut self, value: value::Value) -> error::Result<()> {
+        match (self.field, &mut self.node) {
+            (Some(field), NodeMut::Field(f)) => f.push(self.descriptors, field, value)?,
+            _ => return Err(bad_field_value(self.field)),
+        }
+        self.mark_changed();
+        Ok(())
+    }
+
+    fn mark_changed(&mut self) {
+        if let (Some(changes), Some(field)) = (self.changes.as_mut(), self.field) {
+            changes.mark(field.number());
+        }
+    }
+}
+
+/// A copy of a value of a bool, numeric or enum type.
+fn scalar(value: Option<&value::Value>) -> Option<value::Value> {
+    match value {
+        Some(
+            v @ value::Value::Bool(_)
+            | v @ value::Value::I32(_)
+            | v @ value::Value::I64(_)
+            | v @ value::Value::U32(_)
+            | v @ value::Value::U64(_)
+            | v @ value::Value::F32(_)
+            | v @ value::Value::F64(_)
+            | v @ value::Value::Enum(_),
+        ) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+fn field_by_name<'a>(
+    descriptor: &'a descriptor::MessageDescriptor,
+    name: &str,
+) -> error::Result<&'a descriptor::FieldDescriptor> {
+    descriptor
+        .field_by_name(name)
+        .ok_or_else(|| error::Error::UnknownField {
+            name: name.to_owned(),
+        })
+}
+
+fn message_type<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    field: Option<&'a descriptor::FieldDescriptor>,
+) -> error::Result<&'a descriptor::MessageDescriptor> {
+    match field.map(|f| f.field_type(descriptors)) {
+        Some(descriptor::FieldType::Message(m)) => Ok(m),
+        _ => Err(bad_field_value(field)),
+    }
+}
+
+fn field_name(field: Option<&descriptor::FieldDescriptor>) -> String {
+    field.map_or("", |f| f.name()).to_owned()
+}
+
+fn bad_field_value(field: Option<&descriptor::FieldDescriptor>) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field_name(field),
+    }
+}
+
+fn out_of_bounds(field: Option<&descriptor::FieldDescriptor>, index: usize) -> error::Error {
+    error::Error::IndexOutOfBounds {
+        field: field_name(field),
+        index,
+    }
+}
@@ -63,15 +63,25 @@
 //! #   foo().unwrap();
 //! # }
 //! ```
+//!
+//! Messages are visited as maps keyed by field name, and repeated fields as sequences, with any
+//! error raised while visiting the value it concerns.  Wrapping the deserializer with
+//! `serde_path_to_error` thus reports where in a message a failure occurred, e.g.
+//! `payload.repeated_nested_enum[1]`.
 
 use crate::descriptor;
 use crate::error;
+use crate::field_mask;
+use crate::google_type;
 
 use crate::value;
+use crate::well_known;
 use protobuf;
 use serde;
+use serde::de::IntoDeserializer;
 use std::collections;
 use std::fmt;
+use std::sync::Arc;
 use std::vec;
 
 /// A deserializer that can deserialize a single message type.
@@ -79,34 +89,197 @@ pub struct Deserializer<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::MessageDescriptor,
     input: protobuf::CodedInputStream<'de>,
+    options: Options,
+    mask: Option<&'de field_mask::FieldMaskTree>,
+}
+
+/// Options that control the shape of the deserialized data.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    emit_defaults: bool,
+    field_numbers_as_keys: bool,
+    flatten_wrappers: bool,
+    google_types: bool,
+    time_policy: well_known::TimePolicy,
+    #[cfg(feature = "uuid")]
+    uuid_rule: Option<UuidRule>,
+    stats_callback: Option<StatsCallback>,
+    decode_options: value::DecodeOptions,
+    unknown_enum_values: UnknownEnumValues,
+    oneofs: OneofRepresentation,
+    scalar_representations: Vec<Arc<dyn ScalarRepresentation>>,
+}
+
+#[derive(Clone)]
+struct StatsCallback(Arc<dyn Fn(&value::DecodeStats) + Send + Sync>);
+
+/// A rule that selects the `bytes` fields that hold UUIDs.
+#[cfg(feature = "uuid")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UuidRule {
+    /// Fields with the custom boolean field option with this number set, e.g. `[(uuid) = true]`.
+    CustomOption(u32),
+    /// Fields whose name ends with this suffix, e.g. `"_uuid"`.
+    NameSuffix(String),
+}
+
+/// How to deserialize enum values that their enum type doesn't declare.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum UnknownEnumValues {
+    /// Fail with an `UnknownEnumValue` error.
+    #[default]
+    Error,
+    /// Deserialize them as if they were the value with this name, e.g. `"UNKNOWN"`.
+    Variant(String),
+    /// Deserialize them as their number.  Rust enums match the number against variant indices.
+    Number,
+}
+
+/// How to deserialize the fields of a `oneof`.
+///
+/// The synthetic oneofs of proto3 `optional` fields are always flattened.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum OneofRepresentation {
+    /// Every field of the oneof is its own entry of the message, like any other field.
+    #[default]
+    Flattened,
+    /// The oneof is a single entry of the message, named after the oneof, whose value is a map
+    /// from the name of the field that is set to its value, e.g. `{"foo": {"foo_int": 1}}`.
+    ///
+    /// This is the representation of externally tagged Rust enums, so a oneof can be
+    /// deserialized into an `Option` of an enum with a newtype variant for each field.
+    ExternallyTagged,
+    /// The oneof is a single entry of the message, named after the oneof, whose value is a map
+    /// with the name of the field that is set under the `tag` key and its value under the
+    /// `content` key, e.g. `{"foo": {"kind": "foo_int", "value": 1}}`.
+    AdjacentlyTagged {
+        /// The key of the field name.
+        tag: String,
+        /// The key of the field value.
+        content: String,
+    },
 }
 
-struct MessageVisitor<'de> {
+/// A custom serde representation of the values of selected scalar and enum fields, like a
+/// `fixed64` id as a hex string or enum values in lower case.
+///
+/// The deserializer visits the representation in place of the value, and
+/// `Message::from_typed_with_options` turns serialized representations back into values.
+pub trait ScalarRepresentation: fmt::Debug + Send + Sync {
+    /// Whether the representation applies to a field of the specified type.
+    ///
+    /// Fields can be selected by their name or a custom option, see
+    /// `FieldDescriptor::custom_option`, or by their type, like all fields of an enum type.
+    /// Message fields are never represented.
+    fn applies_to(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        field_type: &descriptor::FieldType,
+    ) -> bool;
+
+    /// The representation of a value of a field.
+    fn represent(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        field_type: &descriptor::FieldType,
+        value: value::Value,
+    ) -> error::Result<value::Value>;
+
+    /// The value of a field that a serialized representation stands for, undoing `represent`.
+    fn parse(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        field_type: &descriptor::FieldType,
+        representation: value::Value,
+    ) -> error::Result<value::Value>;
+}
+
+/// The identifier that an enum value is deserialized as.
+enum EnumIdentifier<'a> {
+    Name(&'a str),
+    Number(i32),
+}
+
+/// The field of a oneof that is set, with its value.
+type OneofValue<'de> = Option<(&'de descriptor::FieldDescriptor, value::Value)>;
+
+struct MessageVisitor<'de, 'o> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::MessageDescriptor,
+    options: &'o Options,
     fields: collections::btree_map::IntoIter<i32, value::Field>,
     field: Option<(&'de descriptor::FieldDescriptor, value::Field)>,
+    oneofs: vec::IntoIter<(&'de descriptor::OneofDescriptor, OneofValue<'de>)>,
+    oneof: Option<OneofValue<'de>>,
+}
+
+struct OneofDeserializer<'de, 'o> {
+    descriptors: &'de descriptor::Descriptors,
+    options: &'o Options,
+    value: OneofValue<'de>,
+}
+
+struct OneofFieldDeserializer<'de, 'o> {
+    descriptors: &'de descriptor::Descriptors,
+    options: &'o Options,
+    field: &'de descriptor::FieldDescriptor,
+    value: value::Value,
+}
+
+struct OneofVisitor<'de, 'o> {
+    descriptors: &'de descriptor::Descriptors,
+    options: &'o Options,
+    field: &'de descriptor::FieldDescriptor,
+    value: Option<value::Value>,
+    keys: std::slice::Iter<'static, OneofKey>,
+    key: Option<OneofKey>,
+}
+
+#[derive(Clone, Copy)]
+enum OneofKey {
+    Field,
+    Tag,
+    Content,
+}
+
+struct DecodedMessageDeserializer<'de, 'o> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    options: &'o Options,
+    message: value::Message,
 }
 
 struct MessageKeyDeserializer<'de> {
     descriptor: &'de descriptor::FieldDescriptor,
+    number: bool,
 }
 
-struct MessageFieldDeserializer<'de> {
+struct MessageFieldDeserializer<'de, 'o> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: &'o Options,
     field: Option<value::Field>,
 }
 
-struct RepeatedValueVisitor<'de> {
+struct RepeatedValueVisitor<'de, 'o> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: &'o Options,
     values: vec::IntoIter<value::Value>,
 }
 
-struct ValueDeserializer<'de> {
+struct MapEntryVisitor<'de, 'o> {
+    descriptors: &'de descriptor::Descriptors,
+    entry: &'de descriptor::MessageDescriptor,
+    options: &'o Options,
+    entries: vec::IntoIter<value::Value>,
+    value: Option<(&'de descriptor::FieldDescriptor, value::Value)>,
+}
+
+struct ValueDeserializer<'de, 'o> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: &'o Options,
     value: Option<value::Value>,
 }
 
@@ -124,6 +297,8 @@ impl<'de> Deserializer<'de> {
             descriptors,
             descriptor,
             input,
+            options: Options::default(),
+            mask: None,
         }
     }
 
@@ -146,6 +321,210 @@ impl<'de> Deserializer<'de> {
     }
 }
 
+impl<'de> Deserializer<'de> {
+    /// Sets the options to use for this deserializer.
+    pub fn with_options(mut self, options: Options) -> Deserializer<'de> {
+        self.options = options;
+        self
+    }
+
+    /// Only deserializes the fields in a field mask that was compiled against the message type,
+    /// e.g. with `field_mask::FieldMask::compile`.
+    ///
+    /// The other fields are skipped on the wire without being decoded, and are deserialized as if
+    /// they were absent.
+    pub fn with_field_mask(mut self, mask: &'de field_mask::FieldMaskTree) -> Deserializer<'de> {
+        self.mask = Some(mask);
+        self
+    }
+}
+
+impl Options {
+    /// Creates the default deserialization options.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Whether to emit absent singular scalar and enum fields with their default value.
+    ///
+    /// By default, absent optional fields without a declared default value are deserialized as
+    /// `None`.  With this option enabled they instead get the zero value of their type (or the
+    /// first value of their enum), so that consumers see a stable set of keys with concrete
+    /// values.  Absent message fields and proto3 `optional` fields, which track presence, are
+    /// still deserialized as `None`.
+    pub fn emit_defaults(mut self, emit_defaults: bool) -> Options {
+        self.emit_defaults = emit_defaults;
+        self
+    }
+
+    /// Whether to key the fields of messages by their field number instead of their name, e.g.
+    /// `"1"` instead of `"optional_int32"`.
+    ///
+    /// Numbers stay stable when fields are renamed.  Converting values back into messages
+    /// accepts fields keyed by number as well as by name.  Oneofs that aren't flattened are still
+    /// keyed by their name.
+    pub fn field_numbers_as_keys(mut self, field_numbers_as_keys: bool) -> Options {
+        self.field_numbers_as_keys = field_numbers_as_keys;
+        self
+    }
+
+    /// Whether to deserialize wrapper messages like `google.protobuf.Int32Value` as their wrapped
+    /// value.
+    ///
+    /// A wrapper field then deserializes into e.g. an `Option<i32>` instead of an optional struct
+    /// with a single `value` field, just like a proto3 `optional int32` field does.
+    pub fn flatten_wrappers(mut self, flatten_wrappers: bool) -> Options {
+        self.flatten_wrappers = flatten_wrappers;
+        self
+    }
+
+    /// Whether to deserialize supported `google.type` messages as strings.
+    ///
+    /// `google.type.Date`, `TimeOfDay`, `Decimal` and `Money` values are then deserialized as
+    /// e.g. `"2019-02-28"`, `"13:45:30.5"`, `"2.5"` and `"12.34 USD"` respectively, instead of as
+    /// maps of their fields.  See the [`google_type`](../google_type/index.html) module.
+    pub fn google_types(mut self, google_types: bool) -> Options {
+        self.google_types = google_types;
+        self
+    }
+
+    /// What to do with `google.protobuf.Timestamp` and `Duration` values that violate the
+    /// protobuf specification.  By default, they are passed through unchecked.
+    pub fn time_policy(mut self, time_policy: well_known::TimePolicy) -> Options {
+        self.time_policy = time_policy;
+        self
+    }
+
+    /// Which `bytes` fields to deserialize as hyphenated UUID strings.
+    ///
+    /// Only values that are exactly 16 bytes long are converted; other values of matching fields
+    /// are still deserialized as bytes.
+    #[cfg(feature = "uuid")]
+    pub fn uuid_rule(mut self, uuid_rule: UuidRule) -> Options {
+        self.uuid_rule = Some(uuid_rule);
+        self
+    }
+
+    /// A callback that receives statistics about the decode of every deserialized message.
+    ///
+    /// This can be used to monitor e.g. rising unknown-field counts that indicate schema drift.
+    pub fn on_stats<F>(mut self, callback: F) -> Options
+    where
+        F: Fn(&value::DecodeStats) + Send + Sync + 'static,
+    {
+        self.stats_callback = Some(StatsCallback(Arc::new(callback)));
+        self
+    }
+
+    /// What to do with fields that the message type doesn't know about.
+    ///
+    /// Unknown fields are never deserialized, but with `UnknownFieldPolicy::Error` their presence
+    /// fails the deserialization, and with `UnknownFieldPolicy::Callback` they can be audited.
+    pub fn unknown_fields(mut self, policy: value::UnknownFieldPolicy) -> Options {
+        self.decode_options = self.decode_options.unknown_fields(policy);
+        self
+    }
+
+    /// Whether to deserialize fields of message types that aren't in the registry as the bytes of
+    /// the encoded message, instead of failing.
+    pub fn unresolved_messages_as_bytes(mut self, as_bytes: bool) -> Options {
+        self.decode_options = self.decode_options.unresolved_messages_as_bytes(as_bytes);
+        self
+    }
+
+    /// Whether to reject encodings that are valid but not canonical, like padded varints; see
+    /// `DecodeOptions::strict`.
+    pub fn strict(mut self, strict: bool) -> Options {
+        self.decode_options = self.decode_options.strict(strict);
+        self
+    }
+
+    /// What to do about deprecated fields that occur in the input; by default, they aren't looked
+    /// for.
+    pub fn deprecated_fields(mut self, policy: value::DeprecatedFieldPolicy) -> Options {
+        self.decode_options = self.decode_options.deprecated_fields(policy);
+        self
+    }
+
+    /// What to do when a singular field occurs more than once; by default, the last value wins.
+    ///
+    /// Values collected with `DuplicateFieldPolicy::Collect` aren't deserialized.
+    pub fn duplicate_fields(mut self, policy: value::DuplicateFieldPolicy) -> Options {
+        self.decode_options = self.decode_options.duplicate_fields(policy);
+        self
+    }
+
+    /// A codec that undoes the transformation of selected `bytes` and `string` fields before
+    /// they are deserialized; see `value::FieldCodec`.
+    pub fn field_codec(mut self, codec: Arc<dyn value::FieldCodec>) -> Options {
+        self.decode_options = self.decode_options.field_codec(codec);
+        self
+    }
+
+    /// What to do with enum values that their enum type doesn't declare.
+    ///
+    /// Enum values are deserialized as their names, which also makes them deserializable into
+    /// `#[derive(Deserialize)]` Rust enums with matching unit variants.  Values that have no name
+    /// fail the deserialization by default.
+    pub fn unknown_enum_values(mut self, policy: UnknownEnumValues) -> Options {
+        self.unknown_enum_values = policy;
+        self
+    }
+
+    /// How to represent the fields of oneofs; by default they are flattened into the message.
+    pub fn oneofs(mut self, representation: OneofRepresentation) -> Options {
+        self.oneofs = representation;
+        self
+    }
+
+    /// Adds a custom representation of the values of selected scalar and enum fields; see
+    /// `ScalarRepresentation`.
+    ///
+    /// The first added representation that applies to a field is used.
+    pub fn scalar_representation(
+        mut self,
+        representation: Arc<dyn ScalarRepresentation>,
+    ) -> Options {
+        self.scalar_representations.push(representation);
+        self
+    }
+
+    /// The scalar representation that applies to a field, if any.
+    pub(crate) fn scalar_representation_for(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        field_type: &descriptor::FieldType,
+    ) -> Option<&dyn ScalarRepresentation> {
+        if let descriptor::FieldType::Message(_) = *field_type {
+            return None;
+        }
+        self.scalar_representations
+            .iter()
+            .find(|r| r.applies_to(field, field_type))
+            .map(|r| &**r)
+    }
+}
+
+impl fmt::Debug for StatsCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatsCallback").finish()
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl UuidRule {
+    /// Whether this rule selects the specified field.
+    pub fn matches(&self, field: &descriptor::FieldDescriptor) -> bool {
+        match *self {
+            UuidRule::CustomOption(number) => match field.custom_option(number) {
+                Some(protobuf::UnknownValueRef::Varint(v)) => v != 0,
+                _ => false,
+            },
+            UuidRule::NameSuffix(ref suffix) => field.name().ends_with(suffix.as_str()),
+        }
+    }
+}
+
 impl<'de> fmt::Debug for Deserializer<'de> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Deserializer").finish()
@@ -167,34 +546,98 @@ impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
         V: serde::de::Visitor<'de>,
     {
         let mut message = value::Message::new(self.descriptor);
-        message.merge_from(self.descriptors, self.descriptor, &mut self.input)?;
+        let mut stats = value::DecodeStats::default();
+        let collect_stats = self.options.stats_callback.is_some();
+        message.merge_from_configured(
+            self.descriptors,
+            self.descriptor,
+            &self.options.decode_options,
+            &mut self.input,
+            self.mask,
+            if collect_stats {
+                Some(&mut stats)
+            } else {
+                None
+            },
+        )?;
+        if let Some(StatsCallback(ref callback)) = self.options.stats_callback {
+            callback(&stats);
+        }
+        self.options
+            .time_policy
+            .apply(self.descriptors, self.descriptor, &mut message)?;
         visitor.visit_map(MessageVisitor::new(
             self.descriptors,
             self.descriptor,
+            &self.options,
             message,
         ))
     }
 }
 
-impl<'de> MessageVisitor<'de> {
+impl<'de, 'o> serde::Deserializer<'de> for DecodedMessageDeserializer<'de, 'o> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_map(MessageVisitor::new(
+            self.descriptors,
+            self.descriptor,
+            self.options,
+            self.message,
+        ))
+    }
+}
+
+impl<'de, 'o> MessageVisitor<'de, 'o> {
     #[inline]
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::MessageDescriptor,
+        options: &'o Options,
         value: value::Message,
-    ) -> MessageVisitor<'de> {
-        let fields = value.fields.into_iter();
-        let field = None;
+    ) -> MessageVisitor<'de, 'o> {
+        let mut fields = value.fields;
+        let mut oneofs = Vec::new();
+        if options.oneofs != OneofRepresentation::Flattened {
+            for (index, oneof) in descriptor.oneofs().iter().enumerate() {
+                if descriptor.is_synthetic_oneof(index) {
+                    continue;
+                }
+                let mut set = None;
+                for field in descriptor.oneof_fields(index) {
+                    match fields.remove(&field.number()) {
+                        Some(value::Field::Singular(Some(value::Value::Null))) | None => (),
+                        Some(value::Field::Singular(Some(v))) => set = Some((field, v)),
+                        Some(_) => (),
+                    }
+                }
+                oneofs.push((oneof, set));
+            }
+        }
+
         MessageVisitor {
             descriptors,
             descriptor,
-            fields,
-            field,
+            options,
+            fields: fields.into_iter(),
+            field: None,
+            oneofs: oneofs.into_iter(),
+            oneof: None,
         }
     }
 }
 
-impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
+impl<'de, 'o> serde::de::MapAccess<'de> for MessageVisitor<'de, 'o> {
     type Error = error::CompatError;
 
     #[inline]
@@ -207,9 +650,16 @@ impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
                 .descriptor
                 .field_by_number(k)
                 .expect("Lost track of field");
-            let key = seed.deserialize(MessageKeyDeserializer::new(descriptor))?;
+            let key = seed.deserialize(MessageKeyDeserializer::new(
+                descriptor,
+                self.options.field_numbers_as_keys,
+            ))?;
             self.field = Some((descriptor, v));
             Ok(Some(key))
+        } else if let Some((oneof, value)) = self.oneofs.next() {
+            let key = seed.deserialize(str_deserializer(oneof.name()))?;
+            self.oneof = Some(value);
+            Ok(Some(key))
         } else {
             Ok(None)
         }
@@ -220,6 +670,13 @@ impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
     where
         V: serde::de::DeserializeSeed<'de>,
     {
+        if let Some(value) = self.oneof.take() {
+            return seed.deserialize(OneofDeserializer {
+                descriptors: self.descriptors,
+                options: self.options,
+                value,
+            });
+        }
         let (descriptor, field) = self
             .field
             .take()
@@ -228,15 +685,148 @@ impl<'de> serde::de::MapAccess<'de> for MessageVisitor<'de> {
         seed.deserialize(MessageFieldDeserializer::new(
             self.descriptors,
             descriptor,
+            self.options,
             field,
         ))
     }
 }
 
+impl<'de, 'o> serde::Deserializer<'de> for OneofDeserializer<'de, 'o> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    /// Oneofs are visited like optional fields, as `None` if no field is set.
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            Some((field, value)) => visitor.visit_some(OneofFieldDeserializer {
+                descriptors: self.descriptors,
+                options: self.options,
+                field,
+                value,
+            }),
+            None => visitor.visit_none(),
+        }
+    }
+}
+
+impl<'de, 'o> serde::Deserializer<'de> for OneofFieldDeserializer<'de, 'o> {
+    type Error = error::CompatError;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+
+    /// Externally tagged oneofs are visited as newtype variants named by the field that is set.
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        if self.options.oneofs == OneofRepresentation::ExternallyTagged {
+            visitor.visit_enum(self)
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_any<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let keys: &'static [OneofKey] = match self.options.oneofs {
+            OneofRepresentation::AdjacentlyTagged { .. } => &[OneofKey::Tag, OneofKey::Content],
+            _ => &[OneofKey::Field],
+        };
+        visitor.visit_map(OneofVisitor {
+            descriptors: self.descriptors,
+            options: self.options,
+            field: self.field,
+            value: Some(self.value),
+            keys: keys.iter(),
+            key: None,
+        })
+    }
+}
+
+impl<'de, 'o> serde::de::EnumAccess<'de> for OneofFieldDeserializer<'de, 'o> {
+    type Error = error::CompatError;
+    type Variant = ValueDeserializer<'de, 'o>;
+
+    fn variant_seed<V>(self, seed: V) -> error::CompatResult<(V::Value, Self::Variant)>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(str_deserializer(self.field.name()))?;
+        Ok((
+            variant,
+            ValueDeserializer::new(self.descriptors, self.field, self.options, self.value),
+        ))
+    }
+}
+
+impl<'de, 'o> serde::de::MapAccess<'de> for OneofVisitor<'de, 'o> {
+    type Error = error::CompatError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        let key = match self.keys.next() {
+            Some(&key) => key,
+            None => return Ok(None),
+        };
+        self.key = Some(key);
+        let name = match (key, &self.options.oneofs) {
+            (OneofKey::Tag, OneofRepresentation::AdjacentlyTagged { tag, .. }) => tag.as_str(),
+            (OneofKey::Content, OneofRepresentation::AdjacentlyTagged { content, .. }) => {
+                content.as_str()
+            }
+            _ => self.field.name(),
+        };
+        seed.deserialize(str_deserializer(name)).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        match self.key.take() {
+            Some(OneofKey::Tag) => seed.deserialize(str_deserializer(self.field.name())),
+            Some(_) => {
+                let value = self.value.take().ok_or(error::Error::EndOfStream)?;
+                seed.deserialize(ValueDeserializer::new(
+                    self.descriptors,
+                    self.field,
+                    self.options,
+                    value,
+                ))
+            }
+            None => panic!("visit_value was called before visit_key"),
+        }
+    }
+}
+
 impl<'de> MessageKeyDeserializer<'de> {
     #[inline]
-    fn new(descriptor: &'de descriptor::FieldDescriptor) -> MessageKeyDeserializer<'de> {
-        MessageKeyDeserializer { descriptor }
+    fn new(
+        descriptor: &'de descriptor::FieldDescriptor,
+        number: bool,
+    ) -> MessageKeyDeserializer<'de> {
+        MessageKeyDeserializer { descriptor, number }
     }
 }
 
@@ -254,33 +844,92 @@ impl<'de> serde::Deserializer<'de> for MessageKeyDeserializer<'de> {
     where
         V: serde::de::Visitor<'de>,
     {
-        visitor.visit_str(self.descriptor.name())
+        if self.number {
+            visitor.visit_string(self.descriptor.number().to_string())
+        } else {
+            visitor.visit_str(self.descriptor.name())
+        }
     }
 }
 
-impl<'de> MessageFieldDeserializer<'de> {
+impl<'de, 'o> MessageFieldDeserializer<'de, 'o> {
     #[inline]
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: &'o Options,
         field: value::Field,
-    ) -> MessageFieldDeserializer<'de> {
+    ) -> MessageFieldDeserializer<'de, 'o> {
+        // A cleared field holds no value, just like an absent one
+        let field = match field {
+            value::Field::Singular(Some(value::Value::Null)) => value::Field::Singular(None),
+            field => field,
+        };
         let field = Some(field);
         MessageFieldDeserializer {
             descriptors,
             descriptor,
+            options,
             field,
         }
     }
 }
 
-impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
+impl<'de, 'o> serde::Deserializer<'de> for MessageFieldDeserializer<'de, 'o> {
     type Error = error::CompatError;
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
         byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
+        tuple_struct struct identifier ignored_any
+    }
+
+    /// Singular enum fields are visited as unit variants, whether they are optional or not.
+    fn deserialize_enum<V>(
+        mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.field.take() {
+            Some(value::Field::Singular(Some(v))) => {
+                ValueDeserializer::new(self.descriptors, self.descriptor, self.options, v)
+                    .deserialize_enum(name, variants, visitor)
+            }
+            field => {
+                self.field = field;
+                self.deserialize_any(visitor)
+            }
+        }
+    }
+
+    /// Map fields are visited as sequences of entry messages, unless a map is asked for.
+    fn deserialize_map<V>(mut self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match (
+            self.descriptor.field_type(self.descriptors),
+            self.field.take(),
+        ) {
+            (descriptor::FieldType::Message(entry), Some(value::Field::Repeated(vs)))
+                if entry.is_map_entry() =>
+            {
+                visitor.visit_map(MapEntryVisitor::new(
+                    self.descriptors,
+                    entry,
+                    self.options,
+                    vs.into_iter(),
+                ))
+            }
+            (_, field) => {
+                self.field = field;
+                self.deserialize_any(visitor)
+            }
+        }
     }
 
     #[inline]
@@ -290,7 +939,14 @@ impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
     {
         let ds = self.descriptors;
         let d = self.descriptor;
-        match self.field.take() {
+        let o = self.options;
+        let field = match self.field.take() {
+            Some(value::Field::Singular(None)) if o.emit_defaults && !d.is_proto3_optional() => {
+                Some(value::Field::Singular(zero_value(ds, d)))
+            }
+            field => field,
+        };
+        match field {
             Some(value::Field::Singular(None)) => {
                 if d.field_label() == descriptor::FieldLabel::Optional {
                     visitor.visit_none()
@@ -300,35 +956,41 @@ impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
             }
             Some(value::Field::Singular(Some(v))) => {
                 if d.field_label() == descriptor::FieldLabel::Optional {
-                    visitor.visit_some(ValueDeserializer::new(ds, d, v))
+                    visitor.visit_some(ValueDeserializer::new(ds, d, o, v))
                 } else {
-                    visit_value(ds, d, v, visitor)
+                    visit_value(ds, d, o, v, visitor)
                 }
             }
             Some(value::Field::Repeated(vs)) => {
-                visitor.visit_seq(&mut RepeatedValueVisitor::new(ds, d, vs.into_iter()))
+                visitor.visit_seq(&mut RepeatedValueVisitor::new(ds, d, o, vs.into_iter()))
+            }
+            Some(value::Field::RepeatedTyped(vs)) => {
+                let vs = vs.iter().collect::<Vec<_>>();
+                visitor.visit_seq(&mut RepeatedValueVisitor::new(ds, d, o, vs.into_iter()))
             }
             None => Err(error::Error::EndOfStream.into()),
         }
     }
 }
 
-impl<'de> RepeatedValueVisitor<'de> {
+impl<'de, 'o> RepeatedValueVisitor<'de, 'o> {
     #[inline]
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: &'o Options,
         values: vec::IntoIter<value::Value>,
-    ) -> RepeatedValueVisitor<'de> {
+    ) -> RepeatedValueVisitor<'de, 'o> {
         RepeatedValueVisitor {
             descriptors,
             descriptor,
+            options,
             values,
         }
     }
 }
 
-impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
+impl<'de, 'o> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de, 'o> {
     type Error = error::CompatError;
 
     #[inline]
@@ -338,8 +1000,9 @@ impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
     {
         let ds = self.descriptors;
         let d = self.descriptor;
+        let o = self.options;
         match self.values.next() {
-            Some(v) => Ok(Some(seed.deserialize(ValueDeserializer::new(ds, d, v))?)),
+            Some(v) => Ok(Some(seed.deserialize(ValueDeserializer::new(ds, d, o, v))?)),
             None => Ok(None),
         }
     }
@@ -350,29 +1013,148 @@ impl<'de> serde::de::SeqAccess<'de> for RepeatedValueVisitor<'de> {
     }
 }
 
-impl<'de> ValueDeserializer<'de> {
+impl<'de, 'o> MapEntryVisitor<'de, 'o> {
+    #[inline]
+    fn new(
+        descriptors: &'de descriptor::Descriptors,
+        entry: &'de descriptor::MessageDescriptor,
+        options: &'o Options,
+        entries: vec::IntoIter<value::Value>,
+    ) -> MapEntryVisitor<'de, 'o> {
+        MapEntryVisitor {
+            descriptors,
+            entry,
+            options,
+            entries,
+            value: None,
+        }
+    }
+
+    /// The key (1) or value (2) field of an entry, and its value or else the zero value.
+    fn entry_field(
+        &self,
+        entry: &mut value::Message,
+        number: i32,
+    ) -> error::Result<(&'de descriptor::FieldDescriptor, value::Value)> {
+        let field = self
+            .entry
+            .field_by_number(number)
+            .ok_or(error::Error::UnknownFieldNumber {
+                number: number as u32,
+            })?;
+        let value = match entry.fields.remove(&number) {
+            Some(value::Field::Singular(Some(v))) => Some(v),
+            _ => match field.field_type(self.descriptors) {
+                descriptor::FieldType::Message(m) => {
+                    Some(value::Value::Message(Arc::new(value::Message::new(m))))
+                }
+                _ => zero_value(self.descriptors, field),
+            },
+        };
+        let value = value.ok_or_else(|| error::Error::BadFieldValue {
+            field: field.name().to_owned(),
+        })?;
+        Ok((field, value))
+    }
+}
+
+impl<'de, 'o> serde::de::MapAccess<'de> for MapEntryVisitor<'de, 'o> {
+    type Error = error::CompatError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> error::CompatResult<Option<K::Value>>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        let mut entry = match self.entries.next() {
+            Some(value::Value::Message(m)) => Arc::unwrap_or_clone(m),
+            Some(_) => {
+                return Err(error::Error::BadFieldValue {
+                    field: self.entry.name().to_owned(),
+                }
+                .into())
+            }
+            None => return Ok(None),
+        };
+        let (key_field, key) = self.entry_field(&mut entry, 1)?;
+        self.value = Some(self.entry_field(&mut entry, 2)?);
+        let key = ValueDeserializer::new(self.descriptors, key_field, self.options, key);
+        Ok(Some(seed.deserialize(key)?))
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let (field, value) = self
+            .value
+            .take()
+            .expect("visit_value was called before visit_key");
+        seed.deserialize(ValueDeserializer::new(
+            self.descriptors,
+            field,
+            self.options,
+            value,
+        ))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> Option<usize> {
+        self.entries.size_hint().1
+    }
+}
+
+impl<'de, 'o> ValueDeserializer<'de, 'o> {
     #[inline]
     fn new(
         descriptors: &'de descriptor::Descriptors,
         descriptor: &'de descriptor::FieldDescriptor,
+        options: &'o Options,
         value: value::Value,
-    ) -> ValueDeserializer<'de> {
+    ) -> ValueDeserializer<'de, 'o> {
         let value = Some(value);
         ValueDeserializer {
             descriptors,
             descriptor,
+            options,
             value,
         }
     }
 }
 
-impl<'de> serde::Deserializer<'de> for ValueDeserializer<'de> {
+impl<'de, 'o> serde::Deserializer<'de> for ValueDeserializer<'de, 'o> {
     type Error = error::CompatError;
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
         byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
+        tuple_struct map struct identifier ignored_any
+    }
+
+    /// Enum values are visited as unit variants named by the enum value name.
+    fn deserialize_enum<V>(
+        mut self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let (ds, d, o) = (self.descriptors, self.descriptor, self.options);
+        match self.value.take() {
+            Some(value::Value::Enum(e)) => match represent(ds, d, o, value::Value::Enum(e))? {
+                value::Value::Enum(e) => match enum_identifier(ds, d, o, e)? {
+                    EnumIdentifier::Name(name) => visitor.visit_enum(name.into_deserializer()),
+                    EnumIdentifier::Number(n) => visitor.visit_enum((n as u32).into_deserializer()),
+                },
+                value::Value::String(s) => visitor.visit_enum(string_deserializer(s)),
+                value => visit_value_as_is(ds, d, o, value, visitor),
+            },
+            value => {
+                self.value = value;
+                self.deserialize_any(visitor)
+            }
+        }
     }
 
     #[inline]
@@ -381,16 +1163,71 @@ impl<'de> serde::Deserializer<'de> for ValueDeserializer<'de> {
         V: serde::de::Visitor<'de>,
     {
         match self.value.take() {
-            Some(value) => visit_value(self.descriptors, self.descriptor, value, visitor),
+            Some(value) => visit_value(
+                self.descriptors,
+                self.descriptor,
+                self.options,
+                value,
+                visitor,
+            ),
             None => Err(error::Error::EndOfStream.into()),
         }
     }
 }
 
+impl<'de, 'o> serde::de::VariantAccess<'de> for ValueDeserializer<'de, 'o> {
+    type Error = error::CompatError;
+
+    fn unit_variant(self) -> error::CompatResult<()> {
+        serde::de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> error::CompatResult<T::Value>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        serde::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        serde::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
 #[inline]
-fn visit_value<'de, V>(
+fn visit_value<'de, 'o, V>(
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::FieldDescriptor,
+    options: &'o Options,
+    value: value::Value,
+    visitor: V,
+) -> error::CompatResult<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    let value = represent(descriptors, descriptor, options, value)?;
+    visit_value_as_is(descriptors, descriptor, options, value, visitor)
+}
+
+/// Visits a value without applying scalar representations.
+fn visit_value_as_is<'de, 'o, V>(
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::FieldDescriptor,
+    options: &'o Options,
     value: value::Value,
     visitor: V,
 ) -> error::CompatResult<V::Value>
@@ -405,21 +1242,148 @@ where
         value::Value::U64(v) => visitor.visit_u64(v),
         value::Value::F32(v) => visitor.visit_f32(v),
         value::Value::F64(v) => visitor.visit_f64(v),
-        value::Value::Bytes(v) => visitor.visit_byte_buf(v),
+        value::Value::Bytes(v) => {
+            #[cfg(feature = "uuid")]
+            {
+                if let Some(ref rule) = options.uuid_rule {
+                    if rule.matches(descriptor) {
+                        if let Ok(uuid) = uuid::Uuid::from_slice(&v) {
+                            return visitor.visit_string(uuid.hyphenated().to_string());
+                        }
+                    }
+                }
+            }
+            visitor.visit_byte_buf(v)
+        }
         value::Value::String(v) => visitor.visit_string(v),
+        value::Value::BytesStream(s) => visitor.visit_byte_buf(s.read_to_vec()?),
         value::Value::Message(m) => {
+            let mut m = Arc::unwrap_or_clone(m);
             if let descriptor::FieldType::Message(d) = descriptor.field_type(descriptors) {
-                visitor.visit_map(MessageVisitor::new(descriptors, d, m))
+                if options.flatten_wrappers && well_known::WRAPPERS.contains(&d.name()) {
+                    if let Some(field) = d.field_by_number(1) {
+                        let value = match m.fields.remove(&1) {
+                            Some(value::Field::Singular(Some(v))) => Some(v),
+                            _ => zero_value(descriptors, field),
+                        };
+                        if let Some(v) = value {
+                            return visit_value(descriptors, field, options, v, visitor);
+                        }
+                    }
+                }
+                if options.google_types {
+                    if let Some(s) = google_type::format_message(d.name(), &m) {
+                        return visitor.visit_string(s?);
+                    }
+                }
+                visitor.visit_map(MessageVisitor::new(descriptors, d, options, m))
             } else {
                 panic!("A field with a message value doesn't have a message type!")
             }
         }
-        value::Value::Enum(e) => {
-            if let descriptor::FieldType::Enum(d) = descriptor.field_type(descriptors) {
-                visitor.visit_str(d.value_by_number(e).unwrap().name())
-            } else {
-                panic!("A field with an enum value doesn't have an enum type!")
-            }
+        value::Value::FieldMask(m) => {
+            visitor.visit_seq(serde::de::value::SeqDeserializer::new(m.paths.into_iter()))
+        }
+        value::Value::Enum(e) => match enum_identifier(descriptors, descriptor, options, e)? {
+            EnumIdentifier::Name(name) => visitor.visit_str(name),
+            EnumIdentifier::Number(n) => visitor.visit_i32(n),
+        },
+        value::Value::Null => visitor.visit_none(),
+        // The deserializer doesn't use type handlers, so this doesn't happen while decoding
+        value::Value::Custom(_) => Err(error::Error::BadFieldValue {
+            field: descriptor.name().to_owned(),
         }
+        .into()),
+    }
+}
+
+fn str_deserializer(s: &str) -> serde::de::value::StrDeserializer<'_, error::CompatError> {
+    s.into_deserializer()
+}
+
+fn string_deserializer(s: String) -> serde::de::value::StringDeserializer<error::CompatError> {
+    s.into_deserializer()
+}
+
+/// The representation of a scalar or enum value, if a scalar representation applies to its field.
+fn represent(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+    options: &Options,
+    value: value::Value,
+) -> error::Result<value::Value> {
+    if options.scalar_representations.is_empty() {
+        return Ok(value);
+    }
+    let field_type = descriptor.field_type(descriptors);
+    match options.scalar_representation_for(descriptor, &field_type) {
+        Some(r) => r.represent(descriptor, &field_type, value),
+        None => Ok(value),
+    }
+}
+
+fn enum_identifier<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::FieldDescriptor,
+    options: &'a Options,
+    value: i32,
+) -> error::Result<EnumIdentifier<'a>> {
+    let d = match descriptor.field_type(descriptors) {
+        descriptor::FieldType::Enum(d) => d,
+        _ => panic!("A field with an enum value doesn't have an enum type!"),
+    };
+    match (d.value_by_number(value), &options.unknown_enum_values) {
+        (Some(v), _) => Ok(EnumIdentifier::Name(v.name())),
+        (None, UnknownEnumValues::Variant(name)) => Ok(EnumIdentifier::Name(name)),
+        (None, UnknownEnumValues::Number) => Ok(EnumIdentifier::Number(value)),
+        (None, UnknownEnumValues::Error) => Err(error::Error::UnknownEnumValue { value }),
+    }
+}
+
+/// Deserializes a message that has already been decoded.
+pub(crate) fn from_message<T>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    options: &Options,
+    mut message: value::Message,
+) -> error::Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    options
+        .time_policy
+        .apply(descriptors, descriptor, &mut message)?;
+    T::deserialize(DecodedMessageDeserializer {
+        descriptors,
+        descriptor,
+        options,
+        message,
+    })
+    .map_err(error::CompatError::into_error)
+}
+
+/// The value that an absent field is deserialized as when emitting defaults.
+pub(crate) fn zero_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::FieldDescriptor,
+) -> Option<value::Value> {
+    use crate::descriptor::FieldType::*;
+
+    if let Some(v) = descriptor.default_value() {
+        return Some(v.clone());
+    }
+
+    match descriptor.field_type(descriptors) {
+        Bool => Some(value::Value::Bool(false)),
+        Int32 | SInt32 | SFixed32 => Some(value::Value::I32(0)),
+        Int64 | SInt64 | SFixed64 => Some(value::Value::I64(0)),
+        UInt32 | Fixed32 => Some(value::Value::U32(0)),
+        UInt64 | Fixed64 => Some(value::Value::U64(0)),
+        Float => Some(value::Value::F32(0.0)),
+        Double => Some(value::Value::F64(0.0)),
+        Bytes => Some(value::Value::Bytes(Vec::new())),
+        String => Some(value::Value::String(std::string::String::new())),
+        Enum(e) => e.values().first().map(|v| value::Value::Enum(v.number())),
+        Message(_) | Group | UnresolvedEnum(_) | UnresolvedMessage(_) => None,
     }
 }
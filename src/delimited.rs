@@ -0,0 +1,374 @@
+//! Export of dynamic messages as delimited text, like CSV or TSV, for quick data dumps.
+//!
+//! A [`Table`](struct.Table.html) flattens messages of a single type into rows with one column
+//! per scalar field, named by the dotted path to it, e.g. `"author.name"`.
+//!
+//!   * Absent fields are empty cells.
+//!   * Enum values are written as their names, `bytes` as lowercase hex and
+//!     `google.protobuf.FieldMask` values as their comma separated paths.
+//!   * Singular message fields are flattened into the columns of their fields.
+//!   * Repeated fields, including repeated message fields and map fields, are handled as chosen
+//!     with [`RepeatedFields`](enum.RepeatedFields.html).
+//!   * Groups and recursive references to an enclosing message type are skipped.
+//!
+//! Cells that contain the delimiter, a quote or a line break are quoted as in RFC 4180.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::delimited::{RepeatedFields, Table};
+//! use serde_protobuf::value::{Field, Message, Value};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.ForeignMessage")
+//!     .unwrap();
+//!
+//! let mut message = Message::new(descriptor);
+//! message.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+//!
+//! let mut output = Vec::new();
+//! Table::new(&descriptors, descriptor)
+//!     .delimiter(b'\t')
+//!     .repeated_fields(RepeatedFields::Explode)
+//!     .write_all(&mut output, &[message])
+//!     .unwrap();
+//! assert_eq!("c\n5\n", String::from_utf8(output).unwrap());
+//! ```
+use std::borrow::Cow;
+use std::io;
+
+use crate::descriptor;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// A flattening of messages of one type into delimited rows.
+#[derive(Debug)]
+pub struct Table<'a> {
+    columns: Vec<Column<'a>>,
+    delimiter: u8,
+    repeated_fields: RepeatedFields,
+}
+
+/// How to flatten repeated fields into rows.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RepeatedFields {
+    /// Write a row for every element, repeating the other cells of the message.
+    ///
+    /// A message with several repeated fields gets a row for every combination of their
+    /// elements.  Empty repeated fields are a single empty cell.
+    Explode,
+    /// Join the elements into a single cell with this separator, e.g. `";"`.
+    ///
+    /// The columns of repeated message fields each join the values of their field in all of the
+    /// elements.
+    Join(String),
+    /// Write the first elements to this many columns, suffixed with their index, e.g. `"tags.0"`
+    /// and `"tags.1"`.  Further elements are left out.
+    Index(usize),
+}
+
+#[derive(Debug)]
+struct Column<'a> {
+    field: &'a descriptor::FieldDescriptor,
+    enum_type: Option<&'a descriptor::EnumDescriptor>,
+    // The columns of the fields of a message field
+    nested: Option<Vec<Column<'a>>>,
+}
+
+type Rows = Vec<Vec<String>>;
+
+impl<'a> Table<'a> {
+    /// Creates a comma separated table of messages of the specified type, that explodes repeated
+    /// fields into rows.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+    ) -> Table<'a> {
+        Table {
+            columns: message_columns(descriptors, descriptor, &mut vec![descriptor.name()]),
+            delimiter: b',',
+            repeated_fields: RepeatedFields::Explode,
+        }
+    }
+
+    /// The delimiter between cells, e.g. `b'\t'` for TSV.
+    pub fn delimiter(mut self, delimiter: u8) -> Table<'a> {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// How to flatten repeated fields.
+    pub fn repeated_fields(mut self, repeated_fields: RepeatedFields) -> Table<'a> {
+        self.repeated_fields = repeated_fields;
+        self
+    }
+
+    /// The names of the columns, which are the dotted paths to their fields.
+    pub fn columns(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        self.add_names(&self.columns, "", &mut names);
+        names
+    }
+
+    /// The rows that a message is flattened into, with a cell for every column.
+    pub fn rows(&self, message: &value::Message) -> error::Result<Vec<Vec<String>>> {
+        self.message_rows(&self.columns, Some(message))
+    }
+
+    /// Writes the header row with the column names.
+    pub fn write_header<W>(&self, output: &mut W) -> error::Result<()>
+    where
+        W: io::Write,
+    {
+        self.write_row(output, &self.columns())
+    }
+
+    /// Writes the rows of a message, returning how many rows were written.
+    pub fn write_rows<W>(&self, output: &mut W, message: &value::Message) -> error::Result<usize>
+    where
+        W: io::Write,
+    {
+        let rows = self.rows(message)?;
+        for row in &rows {
+            self.write_row(output, row)?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Writes the header row followed by the rows of all of the messages.
+    pub fn write_all<'m, W, I>(&self, output: &mut W, messages: I) -> error::Result<()>
+    where
+        W: io::Write,
+        I: IntoIterator<Item = &'m value::Message>,
+    {
+        self.write_header(output)?;
+        for message in messages {
+            self.write_rows(output, message)?;
+        }
+        Ok(())
+    }
+
+    fn add_names(&self, columns: &[Column], prefix: &str, names: &mut Vec<String>) {
+        for column in columns {
+            let name = format!("{}{}", prefix, column.field.name());
+            match self.repeated_fields {
+                RepeatedFields::Index(n) if column.field.is_repeated() => {
+                    for i in 0..n {
+                        self.add_element_names(column, format!("{}.{}", name, i), names);
+                    }
+                }
+                _ => self.add_element_names(column, name, names),
+            }
+        }
+    }
+
+    fn add_element_names(&self, column: &Column, name: String, names: &mut Vec<String>) {
+        match column.nested {
+            Some(ref nested) => self.add_names(nested, &format!("{}.", name), names),
+            None => names.push(name),
+        }
+    }
+
+    fn message_rows(
+        &self,
+        columns: &[Column],
+        message: Option<&value::Message>,
+    ) -> error::Result<Rows> {
+        let mut rows = vec![Vec::new()];
+        for column in columns {
+            let column_rows = self.column_rows(column, message)?;
+            rows = rows
+                .iter()
+                .flat_map(|row| {
+                    column_rows.iter().map(move |cells| {
+                        let mut row = row.clone();
+                        row.extend(cells.iter().cloned());
+                        row
+                    })
+                })
+                .collect();
+        }
+        Ok(rows)
+    }
+
+    fn column_rows(
+        &self,
+        column: &Column,
+        message: Option<&value::Message>,
+    ) -> error::Result<Rows> {
+        let values: Vec<Cow<value::Value>> =
+            match message.and_then(|m| m.fields.get(&column.field.number())) {
+                // A cleared field has empty cells, just like an absent one
+                Some(value::Field::Singular(Some(value::Value::Null))) | None => Vec::new(),
+                Some(value::Field::Singular(v)) => v.iter().map(Cow::Borrowed).collect(),
+                Some(value::Field::Repeated(vs)) => vs.iter().map(Cow::Borrowed).collect(),
+                Some(value::Field::RepeatedTyped(vs)) => vs.iter().map(Cow::Owned).collect(),
+            };
+        if !column.field.is_repeated() {
+            return self.element_rows(column, values.first().map(|v| &**v));
+        }
+
+        match self.repeated_fields {
+            RepeatedFields::Explode if values.is_empty() => self.element_rows(column, None),
+            RepeatedFields::Explode => {
+                let mut rows = Vec::new();
+                for value in &values {
+                    rows.extend(self.element_rows(column, Some(value))?);
+                }
+                Ok(rows)
+            }
+            RepeatedFields::Join(ref separator) => {
+                let mut elements = Vec::with_capacity(values.len());
+                for value in &values {
+                    elements.push(self.element_rows(column, Some(value))?.swap_remove(0));
+                }
+                let width = self.element_width(column);
+                let cells = (0..width)
+                    .map(|i| {
+                        elements
+                            .iter()
+                            .map(|e| e[i].as_str())
+                            .collect::<Vec<_>>()
+                            .join(separator)
+                    })
+                    .collect();
+                Ok(vec![cells])
+            }
+            RepeatedFields::Index(n) => {
+                let mut cells = Vec::new();
+                for i in 0..n {
+                    let value = values.get(i).map(|v| &**v);
+                    cells.extend(self.element_rows(column, value)?.swap_remove(0));
+                }
+                Ok(vec![cells])
+            }
+        }
+    }
+
+    /// The rows of a single value of a column; always a single row unless repeated fields are
+    /// exploded.
+    fn element_rows(&self, column: &Column, value: Option<&value::Value>) -> error::Result<Rows> {
+        match column.nested {
+            Some(ref nested) => match value {
+                None => self.message_rows(nested, None),
+                Some(value::Value::Message(m)) => self.message_rows(nested, Some(m)),
+                Some(_) => Err(bad_field_value(column.field)),
+            },
+            None => Ok(vec![vec![format_value(column, value)?]]),
+        }
+    }
+
+    /// The number of cells of a single value of a column.
+    fn element_width(&self, column: &Column) -> usize {
+        match column.nested {
+            Some(ref nested) => nested
+                .iter()
+                .map(|c| match self.repeated_fields {
+                    RepeatedFields::Index(n) if c.field.is_repeated() => n * self.element_width(c),
+                    _ => self.element_width(c),
+                })
+                .sum(),
+            None => 1,
+        }
+    }
+
+    fn write_row<W>(&self, output: &mut W, cells: &[String]) -> error::Result<()>
+    where
+        W: io::Write,
+    {
+        let delimiter = char::from(self.delimiter);
+        let mut line = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            if i > 0 {
+                line.push(delimiter);
+            }
+            if cell.contains(&[delimiter, '"', '\n', '\r'][..]) {
+                line.push('"');
+                line.push_str(&cell.replace('"', "\"\""));
+                line.push('"');
+            } else {
+                line.push_str(cell);
+            }
+        }
+        line.push('\n');
+        output.write_all(line.as_bytes()).map_err(io_error)
+    }
+}
+
+fn message_columns<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    enclosing: &mut Vec<&'a str>,
+) -> Vec<Column<'a>> {
+    let mut columns = Vec::new();
+    for field in message.fields() {
+        let (enum_type, nested) = match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) if m.name() == field_mask::FIELD_MASK => (None, None),
+            descriptor::FieldType::Message(m) => {
+                if enclosing.contains(&m.name()) {
+                    continue;
+                }
+                enclosing.push(m.name());
+                let nested = message_columns(descriptors, m, enclosing);
+                enclosing.pop();
+                (None, Some(nested))
+            }
+            descriptor::FieldType::Enum(e) => (Some(e), None),
+            descriptor::FieldType::Group
+            | descriptor::FieldType::UnresolvedEnum(_)
+            | descriptor::FieldType::UnresolvedMessage(_) => continue,
+            _ => (None, None),
+        };
+        columns.push(Column {
+            field,
+            enum_type,
+            nested,
+        });
+    }
+    columns
+}
+
+fn format_value(column: &Column, value: Option<&value::Value>) -> error::Result<String> {
+    let value = match value {
+        None => return Ok(String::new()),
+        Some(value) => value,
+    };
+    Ok(match *value {
+        value::Value::Bool(v) => v.to_string(),
+        value::Value::I32(v) => v.to_string(),
+        value::Value::I64(v) => v.to_string(),
+        value::Value::U32(v) => v.to_string(),
+        value::Value::U64(v) => v.to_string(),
+        value::Value::F32(v) => v.to_string(),
+        value::Value::F64(v) => v.to_string(),
+        value::Value::Bytes(ref v) => hex(v),
+        value::Value::BytesStream(ref s) => hex(&s.read_to_vec()?),
+        value::Value::String(ref v) => v.clone(),
+        value::Value::Enum(n) => match column.enum_type.and_then(|e| e.value_by_number(n)) {
+            Some(v) => v.name().to_owned(),
+            None => n.to_string(),
+        },
+        value::Value::FieldMask(ref m) => m.paths.join(","),
+        value::Value::Message(_) | value::Value::Custom(_) | value::Value::Null => {
+            return Err(bad_field_value(column.field))
+        }
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn bad_field_value(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
@@ -48,6 +48,36 @@
 //! # }
 //! ```
 //!
+//! Descriptor sets that are spread over a directory tree, e.g. one per service, can be merged
+//! into one registry with `Descriptors::from_dir` or a `DescriptorLoader`, which also detects
+//! conflicting definitions between the sets.  Registries can also be combined with
+//! `Descriptors::merge`, which reports the types that they define differently instead of letting
+//! one definition shadow the other.
+//!
+//! To save memory, only the packages that an application uses can be loaded from large descriptor
+//! sets with `Descriptors::from_proto_packages`, or kept with `Descriptors::retain_packages`.
+//!
+//! ## Generated code
+//!
+//! Applications that already link code generated by rust-protobuf can build descriptors from the
+//! file descriptors embedded in the generated modules, instead of shipping a descriptor set file:
+//!
+//! ```
+//! extern crate serde_protobuf;
+//! extern crate protobuf;
+//!
+//! use serde_protobuf::descriptor::Descriptors;
+//!
+//! # fn main() {
+//! // The imports of the generated files must be included as well
+//! let descriptors = Descriptors::from_generated(vec![protobuf::descriptor::file_descriptor_proto()]);
+//! assert!(descriptors.message_by_name(".google.protobuf.FileDescriptorProto").is_some());
+//! # }
+//! ```
+//!
+//! Code generators that embed an encoded file descriptor set instead, like `prost-build` with
+//! `file_descriptor_set_path`, are supported by `Descriptors::add_encoded_file_set_proto`.
+//!
 //! ## Manually built schemas
 //!
 //! A descriptor can be built at run-time by incrementally adding new message types and fields:
@@ -126,6 +156,7 @@
 //! ```
 //!
 //! [1]: https://github.com/google/protobuf/blob/master/src/google/protobuf/descriptor.proto
+use std::collections;
 use std::f32;
 use std::f64;
 
@@ -135,6 +166,30 @@ use protobuf::descriptor;
 use crate::error;
 use crate::value;
 
+mod export;
+mod filter;
+mod imports;
+mod loader;
+mod merge;
+mod shared;
+mod snapshot;
+mod source;
+
+#[cfg(feature = "serde_json")]
+mod json_schema;
+#[cfg(feature = "serde_json")]
+mod openapi;
+
+pub use self::loader::DescriptorLoader;
+pub use self::merge::TypeConflict;
+pub use self::shared::SharedDescriptors;
+pub use self::source::SourceLocation;
+
+#[cfg(feature = "serde_json")]
+pub use self::json_schema::to_json_schema;
+#[cfg(feature = "serde_json")]
+pub use self::openapi::to_openapi;
+
 /// An ID used for internal tracking of resolved message descriptors.
 ///
 /// It is not possible to construct a value of this type from outside this module.
@@ -151,38 +206,61 @@ pub struct EnumId(usize);
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct EnumValueId(usize);
 
+/// An ID used for internal tracking of services.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ServiceId(usize);
+
+/// An ID used for internal tracking of methods.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct MethodId(usize);
+
 /// An ID used for internal tracking of resolved fields.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct FieldId(usize);
 
 /// A registry for any number of protocol buffer descriptors.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Descriptors {
     // All found descriptors
     messages: Vec<MessageDescriptor>,
     enums: Vec<EnumDescriptor>,
+    services: Vec<ServiceDescriptor>,
 
     // Indices
     messages_by_name: linked_hash_map::LinkedHashMap<String, MessageId>,
     enums_by_name: linked_hash_map::LinkedHashMap<String, EnumId>,
+    services_by_name: linked_hash_map::LinkedHashMap<String, ServiceId>,
+    // The name of the file that defines each type, by the fully qualified name of the type
+    files_by_type: collections::BTreeMap<String, String>,
+    // The syntax that each file declares, by file name
+    syntax_by_file: collections::BTreeMap<String, String>,
+    // Source locations from source code info, by the fully qualified name of the definition
+    source_locations: collections::BTreeMap<String, SourceLocation>,
 }
 
 /// A descriptor for a single protocol buffer message type.
-// TODO: Support oneof?
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct MessageDescriptor {
     name: String,
+    options: descriptor::MessageOptions,
 
     // All found descriptors
     fields: Vec<FieldDescriptor>,
+    oneofs: Vec<OneofDescriptor>,
 
     // Indices
     fields_by_name: linked_hash_map::LinkedHashMap<String, FieldId>,
     fields_by_number: linked_hash_map::LinkedHashMap<i32, FieldId>,
 }
 
+/// A descriptor for a single `oneof` of a message type.
+#[derive(Clone, Debug)]
+pub struct OneofDescriptor {
+    name: String,
+}
+
 /// A descriptor for a single protocol buffer enum type.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct EnumDescriptor {
     name: String,
 
@@ -194,8 +272,32 @@ pub struct EnumDescriptor {
     values_by_number: linked_hash_map::LinkedHashMap<i32, EnumValueId>,
 }
 
+/// A descriptor for a single protocol buffer service.
+#[derive(Clone, Debug)]
+pub struct ServiceDescriptor {
+    name: String,
+    options: descriptor::ServiceOptions,
+
+    // All found descriptors
+    methods: Vec<MethodDescriptor>,
+
+    // Indices
+    methods_by_name: linked_hash_map::LinkedHashMap<String, MethodId>,
+}
+
+/// A descriptor for a single protocol buffer service method.
+#[derive(Clone, Debug)]
+pub struct MethodDescriptor {
+    name: String,
+    input_type: String,
+    output_type: String,
+    client_streaming: bool,
+    server_streaming: bool,
+    options: descriptor::MethodOptions,
+}
+
 /// A descriptor for a single protocol buffer enum value.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct EnumValueDescriptor {
     name: String,
     number: i32,
@@ -263,7 +365,7 @@ pub enum FieldType<'a> {
 ///
 /// The type owns all of its data, and can refer to an internally tracked ID for resolved type
 /// references.  It's by design not possible to construct those IDs from outside this module.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum InternalFieldType {
     /// A message that is yet to be resolved.
     UnresolvedMessage(String),
@@ -308,13 +410,19 @@ pub enum InternalFieldType {
 }
 
 /// A descriptor for a single protocol buffer message field.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FieldDescriptor {
     name: String,
     number: i32,
     field_label: FieldLabel,
     field_type: InternalFieldType,
     default_value: Option<value::Value>,
+    json_name: String,
+    options: descriptor::FieldOptions,
+    oneof_index: Option<usize>,
+    proto3_optional: bool,
+    packed: bool,
+    implicit_presence: bool,
 }
 
 impl Descriptors {
@@ -323,9 +431,14 @@ impl Descriptors {
         Descriptors {
             messages: Vec::new(),
             enums: Vec::new(),
+            services: Vec::new(),
 
             messages_by_name: linked_hash_map::LinkedHashMap::new(),
             enums_by_name: linked_hash_map::LinkedHashMap::new(),
+            services_by_name: linked_hash_map::LinkedHashMap::new(),
+            files_by_type: collections::BTreeMap::new(),
+            syntax_by_file: collections::BTreeMap::new(),
+            source_locations: collections::BTreeMap::new(),
         }
     }
 
@@ -336,28 +449,139 @@ impl Descriptors {
         descriptors
     }
 
+    /// Builds a descriptor set from the file descriptors embedded in generated code, e.g. the
+    /// `file_descriptor_proto()` functions of modules generated by rust-protobuf.
+    ///
+    /// The files that the generated files import must be included as well.  Files with the same
+    /// name are only added once, so that shared imports can be passed for every generated file.
+    pub fn from_generated<'a, I>(file_protos: I) -> Descriptors
+    where
+        I: IntoIterator<Item = &'a descriptor::FileDescriptorProto>,
+    {
+        let mut descriptors = Descriptors::new();
+        let mut names = collections::HashSet::new();
+        for file_proto in file_protos {
+            if names.insert(file_proto.get_name()) {
+                descriptors.add_file_proto(file_proto);
+            }
+        }
+        descriptors
+    }
+
     /// Looks up a message by its fully qualified name (i.e. `.foo.package.Message`).
     #[inline]
     pub fn message_by_name(&self, name: &str) -> Option<&MessageDescriptor> {
         self.messages_by_name.get(name).map(|m| &self.messages[m.0])
     }
 
+    /// Looks up a message by a `google.protobuf.Any` type URL, e.g.
+    /// `type.googleapis.com/foo.package.Message`.
+    ///
+    /// Only the part after the last `/` is used, so URLs with custom domains or path prefixes
+    /// work as well; a bare type name, with or without the leading `.`, is also accepted.
+    pub fn message_by_type_url(&self, type_url: &str) -> Option<&MessageDescriptor> {
+        let name = match type_url.rfind('/') {
+            Some(i) => &type_url[i + 1..],
+            None => type_url,
+        };
+        if name.starts_with('.') {
+            self.message_by_name(name)
+        } else {
+            self.message_by_name(&format!(".{}", name))
+        }
+    }
+
+    /// Decodes the bytes as a message of the type with the specified fully qualified name, with
+    /// or without the leading `.`.
+    ///
+    /// If there is no such message type, the `UnknownMessageType` error lists the names of the
+    /// message types that there are.
+    pub fn decode(&self, name: &str, bytes: &[u8]) -> error::Result<value::Message> {
+        let descriptor =
+            self.message_by_type_url(name)
+                .ok_or_else(|| error::Error::UnknownMessageType {
+                    name: name.to_owned(),
+                    available: self.messages.iter().map(|m| m.name().to_owned()).collect(),
+                })?;
+        let mut message = value::Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message.merge_from(self, descriptor, &mut input)?;
+        Ok(message)
+    }
+
+    /// The name of the file that defines the type with the specified fully qualified name, if
+    /// the type was added from a file descriptor.
+    pub fn file_of_type(&self, name: &str) -> Option<&str> {
+        self.files_by_type.get(name).map(String::as_str)
+    }
+
+    /// The syntax that the file with the specified name declares, like `proto3`, if types were
+    /// added from it and it declares one.
+    pub fn syntax_of_file(&self, name: &str) -> Option<&str> {
+        self.syntax_by_file.get(name).map(String::as_str)
+    }
+
     /// Looks up an enum by its fully qualified name (i.e. `.foo.package.Enum`).
     #[inline]
     pub fn enum_by_name(&self, name: &str) -> Option<&EnumDescriptor> {
         self.enums_by_name.get(name).map(|e| &self.enums[e.0])
     }
 
+    /// Looks up a service by its fully qualified name (i.e. `.foo.package.Service`).
+    #[inline]
+    pub fn service_by_name(&self, name: &str) -> Option<&ServiceDescriptor> {
+        self.services_by_name.get(name).map(|s| &self.services[s.0])
+    }
+
+    /// All of the messages in the registry, including nested messages and map entries.
+    pub fn messages(&self) -> &[MessageDescriptor] {
+        &self.messages
+    }
+
+    /// All of the enums in the registry, including nested enums.
+    pub fn enums(&self) -> &[EnumDescriptor] {
+        &self.enums
+    }
+
+    /// All of the services in the registry.
+    pub fn services(&self) -> &[ServiceDescriptor] {
+        &self.services
+    }
+
+    /// The names of the files that types in the registry were added from, in sorted order.
+    pub fn files(&self) -> Vec<&str> {
+        let files = self
+            .files_by_type
+            .values()
+            .map(String::as_str)
+            .collect::<collections::BTreeSet<_>>();
+        files.into_iter().collect()
+    }
+
     /// Adds all types defined in the specified protocol buffer file descriptor set to this
     /// registry.
+    ///
+    /// Type names that are relative to the scope of the referring message, rather than fully
+    /// qualified, are resolved among the types of the files that are visible through imports.
     pub fn add_file_set_proto(&mut self, file_set_proto: &descriptor::FileDescriptorSet) {
-        for file_proto in file_set_proto.get_file().iter() {
-            self.add_file_proto(file_proto);
+        let files = file_set_proto.get_file().iter().collect::<Vec<_>>();
+        for file_proto in imports::qualify_type_names(&files) {
+            self.add_file_proto(&file_proto);
         }
     }
 
+    /// Adds all types defined in the encoded file descriptor set to this registry, e.g. one that
+    /// is embedded in a binary with `include_bytes!`.
+    pub fn add_encoded_file_set_proto(&mut self, bytes: &[u8]) -> error::Result<()> {
+        let file_set_proto: descriptor::FileDescriptorSet =
+            protobuf::Message::parse_from_bytes(bytes)?;
+        self.add_file_set_proto(&file_set_proto);
+        Ok(())
+    }
+
     /// Adds all types defined in the specified protocol buffer file descriptor to this registry.
     pub fn add_file_proto(&mut self, file_proto: &descriptor::FileDescriptorProto) {
+        let counts = (self.messages.len(), self.enums.len(), self.services.len());
         let path = if file_proto.has_package() {
             format!(".{}", file_proto.get_package())
         } else {
@@ -371,6 +595,43 @@ impl Descriptors {
         for enum_proto in file_proto.get_enum_type().iter() {
             self.add_enum(EnumDescriptor::from_proto(&path, enum_proto));
         }
+
+        for service_proto in file_proto.get_service().iter() {
+            self.add_service(ServiceDescriptor::from_proto(&path, service_proto));
+        }
+
+        // Repeated scalar fields are packed by default in proto3, and plain singular fields
+        // don't track presence
+        if file_proto.get_syntax() == "proto3" {
+            for message in &mut self.messages[counts.0..] {
+                for field in &mut message.fields {
+                    if !field.options.has_packed() {
+                        field.packed = true;
+                    }
+                    field.implicit_presence = true;
+                }
+            }
+        }
+
+        if file_proto.has_name() {
+            let names = self.messages[counts.0..]
+                .iter()
+                .map(|m| &m.name)
+                .chain(self.enums[counts.1..].iter().map(|e| &e.name))
+                .chain(self.services[counts.2..].iter().map(|s| &s.name));
+            for name in names {
+                self.files_by_type
+                    .insert(name.clone(), file_proto.get_name().to_owned());
+            }
+            if file_proto.has_syntax() {
+                self.syntax_by_file.insert(
+                    file_proto.get_name().to_owned(),
+                    file_proto.get_syntax().to_owned(),
+                );
+            }
+        }
+
+        self.add_source_locations(file_proto);
     }
 
     /// Adds a message and all nested types within that message from the specified protocol buffer
@@ -406,15 +667,48 @@ impl Descriptors {
         self.enums_by_name.insert(name, enum_id);
     }
 
+    /// Adds a single custom built service descriptor.
+    pub fn add_service(&mut self, descriptor: ServiceDescriptor) {
+        let name = descriptor.name.clone();
+        let service_id = ServiceId(store(&mut self.services, descriptor));
+        self.services_by_name.insert(name, service_id);
+    }
+
+    /// The names of the message and enum types that fields refer to but that aren't in the
+    /// registry (yet), sorted and without duplicates.
+    ///
+    /// Fields of such types can't be decoded until the files that define the types are added.
+    pub fn unresolved_refs(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .messages
+            .iter()
+            .flat_map(|m| m.fields.iter())
+            .filter(|f| !f.is_weak())
+            .filter_map(|f| match f.field_type(self) {
+                FieldType::UnresolvedMessage(name) | FieldType::UnresolvedEnum(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
     /// Resolves all internal descriptor type references, making them cheaper to follow.
+    ///
+    /// References to types that aren't in the registry are left pending; they are still looked
+    /// up by name, and this can be called again to resolve them once more descriptors were added.
     pub fn resolve_refs(&mut self) {
         for m in &mut self.messages {
             for f in &mut m.fields {
+                let weak = f.options.get_weak();
                 let field_type = &mut f.field_type;
                 let new = match *field_type {
                     InternalFieldType::UnresolvedMessage(ref name) => {
                         if let Some(res) = self.messages_by_name.get(name) {
                             Some(InternalFieldType::Message(*res))
+                        } else if weak {
+                            None
                         } else {
                             warn!("Inconsistent schema; unknown message type {}", name);
                             None
@@ -447,16 +741,29 @@ impl MessageDescriptor {
     {
         MessageDescriptor {
             name: name.into(),
+            options: descriptor::MessageOptions::new(),
             fields: Vec::new(),
+            oneofs: Vec::new(),
             fields_by_name: linked_hash_map::LinkedHashMap::new(),
             fields_by_number: linked_hash_map::LinkedHashMap::new(),
         }
     }
 
+    /// Sets the options of this message.
+    pub fn with_options(mut self, options: descriptor::MessageOptions) -> MessageDescriptor {
+        self.options = options;
+        self
+    }
+
     /// Reads a message descriptor from a parsed Protobuf descriptor.
     pub fn from_proto(path: &str, proto: &descriptor::DescriptorProto) -> MessageDescriptor {
         let name = format!("{}.{}", path, proto.get_name());
-        let mut message_descriptor = MessageDescriptor::new(name);
+        let mut message_descriptor =
+            MessageDescriptor::new(name).with_options(proto.get_options().clone());
+
+        for oneof_proto in proto.get_oneof_decl().iter() {
+            message_descriptor.add_oneof(OneofDescriptor::new(oneof_proto.get_name()));
+        }
 
         for field_proto in proto.get_field().iter() {
             message_descriptor.add_field(FieldDescriptor::from_proto(field_proto));
@@ -470,18 +777,60 @@ impl MessageDescriptor {
         &self.fields
     }
 
+    /// All of the oneofs in the descriptor, including the synthetic oneofs of proto3 `optional`
+    /// fields.
+    pub fn oneofs(&self) -> &[OneofDescriptor] {
+        &self.oneofs
+    }
+
+    /// The fields that are part of the oneof with the specified index.
+    pub fn oneof_fields(&self, index: usize) -> impl Iterator<Item = &FieldDescriptor> {
+        self.fields
+            .iter()
+            .filter(move |f| f.oneof_index == Some(index))
+    }
+
+    /// Whether the oneof with the specified index is the synthetic oneof of a proto3 `optional`
+    /// field, rather than one declared in the schema.
+    pub fn is_synthetic_oneof(&self, index: usize) -> bool {
+        self.oneof_fields(index).any(|f| f.proto3_optional)
+    }
+
+    /// The options of the message.
+    #[inline]
+    pub fn options(&self) -> &descriptor::MessageOptions {
+        &self.options
+    }
+
+    /// Whether this message is the synthesized entry type of a `map` field.
+    #[inline]
+    pub fn is_map_entry(&self) -> bool {
+        self.options.get_map_entry()
+    }
+
     /// The name of the message.
     #[inline]
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// The type URL of the message for use in `google.protobuf.Any`, e.g.
+    /// `type.googleapis.com/foo.package.Message`.
+    pub fn type_url(&self) -> String {
+        format!("type.googleapis.com/{}", self.name.trim_start_matches('.'))
+    }
+
     /// Finds a field by field name.
     #[inline]
     pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
         self.fields_by_name.get(name).map(|f| &self.fields[f.0])
     }
 
+    /// Finds a field by its name in the JSON mapping.
+    pub fn field_by_json_name(&self, json_name: &str) -> Option<&FieldDescriptor> {
+        self.fields.iter().find(|f| f.json_name == json_name)
+    }
+
     /// Finds a field by field number.
     #[inline]
     pub fn field_by_number(&self, number: i32) -> Option<&FieldDescriptor> {
@@ -500,6 +849,27 @@ impl MessageDescriptor {
         self.fields_by_name.insert(name, field_id);
         self.fields_by_number.insert(number, field_id);
     }
+
+    /// Adds a new oneof to the descriptor; its index is the number of oneofs added before it.
+    pub fn add_oneof(&mut self, descriptor: OneofDescriptor) {
+        self.oneofs.push(descriptor);
+    }
+}
+
+impl OneofDescriptor {
+    /// Creates a new oneof descriptor with the specified name.
+    pub fn new<S>(name: S) -> OneofDescriptor
+    where
+        S: Into<String>,
+    {
+        OneofDescriptor { name: name.into() }
+    }
+
+    /// The name of the oneof.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 impl EnumDescriptor {
@@ -535,6 +905,11 @@ impl EnumDescriptor {
         &self.name
     }
 
+    /// All of the values in the enum, in declaration order.
+    pub fn values(&self) -> &[EnumValueDescriptor] {
+        &self.values
+    }
+
     /// Adds an enum value to the enum.
     pub fn add_value(&mut self, descriptor: EnumValueDescriptor) {
         let name = descriptor.name.clone();
@@ -608,6 +983,34 @@ impl FieldLabel {
     }
 }
 
+impl<'a> FieldType<'a> {
+    /// The name of the type as it is written in a `.proto` file, or the fully qualified name of a
+    /// message or enum type.
+    pub fn name(&self) -> &'a str {
+        match *self {
+            FieldType::UnresolvedMessage(n) | FieldType::UnresolvedEnum(n) => n,
+            FieldType::Message(m) => m.name(),
+            FieldType::Enum(e) => e.name(),
+            FieldType::Double => "double",
+            FieldType::Float => "float",
+            FieldType::Int64 => "int64",
+            FieldType::UInt64 => "uint64",
+            FieldType::Int32 => "int32",
+            FieldType::Fixed64 => "fixed64",
+            FieldType::Fixed32 => "fixed32",
+            FieldType::Bool => "bool",
+            FieldType::String => "string",
+            FieldType::Group => "group",
+            FieldType::Bytes => "bytes",
+            FieldType::UInt32 => "uint32",
+            FieldType::SFixed32 => "sfixed32",
+            FieldType::SFixed64 => "sfixed64",
+            FieldType::SInt32 => "sint32",
+            FieldType::SInt64 => "sint64",
+        }
+    }
+}
+
 impl InternalFieldType {
     /// Converts a proto field type into a native field type.
     pub fn from_proto(
@@ -637,6 +1040,29 @@ impl InternalFieldType {
         }
     }
 
+    /// Whether repeated fields of this type can be packed, i.e. whether it is a scalar type other
+    /// than `string` and `bytes`, or an enum type.
+    fn is_packable(&self) -> bool {
+        !matches!(
+            *self,
+            InternalFieldType::UnresolvedMessage(_)
+                | InternalFieldType::Message(_)
+                | InternalFieldType::Group
+                | InternalFieldType::String
+                | InternalFieldType::Bytes
+        )
+    }
+
+    /// Whether this is a message or group type, resolved or not.
+    fn is_message(&self) -> bool {
+        matches!(
+            *self,
+            InternalFieldType::UnresolvedMessage(_)
+                | InternalFieldType::Message(_)
+                | InternalFieldType::Group
+        )
+    }
+
     #[inline]
     fn resolve<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
         match *self {
@@ -676,6 +1102,163 @@ impl InternalFieldType {
     }
 }
 
+impl ServiceDescriptor {
+    /// Creates a new service descriptor with the specified service name.
+    pub fn new<S>(name: S) -> ServiceDescriptor
+    where
+        S: Into<String>,
+    {
+        ServiceDescriptor {
+            name: name.into(),
+            options: descriptor::ServiceOptions::new(),
+            methods: Vec::new(),
+            methods_by_name: linked_hash_map::LinkedHashMap::new(),
+        }
+    }
+
+    /// Sets the options of this service.
+    pub fn with_options(mut self, options: descriptor::ServiceOptions) -> ServiceDescriptor {
+        self.options = options;
+        self
+    }
+
+    /// Reads a service descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(path: &str, proto: &descriptor::ServiceDescriptorProto) -> ServiceDescriptor {
+        let name = format!("{}.{}", path, proto.get_name());
+        let mut service_descriptor =
+            ServiceDescriptor::new(name).with_options(proto.get_options().clone());
+
+        for method_proto in proto.get_method().iter() {
+            service_descriptor.add_method(MethodDescriptor::from_proto(method_proto));
+        }
+
+        service_descriptor
+    }
+
+    /// The name of the service.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The options of the service.
+    #[inline]
+    pub fn options(&self) -> &descriptor::ServiceOptions {
+        &self.options
+    }
+
+    /// All of the methods in the descriptor.
+    pub fn methods(&self) -> &[MethodDescriptor] {
+        &self.methods
+    }
+
+    /// Finds a method by method name.
+    #[inline]
+    pub fn method_by_name(&self, name: &str) -> Option<&MethodDescriptor> {
+        self.methods_by_name.get(name).map(|m| &self.methods[m.0])
+    }
+
+    /// Adds a new method to the descriptor.
+    pub fn add_method(&mut self, descriptor: MethodDescriptor) {
+        let name = descriptor.name.clone();
+        let method_id = MethodId(store(&mut self.methods, descriptor));
+        self.methods_by_name.insert(name, method_id);
+    }
+}
+
+impl MethodDescriptor {
+    /// Creates a new unary method descriptor with the fully qualified input and output types.
+    pub fn new<S, I, O>(name: S, input_type: I, output_type: O) -> MethodDescriptor
+    where
+        S: Into<String>,
+        I: Into<String>,
+        O: Into<String>,
+    {
+        MethodDescriptor {
+            name: name.into(),
+            input_type: input_type.into(),
+            output_type: output_type.into(),
+            client_streaming: false,
+            server_streaming: false,
+            options: descriptor::MethodOptions::new(),
+        }
+    }
+
+    /// Sets whether the client and the server stream multiple messages.
+    pub fn with_streaming(
+        mut self,
+        client_streaming: bool,
+        server_streaming: bool,
+    ) -> MethodDescriptor {
+        self.client_streaming = client_streaming;
+        self.server_streaming = server_streaming;
+        self
+    }
+
+    /// Sets the options of this method, including any custom options as unknown fields.
+    pub fn with_options(mut self, options: descriptor::MethodOptions) -> MethodDescriptor {
+        self.options = options;
+        self
+    }
+
+    /// Reads a method descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::MethodDescriptorProto) -> MethodDescriptor {
+        MethodDescriptor::new(
+            proto.get_name(),
+            proto.get_input_type(),
+            proto.get_output_type(),
+        )
+        .with_streaming(proto.get_client_streaming(), proto.get_server_streaming())
+        .with_options(proto.get_options().clone())
+    }
+
+    /// The name of the method.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The fully qualified name of the input message type.
+    #[inline]
+    pub fn input_type_name(&self) -> &str {
+        &self.input_type
+    }
+
+    /// The fully qualified name of the output message type.
+    #[inline]
+    pub fn output_type_name(&self) -> &str {
+        &self.output_type
+    }
+
+    /// Looks up the input message type in the specified registry.
+    pub fn input_type<'a>(&self, descriptors: &'a Descriptors) -> Option<&'a MessageDescriptor> {
+        descriptors.message_by_name(&self.input_type)
+    }
+
+    /// Looks up the output message type in the specified registry.
+    pub fn output_type<'a>(&self, descriptors: &'a Descriptors) -> Option<&'a MessageDescriptor> {
+        descriptors.message_by_name(&self.output_type)
+    }
+
+    /// Whether the client streams multiple input messages.
+    #[inline]
+    pub fn client_streaming(&self) -> bool {
+        self.client_streaming
+    }
+
+    /// Whether the server streams multiple output messages.
+    #[inline]
+    pub fn server_streaming(&self) -> bool {
+        self.server_streaming
+    }
+
+    /// The options of the method.
+    #[inline]
+    pub fn options(&self) -> &descriptor::MethodOptions {
+        &self.options
+    }
+}
+
 impl FieldDescriptor {
     /// Creates a new field descriptor.
     pub fn new<S>(
@@ -689,15 +1272,64 @@ impl FieldDescriptor {
         S: Into<String>,
     {
         let name = name.into();
+        let json_name = to_json_name(&name);
         FieldDescriptor {
             name,
             number,
             field_label,
             field_type,
             default_value,
+            json_name,
+            options: descriptor::FieldOptions::new(),
+            oneof_index: None,
+            proto3_optional: false,
+            packed: false,
+            implicit_presence: false,
         }
     }
 
+    /// Sets the options of this field, including any custom options as unknown fields.
+    pub fn with_options(mut self, options: descriptor::FieldOptions) -> FieldDescriptor {
+        self.options = options;
+        self
+    }
+
+    /// Makes this field part of the oneof with the specified index in its message.
+    pub fn with_oneof_index(mut self, index: usize) -> FieldDescriptor {
+        self.oneof_index = Some(index);
+        self
+    }
+
+    /// Marks this field as a proto3 `optional` field, which is the only field of a synthetic
+    /// oneof.
+    pub fn with_proto3_optional(mut self, proto3_optional: bool) -> FieldDescriptor {
+        self.proto3_optional = proto3_optional;
+        self
+    }
+
+    /// Makes this repeated field write its values as a single packed run, if it has a scalar or
+    /// enum type.
+    pub fn with_packed(mut self, packed: bool) -> FieldDescriptor {
+        self.packed = packed;
+        self
+    }
+
+    /// Makes this field follow the proto3 rules for presence, where a singular scalar field that
+    /// is not part of a oneof is indistinguishable from one that is set to its default value.
+    pub fn with_implicit_presence(mut self, implicit_presence: bool) -> FieldDescriptor {
+        self.implicit_presence = implicit_presence;
+        self
+    }
+
+    /// Sets the name of this field in the JSON mapping, if it differs from the default.
+    pub fn with_json_name<S>(mut self, json_name: S) -> FieldDescriptor
+    where
+        S: Into<String>,
+    {
+        self.json_name = json_name.into();
+        self
+    }
+
     /// Reads a field descriptor from a parsed Protobuf descriptor.
     pub fn from_proto(proto: &descriptor::FieldDescriptorProto) -> FieldDescriptor {
         let name = proto.get_name().to_owned();
@@ -712,7 +1344,18 @@ impl FieldDescriptor {
             None
         };
 
-        FieldDescriptor::new(name, number, field_label, field_type, default_value)
+        let mut field = FieldDescriptor::new(name, number, field_label, field_type, default_value)
+            .with_options(proto.get_options().clone())
+            .with_proto3_optional(proto.get_proto3_optional())
+            .with_packed(proto.get_options().get_packed());
+        if proto.has_oneof_index() {
+            field = field.with_oneof_index(proto.get_oneof_index() as usize);
+        }
+        if proto.has_json_name() {
+            field.with_json_name(proto.get_json_name())
+        } else {
+            field
+        }
     }
 
     /// The name of the field.
@@ -721,6 +1364,12 @@ impl FieldDescriptor {
         &self.name
     }
 
+    /// The name of the field in the JSON mapping, by default the field name in lower camel case.
+    #[inline]
+    pub fn json_name(&self) -> &str {
+        &self.json_name
+    }
+
     /// The number of the field.
     #[inline]
     pub fn number(&self) -> i32 {
@@ -739,6 +1388,51 @@ impl FieldDescriptor {
         self.field_label == FieldLabel::Repeated
     }
 
+    /// The index of the oneof that the field is part of in its message, if any.
+    #[inline]
+    pub fn oneof_index(&self) -> Option<usize> {
+        self.oneof_index
+    }
+
+    /// Whether the field is a `weak` field, whose message type may come from a weak import that
+    /// is missing; such fields are decoded like unknown fields while their type isn't registered.
+    #[inline]
+    pub fn is_weak(&self) -> bool {
+        self.options.get_weak()
+    }
+
+    /// Whether the field is marked as `deprecated = true`.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.options.get_deprecated()
+    }
+
+    /// Whether the field is a proto3 `optional` field, and so tracks presence even though it has
+    /// a scalar type.
+    #[inline]
+    pub fn is_proto3_optional(&self) -> bool {
+        self.proto3_optional
+    }
+
+    /// Whether the values of this repeated field are written as a single packed run: the
+    /// `packed` option if it is set, and otherwise the default of the syntax of its file, which
+    /// packs repeated scalar and enum fields in proto3.
+    #[inline]
+    pub fn is_packed(&self) -> bool {
+        self.packed && self.is_repeated() && self.field_type.is_packable()
+    }
+
+    /// Whether the field tracks presence, so that setting it to its default value is different
+    /// from leaving it unset: singular fields in proto2, and message fields, oneof members and
+    /// `optional` fields in proto3.
+    #[inline]
+    pub fn has_presence(&self) -> bool {
+        !self.is_repeated()
+            && (!self.implicit_presence
+                || self.oneof_index.is_some()
+                || self.field_type.is_message())
+    }
+
     /// The type of the field.
     #[inline]
     pub fn field_type<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
@@ -750,6 +1444,42 @@ impl FieldDescriptor {
     pub fn default_value(&self) -> Option<&value::Value> {
         self.default_value.as_ref()
     }
+
+    /// The options of the field.
+    #[inline]
+    pub fn options(&self) -> &descriptor::FieldOptions {
+        &self.options
+    }
+
+    /// The value of the custom (extension) option with the specified field number, if set.
+    ///
+    /// Custom options are not known to the descriptor parser, so they are kept as unknown fields
+    /// of the field options; if an option occurs several times the last value wins.
+    pub fn custom_option(&self, number: u32) -> Option<protobuf::UnknownValueRef<'_>> {
+        use protobuf::Message;
+
+        self.options
+            .get_unknown_fields()
+            .get(number)
+            .and_then(|values| values.iter().last())
+    }
+}
+
+/// Converts a field name to lower camel case like `protoc` does, e.g. `foo_bar` to `fooBar`.
+pub(crate) fn to_json_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut capitalize_next = false;
+    for c in name.chars() {
+        if c == '_' {
+            capitalize_next = true;
+        } else if capitalize_next {
+            result.extend(c.to_uppercase());
+            capitalize_next = false;
+        } else {
+            result.push(c);
+        }
+    }
+    result
 }
 
 fn store<A>(vec: &mut Vec<A>, elem: A) -> usize {
@@ -808,7 +1538,7 @@ fn parse_default_value(value: &str, field_type: &InternalFieldType) -> error::Re
             .map(value::Value::U64)
             .map_err(|_| bad(value)),
         InternalFieldType::String => Ok(value::Value::String(value.to_owned())),
-        InternalFieldType::Group => unimplemented!(),
+        InternalFieldType::Group => Err(bad(value)),
         InternalFieldType::Bytes => Ok(value::Value::Bytes(
             value.chars().map(|c| c as u8).collect(),
         )),
@@ -0,0 +1,263 @@
+//! Turning a registry back into protocol buffer file descriptors.
+//!
+//! The registry doesn't keep the `FileDescriptorProto`s that it was built from, so they are
+//! reconstructed from the types that each file defines, e.g. to describe the types to other
+//! tools.  Only types that were added from file descriptors are exported, and what the registry
+//! doesn't track is left out: the options of files, extensions, reserved ranges, group fields
+//! and the default values of enum fields.
+use std::collections;
+
+use protobuf::descriptor;
+use protobuf::descriptor::FieldDescriptorProto_Label as Label;
+use protobuf::descriptor::FieldDescriptorProto_Type as Type;
+
+use super::{
+    Descriptors, EnumDescriptor, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+    ServiceDescriptor,
+};
+use crate::value;
+
+impl Descriptors {
+    /// Reconstructs the file descriptors of all files that types in the registry were added from,
+    /// in the order of their names.
+    pub fn file_descriptor_protos(&self) -> Vec<descriptor::FileDescriptorProto> {
+        self.files()
+            .into_iter()
+            .filter_map(|f| self.file_descriptor_proto(f))
+            .collect()
+    }
+
+    /// Reconstructs the file descriptor of the file with the specified name, if types in the
+    /// registry were added from it.
+    pub fn file_descriptor_proto(&self, name: &str) -> Option<descriptor::FileDescriptorProto> {
+        let types = self
+            .files_by_type
+            .iter()
+            .filter(|e| e.1 == name)
+            .map(|e| e.0.as_str())
+            .collect::<collections::BTreeSet<_>>();
+        if types.is_empty() {
+            return None;
+        }
+
+        let mut file = descriptor::FileDescriptorProto::new();
+        file.set_name(name.to_owned());
+        if let Some(syntax) = self.syntax_of_file(name) {
+            file.set_syntax(syntax.to_owned());
+        }
+        let mut dependencies = collections::BTreeSet::new();
+
+        // Types whose scope isn't a message of the same file are top-level types, and their scope
+        // is the package of the file
+        for &type_name in &types {
+            let scope = scope_of(type_name);
+            if types.contains(scope) && self.message_by_name(scope).is_some() {
+                continue;
+            }
+            if !scope.is_empty() && !file.has_package() {
+                file.set_package(scope[1..].to_owned());
+            }
+            if let Some(message) = self.message_by_name(type_name) {
+                let proto = self.message_proto(message, &types, &mut dependencies);
+                file.mut_message_type().push(proto);
+            } else if let Some(e) = self.enum_by_name(type_name) {
+                file.mut_enum_type().push(enum_proto(e));
+            } else if let Some(service) = self.service_by_name(type_name) {
+                let proto = service_proto(service);
+                for method in service.methods() {
+                    dependencies.insert(method.input_type_name());
+                    dependencies.insert(method.output_type_name());
+                }
+                file.mut_service().push(proto);
+            }
+        }
+
+        let dependencies = dependencies
+            .into_iter()
+            .filter_map(|t| self.file_of_type(t))
+            .filter(|&f| f != name)
+            .collect::<collections::BTreeSet<_>>();
+        for dependency in dependencies {
+            file.mut_dependency().push(dependency.to_owned());
+        }
+        Some(file)
+    }
+
+    fn message_proto<'a>(
+        &'a self,
+        message: &'a MessageDescriptor,
+        types: &collections::BTreeSet<&str>,
+        dependencies: &mut collections::BTreeSet<&'a str>,
+    ) -> descriptor::DescriptorProto {
+        let mut proto = descriptor::DescriptorProto::new();
+        proto.set_name(simple_name(message.name()).to_owned());
+        proto.set_options(message.options().clone());
+        for oneof in message.oneofs() {
+            let mut oneof_proto = descriptor::OneofDescriptorProto::new();
+            oneof_proto.set_name(oneof.name().to_owned());
+            proto.mut_oneof_decl().push(oneof_proto);
+        }
+        for field in message.fields() {
+            let field_proto = self.field_proto(field);
+            if field_proto.has_type_name() {
+                dependencies.insert(self.type_name_of(&field.field_type));
+            }
+            proto.mut_field().push(field_proto);
+        }
+
+        for &type_name in types.iter().filter(|&&t| scope_of(t) == message.name()) {
+            if let Some(nested) = self.message_by_name(type_name) {
+                let nested_proto = self.message_proto(nested, types, dependencies);
+                proto.mut_nested_type().push(nested_proto);
+            } else if let Some(e) = self.enum_by_name(type_name) {
+                proto.mut_enum_type().push(enum_proto(e));
+            }
+        }
+        proto
+    }
+
+    fn field_proto(&self, field: &FieldDescriptor) -> descriptor::FieldDescriptorProto {
+        let mut proto = descriptor::FieldDescriptorProto::new();
+        proto.set_name(field.name().to_owned());
+        proto.set_number(field.number());
+        proto.set_label(match field.field_label() {
+            FieldLabel::Optional => Label::LABEL_OPTIONAL,
+            FieldLabel::Required => Label::LABEL_REQUIRED,
+            FieldLabel::Repeated => Label::LABEL_REPEATED,
+        });
+        proto.set_field_type(field_type_proto(&field.field_type));
+        match field.field_type {
+            InternalFieldType::UnresolvedMessage(_)
+            | InternalFieldType::UnresolvedEnum(_)
+            | InternalFieldType::Message(_)
+            | InternalFieldType::Enum(_) => {
+                proto.set_type_name(self.type_name_of(&field.field_type).to_owned())
+            }
+            _ => (),
+        }
+        if let Some(default_value) = field.default_value().and_then(default_value_proto) {
+            proto.set_default_value(default_value);
+        }
+        if let Some(index) = field.oneof_index() {
+            proto.set_oneof_index(index as i32);
+        }
+        proto.set_json_name(field.json_name().to_owned());
+        proto.set_options(field.options().clone());
+        if field.is_proto3_optional() {
+            proto.set_proto3_optional(true);
+        }
+        proto
+    }
+
+    /// The fully qualified name of a message or enum field type.
+    fn type_name_of<'a>(&'a self, field_type: &'a InternalFieldType) -> &'a str {
+        match *field_type {
+            InternalFieldType::UnresolvedMessage(ref n)
+            | InternalFieldType::UnresolvedEnum(ref n) => n,
+            InternalFieldType::Message(id) => &self.messages[id.0].name,
+            InternalFieldType::Enum(id) => &self.enums[id.0].name,
+            _ => "",
+        }
+    }
+}
+
+fn enum_proto(e: &EnumDescriptor) -> descriptor::EnumDescriptorProto {
+    let mut proto = descriptor::EnumDescriptorProto::new();
+    proto.set_name(simple_name(e.name()).to_owned());
+    for value in e.values() {
+        let mut value_proto = descriptor::EnumValueDescriptorProto::new();
+        value_proto.set_name(value.name().to_owned());
+        value_proto.set_number(value.number());
+        proto.mut_value().push(value_proto);
+    }
+    // Aliases must be allowed explicitly
+    let mut numbers = collections::HashSet::new();
+    if !e.values().iter().all(|v| numbers.insert(v.number())) {
+        proto.mut_options().set_allow_alias(true);
+    }
+    proto
+}
+
+fn service_proto(service: &ServiceDescriptor) -> descriptor::ServiceDescriptorProto {
+    let mut proto = descriptor::ServiceDescriptorProto::new();
+    proto.set_name(simple_name(service.name()).to_owned());
+    proto.set_options(service.options().clone());
+    for method in service.methods() {
+        let mut method_proto = descriptor::MethodDescriptorProto::new();
+        method_proto.set_name(method.name().to_owned());
+        method_proto.set_input_type(method.input_type_name().to_owned());
+        method_proto.set_output_type(method.output_type_name().to_owned());
+        if method.client_streaming() {
+            method_proto.set_client_streaming(true);
+        }
+        if method.server_streaming() {
+            method_proto.set_server_streaming(true);
+        }
+        method_proto.set_options(method.options().clone());
+        proto.mut_method().push(method_proto);
+    }
+    proto
+}
+
+fn field_type_proto(field_type: &InternalFieldType) -> Type {
+    match *field_type {
+        InternalFieldType::UnresolvedMessage(_) | InternalFieldType::Message(_) => {
+            Type::TYPE_MESSAGE
+        }
+        InternalFieldType::UnresolvedEnum(_) | InternalFieldType::Enum(_) => Type::TYPE_ENUM,
+        InternalFieldType::Double => Type::TYPE_DOUBLE,
+        InternalFieldType::Float => Type::TYPE_FLOAT,
+        InternalFieldType::Int64 => Type::TYPE_INT64,
+        InternalFieldType::UInt64 => Type::TYPE_UINT64,
+        InternalFieldType::Int32 => Type::TYPE_INT32,
+        InternalFieldType::Fixed64 => Type::TYPE_FIXED64,
+        InternalFieldType::Fixed32 => Type::TYPE_FIXED32,
+        InternalFieldType::Bool => Type::TYPE_BOOL,
+        InternalFieldType::String => Type::TYPE_STRING,
+        InternalFieldType::Group => Type::TYPE_GROUP,
+        InternalFieldType::Bytes => Type::TYPE_BYTES,
+        InternalFieldType::UInt32 => Type::TYPE_UINT32,
+        InternalFieldType::SFixed32 => Type::TYPE_SFIXED32,
+        InternalFieldType::SFixed64 => Type::TYPE_SFIXED64,
+        InternalFieldType::SInt32 => Type::TYPE_SINT32,
+        InternalFieldType::SInt64 => Type::TYPE_SINT64,
+    }
+}
+
+/// Formats a default value like `protoc` does in descriptors.
+fn default_value_proto(value: &value::Value) -> Option<String> {
+    fn float<F>(v: F, is_nan: bool, is_infinite: bool, is_positive: bool) -> String
+    where
+        F: ToString,
+    {
+        if is_nan {
+            "nan".to_owned()
+        } else if is_infinite {
+            if is_positive { "inf" } else { "-inf" }.to_owned()
+        } else {
+            v.to_string()
+        }
+    }
+
+    Some(match *value {
+        value::Value::Bool(v) => v.to_string(),
+        value::Value::I32(v) => v.to_string(),
+        value::Value::I64(v) => v.to_string(),
+        value::Value::U32(v) => v.to_string(),
+        value::Value::U64(v) => v.to_string(),
+        value::Value::F32(v) => float(v, v.is_nan(), v.is_infinite(), v > 0.0),
+        value::Value::F64(v) => float(v, v.is_nan(), v.is_infinite(), v > 0.0),
+        value::Value::String(ref v) => v.clone(),
+        value::Value::Bytes(ref v) => v.iter().map(|&b| b as char).collect(),
+        _ => return None,
+    })
+}
+
+/// The scope of a fully qualified name, i.e. the name without its last component.
+fn scope_of(name: &str) -> &str {
+    name.rfind('.').map_or("", |i| &name[..i])
+}
+
+fn simple_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
@@ -0,0 +1,193 @@
+//! Restricting registries to the packages that an application uses.
+//!
+//! Large descriptor sets often bundle thousands of types that an application never decodes.
+//! Files of other packages can be skipped while loading with `Descriptors::from_proto_packages`,
+//! and types of other packages can be dropped from a registry with `retain_packages`.  The
+//! `*_in_packages` methods list the types of some packages without changing the registry.
+use std::collections;
+
+use protobuf::descriptor;
+
+use super::imports;
+use super::merge::unresolve;
+use super::{Descriptors, EnumDescriptor, FieldType, MessageDescriptor, ServiceDescriptor};
+
+impl Descriptors {
+    /// Builds a descriptor set from the files of the specified file descriptor set whose package
+    /// starts with one of the prefixes, e.g. `com.example.`, along with the files that they
+    /// import, directly or indirectly.
+    ///
+    /// The other files aren't materialized at all.  A prefix that ends with a `.` also matches the
+    /// package without it, so `com.example.` matches the `com.example` package.
+    pub fn from_proto_packages(
+        file_set_proto: &descriptor::FileDescriptorSet,
+        packages: &[&str],
+    ) -> Descriptors {
+        let files = file_set_proto.get_file();
+        let by_name = files
+            .iter()
+            .map(|f| (f.get_name(), f))
+            .collect::<collections::HashMap<_, _>>();
+
+        let mut selected = collections::HashSet::new();
+        let mut pending = files
+            .iter()
+            .filter(|f| in_packages(&format!("{}.", f.get_package()), packages))
+            .map(|f| f.get_name())
+            .collect::<Vec<_>>();
+        while let Some(name) = pending.pop() {
+            if selected.insert(name) {
+                if let Some(file) = by_name.get(name) {
+                    pending.extend(file.get_dependency().iter().map(String::as_str));
+                }
+            }
+        }
+
+        let files = files
+            .iter()
+            .filter(|f| selected.contains(f.get_name()))
+            .collect::<Vec<_>>();
+        let mut descriptors = Descriptors::new();
+        for file in imports::qualify_type_names(&files) {
+            descriptors.add_file_proto(&file);
+        }
+        descriptors
+    }
+
+    /// Removes the types whose fully qualified name doesn't start with one of the package
+    /// prefixes, e.g. `com.example.`, except for the types that the remaining types refer to,
+    /// directly or indirectly, so that they can still be decoded.
+    ///
+    /// The prefixes don't include the leading `.` of the names.  References between types are
+    /// unresolved again, so `resolve_refs` should be called afterwards.
+    pub fn retain_packages(&mut self, packages: &[&str]) {
+        let names = self
+            .messages_by_name
+            .keys()
+            .chain(self.enums_by_name.keys())
+            .chain(self.services_by_name.keys());
+        let mut pending = names
+            .filter(|n| in_packages(&n[1..], packages))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut retained = collections::HashSet::new();
+        while let Some(name) = pending.pop() {
+            if retained.contains(&name) {
+                continue;
+            }
+            if let Some(message) = self.message_by_name(&name) {
+                for field in message.fields() {
+                    match field.field_type(self) {
+                        FieldType::Message(m) => pending.push(m.name().to_owned()),
+                        FieldType::Enum(e) => pending.push(e.name().to_owned()),
+                        FieldType::UnresolvedMessage(n) | FieldType::UnresolvedEnum(n) => {
+                            pending.push(n.to_owned())
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            if let Some(service) = self.service_by_name(&name) {
+                for method in service.methods() {
+                    pending.push(method.input_type_name().to_owned());
+                    pending.push(method.output_type_name().to_owned());
+                }
+            }
+            retained.insert(name);
+        }
+
+        let mut filtered = Descriptors::new();
+        for (name, id) in self.messages_by_name.iter() {
+            if retained.contains(name) {
+                let mut message = self.messages[id.0].clone();
+                for field in &mut message.fields {
+                    field.field_type = unresolve(self, &field.field_type);
+                }
+                filtered.add_message(message);
+            }
+        }
+        for (name, id) in self.enums_by_name.iter() {
+            if retained.contains(name) {
+                filtered.add_enum(self.enums[id.0].clone());
+            }
+        }
+        for (name, id) in self.services_by_name.iter() {
+            if retained.contains(name) {
+                filtered.add_service(self.services[id.0].clone());
+            }
+        }
+        filtered.files_by_type = self
+            .files_by_type
+            .iter()
+            .filter(|e| retained.contains(e.0))
+            .map(|(n, f)| (n.clone(), f.clone()))
+            .collect();
+        filtered.syntax_by_file = self
+            .syntax_by_file
+            .iter()
+            .filter(|e| filtered.files_by_type.values().any(|f| f == e.0))
+            .map(|(f, s)| (f.clone(), s.clone()))
+            .collect();
+        // Locations of retained types and of their members
+        filtered.source_locations = self
+            .source_locations
+            .iter()
+            .filter(|e| {
+                retained.contains(e.0)
+                    || e.0.rfind('.').is_some_and(|i| retained.contains(&e.0[..i]))
+            })
+            .map(|(n, l)| (n.clone(), l.clone()))
+            .collect();
+        *self = filtered;
+    }
+
+    /// The messages whose fully qualified name starts with one of the package prefixes, e.g.
+    /// `com.example.`, without the leading `.` of the names.
+    pub fn messages_in_packages<'a>(
+        &'a self,
+        packages: &'a [&str],
+    ) -> impl Iterator<Item = &'a MessageDescriptor> {
+        self.messages
+            .iter()
+            .filter(move |m| in_packages(m.name().trim_start_matches('.'), packages))
+    }
+
+    /// The enums whose fully qualified name starts with one of the package prefixes, like
+    /// `messages_in_packages`.
+    pub fn enums_in_packages<'a>(
+        &'a self,
+        packages: &'a [&str],
+    ) -> impl Iterator<Item = &'a EnumDescriptor> {
+        self.enums
+            .iter()
+            .filter(move |e| in_packages(e.name().trim_start_matches('.'), packages))
+    }
+
+    /// The services whose fully qualified name starts with one of the package prefixes, like
+    /// `messages_in_packages`.
+    pub fn services_in_packages<'a>(
+        &'a self,
+        packages: &'a [&str],
+    ) -> impl Iterator<Item = &'a ServiceDescriptor> {
+        self.services
+            .iter()
+            .filter(move |s| in_packages(s.name().trim_start_matches('.'), packages))
+    }
+
+    /// The names of the files that define types whose fully qualified name starts with one of the
+    /// package prefixes, in sorted order.
+    pub fn files_in_packages(&self, packages: &[&str]) -> Vec<&str> {
+        let files = self
+            .files_by_type
+            .iter()
+            .filter(|e| in_packages(e.0.trim_start_matches('.'), packages))
+            .map(|e| e.1.as_str())
+            .collect::<collections::BTreeSet<_>>();
+        files.into_iter().collect()
+    }
+}
+
+fn in_packages(name: &str, packages: &[&str]) -> bool {
+    packages.iter().any(|p| name.starts_with(p))
+}
@@ -0,0 +1,210 @@
+//! Resolution of relative type names along the imports of files.
+//!
+//! `protoc` writes fully qualified type names into descriptor sets, but hand-built or third-party
+//! descriptors may refer to types relative to the scope of the referring message, like in
+//! `.proto` files.  Such names are resolved by the scoping rules of protobuf, among the types of
+//! the files that are visible from the referring file: the file itself, the files that it
+//! imports, including weak imports, and the files that those re-export with `import public`,
+//! transitively.  Weak imports that are missing from the set are ignored.
+use std::borrow::Cow;
+use std::collections;
+
+use protobuf::descriptor;
+use protobuf::descriptor::FieldDescriptorProto_Type as Type;
+
+/// The kind of a type defined in a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum TypeKind {
+    Message,
+    Enum,
+    Service,
+}
+
+/// Qualifies the relative type names in the files, which must include the files they import.
+///
+/// Files without relative type names are borrowed as they are.
+pub(super) fn qualify_type_names<'a>(
+    files: &[&'a descriptor::FileDescriptorProto],
+) -> Vec<Cow<'a, descriptor::FileDescriptorProto>> {
+    let by_name = files
+        .iter()
+        .map(|f| (f.get_name(), *f))
+        .collect::<collections::HashMap<_, _>>();
+    let mut defined = collections::HashMap::new();
+
+    files
+        .iter()
+        .map(|&file| {
+            if !has_relative_names(file) {
+                return Cow::Borrowed(file);
+            }
+
+            let mut visible = collections::HashMap::new();
+            for name in visible_files(file, &by_name) {
+                let types = defined.entry(name).or_insert_with(|| {
+                    let mut types = Vec::new();
+                    if let Some(f) = by_name.get(name) {
+                        type_names(f, &mut types);
+                    }
+                    types
+                });
+                visible.extend(types.iter().cloned());
+            }
+
+            let mut file = file.clone();
+            let scope = package_scope(&file);
+            for message in file.mut_message_type().iter_mut() {
+                qualify_message(&scope, message, &visible);
+            }
+            for service in file.mut_service().iter_mut() {
+                for method in service.mut_method().iter_mut() {
+                    if let Some((name, _)) = resolve(&scope, method.get_input_type(), &visible) {
+                        method.set_input_type(name);
+                    }
+                    if let Some((name, _)) = resolve(&scope, method.get_output_type(), &visible) {
+                        method.set_output_type(name);
+                    }
+                }
+            }
+            Cow::Owned(file)
+        })
+        .collect()
+}
+
+/// Collects the fully qualified names and kinds of the types that the file defines.
+pub(super) fn type_names(
+    file: &descriptor::FileDescriptorProto,
+    names: &mut Vec<(String, TypeKind)>,
+) {
+    fn message_names(
+        prefix: &str,
+        message: &descriptor::DescriptorProto,
+        names: &mut Vec<(String, TypeKind)>,
+    ) {
+        let name = format!("{}.{}", prefix, message.get_name());
+        names.push((name.clone(), TypeKind::Message));
+        for nested in message.get_nested_type() {
+            message_names(&name, nested, names);
+        }
+        for e in message.get_enum_type() {
+            names.push((format!("{}.{}", name, e.get_name()), TypeKind::Enum));
+        }
+    }
+
+    let prefix = package_scope(file);
+    for message in file.get_message_type() {
+        message_names(&prefix, message, names);
+    }
+    for e in file.get_enum_type() {
+        names.push((format!("{}.{}", prefix, e.get_name()), TypeKind::Enum));
+    }
+    for service in file.get_service() {
+        names.push((
+            format!("{}.{}", prefix, service.get_name()),
+            TypeKind::Service,
+        ));
+    }
+}
+
+/// The names of the files whose types are visible from the file, including itself.
+fn visible_files<'a>(
+    file: &'a descriptor::FileDescriptorProto,
+    by_name: &collections::HashMap<&'a str, &'a descriptor::FileDescriptorProto>,
+) -> collections::HashSet<&'a str> {
+    let mut visible = collections::HashSet::new();
+    visible.insert(file.get_name());
+    let mut pending = file
+        .get_dependency()
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    while let Some(name) = pending.pop() {
+        if visible.insert(name) {
+            if let Some(dependency) = by_name.get(name) {
+                let dependencies = dependency.get_dependency();
+                pending.extend(
+                    dependency
+                        .get_public_dependency()
+                        .iter()
+                        .filter_map(|&i| dependencies.get(i as usize))
+                        .map(String::as_str),
+                );
+            }
+        }
+    }
+    visible
+}
+
+fn has_relative_names(file: &descriptor::FileDescriptorProto) -> bool {
+    fn message_has(message: &descriptor::DescriptorProto) -> bool {
+        message
+            .get_field()
+            .iter()
+            .any(|f| is_relative(f.get_type_name()))
+            || message.get_nested_type().iter().any(message_has)
+    }
+
+    file.get_message_type().iter().any(message_has)
+        || file.get_service().iter().any(|s| {
+            s.get_method()
+                .iter()
+                .any(|m| is_relative(m.get_input_type()) || is_relative(m.get_output_type()))
+        })
+}
+
+fn qualify_message(
+    scope: &str,
+    message: &mut descriptor::DescriptorProto,
+    visible: &collections::HashMap<String, TypeKind>,
+) {
+    let scope = format!("{}.{}", scope, message.get_name());
+    for field in message.mut_field().iter_mut() {
+        if let Some((name, kind)) = resolve(&scope, field.get_type_name(), visible) {
+            field.set_type_name(name);
+            // The type may be left out when the type name is given
+            if !field.has_field_type() {
+                field.set_field_type(match kind {
+                    TypeKind::Enum => Type::TYPE_ENUM,
+                    _ => Type::TYPE_MESSAGE,
+                });
+            }
+        }
+    }
+    for nested in message.mut_nested_type().iter_mut() {
+        qualify_message(&scope, nested, visible);
+    }
+}
+
+/// Resolves a relative type name from the innermost scope outwards.
+fn resolve(
+    scope: &str,
+    name: &str,
+    visible: &collections::HashMap<String, TypeKind>,
+) -> Option<(String, TypeKind)> {
+    if !is_relative(name) {
+        return None;
+    }
+    let mut scope = scope;
+    loop {
+        let candidate = format!("{}.{}", scope, name);
+        if let Some(&kind) = visible.get(&candidate) {
+            return Some((candidate, kind));
+        }
+        match scope.rfind('.') {
+            Some(i) => scope = &scope[..i],
+            None => return None,
+        }
+    }
+}
+
+fn is_relative(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.')
+}
+
+fn package_scope(file: &descriptor::FileDescriptorProto) -> String {
+    if file.has_package() {
+        format!(".{}", file.get_package())
+    } else {
+        String::new()
+    }
+}
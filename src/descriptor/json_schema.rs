@@ -0,0 +1,171 @@
+//! Generation of JSON Schema documents that describe the proto3 JSON mapping of messages.
+use serde_json;
+use serde_json::json;
+
+use crate::descriptor::{Descriptors, FieldDescriptor, FieldLabel, FieldType, MessageDescriptor};
+use crate::field_mask;
+use crate::well_known;
+
+const DRAFT_2020_12: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Collects the schemas of message types, which refer to each other with a common prefix.
+pub(crate) struct Generator<'a> {
+    descriptors: &'a Descriptors,
+    ref_prefix: &'static str,
+    defs: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Generates a JSON Schema (draft 2020-12) for the proto3 JSON mapping of the specified message.
+///
+/// Every message type reachable from the message is described in the `$defs` section of the
+/// schema, keyed by its fully qualified name without the leading dot.  Well-known types are
+/// described by their special JSON representation, for example `google.protobuf.Timestamp` as a
+/// `date-time` string.  Only `required` fields of proto2 messages are marked as required.
+pub fn to_json_schema(descriptors: &Descriptors, message: &MessageDescriptor) -> serde_json::Value {
+    let mut generator = Generator::new(descriptors, "#/$defs/");
+    let mut schema = generator.message_schema(message);
+    let defs = generator.into_defs();
+    if let Some(schema) = schema.as_object_mut() {
+        schema.insert("$schema".to_owned(), json!(DRAFT_2020_12));
+        if !defs.is_empty() {
+            schema.insert("$defs".to_owned(), serde_json::Value::Object(defs));
+        }
+    }
+    schema
+}
+
+impl<'a> Generator<'a> {
+    pub(crate) fn new(descriptors: &'a Descriptors, ref_prefix: &'static str) -> Generator<'a> {
+        Generator {
+            descriptors,
+            ref_prefix,
+            defs: serde_json::Map::new(),
+        }
+    }
+
+    /// The schemas of all message types that were referred to, keyed by name.
+    pub(crate) fn into_defs(self) -> serde_json::Map<String, serde_json::Value> {
+        self.defs
+    }
+
+    /// A reference to the schema of the message, or an inline schema for well-known types.
+    pub(crate) fn message_schema(&mut self, message: &MessageDescriptor) -> serde_json::Value {
+        if let Some(schema) = self.well_known_schema(message) {
+            return schema;
+        }
+
+        let key = message.name().trim_start_matches('.');
+        if !self.defs.contains_key(key) {
+            // Reserve the key first, so that recursive references terminate
+            self.defs.insert(key.to_owned(), serde_json::Value::Null);
+
+            let mut properties = serde_json::Map::new();
+            let mut required = Vec::new();
+            for field in message.fields() {
+                let schema = self.field_schema(field);
+                properties.insert(field.json_name().to_owned(), schema);
+                if field.field_label() == FieldLabel::Required {
+                    required.push(json!(field.json_name()));
+                }
+            }
+
+            let mut schema = json!({
+                "type": "object",
+                "properties": properties,
+            });
+            if !required.is_empty() {
+                schema["required"] = serde_json::Value::Array(required);
+            }
+            self.defs.insert(key.to_owned(), schema);
+        }
+
+        json!({ "$ref": format!("{}{}", self.ref_prefix, key) })
+    }
+
+    /// The schema of the field, taking repeated and map fields into account.
+    pub(crate) fn field_schema(&mut self, field: &FieldDescriptor) -> serde_json::Value {
+        if !field.is_repeated() {
+            return self.value_schema(field);
+        }
+
+        match field.field_type(self.descriptors) {
+            FieldType::Message(entry) if entry.is_map_entry() => {
+                let values = match entry.field_by_number(2) {
+                    Some(value) => self.value_schema(value),
+                    None => json!({}),
+                };
+                json!({ "type": "object", "additionalProperties": values })
+            }
+            _ => json!({ "type": "array", "items": self.value_schema(field) }),
+        }
+    }
+
+    fn value_schema(&mut self, field: &FieldDescriptor) -> serde_json::Value {
+        match field.field_type(self.descriptors) {
+            FieldType::Bool => json!({ "type": "boolean" }),
+            FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => {
+                json!({ "type": "integer", "format": "int32" })
+            }
+            FieldType::UInt32 | FieldType::Fixed32 => {
+                json!({ "type": "integer", "format": "uint32", "minimum": 0 })
+            }
+            FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => {
+                json!({ "type": ["string", "integer"], "format": "int64" })
+            }
+            FieldType::UInt64 | FieldType::Fixed64 => {
+                json!({ "type": ["string", "integer"], "format": "uint64" })
+            }
+            FieldType::Float | FieldType::Double => json!({
+                "anyOf": [
+                    { "type": "number" },
+                    { "enum": ["NaN", "Infinity", "-Infinity"] },
+                ],
+            }),
+            FieldType::String => json!({ "type": "string" }),
+            FieldType::Bytes => json!({ "type": "string", "contentEncoding": "base64" }),
+            FieldType::Enum(e) if e.name() == ".google.protobuf.NullValue" => {
+                json!({ "type": "null" })
+            }
+            FieldType::Enum(e) => {
+                let names: Vec<_> = e.values().iter().map(|v| v.name()).collect();
+                json!({ "type": "string", "enum": names })
+            }
+            FieldType::Message(m) => self.message_schema(m),
+            FieldType::Group | FieldType::UnresolvedEnum(_) | FieldType::UnresolvedMessage(_) => {
+                json!({})
+            }
+        }
+    }
+
+    fn well_known_schema(&mut self, message: &MessageDescriptor) -> Option<serde_json::Value> {
+        let schema = match message.name() {
+            well_known::TIMESTAMP => json!({ "type": "string", "format": "date-time" }),
+            well_known::DURATION => {
+                json!({ "type": "string", "pattern": "^-?[0-9]+(\\.[0-9]{1,9})?s$" })
+            }
+            field_mask::FIELD_MASK => json!({ "type": "string" }),
+            ".google.protobuf.Any" => json!({
+                "type": "object",
+                "properties": { "@type": { "type": "string" } },
+                "required": ["@type"],
+            }),
+            ".google.protobuf.Empty" | ".google.protobuf.Struct" => json!({ "type": "object" }),
+            ".google.protobuf.ListValue" => json!({ "type": "array" }),
+            ".google.protobuf.Value" => json!({}),
+            ".google.protobuf.DoubleValue"
+            | ".google.protobuf.FloatValue"
+            | ".google.protobuf.Int64Value"
+            | ".google.protobuf.UInt64Value"
+            | ".google.protobuf.Int32Value"
+            | ".google.protobuf.UInt32Value"
+            | ".google.protobuf.BoolValue"
+            | ".google.protobuf.StringValue"
+            | ".google.protobuf.BytesValue" => {
+                let value = message.field_by_number(1)?;
+                self.value_schema(value)
+            }
+            _ => return None,
+        };
+        Some(schema)
+    }
+}
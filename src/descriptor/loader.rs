@@ -0,0 +1,271 @@
+//! Loading descriptor sets from directory trees.
+//!
+//! Operationally, descriptor sets are often stored per service in a configuration tree.  A
+//! `DescriptorLoader` walks such trees and merges every descriptor set it finds into one
+//! registry, making sure that the sets don't contradict each other.
+use std::collections;
+#[cfg(feature = "protoc")]
+use std::env;
+use std::fs;
+use std::io;
+use std::path;
+#[cfg(feature = "protoc")]
+use std::process;
+#[cfg(feature = "protoc")]
+use std::sync::atomic;
+
+use protobuf;
+use protobuf::descriptor;
+
+use super::imports;
+use super::Descriptors;
+use crate::error;
+
+/// Loads the descriptor sets in directory trees into one registry.
+///
+/// Files with a `.binpb`, `.desc` or `.pb` extension are parsed as encoded `FileDescriptorSet`s,
+/// in the order of their paths.  A file that occurs in several sets, like a common import that
+/// was included with `protoc --include_imports`, is only added once if its definitions are
+/// identical in every set.  Otherwise, or if different files define a type with the same name,
+/// loading fails with a `DescriptorConflict` error.
+///
+/// With the `protoc` feature, `.proto` files in the trees are compiled as well, by running the
+/// `protoc` compiler (the one named by the `PROTOC` environment variable, or the one on the
+/// `PATH`) with the include paths of the loader and the root of the tree.
+#[derive(Clone, Debug)]
+pub struct DescriptorLoader {
+    dirs: Vec<path::PathBuf>,
+    extensions: Vec<String>,
+    #[cfg(feature = "protoc")]
+    include_paths: Vec<path::PathBuf>,
+}
+
+/// The files added so far, with the paths of the descriptor sets they came from.
+#[derive(Default)]
+struct LoadedFiles {
+    files: Vec<(path::PathBuf, descriptor::FileDescriptorProto)>,
+    files_by_name: collections::HashMap<String, usize>,
+    // The index of the file that defines each fully qualified type name
+    types: collections::HashMap<String, usize>,
+}
+
+impl Descriptors {
+    /// Loads all descriptor sets in the directory tree at the specified path into a new registry,
+    /// using a `DescriptorLoader` with the default settings.
+    pub fn from_dir<P>(dir: P) -> error::Result<Descriptors>
+    where
+        P: AsRef<path::Path>,
+    {
+        DescriptorLoader::new().dir(dir).load()
+    }
+}
+
+impl DescriptorLoader {
+    /// Creates a loader without any directories yet.
+    pub fn new() -> DescriptorLoader {
+        DescriptorLoader {
+            dirs: Vec::new(),
+            extensions: vec!["binpb".to_owned(), "desc".to_owned(), "pb".to_owned()],
+            #[cfg(feature = "protoc")]
+            include_paths: Vec::new(),
+        }
+    }
+
+    /// Adds a directory tree to walk.
+    pub fn dir<P>(mut self, dir: P) -> DescriptorLoader
+    where
+        P: AsRef<path::Path>,
+    {
+        self.dirs.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a file extension, without the leading `.`, of the descriptor sets to load.
+    pub fn extension<S>(mut self, extension: S) -> DescriptorLoader
+    where
+        S: Into<String>,
+    {
+        self.extensions.push(extension.into());
+        self
+    }
+
+    /// Adds a path that `protoc` searches for imports of the `.proto` files being compiled.
+    #[cfg(feature = "protoc")]
+    pub fn include_path<P>(mut self, path: P) -> DescriptorLoader
+    where
+        P: AsRef<path::Path>,
+    {
+        self.include_paths.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Loads the descriptor sets in all directory trees into a new registry.
+    pub fn load(&self) -> error::Result<Descriptors> {
+        let mut loaded = LoadedFiles::default();
+
+        for dir in &self.dirs {
+            let mut paths = Vec::new();
+            walk(dir, &mut paths, &mut collections::HashSet::new())?;
+            paths.sort();
+
+            for path in &paths {
+                if self.is_descriptor_set(path) {
+                    let bytes = fs::read(path).map_err(io_error)?;
+                    let file_set: descriptor::FileDescriptorSet =
+                        protobuf::Message::parse_from_bytes(&bytes)?;
+                    loaded.add_file_set(path, file_set)?;
+                }
+            }
+
+            #[cfg(feature = "protoc")]
+            {
+                let protos = paths
+                    .iter()
+                    .filter(|p| p.extension().is_some_and(|e| e == "proto"))
+                    .collect::<Vec<_>>();
+                if !protos.is_empty() {
+                    let file_set = self.compile(dir, &protos)?;
+                    loaded.add_file_set(dir, file_set)?;
+                }
+            }
+        }
+
+        let files = loaded.files.iter().map(|f| &f.1).collect::<Vec<_>>();
+        let mut descriptors = Descriptors::new();
+        for file in imports::qualify_type_names(&files) {
+            descriptors.add_file_proto(&file);
+        }
+        Ok(descriptors)
+    }
+
+    fn is_descriptor_set(&self, path: &path::Path) -> bool {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => self.extensions.iter().any(|e| e == extension),
+            None => false,
+        }
+    }
+
+    /// Compiles `.proto` files in the directory tree into a descriptor set with `protoc`.
+    #[cfg(feature = "protoc")]
+    fn compile(
+        &self,
+        dir: &path::Path,
+        protos: &[&path::PathBuf],
+    ) -> error::Result<descriptor::FileDescriptorSet> {
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        let output = env::temp_dir().join(format!(
+            "serde-protobuf-{}-{}.binpb",
+            process::id(),
+            COUNTER.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        let protoc = env::var_os("PROTOC").unwrap_or_else(|| "protoc".into());
+
+        let mut command = process::Command::new(protoc);
+        command.arg("--include_imports").arg("-o").arg(&output);
+        for include_path in &self.include_paths {
+            command.arg("-I").arg(include_path);
+        }
+        command.arg("-I").arg(dir).args(protos);
+
+        let result = command.output().map_err(io_error);
+        let bytes = match result {
+            Ok(ref out) if out.status.success() => fs::read(&output).map_err(io_error),
+            Ok(out) => Err(error::Error::ProtocFailed {
+                message: String::from_utf8_lossy(&out.stderr).trim().to_owned(),
+            }),
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(&output);
+        Ok(protobuf::Message::parse_from_bytes(&bytes?)?)
+    }
+}
+
+impl Default for DescriptorLoader {
+    fn default() -> DescriptorLoader {
+        DescriptorLoader::new()
+    }
+}
+
+impl LoadedFiles {
+    fn add_file_set(
+        &mut self,
+        source: &path::Path,
+        file_set: descriptor::FileDescriptorSet,
+    ) -> error::Result<()> {
+        for file in file_set.file.into_iter() {
+            self.add_file(source, file)?;
+        }
+        Ok(())
+    }
+
+    fn add_file(
+        &mut self,
+        source: &path::Path,
+        file: descriptor::FileDescriptorProto,
+    ) -> error::Result<()> {
+        if let Some(&index) = self.files_by_name.get(file.get_name()) {
+            let (ref first, ref existing) = self.files[index];
+            if *existing == file {
+                return Ok(());
+            }
+            return Err(conflict(file.get_name(), first, source));
+        }
+
+        let index = self.files.len();
+        let mut types = Vec::new();
+        imports::type_names(&file, &mut types);
+        let names = types.into_iter().map(|t| t.0).collect::<Vec<_>>();
+        for name in &names {
+            if let Some(&other) = self.types.get(name) {
+                return Err(conflict(name, &self.files[other].0, source));
+            }
+        }
+        for name in names {
+            self.types.insert(name, index);
+        }
+        self.files_by_name.insert(file.get_name().to_owned(), index);
+        self.files.push((source.to_owned(), file));
+        Ok(())
+    }
+}
+
+/// Collects the paths of all files in the directory tree.
+///
+/// Symbolic links are followed, but every directory is only walked once, so that links that form
+/// a cycle don't make the walk recurse forever.
+fn walk(
+    dir: &path::Path,
+    paths: &mut Vec<path::PathBuf>,
+    visited: &mut collections::HashSet<path::PathBuf>,
+) -> error::Result<()> {
+    if !visited.insert(fs::canonicalize(dir).map_err(io_error)?) {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        let path = entry.path();
+        let is_dir = match entry.file_type().map_err(io_error)? {
+            t if t.is_symlink() => path.is_dir(),
+            t => t.is_dir(),
+        };
+        if is_dir {
+            walk(&path, paths, visited)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn conflict(name: &str, first: &path::Path, second: &path::Path) -> error::Error {
+    error::Error::DescriptorConflict {
+        name: name.to_owned(),
+        first: first.display().to_string(),
+        second: second.display().to_string(),
+    }
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
@@ -0,0 +1,273 @@
+//! Merging descriptor registries with conflict detection.
+//!
+//! Adding a file descriptor to a registry that already has a type with the same name replaces
+//! the type silently, so a stale descriptor set can shadow a newer one.  `Descriptors::merge`
+//! instead compares the definitions of such types and reports the ones that differ.
+use std::fmt;
+use std::ops;
+
+use super::{Descriptors, EnumDescriptor, InternalFieldType, MessageDescriptor, ServiceDescriptor};
+use crate::error;
+
+/// A type that two registries define differently.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeConflict {
+    /// The fully qualified name of the type.
+    pub name: String,
+    /// The file that defines the type in the registry being merged into, if known.
+    pub first_file: Option<String>,
+    /// The file that defines the type in the registry being merged, if known.
+    pub second_file: Option<String>,
+    /// Descriptions of the differences between the definitions, e.g.
+    /// `field 2 (id): type int32 != string`.
+    pub differences: Vec<String>,
+}
+
+impl Descriptors {
+    /// Adds the types of another registry to this one, e.g. to combine descriptor sets from
+    /// several sources.
+    ///
+    /// Types that both registries define identically are kept once.  If any type is defined
+    /// differently, a `TypeConflicts` error lists all of them and this registry is left unchanged.
+    /// References of the added types are unresolved again, so `resolve_refs` should be called
+    /// after merging.
+    pub fn merge(&mut self, other: &Descriptors) -> error::Result<()> {
+        let conflicts = self.conflicts(other);
+        if !conflicts.is_empty() {
+            return Err(error::Error::TypeConflicts { conflicts });
+        }
+
+        for (name, id) in other.messages_by_name.iter() {
+            if !self.messages_by_name.contains_key(name) {
+                let mut message = other.messages[id.0].clone();
+                for field in &mut message.fields {
+                    field.field_type = unresolve(other, &field.field_type);
+                }
+                self.add_message(message);
+                self.copy_origin_of_type(other, name);
+            }
+        }
+        for (name, id) in other.enums_by_name.iter() {
+            if !self.enums_by_name.contains_key(name) {
+                self.add_enum(other.enums[id.0].clone());
+                self.copy_origin_of_type(other, name);
+            }
+        }
+        for (name, id) in other.services_by_name.iter() {
+            if !self.services_by_name.contains_key(name) {
+                self.add_service(other.services[id.0].clone());
+                self.copy_origin_of_type(other, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds all types defined in the specified protocol buffer file descriptor set to this
+    /// registry, failing like `merge` if they conflict with types that are already in it.
+    pub fn add_file_set_proto_checked(
+        &mut self,
+        file_set_proto: &protobuf::descriptor::FileDescriptorSet,
+    ) -> error::Result<()> {
+        self.merge(&Descriptors::from_proto(file_set_proto))
+    }
+
+    /// The types that both this registry and another one define, but differently.
+    pub fn conflicts(&self, other: &Descriptors) -> Vec<TypeConflict> {
+        let mut conflicts = Vec::new();
+        let mut check = |name: &str, differences: Vec<String>| {
+            if !differences.is_empty() {
+                conflicts.push(TypeConflict {
+                    name: name.to_owned(),
+                    first_file: self.file_of_type(name).map(str::to_owned),
+                    second_file: other.file_of_type(name).map(str::to_owned),
+                    differences,
+                });
+            }
+        };
+
+        for (name, id) in other.messages_by_name.iter() {
+            if let Some(mine) = self.message_by_name(name) {
+                check(
+                    name,
+                    message_differences(self, mine, other, &other.messages[id.0]),
+                );
+            }
+        }
+        for (name, id) in other.enums_by_name.iter() {
+            if let Some(mine) = self.enum_by_name(name) {
+                check(name, enum_differences(mine, &other.enums[id.0]));
+            }
+        }
+        for (name, id) in other.services_by_name.iter() {
+            if let Some(mine) = self.service_by_name(name) {
+                check(name, service_differences(mine, &other.services[id.0]));
+            }
+        }
+        conflicts
+    }
+
+    fn copy_origin_of_type(&mut self, other: &Descriptors, name: &str) {
+        if let Some(file) = other.file_of_type(name) {
+            self.files_by_type.insert(name.to_owned(), file.to_owned());
+            if let Some(syntax) = other.syntax_of_file(file) {
+                self.syntax_by_file
+                    .insert(file.to_owned(), syntax.to_owned());
+            }
+        }
+        // The locations of the type and of its fields, values or methods
+        let members = format!("{}.", name);
+        let locations = other
+            .source_locations
+            .range::<str, _>((ops::Bound::Included(name), ops::Bound::Unbounded))
+            .take_while(|e| e.0 == name || e.0.starts_with(&members));
+        for (n, location) in locations {
+            self.source_locations.insert(n.clone(), location.clone());
+        }
+    }
+}
+
+impl fmt::Display for TypeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} vs. {}): {}",
+            self.name,
+            self.first_file.as_deref().unwrap_or("unknown file"),
+            self.second_file.as_deref().unwrap_or("unknown file"),
+            self.differences.join(", ")
+        )
+    }
+}
+
+fn message_differences(
+    first: &Descriptors,
+    a: &MessageDescriptor,
+    second: &Descriptors,
+    b: &MessageDescriptor,
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    for fa in a.fields() {
+        let fb = match b.field_by_number(fa.number()) {
+            Some(fb) => fb,
+            None => {
+                differences.push(format!(
+                    "field {} ({}): only in the first definition",
+                    fa.number(),
+                    fa.name()
+                ));
+                continue;
+            }
+        };
+        let prefix = format!("field {} ({})", fa.number(), fa.name());
+        if fa.name() != fb.name() {
+            differences.push(format!("{}: name {} != {}", prefix, fa.name(), fb.name()));
+        }
+        if fa.field_label() != fb.field_label() {
+            differences.push(format!(
+                "{}: label {:?} != {:?}",
+                prefix,
+                fa.field_label(),
+                fb.field_label()
+            ));
+        }
+        let (ta, tb) = (fa.field_type(first).name(), fb.field_type(second).name());
+        if ta != tb {
+            differences.push(format!("{}: type {} != {}", prefix, ta, tb));
+        }
+    }
+    for fb in b.fields() {
+        if a.field_by_number(fb.number()).is_none() {
+            differences.push(format!(
+                "field {} ({}): only in the second definition",
+                fb.number(),
+                fb.name()
+            ));
+        }
+    }
+    differences
+}
+
+fn enum_differences(a: &EnumDescriptor, b: &EnumDescriptor) -> Vec<String> {
+    // Values are compared by name, since aliases share numbers
+    let mut differences = Vec::new();
+    for va in a.values() {
+        match b.value_by_name(va.name()) {
+            Some(vb) if va.number() != vb.number() => differences.push(format!(
+                "value {}: number {} != {}",
+                va.name(),
+                va.number(),
+                vb.number()
+            )),
+            Some(_) => (),
+            None => differences.push(format!(
+                "value {} ({}): only in the first definition",
+                va.name(),
+                va.number()
+            )),
+        }
+    }
+    for vb in b.values() {
+        if a.value_by_name(vb.name()).is_none() {
+            differences.push(format!(
+                "value {} ({}): only in the second definition",
+                vb.name(),
+                vb.number()
+            ));
+        }
+    }
+    differences
+}
+
+fn service_differences(a: &ServiceDescriptor, b: &ServiceDescriptor) -> Vec<String> {
+    let mut differences = Vec::new();
+    for ma in a.methods() {
+        let mb = match b.method_by_name(ma.name()) {
+            Some(mb) => mb,
+            None => {
+                differences.push(format!(
+                    "method {}: only in the first definition",
+                    ma.name()
+                ));
+                continue;
+            }
+        };
+        let signature = |m: &super::MethodDescriptor| {
+            format!(
+                "{}{} -> {}{}",
+                if m.client_streaming() { "stream " } else { "" },
+                m.input_type_name(),
+                if m.server_streaming() { "stream " } else { "" },
+                m.output_type_name()
+            )
+        };
+        let (sa, sb) = (signature(ma), signature(mb));
+        if sa != sb {
+            differences.push(format!("method {}: {} != {}", ma.name(), sa, sb));
+        }
+    }
+    for mb in b.methods() {
+        if a.method_by_name(mb.name()).is_none() {
+            differences.push(format!(
+                "method {}: only in the second definition",
+                mb.name()
+            ));
+        }
+    }
+    differences
+}
+
+/// Turns a field type that refers to a type of the registry by ID back into a reference by name.
+pub(super) fn unresolve(
+    descriptors: &Descriptors,
+    field_type: &InternalFieldType,
+) -> InternalFieldType {
+    match *field_type {
+        InternalFieldType::Message(id) => {
+            InternalFieldType::UnresolvedMessage(descriptors.messages[id.0].name.clone())
+        }
+        InternalFieldType::Enum(id) => {
+            InternalFieldType::UnresolvedEnum(descriptors.enums[id.0].name.clone())
+        }
+        ref other => other.clone(),
+    }
+}
@@ -0,0 +1,201 @@
+//! Generation of OpenAPI documents that describe services.
+use serde_json;
+use serde_json::json;
+
+use super::json_schema::Generator;
+use crate::descriptor::{Descriptors, FieldDescriptor, FieldType, MessageDescriptor};
+use crate::error;
+use crate::http;
+
+/// Generates an OpenAPI 3.1 document for all of the services in the specified registry.
+///
+/// Methods with a `google.api.http` annotation get an operation for each of their bindings,
+/// with the path variables as path parameters, and (unless the whole request is the body) the
+/// remaining scalar fields of the request as query parameters.  Other methods get a `POST`
+/// operation at `/<package>.<Service>/<Method>` that takes the whole request as its body.  The
+/// message types that are used are described in the `components` section of the document.
+pub fn to_openapi(
+    descriptors: &Descriptors,
+    title: &str,
+    version: &str,
+) -> error::Result<serde_json::Value> {
+    let mut generator = Generator::new(descriptors, "#/components/schemas/");
+    let mut paths = serde_json::Map::new();
+
+    for service in descriptors.services() {
+        let service_name = service.name().trim_start_matches('.');
+        let short_name = service_name.rsplit('.').next().unwrap_or(service_name);
+
+        for method in service.methods() {
+            let rule = match http::HttpRule::from_method(method)? {
+                Some(rule) => rule,
+                None => http::HttpRule {
+                    method: "POST".to_owned(),
+                    path: format!("/{}/{}", service_name, method.name()),
+                    body: "*".to_owned(),
+                    ..http::HttpRule::default()
+                },
+            };
+            let input = method.input_type(descriptors);
+            let output = method.output_type(descriptors);
+
+            for (i, binding) in rule.bindings().into_iter().enumerate() {
+                let mut operation_id = format!("{}_{}", short_name, method.name());
+                if i > 0 {
+                    operation_id.push_str(&format!("_{}", i + 1));
+                }
+                let mut operation = json!({
+                    "operationId": operation_id,
+                    "tags": [service_name],
+                });
+
+                let parameters = parameters(&mut generator, descriptors, input, binding);
+                if !parameters.is_empty() {
+                    operation["parameters"] = serde_json::Value::Array(parameters);
+                }
+
+                if !binding.body.is_empty() {
+                    let schema = body_schema(&mut generator, descriptors, input, &binding.body);
+                    operation["requestBody"] = json!({
+                        "required": true,
+                        "content": { "application/json": { "schema": schema } },
+                    });
+                }
+
+                let schema = body_schema(
+                    &mut generator,
+                    descriptors,
+                    output,
+                    if binding.response_body.is_empty() {
+                        "*"
+                    } else {
+                        &binding.response_body
+                    },
+                );
+                operation["responses"] = json!({
+                    "200": {
+                        "description": "OK",
+                        "content": { "application/json": { "schema": schema } },
+                    },
+                });
+
+                let path = paths
+                    .entry(openapi_path(&binding.path))
+                    .or_insert_with(|| json!({}));
+                path[binding.method.to_lowercase()] = operation;
+            }
+        }
+    }
+
+    Ok(json!({
+        "openapi": "3.1.0",
+        "info": { "title": title, "version": version },
+        "paths": paths,
+        "components": { "schemas": generator.into_defs() },
+    }))
+}
+
+fn parameters(
+    generator: &mut Generator,
+    descriptors: &Descriptors,
+    input: Option<&MessageDescriptor>,
+    binding: &http::HttpRule,
+) -> Vec<serde_json::Value> {
+    let variables = binding.path_variables();
+    let mut parameters = Vec::new();
+
+    for variable in &variables {
+        let schema = match input.and_then(|m| resolve_field(descriptors, m, variable)) {
+            Some(field) => generator.field_schema(field),
+            None => json!({ "type": "string" }),
+        };
+        parameters.push(json!({
+            "name": variable,
+            "in": "path",
+            "required": true,
+            "schema": schema,
+        }));
+    }
+
+    if let (Some(input), false) = (input, binding.body == "*") {
+        for field in input.fields() {
+            let bound = field.name() == binding.body
+                || variables
+                    .iter()
+                    .any(|v| v.split('.').next() == Some(field.name()));
+            let scalar = !matches!(
+                field.field_type(descriptors),
+                FieldType::Message(_) | FieldType::Group
+            );
+            if !bound && scalar {
+                parameters.push(json!({
+                    "name": field.json_name(),
+                    "in": "query",
+                    "schema": generator.field_schema(field),
+                }));
+            }
+        }
+    }
+
+    parameters
+}
+
+/// The schema of the whole message for `"*"`, or else of the named field of the message.
+fn body_schema(
+    generator: &mut Generator,
+    descriptors: &Descriptors,
+    message: Option<&MessageDescriptor>,
+    body: &str,
+) -> serde_json::Value {
+    match message {
+        Some(message) if body == "*" => generator.message_schema(message),
+        Some(message) => match resolve_field(descriptors, message, body) {
+            Some(field) => generator.field_schema(field),
+            None => json!({}),
+        },
+        None => json!({}),
+    }
+}
+
+/// Finds the field at a dot-separated path of field names.
+fn resolve_field<'a>(
+    descriptors: &'a Descriptors,
+    message: &'a MessageDescriptor,
+    path: &str,
+) -> Option<&'a FieldDescriptor> {
+    let mut message = message;
+    let mut names = path.split('.').peekable();
+    while let Some(name) = names.next() {
+        let field = message.field_by_name(name)?;
+        if names.peek().is_none() {
+            return Some(field);
+        }
+        match field.field_type(descriptors) {
+            FieldType::Message(m) => message = m,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Converts a path template to an OpenAPI path, e.g. `/v1/{name=shelves/*}` to `/v1/{name}`.
+fn openapi_path(template: &str) -> String {
+    let mut path = String::with_capacity(template.len());
+    let mut in_variable = false;
+    let mut in_pattern = false;
+    for c in template.chars() {
+        match c {
+            '{' => in_variable = true,
+            '=' if in_variable => in_pattern = true,
+            '}' => {
+                in_variable = false;
+                in_pattern = false;
+            }
+            _ => (),
+        }
+        if !in_pattern || c == '}' {
+            path.push(c);
+        }
+    }
+    path
+}
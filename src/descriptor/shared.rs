@@ -0,0 +1,95 @@
+//! A descriptor registry that can be replaced while it is in use.
+use std::mem;
+use std::sync;
+
+use super::Descriptors;
+
+/// A shared handle to a descriptor registry that can be swapped out atomically.
+///
+/// Readers call `load` to get the current registry, and keep using that version for as long as
+/// they hold on to it, even if a new one is stored in the meantime.  This lets long-running
+/// services pick up schema changes without restarting and without interrupting in-flight
+/// decoding.  Cloning the handle is cheap, and all clones share the same registry.
+///
+/// ```
+/// # extern crate protobuf;
+/// # extern crate serde_protobuf;
+/// # use std::fs;
+/// use serde_protobuf::descriptor::{Descriptors, SharedDescriptors};
+///
+/// # fn main() {
+/// let shared = SharedDescriptors::new(Descriptors::new());
+/// let old = shared.load();
+///
+/// # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+/// # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+/// shared.store(Descriptors::from_proto(&proto));
+///
+/// assert!(old.message_by_name(".protobuf_unittest.TestAllTypes").is_none());
+/// assert!(shared.load().message_by_name(".protobuf_unittest.TestAllTypes").is_some());
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SharedDescriptors {
+    current: sync::Arc<sync::RwLock<sync::Arc<Descriptors>>>,
+    // Serializes updates, so that readers are only blocked for the swap itself
+    update: sync::Arc<sync::Mutex<()>>,
+}
+
+impl SharedDescriptors {
+    /// Creates a new shared handle to the specified registry.
+    pub fn new(descriptors: Descriptors) -> SharedDescriptors {
+        SharedDescriptors {
+            current: sync::Arc::new(sync::RwLock::new(sync::Arc::new(descriptors))),
+            update: sync::Arc::new(sync::Mutex::new(())),
+        }
+    }
+
+    /// Returns the current version of the registry.
+    pub fn load(&self) -> sync::Arc<Descriptors> {
+        self.read().clone()
+    }
+
+    /// Replaces the registry with a new version, returning the previous one.
+    pub fn store(&self, descriptors: Descriptors) -> sync::Arc<Descriptors> {
+        let _update = lock(&self.update);
+        self.swap(sync::Arc::new(descriptors))
+    }
+
+    /// Replaces the registry with a version derived from the current one.
+    ///
+    /// Concurrent updates are applied one after another, so every update sees the result of the
+    /// previous one.  Readers keep seeing the current version until `f` returns.
+    pub fn update<F>(&self, f: F) -> sync::Arc<Descriptors>
+    where
+        F: FnOnce(&Descriptors) -> Descriptors,
+    {
+        let _update = lock(&self.update);
+        let descriptors = f(&self.load());
+        self.swap(sync::Arc::new(descriptors))
+    }
+
+    fn swap(&self, descriptors: sync::Arc<Descriptors>) -> sync::Arc<Descriptors> {
+        let mut current = self
+            .current
+            .write()
+            .unwrap_or_else(sync::PoisonError::into_inner);
+        mem::replace(&mut *current, descriptors)
+    }
+
+    fn read(&self) -> sync::RwLockReadGuard<'_, sync::Arc<Descriptors>> {
+        self.current
+            .read()
+            .unwrap_or_else(sync::PoisonError::into_inner)
+    }
+}
+
+impl From<Descriptors> for SharedDescriptors {
+    fn from(descriptors: Descriptors) -> SharedDescriptors {
+        SharedDescriptors::new(descriptors)
+    }
+}
+
+fn lock(mutex: &sync::Mutex<()>) -> sync::MutexGuard<'_, ()> {
+    mutex.lock().unwrap_or_else(sync::PoisonError::into_inner)
+}
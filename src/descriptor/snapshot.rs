@@ -0,0 +1,422 @@
+//! A compact binary format for fully resolved descriptor registries.
+//!
+//! A snapshot stores the registry as it is in memory, including the resolved type references, so
+//! that loading it neither parses `FileDescriptorProto`s nor resolves any names.  The format is
+//! internal to this crate and only meant to be read back by the same version of it.
+use protobuf;
+
+use super::{
+    Descriptors, EnumDescriptor, EnumId, EnumValueDescriptor, FieldDescriptor, FieldLabel,
+    InternalFieldType, MessageDescriptor, MessageId, MethodDescriptor, OneofDescriptor,
+    ServiceDescriptor, SourceLocation,
+};
+use crate::error;
+use crate::value;
+
+const MAGIC: &[u8; 4] = b"SPDS";
+const VERSION: u64 = 7;
+
+impl Descriptors {
+    /// Encodes this registry as a snapshot that `from_snapshot` can load again quickly.
+    ///
+    /// References should be resolved with `resolve_refs` first, so that loading the snapshot
+    /// doesn't have to do it again.
+    pub fn to_snapshot(&self) -> error::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            output.write_raw_bytes(MAGIC)?;
+            output.write_raw_varint64(VERSION)?;
+
+            output.write_raw_varint64(self.messages.len() as u64)?;
+            for message in &self.messages {
+                output.write_string_no_tag(&message.name)?;
+                output.write_message_no_tag(&message.options)?;
+                output.write_raw_varint64(message.oneofs.len() as u64)?;
+                for oneof in &message.oneofs {
+                    output.write_string_no_tag(&oneof.name)?;
+                }
+                output.write_raw_varint64(message.fields.len() as u64)?;
+                for field in &message.fields {
+                    write_field(&mut output, field)?;
+                }
+            }
+
+            output.write_raw_varint64(self.enums.len() as u64)?;
+            for e in &self.enums {
+                output.write_string_no_tag(&e.name)?;
+                output.write_raw_varint64(e.values.len() as u64)?;
+                for value in &e.values {
+                    output.write_string_no_tag(&value.name)?;
+                    output.write_int32_no_tag(value.number)?;
+                }
+            }
+
+            output.write_raw_varint64(self.services.len() as u64)?;
+            for service in &self.services {
+                output.write_string_no_tag(&service.name)?;
+                output.write_message_no_tag(&service.options)?;
+                output.write_raw_varint64(service.methods.len() as u64)?;
+                for method in &service.methods {
+                    output.write_string_no_tag(&method.name)?;
+                    output.write_string_no_tag(&method.input_type)?;
+                    output.write_string_no_tag(&method.output_type)?;
+                    output.write_bool_no_tag(method.client_streaming)?;
+                    output.write_bool_no_tag(method.server_streaming)?;
+                    output.write_message_no_tag(&method.options)?;
+                }
+            }
+
+            output.write_raw_varint64(self.files_by_type.len() as u64)?;
+            for (name, file) in &self.files_by_type {
+                output.write_string_no_tag(name)?;
+                output.write_string_no_tag(file)?;
+            }
+
+            output.write_raw_varint64(self.syntax_by_file.len() as u64)?;
+            for (file, syntax) in &self.syntax_by_file {
+                output.write_string_no_tag(file)?;
+                output.write_string_no_tag(syntax)?;
+            }
+
+            output.write_raw_varint64(self.source_locations.len() as u64)?;
+            for (name, location) in &self.source_locations {
+                output.write_string_no_tag(name)?;
+                write_source_location(&mut output, location)?;
+            }
+            output.flush()?;
+        }
+        Ok(bytes)
+    }
+
+    /// Loads a registry from a snapshot that was created by `to_snapshot`.
+    pub fn from_snapshot(bytes: &[u8]) -> error::Result<Descriptors> {
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        if input.read_raw_bytes(MAGIC.len() as u32)? != MAGIC
+            || input.read_raw_varint64()? != VERSION
+        {
+            return Err(bad_snapshot());
+        }
+
+        let mut descriptors = Descriptors::new();
+
+        let message_count = input.read_raw_varint64()?;
+        for _ in 0..message_count {
+            let name = input.read_string()?;
+            let options = input.read_message()?;
+            let mut message = MessageDescriptor::new(name).with_options(options);
+            for _ in 0..input.read_raw_varint64()? {
+                message.add_oneof(OneofDescriptor::new(input.read_string()?));
+            }
+            for _ in 0..input.read_raw_varint64()? {
+                message.add_field(read_field(&mut input)?);
+            }
+            descriptors.add_message(message);
+        }
+
+        for _ in 0..input.read_raw_varint64()? {
+            let mut e = EnumDescriptor::new(input.read_string()?);
+            for _ in 0..input.read_raw_varint64()? {
+                let name = input.read_string()?;
+                e.add_value(EnumValueDescriptor::new(name, input.read_int32()?));
+            }
+            descriptors.add_enum(e);
+        }
+
+        for _ in 0..input.read_raw_varint64()? {
+            let name = input.read_string()?;
+            let options = input.read_message()?;
+            let mut service = ServiceDescriptor::new(name).with_options(options);
+            for _ in 0..input.read_raw_varint64()? {
+                let name = input.read_string()?;
+                let input_type = input.read_string()?;
+                let output_type = input.read_string()?;
+                let client_streaming = input.read_bool()?;
+                let server_streaming = input.read_bool()?;
+                let options = input.read_message()?;
+                service.add_method(
+                    MethodDescriptor::new(name, input_type, output_type)
+                        .with_streaming(client_streaming, server_streaming)
+                        .with_options(options),
+                );
+            }
+            descriptors.add_service(service);
+        }
+
+        for _ in 0..input.read_raw_varint64()? {
+            let name = input.read_string()?;
+            let file = input.read_string()?;
+            descriptors.files_by_type.insert(name, file);
+        }
+
+        for _ in 0..input.read_raw_varint64()? {
+            let file = input.read_string()?;
+            let syntax = input.read_string()?;
+            descriptors.syntax_by_file.insert(file, syntax);
+        }
+
+        for _ in 0..input.read_raw_varint64()? {
+            let name = input.read_string()?;
+            let location = read_source_location(&mut input)?;
+            descriptors.source_locations.insert(name, location);
+        }
+
+        if !input.eof()? {
+            return Err(bad_snapshot());
+        }
+
+        // Resolved references must point at descriptors that exist
+        for message in &descriptors.messages {
+            for field in &message.fields {
+                match field.field_type {
+                    InternalFieldType::Message(MessageId(i)) if i >= descriptors.messages.len() => {
+                        return Err(bad_snapshot())
+                    }
+                    InternalFieldType::Enum(EnumId(i)) if i >= descriptors.enums.len() => {
+                        return Err(bad_snapshot())
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(descriptors)
+    }
+}
+
+fn write_field(
+    output: &mut protobuf::CodedOutputStream,
+    field: &FieldDescriptor,
+) -> error::Result<()> {
+    output.write_string_no_tag(&field.name)?;
+    output.write_int32_no_tag(field.number)?;
+    output.write_raw_varint32(match field.field_label {
+        FieldLabel::Optional => 0,
+        FieldLabel::Required => 1,
+        FieldLabel::Repeated => 2,
+    })?;
+
+    match field.field_type {
+        InternalFieldType::UnresolvedMessage(ref name) => {
+            output.write_raw_varint32(0)?;
+            output.write_string_no_tag(name)?;
+        }
+        InternalFieldType::UnresolvedEnum(ref name) => {
+            output.write_raw_varint32(1)?;
+            output.write_string_no_tag(name)?;
+        }
+        InternalFieldType::Message(MessageId(i)) => {
+            output.write_raw_varint32(2)?;
+            output.write_raw_varint64(i as u64)?;
+        }
+        InternalFieldType::Enum(EnumId(i)) => {
+            output.write_raw_varint32(3)?;
+            output.write_raw_varint64(i as u64)?;
+        }
+        ref scalar => output.write_raw_varint32(4 + scalar_tag(scalar))?,
+    }
+
+    match field.default_value {
+        None => output.write_raw_varint32(0)?,
+        Some(value::Value::Bool(v)) => {
+            output.write_raw_varint32(1)?;
+            output.write_bool_no_tag(v)?;
+        }
+        Some(value::Value::I32(v)) => {
+            output.write_raw_varint32(2)?;
+            output.write_int32_no_tag(v)?;
+        }
+        Some(value::Value::I64(v)) => {
+            output.write_raw_varint32(3)?;
+            output.write_int64_no_tag(v)?;
+        }
+        Some(value::Value::U32(v)) => {
+            output.write_raw_varint32(4)?;
+            output.write_uint32_no_tag(v)?;
+        }
+        Some(value::Value::U64(v)) => {
+            output.write_raw_varint32(5)?;
+            output.write_uint64_no_tag(v)?;
+        }
+        Some(value::Value::F32(v)) => {
+            output.write_raw_varint32(6)?;
+            output.write_float_no_tag(v)?;
+        }
+        Some(value::Value::F64(v)) => {
+            output.write_raw_varint32(7)?;
+            output.write_double_no_tag(v)?;
+        }
+        Some(value::Value::Bytes(ref v)) => {
+            output.write_raw_varint32(8)?;
+            output.write_bytes_no_tag(v)?;
+        }
+        Some(value::Value::String(ref v)) => {
+            output.write_raw_varint32(9)?;
+            output.write_string_no_tag(v)?;
+        }
+        Some(value::Value::Enum(v)) => {
+            output.write_raw_varint32(10)?;
+            output.write_int32_no_tag(v)?;
+        }
+        Some(value::Value::Message(_))
+        | Some(value::Value::FieldMask(_))
+        | Some(value::Value::BytesStream(_))
+        | Some(value::Value::Custom(_))
+        | Some(value::Value::Null) => {
+            return Err(error::Error::BadDefaultValue {
+                default_value: field.name.clone(),
+            })
+        }
+    }
+
+    output.write_string_no_tag(&field.json_name)?;
+    output.write_message_no_tag(&field.options)?;
+    output.write_raw_varint64(field.oneof_index.map_or(0, |i| i as u64 + 1))?;
+    output.write_bool_no_tag(field.proto3_optional)?;
+    output.write_bool_no_tag(field.packed)?;
+    output.write_bool_no_tag(field.implicit_presence)?;
+    Ok(())
+}
+
+fn read_field(input: &mut protobuf::CodedInputStream) -> error::Result<FieldDescriptor> {
+    let name = input.read_string()?;
+    let number = input.read_int32()?;
+    let field_label = match input.read_raw_varint32()? {
+        0 => FieldLabel::Optional,
+        1 => FieldLabel::Required,
+        2 => FieldLabel::Repeated,
+        _ => return Err(bad_snapshot()),
+    };
+
+    let field_type = match input.read_raw_varint32()? {
+        0 => InternalFieldType::UnresolvedMessage(input.read_string()?),
+        1 => InternalFieldType::UnresolvedEnum(input.read_string()?),
+        2 => InternalFieldType::Message(MessageId(input.read_raw_varint64()? as usize)),
+        3 => InternalFieldType::Enum(EnumId(input.read_raw_varint64()? as usize)),
+        tag => scalar_from_tag(tag.wrapping_sub(4)).ok_or_else(bad_snapshot)?,
+    };
+
+    let default_value = match input.read_raw_varint32()? {
+        0 => None,
+        1 => Some(value::Value::Bool(input.read_bool()?)),
+        2 => Some(value::Value::I32(input.read_int32()?)),
+        3 => Some(value::Value::I64(input.read_int64()?)),
+        4 => Some(value::Value::U32(input.read_uint32()?)),
+        5 => Some(value::Value::U64(input.read_uint64()?)),
+        6 => Some(value::Value::F32(input.read_float()?)),
+        7 => Some(value::Value::F64(input.read_double()?)),
+        8 => Some(value::Value::Bytes(input.read_bytes()?)),
+        9 => Some(value::Value::String(input.read_string()?)),
+        10 => Some(value::Value::Enum(input.read_int32()?)),
+        _ => return Err(bad_snapshot()),
+    };
+
+    let json_name = input.read_string()?;
+    let options = input.read_message()?;
+    let oneof_index = input.read_raw_varint64()?;
+    let mut field = FieldDescriptor::new(name, number, field_label, field_type, default_value)
+        .with_json_name(json_name)
+        .with_options(options)
+        .with_proto3_optional(input.read_bool()?)
+        .with_packed(input.read_bool()?)
+        .with_implicit_presence(input.read_bool()?);
+    if oneof_index > 0 {
+        field = field.with_oneof_index(oneof_index as usize - 1);
+    }
+    Ok(field)
+}
+
+fn write_source_location(
+    output: &mut protobuf::CodedOutputStream,
+    location: &SourceLocation,
+) -> error::Result<()> {
+    output.write_string_no_tag(&location.file)?;
+    output.write_uint32_no_tag(location.start_line)?;
+    output.write_uint32_no_tag(location.start_column)?;
+    output.write_uint32_no_tag(location.end_line)?;
+    output.write_uint32_no_tag(location.end_column)?;
+    for comments in &[&location.leading_comments, &location.trailing_comments] {
+        output.write_bool_no_tag(comments.is_some())?;
+        if let Some(ref comments) = **comments {
+            output.write_string_no_tag(comments)?;
+        }
+    }
+    output.write_raw_varint64(location.leading_detached_comments.len() as u64)?;
+    for comments in &location.leading_detached_comments {
+        output.write_string_no_tag(comments)?;
+    }
+    Ok(())
+}
+
+fn read_source_location(input: &mut protobuf::CodedInputStream) -> error::Result<SourceLocation> {
+    let mut location = SourceLocation {
+        file: input.read_string()?,
+        start_line: input.read_uint32()?,
+        start_column: input.read_uint32()?,
+        end_line: input.read_uint32()?,
+        end_column: input.read_uint32()?,
+        ..SourceLocation::default()
+    };
+    if input.read_bool()? {
+        location.leading_comments = Some(input.read_string()?);
+    }
+    if input.read_bool()? {
+        location.trailing_comments = Some(input.read_string()?);
+    }
+    for _ in 0..input.read_raw_varint64()? {
+        location
+            .leading_detached_comments
+            .push(input.read_string()?);
+    }
+    Ok(location)
+}
+
+fn scalar_tag(field_type: &InternalFieldType) -> u32 {
+    match *field_type {
+        InternalFieldType::Double => 0,
+        InternalFieldType::Float => 1,
+        InternalFieldType::Int64 => 2,
+        InternalFieldType::UInt64 => 3,
+        InternalFieldType::Int32 => 4,
+        InternalFieldType::Fixed64 => 5,
+        InternalFieldType::Fixed32 => 6,
+        InternalFieldType::Bool => 7,
+        InternalFieldType::String => 8,
+        InternalFieldType::Group => 9,
+        InternalFieldType::Bytes => 10,
+        InternalFieldType::UInt32 => 11,
+        InternalFieldType::SFixed32 => 12,
+        InternalFieldType::SFixed64 => 13,
+        InternalFieldType::SInt32 => 14,
+        InternalFieldType::SInt64 => 15,
+        _ => unreachable!("not a scalar type"),
+    }
+}
+
+fn scalar_from_tag(tag: u32) -> Option<InternalFieldType> {
+    Some(match tag {
+        0 => InternalFieldType::Double,
+        1 => InternalFieldType::Float,
+        2 => InternalFieldType::Int64,
+        3 => InternalFieldType::UInt64,
+        4 => InternalFieldType::Int32,
+        5 => InternalFieldType::Fixed64,
+        6 => InternalFieldType::Fixed32,
+        7 => InternalFieldType::Bool,
+        8 => InternalFieldType::String,
+        9 => InternalFieldType::Group,
+        10 => InternalFieldType::Bytes,
+        11 => InternalFieldType::UInt32,
+        12 => InternalFieldType::SFixed32,
+        13 => InternalFieldType::SFixed64,
+        14 => InternalFieldType::SInt32,
+        15 => InternalFieldType::SInt64,
+        _ => return None,
+    })
+}
+
+fn bad_snapshot() -> error::Error {
+    error::Error::Custom {
+        message: "bad descriptor snapshot".to_owned(),
+    }
+}
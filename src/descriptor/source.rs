@@ -0,0 +1,172 @@
+//! Source locations and comments of definitions, from the `SourceCodeInfo` of file descriptors.
+//!
+//! `protoc` only includes source code info in descriptor sets when run with
+//! `--include_source_info`.
+use protobuf::descriptor;
+
+use super::Descriptors;
+
+/// The location of a definition in its `.proto` file, along with its comments.
+///
+/// Lines and columns are zero-based, like in `descriptor.proto`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceLocation {
+    /// The name of the file, e.g. `foo/bar.proto`.
+    pub file: String,
+    /// The line where the definition starts.
+    pub start_line: u32,
+    /// The column where the definition starts.
+    pub start_column: u32,
+    /// The line where the definition ends.
+    pub end_line: u32,
+    /// The column after the end of the definition.
+    pub end_column: u32,
+    /// The comment right before the definition, without comment markers.
+    pub leading_comments: Option<String>,
+    /// The comment right after the definition, without comment markers.
+    pub trailing_comments: Option<String>,
+    /// The comments before the definition that are separated from it by blank lines.
+    pub leading_detached_comments: Vec<String>,
+}
+
+impl Descriptors {
+    /// The source location of the definition with the specified fully qualified name, if its file
+    /// descriptor had source code info.
+    ///
+    /// Messages, enums and services are named like `.foo.package.Message`, and their fields,
+    /// oneofs, enum values and methods by appending their name, e.g. `.foo.package.Message.field`
+    /// or `.foo.package.Enum.VALUE`.
+    pub fn source_location(&self, name: &str) -> Option<&SourceLocation> {
+        self.source_locations.get(name)
+    }
+
+    /// Records the source locations of the definitions in the file descriptor.
+    pub(super) fn add_source_locations(&mut self, file_proto: &descriptor::FileDescriptorProto) {
+        if !file_proto.has_source_code_info() {
+            return;
+        }
+        let scope = if file_proto.has_package() {
+            format!(".{}", file_proto.get_package())
+        } else {
+            String::new()
+        };
+
+        for location in file_proto.get_source_code_info().get_location() {
+            let name = match element_name(file_proto, &scope, location.get_path()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let span = location.get_span();
+            let (start_line, start_column, end_line, end_column) = match *span {
+                [a, b, c] => (a, b, a, c),
+                [a, b, c, d] => (a, b, c, d),
+                _ => continue,
+            };
+            let comments = |present: bool, comments: &str| {
+                if present {
+                    Some(comments.to_owned())
+                } else {
+                    None
+                }
+            };
+            self.source_locations.insert(
+                name,
+                SourceLocation {
+                    file: file_proto.get_name().to_owned(),
+                    start_line: start_line as u32,
+                    start_column: start_column as u32,
+                    end_line: end_line as u32,
+                    end_column: end_column as u32,
+                    leading_comments: comments(
+                        location.has_leading_comments(),
+                        location.get_leading_comments(),
+                    ),
+                    trailing_comments: comments(
+                        location.has_trailing_comments(),
+                        location.get_trailing_comments(),
+                    ),
+                    leading_detached_comments: location.get_leading_detached_comments().to_vec(),
+                },
+            );
+        }
+    }
+}
+
+/// The fully qualified name of the definition at a source code info path, if the path leads to
+/// a named definition rather than to a part of one.
+fn element_name(
+    file: &descriptor::FileDescriptorProto,
+    scope: &str,
+    path: &[i32],
+) -> Option<String> {
+    let (kind, index, rest) = match *path {
+        [kind, index, ref rest @ ..] => (kind, index as usize, rest),
+        _ => return None,
+    };
+    match (kind, rest) {
+        // FileDescriptorProto.message_type
+        (4, _) => message_element(file.get_message_type().get(index)?, scope, rest),
+        // FileDescriptorProto.enum_type
+        (5, _) => enum_element(file.get_enum_type().get(index)?, scope, rest),
+        // FileDescriptorProto.service and ServiceDescriptorProto.method
+        (6, _) => {
+            let service = file.get_service().get(index)?;
+            let name = format!("{}.{}", scope, service.get_name());
+            match *rest {
+                [] => Some(name),
+                [2, method] => {
+                    let method = service.get_method().get(method as usize)?;
+                    Some(format!("{}.{}", name, method.get_name()))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn message_element(
+    message: &descriptor::DescriptorProto,
+    scope: &str,
+    path: &[i32],
+) -> Option<String> {
+    let name = format!("{}.{}", scope, message.get_name());
+    let (kind, index, rest) = match *path {
+        [] => return Some(name),
+        [kind, index, ref rest @ ..] => (kind, index as usize, rest),
+        _ => return None,
+    };
+    match (kind, rest) {
+        // DescriptorProto.field
+        (2, []) => Some(format!(
+            "{}.{}",
+            name,
+            message.get_field().get(index)?.get_name()
+        )),
+        // DescriptorProto.nested_type
+        (3, _) => message_element(message.get_nested_type().get(index)?, &name, rest),
+        // DescriptorProto.enum_type
+        (4, _) => enum_element(message.get_enum_type().get(index)?, &name, rest),
+        // DescriptorProto.oneof_decl
+        (8, []) => Some(format!(
+            "{}.{}",
+            name,
+            message.get_oneof_decl().get(index)?.get_name()
+        )),
+        _ => None,
+    }
+}
+
+fn enum_element(e: &descriptor::EnumDescriptorProto, scope: &str, path: &[i32]) -> Option<String> {
+    let name = format!("{}.{}", scope, e.get_name());
+    match *path {
+        [] => Some(name),
+        // EnumDescriptorProto.value
+        [2, value] => Some(format!(
+            "{}.{}",
+            name,
+            e.get_value().get(value as usize)?.get_name()
+        )),
+        _ => None,
+    }
+}
@@ -0,0 +1,245 @@
+//! Rich diagnostics for errors, for command line and developer tools.
+//!
+//! A `Diagnostic` wraps an `Error` together with what it is about, and implements
+//! `miette::Diagnostic`: errors at a byte offset of the input are labeled in a hex dump of the
+//! input, and misspelled field and message type names get suggestions like "did you mean field
+//! `amount_e8s`?".  Report handlers of `miette` render them, and `miette::Report` and
+//! `anyhow::Error` can wrap them; with the `anyhow` feature as well, `Diagnostic::into_anyhow`
+//! keeps the rendered labels and help when an `anyhow::Error` is printed.
+//!
+//! This module is only available with the `miette` feature.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::diagnostic::Diagnostic;
+//! use serde_protobuf::value::{DecodeOptions, Message};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.TestAllTypes")
+//!     .unwrap();
+//!
+//! // optional_int32 = 5, with a padded varint
+//! let input = [0x08, 0x85, 0x00];
+//! let mut message = Message::new(descriptor);
+//! let error = message
+//!     .merge_from_with_options(
+//!         &descriptors,
+//!         descriptor,
+//!         &DecodeOptions::new().strict(true),
+//!         &mut protobuf::CodedInputStream::from_bytes(&input),
+//!     )
+//!     .unwrap_err();
+//!
+//! let diagnostic = Diagnostic::new(error).with_input(&input);
+//! let mut report = String::new();
+//! miette::NarratableReportHandler::new()
+//!     .render_report(&mut report, &diagnostic)
+//!     .unwrap();
+//! assert!(report.contains("non-minimal varint at byte 1"));
+//! ```
+use std::cmp;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fmt::Write;
+
+use miette::{LabeledSpan, SourceCode};
+
+use crate::descriptor;
+use crate::error;
+
+/// An error along with the input and message type that it is about, as a `miette` diagnostic.
+#[derive(Debug)]
+pub struct Diagnostic {
+    error: error::Error,
+    help: Option<String>,
+    // A hex dump of the input around the position of the error, and the label in it
+    dump: Option<String>,
+    label: Option<LabeledSpan>,
+}
+
+// The number of bytes in a line of a hex dump, and the number of lines shown before and after the
+// line of the position of an error
+const BYTES_PER_LINE: usize = 16;
+const CONTEXT_LINES: usize = 2;
+
+impl Diagnostic {
+    /// Creates a diagnostic for an error.
+    pub fn new(error: error::Error) -> Diagnostic {
+        let help = match error {
+            error::Error::UnknownMessageType {
+                ref name,
+                ref available,
+            } => closest(name, available.iter().map(String::as_str))
+                .map(|n| format!("did you mean message type `{}`?", n)),
+            _ => None,
+        };
+        Diagnostic {
+            error,
+            help,
+            dump: None,
+            label: None,
+        }
+    }
+
+    /// Attaches the input that was being decoded, so that errors at a byte offset point at it.
+    pub fn with_input(mut self, input: &[u8]) -> Diagnostic {
+        let label = match self.error {
+            error::Error::BadWireType { .. } => "this value has the wrong wire type",
+            error::Error::BadFieldNumber { .. } => "this tag has an invalid field number",
+            error::Error::UndefinedWireType { .. } => "this tag has an undefined wire type",
+            error::Error::UnexpectedEof { .. } => "this field is cut off",
+            error::Error::NoProgress { .. } => "decoding got stuck here",
+            error::Error::NonMinimalVarint { .. } => "this varint is padded",
+            error::Error::RecursionLimitExceeded { .. } => "this message is nested too deeply",
+            error::Error::LengthOutOfBounds { .. } => "this length is out of bounds",
+            _ => return self,
+        };
+        let position = match self.error.position() {
+            Some(position) if position < input.len() as u64 => position as usize,
+            _ => return self,
+        };
+
+        let line = position / BYTES_PER_LINE;
+        let first = line.saturating_sub(CONTEXT_LINES);
+        let last = cmp::min(line + CONTEXT_LINES, (input.len() - 1) / BYTES_PER_LINE);
+        let mut dump = String::new();
+        let mut offset = 0;
+        for l in first..=last {
+            let start = l * BYTES_PER_LINE;
+            let end = cmp::min(start + BYTES_PER_LINE, input.len());
+            write!(dump, "{:08x} ", start).unwrap();
+            for (i, byte) in input[start..end].iter().enumerate() {
+                if start + i == position {
+                    offset = dump.len() + 1;
+                }
+                write!(dump, " {:02x}", byte).unwrap();
+            }
+            dump.push('\n');
+        }
+        self.dump = Some(dump);
+        self.label = Some(LabeledSpan::at(offset..offset + 2, label));
+        self
+    }
+
+    /// Converts this diagnostic into an `anyhow::Error`, with the rendered report as its message.
+    ///
+    /// Printing an `anyhow::Error` only shows the messages of its chain of errors, so the labeled
+    /// hex dump and help would otherwise be lost.  The diagnostic remains available with
+    /// `downcast_ref`.  Only available with the `anyhow` feature.
+    #[cfg(feature = "anyhow")]
+    pub fn into_anyhow(self) -> anyhow::Error {
+        let mut report = String::new();
+        match miette::NarratableReportHandler::new().render_report(&mut report, &self) {
+            Ok(()) => anyhow::Error::new(self).context(report.trim_end().to_owned()),
+            Err(_) => anyhow::Error::new(self),
+        }
+    }
+
+    /// Attaches the message type that was being handled, so that unknown field names get
+    /// suggestions of similar field names.
+    pub fn with_message(mut self, message: &descriptor::MessageDescriptor) -> Diagnostic {
+        if let error::Error::UnknownField { ref name } = self.error {
+            if let Some(field) = closest(name, message.fields().iter().map(|f| f.name())) {
+                self.help = Some(format!("did you mean field `{}`?", field));
+            }
+        }
+        self
+    }
+
+    /// The underlying error.
+    pub fn error(&self) -> &error::Error {
+        &self.error
+    }
+
+    /// Converts this diagnostic into the underlying error.
+    pub fn into_error(self) -> error::Error {
+        self.error
+    }
+}
+
+impl From<error::Error> for Diagnostic {
+    fn from(error: error::Error) -> Self {
+        Diagnostic::new(error)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl StdError for Diagnostic {}
+
+impl miette::Diagnostic for Diagnostic {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(self.error.code()))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|h| -> Box<dyn fmt::Display + 'a> { Box::new(h) })
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.dump.as_ref().map(|d| -> &dyn SourceCode { d })
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.label
+            .clone()
+            .map(|l| -> Box<dyn Iterator<Item = LabeledSpan>> { Box::new(Some(l).into_iter()) })
+    }
+}
+
+/// The candidate that is closest to a name, if it is close enough to be a likely misspelling.
+fn closest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let max_distance = cmp::max(1, name.chars().count() / 3);
+    candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= max_distance)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+/// The Levenshtein distance between two strings, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cmp::min(substitution, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(0, edit_distance("amount", "amount"));
+        assert_eq!(1, edit_distance("amount_e8", "amount_e8s"));
+        assert_eq!(2, edit_distance("ammount_e8", "amount_e8s"));
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+        assert_eq!(
+            Some("amount_e8s"),
+            closest("amount_e8", ["id", "amount_e8s"].iter().cloned())
+        );
+        assert_eq!(None, closest("memo", ["id", "amount_e8s"].iter().cloned()));
+    }
+}
@@ -0,0 +1,217 @@
+//! Decoding of envelope messages, whose payload is a message of the type that they name.
+//!
+//! Event logs and queues often wrap messages of many types in a single envelope message type,
+//! with a field for the name of the payload type and a `bytes` field for the encoded payload:
+//!
+//! ```protobuf
+//! message Envelope {
+//!   string type_name = 1;
+//!   bytes payload = 2;
+//!   int64 timestamp = 3;
+//! }
+//! ```
+//!
+//! An `Envelope` decodes such a message, looks up the payload type by its name and decodes the
+//! payload as a dynamic message of that type.  The type name can be a fully qualified name, with
+//! or without the leading `.`, or a `google.protobuf.Any` type URL.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! # use serde_protobuf::value::{Field, Value};
+//! use serde_protobuf::envelope::Envelope;
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let mut descriptors = Descriptors::from_proto(&proto);
+//! # let mut any_file = protobuf::descriptor::FileDescriptorProto::new();
+//! # any_file.set_name("google/protobuf/any.proto".to_owned());
+//! # any_file.set_package("google.protobuf".to_owned());
+//! # let any = protobuf::reflect::MessageDescriptor::for_type::<protobuf::well_known_types::Any>();
+//! # any_file.mut_message_type().push(any.get_proto().clone());
+//! # descriptors.add_file_proto(&any_file);
+//! // google.protobuf.Any is an envelope with the type name and payload in fields 1 and 2
+//! let any = descriptors.message_by_name(".google.protobuf.Any").unwrap();
+//!
+//! // type_url: "protobuf_unittest.ForeignMessage", value: { c: 5 }
+//! let mut bytes = vec![0x0a, 32];
+//! bytes.extend_from_slice(b"protobuf_unittest.ForeignMessage");
+//! bytes.extend_from_slice(&[0x12, 2, 0x08, 5]);
+//!
+//! let decoded = Envelope::new().decode(&descriptors, any, &bytes).unwrap();
+//! let payload = decoded.payload.unwrap();
+//! match payload.fields.get(&1) {
+//!     Some(Field::Singular(Some(Value::I32(5)))) => (),
+//!     f => panic!("unexpected field {:?}", f),
+//! }
+//! ```
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// Which fields of an envelope message hold the payload type name and the payload.
+///
+/// By default, these are fields 1 and 2, like in `google.protobuf.Any`.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    type_name: FieldRef,
+    payload: FieldRef,
+}
+
+/// An envelope message, along with its decoded payload.
+#[derive(Clone, Debug)]
+pub struct DecodedEnvelope {
+    /// The envelope message, including its payload in encoded form.
+    pub envelope: value::Message,
+    /// The fully qualified name of the payload type, if the envelope names one.
+    pub payload_type: Option<String>,
+    /// The decoded payload, if the envelope names a payload type.
+    pub payload: Option<value::Message>,
+}
+
+#[derive(Clone, Debug)]
+enum FieldRef {
+    Name(String),
+    Number(i32),
+}
+
+impl Envelope {
+    /// Creates an envelope with the type name in field 1 and the payload in field 2.
+    pub fn new() -> Envelope {
+        Envelope::default()
+    }
+
+    /// Looks up the field with the type name by its name.
+    pub fn type_name_field(mut self, name: &str) -> Envelope {
+        self.type_name = FieldRef::Name(name.to_owned());
+        self
+    }
+
+    /// Looks up the field with the type name by its number.
+    pub fn type_name_number(mut self, number: i32) -> Envelope {
+        self.type_name = FieldRef::Number(number);
+        self
+    }
+
+    /// Looks up the field with the payload by its name.
+    pub fn payload_field(mut self, name: &str) -> Envelope {
+        self.payload = FieldRef::Name(name.to_owned());
+        self
+    }
+
+    /// Looks up the field with the payload by its number.
+    pub fn payload_number(mut self, number: i32) -> Envelope {
+        self.payload = FieldRef::Number(number);
+        self
+    }
+
+    /// Decodes an envelope message of the specified type, and its payload.
+    pub fn decode(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        envelope: &descriptor::MessageDescriptor,
+        bytes: &[u8],
+    ) -> error::Result<DecodedEnvelope> {
+        let mut message = value::Message::new(envelope);
+        message.merge_from(
+            descriptors,
+            envelope,
+            &mut protobuf::CodedInputStream::from_bytes(bytes),
+        )?;
+        let payload = self.open(descriptors, envelope, &message)?;
+        Ok(DecodedEnvelope {
+            envelope: message,
+            payload_type: payload.as_ref().map(|p| p.0.name().to_owned()),
+            payload: payload.map(|p| p.1),
+        })
+    }
+
+    /// Decodes the payload of an envelope message that is already decoded, along with the
+    /// descriptor of its type.
+    ///
+    /// Returns `None` if the type name is empty.  A type name that isn't in the registry is an
+    /// `UnknownMessageType` error.
+    pub fn open<'a>(
+        &self,
+        descriptors: &'a descriptor::Descriptors,
+        envelope: &descriptor::MessageDescriptor,
+        message: &value::Message,
+    ) -> error::Result<Option<(&'a descriptor::MessageDescriptor, value::Message)>> {
+        let type_name = match self.type_name.value(envelope, message)? {
+            None => return Ok(None),
+            Some((_, value::Value::String(ref s))) if s.is_empty() => return Ok(None),
+            Some((_, value::Value::String(ref s))) => s,
+            Some((field, v)) => return Err(type_mismatch(field, "string", v)),
+        };
+        let payload: &[u8] = match self.payload.value(envelope, message)? {
+            None => &[],
+            Some((_, value::Value::Bytes(ref b))) => b,
+            Some((field, v)) => return Err(type_mismatch(field, "bytes", v)),
+        };
+
+        let payload_type = descriptors.message_by_type_url(type_name).ok_or_else(|| {
+            error::Error::UnknownMessageType {
+                name: type_name.clone(),
+                available: descriptors
+                    .messages()
+                    .iter()
+                    .map(|m| m.name().to_owned())
+                    .collect(),
+            }
+        })?;
+        let mut decoded = value::Message::new(payload_type);
+        decoded.merge_from(
+            descriptors,
+            payload_type,
+            &mut protobuf::CodedInputStream::from_bytes(payload),
+        )?;
+        Ok(Some((payload_type, decoded)))
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope {
+            type_name: FieldRef::Number(1),
+            payload: FieldRef::Number(2),
+        }
+    }
+}
+
+impl FieldRef {
+    /// The field of the envelope type that this refers to, and its value in the message.
+    fn value<'a, 'b>(
+        &self,
+        envelope: &'a descriptor::MessageDescriptor,
+        message: &'b value::Message,
+    ) -> error::Result<Option<(&'a descriptor::FieldDescriptor, &'b value::Value)>> {
+        let field = match *self {
+            FieldRef::Name(ref name) => envelope
+                .field_by_name(name)
+                .ok_or_else(|| error::Error::UnknownField { name: name.clone() })?,
+            FieldRef::Number(number) => {
+                envelope
+                    .field_by_number(number)
+                    .ok_or(error::Error::UnknownFieldNumber {
+                        number: number as u32,
+                    })?
+            }
+        };
+        Ok(match message.fields.get(&field.number()) {
+            Some(value::Field::Singular(Some(v))) => Some((field, v)),
+            _ => None,
+        })
+    }
+}
+
+fn type_mismatch(
+    field: &descriptor::FieldDescriptor,
+    expected: &str,
+    value: &value::Value,
+) -> error::Error {
+    error::Error::TypeMismatch {
+        field: field.name().to_owned(),
+        expected: expected.to_owned(),
+        actual: value.kind(),
+    }
+}
@@ -1,17 +1,24 @@
 //! Common error types for this crate.
 use std::error;
 use std::fmt;
+use std::io;
 use std::result;
 
 use protobuf;
 use protobuf::stream::wire_format;
 use serde;
 
+use crate::descriptor;
+
 /// A result whose error type is `Error`.
 pub type Result<A> = result::Result<A, Error>;
 
 /// An error that may occur when dealing with Protobuf.
+///
+/// New kinds of errors may be added in minor releases; use `code` to tell them apart by a name
+/// that stays the same across releases.
 #[derive(Debug, Fail)]
+#[non_exhaustive]
 pub enum Error {
     /// A native protobuf error.
     #[fail(display = "protobuf error")]
@@ -37,11 +44,221 @@ pub enum Error {
         /// The name of the message.
         name: String,
     },
+    /// A message type was looked up by a name that isn't in the registry.
+    #[fail(
+        display = "unknown message type: {} (known types: {:?})",
+        name, available
+    )]
+    UnknownMessageType {
+        /// The name that was looked up.
+        name: String,
+        /// The fully qualified names of the message types in the registry.
+        available: Vec<String>,
+    },
+    /// A message was converted to a generated message type that its descriptor doesn't describe.
+    #[fail(
+        display = "message type mismatch: expected {}, got {}",
+        expected, actual
+    )]
+    MessageTypeMismatch {
+        /// The fully qualified name of the generated message type.
+        expected: String,
+        /// The fully qualified name of the message descriptor.
+        actual: String,
+    },
+    /// Two descriptor sets contain different definitions of the same file or type.
+    #[fail(
+        display = "conflicting definitions of {} in {} and {}",
+        name, first, second
+    )]
+    DescriptorConflict {
+        /// The name of the file or the fully qualified name of the type.
+        name: String,
+        /// The path of the first descriptor set that defines it.
+        first: String,
+        /// The path of the descriptor set with the conflicting definition.
+        second: String,
+    },
+    /// Descriptor registries that were merged define types differently.
+    #[fail(display = "conflicting type definitions: {:?}", conflicts)]
+    TypeConflicts {
+        /// The conflicting types.
+        conflicts: Vec<descriptor::TypeConflict>,
+    },
+    /// The `protoc` compiler failed to compile `.proto` files.
+    #[fail(display = "protoc failed: {}", message)]
+    ProtocFailed {
+        /// The error output of the compiler.
+        message: String,
+    },
     /// An unexpected wire type was received.
-    #[fail(display = "bad wire type: {:?}", wire_type)]
+    #[fail(display = "bad wire type {:?} at byte {}", wire_type, position)]
     BadWireType {
         /// The encountered wire type.
         wire_type: wire_format::WireType,
+        /// The position in the input of the value of the field.
+        position: u64,
+    },
+    /// A tag with field number 0, or one above the largest valid field number, was encountered
+    /// while decoding, or a field with such a number was about to be encoded.
+    #[fail(display = "bad field number: {}", number)]
+    BadFieldNumber {
+        /// The encountered field number.
+        number: u64,
+        /// The position of the tag in the input, or `None` when encoding.
+        position: Option<u64>,
+    },
+    /// A tag with one of the undefined wire types 6 and 7 was encountered.
+    #[fail(display = "undefined wire type {} at byte {}", wire_type, position)]
+    UndefinedWireType {
+        /// The encountered wire type.
+        wire_type: u32,
+        /// The position of the tag in the input.
+        position: u64,
+    },
+    /// The input ended in the middle of a field.
+    #[fail(display = "unexpected end of input in the field at byte {}", position)]
+    UnexpectedEof {
+        /// The position of the tag of the truncated field in the input.
+        position: u64,
+    },
+    /// Decoding a field didn't consume any input, so it would never finish.
+    #[fail(display = "no decoding progress at byte {}", position)]
+    NoProgress {
+        /// The position in the input where decoding got stuck.
+        position: u64,
+    },
+    /// A varint that is longer than necessary was encountered while decoding strictly.
+    #[fail(display = "non-minimal varint at byte {}", position)]
+    NonMinimalVarint {
+        /// The position of the varint in the input.
+        position: u64,
+    },
+    /// Messages were nested deeper than the recursion limit while decoding.
+    #[fail(
+        display = "messages nested deeper than {} levels at byte {}",
+        limit, position
+    )]
+    RecursionLimitExceeded {
+        /// The maximum nesting depth.
+        limit: u32,
+        /// The position in the input of the message that is nested too deeply.
+        position: u64,
+    },
+    /// A length prefix that reaches past the end of its enclosing message was encountered while
+    /// decoding strictly.
+    #[fail(
+        display = "length {} at byte {} exceeds the {} remaining bytes",
+        length, position, remaining
+    )]
+    LengthOutOfBounds {
+        /// The length that was encountered.
+        length: u64,
+        /// The number of bytes that remain in the enclosing message.
+        remaining: u64,
+        /// The position of the length prefix in the input.
+        position: u64,
+    },
+    /// A value that doesn't match the type of its field was encountered.
+    #[fail(display = "bad value for field: {}", field)]
+    BadFieldValue {
+        /// The name of the field.
+        field: String,
+    },
+    /// A value whose type doesn't match the type of its field was encountered.
+    #[fail(
+        display = "type mismatch for field {}: expected {}, got {}",
+        field, expected, actual
+    )]
+    TypeMismatch {
+        /// The name of the field.
+        field: String,
+        /// The name of the field type, like `int32` or the fully qualified name of a message type.
+        expected: String,
+        /// The kind of value that was encountered, like `i64` or `string`.
+        actual: &'static str,
+    },
+    /// A required field isn't set.
+    #[fail(display = "missing required field: {}", path)]
+    MissingField {
+        /// The path of the field from the top-level message, like `order.id`.
+        path: String,
+    },
+    /// A field value couldn't be converted to the type of the field that it was mapped to.
+    #[fail(display = "cannot convert {} to {}", from, to)]
+    FieldConversion {
+        /// The path of the source field.
+        from: String,
+        /// The path of the target field.
+        to: String,
+    },
+    /// A JSON value that doesn't match the type of its field was encountered.
+    #[fail(display = "bad JSON value for field: {}", field)]
+    BadJsonValue {
+        /// The name of the field.
+        field: String,
+    },
+    /// A field name that the message type doesn't have was encountered.
+    #[fail(display = "unknown field: {}", name)]
+    UnknownField {
+        /// The name of the field.
+        name: String,
+    },
+    /// A field number that the message type doesn't have was encountered while decoding.
+    #[fail(display = "unknown field number: {}", number)]
+    UnknownFieldNumber {
+        /// The number of the field.
+        number: u32,
+    },
+    /// A singular field occurred more than once while decoding with
+    /// `DuplicateFieldPolicy::Error`.
+    #[fail(display = "duplicate field: {}", name)]
+    DuplicateField {
+        /// The name of the field.
+        name: String,
+    },
+    /// A compressed field decompressed to more than the allowed size.
+    #[fail(
+        display = "decompressed content of field {} exceeds {} bytes",
+        field, limit
+    )]
+    DecompressedTooLarge {
+        /// The name of the field.
+        field: String,
+        /// The maximum decompressed size.
+        limit: u64,
+    },
+    /// A message couldn't be truncated or split to fit the size limit.
+    #[fail(
+        display = "message of {} bytes exceeds the limit of {} bytes",
+        size, limit
+    )]
+    MessageTooLarge {
+        /// The encoded size of the message after truncation, or of the smallest message that
+        /// splitting could produce.
+        size: u32,
+        /// The size limit.
+        limit: u32,
+    },
+    /// A field mask path that doesn't refer to an existing field was encountered.
+    #[fail(display = "bad field mask path: {}", path)]
+    BadFieldMaskPath {
+        /// The offending path.
+        path: String,
+    },
+    /// An index past the end of a repeated field was used.
+    #[fail(display = "index {} out of bounds for field: {}", index, field)]
+    IndexOutOfBounds {
+        /// The name of the field.
+        field: String,
+        /// The offending index.
+        index: usize,
+    },
+    /// A value outside of the range allowed for its type was encountered.
+    #[fail(display = "value out of range for {}", name)]
+    OutOfRange {
+        /// The name of the value's message type.
+        name: String,
     },
     /// A default value that can't be parsed was received.
     #[fail(display = "bad default value: {:?}", default_value)]
@@ -49,6 +266,14 @@ pub enum Error {
         /// The default value that couldn't be parsed.
         default_value: String,
     },
+    /// A predicate expression couldn't be parsed or doesn't fit the message type.
+    #[fail(display = "bad expression at offset {}: {}", offset, message)]
+    BadExpression {
+        /// The byte offset in the expression where the problem was found.
+        offset: usize,
+        /// A description of the problem.
+        message: String,
+    },
     /// Some user-defined error occurred.
     #[fail(display = "{}", message)]
     Custom {
@@ -57,6 +282,111 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// A short name for the kind of error, like `type_mismatch`, that doesn't change between
+    /// releases, e.g. for metrics or to map errors to responses.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::Protobuf(_) => "protobuf",
+            Error::EndOfStream => "end_of_stream",
+            Error::UnknownEnum { .. } => "unknown_enum",
+            Error::UnknownEnumValue { .. } => "unknown_enum_value",
+            Error::UnknownMessage { .. } => "unknown_message",
+            Error::UnknownMessageType { .. } => "unknown_message_type",
+            Error::MessageTypeMismatch { .. } => "message_type_mismatch",
+            Error::DescriptorConflict { .. } => "descriptor_conflict",
+            Error::TypeConflicts { .. } => "type_conflicts",
+            Error::ProtocFailed { .. } => "protoc_failed",
+            Error::BadWireType { .. } => "bad_wire_type",
+            Error::BadFieldNumber { .. } => "bad_field_number",
+            Error::UndefinedWireType { .. } => "undefined_wire_type",
+            Error::UnexpectedEof { .. } => "unexpected_eof",
+            Error::NoProgress { .. } => "no_progress",
+            Error::NonMinimalVarint { .. } => "non_minimal_varint",
+            Error::RecursionLimitExceeded { .. } => "recursion_limit_exceeded",
+            Error::LengthOutOfBounds { .. } => "length_out_of_bounds",
+            Error::BadFieldValue { .. } => "bad_field_value",
+            Error::TypeMismatch { .. } => "type_mismatch",
+            Error::MissingField { .. } => "missing_field",
+            Error::FieldConversion { .. } => "field_conversion",
+            Error::BadJsonValue { .. } => "bad_json_value",
+            Error::UnknownField { .. } => "unknown_field",
+            Error::UnknownFieldNumber { .. } => "unknown_field_number",
+            Error::DuplicateField { .. } => "duplicate_field",
+            Error::DecompressedTooLarge { .. } => "decompressed_too_large",
+            Error::MessageTooLarge { .. } => "message_too_large",
+            Error::BadFieldMaskPath { .. } => "bad_field_mask_path",
+            Error::IndexOutOfBounds { .. } => "index_out_of_bounds",
+            Error::OutOfRange { .. } => "out_of_range",
+            Error::BadDefaultValue { .. } => "bad_default_value",
+            Error::BadExpression { .. } => "bad_expression",
+            Error::Custom { .. } => "custom",
+        }
+    }
+
+    /// The position in the input of a decoding error, if it is known.
+    pub fn position(&self) -> Option<u64> {
+        match *self {
+            Error::BadFieldNumber { position, .. } => position,
+            Error::BadWireType { position, .. }
+            | Error::UndefinedWireType { position, .. }
+            | Error::UnexpectedEof { position }
+            | Error::NoProgress { position }
+            | Error::NonMinimalVarint { position }
+            | Error::RecursionLimitExceeded { position, .. }
+            | Error::LengthOutOfBounds { position, .. } => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Moves the position of a decoding error by `offset`, for errors about input that was
+    /// decoded from the middle of a buffer.
+    pub(crate) fn at_offset(mut self, offset: u64) -> Error {
+        match self {
+            Error::BadFieldNumber {
+                position: Some(ref mut position),
+                ..
+            }
+            | Error::BadWireType {
+                ref mut position, ..
+            }
+            | Error::UndefinedWireType {
+                ref mut position, ..
+            }
+            | Error::UnexpectedEof { ref mut position }
+            | Error::NoProgress { ref mut position }
+            | Error::NonMinimalVarint { ref mut position }
+            | Error::RecursionLimitExceeded {
+                ref mut position, ..
+            }
+            | Error::LengthOutOfBounds {
+                ref mut position, ..
+            } => *position += offset,
+            _ => (),
+        }
+        self
+    }
+
+    /// Turns errors about input that ends early into an `UnexpectedEof` error about the field
+    /// whose tag is at `position`.
+    pub(crate) fn truncated_at(self, position: u64) -> Error {
+        use protobuf::error::WireError;
+
+        match self {
+            Error::Protobuf(protobuf::ProtobufError::WireError(
+                WireError::UnexpectedEof | WireError::TruncatedMessage,
+            ))
+            | Error::EndOfStream => Error::UnexpectedEof { position },
+            Error::Protobuf(protobuf::ProtobufError::IoError(ref e))
+                if e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Error::UnexpectedEof { position }
+            }
+            e => e,
+        }
+    }
+}
+
 /// A result whose error type is `CompatError`.
 pub type CompatResult<A> = result::Result<A, CompatError>;
 
@@ -106,3 +436,12 @@ impl serde::de::Error for CompatError {
         )
     }
 }
+
+impl serde::ser::Error for CompatError {
+    fn custom<T>(msg: T) -> CompatError
+    where
+        T: fmt::Display,
+    {
+        serde::de::Error::custom(msg)
+    }
+}
@@ -0,0 +1,293 @@
+//! Support for the `google.protobuf.FieldMask` well-known type.
+//!
+//! Fields whose type is `google.protobuf.FieldMask` are decoded as `value::Value::FieldMask`
+//! instead of as generic messages, and are deserialized as a sequence of paths.
+//!
+//! A `FieldMaskTree` is a field mask compiled against a message type, which
+//! `value::Message::merge_from_masked` uses to decode only the masked fields of messages, and
+//! `value::Message::retain_masked` to prune decoded messages.
+use protobuf;
+use protobuf::stream::wire_format;
+
+use crate::descriptor;
+use crate::error;
+
+/// The name of the `google.protobuf.FieldMask` message.
+pub const FIELD_MASK: &str = ".google.protobuf.FieldMask";
+
+/// A set of symbolic field paths, e.g. `["user.display_name", "photo"]`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FieldMask {
+    /// The field paths, where each path is a dot-separated list of field names.
+    ///
+    /// A field name may be followed by the index of an element of a repeated field, like
+    /// `items.0.price`, or by the key of an entry of a map field, like `labels.env`.
+    pub paths: Vec<String>,
+}
+
+/// A field mask compiled against a message type into a tree of field numbers.
+///
+/// The tree is owned and independent of the mask it was compiled from, so it can be compiled once
+/// and then reused to decode many messages.  Elements of repeated fields and entries of map fields
+/// are selected after decoding their field, while other fields outside of the mask are skipped on
+/// the wire.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FieldMaskTree {
+    // The masked fields by number, with the trees of their masked subfields, sorted by number for
+    // binary search: masks are small, and decoding looks up every tag on the wire
+    fields: Vec<(u32, FieldMaskTree)>,
+    // The masked elements of a repeated or map field, with the trees of their masked subfields
+    elements: Vec<(ElementKey, FieldMaskTree)>,
+}
+
+/// An element of a repeated field or an entry of a map field in a field mask path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ElementKey {
+    Index(usize),
+    // The key in its canonical text form, e.g. `7` for the `int32` key `007`
+    Key(String),
+}
+
+/// A segment of a field mask path.
+#[derive(Debug)]
+pub(crate) enum Segment<'a> {
+    Field(&'a descriptor::FieldDescriptor),
+    Element(ElementKey),
+}
+
+impl FieldMask {
+    /// Creates a field mask with the given paths.
+    pub fn new<I, S>(paths: I) -> FieldMask
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        FieldMask {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Checks that every path in this mask refers to an existing field of the given message type.
+    ///
+    /// All but the last field of a path must be singular message fields, or elements of repeated
+    /// message fields or entries of map fields whose values are messages.  Map keys must be
+    /// valid for the key type.
+    pub fn validate(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<()> {
+        for path in &self.paths {
+            parse_path(descriptors, message, path)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles this field mask against the given message type, see `FieldMaskTree::compile`.
+    pub fn compile(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<FieldMaskTree> {
+        FieldMaskTree::compile(descriptors, message, self)
+    }
+
+    /// Merge data from the given input stream into this field mask.
+    pub fn merge_from(&mut self, input: &mut protobuf::CodedInputStream) -> error::Result<()> {
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            if number == 1 && wire_type == wire_format::WireType::WireTypeLengthDelimited {
+                self.paths.push(input.read_string()?);
+            } else {
+                let mut unknown = protobuf::UnknownFields::new();
+                protobuf::rt::read_unknown_or_skip_group(number, wire_type, input, &mut unknown)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the encoded size of this field mask.
+    pub fn compute_size(&self) -> u32 {
+        self.paths
+            .iter()
+            .map(|p| protobuf::rt::string_size(1, p))
+            .sum()
+    }
+
+    /// Writes this field mask to the given output stream.
+    pub fn write_to(&self, output: &mut protobuf::CodedOutputStream) -> error::Result<()> {
+        for path in &self.paths {
+            output.write_string(1, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl FieldMaskTree {
+    /// Compiles a field mask against the given message type, resolving its paths to field
+    /// numbers once.
+    ///
+    /// The paths must be valid for the message type, see `FieldMask::validate`.  A mask without
+    /// paths compiles to an empty tree, which masks the whole message.
+    pub fn compile(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mask: &FieldMask,
+    ) -> error::Result<FieldMaskTree> {
+        let mut tree = FieldMaskTree::default();
+        for path in &mask.paths {
+            let segments = parse_path(descriptors, message, path)?;
+            let last = segments.len() - 1;
+
+            let mut node = &mut tree;
+            for (i, segment) in segments.into_iter().enumerate() {
+                let (child, masked_entirely) = match segment {
+                    Segment::Field(field) => node.field_entry(field.number() as u32),
+                    Segment::Element(key) => node.element_entry(key),
+                };
+                if i == last {
+                    child.fields.clear();
+                    child.elements.clear();
+                }
+                // A field that is masked entirely stays so, even if a path masks a part of it
+                if masked_entirely || i == last {
+                    break;
+                }
+                node = child;
+            }
+        }
+        Ok(tree)
+    }
+
+    /// The tree of the masked subfields of the field with the specified number, if the field is
+    /// masked.  A field whose tree is empty is masked entirely.
+    pub fn field(&self, number: i32) -> Option<&FieldMaskTree> {
+        self.field_by_tag_number(number as u32)
+    }
+
+    /// Like `field`, but with the field number as read from a tag.
+    #[inline]
+    pub(crate) fn field_by_tag_number(&self, number: u32) -> Option<&FieldMaskTree> {
+        self.index_of(number).ok().map(|i| &self.fields[i].1)
+    }
+
+    /// The masked elements of a repeated or map field, if only some of them are masked.
+    pub(crate) fn elements(&self) -> &[(ElementKey, FieldMaskTree)] {
+        &self.elements
+    }
+
+    /// Whether this tree has no fields or elements, i.e. masks the whole message or field.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.elements.is_empty()
+    }
+
+    /// The tree of a field, inserted if missing, and whether it was masked entirely already.
+    fn field_entry(&mut self, number: u32) -> (&mut FieldMaskTree, bool) {
+        let (index, existed) = match self.index_of(number) {
+            Ok(index) => (index, true),
+            Err(index) => {
+                self.fields
+                    .insert(index, (number, FieldMaskTree::default()));
+                (index, false)
+            }
+        };
+        let child = &mut self.fields[index].1;
+        let masked_entirely = existed && child.is_empty();
+        (child, masked_entirely)
+    }
+
+    /// The tree of an element, inserted if missing, and whether it was masked entirely already.
+    fn element_entry(&mut self, key: ElementKey) -> (&mut FieldMaskTree, bool) {
+        let (index, existed) = match self.elements.iter().position(|e| e.0 == key) {
+            Some(index) => (index, true),
+            None => {
+                self.elements.push((key, FieldMaskTree::default()));
+                (self.elements.len() - 1, false)
+            }
+        };
+        let child = &mut self.elements[index].1;
+        let masked_entirely = existed && child.is_empty();
+        (child, masked_entirely)
+    }
+
+    #[inline]
+    fn index_of(&self, number: u32) -> Result<usize, usize> {
+        self.fields.binary_search_by_key(&number, |f| f.0)
+    }
+}
+
+/// Resolves the segments of a field mask path against a message type.
+pub(crate) fn parse_path<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    path: &str,
+) -> error::Result<Vec<Segment<'a>>> {
+    let bad_path = || error::Error::BadFieldMaskPath {
+        path: path.to_owned(),
+    };
+
+    let mut segments = Vec::new();
+    // The message type whose field the next part names, if any
+    let mut message = Some(message);
+    let mut parts = path.split('.');
+    while let Some(part) = parts.next() {
+        let field = message
+            .and_then(|m| m.field_by_name(part))
+            .ok_or_else(bad_path)?;
+        segments.push(Segment::Field(field));
+        let mut field_type = field.field_type(descriptors);
+
+        if field.is_repeated() {
+            let part = match parts.next() {
+                Some(part) => part,
+                None => break,
+            };
+            match field_type {
+                descriptor::FieldType::Message(entry) if entry.is_map_entry() => {
+                    let key = entry
+                        .field_by_number(1)
+                        .and_then(|k| map_key(descriptors, k, part))
+                        .ok_or_else(bad_path)?;
+                    segments.push(Segment::Element(ElementKey::Key(key)));
+                    let value = entry.field_by_number(2).ok_or_else(bad_path)?;
+                    field_type = value.field_type(descriptors);
+                }
+                _ => {
+                    let index = part.parse().map_err(|_| bad_path())?;
+                    segments.push(Segment::Element(ElementKey::Index(index)));
+                }
+            }
+        }
+        message = match field_type {
+            descriptor::FieldType::Message(m) => Some(m),
+            _ => None,
+        };
+    }
+    Ok(segments)
+}
+
+/// Parses a map key of a path into its canonical text form.
+fn map_key(
+    descriptors: &descriptor::Descriptors,
+    key: &descriptor::FieldDescriptor,
+    text: &str,
+) -> Option<String> {
+    use crate::descriptor::FieldType as T;
+
+    fn canonical<V>(text: &str) -> Option<String>
+    where
+        V: std::str::FromStr + ToString,
+    {
+        text.parse::<V>().ok().map(|v| v.to_string())
+    }
+
+    match key.field_type(descriptors) {
+        T::Bool => canonical::<bool>(text),
+        T::Int32 | T::SInt32 | T::SFixed32 => canonical::<i32>(text),
+        T::Int64 | T::SInt64 | T::SFixed64 => canonical::<i64>(text),
+        T::UInt32 | T::Fixed32 => canonical::<u32>(text),
+        T::UInt64 | T::Fixed64 => canonical::<u64>(text),
+        T::String => Some(text.to_owned()),
+        _ => None,
+    }
+}
@@ -0,0 +1,415 @@
+//! Conversions for common `google.type` messages.
+//!
+//! The messages in the `google.type` package describe everyday values like amounts of money or
+//! calendar dates.  This module provides plain Rust types for some of them, which can be converted
+//! from and to their dynamic message representation.  The same conversions are used by the
+//! deserializer when `de::Options::google_types` is enabled.
+//!
+//! With the `rust_decimal` feature, `Money` and `Decimal` can also be converted to
+//! `rust_decimal::Decimal` values, and with the `chrono` or `time` features, `Date` and `TimeOfDay`
+//! can be converted from and to the corresponding types of those crates.
+use std::fmt;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// The name of the `google.type.Money` message.
+pub const MONEY: &str = ".google.type.Money";
+/// The name of the `google.type.Date` message.
+pub const DATE: &str = ".google.type.Date";
+/// The name of the `google.type.TimeOfDay` message.
+pub const TIME_OF_DAY: &str = ".google.type.TimeOfDay";
+/// The name of the `google.type.Decimal` message.
+pub const DECIMAL: &str = ".google.type.Decimal";
+
+/// The largest magnitude of the `nanos` of `Money`.
+const MAX_NANOS: u32 = 999_999_999;
+
+/// An amount of money with its currency type (`google.type.Money`).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Money {
+    /// The three-letter currency code defined in ISO 4217.
+    pub currency_code: String,
+    /// The whole units of the amount.
+    pub units: i64,
+    /// The number of nano (10^-9) units of the amount, from -999,999,999 to 999,999,999 and with
+    /// the same sign as `units`.
+    pub nanos: i32,
+}
+
+/// A whole or partial calendar date (`google.type.Date`).
+///
+/// Any of the components may be zero to indicate that they are unspecified.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Date {
+    /// The year of the date, from 1 to 9999.
+    pub year: i32,
+    /// The month of the year, from 1 to 12.
+    pub month: i32,
+    /// The day of the month, from 1 to 31.
+    pub day: i32,
+}
+
+/// A time of day (`google.type.TimeOfDay`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TimeOfDay {
+    /// The hours of the day, from 0 to 23.
+    pub hours: i32,
+    /// The minutes of the hour, from 0 to 59.
+    pub minutes: i32,
+    /// The seconds of the minute, from 0 to 59.
+    pub seconds: i32,
+    /// The fractions of seconds in nanoseconds, from 0 to 999,999,999.
+    pub nanos: i32,
+}
+
+/// A decimal number in its string representation (`google.type.Decimal`).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Decimal {
+    /// The decimal value, e.g. `"2.5"`.
+    pub value: String,
+}
+
+impl Money {
+    /// Reads money from a `google.type.Money` message.
+    ///
+    /// Fails with an `OutOfRange` error if the amount is invalid, see `validate`.
+    pub fn from_message(message: &value::Message) -> error::Result<Money> {
+        let money = Money {
+            currency_code: get_string(message, 1, "currency_code")?,
+            units: get_i64(message, 2, "units")?,
+            nanos: get_i32(message, 3, "nanos")?,
+        };
+        money.validate()?;
+        Ok(money)
+    }
+
+    /// Checks that `nanos` is in range and that it doesn't have the opposite sign of `units`,
+    /// failing with an `OutOfRange` error otherwise.
+    pub fn validate(&self) -> error::Result<()> {
+        let opposite_signs =
+            (self.units > 0 && self.nanos < 0) || (self.units < 0 && self.nanos > 0);
+        if self.nanos.unsigned_abs() > MAX_NANOS || opposite_signs {
+            Err(error::Error::OutOfRange {
+                name: MONEY.to_owned(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Creates a `google.type.Money` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set(
+            &mut message,
+            1,
+            value::Value::String(self.currency_code.clone()),
+        );
+        set(&mut message, 2, value::Value::I64(self.units));
+        set(&mut message, 3, value::Value::I32(self.nanos));
+        message
+    }
+
+    /// The amount as a decimal string, without trailing zeros, e.g. `"-1.5"`.
+    ///
+    /// Fails with an `OutOfRange` error if the amount is invalid, see `validate`.
+    pub fn amount(&self) -> error::Result<String> {
+        self.validate()?;
+        let negative = self.units < 0 || self.nanos < 0;
+        let mut amount = format!(
+            "{}{}.{:09}",
+            if negative { "-" } else { "" },
+            self.units.unsigned_abs(),
+            self.nanos.unsigned_abs()
+        );
+        let len = amount.trim_end_matches('0').trim_end_matches('.').len();
+        amount.truncate(len);
+        Ok(amount)
+    }
+
+    /// The amount as a `rust_decimal::Decimal`.
+    ///
+    /// Fails with an `OutOfRange` error if the amount is invalid, see `validate`.
+    #[cfg(feature = "rust_decimal")]
+    pub fn to_decimal(&self) -> error::Result<rust_decimal::Decimal> {
+        self.validate()?;
+        Ok(rust_decimal::Decimal::from(self.units)
+            + rust_decimal::Decimal::new(i64::from(self.nanos), 9))
+    }
+}
+
+impl fmt::Display for Money {
+    /// Displays the amount and the currency code, e.g. `-1.5 USD`; an invalid amount is displayed
+    /// as its units and nanos instead.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.amount() {
+            Ok(amount) => write!(f, "{} {}", amount, self.currency_code),
+            Err(_) => write!(
+                f,
+                "<invalid amount: {} units, {} nanos> {}",
+                self.units, self.nanos, self.currency_code
+            ),
+        }
+    }
+}
+
+impl Date {
+    /// Reads a date from a `google.type.Date` message.
+    pub fn from_message(message: &value::Message) -> error::Result<Date> {
+        Ok(Date {
+            year: get_i32(message, 1, "year")?,
+            month: get_i32(message, 2, "month")?,
+            day: get_i32(message, 3, "day")?,
+        })
+    }
+
+    /// Creates a `google.type.Date` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set(&mut message, 1, value::Value::I32(self.year));
+        set(&mut message, 2, value::Value::I32(self.month));
+        set(&mut message, 3, value::Value::I32(self.day));
+        message
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Date {
+    /// Converts this date to a `chrono` date, which requires all of its components to be set.
+    pub fn to_chrono(&self) -> error::Result<chrono::NaiveDate> {
+        chrono::NaiveDate::from_ymd_opt(self.year, self.month as u32, self.day as u32)
+            .ok_or_else(|| out_of_range(DATE))
+    }
+
+    /// Creates a date from a `chrono` date.
+    pub fn from_chrono(date: &chrono::NaiveDate) -> Date {
+        use chrono::Datelike;
+
+        Date {
+            year: date.year(),
+            month: date.month() as i32,
+            day: date.day() as i32,
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl Date {
+    /// Converts this date to a `time` date, which requires all of its components to be set.
+    pub fn to_time(&self) -> error::Result<time::Date> {
+        use std::convert::TryFrom;
+
+        let month = u8::try_from(self.month)
+            .ok()
+            .and_then(|m| time::Month::try_from(m).ok())
+            .ok_or_else(|| out_of_range(DATE))?;
+        let day = u8::try_from(self.day).map_err(|_| out_of_range(DATE))?;
+        time::Date::from_calendar_date(self.year, month, day).map_err(|_| out_of_range(DATE))
+    }
+
+    /// Creates a date from a `time` date.
+    pub fn from_time(date: &time::Date) -> Date {
+        Date {
+            year: date.year(),
+            month: i32::from(u8::from(date.month())),
+            day: i32::from(date.day()),
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl TimeOfDay {
+    /// Reads a time of day from a `google.type.TimeOfDay` message.
+    pub fn from_message(message: &value::Message) -> error::Result<TimeOfDay> {
+        Ok(TimeOfDay {
+            hours: get_i32(message, 1, "hours")?,
+            minutes: get_i32(message, 2, "minutes")?,
+            seconds: get_i32(message, 3, "seconds")?,
+            nanos: get_i32(message, 4, "nanos")?,
+        })
+    }
+
+    /// Creates a `google.type.TimeOfDay` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set(&mut message, 1, value::Value::I32(self.hours));
+        set(&mut message, 2, value::Value::I32(self.minutes));
+        set(&mut message, 3, value::Value::I32(self.seconds));
+        set(&mut message, 4, value::Value::I32(self.nanos));
+        message
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TimeOfDay {
+    /// Converts this time of day to a `chrono` time.
+    pub fn to_chrono(&self) -> error::Result<chrono::NaiveTime> {
+        chrono::NaiveTime::from_hms_nano_opt(
+            self.hours as u32,
+            self.minutes as u32,
+            self.seconds as u32,
+            self.nanos as u32,
+        )
+        .ok_or_else(|| out_of_range(TIME_OF_DAY))
+    }
+
+    /// Creates a time of day from a `chrono` time.
+    pub fn from_chrono(time: &chrono::NaiveTime) -> TimeOfDay {
+        use chrono::Timelike;
+
+        TimeOfDay {
+            hours: time.hour() as i32,
+            minutes: time.minute() as i32,
+            seconds: time.second() as i32,
+            nanos: time.nanosecond() as i32,
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl TimeOfDay {
+    /// Converts this time of day to a `time` time.
+    pub fn to_time(&self) -> error::Result<time::Time> {
+        use std::convert::TryFrom;
+
+        let component = |v: i32| u8::try_from(v).map_err(|_| out_of_range(TIME_OF_DAY));
+        let nanos = u32::try_from(self.nanos).map_err(|_| out_of_range(TIME_OF_DAY))?;
+        time::Time::from_hms_nano(
+            component(self.hours)?,
+            component(self.minutes)?,
+            component(self.seconds)?,
+            nanos,
+        )
+        .map_err(|_| out_of_range(TIME_OF_DAY))
+    }
+
+    /// Creates a time of day from a `time` time.
+    pub fn from_time(time: &time::Time) -> TimeOfDay {
+        TimeOfDay {
+            hours: i32::from(time.hour()),
+            minutes: i32::from(time.minute()),
+            seconds: i32::from(time.second()),
+            nanos: time.nanosecond() as i32,
+        }
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            self.hours, self.minutes, self.seconds
+        )?;
+        if self.nanos != 0 {
+            let nanos = format!("{:09}", self.nanos);
+            write!(f, ".{}", nanos.trim_end_matches('0'))?;
+        }
+        Ok(())
+    }
+}
+
+impl Decimal {
+    /// Reads a decimal from a `google.type.Decimal` message.
+    pub fn from_message(message: &value::Message) -> error::Result<Decimal> {
+        Ok(Decimal {
+            value: get_string(message, 1, "value")?,
+        })
+    }
+
+    /// Creates a `google.type.Decimal` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set(&mut message, 1, value::Value::String(self.value.clone()));
+        message
+    }
+
+    /// Parses the decimal as a `rust_decimal::Decimal`.
+    #[cfg(feature = "rust_decimal")]
+    pub fn to_decimal(&self) -> error::Result<rust_decimal::Decimal> {
+        use std::str::FromStr;
+
+        rust_decimal::Decimal::from_str(&self.value)
+            .or_else(|_| rust_decimal::Decimal::from_scientific(&self.value))
+            .map_err(|_| error::Error::BadFieldValue {
+                field: "value".to_owned(),
+            })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+/// Formats a `google.type` message as a string, if it is of a supported type.
+pub(crate) fn format_message(
+    name: &str,
+    message: &value::Message,
+) -> Option<error::Result<String>> {
+    match name {
+        MONEY => Some(Money::from_message(message).map(|m| m.to_string())),
+        DATE => Some(Date::from_message(message).map(|d| d.to_string())),
+        TIME_OF_DAY => Some(TimeOfDay::from_message(message).map(|t| t.to_string())),
+        DECIMAL => Some(Decimal::from_message(message).map(|d| d.to_string())),
+        _ => None,
+    }
+}
+
+fn get(message: &value::Message, number: i32) -> Option<&value::Value> {
+    match message.fields.get(&number) {
+        Some(&value::Field::Singular(Some(ref v))) => Some(v),
+        _ => None,
+    }
+}
+
+fn get_i32(message: &value::Message, number: i32, name: &str) -> error::Result<i32> {
+    match get(message, number) {
+        None => Ok(0),
+        Some(&value::Value::I32(v)) => Ok(v),
+        Some(_) => Err(bad_field_value(name)),
+    }
+}
+
+fn get_i64(message: &value::Message, number: i32, name: &str) -> error::Result<i64> {
+    match get(message, number) {
+        None => Ok(0),
+        Some(&value::Value::I64(v)) => Ok(v),
+        Some(_) => Err(bad_field_value(name)),
+    }
+}
+
+fn get_string(message: &value::Message, number: i32, name: &str) -> error::Result<String> {
+    match get(message, number) {
+        None => Ok(String::new()),
+        Some(value::Value::String(v)) => Ok(v.clone()),
+        Some(_) => Err(bad_field_value(name)),
+    }
+}
+
+fn set(message: &mut value::Message, number: i32, value: value::Value) {
+    message
+        .fields
+        .insert(number, value::Field::Singular(Some(value)));
+}
+
+#[cfg(any(feature = "chrono", feature = "time"))]
+fn out_of_range(name: &str) -> error::Error {
+    error::Error::OutOfRange {
+        name: name.to_owned(),
+    }
+}
+
+fn bad_field_value(name: &str) -> error::Error {
+    error::Error::BadFieldValue {
+        field: name.to_owned(),
+    }
+}
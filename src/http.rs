@@ -0,0 +1,612 @@
+//! Support for `google.api.http` annotations on service methods.
+//!
+//! Methods of a gRPC service can be mapped to REST endpoints by annotating them with an
+//! `option (google.api.http) = { ... };` rule.  The option is an extension that the descriptor
+//! parser doesn't know about, so it is kept as an unknown field of the method options; the
+//! [`HttpRule`](struct.HttpRule.html) type decodes it from there.
+//!
+//! With the `serde_json` feature, a [`Transcoder`](struct.Transcoder.html) can map HTTP requests
+//! to request messages of the annotated methods, and their response messages back to JSON.
+#[cfg(feature = "serde_json")]
+use std::sync::Arc;
+
+use protobuf;
+use protobuf::stream::wire_format;
+#[cfg(feature = "serde_json")]
+use serde_json;
+
+use crate::descriptor;
+use crate::error;
+#[cfg(feature = "serde_json")]
+use crate::json;
+#[cfg(feature = "serde_json")]
+use crate::value;
+
+/// The field number of the `google.api.http` extension of `google.protobuf.MethodOptions`.
+pub const HTTP_EXTENSION: u32 = 72_295_728;
+
+/// A decoded `google.api.HttpRule` message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HttpRule {
+    /// The fully qualified name of the method that the rule applies to, if given.
+    pub selector: String,
+    /// The HTTP method, e.g. `"GET"`, or the kind of a custom pattern.
+    pub method: String,
+    /// The path template, e.g. `"/v1/{name=shelves/*}"`.
+    pub path: String,
+    /// The request field that is mapped to the HTTP body; `"*"` for the whole request message.
+    pub body: String,
+    /// The response field that is mapped to the HTTP body; empty for the whole response message.
+    pub response_body: String,
+    /// Alternative bindings for the same method.
+    pub additional_bindings: Vec<HttpRule>,
+}
+
+impl HttpRule {
+    /// Reads the `google.api.http` rule of the specified method, if it has one.
+    pub fn from_method(method: &descriptor::MethodDescriptor) -> error::Result<Option<HttpRule>> {
+        use protobuf::Message;
+
+        let value = method
+            .options()
+            .get_unknown_fields()
+            .get(HTTP_EXTENSION)
+            .and_then(|values| values.length_delimited.last());
+
+        match value {
+            Some(bytes) => {
+                let mut rule = HttpRule::default();
+                rule.merge_from(&mut protobuf::CodedInputStream::from_bytes(bytes))?;
+                Ok(Some(rule))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Merge data from the given input stream into this rule.
+    pub fn merge_from(&mut self, input: &mut protobuf::CodedInputStream) -> error::Result<()> {
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            if wire_type != wire_format::WireType::WireTypeLengthDelimited {
+                skip_field(number, wire_type, input)?;
+                continue;
+            }
+            match number {
+                1 => self.selector = input.read_string()?,
+                2..=6 => {
+                    self.method = match number {
+                        2 => "GET",
+                        3 => "PUT",
+                        4 => "POST",
+                        5 => "DELETE",
+                        _ => "PATCH",
+                    }
+                    .to_owned();
+                    self.path = input.read_string()?;
+                }
+                7 => self.body = input.read_string()?,
+                8 => {
+                    let len = input.read_raw_varint64()?;
+                    let old_limit = input.push_limit(len)?;
+                    while !input.eof()? {
+                        let (number, wire_type) = input.read_tag_unpack()?;
+                        match (number, wire_type) {
+                            (1, wire_format::WireType::WireTypeLengthDelimited) => {
+                                self.method = input.read_string()?
+                            }
+                            (2, wire_format::WireType::WireTypeLengthDelimited) => {
+                                self.path = input.read_string()?
+                            }
+                            _ => skip_field(number, wire_type, input)?,
+                        }
+                    }
+                    input.pop_limit(old_limit);
+                }
+                11 => {
+                    let len = input.read_raw_varint64()?;
+                    let old_limit = input.push_limit(len)?;
+                    let mut binding = HttpRule::default();
+                    binding.merge_from(input)?;
+                    input.pop_limit(old_limit);
+                    self.additional_bindings.push(binding);
+                }
+                12 => self.response_body = input.read_string()?,
+                _ => skip_field(number, wire_type, input)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes this rule as a `google.api.HttpRule` message.
+    pub fn write_to_bytes(&self) -> error::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            if !self.selector.is_empty() {
+                output.write_string(1, &self.selector)?;
+            }
+            match self.method.as_str() {
+                "GET" => output.write_string(2, &self.path)?,
+                "PUT" => output.write_string(3, &self.path)?,
+                "POST" => output.write_string(4, &self.path)?,
+                "DELETE" => output.write_string(5, &self.path)?,
+                "PATCH" => output.write_string(6, &self.path)?,
+                "" => (),
+                kind => {
+                    let mut custom = Vec::new();
+                    {
+                        let mut output = protobuf::CodedOutputStream::vec(&mut custom);
+                        output.write_string(1, kind)?;
+                        output.write_string(2, &self.path)?;
+                        output.flush()?;
+                    }
+                    output.write_bytes(8, &custom)?;
+                }
+            }
+            if !self.body.is_empty() {
+                output.write_string(7, &self.body)?;
+            }
+            for binding in &self.additional_bindings {
+                output.write_bytes(11, &binding.write_to_bytes()?)?;
+            }
+            if !self.response_body.is_empty() {
+                output.write_string(12, &self.response_body)?;
+            }
+            output.flush()?;
+        }
+        Ok(bytes)
+    }
+
+    /// This rule followed by all of its additional bindings.
+    pub fn bindings(&self) -> Vec<&HttpRule> {
+        let mut bindings = vec![self];
+        for binding in &self.additional_bindings {
+            bindings.extend(binding.bindings());
+        }
+        bindings
+    }
+
+    /// The field paths of the variables in the path template, e.g. `["name"]` for
+    /// `"/v1/{name=shelves/*}"`.
+    pub fn path_variables(&self) -> Vec<&str> {
+        let mut variables = Vec::new();
+        let mut rest = self.path.as_str();
+        while let Some(start) = rest.find('{') {
+            rest = &rest[start + 1..];
+            let end = rest.find('}').unwrap_or(rest.len());
+            let variable = &rest[..end];
+            variables.push(variable.split('=').next().unwrap_or(variable));
+            rest = &rest[end..];
+        }
+        variables
+    }
+}
+
+/// Maps HTTP requests to the methods of services according to their `google.api.http` rules.
+#[cfg(feature = "serde_json")]
+#[derive(Debug)]
+pub struct Transcoder<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    routes: Vec<Route<'a>>,
+}
+
+/// A request message that an HTTP request was mapped to.
+#[cfg(feature = "serde_json")]
+#[derive(Debug)]
+pub struct TranscodedRequest<'a> {
+    /// The service that the request is for.
+    pub service: &'a descriptor::ServiceDescriptor,
+    /// The method that the request is for.
+    pub method: &'a descriptor::MethodDescriptor,
+    /// The request message.
+    pub message: value::Message,
+    response_body: String,
+}
+
+#[cfg(feature = "serde_json")]
+#[derive(Debug)]
+struct Route<'a> {
+    service: &'a descriptor::ServiceDescriptor,
+    method: &'a descriptor::MethodDescriptor,
+    binding: HttpRule,
+    template: PathTemplate,
+}
+
+/// A parsed path template, e.g. `/v1/{name=shelves/*}/books:list`.
+#[cfg(feature = "serde_json")]
+#[derive(Debug)]
+struct PathTemplate {
+    segments: Vec<Segment>,
+    /// The field paths of the variables, with the range of segments that each one binds.
+    variables: Vec<(String, usize, usize)>,
+    verb: Option<String>,
+}
+
+#[cfg(feature = "serde_json")]
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Wildcard,
+    DoubleWildcard,
+}
+
+#[cfg(feature = "serde_json")]
+impl<'a> Transcoder<'a> {
+    /// Creates a transcoder for all of the annotated methods of the services in the registry.
+    ///
+    /// Methods without a `google.api.http` rule can't be reached through the transcoder.
+    pub fn new(descriptors: &'a descriptor::Descriptors) -> error::Result<Transcoder<'a>> {
+        let mut routes = Vec::new();
+        for service in descriptors.services() {
+            for method in service.methods() {
+                if let Some(rule) = HttpRule::from_method(method)? {
+                    for binding in rule.bindings() {
+                        routes.push(Route {
+                            service,
+                            method,
+                            template: PathTemplate::parse(&binding.path)?,
+                            binding: binding.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(Transcoder {
+            descriptors,
+            routes,
+        })
+    }
+
+    /// Maps an HTTP request to a request message, or returns `None` if no rule matches it.
+    ///
+    /// The `path` must not include the query string, which is passed separately as `query`
+    /// (without the leading `?`).  Path variables and query parameters are bound to the fields at
+    /// their field paths; query parameters are ignored when the whole request is the body.  The
+    /// `body` is the parsed JSON body of the request, if it has one.
+    pub fn transcode_request(
+        &self,
+        http_method: &str,
+        path: &str,
+        query: &str,
+        body: Option<&serde_json::Value>,
+    ) -> error::Result<Option<TranscodedRequest<'a>>> {
+        for route in &self.routes {
+            if !route.binding.method.eq_ignore_ascii_case(http_method) {
+                continue;
+            }
+            let bindings = match route.template.matches(path) {
+                Some(bindings) => bindings,
+                None => continue,
+            };
+            let input = route.method.input_type(self.descriptors).ok_or_else(|| {
+                error::Error::UnknownMessage {
+                    name: route.method.input_type_name().to_owned(),
+                }
+            })?;
+
+            let mut message = value::Message::new(input);
+            match (route.binding.body.as_str(), body) {
+                ("", _) | (_, None) => (),
+                ("*", Some(body)) => json::merge_json(self.descriptors, input, &mut message, body)?,
+                (field_path, Some(body)) => {
+                    self.set_field(input, &mut message, field_path, &Binding::Json(body))?
+                }
+            }
+            if route.binding.body != "*" {
+                for (name, value) in parse_query(query) {
+                    self.set_field(input, &mut message, &name, &Binding::Text(&value))?;
+                }
+            }
+            for (name, value) in bindings {
+                self.set_field(input, &mut message, &name, &Binding::Text(&value))?;
+            }
+
+            return Ok(Some(TranscodedRequest {
+                service: route.service,
+                method: route.method,
+                message,
+                response_body: route.binding.response_body.clone(),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Sets the field at a dot-separated path of field names, creating intermediate messages.
+    fn set_field(
+        &self,
+        descriptor: &descriptor::MessageDescriptor,
+        message: &mut value::Message,
+        path: &str,
+        binding: &Binding,
+    ) -> error::Result<()> {
+        let (name, rest) = match path.find('.') {
+            Some(i) => (&path[..i], Some(&path[i + 1..])),
+            None => (path, None),
+        };
+        let field = descriptor
+            .field_by_name(name)
+            .or_else(|| descriptor.field_by_json_name(name))
+            .ok_or_else(|| error::Error::UnknownField {
+                name: path.to_owned(),
+            })?;
+
+        if let Some(rest) = rest {
+            let nested = match field.field_type(self.descriptors) {
+                descriptor::FieldType::Message(m) if !field.is_repeated() => m,
+                _ => {
+                    return Err(error::Error::UnknownField {
+                        name: path.to_owned(),
+                    })
+                }
+            };
+            let slot = message
+                .fields
+                .entry(field.number())
+                .or_insert(value::Field::Singular(None));
+            if let value::Field::Singular(None) = slot {
+                *slot = value::Field::Singular(Some(value::Value::Message(Arc::new(
+                    value::Message::new(nested),
+                ))));
+            }
+            return match slot {
+                value::Field::Singular(Some(value::Value::Message(m))) => {
+                    self.set_field(nested, Arc::make_mut(m), rest, binding)
+                }
+                _ => Err(error::Error::BadFieldValue {
+                    field: field.name().to_owned(),
+                }),
+            };
+        }
+
+        let values = match *binding {
+            Binding::Json(json) if field.is_repeated() => match *json {
+                serde_json::Value::Array(ref values) => values
+                    .iter()
+                    .map(|v| json::value_from_json(self.descriptors, field, v))
+                    .collect::<error::Result<Vec<_>>>()?,
+                _ => vec![json::value_from_json(self.descriptors, field, json)?],
+            },
+            Binding::Json(json) => vec![json::value_from_json(self.descriptors, field, json)?],
+            Binding::Text(text) => {
+                let json = match field.field_type(self.descriptors) {
+                    descriptor::FieldType::Bool => match text {
+                        "true" => serde_json::Value::Bool(true),
+                        "false" => serde_json::Value::Bool(false),
+                        _ => {
+                            return Err(error::Error::BadJsonValue {
+                                field: field.name().to_owned(),
+                            })
+                        }
+                    },
+                    _ => serde_json::Value::String(text.to_owned()),
+                };
+                vec![json::value_from_json(self.descriptors, field, &json)?]
+            }
+        };
+
+        if field.is_repeated() {
+            let slot = message
+                .fields
+                .entry(field.number())
+                .or_insert_with(|| value::Field::new(field));
+            slot.make_untyped();
+            if let value::Field::Repeated(vs) = slot {
+                vs.extend(values);
+            }
+        } else if let Some(value) = values.into_iter().last() {
+            message
+                .fields
+                .insert(field.number(), value::Field::Singular(Some(value)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl<'a> TranscodedRequest<'a> {
+    /// Converts a response message of the method to the JSON body of the HTTP response.
+    ///
+    /// If the rule has a `response_body`, only that field of the response is returned.
+    pub fn response_to_json(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        response: &value::Message,
+    ) -> error::Result<serde_json::Value> {
+        let output =
+            self.method
+                .output_type(descriptors)
+                .ok_or_else(|| error::Error::UnknownMessage {
+                    name: self.method.output_type_name().to_owned(),
+                })?;
+        let json = json::to_json(descriptors, output, response)?;
+        if self.response_body.is_empty() {
+            return Ok(json);
+        }
+        let field = output.field_by_name(&self.response_body).ok_or_else(|| {
+            error::Error::UnknownField {
+                name: self.response_body.clone(),
+            }
+        })?;
+        Ok(json
+            .get(field.json_name())
+            .cloned()
+            .unwrap_or(serde_json::Value::Null))
+    }
+}
+
+/// A value bound to a field, either from the JSON body or from the path or query string.
+#[cfg(feature = "serde_json")]
+enum Binding<'b> {
+    Json(&'b serde_json::Value),
+    Text(&'b str),
+}
+
+#[cfg(feature = "serde_json")]
+impl PathTemplate {
+    fn parse(template: &str) -> error::Result<PathTemplate> {
+        let bad = || error::Error::Custom {
+            message: format!("bad path template: {:?}", template),
+        };
+
+        let mut rest = template.strip_prefix('/').ok_or_else(bad)?;
+        let mut verb = None;
+        if let Some(i) = rest.rfind(':') {
+            if !rest[i..].contains('}') && !rest[i..].contains('/') {
+                verb = Some(rest[i + 1..].to_owned());
+                rest = &rest[..i];
+            }
+        }
+
+        let mut segments = Vec::new();
+        let mut variables = Vec::new();
+        while !rest.is_empty() {
+            if let Some(variable) = rest.strip_prefix('{') {
+                let end = variable.find('}').ok_or_else(bad)?;
+                let (field_path, pattern) = match variable[..end].find('=') {
+                    Some(i) => (&variable[..i], &variable[i + 1..end]),
+                    None => (&variable[..end], "*"),
+                };
+                let start = segments.len();
+                for segment in pattern.split('/') {
+                    segments.push(Segment::parse(segment));
+                }
+                variables.push((field_path.to_owned(), start, segments.len()));
+                rest = &variable[end + 1..];
+            } else {
+                let end = rest.find('/').unwrap_or(rest.len());
+                segments.push(Segment::parse(&rest[..end]));
+                rest = &rest[end..];
+            }
+            rest = match rest.strip_prefix('/') {
+                Some(rest) if !rest.is_empty() => rest,
+                None if rest.is_empty() => rest,
+                _ => return Err(bad()),
+            };
+        }
+
+        Ok(PathTemplate {
+            segments,
+            variables,
+            verb,
+        })
+    }
+
+    /// Matches a request path, returning the percent-decoded values of the variables.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut path = path.strip_prefix('/')?;
+        if let Some(ref verb) = self.verb {
+            path = path.strip_suffix(verb.as_str())?.strip_suffix(':')?;
+        }
+        let parts: Vec<&str> = if path.is_empty() {
+            Vec::new()
+        } else {
+            path.split('/').collect()
+        };
+
+        // The start of the part that each segment matched, plus the end of the last one
+        let mut offsets = Vec::with_capacity(self.segments.len() + 1);
+        let mut i = 0;
+        for (n, segment) in self.segments.iter().enumerate() {
+            offsets.push(i);
+            match *segment {
+                Segment::Literal(ref literal) => {
+                    if parts.get(i) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                    i += 1;
+                }
+                Segment::Wildcard => {
+                    if i >= parts.len() {
+                        return None;
+                    }
+                    i += 1;
+                }
+                Segment::DoubleWildcard => {
+                    // Leave enough parts for the remaining segments
+                    let remaining = self.segments.len() - n - 1;
+                    i = parts.len().checked_sub(remaining)?.max(i);
+                }
+            }
+        }
+        offsets.push(i);
+        if i != parts.len() {
+            return None;
+        }
+
+        Some(
+            self.variables
+                .iter()
+                .map(|(field_path, start, end)| {
+                    let values: Vec<_> = parts[offsets[*start]..offsets[*end]]
+                        .iter()
+                        .map(|p| percent_decode(p, false))
+                        .collect();
+                    (field_path.clone(), values.join("/"))
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl Segment {
+    fn parse(segment: &str) -> Segment {
+        match segment {
+            "*" => Segment::Wildcard,
+            "**" => Segment::DoubleWildcard,
+            literal => Segment::Literal(literal.to_owned()),
+        }
+    }
+}
+
+/// Splits a query string into its percent-decoded parameters.
+#[cfg(feature = "serde_json")]
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = match p.find('=') {
+                Some(i) => (&p[..i], &p[i + 1..]),
+                None => (p, ""),
+            };
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+#[cfg(feature = "serde_json")]
+fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(b) => {
+                    decoded.push(b);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn skip_field(
+    number: u32,
+    wire_type: wire_format::WireType,
+    input: &mut protobuf::CodedInputStream,
+) -> error::Result<()> {
+    let mut unknown = protobuf::UnknownFields::new();
+    protobuf::rt::read_unknown_or_skip_group(number, wire_type, input, &mut unknown)?;
+    Ok(())
+}
@@ -0,0 +1,228 @@
+//! Best-effort inference of message descriptors from encoded messages without a schema.
+//!
+//! A `SchemaInference` scans sample payloads of an undocumented message type and guesses the
+//! type of every field number it encounters from the wire types and values, which can be used to
+//! bootstrap a schema:
+//!
+//!   * varints become `int32`, or `int64` if any value doesn't fit;
+//!   * fixed-width values become `fixed32` and `fixed64`, since floating point values can't be
+//!     told apart from integers;
+//!   * length-delimited values become nested messages if every sample parses as one and isn't
+//!     printable text, `string` if every sample is valid UTF-8, and `bytes` otherwise;
+//!   * fields that occur more than once in a message become `repeated`.
+//!
+//! Packed repeated fields are indistinguishable from `bytes` or nested messages, and groups are
+//! skipped.  A field whose wire type differs between samples keeps the type of the first one.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! use serde_protobuf::infer::SchemaInference;
+//!
+//! # fn main() {
+//! let mut inference = SchemaInference::new();
+//! // 1: 150, 2: "hi"
+//! inference.add(&[8, 0x96, 1, 0x12, 2, b'h', b'i']).unwrap();
+//!
+//! let proto = inference.descriptor_proto(".legacy.Feed");
+//! assert_eq!("Feed", proto.get_name());
+//! assert_eq!(2, proto.get_field().len());
+//! # }
+//! ```
+use std::collections;
+use std::str;
+
+use protobuf;
+use protobuf::descriptor;
+use protobuf::descriptor::FieldDescriptorProto_Label as Label;
+use protobuf::descriptor::FieldDescriptorProto_Type as Type;
+use protobuf::stream::wire_format;
+
+use crate::error;
+use crate::value;
+
+/// The nesting depth beyond which length-delimited values aren't parsed as messages.
+const MAX_DEPTH: u32 = 32;
+
+/// Collects observations about the fields of sample payloads of a single message type.
+#[derive(Clone, Debug, Default)]
+pub struct SchemaInference {
+    root: InferredMessage,
+}
+
+#[derive(Clone, Debug, Default)]
+struct InferredMessage {
+    fields: collections::BTreeMap<u32, InferredField>,
+}
+
+#[derive(Clone, Debug)]
+struct InferredField {
+    wire_type: wire_format::WireType,
+    repeated: bool,
+    // Whether any varint doesn't fit into an `int32`
+    wide: bool,
+    // Whether every length-delimited value was valid UTF-8
+    utf8: bool,
+    // Whether every length-delimited value was printable text
+    text: bool,
+    // The inferred nested message, while every length-delimited value parsed as one
+    message: Option<InferredMessage>,
+}
+
+impl SchemaInference {
+    /// Creates an inference that hasn't seen any payloads yet.
+    pub fn new() -> SchemaInference {
+        SchemaInference::default()
+    }
+
+    /// Adds the observations from an encoded message.
+    ///
+    /// Fails if the payload isn't a valid encoded message, in which case no observations are
+    /// added.
+    pub fn add(&mut self, bytes: &[u8]) -> error::Result<()> {
+        let mut message = self.root.clone();
+        message.add(bytes, 0)?;
+        self.root = message;
+        Ok(())
+    }
+
+    /// Describes the inferred message type, with the specified fully qualified name, e.g.
+    /// `.legacy.Feed`.
+    ///
+    /// Fields are named `field_<number>`, and nested message types `Field<number>`.
+    pub fn descriptor_proto(&self, name: &str) -> descriptor::DescriptorProto {
+        self.root.to_proto(name)
+    }
+}
+
+impl InferredMessage {
+    fn add(&mut self, bytes: &[u8], depth: u32) -> error::Result<()> {
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut counts = collections::BTreeMap::<u32, u32>::new();
+
+        while !input.eof()? {
+            let (number, wire_type) = value::read_tag(&mut input, false)?;
+            let value = match wire_type {
+                wire_format::WireType::WireTypeVarint => {
+                    Observed::Varint(input.read_raw_varint64()?)
+                }
+                wire_format::WireType::WireTypeFixed32 => {
+                    input.read_fixed32()?;
+                    Observed::Fixed
+                }
+                wire_format::WireType::WireTypeFixed64 => {
+                    input.read_fixed64()?;
+                    Observed::Fixed
+                }
+                wire_format::WireType::WireTypeLengthDelimited => {
+                    Observed::LengthDelimited(input.read_bytes()?)
+                }
+                _ => {
+                    let mut unknown = protobuf::UnknownFields::new();
+                    protobuf::rt::read_unknown_or_skip_group(
+                        number,
+                        wire_type,
+                        &mut input,
+                        &mut unknown,
+                    )?;
+                    continue;
+                }
+            };
+
+            let count = counts.entry(number).or_insert(0);
+            *count += 1;
+            let field = self
+                .fields
+                .entry(number)
+                .or_insert_with(|| InferredField::new(wire_type));
+            field.repeated |= *count > 1;
+            if field.wire_type == wire_type {
+                field.observe(value, depth);
+            }
+        }
+        Ok(())
+    }
+
+    fn to_proto(&self, name: &str) -> descriptor::DescriptorProto {
+        let mut proto = descriptor::DescriptorProto::new();
+        proto.set_name(name.rsplit('.').next().unwrap_or(name).to_owned());
+
+        for (&number, field) in &self.fields {
+            let mut field_proto = descriptor::FieldDescriptorProto::new();
+            field_proto.set_name(format!("field_{}", number));
+            field_proto.set_number(number as i32);
+            field_proto.set_label(if field.repeated {
+                Label::LABEL_REPEATED
+            } else {
+                Label::LABEL_OPTIONAL
+            });
+            let field_type = match field.wire_type {
+                wire_format::WireType::WireTypeVarint if field.wide => Type::TYPE_INT64,
+                wire_format::WireType::WireTypeVarint => Type::TYPE_INT32,
+                wire_format::WireType::WireTypeFixed32 => Type::TYPE_FIXED32,
+                wire_format::WireType::WireTypeFixed64 => Type::TYPE_FIXED64,
+                _ => match field.message {
+                    Some(ref message) if !field.text => {
+                        let nested_name = format!("{}.Field{}", name, number);
+                        field_proto.set_type_name(nested_name.clone());
+                        proto.mut_nested_type().push(message.to_proto(&nested_name));
+                        Type::TYPE_MESSAGE
+                    }
+                    _ if field.utf8 => Type::TYPE_STRING,
+                    _ => Type::TYPE_BYTES,
+                },
+            };
+            field_proto.set_field_type(field_type);
+            proto.mut_field().push(field_proto);
+        }
+
+        proto
+    }
+}
+
+enum Observed {
+    Varint(u64),
+    Fixed,
+    LengthDelimited(Vec<u8>),
+}
+
+impl InferredField {
+    fn new(wire_type: wire_format::WireType) -> InferredField {
+        InferredField {
+            wire_type,
+            repeated: false,
+            wide: false,
+            utf8: true,
+            text: true,
+            message: if wire_type == wire_format::WireType::WireTypeLengthDelimited {
+                Some(InferredMessage::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn observe(&mut self, value: Observed, depth: u32) {
+        match value {
+            Observed::Varint(v) => self.wide |= v as i64 != i64::from(v as i32),
+            Observed::Fixed => (),
+            Observed::LengthDelimited(bytes) => {
+                match str::from_utf8(&bytes) {
+                    Ok(s) => self.text &= s.chars().all(|c| !c.is_control() || c.is_whitespace()),
+                    Err(_) => {
+                        self.utf8 = false;
+                        self.text = false;
+                    }
+                }
+                if depth >= MAX_DEPTH {
+                    self.message = None;
+                }
+                if let Some(mut message) = self.message.take() {
+                    if message.add(&bytes, depth + 1).is_ok() {
+                        self.message = Some(message);
+                    }
+                }
+            }
+        }
+    }
+}
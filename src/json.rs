@@ -0,0 +1,733 @@
+//! Conversion of dynamic messages from and to the proto3 JSON mapping.
+//!
+//! The functions in this module follow the [JSON mapping][1] of the protobuf specification: fields
+//! are keyed by their JSON name (the lower camel case field name, unless overridden), 64-bit
+//! integers are encoded as strings, `bytes` as base64 and enums by the names of their values, and
+//! the well-known types like `google.protobuf.Timestamp`, `Duration`, `FieldMask`, `Struct`,
+//! `Any` and the wrapper types have their own special representations.
+//!
+//! Absent fields, fields that hold their default value and empty repeated fields are omitted from
+//! the output.  When parsing, fields
+//! can be given by either their JSON name or their original name, and unknown fields cause an
+//! error.
+//!
+//! [1]: https://developers.google.com/protocol-buffers/docs/proto3#json
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde_json;
+
+use crate::de;
+use crate::descriptor;
+use crate::descriptor::FieldType;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+use crate::well_known;
+
+const ANY: &str = ".google.protobuf.Any";
+const STRUCT: &str = ".google.protobuf.Struct";
+const VALUE: &str = ".google.protobuf.Value";
+const LIST_VALUE: &str = ".google.protobuf.ListValue";
+const NULL_VALUE: &str = ".google.protobuf.NullValue";
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Converts a message of the specified type to its JSON representation.
+pub fn to_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<serde_json::Value> {
+    let json = match descriptor.name() {
+        well_known::TIMESTAMP => {
+            let timestamp = well_known::Timestamp::from_message(message)?;
+            if !timestamp.is_valid() {
+                return Err(error::Error::OutOfRange {
+                    name: well_known::TIMESTAMP.to_owned(),
+                });
+            }
+            serde_json::Value::String(timestamp.to_string())
+        }
+        well_known::DURATION => {
+            let duration = well_known::Duration::from_message(message)?;
+            if !duration.is_valid() {
+                return Err(error::Error::OutOfRange {
+                    name: well_known::DURATION.to_owned(),
+                });
+            }
+            serde_json::Value::String(duration.to_string())
+        }
+        field_mask::FIELD_MASK => {
+            let paths = repeated(message, 1)
+                .iter()
+                .map(|v| match v {
+                    value::Value::String(p) => Ok(p.clone()),
+                    _ => Err(bad_field_value("paths")),
+                })
+                .collect::<error::Result<Vec<_>>>()?;
+            field_mask_to_json(&field_mask::FieldMask { paths })
+        }
+        ANY => any_to_json(descriptors, message)?,
+        STRUCT => {
+            let entries = descriptor
+                .field_by_number(1)
+                .ok_or_else(|| bad_field_value("fields"));
+            map_to_json(descriptors, entries?, repeated(message, 1))?
+        }
+        VALUE => {
+            let kind = message.fields.iter().find_map(|(&number, f)| match f {
+                value::Field::Singular(Some(v)) => Some((number, v)),
+                _ => None,
+            });
+            match kind {
+                Some((number, v)) => {
+                    let field = descriptor.field_by_number(number);
+                    value_to_json(
+                        descriptors,
+                        field.ok_or_else(|| bad_field_value("kind"))?,
+                        v,
+                    )?
+                }
+                None => serde_json::Value::Null,
+            }
+        }
+        LIST_VALUE => {
+            let values = descriptor
+                .field_by_number(1)
+                .ok_or_else(|| bad_field_value("values"));
+            repeated_to_json(descriptors, values?, repeated(message, 1))?
+        }
+        name if well_known::WRAPPERS.contains(&name) => {
+            let field = descriptor
+                .field_by_number(1)
+                .ok_or_else(|| bad_field_value("value"))?;
+            match message.fields.get(&1) {
+                Some(value::Field::Singular(Some(v))) => value_to_json(descriptors, field, v)?,
+                _ => match de::zero_value(descriptors, field) {
+                    Some(v) => value_to_json(descriptors, field, &v)?,
+                    None => serde_json::Value::Null,
+                },
+            }
+        }
+        _ => {
+            let mut object = serde_json::Map::new();
+            for field in descriptor.fields() {
+                let json = match message.fields.get(&field.number()) {
+                    Some(value::Field::Singular(Some(v))) if !is_omitted(field, v) => {
+                        value_to_json(descriptors, field, v)?
+                    }
+                    Some(value::Field::Repeated(vs)) if !vs.is_empty() => {
+                        match field.field_type(descriptors) {
+                            FieldType::Message(entry) if entry.is_map_entry() => {
+                                map_to_json(descriptors, field, vs)?
+                            }
+                            _ => repeated_to_json(descriptors, field, vs)?,
+                        }
+                    }
+                    Some(value::Field::RepeatedTyped(vs)) if !vs.is_empty() => {
+                        repeated_to_json(descriptors, field, &vs.iter().collect::<Vec<_>>())?
+                    }
+                    _ => continue,
+                };
+                object.insert(field.json_name().to_owned(), json);
+            }
+            serde_json::Value::Object(object)
+        }
+    };
+    Ok(json)
+}
+
+/// Converts the JSON representation of a message of the specified type to a message.
+pub fn from_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<value::Message> {
+    let mut message = value::Message::new(descriptor);
+    merge_json(descriptors, descriptor, &mut message, json)?;
+    Ok(message)
+}
+
+/// Merges the JSON representation of a message of the specified type into a message.
+///
+/// Singular fields that are present in the JSON replace those of the message, while repeated
+/// fields are appended to.  A `null` clears a singular field, which then holds `Value::Null` so
+/// that it is written back as `null`, e.g. to express clearing a field in the body of a PATCH
+/// request; `null` for a repeated field is ignored.
+pub fn merge_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &mut value::Message,
+    json: &serde_json::Value,
+) -> error::Result<()> {
+    let bad = || bad_json_value(descriptor.name());
+
+    match descriptor.name() {
+        well_known::TIMESTAMP => {
+            let timestamp = json.as_str().and_then(well_known::Timestamp::parse);
+            let timestamp = timestamp.ok_or_else(bad)?.to_message(descriptor);
+            message.fields.extend(timestamp.fields);
+        }
+        well_known::DURATION => {
+            let duration = json.as_str().and_then(well_known::Duration::parse);
+            let duration = duration.ok_or_else(bad)?.to_message(descriptor);
+            message.fields.extend(duration.fields);
+        }
+        field_mask::FIELD_MASK => {
+            let mask = json.as_str().map(field_mask_from_json).ok_or_else(bad)?;
+            let paths = mask.paths.into_iter().map(value::Value::String);
+            message
+                .fields
+                .insert(1, value::Field::Repeated(paths.collect()));
+        }
+        ANY => any_from_json(descriptors, message, json)?,
+        STRUCT => {
+            let field = descriptor.field_by_number(1).ok_or_else(bad)?;
+            let entries = map_from_json(descriptors, field, json)?;
+            push_all(message, field, entries);
+        }
+        VALUE => {
+            let number = match *json {
+                serde_json::Value::Null => 1,
+                serde_json::Value::Number(_) => 2,
+                serde_json::Value::String(_) => 3,
+                serde_json::Value::Bool(_) => 4,
+                serde_json::Value::Object(_) => 5,
+                serde_json::Value::Array(_) => 6,
+            };
+            let field = descriptor.field_by_number(number).ok_or_else(bad)?;
+            let value = value_from_json(descriptors, field, json)?;
+            for field in descriptor.fields() {
+                message
+                    .fields
+                    .insert(field.number(), value::Field::new(field));
+            }
+            message
+                .fields
+                .insert(number, value::Field::Singular(Some(value)));
+        }
+        LIST_VALUE => {
+            let field = descriptor.field_by_number(1).ok_or_else(bad)?;
+            let values = repeated_from_json(descriptors, field, json)?;
+            push_all(message, field, values);
+        }
+        name if well_known::WRAPPERS.contains(&name) => {
+            let field = descriptor.field_by_number(1).ok_or_else(bad)?;
+            let value = value_from_json(descriptors, field, json)?;
+            message
+                .fields
+                .insert(1, value::Field::Singular(Some(value)));
+        }
+        _ => {
+            let object = match *json {
+                serde_json::Value::Object(ref object) => object,
+                serde_json::Value::Null => return Ok(()),
+                _ => return Err(bad()),
+            };
+            for (key, json) in object {
+                let field = descriptor
+                    .field_by_json_name(key)
+                    .or_else(|| descriptor.field_by_name(key))
+                    .ok_or_else(|| error::Error::UnknownField { name: key.clone() })?;
+                if json.is_null() && !accepts_null(descriptors, field) {
+                    if !field.is_repeated() {
+                        message.fields.insert(
+                            field.number(),
+                            value::Field::Singular(Some(value::Value::Null)),
+                        );
+                    }
+                    continue;
+                }
+                if field.is_repeated() {
+                    let values = match field.field_type(descriptors) {
+                        FieldType::Message(entry) if entry.is_map_entry() => {
+                            map_from_json(descriptors, field, json)?
+                        }
+                        _ => repeated_from_json(descriptors, field, json)?,
+                    };
+                    push_all(message, field, values);
+                } else {
+                    let value = value_from_json(descriptors, field, json)?;
+                    message
+                        .fields
+                        .insert(field.number(), value::Field::Singular(Some(value)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a set field is left out of a JSON object.  Fields without presence are omitted when
+/// they hold their default value, and so are fields holding the default declared in the schema,
+/// because `Message::new` fills those in for fields that were never set.
+fn is_omitted(field: &descriptor::FieldDescriptor, value: &value::Value) -> bool {
+    value::is_default(field, value) && (!field.has_presence() || field.default_value().is_some())
+}
+
+/// Converts a single value of the specified field to its JSON representation.
+pub fn value_to_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+) -> error::Result<serde_json::Value> {
+    let json = match (field.field_type(descriptors), value) {
+        (_, &value::Value::Bool(v)) => serde_json::Value::Bool(v),
+        (_, &value::Value::I32(v)) => serde_json::Value::from(v),
+        (_, &value::Value::U32(v)) => serde_json::Value::from(v),
+        (_, &value::Value::I64(v)) => serde_json::Value::String(v.to_string()),
+        (_, &value::Value::U64(v)) => serde_json::Value::String(v.to_string()),
+        // Go through the shortest decimal representation, so that e.g. 0.1f32 stays 0.1
+        (_, &value::Value::F32(v)) => float_to_json(f64::from_str(&v.to_string()).unwrap_or(0.0)),
+        (_, &value::Value::F64(v)) => float_to_json(v),
+        (_, value::Value::Bytes(v)) => serde_json::Value::String(encode_base64(v)),
+        (_, value::Value::String(v)) => serde_json::Value::String(v.clone()),
+        (_, value::Value::FieldMask(m)) => field_mask_to_json(m),
+        (_, &value::Value::Null) => serde_json::Value::Null,
+        (FieldType::Enum(e), _) if e.name() == NULL_VALUE => serde_json::Value::Null,
+        (FieldType::Enum(e), &value::Value::Enum(v)) => match e.value_by_number(v) {
+            Some(v) => serde_json::Value::String(v.name().to_owned()),
+            None => serde_json::Value::from(v),
+        },
+        (FieldType::Message(m), value::Value::Message(v)) => to_json(descriptors, m, v)?,
+        _ => return Err(bad_field_value(field.name())),
+    };
+    Ok(json)
+}
+
+/// Converts the JSON representation of a single value of the specified field to a value.
+pub fn value_from_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<value::Value> {
+    let bad = || bad_json_value(field.name());
+
+    let value = match field.field_type(descriptors) {
+        FieldType::Bool => value::Value::Bool(json.as_bool().ok_or_else(bad)?),
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => {
+            value::Value::I32(integer_from_json(json).ok_or_else(bad)?)
+        }
+        FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => {
+            value::Value::I64(integer_from_json(json).ok_or_else(bad)?)
+        }
+        FieldType::UInt32 | FieldType::Fixed32 => {
+            value::Value::U32(integer_from_json(json).ok_or_else(bad)?)
+        }
+        FieldType::UInt64 | FieldType::Fixed64 => {
+            value::Value::U64(integer_from_json(json).ok_or_else(bad)?)
+        }
+        FieldType::Float => value::Value::F32(float_from_json(json).ok_or_else(bad)? as f32),
+        FieldType::Double => value::Value::F64(float_from_json(json).ok_or_else(bad)?),
+        FieldType::String => value::Value::String(json.as_str().ok_or_else(bad)?.to_owned()),
+        FieldType::Bytes => {
+            value::Value::Bytes(json.as_str().and_then(decode_base64).ok_or_else(bad)?)
+        }
+        FieldType::Enum(e) if e.name() == NULL_VALUE && json.is_null() => value::Value::Enum(0),
+        FieldType::Enum(e) => match *json {
+            serde_json::Value::String(ref name) => {
+                value::Value::Enum(e.value_by_name(name).ok_or_else(bad)?.number())
+            }
+            _ => value::Value::Enum(integer_from_json(json).ok_or_else(bad)?),
+        },
+        FieldType::Message(m) if m.name() == field_mask::FIELD_MASK => {
+            value::Value::FieldMask(json.as_str().map(field_mask_from_json).ok_or_else(bad)?)
+        }
+        FieldType::Message(m) => value::Value::Message(Arc::new(from_json(descriptors, m, json)?)),
+        FieldType::Group | FieldType::UnresolvedEnum(_) | FieldType::UnresolvedMessage(_) => {
+            return Err(bad())
+        }
+    };
+    Ok(value)
+}
+
+fn repeated(message: &value::Message, number: i32) -> &[value::Value] {
+    match message.fields.get(&number) {
+        Some(value::Field::Repeated(vs)) => &vs[..],
+        _ => &[],
+    }
+}
+
+fn push_all(
+    message: &mut value::Message,
+    field: &descriptor::FieldDescriptor,
+    values: Vec<value::Value>,
+) {
+    let entry = message
+        .fields
+        .entry(field.number())
+        .or_insert_with(|| value::Field::new(field));
+    entry.make_untyped();
+    if let value::Field::Repeated(vs) = entry {
+        vs.extend(values);
+    } else {
+        *entry = value::Field::Repeated(values.into_iter().collect());
+    }
+}
+
+fn repeated_to_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    values: &[value::Value],
+) -> error::Result<serde_json::Value> {
+    values
+        .iter()
+        .map(|v| value_to_json(descriptors, field, v))
+        .collect::<error::Result<Vec<_>>>()
+        .map(serde_json::Value::Array)
+}
+
+fn repeated_from_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<Vec<value::Value>> {
+    match *json {
+        serde_json::Value::Array(ref values) => values
+            .iter()
+            .map(|v| value_from_json(descriptors, field, v))
+            .collect(),
+        _ => Err(bad_json_value(field.name())),
+    }
+}
+
+/// Converts the entries of a map field to a JSON object.
+fn map_to_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    entries: &[value::Value],
+) -> error::Result<serde_json::Value> {
+    let (key_field, value_field) = map_entry_fields(descriptors, field)?;
+    let mut object = serde_json::Map::new();
+    for entry in entries {
+        let entry = match entry {
+            value::Value::Message(m) => m,
+            _ => return Err(bad_field_value(field.name())),
+        };
+        let key = match entry.fields.get(&1) {
+            Some(value::Field::Singular(Some(value::Value::String(k)))) => k.clone(),
+            Some(value::Field::Singular(Some(k))) => {
+                match value_to_json(descriptors, key_field, k)? {
+                    serde_json::Value::String(k) => k,
+                    k => k.to_string(),
+                }
+            }
+            _ => match de::zero_value(descriptors, key_field) {
+                Some(value::Value::String(k)) => k,
+                Some(k) => value_to_json(descriptors, key_field, &k)?.to_string(),
+                None => String::new(),
+            },
+        };
+        let value = match entry.fields.get(&2) {
+            Some(value::Field::Singular(Some(v))) => value_to_json(descriptors, value_field, v)?,
+            _ => match de::zero_value(descriptors, value_field) {
+                Some(v) => value_to_json(descriptors, value_field, &v)?,
+                None => serde_json::Value::Object(serde_json::Map::new()),
+            },
+        };
+        object.insert(key, value);
+    }
+    Ok(serde_json::Value::Object(object))
+}
+
+/// Converts a JSON object to the entries of a map field.
+fn map_from_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<Vec<value::Value>> {
+    let (key_field, value_field) = map_entry_fields(descriptors, field)?;
+    let entry_type = match field.field_type(descriptors) {
+        FieldType::Message(m) => m,
+        _ => return Err(bad_json_value(field.name())),
+    };
+    let object = json
+        .as_object()
+        .ok_or_else(|| bad_json_value(field.name()))?;
+
+    let mut entries = Vec::with_capacity(object.len());
+    for (key, json) in object {
+        let key = match key_field.field_type(descriptors) {
+            FieldType::Bool => match key.as_str() {
+                "true" => value::Value::Bool(true),
+                "false" => value::Value::Bool(false),
+                _ => return Err(bad_json_value(key_field.name())),
+            },
+            _ => value_from_json(
+                descriptors,
+                key_field,
+                &serde_json::Value::String(key.clone()),
+            )?,
+        };
+        let value = value_from_json(descriptors, value_field, json)?;
+        let mut entry = value::Message::new(entry_type);
+        entry.fields.insert(1, value::Field::Singular(Some(key)));
+        entry.fields.insert(2, value::Field::Singular(Some(value)));
+        entries.push(value::Value::Message(Arc::new(entry)));
+    }
+    Ok(entries)
+}
+
+fn map_entry_fields<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    field: &'a descriptor::FieldDescriptor,
+) -> error::Result<(
+    &'a descriptor::FieldDescriptor,
+    &'a descriptor::FieldDescriptor,
+)> {
+    match field.field_type(descriptors) {
+        FieldType::Message(entry) => match (entry.field_by_number(1), entry.field_by_number(2)) {
+            (Some(key), Some(value)) => Ok((key, value)),
+            _ => Err(bad_field_value(field.name())),
+        },
+        _ => Err(bad_field_value(field.name())),
+    }
+}
+
+fn any_to_json(
+    descriptors: &descriptor::Descriptors,
+    message: &value::Message,
+) -> error::Result<serde_json::Value> {
+    let type_url = match message.fields.get(&1) {
+        Some(value::Field::Singular(Some(value::Value::String(url)))) => url.as_str(),
+        _ => return Ok(serde_json::Value::Object(serde_json::Map::new())),
+    };
+    let bytes = match message.fields.get(&2) {
+        Some(value::Field::Singular(Some(value::Value::Bytes(b)))) => b.as_slice(),
+        _ => &[],
+    };
+    let descriptor = any_type(descriptors, type_url)?;
+
+    let mut inner = value::Message::new(descriptor);
+    inner.merge_from(
+        descriptors,
+        descriptor,
+        &mut protobuf::CodedInputStream::from_bytes(bytes),
+    )?;
+    let json = to_json(descriptors, descriptor, &inner)?;
+
+    let mut object = match json {
+        serde_json::Value::Object(object) if !has_special_json(descriptor) => object,
+        json => {
+            let mut object = serde_json::Map::new();
+            object.insert("value".to_owned(), json);
+            object
+        }
+    };
+    object.insert(
+        "@type".to_owned(),
+        serde_json::Value::String(type_url.to_owned()),
+    );
+    Ok(serde_json::Value::Object(object))
+}
+
+fn any_from_json(
+    descriptors: &descriptor::Descriptors,
+    message: &mut value::Message,
+    json: &serde_json::Value,
+) -> error::Result<()> {
+    let object = json.as_object().ok_or_else(|| bad_json_value(ANY))?;
+    let type_url = match object.get("@type") {
+        Some(serde_json::Value::String(url)) => url,
+        None if object.is_empty() => return Ok(()),
+        _ => return Err(bad_json_value("@type")),
+    };
+    let descriptor = any_type(descriptors, type_url)?;
+
+    let inner = if has_special_json(descriptor) {
+        from_json(
+            descriptors,
+            descriptor,
+            object.get("value").unwrap_or(&serde_json::Value::Null),
+        )?
+    } else {
+        let mut fields = object.clone();
+        fields.remove("@type");
+        from_json(descriptors, descriptor, &serde_json::Value::Object(fields))?
+    };
+
+    let bytes = inner.write_to_bytes(descriptors, descriptor)?;
+    message.fields.insert(
+        1,
+        value::Field::Singular(Some(value::Value::String(type_url.clone()))),
+    );
+    message
+        .fields
+        .insert(2, value::Field::Singular(Some(value::Value::Bytes(bytes))));
+    Ok(())
+}
+
+/// Looks up the message type of a `google.protobuf.Any` type URL.
+fn any_type<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    type_url: &str,
+) -> error::Result<&'a descriptor::MessageDescriptor> {
+    descriptors
+        .message_by_type_url(type_url)
+        .ok_or_else(|| error::Error::UnknownMessage {
+            name: type_url.to_owned(),
+        })
+}
+
+/// Whether the message type has a JSON representation other than an object of its fields.
+fn has_special_json(descriptor: &descriptor::MessageDescriptor) -> bool {
+    match descriptor.name() {
+        well_known::TIMESTAMP | well_known::DURATION | field_mask::FIELD_MASK => true,
+        ANY | STRUCT | VALUE | LIST_VALUE => true,
+        name => well_known::WRAPPERS.contains(&name),
+    }
+}
+
+fn accepts_null(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+) -> bool {
+    match field.field_type(descriptors) {
+        FieldType::Message(m) => m.name() == VALUE,
+        FieldType::Enum(e) => e.name() == NULL_VALUE,
+        _ => false,
+    }
+}
+
+fn field_mask_to_json(mask: &field_mask::FieldMask) -> serde_json::Value {
+    let paths: Vec<_> = mask
+        .paths
+        .iter()
+        .map(|p| {
+            p.split('.')
+                .map(descriptor::to_json_name)
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect();
+    serde_json::Value::String(paths.join(","))
+}
+
+fn field_mask_from_json(s: &str) -> field_mask::FieldMask {
+    let paths = s.split(',').filter(|p| !p.is_empty()).map(|p| {
+        let mut path = String::with_capacity(p.len() + 4);
+        for c in p.chars() {
+            if c.is_ascii_uppercase() {
+                path.push('_');
+                path.push(c.to_ascii_lowercase());
+            } else {
+                path.push(c);
+            }
+        }
+        path
+    });
+    field_mask::FieldMask::new(paths)
+}
+
+fn float_to_json(v: f64) -> serde_json::Value {
+    if v.is_nan() {
+        serde_json::Value::String("NaN".to_owned())
+    } else if v == f64::INFINITY {
+        serde_json::Value::String("Infinity".to_owned())
+    } else if v == f64::NEG_INFINITY {
+        serde_json::Value::String("-Infinity".to_owned())
+    } else {
+        serde_json::Value::from(v)
+    }
+}
+
+fn float_from_json(json: &serde_json::Value) -> Option<f64> {
+    match *json {
+        serde_json::Value::Number(ref n) => n.as_f64(),
+        serde_json::Value::String(ref s) => match s.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            s => f64::from_str(s).ok(),
+        },
+        _ => None,
+    }
+}
+
+/// Reads an integer from a JSON number or string, which must not have a fractional part.
+fn integer_from_json<T>(json: &serde_json::Value) -> Option<T>
+where
+    T: FromStr + TryFrom<i64> + TryFrom<u64>,
+{
+    match *json {
+        serde_json::Value::Number(ref n) => {
+            if let Some(v) = n.as_i64() {
+                T::try_from(v).ok()
+            } else if let Some(v) = n.as_u64() {
+                T::try_from(v).ok()
+            } else {
+                let v = n.as_f64()?;
+                if v.fract() == 0.0 && v.abs() < 9.007_199_254_740_992e15 {
+                    T::try_from(v as i64).ok()
+                } else {
+                    None
+                }
+            }
+        }
+        serde_json::Value::String(ref s) => T::from_str(s).ok(),
+        _ => None,
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// Decodes standard or URL-safe base64, with or without padding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut result = Vec::with_capacity(s.len() * 3 / 4);
+    let mut n = 0u32;
+    for (i, c) in s.bytes().enumerate() {
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        n = (n << 6) | u32::from(digit);
+        if i % 4 == 3 {
+            result.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8]);
+            n = 0;
+        }
+    }
+    match s.len() % 4 {
+        0 => (),
+        2 => result.push((n >> 4) as u8),
+        3 => result.extend_from_slice(&[(n >> 10) as u8, (n >> 2) as u8]),
+        _ => return None,
+    }
+    Some(result)
+}
+
+fn bad_field_value(name: &str) -> error::Error {
+    error::Error::BadFieldValue {
+        field: name.to_owned(),
+    }
+}
+
+fn bad_json_value(name: &str) -> error::Error {
+    error::Error::BadJsonValue {
+        field: name.to_owned(),
+    }
+}
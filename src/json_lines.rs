@@ -0,0 +1,204 @@
+//! Conversion between JSON Lines and encoded messages, for backfills, imports and exports.
+//!
+//! When importing, every non-blank line of the input is parsed as the proto3 JSON representation of a message of
+//! one type, see the [`json`](../json/index.html) module, and encoded.  A line that isn't valid
+//! JSON or doesn't match the message type doesn't stop the conversion; its error is reported
+//! with its line number, so that bad records can be fixed and imported again.
+//!
+//! When exporting, length-delimited records are read and written as JSON lines one at a time, so
+//! that exports of large files need memory for a single record only.
+//!
+//! This module is only available with the `serde_json` feature.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::json_lines;
+//! use serde_protobuf::value::LengthDelimitedRecords;
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.ForeignMessage")
+//!     .unwrap();
+//!
+//! let input = "{\"c\": 1}\n{\"c\": \"x\"}\n\n{\"c\": 3}\n";
+//! let mut output = Vec::new();
+//! let rejected =
+//!     json_lines::to_length_delimited(&descriptors, descriptor, input.as_bytes(), &mut output)
+//!         .unwrap();
+//! assert_eq!(1, rejected.len());
+//! assert_eq!(2, rejected[0].line);
+//! assert_eq!(2, LengthDelimitedRecords::new(&output).count());
+//! ```
+use std::io;
+
+use serde_json;
+
+use crate::descriptor;
+use crate::error;
+use crate::json;
+use crate::value;
+
+/// An error converting a single line of the input.
+#[derive(Debug)]
+pub struct RecordError {
+    /// The line number of the record, starting at 1.
+    pub line: usize,
+    /// The reason that the record couldn't be converted.
+    pub error: error::Error,
+}
+
+/// An iterator over the encoded messages of the lines of a JSON Lines input.
+///
+/// Blank lines are skipped.  Lines that can't be converted yield a `RecordError`, and the
+/// iteration continues with the next line; reading the input failing ends the iteration after
+/// yielding the error with the number of the line that couldn't be read.
+#[derive(Debug)]
+pub struct Records<'a, R> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    input: R,
+    line: usize,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+/// Creates an iterator over the encoded messages of the lines of a JSON Lines input.
+pub fn records<'a, R>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    input: R,
+) -> Records<'a, R>
+where
+    R: io::BufRead,
+{
+    Records {
+        descriptors,
+        descriptor,
+        input,
+        line: 0,
+        buffer: Vec::new(),
+        done: false,
+    }
+}
+
+/// Writes the messages of the lines of a JSON Lines input as length-delimited records, and
+/// returns the errors of the lines that couldn't be converted.
+///
+/// Fails only if reading the input or writing the output fails.
+pub fn to_length_delimited<R, W>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: R,
+    mut output: W,
+) -> error::Result<Vec<RecordError>>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut output = protobuf::CodedOutputStream::new(&mut output);
+    let mut rejected = Vec::new();
+    let mut records = records(descriptors, descriptor, input);
+    while let Some(record) = records.next() {
+        match record {
+            Ok(bytes) => {
+                output.write_raw_varint32(bytes.len() as u32)?;
+                output.write_raw_bytes(&bytes)?;
+            }
+            // Only failing to read the input ends the iteration with an error
+            Err(e) if records.done => return Err(e.error),
+            Err(e) => rejected.push(e),
+        }
+    }
+    output.flush()?;
+    Ok(rejected)
+}
+
+/// Writes the length-delimited records of an input as JSON lines, and returns the number of
+/// records.
+///
+/// Fails at the first record that can't be read, decoded or converted to JSON.
+pub fn from_length_delimited<R, W>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    mut input: R,
+    mut output: W,
+) -> error::Result<usize>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    let mut input = protobuf::CodedInputStream::new(&mut input);
+    let mut count = 0;
+    while !input.eof()? {
+        let len = input.read_raw_varint32()?;
+        let record = input.read_raw_bytes(len)?;
+        let mut message = value::Message::new(descriptor);
+        message.merge_from(
+            descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&record),
+        )?;
+        let json = json::to_json(descriptors, descriptor, &message)?;
+        serde_json::to_writer(&mut output, &json).map_err(|e| io_error(e.into()))?;
+        output.write_all(b"\n").map_err(io_error)?;
+        count += 1;
+    }
+    output.flush().map_err(io_error)?;
+    Ok(count)
+}
+
+impl<'a, R> Records<'a, R>
+where
+    R: io::BufRead,
+{
+    fn convert(&self, line: &[u8]) -> error::Result<Vec<u8>> {
+        let json: serde_json::Value =
+            serde_json::from_slice(line).map_err(|e| error::Error::Custom {
+                message: e.to_string(),
+            })?;
+        json::from_json(self.descriptors, self.descriptor, &json)?
+            .write_to_bytes(self.descriptors, self.descriptor)
+    }
+}
+
+impl<'a, R> Iterator for Records<'a, R>
+where
+    R: io::BufRead,
+{
+    type Item = Result<Vec<u8>, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buffer.clear();
+            self.line += 1;
+            match self.input.read_until(b'\n', &mut self.buffer) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    let line = self.buffer.trim_ascii();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    return Some(self.convert(line).map_err(|error| RecordError {
+                        line: self.line,
+                        error,
+                    }));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(RecordError {
+                        line: self.line,
+                        error: io_error(e),
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
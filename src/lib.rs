@@ -3,13 +3,83 @@
 //! The crate is split up into several logical parts.
 //!
 //!   * The [`descriptor`](descriptor/index.html) module provides an API for managing dynamically
-//!     loaded protocol buffer schemata.
+//!     loaded protocol buffer schemata, and can describe them as JSON Schema and OpenAPI documents
+//!     (behind the `serde_json` feature).
 //!   * The [`value`](value/index.html) module provides structs that can hold any raw protocol
-//!     buffer decoded data (but the representation is heavily coupled with a schema).
+//!     buffer decoded data (but the representation is heavily coupled with a schema), and can
+//!     encode such values back into the binary format.
+//!   * The [`compression`](compression/index.html) module (behind the `compression` feature)
+//!     compresses designated `bytes` fields with gzip or zstd as they are written, and
+//!     decompresses them as they are read.
+//!   * The [`conformance`](conformance/index.html) module implements the testee side of the
+//!     official protocol buffers conformance test suite.
+//!   * The [`cursor`](cursor/index.html) module navigates and edits messages by field name and
+//!     index, with typed getters and setters.
 //!   * The [`de`](de/index.html) module can be used to deserialize binary encoded protocol buffer
 //!     messages given some schema descriptors.
+//!   * The [`diagnostic`](diagnostic/index.html) module (behind the `miette` feature) renders
+//!     errors as `miette` diagnostics that point at byte offsets and suggest field names.
+//!   * The [`envelope`](envelope/index.html) module decodes envelope messages that carry a
+//!     payload of the type that they name.
+//!   * The [`arena`](arena/index.html) module (behind the `arena` feature) can decode messages
+//!     into storage borrowed from a `bumpalo` arena.
+//!   * The [`mmap`](mmap/index.html) module (behind the `mmap` feature) decodes messages from
+//!     memory-mapped files without copying their strings and byte buffers.
+//!   * The [`parallel`](parallel/index.html) module (behind the `parallel` feature) can decode
+//!     many independent messages in parallel using `rayon`.
+//!   * The [`arrow`](arrow/index.html) module (behind the `arrow` feature) can export decoded
+//!     messages as Apache Arrow record batches.
+//!   * The [`parquet`](parquet/index.html) module (behind the `parquet` feature) writes decoded
+//!     messages to Apache Parquet files.
+//!   * The [`avro`](avro/index.html) module (behind the `serde_json` feature) translates
+//!     message types to Apache Avro schemas, and converts messages to and from Avro datums.
+//!   * The [`msgpack`](msgpack/index.html) and [`cbor`](cbor/index.html) modules (behind the
+//!     `rmp-serde` and `ciborium` features) convert messages to and from MessagePack and CBOR.
+//!   * The [`sql`](sql/index.html) module (behind the `postgres` feature) maps the scalar fields
+//!     of messages to SQL parameters and builds messages from result rows.
+//!   * The [`delimited`](delimited/index.html) module flattens messages into CSV or TSV rows
+//!     with dotted column names, for quick data dumps.
+//!   * The [`google_type`](google_type/index.html) module converts common `google.type` messages
+//!     like `Money` and `Date` to plain Rust types.
+//!   * The [`well_known`](well_known/index.html) module can validate and normalize
+//!     `google.protobuf.Timestamp` and `Duration` values.
+//!   * The [`infer`](infer/index.html) module guesses message descriptors from sample payloads
+//!     of undocumented message types.
+//!   * The [`json`](json/index.html) module (behind the `serde_json` feature) converts messages
+//!     from and to the proto3 JSON mapping.
+//!   * The [`json_lines`](json_lines/index.html) module (behind the `serde_json` feature)
+//!     converts between JSON Lines files and length-delimited records.
+//!   * The [`http`](http/index.html) module reads the `google.api.http` annotations that map
+//!     service methods to REST endpoints, and can transcode HTTP/JSON requests to request
+//!     messages (behind the `serde_json` feature).
+//!   * The [`predicate`](predicate/index.html) module compiles expressions like
+//!     `order.total > 1000 && order.currency == "USD"` into predicates over messages.
+//!   * The [`projection`](projection/index.html) module compiles field selections once and
+//!     projects many messages to them.
+//!   * The [`profile`](profile/index.html) module reports per-field presence rates, value
+//!     ranges and undeclared field numbers over many messages, to detect schema drift.
+//!   * The [`reflection`](reflection/index.html) module (behind the `reflection` feature) serves
+//!     gRPC server reflection from a descriptor registry, for `grpcurl` and similar tools.
+//!   * The [`remap`](remap/index.html) module converts messages between near-identical message
+//!     types along a correspondence between their fields, coercing values between field types.
+//!   * The [`roundtrip`](roundtrip/index.html) module checks that messages survive a decode and
+//!     encode round trip, for use in fuzzing and conformance runs.
+//!   * The [`status`](status/index.html) module can decode `google.rpc.Status` error payloads and
+//!     their details.
+//!   * The [`stream`](stream/index.html) module decodes the elements of huge repeated fields one
+//!     at a time, without decoding the whole message.
 //!
-//! Serialization is not yet implemented in this version.
+//! Decoding is meant for untrusted input: no sequence of input bytes makes it panic, and
+//! malformed input is reported as an error instead.  Tests decode random and mutated input to
+//! check this.  Group fields aren't supported, and are skipped like unknown groups.
+//!
+//! With the `tracing` feature, decoding and encoding of messages is instrumented with `tracing`
+//! spans and events that record the message type, byte count and field counts.
+//!
+//! There is no `serde` serializer for the binary format yet, but values that implement
+//! `Serialize` can be converted into dynamic messages with `value::Message::from_typed`, and
+//! decoded messages can be converted to types that implement `Deserialize` with
+//! `value::Message::to_typed`.
 //!
 //! [1]: https://developers.google.com/protocol-buffers/
 #![deny(warnings)]
@@ -30,9 +100,61 @@ extern crate log;
 #[macro_use]
 extern crate serde;
 
+mod macros;
+
+#[cfg(feature = "arena")]
+pub mod arena;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "serde_json")]
+pub mod avro;
+#[cfg(feature = "ciborium")]
+pub mod cbor;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod conformance;
+pub mod cursor;
 pub mod de;
+pub mod delimited;
 pub mod descriptor;
+#[cfg(feature = "miette")]
+pub mod diagnostic;
+pub mod envelope;
 pub mod error;
+pub mod field_mask;
+pub mod google_type;
+pub mod http;
+pub mod infer;
+#[cfg(feature = "serde_json")]
+pub mod json;
+#[cfg(feature = "serde_json")]
+pub mod json_lines;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "rmp-serde")]
+pub mod msgpack;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod predicate;
+pub mod profile;
+pub mod projection;
+#[cfg(feature = "reflection")]
+pub mod reflection;
+pub mod remap;
+pub mod roundtrip;
+mod ser;
+#[cfg(feature = "postgres")]
+pub mod sql;
+pub mod status;
+pub mod stream;
 pub mod value;
+pub mod well_known;
 
 pub use crate::error::Error;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::ser::{message_from_literal, Content};
+}
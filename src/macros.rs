@@ -0,0 +1,102 @@
+/// Builds a dynamic message from a literal description of its fields.
+///
+/// The first argument is the descriptor registry, followed by the (optionally dot-prefixed)
+/// message type name and the fields in braces.  Nested messages are written in braces, repeated
+/// fields in brackets, and enum values by name as strings.  Literals are converted to the type of
+/// their field, so e.g. `5` works for both `int32` and `uint64` fields, but out of range values
+/// and unknown field names are reported as errors.  Map fields with string keys can be written
+/// in braces, and any map field as a list of `{ key: ..., value: ... }` entries.
+///
+/// ```
+/// # #[macro_use] extern crate serde_protobuf;
+/// # extern crate protobuf;
+/// # use std::fs;
+/// # use serde_protobuf::descriptor::Descriptors;
+/// # fn main() {
+/// # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+/// # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+/// let descriptors = Descriptors::from_proto(&proto);
+/// let message = dynamic_message!(&descriptors, "protobuf_unittest.TestAllTypes" {
+///     optional_int32: 5,
+///     optional_string: "hello",
+///     optional_nested_enum: "BAR",
+///     repeated_nested_message: [{ bb: 1 }, { bb: -2 }],
+/// })
+/// .unwrap();
+/// # let _ = message;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! dynamic_message {
+    ($descriptors:expr, $name:literal { $($fields:tt)* }) => {
+        $crate::__private::message_from_literal(
+            $descriptors,
+            $name,
+            $crate::__dynamic_message_content!({ $($fields)* }),
+        )
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __dynamic_message_content {
+    (@fields [$($out:tt)*]) => {
+        vec![$($out)*]
+    };
+    (@fields [$($out:tt)*] $key:ident : { $($value:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @fields [$($out)* $crate::__dynamic_message_content!(@key $key, { $($value)* }),]
+            $($($rest)*)?
+        )
+    };
+    (@fields [$($out:tt)*] $key:ident : [ $($value:tt)* ] $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @fields [$($out)* $crate::__dynamic_message_content!(@key $key, [ $($value)* ]),]
+            $($($rest)*)?
+        )
+    };
+    (@fields [$($out:tt)*] $key:ident : $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @fields [$($out)* $crate::__dynamic_message_content!(@key $key, $value),]
+            $($($rest)*)?
+        )
+    };
+    (@key $key:ident, $($value:tt)*) => {
+        (
+            $crate::__private::Content::from(stringify!($key)),
+            $crate::__dynamic_message_content!($($value)*),
+        )
+    };
+
+    (@values [$($out:tt)*]) => {
+        vec![$($out)*]
+    };
+    (@values [$($out:tt)*] { $($value:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @values [$($out)* $crate::__dynamic_message_content!({ $($value)* }),]
+            $($($rest)*)?
+        )
+    };
+    (@values [$($out:tt)*] [ $($value:tt)* ] $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @values [$($out)* $crate::__dynamic_message_content!([ $($value)* ]),]
+            $($($rest)*)?
+        )
+    };
+    (@values [$($out:tt)*] $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__dynamic_message_content!(
+            @values [$($out)* $crate::__dynamic_message_content!($value),]
+            $($($rest)*)?
+        )
+    };
+
+    ({ $($fields:tt)* }) => {
+        $crate::__private::Content::Map($crate::__dynamic_message_content!(@fields [] $($fields)*))
+    };
+    ([ $($values:tt)* ]) => {
+        $crate::__private::Content::Seq($crate::__dynamic_message_content!(@values [] $($values)*))
+    };
+    ($value:expr) => {
+        $crate::__private::Content::from($value)
+    };
+}
@@ -0,0 +1,129 @@
+//! Decoding of memory-mapped files, for large offline datasets.
+//!
+//! A `MappedFile` maps a file into memory instead of reading it, and decodes messages from it
+//! into an arena.  Decoded strings and byte buffers borrow directly from the map, so only the
+//! message structure itself is allocated.
+//!
+//! ```
+//! # extern crate bumpalo;
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::arena;
+//! use serde_protobuf::mmap::MappedFile;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//! # let path = std::env::temp_dir().join(format!("mmap-doc-{}", std::process::id()));
+//! # fs::write(&path, &[2, 8, 42, 2, 8, 43]).unwrap();
+//!
+//! let bump = bumpalo::Bump::new();
+//! let file = MappedFile::open(&path).unwrap();
+//! for message in file.decode_records(&descriptors, descriptor, &bump) {
+//!     match message.unwrap().field(1) {
+//!         Some(&arena::Field::Singular(Some(arena::Value::I32(v)))) => assert!(v >= 42),
+//!         f => panic!("unexpected field {:?}", f),
+//!     }
+//! }
+//! # fs::remove_file(&path).unwrap();
+//! # }
+//! ```
+use std::fs;
+use std::io;
+use std::path;
+
+use bumpalo;
+use memmap2;
+use protobuf;
+
+use crate::arena;
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// A file mapped into memory.
+#[derive(Debug)]
+pub struct MappedFile {
+    map: memmap2::Mmap,
+}
+
+/// An iterator over the length-delimited messages in a mapped file.
+///
+/// Created by `MappedFile::decode_records`.
+#[derive(Debug)]
+pub struct MappedRecords<'a> {
+    records: value::LengthDelimitedRecords<'a>,
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    bump: &'a bumpalo::Bump,
+}
+
+impl MappedFile {
+    /// Maps the file at the specified path into memory.
+    ///
+    /// The file must not be modified while it is mapped; decoded strings that change underneath
+    /// the map may no longer be valid UTF-8.
+    pub fn open<P>(path: P) -> error::Result<MappedFile>
+    where
+        P: AsRef<path::Path>,
+    {
+        let file = fs::File::open(path).map_err(io_error)?;
+        // The file is required not to be modified for as long as it is mapped, see above
+        let map = unsafe { memmap2::Mmap::map(&file) }.map_err(io_error)?;
+        Ok(MappedFile { map })
+    }
+
+    /// The content of the file.
+    pub fn bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// Decodes the whole file as a single message of the specified type.
+    pub fn decode_message<'a>(
+        &'a self,
+        descriptors: &descriptor::Descriptors,
+        descriptor: &descriptor::MessageDescriptor,
+        bump: &'a bumpalo::Bump,
+    ) -> error::Result<arena::Message<'a>> {
+        let mut message = arena::Message::new_in(descriptor, bump);
+        message.merge_from_slice(descriptors, descriptor, self.bytes())?;
+        Ok(message)
+    }
+
+    /// Decodes the file as a sequence of messages of the specified type, each prefixed by its
+    /// length encoded as a varint.
+    pub fn decode_records<'a>(
+        &'a self,
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        bump: &'a bumpalo::Bump,
+    ) -> MappedRecords<'a> {
+        MappedRecords {
+            records: value::LengthDelimitedRecords::new(self.bytes()),
+            descriptors,
+            descriptor,
+            bump,
+        }
+    }
+}
+
+impl<'a> Iterator for MappedRecords<'a> {
+    type Item = error::Result<arena::Message<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        Some(record.and_then(|bytes| {
+            let mut message = arena::Message::new_in(self.descriptor, self.bump);
+            message.merge_from_slice(self.descriptors, self.descriptor, bytes)?;
+            Ok(message)
+        }))
+    }
+}
+
+fn io_error(e: io::Error) -> error::Error {
+    error::Error::Protobuf(protobuf::ProtobufError::IoError(e))
+}
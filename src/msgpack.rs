@@ -0,0 +1,41 @@
+//! Conversion of messages to and from MessagePack, using `rmp-serde`.
+//!
+//! Messages become MessagePack maps keyed by field name, in the same shape that `de::Deserializer`
+//! produces.  `bytes` fields are written as MessagePack binaries rather than arrays of integers,
+//! and 64-bit integers keep their full range.
+//!
+//! This module is only available with the `rmp-serde` feature.
+use crate::de;
+use crate::descriptor;
+use crate::error;
+use crate::ser;
+use crate::value;
+
+/// Encodes a message of the specified type as MessagePack.
+pub fn to_vec(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<Vec<u8>> {
+    let content: ser::Content = message.to_typed(descriptors, descriptor)?;
+    rmp_serde::to_vec(&content).map_err(custom_error)
+}
+
+/// Decodes a message of the specified type from MessagePack.
+pub fn from_slice(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: &[u8],
+) -> error::Result<value::Message> {
+    let content: ser::Content = rmp_serde::from_slice(input).map_err(custom_error)?;
+    ser::message_from_content(descriptors, descriptor, &de::Options::new(), content)
+}
+
+fn custom_error<E>(e: E) -> error::Error
+where
+    E: std::fmt::Display,
+{
+    error::Error::Custom {
+        message: e.to_string(),
+    }
+}
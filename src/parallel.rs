@@ -0,0 +1,74 @@
+//! Parallel decoding of independent messages.
+//!
+//! The functions in this module decode many records of the same message type at once, spreading
+//! the work over the [`rayon`](https://docs.rs/rayon) thread pool while sharing a single set of
+//! descriptors.  Results are always returned in the same order as the input records.
+//!
+//! The global `rayon` thread pool is used by default; to use a dedicated pool, call these functions
+//! from within `rayon::ThreadPool::install`.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::parallel;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! // Two length-delimited records, each setting `optional_int32`
+//! let data = [2, 8, 1, 2, 8, 2];
+//! let messages = parallel::decode_length_delimited(&descriptors, descriptor, &data).unwrap();
+//! assert_eq!(2, messages.len());
+//! # }
+//! ```
+use protobuf;
+use rayon::prelude::*;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// Decodes each of the specified records as a message of the given type, in parallel.
+pub fn decode_all<R>(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    records: &[R],
+) -> Vec<error::Result<value::Message>>
+where
+    R: AsRef<[u8]> + Sync,
+{
+    records
+        .par_iter()
+        .map(|record| decode(descriptors, message, record.as_ref()))
+        .collect()
+}
+
+/// Splits a buffer of length-delimited records and decodes each of them as a message of the given
+/// type, in parallel.
+///
+/// An error is returned if the framing of the buffer itself is broken; errors in individual
+/// records are reported in the corresponding result.
+pub fn decode_length_delimited(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<Vec<error::Result<value::Message>>> {
+    let records = value::LengthDelimitedRecords::new(bytes).collect::<error::Result<Vec<_>>>()?;
+    Ok(decode_all(descriptors, message, &records))
+}
+
+fn decode(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<value::Message> {
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    let mut result = value::Message::new(message);
+    result.merge_from(descriptors, message, &mut input)?;
+    Ok(result)
+}
@@ -0,0 +1,484 @@
+//! Export of dynamic messages as Apache Parquet files.
+//!
+//! A [`Writer`](struct.Writer.html) maps a message type to a Parquet schema, and writes messages
+//! of that type to row groups of a Parquet file, so that archived protobuf data can be queried
+//! without an intermediate JSON step.
+//!
+//!   * Singular fields become optional columns, with the field number as their field id.  Absent
+//!     fields are null.
+//!   * `string` fields become `BYTE_ARRAY` columns with the `STRING` logical type, enum fields
+//!     hold the names of their values with the `ENUM` logical type, and `uint32`, `uint64`,
+//!     `fixed32` and `fixed64` fields are unsigned `INT32` or `INT64` columns.
+//!   * Singular message fields become optional groups (structs) of their fields.
+//!   * Repeated fields become required `LIST` groups, with required elements.  Map fields are
+//!     lists of their key-value entries, and `google.protobuf.FieldMask` fields are lists of
+//!     their paths.
+//!   * Groups, recursive references to an enclosing message type and message fields without any
+//!     columns are skipped.
+//!
+//! This module is only available with the `parquet` feature.
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use ::parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use ::parquet::data_type::{
+    BoolType, ByteArray, ByteArrayType, DoubleType, FloatType, Int32Type, Int64Type,
+};
+use ::parquet::errors::ParquetError;
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use ::parquet::schema::types::{Type, TypePtr};
+
+use crate::descriptor;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// A writer of messages of one type to a Parquet file.
+pub struct Writer<'a, W>
+where
+    W: io::Write + Send,
+{
+    fields: Vec<Node<'a>>,
+    writer: SerializedFileWriter<W>,
+}
+
+/// A field of the schema, which is either a leaf column or a group of nested fields.
+struct Node<'a> {
+    field: &'a descriptor::FieldDescriptor,
+    repeated: bool,
+    kind: Kind<'a>,
+}
+
+enum Kind<'a> {
+    Leaf(
+        PhysicalType,
+        Option<LogicalType>,
+        Option<&'a descriptor::EnumDescriptor>,
+    ),
+    Group(Vec<Node<'a>>),
+}
+
+/// The values and levels of a leaf column in a row group.
+struct Column {
+    values: Values,
+    definition_levels: Vec<i16>,
+    repetition_levels: Vec<i16>,
+}
+
+enum Values {
+    Bool(Vec<bool>),
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    ByteArray(Vec<ByteArray>),
+}
+
+/// The Parquet schema that messages of the specified type are written with.
+pub fn schema(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+) -> error::Result<Type> {
+    let fields = schema_nodes(descriptors, descriptor, &mut vec![descriptor.name()]);
+    root_type(descriptor, &fields)
+}
+
+impl<'a, W> Writer<'a, W>
+where
+    W: io::Write + Send,
+{
+    /// Creates a writer of messages of the specified type, with the default writer properties.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        output: W,
+    ) -> error::Result<Writer<'a, W>> {
+        Writer::with_properties(
+            descriptors,
+            descriptor,
+            output,
+            WriterProperties::builder().build(),
+        )
+    }
+
+    /// Creates a writer of messages of the specified type, with the specified writer properties,
+    /// e.g. to enable compression.
+    pub fn with_properties(
+        descriptors: &'a descriptor::Descriptors,
+        descriptor: &'a descriptor::MessageDescriptor,
+        output: W,
+        properties: WriterProperties,
+    ) -> error::Result<Writer<'a, W>> {
+        let fields = schema_nodes(descriptors, descriptor, &mut vec![descriptor.name()]);
+        let schema = Arc::new(root_type(descriptor, &fields)?);
+        let writer = SerializedFileWriter::new(output, schema, Arc::new(properties))
+            .map_err(parquet_error)?;
+        Ok(Writer { fields, writer })
+    }
+
+    /// Writes the messages as a single row group.
+    pub fn write_row_group(&mut self, messages: &[value::Message]) -> error::Result<()> {
+        let mut row_group = self.writer.next_row_group().map_err(parquet_error)?;
+        write_columns(&self.fields, &mut Vec::new(), messages, &mut row_group)?;
+        row_group.close().map_err(parquet_error)?;
+        Ok(())
+    }
+
+    /// Writes a stream of decoded messages in row groups of the specified number of rows,
+    /// returning how many messages were written.
+    ///
+    /// The first error of the stream stops the writing, after the preceding messages were
+    /// written.
+    pub fn write_stream<I>(&mut self, messages: I, row_group_size: usize) -> error::Result<usize>
+    where
+        I: IntoIterator<Item = error::Result<value::Message>>,
+    {
+        let row_group_size = row_group_size.max(1);
+        let mut count = 0;
+        let mut row_group = Vec::with_capacity(row_group_size);
+        for message in messages {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    if !row_group.is_empty() {
+                        self.write_row_group(&row_group)?;
+                    }
+                    return Err(e);
+                }
+            };
+            row_group.push(message);
+            if row_group.len() == row_group_size {
+                self.write_row_group(&row_group)?;
+                count += row_group.len();
+                row_group.clear();
+            }
+        }
+        if !row_group.is_empty() {
+            self.write_row_group(&row_group)?;
+            count += row_group.len();
+        }
+        Ok(count)
+    }
+
+    /// Writes the file footer, and returns the output.
+    pub fn finish(self) -> error::Result<W> {
+        self.writer.into_inner().map_err(parquet_error)
+    }
+}
+
+impl<'a, W> fmt::Debug for Writer<'a, W>
+where
+    W: io::Write + Send,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Writer")
+            .field("fields", &self.fields.len())
+            .finish()
+    }
+}
+
+fn schema_nodes<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    enclosing: &mut Vec<&'a str>,
+) -> Vec<Node<'a>> {
+    use crate::descriptor::FieldType::*;
+
+    let mut nodes = Vec::new();
+    for field in message.fields() {
+        let mut repeated = field.is_repeated();
+        let leaf = |physical, logical| Kind::Leaf(physical, logical, None);
+        let kind = match field.field_type(descriptors) {
+            Message(m) if m.name() == field_mask::FIELD_MASK => {
+                if repeated {
+                    continue;
+                }
+                repeated = true;
+                leaf(PhysicalType::BYTE_ARRAY, Some(LogicalType::String))
+            }
+            Message(m) => {
+                if enclosing.contains(&m.name()) {
+                    continue;
+                }
+                enclosing.push(m.name());
+                let fields = schema_nodes(descriptors, m, enclosing);
+                enclosing.pop();
+                if fields.is_empty() {
+                    continue;
+                }
+                Kind::Group(fields)
+            }
+            Bool => leaf(PhysicalType::BOOLEAN, None),
+            Int32 | SInt32 | SFixed32 => leaf(PhysicalType::INT32, None),
+            Int64 | SInt64 | SFixed64 => leaf(PhysicalType::INT64, None),
+            UInt32 | Fixed32 => leaf(PhysicalType::INT32, Some(unsigned(32))),
+            UInt64 | Fixed64 => leaf(PhysicalType::INT64, Some(unsigned(64))),
+            Float => leaf(PhysicalType::FLOAT, None),
+            Double => leaf(PhysicalType::DOUBLE, None),
+            String => leaf(PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            Bytes => leaf(PhysicalType::BYTE_ARRAY, None),
+            Enum(e) => Kind::Leaf(PhysicalType::BYTE_ARRAY, Some(LogicalType::Enum), Some(e)),
+            Group | UnresolvedEnum(_) | UnresolvedMessage(_) => continue,
+        };
+        nodes.push(Node {
+            field,
+            repeated,
+            kind,
+        });
+    }
+    nodes
+}
+
+fn unsigned(bit_width: i8) -> LogicalType {
+    LogicalType::Integer {
+        bit_width,
+        is_signed: false,
+    }
+}
+
+fn root_type(descriptor: &descriptor::MessageDescriptor, fields: &[Node]) -> error::Result<Type> {
+    Type::group_type_builder(descriptor.name().trim_start_matches('.'))
+        .with_fields(fields.iter().map(node_type).collect::<error::Result<_>>()?)
+        .build()
+        .map_err(parquet_error)
+}
+
+fn node_type(node: &Node) -> error::Result<TypePtr> {
+    let name = if node.repeated {
+        "element"
+    } else {
+        node.field.name()
+    };
+    let repetition = if node.repeated {
+        Repetition::REQUIRED
+    } else {
+        Repetition::OPTIONAL
+    };
+    let id = if node.repeated {
+        None
+    } else {
+        Some(node.field.number())
+    };
+    let element = match node.kind {
+        Kind::Leaf(physical, ref logical, _) => Type::primitive_type_builder(name, physical)
+            .with_repetition(repetition)
+            .with_logical_type(logical.clone())
+            .with_id(id)
+            .build(),
+        Kind::Group(ref fields) => Type::group_type_builder(name)
+            .with_repetition(repetition)
+            .with_fields(fields.iter().map(node_type).collect::<error::Result<_>>()?)
+            .with_id(id)
+            .build(),
+    };
+    let element = Arc::new(element.map_err(parquet_error)?);
+    if !node.repeated {
+        return Ok(element);
+    }
+
+    let list = Type::group_type_builder("list")
+        .with_repetition(Repetition::REPEATED)
+        .with_fields(vec![element])
+        .build()
+        .map_err(parquet_error)?;
+    Type::group_type_builder(node.field.name())
+        .with_repetition(Repetition::REQUIRED)
+        .with_logical_type(Some(LogicalType::List))
+        .with_id(Some(node.field.number()))
+        .with_fields(vec![Arc::new(list)])
+        .build()
+        .map(Arc::new)
+        .map_err(parquet_error)
+}
+
+/// Writes the leaf columns below the nodes, in schema order.
+fn write_columns<'n, W>(
+    nodes: &'n [Node<'n>],
+    path: &mut Vec<&'n Node<'n>>,
+    messages: &[value::Message],
+    row_group: &mut SerializedRowGroupWriter<W>,
+) -> error::Result<()>
+where
+    W: io::Write + Send,
+{
+    for node in nodes {
+        path.push(node);
+        match node.kind {
+            Kind::Leaf(physical, _, _) => {
+                let mut column = Column::new(physical);
+                for message in messages {
+                    column.add(path, message, 0, 0, 0)?;
+                }
+                column.write(row_group)?;
+            }
+            Kind::Group(ref fields) => write_columns(fields, path, messages, row_group)?,
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+impl Column {
+    fn new(physical: PhysicalType) -> Column {
+        let values = match physical {
+            PhysicalType::BOOLEAN => Values::Bool(Vec::new()),
+            PhysicalType::INT32 => Values::Int32(Vec::new()),
+            PhysicalType::INT64 => Values::Int64(Vec::new()),
+            PhysicalType::FLOAT => Values::Float(Vec::new()),
+            PhysicalType::DOUBLE => Values::Double(Vec::new()),
+            _ => Values::ByteArray(Vec::new()),
+        };
+        Column {
+            values,
+            definition_levels: Vec::new(),
+            repetition_levels: Vec::new(),
+        }
+    }
+
+    /// Adds the values of the leaf at the end of the path in a message, with the definition and
+    /// repetition levels of the enclosing fields.
+    fn add(
+        &mut self,
+        path: &[&Node],
+        message: &value::Message,
+        definition_level: i16,
+        repetition_level: i16,
+        max_repetition_level: i16,
+    ) -> error::Result<()> {
+        let node = path[0];
+        let values: Vec<Cow<value::Value>> = match message.fields.get(&node.field.number()) {
+            // A cleared field is written as a null, just like an absent one
+            Some(value::Field::Singular(Some(value::Value::Null))) | None => Vec::new(),
+            Some(value::Field::Singular(Some(value::Value::FieldMask(m)))) => m
+                .paths
+                .iter()
+                .map(|p| Cow::Owned(value::Value::String(p.clone())))
+                .collect(),
+            Some(value::Field::Singular(v)) => v.iter().map(Cow::Borrowed).collect(),
+            Some(value::Field::Repeated(vs)) => vs.iter().map(Cow::Borrowed).collect(),
+            Some(value::Field::RepeatedTyped(vs)) => vs.iter().map(Cow::Owned).collect(),
+        };
+        if values.is_empty() {
+            self.definition_levels.push(definition_level);
+            self.repetition_levels.push(repetition_level);
+            return Ok(());
+        }
+
+        let max_repetition_level = max_repetition_level + i16::from(node.repeated);
+        for (i, value) in values.iter().enumerate() {
+            let repetition_level = if i == 0 {
+                repetition_level
+            } else {
+                max_repetition_level
+            };
+            match (path.len(), &**value) {
+                (1, value) => {
+                    self.push(node, value)?;
+                    self.definition_levels.push(definition_level + 1);
+                    self.repetition_levels.push(repetition_level);
+                }
+                (_, value::Value::Message(m)) => self.add(
+                    &path[1..],
+                    m,
+                    definition_level + 1,
+                    repetition_level,
+                    max_repetition_level,
+                )?,
+                _ => return Err(bad_field_value(node.field)),
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, node: &Node, value: &value::Value) -> error::Result<()> {
+        let enum_type = match node.kind {
+            Kind::Leaf(_, _, enum_type) => enum_type,
+            Kind::Group(_) => None,
+        };
+        match (&mut self.values, value) {
+            (Values::Bool(vs), &value::Value::Bool(v)) => vs.push(v),
+            (Values::Int32(vs), &value::Value::I32(v)) => vs.push(v),
+            (Values::Int32(vs), &value::Value::U32(v)) => vs.push(v as i32),
+            (Values::Int64(vs), &value::Value::I64(v)) => vs.push(v),
+            (Values::Int64(vs), &value::Value::U64(v)) => vs.push(v as i64),
+            (Values::Float(vs), &value::Value::F32(v)) => vs.push(v),
+            (Values::Double(vs), &value::Value::F64(v)) => vs.push(v),
+            (Values::ByteArray(vs), value::Value::String(v)) => vs.push(v.as_str().into()),
+            (Values::ByteArray(vs), value::Value::Bytes(v)) => vs.push(v.clone().into()),
+            (Values::ByteArray(vs), value::Value::BytesStream(s)) => {
+                vs.push(s.read_to_vec()?.into())
+            }
+            (Values::ByteArray(vs), &value::Value::Enum(n)) => {
+                match enum_type.and_then(|e| e.value_by_number(n)) {
+                    Some(v) => vs.push(v.name().into()),
+                    None => vs.push(n.to_string().as_str().into()),
+                }
+            }
+            _ => return Err(bad_field_value(node.field)),
+        }
+        Ok(())
+    }
+
+    fn write<W>(self, row_group: &mut SerializedRowGroupWriter<W>) -> error::Result<()>
+    where
+        W: io::Write + Send,
+    {
+        let mut writer = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .ok_or_else(|| error::Error::Custom {
+                message: "Parquet schema has fewer columns than the message type".to_owned(),
+            })?;
+        let definition_levels = Some(&self.definition_levels[..]);
+        let repetition_levels = Some(&self.repetition_levels[..]);
+        match self.values {
+            Values::Bool(ref vs) => {
+                writer
+                    .typed::<BoolType>()
+                    .write_batch(vs, definition_levels, repetition_levels)
+            }
+            Values::Int32(ref vs) => {
+                writer
+                    .typed::<Int32Type>()
+                    .write_batch(vs, definition_levels, repetition_levels)
+            }
+            Values::Int64(ref vs) => {
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(vs, definition_levels, repetition_levels)
+            }
+            Values::Float(ref vs) => {
+                writer
+                    .typed::<FloatType>()
+                    .write_batch(vs, definition_levels, repetition_levels)
+            }
+            Values::Double(ref vs) => {
+                writer
+                    .typed::<DoubleType>()
+                    .write_batch(vs, definition_levels, repetition_levels)
+            }
+            Values::ByteArray(ref vs) => writer.typed::<ByteArrayType>().write_batch(
+                vs,
+                definition_levels,
+                repetition_levels,
+            ),
+        }
+        .map_err(parquet_error)?;
+        writer.close().map_err(parquet_error)
+    }
+}
+
+fn bad_field_value(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
+
+fn parquet_error(e: ParquetError) -> error::Error {
+    error::Error::Custom {
+        message: e.to_string(),
+    }
+}
@@ -0,0 +1,636 @@
+//! Predicates over dynamic messages, for routing and alerting rules that are configured at
+//! runtime.
+//!
+//! A predicate is written in a small expression language:
+//!
+//!   * Paths like `order.total` refer to singular scalar fields by name, through singular message
+//!     fields.  Absent fields have their default value, like in generated code; `has(path)` tests
+//!     whether a field is present.
+//!   * Literals are numbers, double-quoted strings (with `\"`, `\\`, `\n` and `\t` escapes),
+//!     `true` and `false`.  Enum fields compare to the names of their values as strings, and
+//!     `bytes` fields compare to the UTF-8 encoding of strings.
+//!   * `==`, `!=`, `<`, `<=`, `>` and `>=` compare values of the same kind; all numbers are one
+//!     kind.  `!`, `&&` and `||` combine conditions, and parentheses group them.
+//!
+//! Predicates are compiled once against a message type, which resolves paths to field numbers
+//! and checks the kinds of all comparisons, and can then be evaluated against any number of
+//! messages of that type.  Evaluating a predicate against a message of another type fails.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::predicate::Predicate;
+//! use serde_protobuf::value::{Field, Message, Value};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.TestAllTypes")
+//!     .unwrap();
+//!
+//! let predicate = Predicate::compile(
+//!     &descriptors,
+//!     descriptor,
+//!     r#"optional_int64 > 1000 && optional_nested_enum == "BAR""#,
+//! )
+//! .unwrap();
+//!
+//! let mut message = Message::new(descriptor);
+//! message.fields.insert(2, Field::Singular(Some(Value::I64(1500))));
+//! assert!(!predicate.evaluate(descriptor, &message).unwrap());
+//! message.fields.insert(21, Field::Singular(Some(Value::Enum(2))));
+//! assert!(predicate.evaluate(descriptor, &message).unwrap());
+//! ```
+use std::cmp;
+
+use crate::de;
+use crate::descriptor;
+use crate::descriptor::FieldType;
+use crate::error;
+use crate::value;
+
+/// A compiled predicate over messages of one type.
+#[derive(Clone, Debug)]
+pub struct Predicate {
+    // The fully qualified name of the message type that the predicate was compiled for
+    message_type: String,
+    expr: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Operand),
+    Field {
+        // The field numbers along the path, the name of the last field, and its value when it's
+        // absent
+        path: Vec<i32>,
+        name: String,
+        default: Operand,
+    },
+    Has(Vec<i32>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Comparison, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Bool(bool),
+    Int(i128),
+    Double(f64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+/// The kind of an expression, for checking comparisons while compiling.
+#[derive(Clone, Copy, Debug)]
+enum Kind<'a> {
+    Bool,
+    Number,
+    String,
+    Bytes,
+    Enum(&'a descriptor::EnumDescriptor),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i128),
+    Double(f64),
+    String(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", ".", "-",
+];
+
+impl Predicate {
+    /// Compiles a predicate over messages of the specified type.
+    ///
+    /// Fails with a `BadExpression` error if the expression doesn't parse, refers to fields that
+    /// the message type doesn't have, or compares values of different kinds.
+    pub fn compile(
+        descriptors: &descriptor::Descriptors,
+        descriptor: &descriptor::MessageDescriptor,
+        source: &str,
+    ) -> error::Result<Predicate> {
+        let mut parser = Parser {
+            descriptors,
+            descriptor,
+            tokens: tokenize(source)?,
+            position: 0,
+            end: source.len(),
+        };
+        let (expr, kind) = parser.or()?;
+        if let Some(&(offset, _)) = parser.tokens.get(parser.position) {
+            return Err(bad_expression(offset, "expected the end of the expression"));
+        }
+        match kind {
+            Kind::Bool => Ok(Predicate {
+                message_type: descriptor.name().to_owned(),
+                expr,
+            }),
+            _ => Err(bad_expression(0, "the expression isn't a condition")),
+        }
+    }
+
+    /// Evaluates the predicate against a message of the type that it was compiled for.
+    ///
+    /// Fails with a `MessageTypeMismatch` error if the message has another type, and with a
+    /// `BadFieldValue` error if a field of the message holds a value of the wrong kind.
+    pub fn evaluate(
+        &self,
+        descriptor: &descriptor::MessageDescriptor,
+        message: &value::Message,
+    ) -> error::Result<bool> {
+        if descriptor.name() != self.message_type {
+            return Err(error::Error::MessageTypeMismatch {
+                expected: self.message_type.clone(),
+                actual: descriptor.name().to_owned(),
+            });
+        }
+        self.expr.condition(message)
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, message: &value::Message) -> error::Result<Operand> {
+        Ok(match *self {
+            Expr::Literal(ref v) => v.clone(),
+            Expr::Field {
+                ref path,
+                ref name,
+                ref default,
+            } => match lookup(message, path) {
+                Some(v) => operand(v)?.ok_or_else(|| error::Error::BadFieldValue {
+                    field: name.clone(),
+                })?,
+                None => default.clone(),
+            },
+            Expr::Has(ref path) => Operand::Bool(lookup(message, path).is_some()),
+            Expr::Not(ref e) => Operand::Bool(!e.condition(message)?),
+            Expr::And(ref a, ref b) => {
+                Operand::Bool(a.condition(message)? && b.condition(message)?)
+            }
+            Expr::Or(ref a, ref b) => Operand::Bool(a.condition(message)? || b.condition(message)?),
+            Expr::Compare(comparison, ref a, ref b) => {
+                let ordering = compare(&a.evaluate(message)?, &b.evaluate(message)?);
+                Operand::Bool(match comparison {
+                    Comparison::Eq => ordering == Some(cmp::Ordering::Equal),
+                    Comparison::Ne => ordering != Some(cmp::Ordering::Equal),
+                    Comparison::Lt => ordering == Some(cmp::Ordering::Less),
+                    Comparison::Le => matches!(
+                        ordering,
+                        Some(cmp::Ordering::Less) | Some(cmp::Ordering::Equal)
+                    ),
+                    Comparison::Gt => ordering == Some(cmp::Ordering::Greater),
+                    Comparison::Ge => matches!(
+                        ordering,
+                        Some(cmp::Ordering::Greater) | Some(cmp::Ordering::Equal)
+                    ),
+                })
+            }
+        })
+    }
+
+    fn condition(&self, message: &value::Message) -> error::Result<bool> {
+        match (self.evaluate(message)?, self) {
+            (Operand::Bool(b), _) => Ok(b),
+            // Conditions are checked while compiling, so only a field that holds a value of the
+            // wrong kind gets here
+            (_, Expr::Field { name, .. }) => Err(error::Error::BadFieldValue {
+                field: name.clone(),
+            }),
+            (_, _) => Err(bad_expression(0, "the expression isn't a condition")),
+        }
+    }
+}
+
+/// The value of the last field of a path, if every field along the path is present.
+fn lookup<'a>(message: &'a value::Message, path: &[i32]) -> Option<&'a value::Value> {
+    let (&last, parents) = path.split_last()?;
+    let mut message = message;
+    for number in parents {
+        match message.fields.get(number) {
+            Some(value::Field::Singular(Some(value::Value::Message(m)))) => message = m,
+            _ => return None,
+        }
+    }
+    match message.fields.get(&last) {
+        Some(value::Field::Singular(Some(value::Value::Null))) => None,
+        Some(value::Field::Singular(Some(v))) => Some(v),
+        _ => None,
+    }
+}
+
+/// The operand of a scalar value, or `None` for messages.
+fn operand(v: &value::Value) -> error::Result<Option<Operand>> {
+    Ok(Some(match *v {
+        value::Value::Bool(v) => Operand::Bool(v),
+        value::Value::I32(v) => Operand::Int(i128::from(v)),
+        value::Value::I64(v) => Operand::Int(i128::from(v)),
+        value::Value::U32(v) => Operand::Int(i128::from(v)),
+        value::Value::U64(v) => Operand::Int(i128::from(v)),
+        value::Value::Enum(v) => Operand::Int(i128::from(v)),
+        value::Value::F32(v) => Operand::Double(f64::from(v)),
+        value::Value::F64(v) => Operand::Double(v),
+        value::Value::String(ref v) => Operand::String(v.clone()),
+        value::Value::Bytes(ref v) => Operand::Bytes(v.clone()),
+        value::Value::BytesStream(ref s) => Operand::Bytes(s.read_to_vec()?),
+        value::Value::Message(_)
+        | value::Value::FieldMask(_)
+        | value::Value::Custom(_)
+        | value::Value::Null => return Ok(None),
+    }))
+}
+
+fn compare(a: &Operand, b: &Operand) -> Option<cmp::Ordering> {
+    match (a, b) {
+        (Operand::Bool(a), Operand::Bool(b)) => a.partial_cmp(b),
+        (Operand::Int(a), Operand::Int(b)) => a.partial_cmp(b),
+        (&Operand::Int(a), Operand::Double(b)) => (a as f64).partial_cmp(b),
+        (Operand::Double(a), &Operand::Int(b)) => a.partial_cmp(&(b as f64)),
+        (Operand::Double(a), Operand::Double(b)) => a.partial_cmp(b),
+        (Operand::String(a), Operand::String(b)) => a.partial_cmp(b),
+        (Operand::Bytes(a), Operand::Bytes(b)) => a.partial_cmp(b),
+        _ => None,
+    }
+}
+
+fn tokenize(source: &str) -> error::Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !c.is_ascii_alphanumeric() && c != '_' {
+                    break;
+                }
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push((offset, Token::Ident(ident)));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            let mut double = false;
+            while let Some(&(_, c)) = chars.peek() {
+                let exponent_sign = (c == '+' || c == '-') && number.ends_with(&['e', 'E'][..]);
+                if c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                    double = true;
+                } else if !c.is_ascii_digit() {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let token = if double {
+                number.parse().map(Token::Double).ok()
+            } else {
+                number.parse().map(Token::Int).ok()
+            };
+            let token = token.ok_or_else(|| bad_expression(offset, "bad number"))?;
+            tokens.push((offset, token));
+        } else if c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((escape, '\\')) => match chars.next() {
+                        Some((_, '"')) => string.push('"'),
+                        Some((_, '\\')) => string.push('\\'),
+                        Some((_, 'n')) => string.push('\n'),
+                        Some((_, 't')) => string.push('\t'),
+                        _ => return Err(bad_expression(escape, "bad escape sequence")),
+                    },
+                    Some((_, c)) => string.push(c),
+                    None => return Err(bad_expression(offset, "unterminated string")),
+                }
+            }
+            tokens.push((offset, Token::String(string)));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| source[offset..].starts_with(*s))
+                .ok_or_else(|| bad_expression(offset, &format!("unexpected {:?}", c)))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((offset, Token::Symbol(symbol)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |&(offset, _)| offset)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, t)| t)
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> error::Result<()> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(bad_expression(
+                self.offset(),
+                &format!("expected {:?}", symbol),
+            ))
+        }
+    }
+
+    fn or(&mut self) -> error::Result<(Expr, Kind<'a>)> {
+        let start = self.offset();
+        let mut left = self.and()?;
+        while self.eat("||") {
+            let offset = self.offset();
+            let right = self.and()?;
+            left = (
+                Expr::Or(
+                    Box::new(condition(left, start)?),
+                    Box::new(condition(right, offset)?),
+                ),
+                Kind::Bool,
+            );
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> error::Result<(Expr, Kind<'a>)> {
+        let start = self.offset();
+        let mut left = self.not()?;
+        while self.eat("&&") {
+            let offset = self.offset();
+            let right = self.not()?;
+            left = (
+                Expr::And(
+                    Box::new(condition(left, start)?),
+                    Box::new(condition(right, offset)?),
+                ),
+                Kind::Bool,
+            );
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> error::Result<(Expr, Kind<'a>)> {
+        if self.eat("!") {
+            let offset = self.offset();
+            let operand = self.not()?;
+            return Ok((Expr::Not(Box::new(condition(operand, offset)?)), Kind::Bool));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> error::Result<(Expr, Kind<'a>)> {
+        let left = self.operand()?;
+        let comparison = match self.peek() {
+            Some(Token::Symbol("==")) => Comparison::Eq,
+            Some(Token::Symbol("!=")) => Comparison::Ne,
+            Some(Token::Symbol("<")) => Comparison::Lt,
+            Some(Token::Symbol("<=")) => Comparison::Le,
+            Some(Token::Symbol(">")) => Comparison::Gt,
+            Some(Token::Symbol(">=")) => Comparison::Ge,
+            _ => return Ok(left),
+        };
+        let offset = self.offset();
+        self.position += 1;
+        let right = self.operand()?;
+        let (left, right) = unify(left, right).map_err(|(a, b)| {
+            bad_expression(offset, &format!("cannot compare {} with {}", a, b))
+        })?;
+        if let Kind::Bool = left.1 {
+            if comparison != Comparison::Eq && comparison != Comparison::Ne {
+                return Err(bad_expression(offset, "booleans can't be ordered"));
+            }
+        }
+        Ok((
+            Expr::Compare(comparison, Box::new(left.0), Box::new(right.0)),
+            Kind::Bool,
+        ))
+    }
+
+    fn operand(&mut self) -> error::Result<(Expr, Kind<'a>)> {
+        let offset = self.offset();
+        let token = match self.tokens.get(self.position) {
+            Some((_, token)) => token.clone(),
+            None => return Err(bad_expression(offset, "unexpected end of the expression")),
+        };
+        self.position += 1;
+        Ok(match token {
+            Token::Symbol("(") => {
+                let inner = self.or()?;
+                self.expect(")")?;
+                inner
+            }
+            Token::Symbol("-") => match self.tokens.get(self.position) {
+                Some(&(_, Token::Int(n))) => {
+                    self.position += 1;
+                    (Expr::Literal(Operand::Int(-n)), Kind::Number)
+                }
+                Some(&(_, Token::Double(n))) => {
+                    self.position += 1;
+                    (Expr::Literal(Operand::Double(-n)), Kind::Number)
+                }
+                _ => return Err(bad_expression(self.offset(), "expected a number")),
+            },
+            Token::Int(n) => (Expr::Literal(Operand::Int(n)), Kind::Number),
+            Token::Double(n) => (Expr::Literal(Operand::Double(n)), Kind::Number),
+            Token::String(s) => (Expr::Literal(Operand::String(s)), Kind::String),
+            Token::Ident(ref name) if name == "true" => {
+                (Expr::Literal(Operand::Bool(true)), Kind::Bool)
+            }
+            Token::Ident(ref name) if name == "false" => {
+                (Expr::Literal(Operand::Bool(false)), Kind::Bool)
+            }
+            Token::Ident(ref name) if name == "has" && self.peek() == Some(&Token::Symbol("(")) => {
+                self.position += 1;
+                let (path, _) = self.path(offset, None)?;
+                self.expect(")")?;
+                (Expr::Has(path), Kind::Bool)
+            }
+            Token::Ident(name) => {
+                let (path, field) = self.path(offset, Some(name))?;
+                let kind = match field.field_type(self.descriptors) {
+                    FieldType::Bool => Kind::Bool,
+                    FieldType::String => Kind::String,
+                    FieldType::Bytes => Kind::Bytes,
+                    FieldType::Enum(e) => Kind::Enum(e),
+                    FieldType::Message(_)
+                    | FieldType::Group
+                    | FieldType::UnresolvedEnum(_)
+                    | FieldType::UnresolvedMessage(_) => {
+                        return Err(bad_expression(offset, "the field isn't a scalar field"))
+                    }
+                    _ => Kind::Number,
+                };
+                let default = de::zero_value(self.descriptors, field)
+                    .ok_or_else(|| bad_expression(offset, "the field has no default value"))?;
+                let default = operand(&default)?
+                    .ok_or_else(|| bad_expression(offset, "the field isn't a scalar field"))?;
+                let name = field.name().to_owned();
+                (
+                    Expr::Field {
+                        path,
+                        name,
+                        default,
+                    },
+                    kind,
+                )
+            }
+            _ => return Err(bad_expression(offset, "expected a value")),
+        })
+    }
+
+    /// Parses a path of singular fields, starting with the already consumed first name if any.
+    fn path(
+        &mut self,
+        offset: usize,
+        first: Option<String>,
+    ) -> error::Result<(Vec<i32>, &'a descriptor::FieldDescriptor)> {
+        let mut name = match first {
+            Some(name) => name,
+            None => self.ident()?,
+        };
+        let mut message = self.descriptor;
+        let mut path = Vec::new();
+        loop {
+            let field = message
+                .field_by_name(&name)
+                .ok_or_else(|| bad_expression(offset, &format!("unknown field {:?}", name)))?;
+            if field.is_repeated() {
+                return Err(bad_expression(
+                    offset,
+                    &format!("{:?} is a repeated field", name),
+                ));
+            }
+            path.push(field.number());
+            if !self.eat(".") {
+                return Ok((path, field));
+            }
+            message = match field.field_type(self.descriptors) {
+                FieldType::Message(m) => m,
+                _ => {
+                    return Err(bad_expression(
+                        offset,
+                        &format!("{:?} isn't a message field", name),
+                    ))
+                }
+            };
+            name = self.ident()?;
+        }
+    }
+
+    fn ident(&mut self) -> error::Result<String> {
+        match self.tokens.get(self.position) {
+            Some((_, Token::Ident(name))) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(bad_expression(self.offset(), "expected a field name")),
+        }
+    }
+}
+
+/// Checks that an expression is a condition.
+fn condition((expr, kind): (Expr, Kind), offset: usize) -> error::Result<Expr> {
+    match kind {
+        Kind::Bool => Ok(expr),
+        _ => Err(bad_expression(offset, "expected a condition")),
+    }
+}
+
+type Typed<'a> = (Expr, Kind<'a>);
+
+/// Converts string literals that are compared to enums and bytes, and checks that the kinds of
+/// the operands of a comparison match.
+fn unify<'a>(
+    left: Typed<'a>,
+    right: Typed<'a>,
+) -> Result<(Typed<'a>, Typed<'a>), (&'static str, &'static str)> {
+    let convert = |literal: Typed<'a>, other: Kind<'a>| match (literal, other) {
+        ((Expr::Literal(Operand::String(s)), Kind::String), Kind::Enum(e)) => {
+            match e.value_by_name(&s) {
+                Some(v) => Ok((
+                    Expr::Literal(Operand::Int(i128::from(v.number()))),
+                    Kind::Number,
+                )),
+                None => Err((Expr::Literal(Operand::String(s)), Kind::String)),
+            }
+        }
+        ((Expr::Literal(Operand::String(s)), Kind::String), Kind::Bytes) => {
+            Ok((Expr::Literal(Operand::Bytes(s.into_bytes())), Kind::Bytes))
+        }
+        (literal, _) => Err(literal),
+    };
+    let right_kind = right.1;
+    let left = convert(left, right_kind).unwrap_or_else(|l| l);
+    let right = convert(right, left.1).unwrap_or_else(|r| r);
+    let kinds = (kind_name(left.1), kind_name(right.1));
+    match (left.1, right.1) {
+        (Kind::Bool, Kind::Bool)
+        | (Kind::String, Kind::String)
+        | (Kind::Bytes, Kind::Bytes)
+        | (Kind::Number, Kind::Number)
+        | (Kind::Number, Kind::Enum(_))
+        | (Kind::Enum(_), Kind::Number) => Ok((left, right)),
+        (Kind::Enum(a), Kind::Enum(b)) if a.name() == b.name() => Ok((left, right)),
+        _ => Err(kinds),
+    }
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Bool => "a boolean",
+        Kind::Number => "a number",
+        Kind::String => "a string",
+        Kind::Bytes => "bytes",
+        Kind::Enum(_) => "an enum",
+    }
+}
+
+fn bad_expression(offset: usize, message: &str) -> error::Error {
+    error::Error::BadExpression {
+        offset,
+        message: message.to_owned(),
+    }
+}
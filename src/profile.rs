@@ -0,0 +1,207 @@
+//! Data profiling of decoded messages, e.g. to detect schema drift.
+//!
+//! A `Profiler` scans many messages of the same type and reports, for every field path, how
+//! often the field is present, how many values it has and what range they span, along with the
+//! field numbers that occur in the data but aren't declared in the descriptors.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::profile::Profiler;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! let mut profiler = Profiler::new(&descriptors, descriptor);
+//! // optional_int32 = 5, and undeclared field 1000
+//! profiler.add_bytes(&[8, 5, 0xc0, 0x3e, 1]).unwrap();
+//! profiler.add_bytes(&[]).unwrap();
+//!
+//! let report = profiler.report();
+//! let field = &report.fields["optional_int32"];
+//! assert_eq!(0.5, field.presence_rate());
+//! assert_eq!(Some(5.0), field.max);
+//! assert_eq!(1, report.undeclared["1000"]);
+//! # }
+//! ```
+use std::collections;
+
+use protobuf;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// Scans messages of a single type and collects a `ProfileReport` about them.
+#[derive(Debug)]
+pub struct Profiler<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    report: ProfileReport,
+}
+
+/// The statistics collected by a `Profiler`.
+#[derive(Clone, Debug, Default)]
+pub struct ProfileReport {
+    /// The number of top-level messages that were scanned.
+    pub messages: u64,
+    /// The statistics of each declared field, by path, e.g. `a.b.c`.
+    ///
+    /// Paths lead through singular and repeated message fields alike; fields of messages that
+    /// never occurred are missing.
+    pub fields: collections::BTreeMap<String, FieldProfile>,
+    /// The number of messages in which each undeclared field number occurred, by the path of
+    /// the field number, e.g. `a.b.1000`.
+    pub undeclared: collections::BTreeMap<String, u64>,
+}
+
+/// The statistics of a single field.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldProfile {
+    /// The number of messages that could have had the field.
+    pub messages: u64,
+    /// The number of messages that had the field, with at least one value.
+    pub present: u64,
+    /// The total number of values, counting each element of repeated fields.
+    pub values: u64,
+    /// The smallest numeric value, for numeric, boolean and enum fields.
+    pub min: Option<f64>,
+    /// The largest numeric value, for numeric, boolean and enum fields.
+    pub max: Option<f64>,
+    /// The smallest length in bytes, for string and bytes fields.
+    pub min_len: Option<usize>,
+    /// The largest length in bytes, for string and bytes fields.
+    pub max_len: Option<usize>,
+}
+
+impl<'a> Profiler<'a> {
+    /// Creates a profiler for messages of the specified type.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        message: &'a descriptor::MessageDescriptor,
+    ) -> Profiler<'a> {
+        Profiler {
+            descriptors,
+            message,
+            report: ProfileReport::default(),
+        }
+    }
+
+    /// Adds a decoded message to the profile.
+    pub fn add(&mut self, message: &value::Message) {
+        self.report.messages += 1;
+        add_message(
+            self.descriptors,
+            self.message,
+            message,
+            "",
+            &mut self.report,
+        );
+    }
+
+    /// Decodes a message and adds it to the profile.
+    pub fn add_bytes(&mut self, bytes: &[u8]) -> error::Result<()> {
+        let mut message = value::Message::default();
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message.merge_from(self.descriptors, self.message, &mut input)?;
+        self.add(&message);
+        Ok(())
+    }
+
+    /// The statistics collected so far.
+    pub fn report(&self) -> &ProfileReport {
+        &self.report
+    }
+
+    /// Returns the collected statistics.
+    pub fn into_report(self) -> ProfileReport {
+        self.report
+    }
+}
+
+impl FieldProfile {
+    /// The fraction of messages that had the field, between 0 and 1.
+    pub fn presence_rate(&self) -> f64 {
+        if self.messages == 0 {
+            0.0
+        } else {
+            self.present as f64 / self.messages as f64
+        }
+    }
+
+    fn add_value(&mut self, value: &value::Value) {
+        use crate::value::Value::*;
+
+        self.values += 1;
+        let number = match *value {
+            Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+            I32(v) | Enum(v) => Some(f64::from(v)),
+            I64(v) => Some(v as f64),
+            U32(v) => Some(f64::from(v)),
+            U64(v) => Some(v as f64),
+            F32(v) => Some(f64::from(v)),
+            F64(v) => Some(v),
+            _ => None,
+        };
+        let len = match *value {
+            Bytes(ref v) => Some(v.len()),
+            String(ref v) => Some(v.len()),
+            BytesStream(ref v) => Some(v.len() as usize),
+            _ => None,
+        };
+        if let Some(n) = number.filter(|n| !n.is_nan()) {
+            self.min = Some(self.min.map_or(n, |m| m.min(n)));
+            self.max = Some(self.max.map_or(n, |m| m.max(n)));
+        }
+        if let Some(l) = len {
+            self.min_len = Some(self.min_len.map_or(l, |m| m.min(l)));
+            self.max_len = Some(self.max_len.map_or(l, |m| m.max(l)));
+        }
+    }
+}
+
+fn add_message(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    prefix: &str,
+    report: &mut ProfileReport,
+) {
+    for field in descriptor.fields() {
+        let path = format!("{}{}", prefix, field.name());
+        let values = match message.fields.get(&field.number()) {
+            Some(f) => f.values(),
+            None => Default::default(),
+        };
+
+        let profile = report.fields.entry(path.clone()).or_default();
+        profile.messages += 1;
+        if !values.is_empty() {
+            profile.present += 1;
+        }
+        for v in values.iter() {
+            profile.add_value(v);
+        }
+
+        if let descriptor::FieldType::Message(m) = field.field_type(descriptors) {
+            let prefix = format!("{}.", path);
+            for v in values.iter() {
+                if let value::Value::Message(ref nested) = *v {
+                    add_message(descriptors, m, nested, &prefix, report);
+                }
+            }
+        }
+    }
+
+    for (number, _) in message.unknown.iter() {
+        *report
+            .undeclared
+            .entry(format!("{}{}", prefix, number))
+            .or_default() += 1;
+    }
+}
@@ -0,0 +1,163 @@
+//! Projections of messages to a selection of their fields, for subscribers that only want parts
+//! of the messages of a stream.
+//!
+//! A projection is compiled once from field mask paths or from a GraphQL-like selection like
+//! `id customer { name } items { price }`, which resolves the selected fields to field numbers.
+//! It can then produce pruned copies of decoded messages, decode only the selected fields from the
+//! wire, or encode only the selected fields of a message, without resolving any names again.
+//!
+//! Like field masks, a projection without any selected fields selects whole messages.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::projection::Projection;
+//! use serde_protobuf::value::{Field, Message, Value};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.TestAllTypes")
+//!     .unwrap();
+//! let projection =
+//!     Projection::parse(&descriptors, descriptor, "optional_int32 optional_nested_message { bb }")
+//!         .unwrap();
+//!
+//! let mut message = Message::new(descriptor);
+//! message.fields.insert(1, Field::Singular(Some(Value::I32(1))));
+//! message.fields.insert(2, Field::Singular(Some(Value::I64(2))));
+//! let projected = projection.project(&descriptors, descriptor, &message);
+//! assert!(!projected.fields[&1].is_empty());
+//! assert!(projected.fields[&2].is_empty());
+//! ```
+use crate::descriptor;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// A compiled selection of the fields of a message type.
+#[derive(Clone, Debug)]
+pub struct Projection {
+    tree: field_mask::FieldMaskTree,
+}
+
+impl Projection {
+    /// Compiles a projection to the paths of a field mask.
+    ///
+    /// Fails with a `BadFieldMaskPath` error if a path doesn't refer to a field of the message
+    /// type.
+    pub fn new(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mask: &field_mask::FieldMask,
+    ) -> error::Result<Projection> {
+        let tree = field_mask::FieldMaskTree::compile(descriptors, message, mask)?;
+        Ok(Projection { tree })
+    }
+
+    /// Compiles a projection to a GraphQL-like selection.
+    ///
+    /// A selection is a list of field names, separated by whitespace or commas.  The fields of a
+    /// message field can be selected by following its name with a nested selection in braces;
+    /// a message field without one is selected entirely.
+    pub fn parse(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        selection: &str,
+    ) -> error::Result<Projection> {
+        let mut paths = Vec::new();
+        let mut prefixes: Vec<(usize, String)> = Vec::new();
+        let mut last: Option<String> = None;
+        let bad = |message: &str| error::Error::BadFieldMaskPath {
+            path: format!("{}: {}", message, selection),
+        };
+
+        let mut rest = selection;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+            let c = match rest.chars().next() {
+                Some(c) => c,
+                None => break,
+            };
+            let prefix = prefixes.last().map_or("", |p| &p.1[..]);
+            if c == '{' {
+                let name = last
+                    .take()
+                    .ok_or_else(|| bad("selection without a field"))?;
+                prefixes.push((paths.len(), format!("{}{}.", prefix, name)));
+                rest = &rest[1..];
+            } else if c == '}' {
+                if let Some(name) = last.take() {
+                    paths.push(format!("{}{}", prefix, name));
+                }
+                let (start, _) = prefixes.pop().ok_or_else(|| bad("unbalanced braces"))?;
+                if paths.len() == start {
+                    return Err(bad("empty selection"));
+                }
+                rest = &rest[1..];
+            } else {
+                let end = rest
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                if end == 0 {
+                    return Err(bad(&format!("unexpected {:?}", c)));
+                }
+                if let Some(name) = last.replace(rest[..end].to_owned()) {
+                    paths.push(format!("{}{}", prefix, name));
+                }
+                rest = &rest[end..];
+            }
+        }
+        if !prefixes.is_empty() {
+            return Err(bad("unbalanced braces"));
+        }
+        paths.extend(last);
+
+        Projection::new(descriptors, message, &field_mask::FieldMask::new(paths))
+    }
+
+    /// The compiled field mask of this projection.
+    pub fn tree(&self) -> &field_mask::FieldMaskTree {
+        &self.tree
+    }
+
+    /// Copies the selected fields of a message, without copying the other fields.
+    ///
+    /// The fields that aren't selected are absent in the copy, and unknown fields are dropped.
+    pub fn project(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        value: &value::Message,
+    ) -> value::Message {
+        value.masked_copy(descriptors, message, &self.tree)
+    }
+
+    /// Decodes only the selected fields of an encoded message; other fields are skipped on the
+    /// wire.
+    pub fn decode(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &[u8],
+    ) -> error::Result<value::Message> {
+        let mut result = value::Message::new(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(input);
+        result.merge_from_masked(descriptors, message, &self.tree, &mut input)?;
+        // Clears the default values of fields that aren't selected
+        result.retain_masked(descriptors, message, &self.tree);
+        Ok(result)
+    }
+
+    /// Encodes only the selected fields of a message.
+    pub fn encode(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        value: &value::Message,
+    ) -> error::Result<Vec<u8>> {
+        self.project(descriptors, message, value)
+            .write_to_bytes(descriptors, message)
+    }
+}
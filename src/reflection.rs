@@ -0,0 +1,245 @@
+//! A gRPC server reflection service for the types of a descriptor registry.
+//!
+//! Dynamic gateways built on this crate have no generated code that describes their services,
+//! so tools like `grpcurl` can't discover them through the usual reflection service.  A
+//! `ReflectionService` answers `grpc.reflection.v1.ServerReflection` requests from a
+//! `Descriptors` registry instead, with the file descriptors that
+//! `Descriptors::file_descriptor_protos` reconstructs.
+//!
+//! This module is only available with the `reflection` feature.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::reflection::ReflectionService;
+//! use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+//! use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+//! use tonic_reflection::pb::v1::ServerReflectionRequest;
+//!
+//! # fn main() -> serde_protobuf::error::Result<()> {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let service = ReflectionService::new(&descriptors)?;
+//!
+//! let response = service.respond(&ServerReflectionRequest {
+//!     host: String::new(),
+//!     message_request: Some(MessageRequest::ListServices(String::new())),
+//! });
+//! match response.message_response {
+//!     Some(MessageResponse::ListServicesResponse(list)) => {
+//!         assert!(list.service.iter().any(|s| s.name == "protobuf_unittest.TestService"))
+//!     }
+//!     _ => unreachable!(),
+//! }
+//!
+//! // Serve it next to the services of the gateway, e.g. with `tonic::transport::Server`
+//! let server = service.into_server();
+//! # Ok(())
+//! # }
+//! ```
+use std::collections;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use protobuf;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+use tonic_reflection::pb::v1::server_reflection_server::{
+    ServerReflection, ServerReflectionServer,
+};
+use tonic_reflection::pb::v1::{
+    ErrorResponse, ExtensionNumberResponse, FileDescriptorResponse, ListServiceResponse,
+    ServerReflectionRequest, ServerReflectionResponse, ServiceResponse,
+};
+
+use crate::descriptor;
+use crate::error;
+
+/// A `grpc.reflection.v1.ServerReflection` service that describes the types of a registry.
+///
+/// Symbols can be looked up by the fully qualified names of messages, enums and services, and of
+/// fields and methods.  Extensions aren't supported, so they are never found.
+#[derive(Clone, Debug)]
+pub struct ReflectionService {
+    index: Arc<Index>,
+}
+
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = Result<ServerReflectionResponse, tonic::Status>> + Send>>;
+
+#[derive(Debug, Default)]
+struct Index {
+    // The encoded file descriptors and the names of the files they import, by file name
+    files: collections::BTreeMap<String, (Vec<u8>, Vec<String>)>,
+    // The name of the file that defines each symbol, by its name without the leading `.`
+    symbols: collections::HashMap<String, String>,
+    messages: collections::HashSet<String>,
+    services: Vec<String>,
+}
+
+// The gRPC status codes of error responses
+const INVALID_ARGUMENT: i32 = 3;
+const NOT_FOUND: i32 = 5;
+
+impl ReflectionService {
+    /// Creates a service that describes the types of the registry, as they are now.
+    pub fn new(descriptors: &descriptor::Descriptors) -> error::Result<ReflectionService> {
+        let mut index = Index::default();
+
+        for file in descriptors.file_descriptor_protos() {
+            let bytes = protobuf::Message::write_to_bytes(&file)?;
+            index.files.insert(
+                file.get_name().to_owned(),
+                (bytes, file.get_dependency().to_vec()),
+            );
+        }
+
+        let mut add = |name: &str, file: &str| {
+            index
+                .symbols
+                .insert(name.trim_start_matches('.').to_owned(), file.to_owned());
+        };
+        for message in descriptors.messages() {
+            if let Some(file) = descriptors.file_of_type(message.name()) {
+                add(message.name(), file);
+                for field in message.fields() {
+                    add(&format!("{}.{}", message.name(), field.name()), file);
+                }
+            }
+        }
+        for e in descriptors.enums() {
+            if let Some(file) = descriptors.file_of_type(e.name()) {
+                add(e.name(), file);
+            }
+        }
+        for service in descriptors.services() {
+            if let Some(file) = descriptors.file_of_type(service.name()) {
+                add(service.name(), file);
+                for method in service.methods() {
+                    add(&format!("{}.{}", service.name(), method.name()), file);
+                }
+            }
+        }
+
+        index.messages = descriptors
+            .messages()
+            .iter()
+            .map(|m| m.name().trim_start_matches('.').to_owned())
+            .collect();
+        index.services = descriptors
+            .services()
+            .iter()
+            .map(|s| s.name().trim_start_matches('.').to_owned())
+            .collect();
+
+        Ok(ReflectionService {
+            index: Arc::new(index),
+        })
+    }
+
+    /// Wraps this service in a `tonic` server that can be added to a router.
+    pub fn into_server(self) -> ServerReflectionServer<ReflectionService> {
+        ServerReflectionServer::new(self)
+    }
+
+    /// Answers a single reflection request.
+    ///
+    /// Files are returned along with all the files that they import, directly or indirectly.
+    pub fn respond(&self, request: &ServerReflectionRequest) -> ServerReflectionResponse {
+        let index = &self.index;
+        let response = match request.message_request {
+            Some(MessageRequest::FileByFilename(ref name)) => self.files(name),
+            Some(MessageRequest::FileContainingSymbol(ref symbol)) => {
+                match index.symbols.get(symbol.trim_start_matches('.')) {
+                    Some(file) => self.files(file),
+                    None => error(NOT_FOUND, format!("symbol not found: {}", symbol)),
+                }
+            }
+            Some(MessageRequest::FileContainingExtension(ref extension)) => error(
+                NOT_FOUND,
+                format!(
+                    "extension not found: {} {}",
+                    extension.containing_type, extension.extension_number
+                ),
+            ),
+            Some(MessageRequest::AllExtensionNumbersOfType(ref name)) => {
+                if index.messages.contains(name.trim_start_matches('.')) {
+                    MessageResponse::AllExtensionNumbersResponse(ExtensionNumberResponse {
+                        base_type_name: name.clone(),
+                        extension_number: Vec::new(),
+                    })
+                } else {
+                    error(NOT_FOUND, format!("type not found: {}", name))
+                }
+            }
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: index
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                })
+            }
+            None => error(INVALID_ARGUMENT, "missing request".to_owned()),
+        };
+
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request.clone()),
+            message_response: Some(response),
+        }
+    }
+
+    /// The file with the specified name and the files that it imports.
+    fn files(&self, name: &str) -> MessageResponse {
+        let files = &self.index.files;
+        if !files.contains_key(name) {
+            return error(NOT_FOUND, format!("file not found: {}", name));
+        }
+
+        let mut seen = collections::HashSet::new();
+        let mut pending = vec![name];
+        let mut file_descriptor_proto = Vec::new();
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name) {
+                continue;
+            }
+            // Imports that aren't in the registry are left out
+            if let Some((bytes, dependencies)) = files.get(name) {
+                file_descriptor_proto.push(bytes.clone());
+                pending.extend(dependencies.iter().map(String::as_str));
+            }
+        }
+        MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+            file_descriptor_proto,
+        })
+    }
+}
+
+// `tonic::Status` is large, but it is the error type of the service
+#[allow(clippy::result_large_err)]
+#[tonic::async_trait]
+impl ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream = ResponseStream;
+
+    async fn server_reflection_info(
+        &self,
+        request: tonic::Request<tonic::Streaming<ServerReflectionRequest>>,
+    ) -> Result<tonic::Response<ResponseStream>, tonic::Status> {
+        let service = self.clone();
+        let responses = request
+            .into_inner()
+            .map(move |request| request.map(|r| service.respond(&r)));
+        Ok(tonic::Response::new(Box::pin(responses)))
+    }
+}
+
+fn error(error_code: i32, error_message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code,
+        error_message,
+    })
+}
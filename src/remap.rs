@@ -0,0 +1,455 @@
+//! Conversion of messages between sibling message types.
+//!
+//! Teams often define near-identical messages for the same data, with fields that were renamed,
+//! renumbered, moved into nested messages or given another type.  A `FieldMapping` converts
+//! messages of one such type into the other along a correspondence between field paths like
+//! `customer.id`, whose components are the names of singular message fields and finally the name
+//! of the mapped field.  By default, fields that aren't mapped explicitly are mapped to the field
+//! with the same name in the target type, if there is one, and nested messages are converted by
+//! field name as well.  Unknown fields are dropped, since their numbers mean nothing to the
+//! target type.
+//!
+//! Values are coerced to the type of the target field: between numeric types if the value fits,
+//! between numbers, booleans and their string representations, and between UTF-8 `bytes` and
+//! `string`s.  Enum values are converted to numbers by their number, and to strings and other
+//! enum types by their name.  A single value can be mapped to a repeated field, and a repeated
+//! field with at most one value to a singular field.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::cursor::{Cursor, CursorMut};
+//! use serde_protobuf::remap::FieldMapping;
+//! use serde_protobuf::value::{Message, Value};
+//!
+//! # fn main() -> serde_protobuf::error::Result<()> {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//! let mut message = Message::new(descriptor);
+//! let mut cursor = CursorMut::new(&descriptors, descriptor, &mut message);
+//! cursor.field("optional_int32")?.set(Value::I32(5))?;
+//! let mut cursor = CursorMut::new(&descriptors, descriptor, &mut message);
+//! cursor.field("optional_string")?.set(Value::String("17".to_owned()))?;
+//!
+//! // Swap the fields, e.g. into a sibling type of another registry
+//! let mapping = FieldMapping::new()
+//!     .field("optional_int32", "optional_string")
+//!     .field("optional_string", "optional_int32");
+//! let converted = mapping.apply(&descriptors, descriptor, &message, &descriptors, descriptor)?;
+//!
+//! let cursor = Cursor::new(&descriptors, descriptor, &converted);
+//! assert_eq!(Some(17), cursor.field("optional_int32")?.as_i32());
+//! assert_eq!(Some("5"), cursor.field("optional_string")?.as_str());
+//! # Ok(())
+//! # }
+//! ```
+use std::collections;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+use crate::descriptor::FieldType as T;
+use crate::value::Value as V;
+
+const FIELD_MASK: &str = ".google.protobuf.FieldMask";
+
+/// A correspondence between the fields of two message types, that converts messages of one type
+/// into the other.
+#[derive(Clone, Debug)]
+pub struct FieldMapping {
+    // Pairs of source and target field paths
+    fields: Vec<(String, String)>,
+    by_name: bool,
+}
+
+/// The registries of the source and target types.
+#[derive(Clone, Copy)]
+struct Registries<'a> {
+    source: &'a descriptor::Descriptors,
+    target: &'a descriptor::Descriptors,
+}
+
+/// A scalar value, widened for coercion.
+enum Scalar<'a> {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl FieldMapping {
+    /// Creates a mapping that maps fields by name.
+    pub fn new() -> FieldMapping {
+        FieldMapping {
+            fields: Vec::new(),
+            by_name: true,
+        }
+    }
+
+    /// Maps the field at a path of the source type to the field at a path of the target type.
+    ///
+    /// The source field is then no longer mapped by name.
+    pub fn field<S, U>(mut self, source: S, target: U) -> FieldMapping
+    where
+        S: Into<String>,
+        U: Into<String>,
+    {
+        self.fields.push((source.into(), target.into()));
+        self
+    }
+
+    /// Sets whether fields that aren't mapped explicitly are mapped to the field with the same
+    /// name in the target type.  Enabled by default.
+    pub fn by_name(mut self, by_name: bool) -> FieldMapping {
+        self.by_name = by_name;
+        self
+    }
+
+    /// Converts a message of the source type into a new message of the target type.
+    ///
+    /// Fails with an `UnknownField` error if a mapped path doesn't lead through singular message
+    /// fields to a field, and with a `FieldConversion` error if a value can't be coerced to the
+    /// type of its target field.
+    pub fn apply(
+        &self,
+        source_descriptors: &descriptor::Descriptors,
+        source_message: &descriptor::MessageDescriptor,
+        message: &value::Message,
+        target_descriptors: &descriptor::Descriptors,
+        target_message: &descriptor::MessageDescriptor,
+    ) -> error::Result<value::Message> {
+        let registries = Registries {
+            source: source_descriptors,
+            target: target_descriptors,
+        };
+        let mapped = self
+            .fields
+            .iter()
+            .map(|f| f.0.as_str())
+            .collect::<collections::HashSet<_>>();
+
+        let mut target = if self.by_name {
+            registries.convert_message(
+                source_message,
+                message,
+                target_message,
+                &mapped,
+                &mut String::new(),
+                &mut String::new(),
+            )?
+        } else {
+            value::Message::new(target_message)
+        };
+
+        for (from, to) in &self.fields {
+            let found = find(source_descriptors, source_message, message, from, from)?;
+            let (field, value) = match found {
+                Some(found) => found,
+                None => continue,
+            };
+            let (target_field, slot) =
+                slot(target_descriptors, target_message, &mut target, to, to)?;
+            *slot = registries.convert_field(
+                field,
+                value,
+                target_field,
+                &mapped,
+                &mut from.clone(),
+                &mut to.clone(),
+            )?;
+        }
+        Ok(target)
+    }
+}
+
+impl Default for FieldMapping {
+    fn default() -> FieldMapping {
+        FieldMapping::new()
+    }
+}
+
+impl<'a> Registries<'a> {
+    /// Converts a message by field name, skipping the explicitly mapped source paths.
+    fn convert_message(
+        self,
+        source: &descriptor::MessageDescriptor,
+        message: &value::Message,
+        target: &descriptor::MessageDescriptor,
+        mapped: &collections::HashSet<&str>,
+        from: &mut String,
+        to: &mut String,
+    ) -> error::Result<value::Message> {
+        let mut converted = value::Message::new(target);
+        for (&number, value) in &message.fields {
+            let field = match source.field_by_number(number) {
+                Some(field) if !value.is_empty() => field,
+                _ => continue,
+            };
+            let target_field = match target.field_by_name(field.name()) {
+                Some(target_field) => target_field,
+                None => continue,
+            };
+
+            let (from_len, to_len) = (from.len(), to.len());
+            push_path(from, field.name());
+            push_path(to, target_field.name());
+            if !mapped.contains(from.as_str()) {
+                let value = self.convert_field(field, value, target_field, mapped, from, to)?;
+                converted.fields.insert(target_field.number(), value);
+            }
+            from.truncate(from_len);
+            to.truncate(to_len);
+        }
+        Ok(converted)
+    }
+
+    fn convert_field(
+        self,
+        field: &descriptor::FieldDescriptor,
+        value: &value::Field,
+        target_field: &descriptor::FieldDescriptor,
+        mapped: &collections::HashSet<&str>,
+        from: &mut String,
+        to: &mut String,
+    ) -> error::Result<value::Field> {
+        let values = value.values();
+        if target_field.is_repeated() {
+            let converted = values
+                .iter()
+                .map(|v| self.convert_value(field, v, target_field, mapped, from, to))
+                .collect::<error::Result<_>>()?;
+            Ok(value::Field::Repeated(converted))
+        } else {
+            match *values {
+                [] => Ok(value::Field::Singular(None)),
+                [ref v] => Ok(value::Field::Singular(Some(self.convert_value(
+                    field,
+                    v,
+                    target_field,
+                    mapped,
+                    from,
+                    to,
+                )?))),
+                _ => Err(conversion_error(from, to)),
+            }
+        }
+    }
+
+    fn convert_value(
+        self,
+        field: &descriptor::FieldDescriptor,
+        value: &value::Value,
+        target_field: &descriptor::FieldDescriptor,
+        mapped: &collections::HashSet<&str>,
+        from: &mut String,
+        to: &mut String,
+    ) -> error::Result<value::Value> {
+        let source_type = field.field_type(self.source);
+        let target_type = target_field.field_type(self.target);
+        let converted = match (value, &source_type, &target_type) {
+            (V::Message(m), T::Message(source), T::Message(target)) => {
+                let m = self.convert_message(source, m, target, mapped, from, to)?;
+                Some(V::Message(Arc::new(m)))
+            }
+            (V::FieldMask(m), _, T::Message(target)) if target.name() == FIELD_MASK => {
+                Some(V::FieldMask(m.clone()))
+            }
+            (V::BytesStream(s), _, T::Bytes) | (V::BytesStream(s), _, T::String) => {
+                Some(V::BytesStream(s.clone()))
+            }
+            (_, source_type, target_type) => coerce(value, source_type, target_type),
+        };
+        converted.ok_or_else(|| conversion_error(from, to))
+    }
+}
+
+/// Coerces a scalar value to a field type, if it can be represented.
+fn coerce(value: &value::Value, source_type: &T, target_type: &T) -> Option<value::Value> {
+    // Enum values have names beyond their numbers
+    if let (&V::Enum(number), &T::Enum(source)) = (value, source_type) {
+        let name = source.value_by_number(number).map(|v| v.name());
+        match *target_type {
+            T::String => return name.map(|n| V::String(n.to_owned())),
+            T::Enum(target) => {
+                return match name {
+                    Some(name) => target.value_by_name(name).map(|v| V::Enum(v.number())),
+                    None => Some(V::Enum(number)),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let scalar = match *value {
+        V::Bool(v) => Scalar::Bool(v),
+        V::I32(v) | V::Enum(v) => Scalar::Int(i128::from(v)),
+        V::I64(v) => Scalar::Int(i128::from(v)),
+        V::U32(v) => Scalar::Int(i128::from(v)),
+        V::U64(v) => Scalar::Int(i128::from(v)),
+        V::F32(v) => Scalar::Float(f64::from(v)),
+        V::F64(v) => Scalar::Float(v),
+        V::String(ref v) => Scalar::Str(v),
+        V::Bytes(ref v) => Scalar::Bytes(v),
+        _ => return None,
+    };
+
+    Some(match *target_type {
+        T::Bool => V::Bool(match scalar {
+            Scalar::Bool(v) => v,
+            Scalar::Int(0) | Scalar::Str("false") => false,
+            Scalar::Int(1) | Scalar::Str("true") => true,
+            _ => return None,
+        }),
+        T::Int32 | T::SInt32 | T::SFixed32 => V::I32(integer(scalar)?),
+        T::Int64 | T::SInt64 | T::SFixed64 => V::I64(integer(scalar)?),
+        T::UInt32 | T::Fixed32 => V::U32(integer(scalar)?),
+        T::UInt64 | T::Fixed64 => V::U64(integer(scalar)?),
+        T::Enum(e) => match scalar {
+            Scalar::Str(name) => V::Enum(e.value_by_name(name)?.number()),
+            scalar => V::Enum(integer(scalar)?),
+        },
+        T::Double => V::F64(float(scalar)?),
+        T::Float => V::F32(float(scalar)? as f32),
+        // Formatted from the original value, so that `float`s don't gain digits
+        T::String => V::String(match *value {
+            V::String(ref v) => v.clone(),
+            V::Bytes(ref v) => String::from_utf8(v.clone()).ok()?,
+            V::Bool(v) => v.to_string(),
+            V::I32(v) | V::Enum(v) => v.to_string(),
+            V::I64(v) => v.to_string(),
+            V::U32(v) => v.to_string(),
+            V::U64(v) => v.to_string(),
+            V::F32(v) => v.to_string(),
+            V::F64(v) => v.to_string(),
+            _ => return None,
+        }),
+        T::Bytes => V::Bytes(match scalar {
+            Scalar::Bytes(v) => v.to_vec(),
+            Scalar::Str(v) => v.as_bytes().to_vec(),
+            _ => return None,
+        }),
+        _ => return None,
+    })
+}
+
+/// Converts a scalar to an integer type, if it is an integer that fits.
+fn integer<I>(scalar: Scalar) -> Option<I>
+where
+    I: TryFrom<i128>,
+{
+    let v = match scalar {
+        Scalar::Int(v) => v,
+        Scalar::Float(v) if v.fract() == 0.0 => v as i128,
+        Scalar::Bool(v) => i128::from(v),
+        Scalar::Str(v) => v.parse().ok()?,
+        _ => return None,
+    };
+    I::try_from(v).ok()
+}
+
+fn float(scalar: Scalar) -> Option<f64> {
+    match scalar {
+        Scalar::Int(v) => Some(v as f64),
+        Scalar::Float(v) => Some(v),
+        Scalar::Str(v) => v.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Finds the field at the rest of a path, if the messages on the way are present.
+fn find<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    message: &'a value::Message,
+    path: &str,
+    rest: &str,
+) -> error::Result<Option<(&'a descriptor::FieldDescriptor, &'a value::Field)>> {
+    let (name, rest) = split_first(rest);
+    let field = descriptor
+        .field_by_name(name)
+        .ok_or_else(|| unknown_field(path))?;
+    let value = match message.fields.get(&field.number()) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let rest = match rest {
+        Some(rest) => rest,
+        None => return Ok(Some((field, value))),
+    };
+    match (field.field_type(descriptors), value) {
+        (T::Message(nested), value::Field::Singular(v)) => match *v {
+            Some(V::Message(ref m)) => find(descriptors, nested, m, path, rest),
+            _ => Ok(None),
+        },
+        _ => Err(unknown_field(path)),
+    }
+}
+
+/// Finds the field at the rest of a path, creating the messages on the way.
+fn slot<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    message: &'a mut value::Message,
+    path: &str,
+    rest: &str,
+) -> error::Result<(&'a descriptor::FieldDescriptor, &'a mut value::Field)> {
+    let (name, rest) = split_first(rest);
+    let field = descriptor
+        .field_by_name(name)
+        .ok_or_else(|| unknown_field(path))?;
+    let value = message
+        .fields
+        .entry(field.number())
+        .or_insert_with(|| value::Field::new(field));
+    let rest = match rest {
+        Some(rest) => rest,
+        None => return Ok((field, value)),
+    };
+    match (field.field_type(descriptors), value) {
+        (T::Message(nested), &mut value::Field::Singular(ref mut v)) => {
+            if !matches!(*v, Some(V::Message(_))) {
+                *v = Some(V::Message(Arc::new(value::Message::new(nested))));
+            }
+            match *v {
+                Some(V::Message(ref mut m)) => {
+                    slot(descriptors, nested, Arc::make_mut(m), path, rest)
+                }
+                _ => unreachable!(),
+            }
+        }
+        _ => Err(unknown_field(path)),
+    }
+}
+
+fn split_first(path: &str) -> (&str, Option<&str>) {
+    match path.find('.') {
+        Some(i) => (&path[..i], Some(&path[i + 1..])),
+        None => (path, None),
+    }
+}
+
+fn push_path(path: &mut String, name: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(name);
+}
+
+fn unknown_field(path: &str) -> error::Error {
+    error::Error::UnknownField {
+        name: path.to_owned(),
+    }
+}
+
+fn conversion_error(from: &str, to: &str) -> error::Error {
+    error::Error::FieldConversion {
+        from: from.to_owned(),
+        to: to.to_owned(),
+    }
+}
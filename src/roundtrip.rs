@@ -0,0 +1,285 @@
+//! Round-trip checking of the binary encoding, for fuzzing and conformance runs.
+//!
+//! `check_roundtrip` decodes some bytes, encodes the message again, decodes the result and
+//! compares both messages field by field.  Any difference, as well as any failure to encode or
+//! to decode the re-encoded bytes, indicates a bug in either the decoder or the encoder.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::roundtrip;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! let report = roundtrip::check_roundtrip(&descriptors, descriptor, &[8, 42]).unwrap();
+//! assert!(report.is_ok(), "{}", report);
+//! # }
+//! ```
+use std::fmt;
+
+use protobuf;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// The outcome of a round-trip check.
+#[derive(Clone, Debug, Default)]
+pub struct RoundtripReport {
+    /// The bytes that the decoded message was encoded as, if encoding succeeded.
+    pub encoded: Vec<u8>,
+    /// The differences that were found; empty if the round trip was faithful.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// A single difference found by a round-trip check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// The path to the differing value, e.g. `a.b[2].c`, or empty for the whole message.
+    pub path: String,
+    /// A description of the value as originally decoded.
+    pub original: String,
+    /// A description of the value after the round trip.
+    pub roundtripped: String,
+}
+
+/// Decodes the bytes as a message of the specified type, re-encodes and re-decodes it, and
+/// reports how the two decoded messages differ.
+///
+/// An error is only returned if the original bytes can't be decoded; all failures after that
+/// are reported as mismatches.  Encoding the re-decoded message must also give the same bytes
+/// as the first encoding.
+pub fn check_roundtrip(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<RoundtripReport> {
+    let original = decode(descriptors, descriptor, bytes)?;
+    let mut report = RoundtripReport::default();
+
+    report.encoded = match original.write_to_bytes(descriptors, descriptor) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            report.failure(format!("encoding failed: {}", e));
+            return Ok(report);
+        }
+    };
+    let roundtripped = match decode(descriptors, descriptor, &report.encoded) {
+        Ok(message) => message,
+        Err(e) => {
+            report.failure(format!("decoding the encoded bytes failed: {}", e));
+            return Ok(report);
+        }
+    };
+
+    let mut path = String::new();
+    diff_messages(
+        descriptors,
+        descriptor,
+        &original,
+        &roundtripped,
+        &mut path,
+        &mut report.mismatches,
+    );
+
+    match roundtripped.write_to_bytes(descriptors, descriptor) {
+        Ok(ref encoded) if *encoded == report.encoded => (),
+        Ok(encoded) => report.mismatches.push(Mismatch {
+            path: String::new(),
+            original: format!("{} encoded bytes", report.encoded.len()),
+            roundtripped: format!("{} different encoded bytes", encoded.len()),
+        }),
+        Err(e) => report.failure(format!("encoding again failed: {}", e)),
+    }
+    Ok(report)
+}
+
+impl RoundtripReport {
+    /// Whether the round trip was faithful.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    fn failure(&mut self, message: String) {
+        self.mismatches.push(Mismatch {
+            path: String::new(),
+            original: "a valid message".to_owned(),
+            roundtripped: message,
+        });
+    }
+}
+
+impl fmt::Display for RoundtripReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "round trip ok");
+        }
+        write!(f, "{} round trip mismatches:", self.mismatches.len())?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "<message>"
+        } else {
+            &self.path
+        };
+        write!(f, "{}: {} != {}", path, self.original, self.roundtripped)
+    }
+}
+
+fn decode(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<value::Message> {
+    let mut message = value::Message::new(descriptor);
+    message.merge_from(
+        descriptors,
+        descriptor,
+        &mut protobuf::CodedInputStream::from_bytes(bytes),
+    )?;
+    Ok(message)
+}
+
+fn diff_messages(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    a: &value::Message,
+    b: &value::Message,
+    path: &mut String,
+    out: &mut Vec<Mismatch>,
+) {
+    let numbers = a
+        .fields
+        .keys()
+        .chain(b.fields.keys().filter(|n| !a.fields.contains_key(n)));
+    for &number in numbers {
+        let len = path.len();
+        if !path.is_empty() {
+            path.push('.');
+        }
+        let field = descriptor.field_by_number(number);
+        match field {
+            Some(field) => path.push_str(field.name()),
+            None => path.push_str(&number.to_string()),
+        }
+
+        match (a.fields.get(&number), b.fields.get(&number)) {
+            (Some(value::Field::Singular(a)), Some(value::Field::Singular(b))) => {
+                diff_options(descriptors, field, a.as_ref(), b.as_ref(), path, out)
+            }
+            (Some(a), Some(b)) if !is_singular(a) && !is_singular(b) => {
+                let (a, b) = (a.values(), b.values());
+                for i in 0..a.len().max(b.len()) {
+                    let len = path.len();
+                    path.push_str(&format!("[{}]", i));
+                    diff_options(descriptors, field, a.get(i), b.get(i), path, out);
+                    path.truncate(len);
+                }
+            }
+            (a, b) => out.push(mismatch(path, a, b)),
+        }
+        path.truncate(len);
+    }
+
+    if a.unknown != b.unknown {
+        out.push(Mismatch {
+            path: format!(
+                "{}{}<unknown>",
+                path,
+                if path.is_empty() { "" } else { "." }
+            ),
+            original: format!("{:?}", a.unknown),
+            roundtripped: format!("{:?}", b.unknown),
+        });
+    }
+}
+
+fn diff_options(
+    descriptors: &descriptor::Descriptors,
+    field: Option<&descriptor::FieldDescriptor>,
+    a: Option<&value::Value>,
+    b: Option<&value::Value>,
+    path: &mut String,
+    out: &mut Vec<Mismatch>,
+) {
+    match (a, b) {
+        (None, None) => (),
+        (Some(a), Some(b)) => diff_values(descriptors, field, a, b, path, out),
+        (a, b) => out.push(mismatch(path, a, b)),
+    }
+}
+
+fn diff_values(
+    descriptors: &descriptor::Descriptors,
+    field: Option<&descriptor::FieldDescriptor>,
+    a: &value::Value,
+    b: &value::Value,
+    path: &mut String,
+    out: &mut Vec<Mismatch>,
+) {
+    use crate::value::Value::*;
+
+    let equal = match (a, b) {
+        (Message(a), Message(b)) => {
+            if let Some(descriptor::FieldType::Message(m)) =
+                field.map(|f| f.field_type(descriptors))
+            {
+                return diff_messages(descriptors, m, a, b, path, out);
+            }
+            false
+        }
+        (&Bool(a), &Bool(b)) => a == b,
+        (&I32(a), &I32(b)) | (&Enum(a), &Enum(b)) => a == b,
+        (&I64(a), &I64(b)) => a == b,
+        (&U32(a), &U32(b)) => a == b,
+        (&U64(a), &U64(b)) => a == b,
+        (&F32(a), &F32(b)) => a.to_bits() == b.to_bits(),
+        (&F64(a), &F64(b)) => a.to_bits() == b.to_bits(),
+        (Bytes(a), Bytes(b)) => a == b,
+        (String(a), String(b)) => a == b,
+        (FieldMask(a), FieldMask(b)) => a.paths == b.paths,
+        _ => false,
+    };
+    if !equal {
+        out.push(mismatch(path, Some(a), Some(b)));
+    }
+}
+
+fn mismatch<A, B>(path: &str, a: Option<A>, b: Option<B>) -> Mismatch
+where
+    A: fmt::Debug,
+    B: fmt::Debug,
+{
+    Mismatch {
+        path: path.to_owned(),
+        original: describe(a),
+        roundtripped: describe(b),
+    }
+}
+
+fn is_singular(field: &value::Field) -> bool {
+    matches!(*field, value::Field::Singular(_))
+}
+
+fn describe<A>(value: Option<A>) -> String
+where
+    A: fmt::Debug,
+{
+    match value {
+        Some(v) => format!("{:?}", v),
+        None => "absent".to_owned(),
+    }
+}
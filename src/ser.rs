@@ -0,0 +1,800 @@
+//! Conversion of values that implement `Serialize` into dynamic messages.
+//!
+//! The value is first serialized into a generic tree, which is then matched against the message
+//! descriptors: structs and maps become messages with fields matched by name or by number,
+//! sequences become repeated fields, and numbers are checked against the range of their field
+//! type.
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::ser;
+
+use crate::de;
+use crate::descriptor;
+use crate::descriptor::FieldType;
+use crate::error;
+use crate::field_mask;
+use crate::value;
+
+/// Converts a value into a message of the specified type.
+pub(crate) fn to_message<T>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    options: &de::Options,
+    value: &T,
+) -> error::Result<value::Message>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let content = value
+        .serialize(ContentSerializer)
+        .map_err(error::CompatError::into_error)?;
+    message_from_content(descriptors, descriptor, options, content)
+}
+
+/// Builds a message of the named type from the literal tree that `dynamic_message!` expands to.
+#[doc(hidden)]
+pub fn message_from_literal(
+    descriptors: &descriptor::Descriptors,
+    name: &str,
+    content: Content,
+) -> error::Result<value::Message> {
+    let qualified;
+    let name = if name.starts_with('.') {
+        name
+    } else {
+        qualified = format!(".{}", name);
+        &qualified
+    };
+    let descriptor =
+        descriptors
+            .message_by_name(name)
+            .ok_or_else(|| error::Error::UnknownMessage {
+                name: name.to_owned(),
+            })?;
+    message_from_content(descriptors, descriptor, &de::Options::new(), content)
+}
+
+/// A serialized value that doesn't know about protocol buffer types yet.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum Content {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    None,
+    Seq(Vec<Content>),
+    Map(Vec<(Content, Content)>),
+}
+
+macro_rules! content_from {
+    ($($ty:ty => $variant:ident,)*) => {
+        $(
+            impl From<$ty> for Content {
+                fn from(v: $ty) -> Content {
+                    Content::$variant(v.into())
+                }
+            }
+        )*
+    };
+}
+
+content_from! {
+    bool => Bool,
+    i8 => I64,
+    i16 => I64,
+    i32 => I64,
+    i64 => I64,
+    u8 => U64,
+    u16 => U64,
+    u32 => U64,
+    u64 => U64,
+    f32 => F64,
+    f64 => F64,
+    &str => String,
+    String => String,
+    &[u8] => Bytes,
+    Vec<u8> => Bytes,
+}
+
+impl<const N: usize> From<&[u8; N]> for Content {
+    fn from(v: &[u8; N]) -> Content {
+        Content::Bytes(v.to_vec())
+    }
+}
+
+impl<T> From<Option<T>> for Content
+where
+    T: Into<Content>,
+{
+    fn from(v: Option<T>) -> Content {
+        v.map_or(Content::None, Into::into)
+    }
+}
+
+impl serde::Serialize for Content {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::{SerializeMap, SerializeSeq};
+
+        match *self {
+            Content::Bool(v) => serializer.serialize_bool(v),
+            Content::I64(v) => serializer.serialize_i64(v),
+            Content::U64(v) => serializer.serialize_u64(v),
+            Content::F64(v) => serializer.serialize_f64(v),
+            Content::String(ref v) => serializer.serialize_str(v),
+            Content::Bytes(ref v) => serializer.serialize_bytes(v),
+            Content::None => serializer.serialize_none(),
+            Content::Seq(ref items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Content::Map(ref entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Content {
+    fn deserialize<D>(deserializer: D) -> Result<Content, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ContentVisitor)
+    }
+}
+
+struct ContentVisitor;
+
+impl<'de> serde::de::Visitor<'de> for ContentVisitor {
+    type Value = Content;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Content, E> {
+        Ok(Content::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Content, E> {
+        Ok(Content::I64(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Content, E> {
+        Ok(Content::U64(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Content, E> {
+        Ok(Content::F64(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Content, E> {
+        Ok(Content::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Content, E> {
+        Ok(Content::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Content, E> {
+        Ok(Content::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Content, E> {
+        Ok(Content::Bytes(v))
+    }
+
+    fn visit_none<E>(self) -> Result<Content, E> {
+        Ok(Content::None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Content, E> {
+        Ok(Content::None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Content, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        serde::Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Content, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Content::Seq(items))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Content, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Content::Map(entries))
+    }
+}
+
+#[derive(Debug)]
+struct ContentSerializer;
+
+#[derive(Debug)]
+struct SeqSerializer {
+    variant: Option<&'static str>,
+    items: Vec<Content>,
+}
+
+#[derive(Debug)]
+struct MapSerializer {
+    variant: Option<&'static str>,
+    entries: Vec<(Content, Content)>,
+    key: Option<Content>,
+}
+
+pub(crate) fn message_from_content(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    options: &de::Options,
+    content: Content,
+) -> error::Result<value::Message> {
+    let mut message = value::Message::new(descriptor);
+    let entries = match content {
+        Content::Map(entries) => entries,
+        Content::None => return Ok(message),
+        _ => return Err(bad_field_value(descriptor.name())),
+    };
+
+    for (key, content) in entries {
+        let name = match key {
+            Content::String(name) => name,
+            _ => return Err(bad_field_value(descriptor.name())),
+        };
+        let field = descriptor
+            .field_by_name(&name)
+            .or_else(|| descriptor.field_by_json_name(&name))
+            .or_else(|| {
+                name.parse()
+                    .ok()
+                    .and_then(|number| descriptor.field_by_number(number))
+            })
+            .ok_or(error::Error::UnknownField { name })?;
+
+        let value = match content {
+            Content::None => value::Field::new(field),
+            content if field.is_repeated() => {
+                let values = match (field.field_type(descriptors), content) {
+                    (FieldType::Message(entry), Content::Map(entries)) if entry.is_map_entry() => {
+                        entries
+                            .into_iter()
+                            .map(|(k, v)| map_entry(descriptors, entry, options, k, v))
+                            .collect::<error::Result<_>>()?
+                    }
+                    (_, Content::Seq(items)) => items
+                        .into_iter()
+                        .map(|item| value_from_content(descriptors, field, options, item))
+                        .collect::<error::Result<_>>()?,
+                    _ => return Err(bad_field_value(field.name())),
+                };
+                value::Field::Repeated(values)
+            }
+            content => value::Field::Singular(Some(value_from_content(
+                descriptors,
+                field,
+                options,
+                content,
+            )?)),
+        };
+        message.fields.insert(field.number(), value);
+    }
+    Ok(message)
+}
+
+fn map_entry(
+    descriptors: &descriptor::Descriptors,
+    entry: &descriptor::MessageDescriptor,
+    options: &de::Options,
+    key: Content,
+    value: Content,
+) -> error::Result<value::Value> {
+    let bad = || bad_field_value(entry.name());
+    let key_field = entry.field_by_number(1).ok_or_else(bad)?;
+    let value_field = entry.field_by_number(2).ok_or_else(bad)?;
+
+    // Map keys are often serialized as strings even if they are integers or booleans
+    let key = match (key_field.field_type(descriptors), key) {
+        (FieldType::String, key) => key,
+        (FieldType::Bool, Content::String(s)) => Content::Bool(s.parse().map_err(|_| bad())?),
+        (_, Content::String(s)) => match s.parse::<i64>() {
+            Ok(v) => Content::I64(v),
+            Err(_) => Content::U64(s.parse().map_err(|_| bad())?),
+        },
+        (_, key) => key,
+    };
+
+    let mut message = value::Message::new(entry);
+    message.fields.insert(
+        1,
+        value::Field::Singular(Some(value_from_content(
+            descriptors,
+            key_field,
+            options,
+            key,
+        )?)),
+    );
+    if !matches!(value, Content::None) {
+        message.fields.insert(
+            2,
+            value::Field::Singular(Some(value_from_content(
+                descriptors,
+                value_field,
+                options,
+                value,
+            )?)),
+        );
+    }
+    Ok(value::Value::Message(Arc::new(message)))
+}
+
+fn value_from_content(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    options: &de::Options,
+    content: Content,
+) -> error::Result<value::Value> {
+    let bad = || bad_field_value(field.name());
+
+    let field_type = field.field_type(descriptors);
+    if let Some(representation) = options.scalar_representation_for(field, &field_type) {
+        let content = match content {
+            Content::Bool(v) => value::Value::Bool(v),
+            Content::I64(v) => value::Value::I64(v),
+            Content::U64(v) => value::Value::U64(v),
+            Content::F64(v) => value::Value::F64(v),
+            Content::String(v) => value::Value::String(v),
+            Content::Bytes(v) => value::Value::Bytes(v),
+            Content::None | Content::Seq(_) | Content::Map(_) => return Err(bad()),
+        };
+        return representation.parse(field, &field_type, content);
+    }
+
+    let value = match (field_type, content) {
+        (FieldType::Bool, Content::Bool(v)) => value::Value::Bool(v),
+        (FieldType::Int32, c) | (FieldType::SInt32, c) | (FieldType::SFixed32, c) => {
+            value::Value::I32(integer(c).ok_or_else(bad)?)
+        }
+        (FieldType::Int64, c) | (FieldType::SInt64, c) | (FieldType::SFixed64, c) => {
+            value::Value::I64(integer(c).ok_or_else(bad)?)
+        }
+        (FieldType::UInt32, c) | (FieldType::Fixed32, c) => {
+            value::Value::U32(integer(c).ok_or_else(bad)?)
+        }
+        (FieldType::UInt64, c) | (FieldType::Fixed64, c) => {
+            value::Value::U64(integer(c).ok_or_else(bad)?)
+        }
+        (FieldType::Float, c) => value::Value::F32(float(c).ok_or_else(bad)? as f32),
+        (FieldType::Double, c) => value::Value::F64(float(c).ok_or_else(bad)?),
+        (FieldType::String, Content::String(v)) => value::Value::String(v),
+        (FieldType::Bytes, Content::Bytes(v)) => value::Value::Bytes(v),
+        (FieldType::Bytes, Content::String(v)) => value::Value::Bytes(v.into_bytes()),
+        (FieldType::Bytes, Content::Seq(items)) => value::Value::Bytes(
+            items
+                .into_iter()
+                .map(integer)
+                .collect::<Option<_>>()
+                .ok_or_else(bad)?,
+        ),
+        (FieldType::Enum(e), Content::String(name)) => {
+            value::Value::Enum(e.value_by_name(&name).ok_or_else(bad)?.number())
+        }
+        (FieldType::Enum(_), c) => value::Value::Enum(integer(c).ok_or_else(bad)?),
+        (FieldType::Message(m), Content::Seq(paths)) if m.name() == field_mask::FIELD_MASK => {
+            let paths = paths.into_iter().map(|path| match path {
+                Content::String(path) => Ok(path),
+                _ => Err(bad()),
+            });
+            value::Value::FieldMask(field_mask::FieldMask {
+                paths: paths.collect::<error::Result<_>>()?,
+            })
+        }
+        (FieldType::Message(m), c) => {
+            value::Value::Message(Arc::new(message_from_content(descriptors, m, options, c)?))
+        }
+        _ => return Err(bad()),
+    };
+    Ok(value)
+}
+
+fn integer<T>(content: Content) -> Option<T>
+where
+    T: TryFrom<i64> + TryFrom<u64>,
+{
+    match content {
+        Content::I64(v) => T::try_from(v).ok(),
+        Content::U64(v) => T::try_from(v).ok(),
+        _ => None,
+    }
+}
+
+fn float(content: Content) -> Option<f64> {
+    match content {
+        Content::F64(v) => Some(v),
+        Content::I64(v) => Some(v as f64),
+        Content::U64(v) => Some(v as f64),
+        _ => None,
+    }
+}
+
+fn bad_field_value(name: &str) -> error::Error {
+    error::Error::BadFieldValue {
+        field: name.to_owned(),
+    }
+}
+
+/// Wraps the content of an enum variant as a single-entry map keyed by the variant name.
+fn variant(variant: Option<&'static str>, content: Content) -> Content {
+    match variant {
+        Some(name) => Content::Map(vec![(Content::String(name.to_owned()), content)]),
+        None => content,
+    }
+}
+
+impl ser::Serializer for ContentSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = SeqSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = MapSerializer;
+
+    fn serialize_bool(self, v: bool) -> error::CompatResult<Content> {
+        Ok(Content::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> error::CompatResult<Content> {
+        Ok(Content::I64(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> error::CompatResult<Content> {
+        Ok(Content::I64(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> error::CompatResult<Content> {
+        Ok(Content::I64(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> error::CompatResult<Content> {
+        Ok(Content::I64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> error::CompatResult<Content> {
+        Ok(Content::U64(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> error::CompatResult<Content> {
+        Ok(Content::U64(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> error::CompatResult<Content> {
+        Ok(Content::U64(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> error::CompatResult<Content> {
+        Ok(Content::U64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> error::CompatResult<Content> {
+        Ok(Content::F64(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> error::CompatResult<Content> {
+        Ok(Content::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> error::CompatResult<Content> {
+        Ok(Content::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> error::CompatResult<Content> {
+        Ok(Content::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> error::CompatResult<Content> {
+        Ok(Content::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> error::CompatResult<Content> {
+        Ok(Content::None)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> error::CompatResult<Content>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> error::CompatResult<Content> {
+        Ok(Content::None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> error::CompatResult<Content> {
+        Ok(Content::None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> error::CompatResult<Content> {
+        Ok(Content::String(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> error::CompatResult<Content>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> error::CompatResult<Content>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        Ok(variant(Some(name), value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> error::CompatResult<SeqSerializer> {
+        Ok(SeqSerializer::new(None, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> error::CompatResult<SeqSerializer> {
+        Ok(SeqSerializer::new(None, Some(len)))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> error::CompatResult<SeqSerializer> {
+        Ok(SeqSerializer::new(None, Some(len)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> error::CompatResult<SeqSerializer> {
+        Ok(SeqSerializer::new(Some(name), Some(len)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> error::CompatResult<MapSerializer> {
+        Ok(MapSerializer::new(None, len))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> error::CompatResult<MapSerializer> {
+        Ok(MapSerializer::new(None, Some(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> error::CompatResult<MapSerializer> {
+        Ok(MapSerializer::new(Some(name), Some(len)))
+    }
+}
+
+impl SeqSerializer {
+    fn new(variant: Option<&'static str>, len: Option<usize>) -> SeqSerializer {
+        SeqSerializer {
+            variant,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        }
+    }
+
+    fn push<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.items.push(value.serialize(ContentSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> error::CompatResult<Content> {
+        Ok(variant(self.variant, Content::Seq(self.items)))
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl MapSerializer {
+    fn new(variant: Option<&'static str>, len: Option<usize>) -> MapSerializer {
+        MapSerializer {
+            variant,
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        }
+    }
+
+    fn field<T>(&mut self, key: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let value = value.serialize(ContentSerializer)?;
+        self.entries.push((Content::String(key.to_owned()), value));
+        Ok(())
+    }
+
+    fn finish(self) -> error::CompatResult<Content> {
+        Ok(variant(self.variant, Content::Map(self.entries)))
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.key = Some(key.serialize(ContentSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value was called before serialize_key");
+        self.entries
+            .push((key, value.serialize(ContentSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.field(key, value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer {
+    type Ok = Content;
+    type Error = error::CompatError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> error::CompatResult<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.field(key, value)
+    }
+
+    fn end(self) -> error::CompatResult<Content> {
+        self.finish()
+    }
+}
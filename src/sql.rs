@@ -0,0 +1,294 @@
+//! Mapping of the top-level scalar fields of messages to SQL parameters and result rows, for
+//! generic persistence layers.
+//!
+//! The scalar fields of a message type (the singular fields that aren't messages or groups) are
+//! its columns, named like the fields.  `to_params` converts a message to parameter values in
+//! column order, and `from_row` builds a message from the columns of a row with matching names.
+//!
+//! `SqlValue` implements `ToSql` and `FromSql` of the `postgres-types` crate, so the parameters
+//! can be bound directly with `postgres` or `tokio-postgres`:
+//!
+//!   * `bool` fields map to `BOOL`; `int32`, `sint32` and `sfixed32` fields to `INT4`; other
+//!     integer fields to `INT8`.  `uint64` and `fixed64` values above `i64::MAX` can't be
+//!     converted.
+//!   * `float` and `double` fields map to `FLOAT4` and `FLOAT8`.
+//!   * `string` fields and enum fields (by the names of their values) map to `TEXT`.
+//!   * `bytes` fields map to `BYTEA`.
+//!   * Absent fields are `NULL`, and `NULL` columns leave their field absent.
+//!
+//! This module is only available with the `postgres` feature.
+//!
+//! ```
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::sql::{self, SqlValue};
+//! use serde_protobuf::value::{Field, Message, Value};
+//!
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors
+//!     .message_by_name(".protobuf_unittest.ForeignMessage")
+//!     .unwrap();
+//!
+//! let columns = sql::columns(&descriptors, descriptor);
+//! let query = format!(
+//!     "INSERT INTO foreign ({}) VALUES ({})",
+//!     columns.join(", "),
+//!     (1..=columns.len()).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", "),
+//! );
+//! assert_eq!("INSERT INTO foreign (c) VALUES ($1)", query);
+//!
+//! let mut message = Message::new(descriptor);
+//! message.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+//! let params = sql::to_params(&descriptors, descriptor, &message).unwrap();
+//! assert_eq!(vec![SqlValue::Int4(5)], params);
+//! ```
+use std::convert::TryFrom;
+use std::error::Error;
+
+use bytes::BytesMut;
+use postgres_types::{FromSql, IsNull, ToSql, Type};
+
+use crate::descriptor;
+use crate::descriptor::FieldType;
+use crate::error;
+use crate::value;
+
+/// A SQL parameter or column value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    /// `NULL`.
+    Null,
+    /// A `BOOL` value.
+    Bool(bool),
+    /// An `INT4` value.
+    Int4(i32),
+    /// An `INT8` value.
+    Int8(i64),
+    /// A `FLOAT4` value.
+    Float4(f32),
+    /// A `FLOAT8` value.
+    Float8(f64),
+    /// A `TEXT` value.
+    Text(String),
+    /// A `BYTEA` value.
+    Bytea(Vec<u8>),
+}
+
+/// The names of the columns of a message type, in field declaration order.
+pub fn columns<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+) -> Vec<&'a str> {
+    scalar_fields(descriptors, descriptor)
+        .map(|(f, _)| f.name())
+        .collect()
+}
+
+/// Converts the scalar fields of a message to parameter values, in the order of `columns`.
+pub fn to_params(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<Vec<SqlValue>> {
+    scalar_fields(descriptors, descriptor)
+        .map(
+            |(field, field_type)| match message.fields.get(&field.number()) {
+                Some(value::Field::Singular(Some(value::Value::Null))) => Ok(SqlValue::Null),
+                Some(value::Field::Singular(Some(v))) => to_sql_value(field, &field_type, v),
+                _ => Ok(SqlValue::Null),
+            },
+        )
+        .collect()
+}
+
+/// Builds a message from the named column values of a row.
+///
+/// Columns are matched to fields by name; columns without a matching scalar field are ignored.
+pub fn from_row<'a, I>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    row: I,
+) -> error::Result<value::Message>
+where
+    I: IntoIterator<Item = (&'a str, SqlValue)>,
+{
+    let mut message = value::Message::new(descriptor);
+    for (column, v) in row {
+        let found = scalar_fields(descriptors, descriptor).find(|(f, _)| f.name() == column);
+        if let Some((field, field_type)) = found {
+            let v = match v {
+                SqlValue::Null => None,
+                v => Some(from_sql_value(field, &field_type, v)?),
+            };
+            message
+                .fields
+                .insert(field.number(), value::Field::Singular(v));
+        }
+    }
+    Ok(message)
+}
+
+fn scalar_fields<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+) -> impl Iterator<Item = (&'a descriptor::FieldDescriptor, FieldType<'a>)> {
+    descriptor.fields().iter().filter_map(move |f| {
+        if f.is_repeated() {
+            return None;
+        }
+        match f.field_type(descriptors) {
+            FieldType::Message(_)
+            | FieldType::Group
+            | FieldType::UnresolvedEnum(_)
+            | FieldType::UnresolvedMessage(_) => None,
+            field_type => Some((f, field_type)),
+        }
+    })
+}
+
+fn to_sql_value(
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType,
+    v: &value::Value,
+) -> error::Result<SqlValue> {
+    Ok(match (field_type, v) {
+        (_, &value::Value::Bool(v)) => SqlValue::Bool(v),
+        (_, &value::Value::I32(v)) => SqlValue::Int4(v),
+        (_, &value::Value::I64(v)) => SqlValue::Int8(v),
+        (_, &value::Value::U32(v)) => SqlValue::Int8(i64::from(v)),
+        (_, &value::Value::U64(v)) => {
+            SqlValue::Int8(i64::try_from(v).map_err(|_| bad_field_value(field))?)
+        }
+        (_, &value::Value::F32(v)) => SqlValue::Float4(v),
+        (_, &value::Value::F64(v)) => SqlValue::Float8(v),
+        (_, value::Value::String(v)) => SqlValue::Text(v.clone()),
+        (_, value::Value::Bytes(v)) => SqlValue::Bytea(v.clone()),
+        (_, value::Value::BytesStream(s)) => SqlValue::Bytea(s.read_to_vec()?),
+        (&FieldType::Enum(e), &value::Value::Enum(n)) => match e.value_by_number(n) {
+            Some(v) => SqlValue::Text(v.name().to_owned()),
+            None => return Err(error::Error::UnknownEnumValue { value: n }),
+        },
+        _ => return Err(bad_field_value(field)),
+    })
+}
+
+fn from_sql_value(
+    field: &descriptor::FieldDescriptor,
+    field_type: &FieldType,
+    v: SqlValue,
+) -> error::Result<value::Value> {
+    let bad = || bad_field_value(field);
+    let integer = match v {
+        SqlValue::Int4(v) => Some(i64::from(v)),
+        SqlValue::Int8(v) => Some(v),
+        _ => None,
+    };
+    Ok(match (field_type, v) {
+        (&FieldType::Bool, SqlValue::Bool(v)) => value::Value::Bool(v),
+        (&FieldType::Int32, _) | (&FieldType::SInt32, _) | (&FieldType::SFixed32, _) => {
+            value::Value::I32(
+                integer
+                    .and_then(|v| i32::try_from(v).ok())
+                    .ok_or_else(bad)?,
+            )
+        }
+        (&FieldType::Int64, _) | (&FieldType::SInt64, _) | (&FieldType::SFixed64, _) => {
+            value::Value::I64(integer.ok_or_else(bad)?)
+        }
+        (&FieldType::UInt32, _) | (&FieldType::Fixed32, _) => value::Value::U32(
+            integer
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        (&FieldType::UInt64, _) | (&FieldType::Fixed64, _) => value::Value::U64(
+            integer
+                .and_then(|v| u64::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        (&FieldType::Float, SqlValue::Float4(v)) => value::Value::F32(v),
+        (&FieldType::Double, SqlValue::Float4(v)) => value::Value::F64(f64::from(v)),
+        (&FieldType::Double, SqlValue::Float8(v)) => value::Value::F64(v),
+        (&FieldType::String, SqlValue::Text(v)) => value::Value::String(v),
+        (&FieldType::Bytes, SqlValue::Bytea(v)) => value::Value::Bytes(v),
+        (&FieldType::Enum(e), SqlValue::Text(name)) => {
+            value::Value::Enum(e.value_by_name(&name).ok_or_else(bad)?.number())
+        }
+        (&FieldType::Enum(_), _) => value::Value::Enum(
+            integer
+                .and_then(|v| i32::try_from(v).ok())
+                .ok_or_else(bad)?,
+        ),
+        _ => return Err(bad()),
+    })
+}
+
+fn bad_field_value(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // Integers may be bound to any integer column that can hold them
+        let integer = match *self {
+            SqlValue::Int4(v) => Some(i64::from(v)),
+            SqlValue::Int8(v) => Some(v),
+            _ => None,
+        };
+        match (self, integer) {
+            (&SqlValue::Null, _) => Ok(IsNull::Yes),
+            (_, Some(v)) if *ty == Type::INT2 => i16::try_from(v)?.to_sql(ty, out),
+            (_, Some(v)) if *ty == Type::INT4 => i32::try_from(v)?.to_sql(ty, out),
+            (_, Some(v)) if *ty == Type::INT8 => v.to_sql(ty, out),
+            (&SqlValue::Bool(v), _) if *ty == Type::BOOL => v.to_sql(ty, out),
+            (&SqlValue::Float4(v), _) if *ty == Type::FLOAT4 => v.to_sql(ty, out),
+            (&SqlValue::Float4(v), _) if *ty == Type::FLOAT8 => f64::from(v).to_sql(ty, out),
+            (&SqlValue::Float8(v), _) if *ty == Type::FLOAT8 => v.to_sql(ty, out),
+            (SqlValue::Text(v), _) if <String as ToSql>::accepts(ty) => v.to_sql(ty, out),
+            (SqlValue::Bytea(v), _) if *ty == Type::BYTEA => v.to_sql(ty, out),
+            _ => Err(format!("can't bind {:?} to a {} parameter", self, ty).into()),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <bool as ToSql>::accepts(ty)
+            || <i16 as ToSql>::accepts(ty)
+            || <i32 as ToSql>::accepts(ty)
+            || <i64 as ToSql>::accepts(ty)
+            || <f64 as ToSql>::accepts(ty)
+            || <String as ToSql>::accepts(ty)
+            || <Vec<u8> as ToSql>::accepts(ty)
+    }
+
+    postgres_types::to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for SqlValue {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<SqlValue, Box<dyn Error + Sync + Send>> {
+        Ok(match *ty {
+            Type::BOOL => SqlValue::Bool(bool::from_sql(ty, raw)?),
+            Type::INT2 => SqlValue::Int4(i32::from(i16::from_sql(ty, raw)?)),
+            Type::INT4 => SqlValue::Int4(i32::from_sql(ty, raw)?),
+            Type::INT8 => SqlValue::Int8(i64::from_sql(ty, raw)?),
+            Type::FLOAT4 => SqlValue::Float4(f32::from_sql(ty, raw)?),
+            Type::FLOAT8 => SqlValue::Float8(f64::from_sql(ty, raw)?),
+            Type::BYTEA => SqlValue::Bytea(Vec::from_sql(ty, raw)?),
+            _ => SqlValue::Text(String::from_sql(ty, raw)?),
+        })
+    }
+
+    fn from_sql_null(_: &Type) -> Result<SqlValue, Box<dyn Error + Sync + Send>> {
+        Ok(SqlValue::Null)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <SqlValue as ToSql>::accepts(ty)
+    }
+}
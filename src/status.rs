@@ -0,0 +1,125 @@
+//! Decoding of `google.rpc.Status` error payloads.
+//!
+//! gRPC servers can attach a `google.rpc.Status` message to an error response (in the
+//! `grpc-status-details-bin` trailer), whose `details` are `google.protobuf.Any` messages of
+//! arbitrary types.  The functions in this module decode such a status and expand each of the
+//! details into a dynamic message, provided that its type is present in the descriptor pool.
+//!
+//! Note that the `grpc-status-details-bin` trailer is base64 encoded on the wire; most gRPC
+//! libraries already decode `-bin` metadata, otherwise it must be decoded before calling
+//! `Status::decode`.
+use protobuf;
+use protobuf::stream::wire_format;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// A decoded `google.rpc.Status` message.
+#[derive(Clone, Debug, Default)]
+pub struct Status {
+    /// The status code, which should be a `google.rpc.Code` value.
+    pub code: i32,
+    /// A developer-facing error message.
+    pub message: String,
+    /// Additional details about the error.
+    pub details: Vec<Detail>,
+}
+
+/// A single error detail, as carried by a `google.protobuf.Any` message.
+#[derive(Clone, Debug, Default)]
+pub struct Detail {
+    /// The type URL of the detail, e.g. `type.googleapis.com/google.rpc.BadRequest`.
+    pub type_url: String,
+    /// The encoded detail message.
+    pub value: Vec<u8>,
+    /// The decoded detail message, if its type was found in the descriptor pool.
+    pub message: Option<value::Message>,
+}
+
+impl Status {
+    /// Decodes a `google.rpc.Status` message from the given bytes, expanding its details against
+    /// the given descriptors.
+    ///
+    /// Details whose type is unknown are kept in their encoded form; details whose type is known
+    /// but that fail to decode cause an error.
+    pub fn decode(descriptors: &descriptor::Descriptors, bytes: &[u8]) -> error::Result<Status> {
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut status = Status::default();
+
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            match (number, wire_type) {
+                (1, wire_format::WireType::WireTypeVarint) => status.code = input.read_int32()?,
+                (2, wire_format::WireType::WireTypeLengthDelimited) => {
+                    status.message = input.read_string()?
+                }
+                (3, wire_format::WireType::WireTypeLengthDelimited) => {
+                    let len = input.read_raw_varint64()?;
+                    let old_limit = input.push_limit(len)?;
+                    let detail = Detail::merge_from(descriptors, &mut input)?;
+                    input.pop_limit(old_limit);
+                    status.details.push(detail);
+                }
+                _ => skip_field(number, wire_type, &mut input)?,
+            }
+        }
+
+        Ok(status)
+    }
+}
+
+impl Detail {
+    /// The fully qualified name of the detail's message type, as used by `Descriptors`.
+    ///
+    /// This is the part of the type URL after its last `/`, prefixed with a `.`.
+    pub fn type_name(&self) -> String {
+        let name = match self.type_url.rfind('/') {
+            Some(i) => &self.type_url[i + 1..],
+            None => &self.type_url,
+        };
+        format!(".{}", name)
+    }
+
+    fn merge_from(
+        descriptors: &descriptor::Descriptors,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<Detail> {
+        let mut detail = Detail::default();
+
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            match (number, wire_type) {
+                (1, wire_format::WireType::WireTypeLengthDelimited) => {
+                    detail.type_url = input.read_string()?
+                }
+                (2, wire_format::WireType::WireTypeLengthDelimited) => {
+                    detail.value = input.read_bytes()?
+                }
+                _ => skip_field(number, wire_type, input)?,
+            }
+        }
+
+        if let Some(message) = descriptors.message_by_type_url(&detail.type_url) {
+            let mut m = value::Message::new(message);
+            m.merge_from(
+                descriptors,
+                message,
+                &mut protobuf::CodedInputStream::from_bytes(&detail.value),
+            )?;
+            detail.message = Some(m);
+        }
+
+        Ok(detail)
+    }
+}
+
+fn skip_field(
+    number: u32,
+    wire_type: wire_format::WireType,
+    input: &mut protobuf::CodedInputStream,
+) -> error::Result<()> {
+    let mut unknown = protobuf::UnknownFields::new();
+    protobuf::rt::read_unknown_or_skip_group(number, wire_type, input, &mut unknown)?;
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! Streaming access to huge repeated fields.
+//!
+//! Decoding a message materializes every element of its repeated fields at once.  For fields
+//! with millions of elements, `decode_repeated_iter` instead returns an iterator that decodes
+//! the elements of a single repeated field one at a time, directly from the encoded bytes, so
+//! they can be processed with constant memory.
+//!
+//! ```
+//! # extern crate protobuf;
+//! # extern crate serde_protobuf;
+//! # use std::fs;
+//! # use serde_protobuf::descriptor::Descriptors;
+//! use serde_protobuf::stream;
+//! use serde_protobuf::value::Value;
+//!
+//! # fn main() {
+//! # let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! # let proto = protobuf::parse_from_reader(&mut file).unwrap();
+//! let descriptors = Descriptors::from_proto(&proto);
+//! let descriptor = descriptors.message_by_name(".protobuf_unittest.TestAllTypes").unwrap();
+//!
+//! // repeated_int32 = [1, 2], packed
+//! let bytes = [0xfa, 0x01, 2, 1, 2];
+//! let values = stream::decode_repeated_iter(&descriptors, descriptor, "repeated_int32", &bytes)
+//!     .unwrap()
+//!     .collect::<Result<Vec<_>, _>>()
+//!     .unwrap();
+//! assert_eq!(2, values.len());
+//! match values[1] {
+//!     Value::I32(v) => assert_eq!(2, v),
+//!     ref v => panic!("unexpected value: {:?}", v),
+//! }
+//! # }
+//! ```
+use protobuf;
+use protobuf::stream::wire_format;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// A lazily decoding iterator over the elements of a repeated field.
+///
+/// Created by `decode_repeated_iter`.  After yielding an error, the iterator is exhausted.
+#[derive(Debug)]
+pub struct RepeatedIter<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    path: Vec<&'a descriptor::FieldDescriptor>,
+    // The whole encoded message, which the positions of errors are relative to
+    input: &'a [u8],
+    // The remaining bytes of each enclosing message along the path
+    stack: Vec<&'a [u8]>,
+    // The remaining bytes of the packed run being read, if any
+    packed: &'a [u8],
+}
+
+/// Returns an iterator over the elements of the repeated field at `path` in the encoded message.
+///
+/// The path consists of field names separated by dots; all fields but the last must be singular
+/// message fields, and the last must be a repeated field.  Elements are yielded in encoding
+/// order, including elements of every occurrence of the enclosing messages, which matches the
+/// order they would have after decoding the whole message.
+pub fn decode_repeated_iter<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    descriptor: &'a descriptor::MessageDescriptor,
+    path: &str,
+    bytes: &'a [u8],
+) -> error::Result<RepeatedIter<'a>> {
+    let mut fields = Vec::new();
+    let mut message = descriptor;
+    let mut names = path.split('.').peekable();
+    while let Some(name) = names.next() {
+        let field = message
+            .field_by_name(name)
+            .ok_or_else(|| error::Error::UnknownField {
+                name: name.to_owned(),
+            })?;
+        fields.push(field);
+
+        if names.peek().is_none() {
+            if !field.is_repeated() {
+                return Err(error::Error::BadFieldValue {
+                    field: field.name().to_owned(),
+                });
+            }
+        } else {
+            message = match field.field_type(descriptors) {
+                descriptor::FieldType::Message(m) if !field.is_repeated() => m,
+                descriptor::FieldType::UnresolvedMessage(m) => {
+                    return Err(error::Error::UnknownMessage { name: m.to_owned() })
+                }
+                _ => {
+                    return Err(error::Error::BadFieldValue {
+                        field: field.name().to_owned(),
+                    })
+                }
+            };
+        }
+    }
+
+    Ok(RepeatedIter {
+        descriptors,
+        path: fields,
+        input: bytes,
+        stack: vec![bytes],
+        packed: &[],
+    })
+}
+
+impl<'a> RepeatedIter<'a> {
+    fn next_value(&mut self) -> error::Result<Option<value::Value>> {
+        let target = self.path[self.path.len() - 1];
+
+        loop {
+            if !self.packed.is_empty() {
+                let wire_type = packed_wire_type(target.field_type(self.descriptors))
+                    .expect("packed run of a non-packable field");
+                let packed = self.packed;
+                let mut input = protobuf::CodedInputStream::from_bytes(packed);
+                let value = self
+                    .read_value(target, &mut input, wire_type)
+                    .map_err(|e| self.locate(e, packed))?;
+                self.packed = &packed[input.pos() as usize..];
+                return Ok(Some(value));
+            }
+
+            let depth = self.stack.len() - 1;
+            let bytes = self.stack[depth];
+            if bytes.is_empty() {
+                self.stack.pop();
+                if self.stack.is_empty() {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            match self.next_field(depth, bytes, &mut input) {
+                Ok(Some(value)) => return Ok(Some(value)),
+                Ok(None) => (),
+                Err(e) => return Err(self.locate(e, bytes)),
+            }
+        }
+    }
+
+    // Reads the field at the start of `bytes`, the remaining bytes of the message at `depth`,
+    // returning its value if it is an element of the repeated field
+    fn next_field(
+        &mut self,
+        depth: usize,
+        bytes: &'a [u8],
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<Option<value::Value>> {
+        let (number, wire_type) = value::read_tag(input, false)?;
+        let field = self.path[depth];
+
+        if number != field.number() as u32 {
+            let mut unknown = protobuf::UnknownFields::new();
+            protobuf::rt::read_unknown_or_skip_group(number, wire_type, input, &mut unknown)?;
+            self.stack[depth] = &bytes[input.pos() as usize..];
+        } else if depth + 1 < self.path.len() {
+            if wire_type != wire_format::WireType::WireTypeLengthDelimited {
+                return Err(error::Error::BadWireType {
+                    wire_type,
+                    position: input.pos(),
+                });
+            }
+            let (message, rest) = split_length_delimited(input, bytes)?;
+            self.stack[depth] = rest;
+            self.stack.push(message);
+        } else if wire_type == wire_format::WireType::WireTypeLengthDelimited
+            && packed_wire_type(field.field_type(self.descriptors)).is_some()
+        {
+            let (packed, rest) = split_length_delimited(input, bytes)?;
+            self.stack[depth] = rest;
+            self.packed = packed;
+        } else {
+            let value = self.read_value(field, input, wire_type)?;
+            self.stack[depth] = &bytes[input.pos() as usize..];
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+
+    fn read_value(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+    ) -> error::Result<value::Value> {
+        let mut element = value::Field::Singular(None);
+        element.merge_from(self.descriptors, field, input, wire_type)?;
+        match element {
+            value::Field::Singular(Some(value)) => Ok(value),
+            _ => Err(error::Error::BadFieldValue {
+                field: field.name().to_owned(),
+            }),
+        }
+    }
+
+    // Makes the position of an error about the field or value at the start of `bytes`, a part of
+    // the input, a position in the input
+    fn locate(&self, error: error::Error, bytes: &[u8]) -> error::Error {
+        let offset = (bytes.as_ptr() as usize - self.input.as_ptr() as usize) as u64;
+        error.at_offset(offset).truncated_at(offset)
+    }
+}
+
+impl<'a> Iterator for RepeatedIter<'a> {
+    type Item = error::Result<value::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stack.is_empty() {
+            return None;
+        }
+        match self.next_value() {
+            Ok(value) => value.map(Ok),
+            Err(e) => {
+                self.stack.clear();
+                self.packed = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The wire type of the elements of a packed run of the field type, if it can be packed.
+fn packed_wire_type(field_type: descriptor::FieldType) -> Option<wire_format::WireType> {
+    use crate::descriptor::FieldType::*;
+    use protobuf::stream::wire_format::WireType::*;
+
+    match field_type {
+        Bool | Int32 | Int64 | SInt32 | SInt64 | UInt32 | UInt64 | Enum(_) => Some(WireTypeVarint),
+        Fixed32 | SFixed32 | Float => Some(WireTypeFixed32),
+        Fixed64 | SFixed64 | Double => Some(WireTypeFixed64),
+        _ => None,
+    }
+}
+
+/// Reads a length prefix at the current position of `input`, which reads from `bytes`, and
+/// splits off the delimited bytes from the rest.
+fn split_length_delimited<'a>(
+    input: &mut protobuf::CodedInputStream,
+    bytes: &'a [u8],
+) -> error::Result<(&'a [u8], &'a [u8])> {
+    let len = input.read_raw_varint64()?;
+    let rest = &bytes[input.pos() as usize..];
+    if len > rest.len() as u64 {
+        return Err(
+            protobuf::ProtobufError::WireError(protobuf::error::WireError::UnexpectedEof).into(),
+        );
+    }
+    Ok(rest.split_at(len as usize))
+}
@@ -1,11 +1,32 @@
 //! Types for representing runtime Protobuf values.
+//!
+//! Messages, fields and values own all of their data, so they are `Send` and `Sync`: decoded
+//! messages can be moved to other threads, or shared between them behind an `Arc`.  Encoding a
+//! shared message from several threads at once is fine as well, even with different options,
+//! since each encode keeps the sizes of nested messages to itself.  The exception is the size that
+//! `Message::compute_size` stores in a message for `Message::write_to_with_cached_sizes`: sizing
+//! the message again with other options in between makes the write use the wrong sizes.  The
+//! decode and encode options can be shared between threads too.
+use std::any;
+use std::borrow;
+use std::cmp;
 use std::collections;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::mem;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex, PoisonError};
 
 use protobuf;
 use protobuf::stream::wire_format;
+use serde;
 
+use crate::de;
 use crate::descriptor;
 use crate::error;
+use crate::field_mask;
+use crate::ser;
 
 /// Any protobuf value.
 #[derive(Clone, Debug)]
@@ -30,17 +51,71 @@ pub enum Value {
     String(String),
     /// An enum value.
     Enum(i32),
-    /// A message.
-    Message(Message),
+    /// A message, shared between clones until one of them modifies it with `Arc::make_mut`.
+    Message(Arc<Message>),
+    /// A `google.protobuf.FieldMask` message.
+    FieldMask(field_mask::FieldMask),
+    /// A `bytes` or `string` value whose content is read from a stream while encoding.
+    BytesStream(BytesStream),
+    /// A message of a type with a `TypeHandler`, in the representation of the handler.
+    Custom(CustomValue),
+    /// The value of a singular field that was explicitly cleared, as opposed to one that is
+    /// absent, e.g. for updates that must be able to clear fields.
+    ///
+    /// Decoding never produces `Null`, and fields holding it are encoded like absent fields.
+    Null,
 }
 
 /// A message value.
-#[derive(Clone, Debug)]
+///
+/// Nested messages are held in an `Arc` (`Value::Message` holds an `Arc<Message>` since version
+/// 0.9), so cloning a message only copies its top level and shares its nested messages with the
+/// clone.  A nested message is copied when one of the messages sharing it modifies it through
+/// `Arc::make_mut`, which is what the mutating methods of this crate do.  Encoding doesn't store
+/// anything in nested messages, so clones can be encoded with different options at the same time.
+///
+/// Besides its fields, a message keeps private bookkeeping like its cached encoded size, so it
+/// can't be built with a struct literal (which was possible up to version 0.8); start from
+/// `Message::new` or `Message::default` and fill in `fields` instead.
+#[derive(Clone, Debug, Default)]
 pub struct Message {
     /// Known fields on the message.
     pub fields: collections::BTreeMap<i32, Field>,
     /// Unknown fields on the message.
     pub unknown: protobuf::UnknownFields,
+    cached_size: CachedSize,
+    // Boxed to keep messages small, since few messages need it
+    state: Option<Box<MessageState>>,
+}
+
+/// The encoded size of a message as computed by the last call to `Message::compute_size`, with the
+/// sizes of its nested messages.
+///
+/// The sizes of nested messages are kept here rather than in the nested messages themselves,
+/// since those can be shared with clones that are encoded with different options.
+#[derive(Default)]
+struct CachedSize {
+    size: protobuf::CachedSize,
+    nested: Mutex<Sizes>,
+}
+
+/// The encoded sizes of the nested messages of a message, by address, as computed for one encode.
+#[derive(Clone, Debug, Default)]
+struct Sizes(collections::HashMap<usize, u32>);
+
+/// Bookkeeping about where a message came from and how it was modified since.
+#[derive(Clone, Debug, Default)]
+struct MessageState {
+    // The numbers of the fields that were marked as changed
+    changed: collections::BTreeSet<i32>,
+    // The values of singular fields that later occurrences replaced while decoding
+    duplicates: Vec<(i32, Value)>,
+    #[cfg(feature = "bytes")]
+    source: Option<bytes::Bytes>,
+    // Whether the source bytes were forgotten because the message was modified, which makes the
+    // source bytes of the messages enclosing it stale as well
+    #[cfg(feature = "bytes")]
+    modified: bool,
 }
 
 /// A message field value.
@@ -50,16 +125,411 @@ pub enum Field {
     Singular(Option<Value>),
     /// A field with several (repeated) values.
     Repeated(Vec<Value>),
+    /// A repeated scalar field whose values are stored by type, without the overhead of a `Value`
+    /// per element.
+    RepeatedTyped(TypedValues),
+}
+
+/// The values of a repeated bool or numeric field, stored as a vector of their type.
+///
+/// Enum fields don't have typed storage.  Decoding only produces typed storage with
+/// `DecodeOptions::typed_repeated_scalars`; `Field::values` views it as values either way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypedValues {
+    /// The values of a `bool` field.
+    Bool(Vec<bool>),
+    /// The values of an `int32`, `sint32` or `sfixed32` field.
+    I32(Vec<i32>),
+    /// The values of an `int64`, `sint64` or `sfixed64` field.
+    I64(Vec<i64>),
+    /// The values of a `uint32` or `fixed32` field.
+    U32(Vec<u32>),
+    /// The values of a `uint64` or `fixed64` field.
+    U64(Vec<u64>),
+    /// The values of a `float` field.
+    F32(Vec<f32>),
+    /// The values of a `double` field.
+    F64(Vec<f64>),
+}
+
+/// An iterator over a buffer holding a sequence of records, each prefixed by its length encoded as
+/// a varint (the framing used by e.g. `writeDelimitedTo` in the Java library).
+#[derive(Clone, Debug)]
+pub struct LengthDelimitedRecords<'a> {
+    bytes: &'a [u8],
+}
+
+/// The content of a large `bytes` or `string` field, streamed from a reader while encoding
+/// instead of being held in memory.
+///
+/// The length must be known up front, since it precedes the content in the encoding.  The reader
+/// is consumed by the first encoding; clones share the same reader.
+#[derive(Clone)]
+pub struct BytesStream {
+    len: u32,
+    reader: Arc<Mutex<Option<Box<dyn io::Read + Send>>>>,
+}
+
+/// A value of any Rust type that represents a message, produced by a `TypeHandler`.
+///
+/// Clones share the same value.
+#[derive(Clone)]
+pub struct CustomValue {
+    value: Arc<dyn any::Any + Send + Sync>,
+    type_name: &'static str,
+}
+
+/// Converts messages of a type between their dynamic representation and a custom Rust type,
+/// e.g. a `Decimal` message to a `rust_decimal::Decimal`, so that they appear as `Value::Custom`
+/// values in decoded messages.
+pub trait TypeHandler: fmt::Debug + Send + Sync {
+    /// Converts a decoded message into its custom representation.
+    fn decode(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message_type: &descriptor::MessageDescriptor,
+        message: Message,
+    ) -> error::Result<CustomValue>;
+
+    /// Converts a custom value back into a message to encode it.
+    fn encode(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message_type: &descriptor::MessageDescriptor,
+        value: &CustomValue,
+    ) -> error::Result<Message>;
+}
+
+/// The `TypeHandler`s of message types, by the fully qualified names of the types.
+#[derive(Clone, Debug, Default)]
+pub struct TypeHandlers {
+    handlers: collections::HashMap<String, Arc<dyn TypeHandler>>,
+}
+
+/// Records changes to the fields of a message while the fields themselves are borrowed.
+#[derive(Debug)]
+pub(crate) struct ChangeRecorder<'a> {
+    state: &'a mut Option<Box<MessageState>>,
+}
+
+/// Reads from a sequence of buffers as if they were concatenated.
+#[cfg(feature = "bytes")]
+struct BufsReader<I>
+where
+    I: Iterator,
+{
+    current: Option<I::Item>,
+    rest: I,
+}
+
+/// Options that control how messages are encoded.
+///
+/// By default, every singular field that holds a value is written, including fields that hold their
+/// default value (proto2 presence semantics).  With `skip_defaults`, singular fields that are equal
+/// to their default value are omitted instead (proto3 semantics).
+///
+/// Known fields are always written in field number order, followed by the unknown fields, also in
+/// field number order.  Repeated scalar and enum fields are written as a single packed run if
+/// their descriptor says so, see `FieldDescriptor::is_packed`.
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+    skip_defaults: bool,
+    canonical: bool,
+    field_codec: Option<Arc<dyn FieldCodec>>,
+    type_handlers: Option<Arc<TypeHandlers>>,
+    #[cfg(feature = "bytes")]
+    pass_through: bool,
+    deprecated_fields: DeprecatedFieldPolicy,
+}
+
+/// Statistics about a single decode, for monitoring e.g. schema drift in production.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DecodeStats {
+    /// The number of bytes that were read from the input.
+    pub bytes_read: u64,
+    /// The number of known fields that were decoded, counting each occurrence of a field tag.
+    pub fields_decoded: u64,
+    /// The number of fields that the message types don't know about.
+    pub unknown_fields: u64,
+    /// The maximum nesting depth of messages, where the top-level message has a depth of 1.
+    pub max_depth: u32,
+    /// An estimate of the number of heap allocations: one for each string and bytes value, and
+    /// for each nested message that wasn't taken from a pool or merged into.
+    pub allocations: u64,
+}
+
+/// The default maximum nesting depth of messages when decoding, like in the other Protobuf
+/// implementations; see `DecodeOptions::recursion_limit`.
+pub const DEFAULT_RECURSION_LIMIT: u32 = 100;
+
+/// Options that control how messages are decoded.
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+    recursion_limit: u32,
+    unknown_fields: UnknownFieldPolicy,
+    unresolved_messages_as_bytes: bool,
+    strict: bool,
+    typed_repeated_scalars: bool,
+    duplicate_fields: DuplicateFieldPolicy,
+    field_codec: Option<Arc<dyn FieldCodec>>,
+    type_handlers: Option<Arc<TypeHandlers>>,
+    deprecated_fields: DeprecatedFieldPolicy,
+}
+
+/// What to do with fields that the message type doesn't know about when decoding.
+#[derive(Clone, Debug, Default)]
+pub enum UnknownFieldPolicy {
+    /// Keep the fields in the `unknown` fields of the message, so that they are written back out.
+    #[default]
+    Preserve,
+    /// Skip over the fields.
+    Drop,
+    /// Fail the decode with an `UnknownFieldNumber` error.
+    Error,
+    /// Pass the number, wire type and encoded value of each field to a callback, and then keep
+    /// the field like `Preserve` does.  Groups are skipped without invoking the callback.
+    Callback(UnknownFieldCallback),
+}
+
+/// What to do about fields that are marked as `deprecated = true` when they are decoded or
+/// encoded, e.g. to find the producers that still set them during a schema migration.
+///
+/// Fields are reported with their path from the top-level message, like `order.legacy_id`, once
+/// for every message in which they are set.
+#[derive(Clone, Debug, Default)]
+pub enum DeprecatedFieldPolicy {
+    /// Don't look for deprecated fields.
+    #[default]
+    Ignore,
+    /// Pass the path and descriptor of each deprecated field to a callback.
+    Callback(DeprecatedFieldCallback),
+    /// Emit a `tracing` warning for each deprecated field.
+    #[cfg(feature = "tracing")]
+    Trace,
+}
+
+/// What to do when a singular field occurs more than once when decoding.
+///
+/// Only scalar, string, bytes and enum fields are affected; the occurrences of singular message
+/// fields are always merged, as the protobuf specification requires.  A field that is set in
+/// several occurrences of a merged message is a duplicate as well.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DuplicateFieldPolicy {
+    /// Keep the last value, as the protobuf specification requires.
+    #[default]
+    Overwrite,
+    /// Fail the decode with a `DuplicateField` error.
+    Error,
+    /// Keep the last value, and collect the values that it replaced in the `duplicates` of the
+    /// message.
+    Collect,
+}
+
+/// A transformation of the content of selected `bytes` and `string` fields, like encryption,
+/// tokenization or compression, that is undone when decoding and applied when encoding.
+///
+/// Fields of other types are never passed to a codec.  Transformed `string` fields must decode
+/// to valid UTF-8.
+pub trait FieldCodec: fmt::Debug + Send + Sync {
+    /// Whether the codec transforms the specified field of the specified message type.
+    ///
+    /// Fields can be selected by their message type and name, or by a custom option, see
+    /// `FieldDescriptor::custom_option`.
+    fn applies_to(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) -> bool;
+
+    /// Transforms the content of a field before it is written.
+    ///
+    /// The result must only depend on the content, since the content is transformed once to
+    /// compute the encoded size and again to write it.
+    fn encode(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        content: &[u8],
+    ) -> error::Result<Vec<u8>>;
+
+    /// Undoes the transformation of `encode` on the content of a field that was read.
+    fn decode(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        content: Vec<u8>,
+    ) -> error::Result<Vec<u8>>;
+}
+
+/// How `Message::truncate_to` picks the fields to remove.
+#[derive(Clone, Debug)]
+pub enum TruncationStrategy {
+    /// Repeatedly shrink the field with the largest encoded size: repeated fields lose their
+    /// last element, and singular fields are cleared.
+    LargestFirst,
+    /// Clear the fields at the paths of the mask, in order of the paths, until the message fits.
+    Priority(field_mask::FieldMask),
+}
+
+/// What `Message::project_onto` does with the fields that it removes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemovedFieldPolicy {
+    /// Discard the fields.
+    Drop,
+    /// Keep the fields as unknown fields of their message.
+    Unknown,
+}
+
+/// What `Message::truncate_to` removed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TruncationReport {
+    /// The paths of the fields that were cleared, in order.
+    pub cleared: Vec<String>,
+    /// The names of the repeated fields that lost elements, with the number of elements
+    /// removed from their end.
+    pub trimmed: Vec<(String, usize)>,
+    /// The encoded size of the message after truncation.
+    pub size: u32,
+}
+
+/// A callback that receives the number, wire type and encoded value of unknown fields.
+///
+/// The value is the raw varint or little-endian fixed-width encoding, or the contents of a
+/// length-delimited field without its length prefix.
+#[derive(Clone)]
+pub struct UnknownFieldCallback(Arc<UnknownFieldFn>);
+
+type UnknownFieldFn = dyn Fn(u32, wire_format::WireType, &[u8]) + Send + Sync;
+
+/// A callback that receives the path and descriptor of deprecated fields.
+#[derive(Clone)]
+pub struct DeprecatedFieldCallback(Arc<DeprecatedFieldFn>);
+
+type DeprecatedFieldFn = dyn Fn(&str, &descriptor::FieldDescriptor) + Send + Sync;
+
+/// State that is threaded through the decoding of a message and all of its nested messages.
+struct DecodeContext<'a> {
+    options: &'a DecodeOptions,
+    pool: Option<&'a mut MessagePool>,
+    stats: Option<&'a mut DecodeStats>,
+    depth: u32,
+    // The path of the message being decoded, if deprecated fields are reported
+    path: String,
+    // The fields to decode of the message being decoded, or `None` to decode all of them
+    mask: Option<&'a field_mask::FieldMaskTree>,
+    // The buffer that the input reads from, if the source bytes of messages are retained
+    #[cfg(feature = "bytes")]
+    source: Option<&'a bytes::Bytes>,
+    // The fields that earlier occurrences of the message about to be decoded set, handed back once
+    // it is decoded
+    seen: Option<SeenFields>,
+}
+
+/// The singular fields that occurred on the wire while decoding a message, to detect duplicates
+/// without mistaking the defaults that messages are created with for earlier occurrences.
+///
+/// The fields of nested singular messages are tracked as well, since all the occurrences of such
+/// a message are merged.
+#[derive(Default)]
+struct SeenFields {
+    numbers: collections::BTreeSet<i32>,
+    nested: Vec<(i32, SeenFields)>,
+}
+
+static DEFAULT_DECODE_OPTIONS: DecodeOptions = DecodeOptions {
+    recursion_limit: DEFAULT_RECURSION_LIMIT,
+    unknown_fields: UnknownFieldPolicy::Preserve,
+    unresolved_messages_as_bytes: false,
+    strict: false,
+    typed_repeated_scalars: false,
+    duplicate_fields: DuplicateFieldPolicy::Overwrite,
+    field_codec: None,
+    type_handlers: None,
+    deprecated_fields: DeprecatedFieldPolicy::Ignore,
+};
+
+/// A pool of recycled messages, keyed by message type.
+///
+/// Messages that are handed back to the pool via `recycle` keep their field storage allocated, so
+/// that subsequent decodes of the same message type can reuse it instead of going through the
+/// allocator again.
+#[derive(Debug, Default)]
+pub struct MessagePool {
+    messages: collections::HashMap<String, Vec<Message>>,
+    max_per_type: Option<usize>,
+}
+
+/// A decoder for many messages of the same type, like the records of a batch job.
+///
+/// The message type is looked up once, and messages that are handed back with `recycle` are
+/// pooled, so that decoding further messages reuses their field storage instead of allocating it
+/// anew for every message.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    descriptors: &'a descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    options: DecodeOptions,
+    pool: MessagePool,
+}
+
+/// An iterator over the messages decoded from a sequence of records.
+///
+/// Created by `Decoder::decode_many`.
+#[derive(Debug)]
+pub struct DecodeMany<'a, 'b, I> {
+    decoder: &'b mut Decoder<'a>,
+    records: I,
+}
+
+impl<'a> Default for DecodeContext<'a> {
+    fn default() -> DecodeContext<'a> {
+        DecodeContext {
+            options: &DEFAULT_DECODE_OPTIONS,
+            pool: None,
+            stats: None,
+            depth: 0,
+            path: String::new(),
+            mask: None,
+            #[cfg(feature = "bytes")]
+            source: None,
+            seen: None,
+        }
+    }
+}
+
+impl<'a> DecodeContext<'a> {
+    /// Updates the statistics, if they are being collected.
+    #[inline]
+    fn record<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut DecodeStats),
+    {
+        if let Some(ref mut stats) = self.stats {
+            f(stats);
+        }
+    }
+}
+
+impl SeenFields {
+    /// Records an occurrence of the given field, returning whether it occurred before.
+    fn occurred(&mut self, number: i32) -> bool {
+        !self.numbers.insert(number)
+    }
+
+    /// Takes the fields seen in the earlier occurrences of the given nested message field.
+    fn take_nested(&mut self, number: i32) -> SeenFields {
+        match self.nested.iter().position(|&(n, _)| n == number) {
+            Some(i) => self.nested.swap_remove(i).1,
+            None => SeenFields::default(),
+        }
+    }
 }
 
 impl Message {
     /// Creates a message given a Protobuf descriptor.
     #[inline]
     pub fn new(message: &descriptor::MessageDescriptor) -> Message {
-        let mut m = Message {
-            fields: collections::BTreeMap::new(),
-            unknown: protobuf::UnknownFields::new(),
-        };
+        let mut m = Message::default();
 
         for field in message.fields() {
             m.fields.insert(
@@ -75,228 +545,4545 @@ impl Message {
         m
     }
 
-    /// Merge data from the given input stream into this message.
-    #[inline]
-    pub fn merge_from(
-        &mut self,
+    /// Creates an example message of the specified type with sample values in its fields, for
+    /// documentation, mock servers and form generation.
+    ///
+    /// Scalar fields get their declared default value, or else a sample value of their type:
+    /// `true`, `1`, `1.5`, or the field name as a string or bytes.  Enum fields get the first
+    /// value of their enum, and repeated and map fields a single element.  Only the first field
+    /// of each oneof is set.
+    ///
+    /// Message fields are filled up to `depth` levels of nesting and left absent below that,
+    /// which bounds the size of examples of recursive types.  Groups and fields of unresolved
+    /// types are left absent too.
+    pub fn example(
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
-        input: &mut protobuf::CodedInputStream,
-    ) -> error::Result<()> {
-        while !input.eof()? {
-            let (number, wire_type) = input.read_tag_unpack()?;
+        depth: usize,
+    ) -> Message {
+        use crate::descriptor::FieldType;
 
-            if let Some(field) = message.field_by_number(number as i32) {
-                let value = self.ensure_field(field);
-                value.merge_from(descriptors, field, input, wire_type)?;
-            } else {
-                use protobuf::rt::read_unknown_or_skip_group as u;
-                u(number, wire_type, input, &mut self.unknown)?;
+        let mut m = Message::new(message);
+        let mut oneofs = collections::HashSet::new();
+        for field in message.fields() {
+            if let Some(index) = field.oneof_index() {
+                if !message.is_synthetic_oneof(index) && !oneofs.insert(index) {
+                    continue;
+                }
             }
+            let value = match (field.default_value(), field.field_type(descriptors)) {
+                (Some(v), _) => Some(v.clone()),
+                (None, FieldType::Bool) => Some(Value::Bool(true)),
+                (None, FieldType::Int32)
+                | (None, FieldType::SInt32)
+                | (None, FieldType::SFixed32) => Some(Value::I32(1)),
+                (None, FieldType::Int64)
+                | (None, FieldType::SInt64)
+                | (None, FieldType::SFixed64) => Some(Value::I64(1)),
+                (None, FieldType::UInt32) | (None, FieldType::Fixed32) => Some(Value::U32(1)),
+                (None, FieldType::UInt64) | (None, FieldType::Fixed64) => Some(Value::U64(1)),
+                (None, FieldType::Float) => Some(Value::F32(1.5)),
+                (None, FieldType::Double) => Some(Value::F64(1.5)),
+                (None, FieldType::String) => Some(Value::String(field.name().to_owned())),
+                (None, FieldType::Bytes) => Some(Value::Bytes(field.name().as_bytes().to_vec())),
+                (None, FieldType::Enum(e)) => e.values().first().map(|v| Value::Enum(v.number())),
+                // Map entries don't count as a level of nesting
+                (None, FieldType::Message(entry)) if entry.is_map_entry() => Some(Value::Message(
+                    Arc::new(Message::example(descriptors, entry, depth)),
+                )),
+                (None, FieldType::Message(nested)) if depth > 0 => Some(Value::Message(Arc::new(
+                    Message::example(descriptors, nested, depth - 1),
+                ))),
+                (None, FieldType::Message(_))
+                | (None, FieldType::Group)
+                | (None, FieldType::UnresolvedEnum(_))
+                | (None, FieldType::UnresolvedMessage(_)) => None,
+            };
+            let value = match value {
+                Some(v) if field.is_repeated() => Field::Repeated(iter::once(v).collect()),
+                None if field.is_repeated() => Field::Repeated(Vec::new()),
+                v => Field::Singular(v),
+            };
+            m.fields.insert(field.number(), value);
         }
-        Ok(())
+        m
     }
 
-    #[inline]
-    fn ensure_field(&mut self, field: &descriptor::FieldDescriptor) -> &mut Field {
-        self.fields
-            .entry(field.number())
-            .or_insert_with(|| Field::new(field))
+    /// Converts a value of a type that implements `Serialize` to a message of the specified type.
+    ///
+    /// Struct fields and map keys are matched to message fields by name (or by number, like
+    /// `"1"`), sequences fill repeated fields, maps fill map fields and unit enum variants are
+    /// matched to enum values by name.
+    pub fn from_typed<T>(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        value: &T,
+    ) -> error::Result<Message>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        ser::to_message(descriptors, message, &de::Options::new(), value)
     }
-}
 
-impl Field {
-    /// Creates a field given a Protobuf descriptor.
-    #[inline]
-    pub fn new(field: &descriptor::FieldDescriptor) -> Field {
-        if field.is_repeated() {
-            Field::Repeated(Vec::new())
-        } else {
-            Field::Singular(None)
-        }
+    /// Converts a value of a type that implements `Serialize` to a message of the specified type,
+    /// parsing the scalar representations of the deserialization options, see
+    /// `de::ScalarRepresentation`.
+    ///
+    /// This undoes `to_typed_with_options` with the same options; the other options are ignored.
+    pub fn from_typed_with_options<T>(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &de::Options,
+        value: &T,
+    ) -> error::Result<Message>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        ser::to_message(descriptors, message, options, value)
     }
 
-    /// Merge data from the given input stream into this field.
-    #[inline]
-    pub fn merge_from(
-        &mut self,
+    /// Converts this message to a type that implements `Deserialize`.
+    ///
+    /// The type sees the message in the same shape as when decoding it with `de::Deserializer`.
+    pub fn to_typed<T>(
+        &self,
         descriptors: &descriptor::Descriptors,
-        field: &descriptor::FieldDescriptor,
-        input: &mut protobuf::CodedInputStream,
-        wire_type: protobuf::stream::wire_format::WireType,
-    ) -> error::Result<()> {
-        // Make the type dispatch below more compact
-        use crate::descriptor::FieldType::*;
-        use protobuf::stream::wire_format::WireType::*;
-        use protobuf::CodedInputStream as I;
-
-        // Singular scalar
-        macro_rules! ss {
-            ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
-                self.merge_scalar(input, wire_type, $expected_wire_type, $visit_func, $reader)
-            };
-        }
-
-        // Packable scalar
-        macro_rules! ps {
-            ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
-                self.merge_packable_scalar(
-                    input,
-                    wire_type,
-                    $expected_wire_type,
-                    $visit_func,
-                    $reader,
-                )
-            };
-            ($expected_wire_type:expr, $size:expr, $visit_func:expr, $reader:expr) => {
-                // TODO: use size to pre-allocate buffer space
-                self.merge_packable_scalar(
-                    input,
-                    wire_type,
-                    $expected_wire_type,
-                    $visit_func,
-                    $reader,
-                )
-            };
-        }
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.to_typed_with_options(descriptors, message, &de::Options::new())
+    }
 
-        match field.field_type(descriptors) {
-            Bool => ps!(WireTypeVarint, Value::Bool, I::read_bool),
-            Int32 => ps!(WireTypeVarint, Value::I32, I::read_int32),
-            Int64 => ps!(WireTypeVarint, Value::I64, I::read_int64),
-            SInt32 => ps!(WireTypeVarint, Value::I32, I::read_sint32),
-            SInt64 => ps!(WireTypeVarint, Value::I64, I::read_sint64),
-            UInt32 => ps!(WireTypeVarint, Value::U32, I::read_uint32),
-            UInt64 => ps!(WireTypeVarint, Value::U64, I::read_uint64),
-            Fixed32 => ps!(WireTypeFixed32, 4, Value::U32, I::read_fixed32),
-            Fixed64 => ps!(WireTypeFixed64, 8, Value::U64, I::read_fixed64),
-            SFixed32 => ps!(WireTypeFixed32, 4, Value::I32, I::read_sfixed32),
-            SFixed64 => ps!(WireTypeFixed64, 8, Value::I64, I::read_sfixed64),
-            Float => ps!(WireTypeFixed32, 4, Value::F32, I::read_float),
-            Double => ps!(WireTypeFixed64, 8, Value::F64, I::read_double),
-            Bytes => ss!(WireTypeLengthDelimited, Value::Bytes, I::read_bytes),
-            String => ss!(WireTypeLengthDelimited, Value::String, I::read_string),
-            Enum(_) => self.merge_enum(input, wire_type),
-            Message(ref m) => self.merge_message(input, descriptors, m, wire_type),
-            Group => unimplemented!(),
-            UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
-            UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
-        }
+    /// Converts this message to a type that implements `Deserialize`, using the specified
+    /// deserialization options.
+    pub fn to_typed_with_options<T>(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &de::Options,
+    ) -> error::Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        de::from_message(descriptors, message, options, self.clone())
     }
 
-    #[inline]
-    fn merge_scalar<'a, A, V, R>(
-        &mut self,
-        input: &mut protobuf::CodedInputStream<'a>,
-        actual_wire_type: wire_format::WireType,
-        expected_wire_type: wire_format::WireType,
-        value_ctor: V,
-        reader: R,
-    ) -> error::Result<()>
+    /// Converts this message to a message type generated by rust-protobuf, e.g. to hand it to
+    /// existing typed code, by encoding it and parsing the result.
+    ///
+    /// Fails with a `MessageTypeMismatch` error if the descriptor doesn't have the fully qualified
+    /// name of the generated type.
+    pub fn into_generated<M>(
+        self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<M>
     where
-        V: Fn(A) -> Value,
-        R: Fn(&mut protobuf::CodedInputStream<'a>) -> protobuf::ProtobufResult<A>,
+        M: protobuf::Message,
     {
-        if expected_wire_type == actual_wire_type {
-            self.put(value_ctor(reader(input)?));
-            Ok(())
-        } else {
-            Err(error::Error::BadWireType {
-                wire_type: actual_wire_type,
-            })
-        }
+        check_generated_type::<M>(message)?;
+        let bytes = self.write_to_bytes(descriptors, message)?;
+        Ok(M::parse_from_bytes(&bytes)?)
     }
 
-    #[inline]
-    fn merge_packable_scalar<'a, A, V, R>(
-        &mut self,
-        input: &mut protobuf::CodedInputStream<'a>,
-        actual_wire_type: wire_format::WireType,
-        expected_wire_type: wire_format::WireType,
-        value_ctor: V,
-        reader: R,
-    ) -> error::Result<()>
+    /// Converts a message of a type generated by rust-protobuf to a message of the specified
+    /// type, which must have the fully qualified name of the generated type.
+    pub fn from_generated<M>(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        generated: &M,
+    ) -> error::Result<Message>
     where
-        V: Fn(A) -> Value,
-        R: Fn(&mut protobuf::CodedInputStream<'a>) -> protobuf::ProtobufResult<A>,
+        M: protobuf::Message,
     {
-        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
-            let len = input.read_raw_varint64()?;
+        check_generated_type::<M>(message)?;
+        let bytes = generated.write_to_bytes()?;
+        let mut result = Message::new(message);
+        result.merge_from(
+            descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )?;
+        Ok(result)
+    }
 
-            let old_limit = input.push_limit(len)?;
-            while !input.eof()? {
-                self.put(value_ctor(reader(input)?));
+    /// Decodes the unknown fields of this message and of its nested messages that the message
+    /// types now know about, e.g. after newer descriptors were registered, and moves them into the
+    /// typed fields.  Returns the number of field numbers that were promoted.
+    ///
+    /// The values of a promoted field replace any value that the field already has.  If an unknown
+    /// field can't be decoded as the known field, e.g. because of a wire type mismatch, the error
+    /// is returned and the unknown fields of that message are left as they are.
+    pub fn reinterpret_unknown(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<usize> {
+        let mut promoted = 0;
+
+        let mut known = self
+            .unknown
+            .iter()
+            .map(|(number, _)| number)
+            .filter(|&number| {
+                check_tag_number(number).is_ok()
+                    && decoded_field(descriptors, message, number).is_some()
+            })
+            .collect::<Vec<_>>();
+        if !known.is_empty() {
+            known.sort_unstable();
+            let mut bytes = Vec::new();
+            {
+                let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+                for &number in &known {
+                    for value in self.unknown.get(number).into_iter().flatten() {
+                        output.write_unknown(number, value)?;
+                    }
+                }
+                output.flush()?;
             }
-            input.pop_limit(old_limit);
 
-            Ok(())
-        } else {
-            self.merge_scalar(
-                input,
-                actual_wire_type,
-                expected_wire_type,
-                value_ctor,
-                reader,
-            )
+            let mut decoded = Message::default();
+            decoded.merge_from_impl(
+                descriptors,
+                message,
+                &mut protobuf::CodedInputStream::from_bytes(&bytes),
+                &mut DecodeContext::default(),
+            )?;
+            for number in known {
+                self.unknown.remove(number);
+            }
+            promoted += decoded.fields.len();
+            self.fields.extend(decoded.fields);
         }
+
+        for (&number, field) in self.fields.iter_mut() {
+            let nested = match message.field_by_number(number) {
+                Some(f) => match f.field_type(descriptors) {
+                    descriptor::FieldType::Message(m) => m,
+                    _ => continue,
+                },
+                None => continue,
+            };
+            let values = match *field {
+                Field::Singular(ref mut v) => v.as_mut_slice(),
+                Field::Repeated(ref mut vs) => &mut vs[..],
+                Field::RepeatedTyped(_) => continue,
+            };
+            for value in values {
+                if let Value::Message(ref mut m) = *value {
+                    promoted += Arc::make_mut(m).reinterpret_unknown(descriptors, nested)?;
+                }
+            }
+        }
+        Ok(promoted)
     }
 
+    /// Merge data from the given input stream into this message.
     #[inline]
-    fn merge_enum(
+    pub fn merge_from(
         &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
         input: &mut protobuf::CodedInputStream,
-        actual_wire_type: wire_format::WireType,
     ) -> error::Result<()> {
-        if wire_format::WireType::WireTypeVarint == actual_wire_type {
-            let v = input.read_raw_varint32()? as i32;
-            self.put(Value::Enum(v));
-            Ok(())
-        } else {
-            Err(error::Error::BadWireType {
-                wire_type: actual_wire_type,
-            })
-        }
+        self.merge_from_root(descriptors, message, input, &mut DecodeContext::default())
     }
 
+    /// Merge data from the given input stream into this message, taking any nested messages from
+    /// the specified pool.
     #[inline]
-    fn merge_message(
+    pub fn merge_from_pooled(
         &mut self,
-        input: &mut protobuf::CodedInputStream,
         descriptors: &descriptor::Descriptors,
         message: &descriptor::MessageDescriptor,
-        actual_wire_type: wire_format::WireType,
+        input: &mut protobuf::CodedInputStream,
+        pool: &mut MessagePool,
     ) -> error::Result<()> {
-        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
-            let len = input.read_raw_varint64()?;
-            let mut msg = match *self {
-                Field::Singular(ref mut o) => {
-                    if let Some(Value::Message(m)) = o.take() {
-                        m
-                    } else {
-                        Message::new(message)
-                    }
-                }
-                _ => Message::new(message),
-            };
+        let mut context = DecodeContext {
+            pool: Some(pool),
+            ..DecodeContext::default()
+        };
+        self.merge_from_root(descriptors, message, input, &mut context)
+    }
 
-            let old_limit = input.push_limit(len)?;
-            msg.merge_from(descriptors, message, input)?;
-            input.pop_limit(old_limit);
+    /// Merge data from the given input stream into this message, collecting statistics about the
+    /// decode.
+    pub fn merge_from_with_stats(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<DecodeStats> {
+        let mut stats = DecodeStats::default();
+        let mut context = DecodeContext {
+            stats: Some(&mut stats),
+            ..DecodeContext::default()
+        };
+        self.merge_from_root(descriptors, message, input, &mut context)?;
+        Ok(stats)
+    }
+
+    /// Merge the fields in a compiled field mask from the given input stream into this message.
+    ///
+    /// All other fields, including unknown fields, are skipped on the wire without being decoded.
+    pub fn merge_from_masked(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mask: &field_mask::FieldMaskTree,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        let mut context = DecodeContext {
+            mask: Some(mask).filter(|m| !m.is_empty()),
+            ..DecodeContext::default()
+        };
+        self.merge_from_root(descriptors, message, input, &mut context)
+    }
+
+    /// Merge data from the given input stream into this message using the given options.
+    pub fn merge_from_with_options(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &DecodeOptions,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<()> {
+        self.merge_from_configured(descriptors, message, options, input, None, None)
+    }
+
+    /// Merge data from the given input stream into this message using the given options, only
+    /// decoding the fields in `mask` if it is given, and collect statistics about the decode if
+    /// `stats` is given.
+    pub(crate) fn merge_from_configured(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &DecodeOptions,
+        input: &mut protobuf::CodedInputStream,
+        mask: Option<&field_mask::FieldMaskTree>,
+        stats: Option<&mut DecodeStats>,
+    ) -> error::Result<()> {
+        let mut context = DecodeContext {
+            options,
+            stats,
+            mask: mask.filter(|m| !m.is_empty()),
+            ..DecodeContext::default()
+        };
+        self.merge_from_root(descriptors, message, input, &mut context)
+    }
+
+    /// Returns an estimate of the number of heap bytes held by this message, including its fields,
+    /// nested messages and unknown fields, but not the message value itself.
+    pub fn deep_size_of(&self) -> usize {
+        let fields = self
+            .fields
+            .values()
+            .map(|f| size_of::<(i32, Field)>() + f.deep_size_of())
+            .sum::<usize>();
+
+        let unknown = self
+            .unknown
+            .iter()
+            .map(|(_, v)| {
+                size_of::<(u32, protobuf::UnknownValues)>()
+                    + v.fixed32.capacity() * size_of::<u32>()
+                    + v.fixed64.capacity() * size_of::<u64>()
+                    + v.varint.capacity() * size_of::<u64>()
+                    + v.length_delimited.capacity() * size_of::<Vec<u8>>()
+                    + v.length_delimited.iter().map(Vec::capacity).sum::<usize>()
+            })
+            .sum::<usize>();
+
+        fields + unknown
+    }
+
+    /// Computes the encoded size of this message.
+    ///
+    /// The sizes of this message and all of its nested messages are cached, so that a subsequent
+    /// call to `write_to_with_cached_sizes` doesn't have to compute them again.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<u32> {
+        self.compute_size_with_options(descriptors, message, &EncodeOptions::default())
+    }
+
+    /// Computes the encoded size of this message when encoded with the given options.
+    ///
+    /// The same options must be passed to a subsequent call to `write_to_with_cached_sizes`.
+    pub fn compute_size_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<u32> {
+        let mut sizes = Sizes::default();
+        let size = self.compute_sizes(descriptors, message, options, &mut sizes)?;
+        self.cached_size.set(size, sizes);
+        Ok(size)
+    }
+
+    /// Computes the encoded size of this message, recording the sizes of its nested messages.
+    fn compute_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        sizes: &mut Sizes,
+    ) -> error::Result<u32> {
+        #[cfg(feature = "bytes")]
+        {
+            if let Some(source) = self.pass_through_source(options) {
+                return Ok(source.len() as u32);
+            }
+        }
+
+        let mut size = 0;
+        for (&number, field) in &self.fields {
+            if let Some(f) = message.field_by_number(number) {
+                size += match codec_for(&options.field_codec, descriptors, message, f) {
+                    Some(codec) => field.compute_encoded_size(message, f, codec, options)?,
+                    None => field.compute_sizes(descriptors, f, options, sizes)?,
+                };
+            }
+        }
+        for (number, _) in &self.unknown {
+            check_tag_number(number)?;
+        }
+        size += protobuf::rt::unknown_fields_size(&self.unknown);
+        Ok(size)
+    }
+
+    /// The size of this message as computed by the last call to `compute_size`.
+    #[inline]
+    pub fn cached_size(&self) -> u32 {
+        self.cached_size.size.get()
+    }
+
+    /// Writes this message to the given output stream.
+    #[inline]
+    pub fn write_to(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        self.write_to_with_options(descriptors, message, &EncodeOptions::default(), output)
+    }
+
+    /// Writes this message to the given output stream using the given options.
+    pub fn write_to_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("encode", message = message.name()).entered();
+        self.report_deprecated(
+            descriptors,
+            message,
+            &options.deprecated_fields,
+            &mut String::new(),
+        );
+        let mut sizes = Sizes::default();
+        let _size = self.compute_sizes(descriptors, message, options, &mut sizes)?;
+        self.write_with_sizes(descriptors, message, options, &sizes, output)?;
+        #[cfg(feature = "tracing")]
+        self.trace_encoded(_size);
+        Ok(())
+    }
+
+    /// Writes this message to the given output stream, using the sizes cached by a previous call
+    /// to `compute_size` or `compute_size_with_options`.
+    ///
+    /// The message must not have been modified since the sizes were computed, and the options must
+    /// be the same as the ones that the sizes were computed with.
+    pub fn write_to_with_cached_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        let sizes = self
+            .cached_size
+            .nested
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.write_with_sizes(descriptors, message, options, &sizes, output)
+    }
+
+    /// Writes this message to the given output stream, using the given sizes of its nested
+    /// messages.
+    fn write_with_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        sizes: &Sizes,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        #[cfg(feature = "bytes")]
+        {
+            if let Some(source) = self.pass_through_source(options) {
+                output.write_raw_bytes(source)?;
+                return Ok(());
+            }
+        }
+
+        for (&number, field) in &self.fields {
+            if let Some(f) = message.field_by_number(number) {
+                match codec_for(&options.field_codec, descriptors, message, f) {
+                    Some(codec) => field.write_encoded(message, f, codec, options, output)?,
+                    None => field.write_with_sizes(descriptors, f, options, sizes, output)?,
+                }
+            }
+        }
+        write_unknown_fields(&self.unknown, output)?;
+        Ok(())
+    }
+
+    /// Encodes this message into a new byte vector.
+    #[inline]
+    pub fn write_to_bytes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<Vec<u8>> {
+        self.write_to_bytes_with_options(descriptors, message, &EncodeOptions::default())
+    }
+
+    /// Encodes this message into a new byte vector using the given options.
+    pub fn write_to_bytes_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<Vec<u8>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("encode", message = message.name()).entered();
+        self.report_deprecated(
+            descriptors,
+            message,
+            &options.deprecated_fields,
+            &mut String::new(),
+        );
+        let mut sizes = Sizes::default();
+        let size = self.compute_sizes(descriptors, message, options, &mut sizes)?;
+        let mut bytes = Vec::with_capacity(size as usize);
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            self.write_with_sizes(descriptors, message, options, &sizes, &mut output)?;
+            output.flush()?;
+        }
+        #[cfg(feature = "tracing")]
+        self.trace_encoded(size);
+        Ok(bytes)
+    }
+
+    /// Encodes this message into the given buffer.
+    ///
+    /// The buffer must have enough remaining capacity to hold the whole message, or it must grow on
+    /// demand like `bytes::BytesMut` does.
+    #[cfg(feature = "bytes")]
+    #[inline]
+    pub fn write_to_buf<B>(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        buf: &mut B,
+    ) -> error::Result<()>
+    where
+        B: bytes::BufMut,
+    {
+        self.write_to_buf_with_options(descriptors, message, &EncodeOptions::default(), buf)
+    }
+
+    /// Encodes this message into the given buffer using the given options.
+    ///
+    /// The message is encoded straight into the spare capacity of the buffer if it is large
+    /// enough; otherwise it is encoded into a temporary buffer first and copied.
+    #[cfg(feature = "bytes")]
+    pub fn write_to_buf_with_options<B>(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        buf: &mut B,
+    ) -> error::Result<()>
+    where
+        B: bytes::BufMut,
+    {
+        use std::ptr;
+        use std::slice;
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("encode", message = message.name()).entered();
+        self.report_deprecated(
+            descriptors,
+            message,
+            &options.deprecated_fields,
+            &mut String::new(),
+        );
+        let mut sizes = Sizes::default();
+        let size = self.compute_sizes(descriptors, message, options, &mut sizes)?;
+        let len = size as usize;
+        let chunk = buf.chunk_mut();
+        if chunk.len() >= len {
+            // Zeroed first, since the output stream writes to initialized bytes
+            let dst = unsafe {
+                ptr::write_bytes(chunk.as_mut_ptr(), 0, len);
+                slice::from_raw_parts_mut(chunk.as_mut_ptr(), len)
+            };
+            self.write_with_sizes_to_slice(descriptors, message, options, &sizes, dst)?;
+            unsafe { buf.advance_mut(len) };
+        } else {
+            let mut bytes = vec![0; len];
+            self.write_with_sizes_to_slice(descriptors, message, options, &sizes, &mut bytes)?;
+            buf.put_slice(&bytes);
+        }
+        #[cfg(feature = "tracing")]
+        self.trace_encoded(size);
+        Ok(())
+    }
+
+    /// Encodes this message into a new `bytes::BytesMut` buffer of exactly the right size.
+    #[cfg(feature = "bytes")]
+    #[inline]
+    pub fn write_to_bytes_mut(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<bytes::BytesMut> {
+        self.write_to_bytes_mut_with_options(descriptors, message, &EncodeOptions::default())
+    }
+
+    /// Encodes this message into a new `bytes::BytesMut` buffer of exactly the right size using
+    /// the given options.
+    #[cfg(feature = "bytes")]
+    pub fn write_to_bytes_mut_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<bytes::BytesMut> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("encode", message = message.name()).entered();
+        self.report_deprecated(
+            descriptors,
+            message,
+            &options.deprecated_fields,
+            &mut String::new(),
+        );
+        let mut sizes = Sizes::default();
+        let size = self.compute_sizes(descriptors, message, options, &mut sizes)?;
+        let mut buf = bytes::BytesMut::with_capacity(size as usize);
+        buf.resize(size as usize, 0);
+        self.write_with_sizes_to_slice(descriptors, message, options, &sizes, &mut buf)?;
+        #[cfg(feature = "tracing")]
+        self.trace_encoded(size);
+        Ok(buf)
+    }
+
+    /// Writes this message into a slice of exactly its encoded size, using the given sizes of its
+    /// nested messages.
+    #[cfg(feature = "bytes")]
+    fn write_with_sizes_to_slice(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &EncodeOptions,
+        sizes: &Sizes,
+        dst: &mut [u8],
+    ) -> error::Result<()> {
+        let mut output = protobuf::CodedOutputStream::bytes(dst);
+        self.write_with_sizes(descriptors, message, options, sizes, &mut output)?;
+        output.check_eof();
+        Ok(())
+    }
+
+    /// Merge data from the given buffer into this message.
+    #[cfg(feature = "bytes")]
+    pub fn merge_from_buf<B>(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        buf: B,
+    ) -> error::Result<()>
+    where
+        B: bytes::Buf,
+    {
+        self.merge_from_bufs(descriptors, message, Some(buf))
+    }
+
+    /// Merge data from a sequence of buffers into this message, as if they were concatenated.
+    ///
+    /// This can be used to decode messages that were received in several chunks, e.g. a
+    /// `VecDeque<bytes::Bytes>` of socket reads, without first copying them into one contiguous
+    /// buffer.
+    #[cfg(feature = "bytes")]
+    pub fn merge_from_bufs<I>(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bufs: I,
+    ) -> error::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: bytes::Buf,
+    {
+        let mut reader = BufsReader {
+            current: None,
+            rest: bufs.into_iter(),
+        };
+        let mut input = protobuf::CodedInputStream::new(&mut reader);
+        self.merge_from(descriptors, message, &mut input)
+    }
+
+    /// Merge data from the given buffer into this message using the given options, retaining the
+    /// bytes that this message and its nested messages are decoded from; see `source_bytes`.
+    #[cfg(feature = "bytes")]
+    pub fn merge_from_bytes(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        options: &DecodeOptions,
+        bytes: &bytes::Bytes,
+    ) -> error::Result<()> {
+        // A message that already holds anything is not exactly described by the new bytes
+        let merged = self.source_bytes().is_some() || !self.is_unset(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut context = DecodeContext {
+            options,
+            source: Some(bytes),
+            ..DecodeContext::default()
+        };
+        self.merge_from_root(descriptors, message, &mut input, &mut context)?;
+        if !merged {
+            self.set_source(bytes.clone());
+        }
+        Ok(())
+    }
+
+    /// The exact bytes that this message was decoded from, if it was decoded with
+    /// `merge_from_bytes`.
+    ///
+    /// The bytes are shared with the buffer that was decoded, so retaining them is cheap.  They
+    /// can be used to forward the message or verify a signature over it without re-encoding it.
+    /// Messages that were merged from several occurrences in the input, or into a message that
+    /// already had fields set, don't retain their bytes.  The bytes are forgotten when a field of
+    /// the message is marked as changed, see `mark_changed`.
+    #[cfg(feature = "bytes")]
+    pub fn source_bytes(&self) -> Option<&bytes::Bytes> {
+        self.state.as_ref().and_then(|s| s.source.as_ref())
+    }
+
+    /// The values of singular fields that later occurrences of the same field replaced, by field
+    /// number, in the order they were decoded.
+    ///
+    /// Only collected when decoding with `DuplicateFieldPolicy::Collect`; a non-empty list
+    /// usually indicates a bug in the producer of the message.
+    pub fn duplicates(&self) -> &[(i32, Value)] {
+        match self.state {
+            Some(ref state) => &state.duplicates,
+            None => &[],
+        }
+    }
+
+    /// Forgets the source bytes of this message, so that it is encoded from its fields again.
+    ///
+    /// This must be called after modifying the fields of the message directly, unless the
+    /// modified fields are marked as changed, when encoding with
+    /// `EncodeOptions::pass_through_source_bytes`.  The messages enclosing the modified message
+    /// don't pass their source bytes through either from then on, since those bytes are stale.
+    #[cfg(feature = "bytes")]
+    pub fn clear_source_bytes(&mut self) {
+        let state = self.state.get_or_insert_with(Default::default);
+        state.source = None;
+        state.modified = true;
+    }
+
+    /// Forgets the source bytes of this message if it has any, because its fields are about to be
+    /// merged with more input.
+    #[cfg(feature = "bytes")]
+    fn forget_source(&mut self) {
+        if self.take_source().is_some() {
+            self.clear_source_bytes();
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    fn take_source(&mut self) -> Option<bytes::Bytes> {
+        self.state.as_mut().and_then(|s| s.source.take())
+    }
+
+    #[cfg(feature = "bytes")]
+    fn set_source(&mut self, source: bytes::Bytes) {
+        let state = self.state.get_or_insert_with(Default::default);
+        state.source = Some(source);
+        state.modified = false;
+    }
+
+    /// The source bytes to copy when encoding this message with the given options, if any.
+    #[cfg(feature = "bytes")]
+    fn pass_through_source(&self, options: &EncodeOptions) -> Option<&bytes::Bytes> {
+        if options.pass_through {
+            self.source_bytes().filter(|_| !self.is_modified())
+        } else {
+            None
+        }
+    }
+
+    /// Whether this message or any of its nested messages forgot its source bytes because it was
+    /// modified.
+    #[cfg(feature = "bytes")]
+    fn is_modified(&self) -> bool {
+        matches!(self.state, Some(ref state) if state.modified)
+            || self.fields.values().any(|f| match *f {
+                Field::Singular(Some(Value::Message(ref m))) => m.is_modified(),
+                Field::Repeated(ref vs) => vs.iter().any(|v| match *v {
+                    Value::Message(ref m) => m.is_modified(),
+                    _ => false,
+                }),
+                _ => false,
+            })
+    }
+
+    /// Whether this message holds nothing but what `Message::new` fills in: no unknown fields,
+    /// and no fields set to anything other than their declared default values.
+    #[cfg(feature = "bytes")]
+    fn is_unset(&self, message: &descriptor::MessageDescriptor) -> bool {
+        self.unknown.iter().next().is_none()
+            && self.fields.iter().all(|(&number, field)| match *field {
+                Field::Singular(None) | Field::Singular(Some(Value::Null)) => true,
+                Field::Singular(Some(ref v)) => match message.field_by_number(number) {
+                    Some(f) => f.default_value().is_some() && is_default(f, v),
+                    None => false,
+                },
+                Field::Repeated(_) | Field::RepeatedTyped(_) => field.is_empty(),
+            })
+    }
+
+    /// Records that the field with the specified number was modified.
+    ///
+    /// Modifications made through `cursor::CursorMut` are recorded automatically; other
+    /// modifications of `fields` can be recorded with this method.  With the `bytes` feature,
+    /// this also forgets the source bytes of the message.
+    pub fn mark_changed(&mut self, number: i32) {
+        self.fields_and_changes().1.mark(number);
+    }
+
+    /// The paths of the fields that were marked as changed, in this message and in its nested
+    /// messages, e.g. to send as the update mask of a PATCH request.
+    ///
+    /// Paths lead through singular message fields; a repeated message field with changes in any
+    /// of its elements is reported as a whole.
+    pub fn changed_paths(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> field_mask::FieldMask {
+        let mut mask = field_mask::FieldMask::default();
+        self.collect_changed_paths(descriptors, message, "", &mut mask.paths);
+        mask
+    }
+
+    /// Whether any field of this message or of its nested messages was marked as changed.
+    pub fn has_changes(&self) -> bool {
+        let changed = match self.state {
+            Some(ref state) => !state.changed.is_empty(),
+            None => false,
+        };
+        changed
+            || self.fields.values().any(|f| match *f {
+                Field::Singular(Some(Value::Message(ref m))) => m.has_changes(),
+                Field::Repeated(ref vs) => vs.iter().any(|v| match *v {
+                    Value::Message(ref m) => m.has_changes(),
+                    _ => false,
+                }),
+                _ => false,
+            })
+    }
+
+    /// Forgets the changes recorded in this message and its nested messages.
+    pub fn clear_changes(&mut self) {
+        if let Some(ref mut state) = self.state {
+            state.changed.clear();
+        }
+        for field in self.fields.values_mut() {
+            match *field {
+                // Shared messages without changes are left shared
+                Field::Singular(Some(Value::Message(ref mut m))) if m.has_changes() => {
+                    Arc::make_mut(m).clear_changes()
+                }
+                Field::Repeated(ref mut vs) => {
+                    for v in vs.iter_mut() {
+                        match *v {
+                            Value::Message(ref mut m) if m.has_changes() => {
+                                Arc::make_mut(m).clear_changes()
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Checks that the required fields of this message and of its nested messages are set,
+    /// failing with a `MissingField` error for the first one that isn't.
+    pub fn check_required_fields(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) -> error::Result<()> {
+        self.check_required_fields_at(descriptors, message, &mut String::new())
+    }
+
+    fn check_required_fields_at(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        path: &mut String,
+    ) -> error::Result<()> {
+        for f in message.fields() {
+            let path_len = path.len();
+            push_path(path, f.name());
+            let field = self.fields.get(&f.number());
+            if f.field_label() == descriptor::FieldLabel::Required
+                && field.map_or(0, Field::len) == 0
+            {
+                return Err(error::Error::MissingField { path: path.clone() });
+            }
+            if let (descriptor::FieldType::Message(nested), Some(field)) =
+                (f.field_type(descriptors), field)
+            {
+                for value in field.values().iter() {
+                    if let Value::Message(ref m) = *value {
+                        m.check_required_fields_at(descriptors, nested, path)?;
+                    }
+                }
+            }
+            path.truncate(path_len);
+        }
+        Ok(())
+    }
+
+    /// Whether this message is equal to another message of the same type by the semantics of
+    /// protobuf, rather than by representation.
+    ///
+    /// Unknown fields, and fields that aren't in the descriptor, are ignored.  Repeated fields
+    /// that are empty equal absent ones, and so do singular fields without presence that are set
+    /// to their default value, i.e. plain proto3 fields and the keys and values of map entries.
+    /// Proto2 `optional` fields keep their presence, so one that is set to its default value
+    /// doesn't equal an absent one, unless the schema declares that default: `Message::new` fills
+    /// those in for fields that were never set.  Map fields are compared by their entries regardless of
+    /// order, with later entries replacing earlier ones with the same key.
+    pub fn semantic_eq(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        other: &Message,
+    ) -> bool {
+        let numbers = self
+            .fields
+            .keys()
+            .chain(other.fields.keys().filter(|n| !self.fields.contains_key(n)));
+        for &number in numbers {
+            let field = match message.field_by_number(number) {
+                Some(field) => field,
+                None => continue,
+            };
+            let a = self.fields.get(&number);
+            let b = other.fields.get(&number);
+            if !fields_semantic_eq(descriptors, message, field, a, b) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Removes fields from this message until its encoded size is at most `max_size` bytes, e.g.
+    /// to fit a transport limit, and reports what was removed.
+    ///
+    /// Only the top-level fields are shrunk with `TruncationStrategy::LargestFirst`; unknown
+    /// fields are never removed.  Removed fields are marked as changed.  If the message can't be
+    /// made small enough, a `MessageTooLarge` error is returned, and the message is left with all
+    /// the fields that the strategy allows to remove removed.
+    pub fn truncate_to(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        max_size: u32,
+        strategy: &TruncationStrategy,
+    ) -> error::Result<TruncationReport> {
+        let mut report = TruncationReport::default();
+        let mut size = self.compute_size(descriptors, message)?;
+
+        match *strategy {
+            TruncationStrategy::LargestFirst => {
+                let mut sizes = collections::BTreeMap::new();
+                for (&number, field) in &self.fields {
+                    if let Some(f) = message.field_by_number(number) {
+                        sizes.insert(number, field.compute_size(descriptors, f)?);
+                    }
+                }
+                while size > max_size {
+                    // The largest field, preferring the last one of equal size
+                    let number = match sizes.iter().filter(|e| *e.1 > 0).max_by_key(|e| *e.1) {
+                        Some((&number, _)) => number,
+                        None => break,
+                    };
+                    let f = message
+                        .field_by_number(number)
+                        .expect("field of known size");
+                    let field = self.fields.get_mut(&number).expect("field of known size");
+                    match *field {
+                        Field::Repeated(_) | Field::RepeatedTyped(_) if field.len() > 1 => {
+                            field.truncate(field.len() - 1);
+                            match report.trimmed.iter_mut().find(|t| t.0 == f.name()) {
+                                Some(t) => t.1 += 1,
+                                None => report.trimmed.push((f.name().to_owned(), 1)),
+                            }
+                        }
+                        _ => {
+                            *field = Field::new(f);
+                            report.cleared.push(f.name().to_owned());
+                        }
+                    }
+                    let old = sizes[&number];
+                    let new = field.compute_size(descriptors, f)?;
+                    sizes.insert(number, new);
+                    size = size - old + new;
+                    self.mark_changed(number);
+                }
+            }
+            TruncationStrategy::Priority(ref mask) => {
+                mask.validate(descriptors, message)?;
+                for path in &mask.paths {
+                    if size <= max_size {
+                        break;
+                    }
+                    if self.clear_path(descriptors, message, path) {
+                        report.cleared.push(path.clone());
+                        size = self.compute_size(descriptors, message)?;
+                    }
+                }
+            }
+        }
+
+        if size > max_size {
+            return Err(error::Error::MessageTooLarge {
+                size,
+                limit: max_size,
+            });
+        }
+        report.size = size;
+        Ok(report)
+    }
+
+    /// Splits this message into messages whose encoded size is at most `max_size` bytes each, by
+    /// distributing the elements of the repeated field named `field` among them, e.g. to publish
+    /// a large batch to a broker that limits the size of messages.
+    ///
+    /// Every message shares the other fields of this message, and the elements keep their order.
+    /// A message that already fits is returned as is; otherwise the split field is marked as
+    /// changed in every message.  If the other fields, or the other fields with a single element,
+    /// don't fit, a `MessageTooLarge` error is returned.
+    pub fn split_by_field(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        field: &str,
+        max_size: u32,
+    ) -> error::Result<Vec<Message>> {
+        let f = repeated_field_by_name(message, field)?;
+        if self.compute_size(descriptors, message)? <= max_size {
+            return Ok(vec![self.clone()]);
+        }
+
+        let mut base = self.clone();
+        let values = match base.fields.insert(f.number(), Field::new(f)) {
+            Some(Field::Repeated(vs)) => vs,
+            Some(Field::RepeatedTyped(vs)) => vs.iter().collect(),
+            _ => Vec::new(),
+        };
+        base.mark_changed(f.number());
+        let base_size = base.compute_size(descriptors, message)?;
+        if base_size > max_size {
+            return Err(error::Error::MessageTooLarge {
+                size: base_size,
+                limit: max_size,
+            });
+        }
+        let split = |values| {
+            let mut message = base.clone();
+            message.fields.insert(f.number(), Field::Repeated(values));
+            message
+        };
+
+        let tag_size = protobuf::rt::tag_size(check_tag_number(f.number() as u32)?);
+        let mut messages = Vec::new();
+        let mut chunk = Vec::new();
+        let mut size = base_size;
+        for v in values {
+            let value_size = tag_size + v.compute_size(descriptors, f)?;
+            if !chunk.is_empty() && size + value_size > max_size {
+                messages.push(split(mem::take(&mut chunk)));
+                size = base_size;
+            }
+            if size + value_size > max_size {
+                return Err(error::Error::MessageTooLarge {
+                    size: size + value_size,
+                    limit: max_size,
+                });
+            }
+            size += value_size;
+            chunk.push(v);
+        }
+        messages.push(split(chunk));
+        Ok(messages)
+    }
+
+    /// Joins messages that were split with `split_by_field` back into one message, by
+    /// concatenating the elements of their repeated field named `field` in order.
+    ///
+    /// The other fields are taken from the first message, and the joined field is marked as
+    /// changed if there is more than one message.  Joining no messages gives an empty message.
+    pub fn join_by_field<I>(
+        message: &descriptor::MessageDescriptor,
+        field: &str,
+        messages: I,
+    ) -> error::Result<Message>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        let f = repeated_field_by_name(message, field)?;
+        let mut messages = messages.into_iter();
+        let mut joined = match messages.next() {
+            Some(first) => first,
+            None => return Ok(Message::default()),
+        };
+        for mut next in messages {
+            let values = joined
+                .fields
+                .entry(f.number())
+                .or_insert_with(|| Field::new(f));
+            if let Some(mut rest) = next.fields.remove(&f.number()) {
+                values.make_untyped();
+                rest.make_untyped();
+                if let (Field::Repeated(vs), Field::Repeated(rest)) = (values, rest) {
+                    vs.extend(rest);
+                }
+            }
+            joined.mark_changed(f.number());
+        }
+        Ok(joined)
+    }
+
+    /// Removes the fields that an older version of the message type doesn't know about, in this
+    /// message and in its nested messages, so that the message can be sent to consumers of the
+    /// older version.  Returns the paths of the removed fields, like `order.discount`.
+    ///
+    /// Fields are matched by number.  With `RemovedFieldPolicy::Unknown`, removed fields are
+    /// encoded into the unknown fields of their message, so that consumers that preserve unknown
+    /// fields still pass them on.  Removed fields are marked as changed.
+    pub fn project_onto(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        old_descriptors: &descriptor::Descriptors,
+        old_message: &descriptor::MessageDescriptor,
+        policy: RemovedFieldPolicy,
+    ) -> error::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        self.project_onto_impl(
+            descriptors,
+            message,
+            old_descriptors,
+            old_message,
+            policy,
+            &mut String::new(),
+            &mut removed,
+        )?;
+        Ok(removed)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn project_onto_impl(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        old_descriptors: &descriptor::Descriptors,
+        old_message: &descriptor::MessageDescriptor,
+        policy: RemovedFieldPolicy,
+        path: &mut String,
+        removed: &mut Vec<String>,
+    ) -> error::Result<()> {
+        let numbers = self.fields.keys().cloned().collect::<Vec<_>>();
+        for number in numbers {
+            let field = match message.field_by_number(number) {
+                Some(field) => field,
+                None => continue,
+            };
+            let path_len = path.len();
+            push_path(path, field.name());
+
+            match old_message.field_by_number(number) {
+                Some(old_field) => {
+                    let types = (
+                        field.field_type(descriptors),
+                        old_field.field_type(old_descriptors),
+                    );
+                    if let (
+                        descriptor::FieldType::Message(nested),
+                        descriptor::FieldType::Message(old_nested),
+                    ) = types
+                    {
+                        #[cfg(feature = "bytes")]
+                        let before = removed.len();
+                        let values = match self.fields.get_mut(&number) {
+                            Some(Field::Singular(v)) => v.as_mut_slice(),
+                            Some(Field::Repeated(vs)) => &mut vs[..],
+                            Some(Field::RepeatedTyped(_)) | None => &mut [],
+                        };
+                        for value in values {
+                            if let Value::Message(ref mut m) = *value {
+                                Arc::make_mut(m).project_onto_impl(
+                                    descriptors,
+                                    nested,
+                                    old_descriptors,
+                                    old_nested,
+                                    policy,
+                                    path,
+                                    removed,
+                                )?;
+                            }
+                        }
+                        // The source bytes still hold the removed fields of nested messages
+                        #[cfg(feature = "bytes")]
+                        {
+                            if removed.len() > before {
+                                self.clear_source_bytes();
+                            }
+                        }
+                    }
+                }
+                None => {
+                    let value = match self.fields.get_mut(&number) {
+                        Some(value) => mem::replace(value, Field::new(field)),
+                        None => continue,
+                    };
+                    if !value.is_empty() {
+                        if policy == RemovedFieldPolicy::Unknown {
+                            self.add_unknown(descriptors, message, field, value)?;
+                        }
+                        removed.push(path.clone());
+                        self.mark_changed(number);
+                    }
+                }
+            }
+            path.truncate(path_len);
+        }
+        Ok(())
+    }
+
+    /// Encodes a field into the unknown fields of this message.
+    fn add_unknown(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        value: Field,
+    ) -> error::Result<()> {
+        let mut single = Message::default();
+        single.fields.insert(field.number(), value);
+        let bytes = single.write_to_bytes(descriptors, message)?;
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        while !input.eof()? {
+            let (number, wire_type) = read_tag(&mut input, false)?;
+            self.merge_unknown(number, wire_type, &mut input, &UnknownFieldPolicy::Preserve)?;
+        }
+        Ok(())
+    }
+
+    /// Clears the fields of this message that aren't in a compiled field mask, along with the
+    /// unknown fields, as if the message had been decoded with `merge_from_masked`.
+    ///
+    /// Elements of repeated and map fields that aren't masked are removed, so the indices of the
+    /// remaining elements may change.  This isn't recorded as a change of the message.
+    pub fn retain_masked(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mask: &field_mask::FieldMaskTree,
+    ) {
+        if mask.is_empty() {
+            return;
+        }
+        for (&number, value) in self.fields.iter_mut() {
+            match (mask.field(number), message.field_by_number(number)) {
+                (Some(nested), Some(field)) => value.retain_masked(descriptors, field, nested),
+                _ => match *value {
+                    Field::Singular(ref mut v) => *v = None,
+                    Field::Repeated(ref mut vs) => vs.clear(),
+                    Field::RepeatedTyped(ref mut vs) => vs.clear(),
+                },
+            }
+        }
+        self.unknown = protobuf::UnknownFields::new();
+        #[cfg(feature = "bytes")]
+        self.clear_source_bytes();
+    }
+
+    /// Copies the parts of this message that are in a compiled field mask, like `retain_masked`
+    /// on a clone of the message, but without copying the parts that the mask leaves out.
+    pub fn masked_copy(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mask: &field_mask::FieldMaskTree,
+    ) -> Message {
+        if mask.is_empty() {
+            return self.clone();
+        }
+        let mut copy = Message::default();
+        for (&number, value) in &self.fields {
+            let value = match (mask.field(number), message.field_by_number(number)) {
+                (Some(nested), Some(field)) => value.masked_copy(descriptors, field, nested),
+                _ => match *value {
+                    Field::Singular(_) => Field::Singular(None),
+                    Field::Repeated(_) | Field::RepeatedTyped(_) => Field::Repeated(Vec::new()),
+                },
+            };
+            copy.fields.insert(number, value);
+        }
+        copy
+    }
+
+    /// Applies an update to this message following the update semantics of AIP-134, e.g. for
+    /// the update mask of a PATCH request.
+    ///
+    /// The field at each path of the mask is replaced with the field of the update, or cleared if
+    /// the update doesn't set it or holds `Value::Null`.  The single path `*` replaces the whole
+    /// message, and an empty mask stands for the fields that the update sets or holds `Null`.
+    /// Setting a member of a oneof clears its other members, and replaced fields are recorded as
+    /// changes of the message.
+    ///
+    /// Fails with a `BadFieldMaskPath` error if a path doesn't refer to a field of the message
+    /// type, refers to an element of a repeated or map field, or if `*` isn't the only path.
+    pub fn apply_update(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        update: &Message,
+        mask: &field_mask::FieldMask,
+    ) -> error::Result<()> {
+        let implied;
+        let paths = match &mask.paths[..] {
+            [] => {
+                implied = message
+                    .fields()
+                    .iter()
+                    .filter(|f| {
+                        matches!(update.fields.get(&f.number()), Some(v) if !v.is_empty() || v.is_null())
+                    })
+                    .map(|f| f.name().to_owned())
+                    .collect::<Vec<_>>();
+                &implied[..]
+            }
+            [star] if star == "*" => {
+                let numbers = self.fields.keys().chain(update.fields.keys()).cloned();
+                for number in numbers.collect::<collections::BTreeSet<_>>() {
+                    if let Some(field) = message.field_by_number(number) {
+                        self.update_field(field, update.fields.get(&number));
+                    }
+                }
+                self.unknown = update.unknown.clone();
+                return Ok(());
+            }
+            paths => paths,
+        };
+
+        for path in paths {
+            let bad_path = || error::Error::BadFieldMaskPath { path: path.clone() };
+            let segments = field_mask::parse_path(descriptors, message, path)
+                .map_err(|_| bad_path())?
+                .into_iter()
+                .map(|segment| match segment {
+                    field_mask::Segment::Field(field) => Ok(field),
+                    field_mask::Segment::Element(_) => Err(bad_path()),
+                })
+                .collect::<error::Result<Vec<_>>>()?;
+            self.update_path(descriptors, message, &segments, Some(update));
+        }
+        Ok(())
+    }
+
+    /// Replaces the field at a path of fields with the field of an update, if any.
+    fn update_path(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        path: &[&descriptor::FieldDescriptor],
+        update: Option<&Message>,
+    ) {
+        let (field, rest) = match path.split_first() {
+            Some((&field, rest)) => (field, rest),
+            None => return,
+        };
+        let value = update.and_then(|u| u.fields.get(&field.number()));
+        if rest.is_empty() {
+            if matches!(value, Some(v) if !v.is_empty()) {
+                self.clear_oneof_siblings(message, field);
+            }
+            self.update_field(field, value);
+            return;
+        }
+
+        // The path continues in a singular message field, see `field_mask::parse_path`
+        let nested_update = match value {
+            Some(Field::Singular(Some(Value::Message(m)))) => Some(&**m),
+            _ => None,
+        };
+        let nested_type = match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) => m,
+            _ => return,
+        };
+        if nested_update.is_some() {
+            self.clear_oneof_siblings(message, field);
+        }
+        match self.fields.get_mut(&field.number()) {
+            Some(Field::Singular(Some(Value::Message(nested)))) => {
+                Arc::make_mut(nested).update_path(descriptors, nested_type, rest, nested_update)
+            }
+            // Nothing to clear in a missing message
+            _ if nested_update.is_none() => (),
+            _ => {
+                let mut nested = Message::new(nested_type);
+                nested.update_path(descriptors, nested_type, rest, nested_update);
+                self.fields.insert(
+                    field.number(),
+                    Field::Singular(Some(Value::Message(Arc::new(nested)))),
+                );
+                self.mark_changed(field.number());
+            }
+        }
+    }
+
+    /// Clears the other members of the oneof that a field which is about to be set belongs to.
+    fn clear_oneof_siblings(
+        &mut self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) {
+        let index = match field.oneof_index() {
+            Some(index) => index,
+            None => return,
+        };
+        for other in message.fields() {
+            let number = other.number();
+            if other.oneof_index() == Some(index)
+                && number != field.number()
+                && matches!(self.fields.get(&number), Some(f) if !f.is_empty())
+            {
+                self.update_field(other, None);
+            }
+        }
+    }
+
+    /// Replaces a field with the field of an update, clearing it if the update doesn't set it.
+    fn update_field(&mut self, field: &descriptor::FieldDescriptor, value: Option<&Field>) {
+        let value = match value {
+            Some(value) if !value.is_empty() => value.clone(),
+            _ => Field::new(field),
+        };
+        self.fields.insert(field.number(), value);
+        self.mark_changed(field.number());
+    }
+
+    /// Clears the field or element at a valid field mask path, returning whether it had any
+    /// value.
+    fn clear_path(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        path: &str,
+    ) -> bool {
+        match field_mask::parse_path(descriptors, message, path) {
+            Ok(segments) => self.clear_segments(descriptors, &segments),
+            Err(_) => false,
+        }
+    }
+
+    fn clear_segments(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        segments: &[field_mask::Segment],
+    ) -> bool {
+        use crate::field_mask::Segment;
+
+        let (field, rest) = match *segments {
+            [Segment::Field(field), ref rest @ ..] => (field, rest),
+            _ => return false,
+        };
+        let value = match self.fields.get_mut(&field.number()) {
+            Some(value) => value,
+            None => return false,
+        };
+        let field_type = field.field_type(descriptors);
+        if let [Segment::Element(_), ..] = rest {
+            value.make_untyped();
+        }
+        let (cleared, changed) = match (value, rest) {
+            (value, []) if !value.is_empty() => {
+                *value = Field::new(field);
+                (true, true)
+            }
+            (Field::Repeated(values), [Segment::Element(key), rest @ ..]) => {
+                let entry = match field_type {
+                    descriptor::FieldType::Message(m) if m.is_map_entry() => Some(m),
+                    _ => None,
+                };
+                let index = match (entry, key) {
+                    (Some(entry), field_mask::ElementKey::Key(key)) => values
+                        .iter()
+                        .position(|v| map_key_text(descriptors, entry, v) == *key),
+                    (None, field_mask::ElementKey::Index(index)) => {
+                        Some(*index).filter(|&i| i < values.len())
+                    }
+                    _ => None,
+                };
+                match (index, rest, entry) {
+                    (None, _, _) => (false, false),
+                    (Some(index), [], _) => {
+                        values.remove(index);
+                        (true, true)
+                    }
+                    // The rest of the path continues in the map value
+                    (Some(index), rest, Some(_)) => match values[index] {
+                        Value::Message(ref mut m) => match Arc::make_mut(m).fields.get_mut(&2) {
+                            Some(Field::Singular(Some(Value::Message(nested)))) => (
+                                Arc::make_mut(nested).clear_segments(descriptors, rest),
+                                false,
+                            ),
+                            _ => (false, false),
+                        },
+                        _ => (false, false),
+                    },
+                    (Some(index), rest, None) => match values[index] {
+                        Value::Message(ref mut nested) => (
+                            Arc::make_mut(nested).clear_segments(descriptors, rest),
+                            false,
+                        ),
+                        _ => (false, false),
+                    },
+                }
+            }
+            (Field::Singular(Some(Value::Message(nested))), rest) if !rest.is_empty() => (
+                Arc::make_mut(nested).clear_segments(descriptors, rest),
+                false,
+            ),
+            _ => (false, false),
+        };
+        if changed {
+            self.mark_changed(field.number());
+        }
+        cleared
+    }
+
+    /// Borrows the fields of this message along with a recorder for changes to them.
+    pub(crate) fn fields_and_changes(
+        &mut self,
+    ) -> (&mut collections::BTreeMap<i32, Field>, ChangeRecorder<'_>) {
+        (
+            &mut self.fields,
+            ChangeRecorder {
+                state: &mut self.state,
+            },
+        )
+    }
+
+    fn is_marked_changed(&self, number: i32) -> bool {
+        match self.state {
+            Some(ref state) => state.changed.contains(&number),
+            None => false,
+        }
+    }
+
+    fn collect_changed_paths(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        prefix: &str,
+        paths: &mut Vec<String>,
+    ) {
+        for (&number, field) in &self.fields {
+            let f = match message.field_by_number(number) {
+                Some(f) => f,
+                None => continue,
+            };
+            let path = format!("{}{}", prefix, f.name());
+            if self.is_marked_changed(number) {
+                paths.push(path);
+                continue;
+            }
+            let nested = match f.field_type(descriptors) {
+                descriptor::FieldType::Message(m) => m,
+                _ => continue,
+            };
+            match *field {
+                Field::Singular(Some(Value::Message(ref m))) => {
+                    m.collect_changed_paths(descriptors, nested, &format!("{}.", path), paths)
+                }
+                Field::Repeated(ref vs) => {
+                    let changed = vs.iter().any(|v| match *v {
+                        Value::Message(ref m) => m.has_changes(),
+                        _ => false,
+                    });
+                    if changed {
+                        paths.push(path);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Merges a top-level message, reporting it to `tracing` when that feature is enabled.
+    #[inline]
+    fn merge_from_root(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        context: &mut DecodeContext,
+    ) -> error::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("decode", message = message.name()).entered();
+        let start = input.pos();
+        #[cfg(feature = "bytes")]
+        self.forget_source();
+
+        let result = self.merge_from_impl(descriptors, message, input, context);
+
+        context.record(|s| s.bytes_read += input.pos() - start);
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(()) => tracing::debug!(
+                bytes = input.pos() - start,
+                fields = self.present_field_count(),
+                unknown_fields = unknown_field_count(&self.unknown),
+                "decoded message"
+            ),
+            Err(ref error) => tracing::debug!(
+                bytes = input.pos() - start,
+                %error,
+                "failed to decode message"
+            ),
+        }
+        result
+    }
+
+    /// Reports the deprecated fields that are set in this message and its nested messages.
+    fn report_deprecated(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        policy: &DeprecatedFieldPolicy,
+        path: &mut String,
+    ) {
+        if policy.is_ignore() {
+            return;
+        }
+        for (&number, field) in &self.fields {
+            let f = match message.field_by_number(number) {
+                Some(f) => f,
+                None => continue,
+            };
+            let values = field.values();
+            let path_len = path.len();
+            push_path(path, f.name());
+            if f.is_deprecated() && !values.is_empty() {
+                policy.report(message, path, f);
+            }
+            if let descriptor::FieldType::Message(nested) = f.field_type(descriptors) {
+                for value in values.iter() {
+                    if let Value::Message(ref m) = *value {
+                        m.report_deprecated(descriptors, nested, policy, path);
+                    }
+                }
+            }
+            path.truncate(path_len);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn trace_encoded(&self, size: u32) {
+        tracing::debug!(
+            bytes = size,
+            fields = self.present_field_count(),
+            unknown_fields = unknown_field_count(&self.unknown),
+            "encoded message"
+        );
+    }
+
+    /// The number of fields that hold a value, or at least one value if repeated.
+    #[cfg(feature = "tracing")]
+    fn present_field_count(&self) -> usize {
+        self.fields
+            .values()
+            .filter(|f| match f {
+                Field::Singular(v) => v.is_some(),
+                Field::Repeated(vs) => !vs.is_empty(),
+                Field::RepeatedTyped(vs) => !vs.is_empty(),
+            })
+            .count()
+    }
+
+    #[inline]
+    fn merge_from_impl(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        context: &mut DecodeContext,
+    ) -> error::Result<()> {
+        context.depth += 1;
+        let depth = context.depth;
+        context.record(|s| s.max_depth = cmp::max(s.max_depth, depth));
+        if depth > context.options.recursion_limit {
+            return Err(error::Error::RecursionLimitExceeded {
+                limit: context.options.recursion_limit,
+                position: input.pos(),
+            });
+        }
+        let report_deprecated = !context.options.deprecated_fields.is_ignore();
+        let mut deprecated = Vec::new();
+        let mask = context.mask;
+        // The masked fields whose elements are selected once they are decoded
+        let mut element_masks = Vec::new();
+        let mut seen = context.seen.take().unwrap_or_default();
+
+        while !input.eof()? {
+            let position = input.pos();
+            let truncated = |e: error::Error| e.truncated_at(position);
+            let (number, wire_type) = read_tag(input, context.options.strict).map_err(truncated)?;
+
+            // Fields outside of the mask are skipped before looking up their descriptor
+            if let Some(mask) = mask {
+                match mask.field_by_tag_number(number) {
+                    Some(nested) if !nested.elements().is_empty() => {
+                        if !element_masks.iter().any(|&(n, _)| n == number) {
+                            element_masks.push((number, nested));
+                        }
+                        context.mask = None;
+                    }
+                    Some(nested) => context.mask = Some(nested).filter(|m| !m.is_empty()),
+                    None => {
+                        self.merge_unknown(number, wire_type, input, &UnknownFieldPolicy::Drop)
+                            .map_err(truncated)?;
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(field) = decoded_field(descriptors, message, number) {
+                context.record(|s| s.fields_decoded += 1);
+                let policy = context.options.duplicate_fields;
+                let value = self.ensure_field(field);
+                if context.options.typed_repeated_scalars {
+                    value.make_typed(descriptors, field);
+                }
+                let mut nested_seen = false;
+                let replaced = match *value {
+                    Field::Singular(ref mut v) if policy != DuplicateFieldPolicy::Overwrite => {
+                        if is_message(descriptors, field) {
+                            context.seen = Some(seen.take_nested(field.number()));
+                            nested_seen = true;
+                            None
+                        } else if seen.occurred(field.number()) {
+                            if policy == DuplicateFieldPolicy::Error {
+                                return Err(error::Error::DuplicateField {
+                                    name: field.name().to_owned(),
+                                });
+                            }
+                            v.take()
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                let options = context.options;
+                let path_len = context.path.len();
+                if report_deprecated {
+                    if field.is_deprecated() && !deprecated.contains(&field.number()) {
+                        deprecated.push(field.number());
+                    }
+                    push_path(&mut context.path, field.name());
+                }
+                match codec_for(&options.field_codec, descriptors, message, field) {
+                    Some(codec) => value.put(
+                        decode_content(
+                            descriptors,
+                            message,
+                            field,
+                            codec,
+                            input,
+                            wire_type,
+                            options.strict,
+                        )
+                        .map_err(truncated)?,
+                    ),
+                    None => value
+                        .merge_from_impl(descriptors, field, input, wire_type, context)
+                        .map_err(truncated)?,
+                }
+                context.path.truncate(path_len);
+                context.mask = mask;
+                match context.seen.take() {
+                    Some(nested) if nested_seen => seen.nested.push((field.number(), nested)),
+                    _ => (),
+                }
+                if let Some(replaced) = replaced {
+                    let state = self.state.get_or_insert_with(Default::default);
+                    state.duplicates.push((field.number(), replaced));
+                }
+            } else {
+                context.record(|s| s.unknown_fields += 1);
+                self.merge_unknown(number, wire_type, input, &context.options.unknown_fields)
+                    .map_err(truncated)?;
+            }
+
+            // Guards against looping forever should a field ever be decoded without reading input
+            if input.pos() == position {
+                return Err(error::Error::NoProgress { position });
+            }
+        }
+
+        for (number, mask) in element_masks {
+            if let (Some(field), Some(value)) = (
+                message.field_by_number(number as i32),
+                self.fields.get_mut(&(number as i32)),
+            ) {
+                value.retain_masked(descriptors, field, mask);
+            }
+        }
+
+        for number in deprecated {
+            if let Some(field) = message.field_by_number(number) {
+                let path_len = context.path.len();
+                push_path(&mut context.path, field.name());
+                context
+                    .options
+                    .deprecated_fields
+                    .report(message, &context.path, field);
+                context.path.truncate(path_len);
+            }
+        }
+        context.depth -= 1;
+        context.seen = Some(seen);
+        Ok(())
+    }
+
+    #[inline]
+    fn merge_unknown(
+        &mut self,
+        number: u32,
+        wire_type: wire_format::WireType,
+        input: &mut protobuf::CodedInputStream,
+        policy: &UnknownFieldPolicy,
+    ) -> error::Result<()> {
+        use protobuf::rt::read_unknown_or_skip_group as u;
+
+        match *policy {
+            UnknownFieldPolicy::Preserve => u(number, wire_type, input, &mut self.unknown)?,
+            UnknownFieldPolicy::Drop => u(
+                number,
+                wire_type,
+                input,
+                &mut protobuf::UnknownFields::new(),
+            )?,
+            UnknownFieldPolicy::Error => return Err(error::Error::UnknownFieldNumber { number }),
+            UnknownFieldPolicy::Callback(UnknownFieldCallback(ref callback)) => {
+                if wire_type == wire_format::WireType::WireTypeStartGroup {
+                    return Ok(u(number, wire_type, input, &mut self.unknown)?);
+                }
+                let value = input.read_unknown(wire_type)?;
+                match value {
+                    protobuf::UnknownValue::Fixed32(v) => {
+                        callback(number, wire_type, &v.to_le_bytes())
+                    }
+                    protobuf::UnknownValue::Fixed64(v) => {
+                        callback(number, wire_type, &v.to_le_bytes())
+                    }
+                    protobuf::UnknownValue::Varint(v) => {
+                        let mut bytes = Vec::with_capacity(10);
+                        {
+                            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+                            output.write_raw_varint64(v)?;
+                            output.flush()?;
+                        }
+                        callback(number, wire_type, &bytes)
+                    }
+                    protobuf::UnknownValue::LengthDelimited(ref v) => {
+                        callback(number, wire_type, v)
+                    }
+                }
+                self.unknown.add_value(number, value);
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn ensure_field(&mut self, field: &descriptor::FieldDescriptor) -> &mut Field {
+        self.fields
+            .entry(field.number())
+            .or_insert_with(|| Field::new(field))
+    }
+}
+
+impl Field {
+    /// Creates a field given a Protobuf descriptor.
+    #[inline]
+    pub fn new(field: &descriptor::FieldDescriptor) -> Field {
+        if field.is_repeated() {
+            Field::Repeated(Vec::new())
+        } else {
+            Field::Singular(None)
+        }
+    }
+
+    /// Returns an estimate of the number of heap bytes held by this field, not including the field
+    /// value itself.
+    pub fn deep_size_of(&self) -> usize {
+        match *self {
+            Field::Singular(None) => 0,
+            Field::Singular(Some(ref v)) => v.deep_size_of(),
+            Field::Repeated(ref vs) => {
+                repeated_heap_size(vs) + vs.iter().map(Value::deep_size_of).sum::<usize>()
+            }
+            Field::RepeatedTyped(ref vs) => vs.deep_size_of(),
+        }
+    }
+
+    /// The values of this field: none or one for a singular field, and the elements of a
+    /// repeated field.
+    ///
+    /// Only typed storage is converted to values, so that code that handles values can deal
+    /// with both kinds of repeated fields.
+    pub fn values(&self) -> borrow::Cow<'_, [Value]> {
+        match *self {
+            Field::Singular(ref v) => borrow::Cow::Borrowed(v.as_slice()),
+            Field::Repeated(ref vs) => borrow::Cow::Borrowed(&vs[..]),
+            Field::RepeatedTyped(ref vs) => borrow::Cow::Owned(vs.iter().collect()),
+        }
+    }
+
+    /// Converts typed storage to `Field::Repeated`; other fields are left as they are.
+    pub fn make_untyped(&mut self) {
+        if let Field::RepeatedTyped(ref vs) = *self {
+            *self = Field::Repeated(vs.iter().collect());
+        }
+    }
+
+    /// Converts the values of a repeated bool or numeric field to typed storage, unless they
+    /// don't match the field type.
+    pub fn make_typed(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) {
+        if let Field::Repeated(ref mut vs) = *self {
+            let mut typed = match TypedValues::for_field(descriptors, field) {
+                Some(typed) => typed,
+                None => return,
+            };
+            typed.reserve(vs.len());
+            for v in vs.iter() {
+                if typed.push(v.clone()).is_err() {
+                    return;
+                }
+            }
+            *self = Field::RepeatedTyped(typed);
+        }
+    }
+
+    /// Appends a value to this repeated field, checking that it matches the field type.
+    pub fn push(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        value: Value,
+    ) -> error::Result<()> {
+        let len = self.len();
+        self.insert(descriptors, field, len, value)
+    }
+
+    /// Inserts a value into this repeated field at the specified index, checking that it matches
+    /// the field type.
+    pub fn insert(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        index: usize,
+        value: Value,
+    ) -> error::Result<()> {
+        check_value(descriptors, field, &value)?;
+        self.make_untyped();
+        match *self {
+            Field::Repeated(ref mut vs) if index <= vs.len() => {
+                vs.insert(index, value);
+                Ok(())
+            }
+            Field::Repeated(_) | Field::RepeatedTyped(_) => Err(error::Error::IndexOutOfBounds {
+                field: field.name().to_owned(),
+                index,
+            }),
+            Field::Singular(_) => Err(bad_field_value(field)),
+        }
+    }
+
+    /// Removes and returns the value at the specified index of this repeated field, if there is
+    /// one.
+    pub fn remove(&mut self, index: usize) -> Option<Value> {
+        self.make_untyped();
+        match *self {
+            Field::Repeated(ref mut vs) if index < vs.len() => Some(vs.remove(index)),
+            _ => None,
+        }
+    }
+
+    /// Shortens this repeated field to at most `len` values.
+    pub fn truncate(&mut self, len: usize) {
+        match *self {
+            Field::Repeated(ref mut vs) => vs.truncate(len),
+            Field::RepeatedTyped(ref mut vs) => vs.truncate(len),
+            Field::Singular(_) => (),
+        }
+    }
+
+    /// Keeps only the parts of this field that are in the tree of a masked field.
+    fn retain_masked(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        mask: &field_mask::FieldMaskTree,
+    ) {
+        if mask.is_empty() {
+            return;
+        }
+        let field_type = field.field_type(descriptors);
+        let values = match *self {
+            Field::Singular(Some(Value::Message(ref mut m))) => {
+                if let descriptor::FieldType::Message(message) = field_type {
+                    Arc::make_mut(m).retain_masked(descriptors, message, mask);
+                }
+                return;
+            }
+            Field::Singular(_) => return,
+            Field::Repeated(ref mut vs) => vs,
+            Field::RepeatedTyped(ref mut vs) => {
+                let mut index = 0;
+                vs.retain(|_| {
+                    let key = field_mask::ElementKey::Index(index);
+                    index += 1;
+                    mask.elements().iter().any(|e| e.0 == key)
+                });
+                return;
+            }
+        };
+
+        let element_type = match field_type {
+            descriptor::FieldType::Message(m) => Some(m),
+            _ => None,
+        };
+        let entry = element_type.filter(|m| m.is_map_entry());
+        let mut retained = Vec::new();
+        for (index, mut value) in mem::take(values).into_iter().enumerate() {
+            let key = match entry {
+                Some(entry) => {
+                    field_mask::ElementKey::Key(map_key_text(descriptors, entry, &value))
+                }
+                None => field_mask::ElementKey::Index(index),
+            };
+            let nested = match mask.elements().iter().find(|e| e.0 == key) {
+                Some(element) => &element.1,
+                None => continue,
+            };
+            // The masked subfields of map entries are those of their values
+            match (&mut value, entry, element_type) {
+                (Value::Message(m), Some(entry), _) => {
+                    let m = Arc::make_mut(m);
+                    if let (Some(v), Some(f)) = (m.fields.get_mut(&2), entry.field_by_number(2)) {
+                        v.retain_masked(descriptors, f, nested);
+                    }
+                }
+                (Value::Message(m), None, Some(message)) => {
+                    Arc::make_mut(m).retain_masked(descriptors, message, nested)
+                }
+                _ => (),
+            }
+            retained.push(value);
+        }
+        *values = retained;
+    }
+
+    /// Copies the parts of this field that are in the tree of a masked field.
+    fn masked_copy(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        mask: &field_mask::FieldMaskTree,
+    ) -> Field {
+        if mask.is_empty() {
+            return self.clone();
+        }
+        let field_type = field.field_type(descriptors);
+        let masked = |index| {
+            let key = field_mask::ElementKey::Index(index);
+            mask.elements().iter().any(|e| e.0 == key)
+        };
+        let values = match *self {
+            Field::Singular(Some(Value::Message(ref m))) => {
+                return match field_type {
+                    descriptor::FieldType::Message(message) => Field::Singular(Some(
+                        Value::Message(Arc::new(m.masked_copy(descriptors, message, mask))),
+                    )),
+                    _ => self.clone(),
+                };
+            }
+            Field::Singular(_) => return self.clone(),
+            Field::Repeated(ref vs) => vs,
+            Field::RepeatedTyped(ref vs) => {
+                return Field::Repeated(
+                    vs.iter()
+                        .enumerate()
+                        .filter(|&(index, _)| masked(index))
+                        .map(|(_, v)| v)
+                        .collect(),
+                );
+            }
+        };
+
+        let element_type = match field_type {
+            descriptor::FieldType::Message(m) => Some(m),
+            _ => None,
+        };
+        let entry = element_type.filter(|m| m.is_map_entry());
+        let mut copied = Vec::new();
+        for (index, value) in values.iter().enumerate() {
+            let key = match entry {
+                Some(entry) => field_mask::ElementKey::Key(map_key_text(descriptors, entry, value)),
+                None => field_mask::ElementKey::Index(index),
+            };
+            let nested = match mask.elements().iter().find(|e| e.0 == key) {
+                Some(element) => &element.1,
+                None => continue,
+            };
+            // The masked subfields of map entries are those of their values
+            let value = match (value, entry, element_type) {
+                (Value::Message(m), Some(entry), _) => {
+                    let mut copy = Message::default();
+                    for (&number, f) in &m.fields {
+                        let f = match entry.field_by_number(number) {
+                            Some(value_field) if number == 2 => {
+                                f.masked_copy(descriptors, value_field, nested)
+                            }
+                            _ => f.clone(),
+                        };
+                        copy.fields.insert(number, f);
+                    }
+                    Value::Message(Arc::new(copy))
+                }
+                (Value::Message(m), None, Some(message)) => {
+                    Value::Message(Arc::new(m.masked_copy(descriptors, message, nested)))
+                }
+                _ => value.clone(),
+            };
+            copied.push(value);
+        }
+        Field::Repeated(copied)
+    }
+
+    /// Keeps only the values of this repeated field for which the predicate returns `true`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Value) -> bool,
+    {
+        match *self {
+            Field::Repeated(ref mut vs) => vs.retain(|v| f(v)),
+            Field::RepeatedTyped(ref mut vs) => vs.retain(f),
+            Field::Singular(_) => (),
+        }
+    }
+
+    /// Sorts the values of this repeated field by the key that the function extracts.
+    ///
+    /// The sort is stable, so values with equal keys keep their order.
+    pub fn sort_by_key<K, F>(&mut self, f: F)
+    where
+        K: Ord,
+        F: FnMut(&Value) -> K,
+    {
+        self.make_untyped();
+        if let Field::Repeated(ref mut vs) = *self {
+            vs.sort_by_key(f);
+        }
+    }
+
+    /// Sorts the messages of this repeated message field by the value of their field named
+    /// `key`, which must be a singular field of a scalar, string, bytes or enum type.
+    ///
+    /// Messages without the key field sort as if it were set to its default value.  The sort is
+    /// stable, so messages with equal keys keep their order.
+    pub fn sort_by_field(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        key: &str,
+    ) -> error::Result<()> {
+        let key = key_field(descriptors, field, key)?;
+        let default = de::zero_value(descriptors, key);
+        if let Field::Repeated(ref mut vs) = *self {
+            vs.sort_by(|a, b| {
+                compare_keys(
+                    message_key(a, key, default.as_ref()),
+                    message_key(b, key, default.as_ref()),
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Removes the messages of this repeated message field whose field named `key` is equal to
+    /// that of a later message, like later map entries replace earlier ones with the same key.
+    ///
+    /// The key field is treated like in `sort_by_field`, and the remaining messages keep their
+    /// order.
+    pub fn dedup_by_field(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        key: &str,
+    ) -> error::Result<()> {
+        let key = key_field(descriptors, field, key)?;
+        let default = de::zero_value(descriptors, key);
+        if let Field::Repeated(ref mut vs) = *self {
+            let keep = {
+                let mut keys = vs
+                    .iter()
+                    .map(|v| message_key(v, key, default.as_ref()))
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                keys.sort_by(|a, b| compare_keys(a.1, b.1));
+                let mut keep = vec![false; keys.len()];
+                for (i, &(index, k)) in keys.iter().enumerate() {
+                    let last = match keys.get(i + 1) {
+                        Some(next) => compare_keys(k, next.1) != cmp::Ordering::Equal,
+                        None => true,
+                    };
+                    keep[index] = last;
+                }
+                keep
+            };
+            let mut keep = keep.into_iter();
+            vs.retain(|_| keep.next().unwrap_or(true));
+        }
+        Ok(())
+    }
+
+    /// The number of values of this field: the number of elements of a repeated field, or zero
+    /// or one for a singular field.  An explicitly cleared singular field has no values.
+    pub fn len(&self) -> usize {
+        match *self {
+            Field::Singular(Some(Value::Null)) => 0,
+            Field::Singular(ref v) => v.iter().count(),
+            Field::Repeated(ref vs) => vs.len(),
+            Field::RepeatedTyped(ref vs) => vs.len(),
+        }
+    }
+
+    /// Whether this field has no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether this singular field was explicitly cleared, i.e. holds `Value::Null`, rather than
+    /// being absent or set.
+    pub fn is_null(&self) -> bool {
+        matches!(*self, Field::Singular(Some(Value::Null)))
+    }
+
+    /// Computes the encoded size of this field, including the tags of its values.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> error::Result<u32> {
+        self.compute_size_with_options(descriptors, field, &EncodeOptions::default())
+    }
+
+    /// Computes the encoded size of this field when encoded with the given options.
+    #[inline]
+    pub fn compute_size_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<u32> {
+        self.compute_sizes(descriptors, field, options, &mut Sizes::default())
+    }
+
+    fn compute_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+        sizes: &mut Sizes,
+    ) -> error::Result<u32> {
+        // Fields without values are never written, whatever their number
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let tag_size = protobuf::rt::tag_size(check_tag_number(field.number() as u32)?);
+        match *self {
+            Field::Singular(None) => Ok(0),
+            Field::Singular(Some(ref v)) if options.skips(field, v) => Ok(0),
+            Field::Singular(Some(ref v)) => {
+                Ok(tag_size + v.compute_sizes(descriptors, field, options, sizes)?)
+            }
+            Field::Repeated(_) | Field::RepeatedTyped(_) if field.is_packed() => {
+                let size = self.packed_size(descriptors, field, options)?;
+                Ok(tag_size + length_delimited_size(size))
+            }
+            Field::Repeated(ref vs) => {
+                let mut size = 0;
+                for v in vs.iter() {
+                    size += tag_size + v.compute_sizes(descriptors, field, options, sizes)?;
+                }
+                Ok(size)
+            }
+            Field::RepeatedTyped(ref vs) => {
+                let mut size = 0;
+                for v in vs.iter() {
+                    size += tag_size + v.compute_sizes(descriptors, field, options, sizes)?;
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    fn write_with_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+        sizes: &Sizes,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        match *self {
+            Field::Singular(None) => {}
+            Field::Singular(Some(ref v)) if options.skips(field, v) => {}
+            Field::Singular(Some(ref v)) => {
+                v.write_with_sizes(descriptors, field, options, sizes, output)?
+            }
+            Field::Repeated(ref vs) if options.canonical && is_map(descriptors, field) => {
+                let mut entries: Vec<&Value> = vs.iter().collect();
+                entries.sort_by(|a, b| compare_map_keys(a, b));
+                for v in entries {
+                    v.write_with_sizes(descriptors, field, options, sizes, output)?;
+                }
+            }
+            Field::Repeated(_) | Field::RepeatedTyped(_) if field.is_packed() => {
+                if self.is_empty() {
+                    return Ok(());
+                }
+                let n = check_tag_number(field.number() as u32)?;
+                output.write_tag(n, wire_format::WireType::WireTypeLengthDelimited)?;
+                output.write_raw_varint32(self.packed_size(descriptors, field, options)?)?;
+                if fixed_width(descriptors, field).is_some() {
+                    return self.write_packed_fixed(descriptors, field, output);
+                }
+                match *self {
+                    Field::Repeated(ref vs) => {
+                        for v in vs.iter() {
+                            v.write_packed_element(descriptors, field, output)?;
+                        }
+                    }
+                    Field::RepeatedTyped(ref vs) => {
+                        for v in vs.iter() {
+                            v.write_packed_element(descriptors, field, output)?;
+                        }
+                    }
+                    Field::Singular(_) => (),
+                }
+            }
+            Field::Repeated(ref vs) => {
+                for v in vs.iter() {
+                    v.write_with_sizes(descriptors, field, options, sizes, output)?;
+                }
+            }
+            Field::RepeatedTyped(ref vs) => {
+                for v in vs.iter() {
+                    v.write_with_sizes(descriptors, field, options, sizes, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The size of the packed run of the values of this repeated field, without its tag and
+    /// length prefix.
+    fn packed_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<u32> {
+        if let Some(width) = fixed_width(descriptors, field) {
+            return Ok(self.len() as u32 * width);
+        }
+        let mut size = 0;
+        match *self {
+            Field::Repeated(ref vs) => {
+                for v in vs.iter() {
+                    size += v.compute_size_with_options(descriptors, field, options)?;
+                }
+            }
+            Field::RepeatedTyped(ref vs) => {
+                for v in vs.iter() {
+                    size += v.compute_size_with_options(descriptors, field, options)?;
+                }
+            }
+            Field::Singular(_) => (),
+        }
+        Ok(size)
+    }
+
+    /// Writes the values of this packed fixed-width field as a single run of little-endian bytes.
+    ///
+    /// On little-endian targets, typed storage already holds the run as it is written.
+    fn write_packed_fixed(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        #[cfg(target_endian = "little")]
+        {
+            if let Field::RepeatedTyped(ref vs) = *self {
+                let bytes = match (field.field_type(descriptors), vs) {
+                    (Fixed32, TypedValues::U32(vs)) => le_bytes(vs),
+                    (SFixed32, TypedValues::I32(vs)) => le_bytes(vs),
+                    (Float, TypedValues::F32(vs)) => le_bytes(vs),
+                    (Fixed64, TypedValues::U64(vs)) => le_bytes(vs),
+                    (SFixed64, TypedValues::I64(vs)) => le_bytes(vs),
+                    (Double, TypedValues::F64(vs)) => le_bytes(vs),
+                    _ => return Err(bad_field_value(field)),
+                };
+                output.write_raw_bytes(bytes)?;
+                return Ok(());
+            }
+        }
+
+        let field_type = field.field_type(descriptors);
+        let width = fixed_width(descriptors, field).unwrap_or(0);
+        let mut bytes = Vec::with_capacity(self.len() * width as usize);
+        for v in self.values().iter() {
+            match (&field_type, v) {
+                (Fixed32, &Value::U32(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                (SFixed32, &Value::I32(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                (Float, &Value::F32(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                (Fixed64, &Value::U64(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                (SFixed64, &Value::I64(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                (Double, &Value::F64(v)) => bytes.extend_from_slice(&v.to_le_bytes()),
+                _ => return Err(bad_field_value(field)),
+            }
+        }
+        output.write_raw_bytes(&bytes)?;
+        Ok(())
+    }
+
+    /// The values of this field that are written, in order.
+    fn written_values(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+    ) -> Vec<&Value> {
+        match *self {
+            Field::Singular(Some(ref v)) if !options.skips(field, v) => vec![v],
+            Field::Singular(_) => Vec::new(),
+            Field::Repeated(ref vs) => vs.iter().collect(),
+            // Codecs only transform bytes and string fields, which aren't typed
+            Field::RepeatedTyped(_) => Vec::new(),
+        }
+    }
+
+    /// Computes the encoded size of this field when its content is transformed by a codec.
+    fn compute_encoded_size(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        codec: &dyn FieldCodec,
+        options: &EncodeOptions,
+    ) -> error::Result<u32> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let tag_size = protobuf::rt::tag_size(check_tag_number(field.number() as u32)?);
+        let mut size = 0;
+        for v in self.written_values(field, options) {
+            let encoded = codec.encode(message, field, content(field, v)?)?;
+            size += tag_size + length_delimited_size(encoded.len() as u32);
+        }
+        Ok(size)
+    }
+
+    fn write_encoded(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+        codec: &dyn FieldCodec,
+        options: &EncodeOptions,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        for v in self.written_values(field, options) {
+            let encoded = codec.encode(message, field, content(field, v)?)?;
+            output.write_bytes(check_tag_number(field.number() as u32)?, &encoded)?;
+        }
+        Ok(())
+    }
+
+    /// Merge data from the given input stream into this field.
+    #[inline]
+    pub fn merge_from(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: protobuf::stream::wire_format::WireType,
+    ) -> error::Result<()> {
+        self.merge_from_impl(
+            descriptors,
+            field,
+            input,
+            wire_type,
+            &mut DecodeContext::default(),
+        )
+    }
+
+    #[inline]
+    fn merge_from_impl(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        context: &mut DecodeContext,
+    ) -> error::Result<()> {
+        // Make the type dispatch below more compact
+        use crate::descriptor::FieldType::*;
+        use protobuf::stream::wire_format::WireType::*;
+        use protobuf::CodedInputStream as I;
+
+        let strict = context.options.strict;
+
+        // Singular length-delimited scalar
+        macro_rules! ss {
+            ($visit_func:expr, $reader:expr) => {
+                self.merge_scalar(
+                    input,
+                    wire_type,
+                    WireTypeLengthDelimited,
+                    $visit_func,
+                    |i| $reader(i, strict),
+                )
+            };
+        }
+
+        // Packable varint scalar, converted from the raw varint
+        macro_rules! pv {
+            ($visit_func:expr, $convert:expr) => {
+                pv!(VARINT_ESTIMATED_SIZE, $visit_func, $convert)
+            };
+            ($size:expr, $visit_func:expr, $convert:expr) => {
+                self.merge_packable_varint(input, wire_type, $size, strict, $visit_func, $convert)
+            };
+        }
+
+        // Packable fixed-width scalar
+        macro_rules! pf {
+            ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
+                self.merge_packable_fixed(
+                    input,
+                    wire_type,
+                    $expected_wire_type,
+                    strict,
+                    $visit_func,
+                    |i| Ok($reader(i)?),
+                )
+            };
+        }
+
+        match field.field_type(descriptors) {
+            Bool => pv!(1, Value::Bool, |v| v != 0),
+            Int32 => pv!(Value::I32, |v| v as i32),
+            Int64 => pv!(Value::I64, |v| v as i64),
+            SInt32 => pv!(Value::I32, |v| decode_zig_zag_32(v as u32)),
+            SInt64 => pv!(Value::I64, decode_zig_zag_64),
+            UInt32 => pv!(Value::U32, |v| v as u32),
+            UInt64 => pv!(Value::U64, |v| v),
+            Fixed32 => pf!(WireTypeFixed32, Value::U32, I::read_fixed32),
+            Fixed64 => pf!(WireTypeFixed64, Value::U64, I::read_fixed64),
+            SFixed32 => pf!(WireTypeFixed32, Value::I32, I::read_sfixed32),
+            SFixed64 => pf!(WireTypeFixed64, Value::I64, I::read_sfixed64),
+            Float => pf!(WireTypeFixed32, Value::F32, I::read_float),
+            Double => pf!(WireTypeFixed64, Value::F64, I::read_double),
+            Bytes => {
+                context.record(|s| s.allocations += 1);
+                ss!(Value::Bytes, read_bytes)
+            }
+            String => {
+                context.record(|s| s.allocations += 1);
+                ss!(Value::String, read_string)
+            }
+            Enum(_) => pv!(Value::Enum, |v| v as i32),
+            Message(ref m) => self.merge_message(input, descriptors, m, wire_type, context),
+            // Group fields are decoded like unknown fields instead; see `decoded_field`
+            Group => Err(bad_field_value(field)),
+            UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            UnresolvedMessage(_) if context.options.unresolved_messages_as_bytes => {
+                context.record(|s| s.allocations += 1);
+                self.merge_unresolved_message(input, wire_type, strict)
+            }
+            UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
+        }
+    }
+
+    #[inline]
+    fn merge_scalar<'a, A, V, R>(
+        &mut self,
+        input: &mut protobuf::CodedInputStream<'a>,
+        actual_wire_type: wire_format::WireType,
+        expected_wire_type: wire_format::WireType,
+        value_ctor: V,
+        reader: R,
+    ) -> error::Result<()>
+    where
+        V: Fn(A) -> Value,
+        R: Fn(&mut protobuf::CodedInputStream<'a>) -> error::Result<A>,
+    {
+        if expected_wire_type == actual_wire_type {
+            self.put(value_ctor(reader(input)?));
+            Ok(())
+        } else {
+            Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+                position: input.pos(),
+            })
+        }
+    }
+
+    /// The elements of packed runs are converted and pushed straight into the storage of the
+    /// field, instead of going through `put` once per element.
+    #[inline]
+    fn merge_packable_varint<'a, A, V, C>(
+        &mut self,
+        input: &mut protobuf::CodedInputStream<'a>,
+        actual_wire_type: wire_format::WireType,
+        estimated_size: u64,
+        strict: bool,
+        value_ctor: V,
+        convert: C,
+    ) -> error::Result<()>
+    where
+        V: Fn(A) -> Value,
+        C: Fn(u64) -> A,
+    {
+        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            let len = read_length(input, strict)?;
+            self.reserve(input, len, estimated_size);
+
+            match *self {
+                Field::Singular(ref mut s) => {
+                    read_packed_varints(input, len, strict, |v| *s = Some(value_ctor(convert(v))))
+                }
+                Field::Repeated(ref mut r) => {
+                    read_packed_varints(input, len, strict, |v| r.push(value_ctor(convert(v))))
+                }
+                Field::RepeatedTyped(ref mut t) => {
+                    let mut rejected = Vec::new();
+                    read_packed_varints(input, len, strict, |v| {
+                        if let Err(v) = t.push(value_ctor(convert(v))) {
+                            rejected.push(v);
+                        }
+                    })?;
+                    self.put_rejected(rejected);
+                    Ok(())
+                }
+            }
+        } else {
+            self.merge_scalar(
+                input,
+                actual_wire_type,
+                wire_format::WireType::WireTypeVarint,
+                value_ctor,
+                |i| read_varint(i, strict).map(&convert),
+            )
+        }
+    }
+
+    /// Like `merge_packable_varint`, but for fixed-width types whose packed representation can be
+    /// read in bulk: the whole packed run is copied out of the input in large chunks and then
+    /// decoded from little-endian bytes.
+    #[inline]
+    fn merge_packable_fixed<'a, A, V, R>(
+        &mut self,
+        input: &mut protobuf::CodedInputStream<'a>,
+        actual_wire_type: wire_format::WireType,
+        expected_wire_type: wire_format::WireType,
+        strict: bool,
+        value_ctor: V,
+        reader: R,
+    ) -> error::Result<()>
+    where
+        A: FixedWidth,
+        V: Fn(A) -> Value,
+        R: Fn(&mut protobuf::CodedInputStream<'a>) -> error::Result<A>,
+    {
+        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            let size = size_of::<A>();
+            let len = read_length(input, strict)?;
+            if len % size as u64 != 0 {
+                return Err(protobuf::ProtobufError::WireError(
+                    protobuf::error::WireError::UnexpectedEof,
+                )
+                .into());
+            }
+            self.reserve(input, len, size as u64);
+
+            match *self {
+                Field::Singular(ref mut s) => read_packed_fixed(input, len, size, |b| {
+                    *s = Some(value_ctor(A::from_le_bytes(b)))
+                }),
+                Field::Repeated(ref mut r) => read_packed_fixed(input, len, size, |b| {
+                    r.push(value_ctor(A::from_le_bytes(b)))
+                }),
+                Field::RepeatedTyped(ref mut t) => {
+                    let mut rejected = Vec::new();
+                    read_packed_fixed(input, len, size, |b| {
+                        if let Err(v) = t.push(value_ctor(A::from_le_bytes(b))) {
+                            rejected.push(v);
+                        }
+                    })?;
+                    self.put_rejected(rejected);
+                    Ok(())
+                }
+            }
+        } else {
+            self.merge_scalar(
+                input,
+                actual_wire_type,
+                expected_wire_type,
+                value_ctor,
+                reader,
+            )
+        }
+    }
+
+    /// Reads an encoded message of an unknown type as bytes.  Like messages, multiple occurrences
+    /// of a singular field are merged, which for encoded messages means concatenating them.
+    fn merge_unresolved_message(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        actual_wire_type: wire_format::WireType,
+        strict: bool,
+    ) -> error::Result<()> {
+        if wire_format::WireType::WireTypeLengthDelimited != actual_wire_type {
+            return Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+                position: input.pos(),
+            });
+        }
+        let bytes = read_bytes(input, strict)?;
+        match *self {
+            Field::Singular(Some(Value::Bytes(ref mut existing))) => existing.extend(bytes),
+            _ => self.put(Value::Bytes(bytes)),
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn merge_message(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        actual_wire_type: wire_format::WireType,
+        context: &mut DecodeContext,
+    ) -> error::Result<()> {
+        if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type
+            && message.name() == field_mask::FIELD_MASK
+        {
+            let len = read_length(input, context.options.strict)?;
+            let mut mask = match *self {
+                Field::Singular(ref mut o) => match o.take() {
+                    Some(Value::FieldMask(m)) => m,
+                    _ => field_mask::FieldMask::default(),
+                },
+                _ => field_mask::FieldMask::default(),
+            };
+
+            let old_limit = input.push_limit(len)?;
+            mask.merge_from(input)?;
+            input.pop_limit(old_limit);
+
+            self.put(Value::FieldMask(mask));
+            Ok(())
+        } else if wire_format::WireType::WireTypeLengthDelimited == actual_wire_type {
+            let len = read_length(input, context.options.strict)?;
+            let existing = match *self {
+                Field::Singular(ref mut o) => match o.take() {
+                    Some(Value::Message(m)) => Some(m),
+                    _ => None,
+                },
+                _ => None,
+            };
+            #[cfg(feature = "bytes")]
+            let (merged, start) = (existing.is_some(), input.pos() as usize);
+            let mut msg = match (existing, context.pool.as_mut()) {
+                (Some(m), _) => m,
+                (None, Some(p)) => Arc::new(p.get(message)),
+                (None, None) => {
+                    context.record(|s| s.allocations += 1);
+                    Arc::new(Message::new(message))
+                }
+            };
+
+            let old_limit = input.push_limit(len)?;
+            Arc::make_mut(&mut msg).merge_from_impl(descriptors, message, input, context)?;
+            input.pop_limit(old_limit);
+
+            #[cfg(feature = "bytes")]
+            {
+                match context.source {
+                    Some(source) if !merged => Arc::make_mut(&mut msg)
+                        .set_source(source.slice(start..start + len as usize)),
+                    _ => {
+                        Arc::make_mut(&mut msg).take_source();
+                    }
+                }
+            }
+            let handler = context
+                .options
+                .type_handlers
+                .as_ref()
+                .and_then(|h| h.get(message.name()));
+            match handler {
+                Some(handler) => self.put(Value::Custom(handler.decode(
+                    descriptors,
+                    message,
+                    Arc::unwrap_or_clone(msg),
+                )?)),
+                None => self.put(Value::Message(msg)),
+            }
+            Ok(())
+        } else {
+            Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+                position: input.pos(),
+            })
+        }
+    }
+
+    /// Makes room in a repeated field for the values of a packed run of `len` bytes, with values
+    /// of `value_size` bytes on average.
+    ///
+    /// The length prefix is untrusted, so the amount is bounded by the bytes that remain in the
+    /// input before its limit, and capped.
+    #[inline]
+    fn reserve(&mut self, input: &protobuf::CodedInputStream, len: u64, value_size: u64) {
+        let len = cmp::min(len, input.bytes_until_limit());
+        let additional = cmp::min(len / value_size, MAX_RESERVED_VALUES) as usize;
+        match *self {
+            Field::Repeated(ref mut r) => r.reserve(additional),
+            Field::RepeatedTyped(ref mut r) => r.reserve(additional),
+            Field::Singular(_) => (),
+        }
+    }
+
+    #[inline]
+    fn put(&mut self, value: Value) {
+        match *self {
+            Field::Singular(ref mut s) => *s = Some(value),
+            Field::Repeated(ref mut r) => r.push(value),
+            Field::RepeatedTyped(ref mut t) => {
+                if let Err(value) = t.push(value) {
+                    self.put_rejected(vec![value]);
+                }
+            }
+        }
+    }
+
+    /// Appends values that typed storage didn't accept, after converting it to `Repeated`.
+    fn put_rejected(&mut self, rejected: Vec<Value>) {
+        if rejected.is_empty() {
+            return;
+        }
+        self.make_untyped();
+        if let Field::Repeated(ref mut r) = *self {
+            r.extend(rejected);
+        }
+    }
+}
+
+impl TypedValues {
+    /// Creates empty typed storage for a repeated bool or numeric field.
+    pub fn for_field(
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> Option<TypedValues> {
+        use crate::descriptor::FieldType as T;
+
+        if !field.is_repeated() {
+            return None;
+        }
+        Some(match field.field_type(descriptors) {
+            T::Bool => TypedValues::Bool(Vec::new()),
+            T::Int32 | T::SInt32 | T::SFixed32 => TypedValues::I32(Vec::new()),
+            T::Int64 | T::SInt64 | T::SFixed64 => TypedValues::I64(Vec::new()),
+            T::UInt32 | T::Fixed32 => TypedValues::U32(Vec::new()),
+            T::UInt64 | T::Fixed64 => TypedValues::U64(Vec::new()),
+            T::Float => TypedValues::F32(Vec::new()),
+            T::Double => TypedValues::F64(Vec::new()),
+            _ => return None,
+        })
+    }
+
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        match *self {
+            TypedValues::Bool(ref vs) => vs.len(),
+            TypedValues::I32(ref vs) => vs.len(),
+            TypedValues::I64(ref vs) => vs.len(),
+            TypedValues::U32(ref vs) => vs.len(),
+            TypedValues::U64(ref vs) => vs.len(),
+            TypedValues::F32(ref vs) => vs.len(),
+            TypedValues::F64(ref vs) => vs.len(),
+        }
+    }
+
+    /// Whether there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value at the specified index, if there is one.
+    pub fn get(&self, index: usize) -> Option<Value> {
+        match *self {
+            TypedValues::Bool(ref vs) => vs.get(index).map(|&v| Value::Bool(v)),
+            TypedValues::I32(ref vs) => vs.get(index).map(|&v| Value::I32(v)),
+            TypedValues::I64(ref vs) => vs.get(index).map(|&v| Value::I64(v)),
+            TypedValues::U32(ref vs) => vs.get(index).map(|&v| Value::U32(v)),
+            TypedValues::U64(ref vs) => vs.get(index).map(|&v| Value::U64(v)),
+            TypedValues::F32(ref vs) => vs.get(index).map(|&v| Value::F32(v)),
+            TypedValues::F64(ref vs) => vs.get(index).map(|&v| Value::F64(v)),
+        }
+    }
+
+    /// Iterates over the values, converted to `Value`s.
+    pub fn iter(&self) -> impl Iterator<Item = Value> + '_ {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+
+    /// Appends a value, or gives it back if it doesn't match the type of the values.
+    pub fn push(&mut self, value: Value) -> Result<(), Value> {
+        match (self, value) {
+            (TypedValues::Bool(vs), Value::Bool(v)) => vs.push(v),
+            (TypedValues::I32(vs), Value::I32(v)) => vs.push(v),
+            (TypedValues::I64(vs), Value::I64(v)) => vs.push(v),
+            (TypedValues::U32(vs), Value::U32(v)) => vs.push(v),
+            (TypedValues::U64(vs), Value::U64(v)) => vs.push(v),
+            (TypedValues::F32(vs), Value::F32(v)) => vs.push(v),
+            (TypedValues::F64(vs), Value::F64(v)) => vs.push(v),
+            (_, value) => return Err(value),
+        }
+        Ok(())
+    }
+
+    /// Keeps only the values for which the predicate returns `true`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Value) -> bool,
+    {
+        match *self {
+            TypedValues::Bool(ref mut vs) => vs.retain(|&v| f(&Value::Bool(v))),
+            TypedValues::I32(ref mut vs) => vs.retain(|&v| f(&Value::I32(v))),
+            TypedValues::I64(ref mut vs) => vs.retain(|&v| f(&Value::I64(v))),
+            TypedValues::U32(ref mut vs) => vs.retain(|&v| f(&Value::U32(v))),
+            TypedValues::U64(ref mut vs) => vs.retain(|&v| f(&Value::U64(v))),
+            TypedValues::F32(ref mut vs) => vs.retain(|&v| f(&Value::F32(v))),
+            TypedValues::F64(ref mut vs) => vs.retain(|&v| f(&Value::F64(v))),
+        }
+    }
+
+    /// Shortens the values to at most `len` values.
+    pub fn truncate(&mut self, len: usize) {
+        match *self {
+            TypedValues::Bool(ref mut vs) => vs.truncate(len),
+            TypedValues::I32(ref mut vs) => vs.truncate(len),
+            TypedValues::I64(ref mut vs) => vs.truncate(len),
+            TypedValues::U32(ref mut vs) => vs.truncate(len),
+            TypedValues::U64(ref mut vs) => vs.truncate(len),
+            TypedValues::F32(ref mut vs) => vs.truncate(len),
+            TypedValues::F64(ref mut vs) => vs.truncate(len),
+        }
+    }
+
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        match *self {
+            TypedValues::Bool(ref mut vs) => vs.reserve(additional),
+            TypedValues::I32(ref mut vs) => vs.reserve(additional),
+            TypedValues::I64(ref mut vs) => vs.reserve(additional),
+            TypedValues::U32(ref mut vs) => vs.reserve(additional),
+            TypedValues::U64(ref mut vs) => vs.reserve(additional),
+            TypedValues::F32(ref mut vs) => vs.reserve(additional),
+            TypedValues::F64(ref mut vs) => vs.reserve(additional),
+        }
+    }
+
+    fn deep_size_of(&self) -> usize {
+        match *self {
+            TypedValues::Bool(ref vs) => vs.capacity() * size_of::<bool>(),
+            TypedValues::I32(ref vs) => vs.capacity() * size_of::<i32>(),
+            TypedValues::I64(ref vs) => vs.capacity() * size_of::<i64>(),
+            TypedValues::U32(ref vs) => vs.capacity() * size_of::<u32>(),
+            TypedValues::U64(ref vs) => vs.capacity() * size_of::<u64>(),
+            TypedValues::F32(ref vs) => vs.capacity() * size_of::<f32>(),
+            TypedValues::F64(ref vs) => vs.capacity() * size_of::<f64>(),
+        }
+    }
+}
+
+impl Value {
+    /// The name of the kind of this value, like `i32` or `message`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Value::Bool(_) => "bool",
+            Value::I32(_) => "i32",
+            Value::I64(_) => "i64",
+            Value::U32(_) => "u32",
+            Value::U64(_) => "u64",
+            Value::F32(_) => "f32",
+            Value::F64(_) => "f64",
+            Value::Bytes(_) => "bytes",
+            Value::String(_) => "string",
+            Value::Enum(_) => "enum",
+            Value::Message(_) => "message",
+            Value::FieldMask(_) => "field mask",
+            Value::BytesStream(_) => "bytes stream",
+            Value::Custom(_) => "custom",
+            Value::Null => "null",
+        }
+    }
+
+    /// Returns an estimate of the number of heap bytes held by this value, not including the value
+    /// itself.
+    pub fn deep_size_of(&self) -> usize {
+        match *self {
+            Value::Bytes(ref b) => b.capacity(),
+            Value::String(ref s) => s.capacity(),
+            Value::Message(ref m) => m.deep_size_of(),
+            Value::FieldMask(ref m) => {
+                m.paths.capacity() * size_of::<String>()
+                    + m.paths.iter().map(String::capacity).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Computes the encoded size of this value as the given field, not including its tag.
+    #[inline]
+    pub fn compute_size(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) -> error::Result<u32> {
+        self.compute_size_with_options(descriptors, field, &EncodeOptions::default())
+    }
+
+    /// Computes the encoded size of this value as the given field when encoded with the given
+    /// options, not including its tag.
+    #[inline]
+    pub fn compute_size_with_options(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+    ) -> error::Result<u32> {
+        self.compute_sizes(descriptors, field, options, &mut Sizes::default())
+    }
+
+    fn compute_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+        sizes: &mut Sizes,
+    ) -> error::Result<u32> {
+        use crate::descriptor::FieldType::*;
+        use protobuf::rt;
+
+        Ok(match (field.field_type(descriptors), self) {
+            (Bool, &Value::Bool(_)) => 1,
+            (Int32, &Value::I32(v)) => rt::compute_raw_varint64_size(v as u64),
+            (Int64, &Value::I64(v)) => rt::compute_raw_varint64_size(v as u64),
+            (SInt32, &Value::I32(v)) => rt::value_varint_zigzag_size_no_tag(v),
+            (SInt64, &Value::I64(v)) => rt::value_varint_zigzag_size_no_tag(v),
+            (UInt32, &Value::U32(v)) => rt::compute_raw_varint32_size(v),
+            (UInt64, &Value::U64(v)) => rt::compute_raw_varint64_size(v),
+            (Fixed32, &Value::U32(_)) | (SFixed32, &Value::I32(_)) | (Float, &Value::F32(_)) => 4,
+            (Fixed64, &Value::U64(_)) | (SFixed64, &Value::I64(_)) | (Double, &Value::F64(_)) => 8,
+            (Bytes, Value::Bytes(v)) => length_delimited_size(v.len() as u32),
+            (String, Value::String(v)) => length_delimited_size(v.len() as u32),
+            (Bytes, Value::BytesStream(v)) | (String, Value::BytesStream(v)) => {
+                length_delimited_size(v.len())
+            }
+            (Enum(_), &Value::Enum(v)) => rt::compute_raw_varint64_size(v as u64),
+            (Message(m), Value::Message(v)) => {
+                let size = v.compute_sizes(descriptors, m, options, sizes)?;
+                sizes.insert(v, size);
+                length_delimited_size(size)
+            }
+            (Message(m), Value::FieldMask(v)) if m.name() == field_mask::FIELD_MASK => {
+                length_delimited_size(v.compute_size())
+            }
+            (Message(m), Value::Custom(v)) => {
+                // The converted message is dropped right away, so its sizes aren't recorded
+                let message = custom_message(descriptors, field, m, options, v)?;
+                let size = message.compute_sizes(descriptors, m, options, &mut Sizes::default())?;
+                length_delimited_size(size)
+            }
+            (UnresolvedMessage(_), Value::Bytes(v)) => length_delimited_size(v.len() as u32),
+            (UnresolvedEnum(e), _) => return Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            (UnresolvedMessage(m), _) => {
+                return Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
+            _ => return Err(bad_field_value(field)),
+        })
+    }
+
+    fn write_with_sizes(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        options: &EncodeOptions,
+        sizes: &Sizes,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        let n = check_tag_number(field.number() as u32)?;
+        match (field.field_type(descriptors), self) {
+            (Bool, &Value::Bool(v)) => output.write_bool(n, v)?,
+            (Int32, &Value::I32(v)) => output.write_int32(n, v)?,
+            (Int64, &Value::I64(v)) => output.write_int64(n, v)?,
+            (SInt32, &Value::I32(v)) => output.write_sint32(n, v)?,
+            (SInt64, &Value::I64(v)) => output.write_sint64(n, v)?,
+            (UInt32, &Value::U32(v)) => output.write_uint32(n, v)?,
+            (UInt64, &Value::U64(v)) => output.write_uint64(n, v)?,
+            (Fixed32, &Value::U32(v)) => output.write_fixed32(n, v)?,
+            (Fixed64, &Value::U64(v)) => output.write_fixed64(n, v)?,
+            (SFixed32, &Value::I32(v)) => output.write_sfixed32(n, v)?,
+            (SFixed64, &Value::I64(v)) => output.write_sfixed64(n, v)?,
+            (Float, &Value::F32(v)) => output.write_float(n, v)?,
+            (Double, &Value::F64(v)) => output.write_double(n, v)?,
+            (Bytes, Value::Bytes(v)) => output.write_bytes(n, v)?,
+            (String, Value::String(v)) => output.write_string(n, v)?,
+            (Bytes, Value::BytesStream(v)) | (String, Value::BytesStream(v)) => {
+                output.write_tag(n, wire_format::WireType::WireTypeLengthDelimited)?;
+                output.write_raw_varint32(v.len())?;
+                v.copy(|chunk| Ok(output.write_raw_bytes(chunk)?))?;
+            }
+            (Enum(_), &Value::Enum(v)) => output.write_enum(n, v)?,
+            (Message(m), Value::Message(v)) => {
+                output.write_tag(n, wire_format::WireType::WireTypeLengthDelimited)?;
+                output.write_raw_varint32(sizes.get(v))?;
+                v.write_with_sizes(descriptors, m, options, sizes, output)?;
+            }
+            (Message(m), Value::FieldMask(v)) if m.name() == field_mask::FIELD_MASK => {
+                output.write_tag(n, wire_format::WireType::WireTypeLengthDelimited)?;
+                output.write_raw_varint32(v.compute_size())?;
+                v.write_to(output)?;
+            }
+            (Message(m), Value::Custom(v)) => {
+                // Custom values have no cached sizes, so they are converted again
+                let message = custom_message(descriptors, field, m, options, v)?;
+                output.write_tag(n, wire_format::WireType::WireTypeLengthDelimited)?;
+                let mut message_sizes = Sizes::default();
+                let size = message.compute_sizes(descriptors, m, options, &mut message_sizes)?;
+                output.write_raw_varint32(size)?;
+                message.write_with_sizes(descriptors, m, options, &message_sizes, output)?;
+            }
+            (UnresolvedMessage(_), Value::Bytes(v)) => output.write_bytes(n, v)?,
+            (UnresolvedEnum(e), _) => return Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            (UnresolvedMessage(m), _) => {
+                return Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
+            _ => return Err(bad_field_value(field)),
+        }
+        Ok(())
+    }
+}
+
+impl CachedSize {
+    fn set(&self, size: u32, nested: Sizes) {
+        self.size.set(size);
+        *self.nested.lock().unwrap_or_else(PoisonError::into_inner) = nested;
+    }
+}
+
+impl Clone for CachedSize {
+    fn clone(&self) -> CachedSize {
+        let nested = self.nested.lock().unwrap_or_else(PoisonError::into_inner);
+        CachedSize {
+            size: self.size.clone(),
+            nested: Mutex::new(nested.clone()),
+        }
+    }
+}
+
+impl fmt::Debug for CachedSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.size, f)
+    }
+}
+
+impl Sizes {
+    fn insert(&mut self, message: &Message, size: u32) {
+        self.0.insert(Sizes::key(message), size);
+    }
+
+    /// The recorded size of the given message, which is zero if it wasn't sized.
+    fn get(&self, message: &Message) -> u32 {
+        self.0.get(&Sizes::key(message)).copied().unwrap_or(0)
+    }
+
+    fn key(message: &Message) -> usize {
+        let address: *const Message = message;
+        address as usize
+    }
+}
+
+impl Value {
+    /// Writes this scalar or enum value without a tag, as an element of a packed run.
+    fn write_packed_element(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        output: &mut protobuf::CodedOutputStream,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType::*;
+
+        match (field.field_type(descriptors), self) {
+            (Bool, &Value::Bool(v)) => output.write_bool_no_tag(v)?,
+            (Int32, &Value::I32(v)) => output.write_int32_no_tag(v)?,
+            (Int64, &Value::I64(v)) => output.write_int64_no_tag(v)?,
+            (SInt32, &Value::I32(v)) => output.write_sint32_no_tag(v)?,
+            (SInt64, &Value::I64(v)) => output.write_sint64_no_tag(v)?,
+            (UInt32, &Value::U32(v)) => output.write_uint32_no_tag(v)?,
+            (UInt64, &Value::U64(v)) => output.write_uint64_no_tag(v)?,
+            (Fixed32, &Value::U32(v)) => output.write_fixed32_no_tag(v)?,
+            (Fixed64, &Value::U64(v)) => output.write_fixed64_no_tag(v)?,
+            (SFixed32, &Value::I32(v)) => output.write_sfixed32_no_tag(v)?,
+            (SFixed64, &Value::I64(v)) => output.write_sfixed64_no_tag(v)?,
+            (Float, &Value::F32(v)) => output.write_float_no_tag(v)?,
+            (Double, &Value::F64(v)) => output.write_double_no_tag(v)?,
+            (Enum(_), &Value::Enum(v)) => output.write_enum_no_tag(v)?,
+            (UnresolvedEnum(e), _) => return Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            _ => return Err(bad_field_value(field)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl Value {
+    /// Interprets a 16-byte `bytes` value as a UUID.
+    pub fn to_uuid(&self) -> Option<uuid::Uuid> {
+        match *self {
+            Value::Bytes(ref b) => uuid::Uuid::from_slice(b).ok(),
+            _ => None,
+        }
+    }
+
+    /// Creates a `bytes` value holding the 16 bytes of a UUID.
+    pub fn from_uuid(uuid: uuid::Uuid) -> Value {
+        Value::Bytes(uuid.as_bytes().to_vec())
+    }
+}
+
+#[inline]
+fn length_delimited_size(len: u32) -> u32 {
+    protobuf::rt::compute_raw_varint32_size(len) + len
+}
+
+/// Writes unknown fields in field number order.  The values of a field are written grouped by
+/// wire type, each group in the order in which the values were added.
+fn write_unknown_fields(
+    unknown: &protobuf::UnknownFields,
+    output: &mut protobuf::CodedOutputStream,
+) -> error::Result<()> {
+    let mut fields: Vec<_> = unknown.iter().collect();
+    fields.sort_unstable_by_key(|&(number, _)| number);
+    for (number, values) in fields {
+        check_tag_number(number)?;
+        for value in values {
+            output.write_unknown(number, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts a custom value back into a message with the handler of its type.
+fn custom_message(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    message: &descriptor::MessageDescriptor,
+    options: &EncodeOptions,
+    value: &CustomValue,
+) -> error::Result<Message> {
+    let handler = options
+        .type_handlers
+        .as_ref()
+        .and_then(|h| h.get(message.name()))
+        .ok_or_else(|| error::Error::TypeMismatch {
+            field: field.name().to_owned(),
+            expected: message.name().to_owned(),
+            actual: "custom",
+        })?;
+    handler.encode(descriptors, message, value)
+}
+
+/// The codec that transforms the specified field, if any.
+fn codec_for<'a>(
+    codec: &'a Option<Arc<dyn FieldCodec>>,
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    field: &descriptor::FieldDescriptor,
+) -> Option<&'a dyn FieldCodec> {
+    let codec = codec.as_deref()?;
+    let content = matches!(
+        field.field_type(descriptors),
+        descriptor::FieldType::Bytes | descriptor::FieldType::String
+    );
+    if content && codec.applies_to(message, field) {
+        Some(codec)
+    } else {
+        None
+    }
+}
+
+/// Reads a value whose content was transformed by a codec.
+fn decode_content(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    field: &descriptor::FieldDescriptor,
+    codec: &dyn FieldCodec,
+    input: &mut protobuf::CodedInputStream,
+    wire_type: wire_format::WireType,
+    strict: bool,
+) -> error::Result<Value> {
+    if wire_type != wire_format::WireType::WireTypeLengthDelimited {
+        return Err(error::Error::BadWireType {
+            wire_type,
+            position: input.pos(),
+        });
+    }
+    let content = codec.decode(message, field, read_bytes(input, strict)?)?;
+    match field.field_type(descriptors) {
+        descriptor::FieldType::String => String::from_utf8(content)
+            .map(Value::String)
+            .map_err(|_| bad_field_value(field)),
+        _ => Ok(Value::Bytes(content)),
+    }
+}
+
+/// The content of a `bytes` or `string` value.
+fn content<'a>(field: &descriptor::FieldDescriptor, value: &'a Value) -> error::Result<&'a [u8]> {
+    match *value {
+        Value::Bytes(ref b) => Ok(b),
+        Value::String(ref s) => Ok(s.as_bytes()),
+        _ => Err(bad_field_value(field)),
+    }
+}
+
+/// Looks up the field with the number to decode it; group fields, which aren't supported, and
+/// weak fields whose message type isn't registered are decoded like unknown fields instead.
+pub(crate) fn decoded_field<'a>(
+    descriptors: &descriptor::Descriptors,
+    message: &'a descriptor::MessageDescriptor,
+    number: u32,
+) -> Option<&'a descriptor::FieldDescriptor> {
+    message
+        .field_by_number(number as i32)
+        .filter(|f| match f.field_type(descriptors) {
+            descriptor::FieldType::Group => false,
+            descriptor::FieldType::UnresolvedMessage(_) => !f.is_weak(),
+            _ => true,
+        })
+}
+
+/// The key of a map entry in the text form of field mask paths.
+fn map_key_text(
+    descriptors: &descriptor::Descriptors,
+    entry: &descriptor::MessageDescriptor,
+    value: &Value,
+) -> String {
+    let key = match *value {
+        Value::Message(ref m) => match m.fields.get(&1) {
+            Some(Field::Singular(Some(key))) => Some(key),
+            _ => None,
+        },
+        _ => None,
+    };
+    match key {
+        Some(Value::String(k)) => k.clone(),
+        Some(Value::Bool(k)) => k.to_string(),
+        Some(Value::I32(k)) => k.to_string(),
+        Some(Value::I64(k)) => k.to_string(),
+        Some(Value::U32(k)) => k.to_string(),
+        Some(Value::U64(k)) => k.to_string(),
+        // Absent keys have the default value of their type
+        _ => match entry.field_by_number(1).map(|k| k.field_type(descriptors)) {
+            Some(descriptor::FieldType::String) => String::new(),
+            Some(descriptor::FieldType::Bool) => "false".to_owned(),
+            _ => "0".to_owned(),
+        },
+    }
+}
+
+fn is_message(descriptors: &descriptor::Descriptors, field: &descriptor::FieldDescriptor) -> bool {
+    matches!(
+        field.field_type(descriptors),
+        descriptor::FieldType::Message(_) | descriptor::FieldType::UnresolvedMessage(_)
+    )
+}
+
+fn is_map(descriptors: &descriptor::Descriptors, field: &descriptor::FieldDescriptor) -> bool {
+    match field.field_type(descriptors) {
+        descriptor::FieldType::Message(m) => m.is_map_entry(),
+        _ => false,
+    }
+}
+
+/// Orders map entries by their keys, which are integers, booleans or strings.
+fn compare_map_keys(a: &Value, b: &Value) -> cmp::Ordering {
+    fn key(entry: &Value) -> Option<&Value> {
+        match *entry {
+            Value::Message(ref m) => match m.fields.get(&1) {
+                Some(Field::Singular(Some(ref k))) => Some(k),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    compare_keys(key(a), key(b))
+}
+
+/// Orders key values, which are of a scalar, string, bytes or enum type; absent keys come first.
+fn compare_keys(a: Option<&Value>, b: Option<&Value>) -> cmp::Ordering {
+    match (a, b) {
+        (Some(&Value::Bool(a)), Some(&Value::Bool(b))) => a.cmp(&b),
+        (Some(&Value::I32(a)), Some(&Value::I32(b))) => a.cmp(&b),
+        (Some(&Value::Enum(a)), Some(&Value::Enum(b))) => a.cmp(&b),
+        (Some(&Value::I64(a)), Some(&Value::I64(b))) => a.cmp(&b),
+        (Some(&Value::U32(a)), Some(&Value::U32(b))) => a.cmp(&b),
+        (Some(&Value::U64(a)), Some(&Value::U64(b))) => a.cmp(&b),
+        (Some(&Value::F32(a)), Some(&Value::F32(b))) => a.total_cmp(&b),
+        (Some(&Value::F64(a)), Some(&Value::F64(b))) => a.total_cmp(&b),
+        (Some(Value::Bytes(a)), Some(Value::Bytes(b))) => a.cmp(b),
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+/// Checks that the descriptor describes the generated message type `M`.
+fn check_generated_type<M>(message: &descriptor::MessageDescriptor) -> error::Result<()>
+where
+    M: protobuf::Message,
+{
+    let expected = M::descriptor_static().full_name();
+    if message.name().trim_start_matches('.') != expected {
+        return Err(error::Error::MessageTypeMismatch {
+            expected: format!(".{}", expected),
+            actual: message.name().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Looks up a repeated field of the message by name.
+fn repeated_field_by_name<'a>(
+    message: &'a descriptor::MessageDescriptor,
+    name: &str,
+) -> error::Result<&'a descriptor::FieldDescriptor> {
+    let field = message
+        .field_by_name(name)
+        .ok_or_else(|| error::Error::UnknownField {
+            name: name.to_owned(),
+        })?;
+    if !field.is_repeated() {
+        return Err(bad_field_value(field));
+    }
+    Ok(field)
+}
+
+/// Looks up the field named `key` of the messages of a repeated message field.
+fn key_field<'a>(
+    descriptors: &'a descriptor::Descriptors,
+    field: &'a descriptor::FieldDescriptor,
+    key: &str,
+) -> error::Result<&'a descriptor::FieldDescriptor> {
+    let message = match field.field_type(descriptors) {
+        descriptor::FieldType::Message(m) if field.is_repeated() => m,
+        _ => return Err(bad_field_value(field)),
+    };
+    let key = message
+        .field_by_name(key)
+        .ok_or_else(|| error::Error::UnknownField {
+            name: key.to_owned(),
+        })?;
+    if key.is_repeated() || is_message(descriptors, key) {
+        return Err(bad_field_value(key));
+    }
+    Ok(key)
+}
+
+/// The value of the key field of a message, or the default value if it is absent.
+fn message_key<'a>(
+    value: &'a Value,
+    key: &descriptor::FieldDescriptor,
+    default: Option<&'a Value>,
+) -> Option<&'a Value> {
+    match *value {
+        Value::Message(ref m) => match m.fields.get(&key.number()) {
+            Some(Field::Singular(Some(ref k))) => Some(k),
+            _ => default,
+        },
+        _ => None,
+    }
+}
+
+fn fields_semantic_eq(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    field: &descriptor::FieldDescriptor,
+    a: Option<&Field>,
+    b: Option<&Field>,
+) -> bool {
+    fn singular(f: Option<&Field>) -> Option<&Value> {
+        match f {
+            Some(Field::Singular(Some(v))) => Some(v),
+            _ => None,
+        }
+    }
+    fn repeated(f: Option<&Field>) -> borrow::Cow<'_, [Value]> {
+        match f {
+            Some(f @ Field::Repeated(_)) | Some(f @ Field::RepeatedTyped(_)) => f.values(),
+            _ => borrow::Cow::Borrowed(&[]),
+        }
+    }
+
+    if !field.is_repeated() {
+        // Without presence, a field that is set to its default value counts as absent; the
+        // entries of maps never track presence, whatever the syntax of their file, and declared
+        // defaults may have been filled in by `Message::new`
+        let implicit_presence = !field.has_presence()
+            || (!is_message(descriptors, field)
+                && (message.is_map_entry() || field.default_value().is_some()));
+        let present = |v: &&Value| !(implicit_presence && is_default(field, v));
+        return match (singular(a).filter(present), singular(b).filter(present)) {
+            (None, None) => true,
+            (Some(a), Some(b)) => values_semantic_eq(descriptors, field, a, b),
+            _ => false,
+        };
+    }
+
+    let (a, b) = (repeated(a), repeated(b));
+    let (mut a, mut b): (Vec<&Value>, Vec<&Value>) = (a.iter().collect(), b.iter().collect());
+    if is_map(descriptors, field) {
+        a = map_entries(a);
+        b = map_entries(b);
+    }
+    a.len() == b.len()
+        && a.iter()
+            .zip(&b)
+            .all(|(a, b)| values_semantic_eq(descriptors, field, a, b))
+}
+
+fn values_semantic_eq(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    a: &Value,
+    b: &Value,
+) -> bool {
+    match (a, b) {
+        (Value::Message(a), Value::Message(b)) => match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) => a.semantic_eq(descriptors, m, b),
+            _ => false,
+        },
+        (&Value::Bool(a), &Value::Bool(b)) => a == b,
+        (&Value::I32(a), &Value::I32(b)) | (&Value::Enum(a), &Value::Enum(b)) => a == b,
+        (&Value::I64(a), &Value::I64(b)) => a == b,
+        (&Value::U32(a), &Value::U32(b)) => a == b,
+        (&Value::U64(a), &Value::U64(b)) => a == b,
+        (&Value::F32(a), &Value::F32(b)) => a.to_bits() == b.to_bits(),
+        (&Value::F64(a), &Value::F64(b)) => a.to_bits() == b.to_bits(),
+        (Value::Bytes(a), Value::Bytes(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::FieldMask(a), Value::FieldMask(b)) => a.paths == b.paths,
+        _ => false,
+    }
+}
+
+/// Sorts map entries by key, keeping only the last entry for each key.
+fn map_entries(mut entries: Vec<&Value>) -> Vec<&Value> {
+    // The sort is stable, so the last entry of each run of equal keys is the last one set
+    entries.sort_by(|a, b| compare_map_keys(a, b));
+    let mut result: Vec<&Value> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match result.last_mut() {
+            Some(last) if compare_map_keys(last, entry) == cmp::Ordering::Equal => *last = entry,
+            _ => result.push(entry),
+        }
+    }
+    result
+}
+
+#[cfg(feature = "tracing")]
+fn unknown_field_count(unknown: &protobuf::UnknownFields) -> usize {
+    unknown
+        .iter()
+        .map(|(_, v)| v.fixed32.len() + v.fixed64.len() + v.varint.len() + v.length_delimited.len())
+        .sum()
+}
+
+pub(crate) fn is_default(field: &descriptor::FieldDescriptor, value: &Value) -> bool {
+    match (field.default_value(), value) {
+        (Some(&Value::Bool(d)), &Value::Bool(v)) => d == v,
+        (Some(&Value::I32(d)), &Value::I32(v)) => d == v,
+        (Some(&Value::I64(d)), &Value::I64(v)) => d == v,
+        (Some(&Value::U32(d)), &Value::U32(v)) => d == v,
+        (Some(&Value::U64(d)), &Value::U64(v)) => d == v,
+        (Some(&Value::F32(d)), &Value::F32(v)) => d.to_bits() == v.to_bits(),
+        (Some(&Value::F64(d)), &Value::F64(v)) => d.to_bits() == v.to_bits(),
+        (Some(Value::Bytes(d)), Value::Bytes(v)) => d == v,
+        (Some(Value::String(d)), Value::String(v)) => d == v,
+        (Some(&Value::Enum(d)), &Value::Enum(v)) => d == v,
+        (Some(_), _) => false,
+        (None, &Value::Bool(v)) => !v,
+        (None, &Value::I32(v)) => v == 0,
+        (None, &Value::I64(v)) => v == 0,
+        (None, &Value::U32(v)) => v == 0,
+        (None, &Value::U64(v)) => v == 0,
+        (None, &Value::F32(v)) => v.to_bits() == 0,
+        (None, &Value::F64(v)) => v.to_bits() == 0,
+        (None, Value::Bytes(v)) => v.is_empty(),
+        (None, Value::String(v)) => v.is_empty(),
+        (None, &Value::Enum(v)) => v == 0,
+        (None, Value::BytesStream(v)) => v.is_empty(),
+        (None, &Value::Message(_))
+        | (None, &Value::FieldMask(_))
+        | (None, &Value::Custom(_))
+        | (None, &Value::Null) => false,
+    }
+}
+
+/// Checks that a value has the right representation for the type of the specified field.
+pub(crate) fn check_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &Value,
+) -> error::Result<()> {
+    use crate::descriptor::FieldType::*;
+
+    let matches = match (field.field_type(descriptors), value) {
+        (Bool, &Value::Bool(_)) => true,
+        (Int32, &Value::I32(_)) | (SInt32, &Value::I32(_)) | (SFixed32, &Value::I32(_)) => true,
+        (Int64, &Value::I64(_)) | (SInt64, &Value::I64(_)) | (SFixed64, &Value::I64(_)) => true,
+        (UInt32, &Value::U32(_)) | (Fixed32, &Value::U32(_)) => true,
+        (UInt64, &Value::U64(_)) | (Fixed64, &Value::U64(_)) => true,
+        (Float, &Value::F32(_)) | (Double, &Value::F64(_)) => true,
+        (String, &Value::String(_)) | (Bytes, &Value::Bytes(_)) => true,
+        (String, &Value::BytesStream(_)) | (Bytes, &Value::BytesStream(_)) => true,
+        (Enum(_), &Value::Enum(_)) => true,
+        (Message(m), &Value::FieldMask(_)) => m.name() == field_mask::FIELD_MASK,
+        (Message(_), &Value::Custom(_)) => true,
+        (Message(m), &Value::Message(_)) => m.name() != field_mask::FIELD_MASK,
+        _ => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(error::Error::TypeMismatch {
+            field: field.name().to_owned(),
+            expected: field.field_type(descriptors).name().to_owned(),
+            actual: value.kind(),
+        })
+    }
+}
+
+/// Checks that a field number is valid before it is written in a tag, since `protobuf` panics on
+/// invalid tags.  Negative field numbers of descriptors are passed in two's complement.
+fn check_tag_number(number: u32) -> error::Result<u32> {
+    if number == 0 || u64::from(number) > MAX_FIELD_NUMBER {
+        return Err(error::Error::BadFieldNumber {
+            number: u64::from(number),
+            position: None,
+        });
+    }
+    Ok(number)
+}
+
+/// Reads a tag, rejecting field number 0 and the undefined wire types 6 and 7.
+pub(crate) fn read_tag(
+    input: &mut protobuf::CodedInputStream,
+    strict: bool,
+) -> error::Result<(u32, wire_format::WireType)> {
+    let position = input.pos();
+    let tag = read_varint(input, strict)?;
+    let number = tag >> 3;
+    if number == 0 || number > MAX_FIELD_NUMBER {
+        return Err(error::Error::BadFieldNumber {
+            number,
+            position: Some(position),
+        });
+    }
+    match wire_format::WireType::new((tag & 7) as u32) {
+        Some(wire_type) => Ok((number as u32, wire_type)),
+        None => Err(error::Error::UndefinedWireType {
+            wire_type: (tag & 7) as u32,
+            position,
+        }),
+    }
+}
+
+/// Reads a varint, which must be minimally encoded if `strict` is set.
+fn read_varint(input: &mut protobuf::CodedInputStream, strict: bool) -> error::Result<u64> {
+    let position = input.pos();
+    let v = input.read_raw_varint64()?;
+    if strict && input.pos() - position != u64::from(protobuf::rt::compute_raw_varint64_size(v)) {
+        return Err(error::Error::NonMinimalVarint { position });
+    }
+    Ok(v)
+}
 
-            self.put(Value::Message(msg));
+/// Reads a packed run of `len` bytes of varints, which must be minimally encoded if `strict` is
+/// set.
+#[inline]
+fn read_packed_varints<F>(
+    input: &mut protobuf::CodedInputStream,
+    len: u64,
+    strict: bool,
+    mut push: F,
+) -> error::Result<()>
+where
+    F: FnMut(u64),
+{
+    let old_limit = input.push_limit(len)?;
+    if strict {
+        while !input.eof()? {
+            push(read_varint(input, strict)?);
+        }
+    } else {
+        while !input.eof()? {
+            push(input.read_raw_varint64()?);
+        }
+    }
+    input.pop_limit(old_limit);
+    Ok(())
+}
+
+/// Reads a packed run of `len` bytes of fixed-width elements of `size` bytes in chunks.
+fn read_packed_fixed<F>(
+    input: &mut protobuf::CodedInputStream,
+    len: u64,
+    size: usize,
+    mut push: F,
+) -> error::Result<()>
+where
+    F: FnMut(&[u8]),
+{
+    let mut buf = [0; PACKED_CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buf[..cmp::min(remaining, PACKED_CHUNK_SIZE as u64) as usize];
+        input.read(chunk)?;
+        chunk.chunks_exact(size).for_each(&mut push);
+        remaining -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Reads a length prefix, which must also fit into the enclosing message if `strict` is set.
+fn read_length(input: &mut protobuf::CodedInputStream, strict: bool) -> error::Result<u64> {
+    let position = input.pos();
+    let length = read_varint(input, strict)?;
+    let remaining = input.bytes_until_limit();
+    if strict && length > remaining {
+        return Err(error::Error::LengthOutOfBounds {
+            length,
+            remaining,
+            position,
+        });
+    }
+    Ok(length)
+}
+
+fn read_bytes(input: &mut protobuf::CodedInputStream, strict: bool) -> error::Result<Vec<u8>> {
+    if !strict {
+        return Ok(input.read_bytes()?);
+    }
+    let length = read_length(input, strict)?;
+    if length > u64::from(u32::MAX) {
+        return Err(
+            protobuf::ProtobufError::WireError(protobuf::error::WireError::UnexpectedEof).into(),
+        );
+    }
+    Ok(input.read_raw_bytes(length as u32)?)
+}
+
+fn read_string(input: &mut protobuf::CodedInputStream, strict: bool) -> error::Result<String> {
+    if !strict {
+        return Ok(input.read_string()?);
+    }
+    String::from_utf8(read_bytes(input, strict)?).map_err(|_| {
+        protobuf::ProtobufError::WireError(protobuf::error::WireError::Utf8Error).into()
+    })
+}
+
+/// Appends a field name to a dotted path.
+fn push_path(path: &mut String, name: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(name);
+}
+
+fn decode_zig_zag_32(n: u32) -> i32 {
+    ((n >> 1) as i32) ^ (-((n & 1) as i32))
+}
+
+fn decode_zig_zag_64(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ (-((n & 1) as i64))
+}
+
+fn bad_field_value(field: &descriptor::FieldDescriptor) -> error::Error {
+    error::Error::BadFieldValue {
+        field: field.name().to_owned(),
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<I> io::Read for BufsReader<I>
+where
+    I: Iterator,
+    I::Item: bytes::Buf,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        use bytes::Buf;
+
+        loop {
+            match self.current {
+                Some(ref mut buf) if buf.has_remaining() => {
+                    let n = cmp::min(buf.remaining(), out.len());
+                    buf.copy_to_slice(&mut out[..n]);
+                    return Ok(n);
+                }
+                _ => match self.rest.next() {
+                    Some(buf) => self.current = Some(buf),
+                    None => return Ok(0),
+                },
+            }
+        }
+    }
+}
+
+impl EncodeOptions {
+    /// Creates the default encoding options.
+    pub fn new() -> EncodeOptions {
+        EncodeOptions::default()
+    }
+
+    /// Whether to omit singular fields that are equal to their default value.
+    ///
+    /// The default value of a field is the one declared in its descriptor, or else the zero value
+    /// of its type.  Message fields are never omitted.
+    pub fn skip_defaults(mut self, skip_defaults: bool) -> EncodeOptions {
+        self.skip_defaults = skip_defaults;
+        self
+    }
+
+    /// Whether to use the canonical encoding, which additionally writes the entries of map fields
+    /// sorted by key.
+    ///
+    /// Together with the field number order of known and unknown fields, this makes the encoding
+    /// of equal messages byte-for-byte identical, e.g. for computing signatures over them.
+    pub fn canonical(mut self, canonical: bool) -> EncodeOptions {
+        self.canonical = canonical;
+        self
+    }
+
+    /// Whether to write messages that still have their source bytes (see
+    /// `Message::source_bytes`) by copying those bytes verbatim, instead of encoding their fields.
+    ///
+    /// This makes re-encoding a decoded message cheap when only a few of its fields are changed,
+    /// as in proxies that rewrite some fields: only the modified messages are encoded again, and
+    /// the bytes of all other messages are copied as they are, regardless of the other options.
+    /// Messages must forget their source bytes when they are modified, see
+    /// `Message::clear_source_bytes`; the messages enclosing them are then encoded from their
+    /// fields too.
+    #[cfg(feature = "bytes")]
+    pub fn pass_through_source_bytes(mut self, pass_through: bool) -> EncodeOptions {
+        self.pass_through = pass_through;
+        self
+    }
+
+    /// A codec that transforms the content of selected `bytes` and `string` fields as they are
+    /// written; see `FieldCodec`.
+    ///
+    /// Decode with the same codec in `DecodeOptions::field_codec` to undo the transformation.
+    pub fn field_codec(mut self, codec: Arc<dyn FieldCodec>) -> EncodeOptions {
+        self.field_codec = Some(codec);
+        self
+    }
+
+    /// The handlers that convert `Value::Custom` values back into messages to write them; see
+    /// `TypeHandler`.
+    pub fn type_handlers(mut self, handlers: Arc<TypeHandlers>) -> EncodeOptions {
+        self.type_handlers = Some(handlers);
+        self
+    }
+
+    /// What to do about deprecated fields that are set in the messages being written.  By
+    /// default, they aren't looked for.
+    pub fn deprecated_fields(mut self, policy: DeprecatedFieldPolicy) -> EncodeOptions {
+        self.deprecated_fields = policy;
+        self
+    }
+
+    fn skips(&self, field: &descriptor::FieldDescriptor, value: &Value) -> bool {
+        if let Value::Null = *value {
+            return true;
+        }
+        self.skip_defaults && is_default(field, value)
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DEFAULT_DECODE_OPTIONS.clone()
+    }
+}
+
+impl DecodeOptions {
+    /// Creates the default decoding options.
+    pub fn new() -> DecodeOptions {
+        DecodeOptions::default()
+    }
+
+    /// The maximum nesting depth of messages, where the top-level message has a depth of 1.
+    /// Deeper messages fail the decode with a `RecursionLimitExceeded` error, rather than
+    /// exhausting the stack.  The default is `DEFAULT_RECURSION_LIMIT`.
+    pub fn recursion_limit(mut self, limit: u32) -> DecodeOptions {
+        self.recursion_limit = limit;
+        self
+    }
+
+    /// What to do with fields that the message type doesn't know about.  By default, they are
+    /// preserved.
+    pub fn unknown_fields(mut self, policy: UnknownFieldPolicy) -> DecodeOptions {
+        self.unknown_fields = policy;
+        self
+    }
+
+    /// Whether to decode fields of message types that aren't in the registry as `Value::Bytes`
+    /// holding the encoded message, instead of failing with an `UnknownMessage` error.
+    ///
+    /// Such values are encoded back as they are, so messages can be passed through without
+    /// knowing all of their nested types.
+    pub fn unresolved_messages_as_bytes(mut self, as_bytes: bool) -> DecodeOptions {
+        self.unresolved_messages_as_bytes = as_bytes;
+        self
+    }
+
+    /// Whether to reject encodings that are valid but not canonical, which are a sign of a broken
+    /// or malicious producer.
+    ///
+    /// Tags, length prefixes and the varints of known fields must then be encoded in as few bytes
+    /// as possible, and length prefixes must not reach past the end of the enclosing message.
+    /// Unknown fields are skipped without these checks.
+    pub fn strict(mut self, strict: bool) -> DecodeOptions {
+        self.strict = strict;
+        self
+    }
+
+    /// Whether to store the values of repeated bool and numeric fields as `Field::RepeatedTyped`,
+    /// which takes a fraction of the memory of `Field::Repeated` for long repeated fields.
+    pub fn typed_repeated_scalars(mut self, typed: bool) -> DecodeOptions {
+        self.typed_repeated_scalars = typed;
+        self
+    }
+
+    /// What to do when a singular field occurs more than once.  By default, the last value wins.
+    pub fn duplicate_fields(mut self, policy: DuplicateFieldPolicy) -> DecodeOptions {
+        self.duplicate_fields = policy;
+        self
+    }
+
+    /// A codec that undoes the transformation of the content of selected `bytes` and `string`
+    /// fields as they are read; see `FieldCodec`.
+    pub fn field_codec(mut self, codec: Arc<dyn FieldCodec>) -> DecodeOptions {
+        self.field_codec = Some(codec);
+        self
+    }
+
+    /// The handlers that convert nested messages of their types into `Value::Custom` values;
+    /// see `TypeHandler`.
+    ///
+    /// A custom value isn't merged into like a message: a later occurrence of a singular field
+    /// replaces it.  Top-level messages are never converted.
+    pub fn type_handlers(mut self, handlers: Arc<TypeHandlers>) -> DecodeOptions {
+        self.type_handlers = Some(handlers);
+        self
+    }
+
+    /// What to do about deprecated fields that occur in the input.  By default, they aren't
+    /// looked for.
+    pub fn deprecated_fields(mut self, policy: DeprecatedFieldPolicy) -> DecodeOptions {
+        self.deprecated_fields = policy;
+        self
+    }
+}
+
+impl UnknownFieldCallback {
+    /// Wraps a function as an unknown-field callback.
+    pub fn new<F>(callback: F) -> UnknownFieldCallback
+    where
+        F: Fn(u32, wire_format::WireType, &[u8]) + Send + Sync + 'static,
+    {
+        UnknownFieldCallback(Arc::new(callback))
+    }
+}
+
+impl DeprecatedFieldPolicy {
+    fn is_ignore(&self) -> bool {
+        matches!(*self, DeprecatedFieldPolicy::Ignore)
+    }
+
+    fn report(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        path: &str,
+        field: &descriptor::FieldDescriptor,
+    ) {
+        match *self {
+            DeprecatedFieldPolicy::Ignore => (),
+            DeprecatedFieldPolicy::Callback(DeprecatedFieldCallback(ref callback)) => {
+                callback(path, field)
+            }
+            #[cfg(feature = "tracing")]
+            DeprecatedFieldPolicy::Trace => {
+                tracing::warn!(message = message.name(), path, "deprecated field is set")
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = message;
+    }
+}
+
+impl DeprecatedFieldCallback {
+    /// Wraps a function as a deprecated-field callback.
+    pub fn new<F>(callback: F) -> DeprecatedFieldCallback
+    where
+        F: Fn(&str, &descriptor::FieldDescriptor) + Send + Sync + 'static,
+    {
+        DeprecatedFieldCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for DeprecatedFieldCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeprecatedFieldCallback").finish()
+    }
+}
+
+impl fmt::Debug for UnknownFieldCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnknownFieldCallback").finish()
+    }
+}
+
+impl<'a> ChangeRecorder<'a> {
+    /// Records that the field with the specified number was modified.
+    pub(crate) fn mark(&mut self, number: i32) {
+        let state = self.state.get_or_insert_with(Default::default);
+        state.changed.insert(number);
+        #[cfg(feature = "bytes")]
+        {
+            state.source = None;
+            state.modified = true;
+        }
+    }
+}
+
+impl BytesStream {
+    /// Creates a stream of exactly `len` bytes read from the specified reader.
+    pub fn new<R>(len: u32, reader: R) -> BytesStream
+    where
+        R: io::Read + Send + 'static,
+    {
+        BytesStream {
+            len,
+            reader: Arc::new(Mutex::new(Some(Box::new(reader)))),
+        }
+    }
+
+    /// The number of bytes in the stream.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Whether the stream is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the whole content of the stream into memory, consuming the stream.
+    pub fn read_to_vec(&self) -> error::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len as usize);
+        self.copy(|chunk| {
+            bytes.extend_from_slice(chunk);
             Ok(())
-        } else {
-            Err(error::Error::BadWireType {
-                wire_type: actual_wire_type,
-            })
+        })?;
+        Ok(bytes)
+    }
+
+    /// Passes the content of the stream to `f` in chunks, consuming the stream.
+    fn copy<F>(&self, mut f: F) -> error::Result<()>
+    where
+        F: FnMut(&[u8]) -> error::Result<()>,
+    {
+        let reader = self
+            .reader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let mut reader = reader.ok_or_else(|| error::Error::Custom {
+            message: "bytes stream was already consumed".to_owned(),
+        })?;
+
+        let mut chunk = vec![0; cmp::min(self.len as usize, STREAM_CHUNK_SIZE)];
+        let mut remaining = self.len as usize;
+        while remaining > 0 {
+            let len = cmp::min(remaining, chunk.len());
+            let n = match reader.read(&mut chunk[..len]) {
+                Ok(0) => {
+                    return Err(protobuf::ProtobufError::IoError(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "bytes stream ended early",
+                    ))
+                    .into())
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(protobuf::ProtobufError::IoError(e).into()),
+            };
+            f(&chunk[..n])?;
+            remaining -= n;
+        }
+        Ok(())
+    }
+}
+
+impl CustomValue {
+    /// Wraps a value of a custom type.
+    pub fn new<T>(value: T) -> CustomValue
+    where
+        T: any::Any + Send + Sync,
+    {
+        CustomValue {
+            value: Arc::new(value),
+            type_name: any::type_name::<T>(),
+        }
+    }
+
+    /// The value, if it is of type `T`.
+    pub fn downcast_ref<T>(&self) -> Option<&T>
+    where
+        T: any::Any,
+    {
+        self.value.downcast_ref()
+    }
+
+    /// Whether the value is of type `T`.
+    pub fn is<T>(&self) -> bool
+    where
+        T: any::Any,
+    {
+        self.value.is::<T>()
+    }
+}
+
+impl fmt::Debug for CustomValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CustomValue").field(&self.type_name).finish()
+    }
+}
+
+impl TypeHandlers {
+    /// Creates a registry without handlers.
+    pub fn new() -> TypeHandlers {
+        TypeHandlers::default()
+    }
+
+    /// Handles the message type with the specified fully qualified name, like `.foo.Decimal`.
+    pub fn register(&mut self, name: &str, handler: Arc<dyn TypeHandler>) {
+        self.handlers.insert(name.to_owned(), handler);
+    }
+
+    /// The handler of the message type with the specified fully qualified name.
+    pub fn get(&self, name: &str) -> Option<&dyn TypeHandler> {
+        self.handlers.get(name).map(|h| &**h)
+    }
+}
+
+impl fmt::Debug for BytesStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BytesStream")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<'a> LengthDelimitedRecords<'a> {
+    /// Creates an iterator over the length-delimited records in the specified buffer.
+    pub fn new(bytes: &'a [u8]) -> LengthDelimitedRecords<'a> {
+        LengthDelimitedRecords { bytes }
+    }
+}
+
+impl<'a> Iterator for LengthDelimitedRecords<'a> {
+    type Item = error::Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let (len, pos) = {
+            let mut input = protobuf::CodedInputStream::from_bytes(self.bytes);
+            match input.read_raw_varint64() {
+                Ok(len) => (len, input.pos() as usize),
+                Err(e) => {
+                    self.bytes = &[];
+                    return Some(Err(e.into()));
+                }
+            }
+        };
+
+        let rest = &self.bytes[pos..];
+        if len > rest.len() as u64 {
+            self.bytes = &[];
+            return Some(Err(protobuf::ProtobufError::WireError(
+                protobuf::error::WireError::UnexpectedEof,
+            )
+            .into()));
+        }
+
+        let (record, rest) = rest.split_at(len as usize);
+        self.bytes = rest;
+        Some(Ok(record))
+    }
+}
+
+fn repeated_heap_size(values: &Vec<Value>) -> usize {
+    values.capacity() * size_of::<Value>()
+}
+
+/// The largest field number that a tag can have.
+const MAX_FIELD_NUMBER: u64 = (1 << 29) - 1;
+
+/// The assumed average encoded size of a varint in a packed run, used to estimate how many values
+/// it contains; most varints in practice are small.
+const VARINT_ESTIMATED_SIZE: u64 = 2;
+
+/// The maximum number of values to reserve space for up front when decoding a packed run.
+const MAX_RESERVED_VALUES: u64 = 1 << 16;
+
+// Checks the thread safety that the module documentation promises at compile time
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Message>();
+    assert_send_sync::<Field>();
+    assert_send_sync::<Value>();
+    assert_send_sync::<TypedValues>();
+    assert_send_sync::<CustomValue>();
+    assert_send_sync::<TypeHandlers>();
+    assert_send_sync::<DecodeOptions>();
+    assert_send_sync::<EncodeOptions>();
+};
+
+/// The number of bytes of a packed fixed-width run that are read from the input at a time; a
+/// multiple of every fixed width.
+const PACKED_CHUNK_SIZE: usize = 512;
+
+/// The number of bytes of a `BytesStream` that are copied to the output at a time.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A scalar type with a fixed-width little-endian wire representation.
+trait FixedWidth: Sized {
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! fixed_width {
+    ($t:ty, $size:expr) => {
+        impl FixedWidth for $t {
+            #[inline]
+            fn from_le_bytes(bytes: &[u8]) -> Self {
+                let mut buf = [0; $size];
+                buf.copy_from_slice(bytes);
+                <$t>::from_le_bytes(buf)
+            }
+        }
+    };
+}
+
+fixed_width!(u32, 4);
+fixed_width!(u64, 8);
+fixed_width!(i32, 4);
+fixed_width!(i64, 8);
+fixed_width!(f32, 4);
+fixed_width!(f64, 8);
+
+/// The size of each value of the specified field on the wire, if it has a fixed-width type.
+fn fixed_width(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+) -> Option<u32> {
+    use crate::descriptor::FieldType::*;
+
+    match field.field_type(descriptors) {
+        Fixed32 | SFixed32 | Float => Some(4),
+        Fixed64 | SFixed64 | Double => Some(8),
+        _ => None,
+    }
+}
+
+/// The bytes of a slice of fixed-width values, which are their little-endian wire representation
+/// on little-endian targets.
+#[cfg(target_endian = "little")]
+fn le_bytes<T: FixedWidth>(values: &[T]) -> &[u8] {
+    use std::mem::size_of_val;
+    use std::slice;
+
+    // Fixed-width values have no padding, and any alignment is valid for bytes
+    unsafe { slice::from_raw_parts(values.as_ptr().cast::<u8>(), size_of_val(values)) }
+}
+
+impl MessagePool {
+    /// Creates a new empty pool that retains any number of recycled messages.
+    pub fn new() -> MessagePool {
+        MessagePool::default()
+    }
+
+    /// Creates a new empty pool that retains at most `max_per_type` recycled messages per message
+    /// type; any further recycled messages are dropped.
+    pub fn with_max_per_type(max_per_type: usize) -> MessagePool {
+        MessagePool {
+            messages: collections::HashMap::new(),
+            max_per_type: Some(max_per_type),
         }
     }
 
+    /// Takes a message of the specified type from the pool, or creates a new one if none are
+    /// available.
+    ///
+    /// The returned message is in the same state as one returned by `Message::new`.
     #[inline]
-    fn put(&mut self, value: Value) {
-        match *self {
-            Field::Singular(ref mut s) => *s = Some(value),
-            Field::Repeated(ref mut r) => r.push(value),
+    pub fn get(&mut self, message: &descriptor::MessageDescriptor) -> Message {
+        self.messages
+            .get_mut(message.name())
+            .and_then(Vec::pop)
+            .unwrap_or_else(|| Message::new(message))
+    }
+
+    /// Hands a message (and any messages nested within it) back to the pool for later reuse.
+    pub fn recycle(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        mut value: Message,
+    ) {
+        for field in message.fields() {
+            let nested = match field.field_type(descriptors) {
+                descriptor::FieldType::Message(m) => Some(m),
+                _ => None,
+            };
+
+            let slot = value
+                .fields
+                .entry(field.number())
+                .or_insert_with(|| Field::new(field));
+
+            match *slot {
+                Field::Singular(ref mut o) => {
+                    if let (Some(m), Some(Value::Message(v))) = (nested, o.take()) {
+                        // Messages that are still shared with clones can't be reused
+                        if let Ok(v) = Arc::try_unwrap(v) {
+                            self.recycle(descriptors, m, v);
+                        }
+                    }
+                    *o = field.default_value().cloned();
+                }
+                Field::Repeated(ref mut r) => {
+                    if let Some(m) = nested {
+                        for v in r.drain(..) {
+                            if let Value::Message(v) = v {
+                                if let Ok(v) = Arc::try_unwrap(v) {
+                                    self.recycle(descriptors, m, v);
+                                }
+                            }
+                        }
+                    } else {
+                        r.clear();
+                    }
+                }
+                Field::RepeatedTyped(ref mut r) => r.clear(),
+            }
+        }
+        value.unknown = protobuf::UnknownFields::new();
+        value.state = None;
+
+        let recycled = self.messages.entry(message.name().to_owned()).or_default();
+        if recycled.len() < self.max_per_type.unwrap_or(usize::MAX) {
+            recycled.push(value);
+        }
+    }
+
+    /// The total number of messages currently held by the pool.
+    pub fn len(&self) -> usize {
+        self.messages.values().map(Vec::len).sum()
+    }
+
+    /// Whether the pool currently holds no messages.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a decoder for messages of the specified type.
+    pub fn new(
+        descriptors: &'a descriptor::Descriptors,
+        message: &'a descriptor::MessageDescriptor,
+    ) -> Decoder<'a> {
+        Decoder {
+            descriptors,
+            message,
+            options: DecodeOptions::default(),
+            pool: MessagePool::new(),
+        }
+    }
+
+    /// Sets the options that messages are decoded with.
+    pub fn with_options(mut self, options: DecodeOptions) -> Decoder<'a> {
+        self.options = options;
+        self
+    }
+
+    /// Limits the number of recycled messages that are kept for reuse, per message type.
+    pub fn with_max_pooled(mut self, max_per_type: usize) -> Decoder<'a> {
+        self.pool = MessagePool::with_max_per_type(max_per_type);
+        self
+    }
+
+    /// Decodes a single message.
+    pub fn decode(&mut self, bytes: &[u8]) -> error::Result<Message> {
+        let mut message = self.pool.get(self.message);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut context = DecodeContext {
+            options: &self.options,
+            pool: Some(&mut self.pool),
+            ..DecodeContext::default()
+        };
+        message.merge_from_root(self.descriptors, self.message, &mut input, &mut context)?;
+        Ok(message)
+    }
+
+    /// Decodes each of the records lazily, in order.
+    ///
+    /// Hand the decoded messages back with `recycle` when they are no longer needed, so that
+    /// later records can reuse them.
+    pub fn decode_many<'b, I>(&'b mut self, records: I) -> DecodeMany<'a, 'b, I::IntoIter>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        DecodeMany {
+            decoder: self,
+            records: records.into_iter(),
         }
     }
+
+    /// Hands a decoded message back to the decoder for reuse.
+    pub fn recycle(&mut self, message: Message) {
+        self.pool.recycle(self.descriptors, self.message, message);
+    }
+}
+
+impl<'a, 'b, I> DecodeMany<'a, 'b, I> {
+    /// Hands a decoded message back to the decoder for reuse.
+    pub fn recycle(&mut self, message: Message) {
+        self.decoder.recycle(message);
+    }
+}
+
+impl<'a, 'b, I> Iterator for DecodeMany<'a, 'b, I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    type Item = error::Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        Some(self.decoder.decode(record.as_ref()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
 }
@@ -0,0 +1,555 @@
+//! Support for the `google.protobuf.Timestamp` and `google.protobuf.Duration` well-known types.
+//!
+//! The names of the wrapper types like `google.protobuf.Int32Value` are listed in `WRAPPERS`.
+//!
+//! The protobuf specification restricts both types to a range of about ten thousand years, and
+//! requires their `nanos` to be normalized.  Decoded values aren't checked by default; use a
+//! `TimePolicy` (for example via `de::Options::time_policy`) to reject or clamp invalid values.
+//!
+//! Both types are displayed in their JSON form (e.g. `"1972-01-01T10:00:20.021Z"` and `"1.5s"`),
+//! and can be parsed from it.  With the `chrono` or `time` features, both types can also be
+//! converted from and to the corresponding types of those crates.
+use std::fmt;
+use std::sync::Arc;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// The name of the `google.protobuf.Timestamp` message.
+pub const TIMESTAMP: &str = ".google.protobuf.Timestamp";
+/// The name of the `google.protobuf.Duration` message.
+pub const DURATION: &str = ".google.protobuf.Duration";
+/// The names of the wrapper messages like `google.protobuf.Int32Value`, which hold a single
+/// `value` field with number 1.
+pub const WRAPPERS: &[&str] = &[
+    ".google.protobuf.DoubleValue",
+    ".google.protobuf.FloatValue",
+    ".google.protobuf.Int64Value",
+    ".google.protobuf.UInt64Value",
+    ".google.protobuf.Int32Value",
+    ".google.protobuf.UInt32Value",
+    ".google.protobuf.BoolValue",
+    ".google.protobuf.StringValue",
+    ".google.protobuf.BytesValue",
+];
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+const MAX_NANOS: i32 = 999_999_999;
+
+/// The smallest valid timestamp, `0001-01-01T00:00:00Z`, in seconds since the Unix epoch.
+const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+/// The largest valid timestamp, `9999-12-31T23:59:59Z`, in seconds since the Unix epoch.
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+/// The largest valid duration, about ten thousand years, in seconds.
+const MAX_DURATION_SECONDS: i64 = 315_576_000_000;
+
+/// What to do with `Timestamp` and `Duration` values that violate the protobuf specification.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TimePolicy {
+    /// Accept all values as they are.
+    #[default]
+    Unchecked,
+    /// Reject invalid values with an error.
+    Reject,
+    /// Normalize values and clamp them to the valid range.
+    Clamp,
+}
+
+/// A point in time independent of any time zone (`google.protobuf.Timestamp`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch.
+    pub seconds: i64,
+    /// Non-negative fractions of a second at nanosecond resolution.
+    pub nanos: i32,
+}
+
+/// A signed span of time (`google.protobuf.Duration`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Duration {
+    /// Signed seconds of the span of time.
+    pub seconds: i64,
+    /// Fractions of a second at nanosecond resolution, with the same sign as `seconds`.
+    pub nanos: i32,
+}
+
+impl TimePolicy {
+    /// Applies this policy to every `Timestamp` and `Duration` in the given message, including
+    /// nested messages.
+    pub fn apply(
+        self,
+        descriptors: &descriptor::Descriptors,
+        descriptor: &descriptor::MessageDescriptor,
+        message: &mut value::Message,
+    ) -> error::Result<()> {
+        if self == TimePolicy::Unchecked {
+            return Ok(());
+        }
+
+        match descriptor.name() {
+            TIMESTAMP => {
+                let t = Timestamp::from_message(message)?.checked(self)?;
+                set_time(message, t.seconds, t.nanos);
+            }
+            DURATION => {
+                let d = Duration::from_message(message)?.checked(self)?;
+                set_time(message, d.seconds, d.nanos);
+            }
+            _ => {
+                for (&number, field) in &mut message.fields {
+                    let nested = match descriptor.field_by_number(number) {
+                        Some(f) => match f.field_type(descriptors) {
+                            descriptor::FieldType::Message(m) => m,
+                            _ => continue,
+                        },
+                        None => continue,
+                    };
+
+                    match *field {
+                        value::Field::Singular(Some(value::Value::Message(ref mut m))) => {
+                            self.apply(descriptors, nested, Arc::make_mut(m))?
+                        }
+                        value::Field::Repeated(ref mut vs) => {
+                            for v in vs.iter_mut() {
+                                if let value::Value::Message(ref mut m) = *v {
+                                    self.apply(descriptors, nested, Arc::make_mut(m))?;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Timestamp {
+    /// Reads a timestamp from a `google.protobuf.Timestamp` message.
+    pub fn from_message(message: &value::Message) -> error::Result<Timestamp> {
+        let (seconds, nanos) = get_time(message)?;
+        Ok(Timestamp { seconds, nanos })
+    }
+
+    /// Creates a `google.protobuf.Timestamp` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set_time(&mut message, self.seconds, self.nanos);
+        message
+    }
+
+    /// Whether this timestamp is normalized and within the range allowed by the specification.
+    pub fn is_valid(&self) -> bool {
+        self.seconds >= MIN_TIMESTAMP_SECONDS
+            && self.seconds <= MAX_TIMESTAMP_SECONDS
+            && self.nanos >= 0
+            && self.nanos <= MAX_NANOS
+    }
+
+    /// Returns this timestamp with its nanos carried over into its seconds where necessary.
+    pub fn normalized(&self) -> Timestamp {
+        let nanos = i64::from(self.nanos);
+        let seconds = self
+            .seconds
+            .saturating_add(nanos.div_euclid(NANOS_PER_SECOND));
+        Timestamp {
+            seconds,
+            nanos: nanos.rem_euclid(NANOS_PER_SECOND) as i32,
+        }
+    }
+
+    /// Returns this timestamp normalized and clamped to the range allowed by the specification.
+    pub fn clamped(&self) -> Timestamp {
+        let t = self.normalized();
+        if t.seconds < MIN_TIMESTAMP_SECONDS {
+            Timestamp {
+                seconds: MIN_TIMESTAMP_SECONDS,
+                nanos: 0,
+            }
+        } else if t.seconds > MAX_TIMESTAMP_SECONDS {
+            Timestamp {
+                seconds: MAX_TIMESTAMP_SECONDS,
+                nanos: MAX_NANOS,
+            }
+        } else {
+            t
+        }
+    }
+
+    /// Applies the given policy to this timestamp.
+    pub fn checked(&self, policy: TimePolicy) -> error::Result<Timestamp> {
+        match policy {
+            TimePolicy::Unchecked => Ok(*self),
+            TimePolicy::Reject if self.is_valid() => Ok(*self),
+            TimePolicy::Reject => Err(out_of_range(TIMESTAMP)),
+            TimePolicy::Clamp => Ok(self.clamped()),
+        }
+    }
+
+    /// Parses an RFC 3339 timestamp like `"1972-01-01T10:00:20.021-05:00"`.
+    ///
+    /// Returns `None` if the string is malformed or outside of the range allowed by the
+    /// specification.
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let b = s.as_bytes();
+        if b.len() < 20
+            || b[4] != b'-'
+            || b[7] != b'-'
+            || (b[10] != b'T' && b[10] != b't')
+            || b[13] != b':'
+            || b[16] != b':'
+        {
+            return None;
+        }
+        let year = parse_digits(&s[0..4])?;
+        let month = parse_digits(&s[5..7])?;
+        let day = parse_digits(&s[8..10])?;
+        let hour = parse_digits(&s[11..13])?;
+        let minute = parse_digits(&s[14..16])?;
+        let second = parse_digits(&s[17..19])?;
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        let (nanos, rest) = parse_fraction(&s[19..])?;
+        let offset = match rest {
+            "Z" | "z" => 0,
+            _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+                let hours = parse_digits(&rest[1..3])?;
+                let minutes = parse_digits(&rest[4..6])?;
+                let offset = hours * 3600 + minutes * 60;
+                match rest.as_bytes()[0] {
+                    b'+' => offset,
+                    b'-' => -offset,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        let days = days_from_civil(year, month, day);
+        let timestamp = Timestamp {
+            seconds: days * 86_400 + hour * 3600 + minute * 60 + second - offset,
+            nanos,
+        };
+        if timestamp.is_valid() {
+            Some(timestamp)
+        } else {
+            None
+        }
+    }
+
+    /// Converts this timestamp to a `chrono` date time.
+    #[cfg(feature = "chrono")]
+    pub fn to_chrono(&self) -> error::Result<chrono::DateTime<chrono::Utc>> {
+        let t = self.normalized();
+        chrono::DateTime::from_timestamp(t.seconds, t.nanos as u32)
+            .ok_or_else(|| out_of_range(TIMESTAMP))
+    }
+
+    /// Creates a timestamp from a `chrono` date time.
+    #[cfg(feature = "chrono")]
+    pub fn from_chrono(date_time: &chrono::DateTime<chrono::Utc>) -> Timestamp {
+        Timestamp {
+            seconds: date_time.timestamp(),
+            nanos: date_time.timestamp_subsec_nanos() as i32,
+        }
+    }
+
+    /// Converts this timestamp to a `time` date time.
+    #[cfg(feature = "time")]
+    pub fn to_time(&self) -> error::Result<time::OffsetDateTime> {
+        let nanos =
+            i128::from(self.seconds) * i128::from(NANOS_PER_SECOND) + i128::from(self.nanos);
+        time::OffsetDateTime::from_unix_timestamp_nanos(nanos).map_err(|_| out_of_range(TIMESTAMP))
+    }
+
+    /// Creates a timestamp from a `time` date time.
+    #[cfg(feature = "time")]
+    pub fn from_time(date_time: &time::OffsetDateTime) -> Timestamp {
+        Timestamp {
+            seconds: date_time.unix_timestamp(),
+            nanos: date_time.nanosecond() as i32,
+        }
+    }
+}
+
+impl Duration {
+    /// Reads a duration from a `google.protobuf.Duration` message.
+    pub fn from_message(message: &value::Message) -> error::Result<Duration> {
+        let (seconds, nanos) = get_time(message)?;
+        Ok(Duration { seconds, nanos })
+    }
+
+    /// Creates a `google.protobuf.Duration` message given its descriptor.
+    pub fn to_message(&self, descriptor: &descriptor::MessageDescriptor) -> value::Message {
+        let mut message = value::Message::new(descriptor);
+        set_time(&mut message, self.seconds, self.nanos);
+        message
+    }
+
+    /// Whether this duration is normalized and within the range allowed by the specification.
+    pub fn is_valid(&self) -> bool {
+        self.seconds >= -MAX_DURATION_SECONDS
+            && self.seconds <= MAX_DURATION_SECONDS
+            && self.nanos >= -MAX_NANOS
+            && self.nanos <= MAX_NANOS
+            && (self.seconds == 0 || self.nanos == 0 || (self.seconds > 0) == (self.nanos > 0))
+    }
+
+    /// Returns this duration with its nanos carried over into its seconds where necessary, so that
+    /// both have the same sign.
+    pub fn normalized(&self) -> Duration {
+        let nanos = i64::from(self.nanos);
+        let mut seconds = self.seconds.saturating_add(nanos / NANOS_PER_SECOND);
+        let mut nanos = nanos % NANOS_PER_SECOND;
+        if seconds > 0 && nanos < 0 {
+            seconds -= 1;
+            nanos += NANOS_PER_SECOND;
+        } else if seconds < 0 && nanos > 0 {
+            seconds += 1;
+            nanos -= NANOS_PER_SECOND;
+        }
+        Duration {
+            seconds,
+            nanos: nanos as i32,
+        }
+    }
+
+    /// Returns this duration normalized and clamped to the range allowed by the specification.
+    pub fn clamped(&self) -> Duration {
+        let d = self.normalized();
+        if d.seconds < -MAX_DURATION_SECONDS {
+            Duration {
+                seconds: -MAX_DURATION_SECONDS,
+                nanos: 0,
+            }
+        } else if d.seconds > MAX_DURATION_SECONDS {
+            Duration {
+                seconds: MAX_DURATION_SECONDS,
+                nanos: 0,
+            }
+        } else {
+            d
+        }
+    }
+
+    /// Applies the given policy to this duration.
+    pub fn checked(&self, policy: TimePolicy) -> error::Result<Duration> {
+        match policy {
+            TimePolicy::Unchecked => Ok(*self),
+            TimePolicy::Reject if self.is_valid() => Ok(*self),
+            TimePolicy::Reject => Err(out_of_range(DURATION)),
+            TimePolicy::Clamp => Ok(self.clamped()),
+        }
+    }
+
+    /// Parses a duration in seconds with an `s` suffix, like `"-1.5s"`.
+    ///
+    /// Returns `None` if the string is malformed or outside of the range allowed by the
+    /// specification.
+    pub fn parse(s: &str) -> Option<Duration> {
+        let s = s.strip_suffix('s')?;
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let end = s.find('.').unwrap_or(s.len());
+        let seconds = parse_digits(&s[..end])?;
+        let (nanos, rest) = parse_fraction(&s[end..])?;
+        if !rest.is_empty() {
+            return None;
+        }
+
+        let duration = if negative {
+            Duration {
+                seconds: -seconds,
+                nanos: -nanos,
+            }
+        } else {
+            Duration { seconds, nanos }
+        };
+        if duration.is_valid() {
+            Some(duration)
+        } else {
+            None
+        }
+    }
+
+    /// Converts this duration to a `chrono` time delta.
+    #[cfg(feature = "chrono")]
+    pub fn to_chrono(&self) -> error::Result<chrono::TimeDelta> {
+        // chrono wants non-negative nanos, even for negative deltas
+        let nanos = i64::from(self.nanos);
+        let seconds = self.seconds.checked_add(nanos.div_euclid(NANOS_PER_SECOND));
+        seconds
+            .and_then(|s| chrono::TimeDelta::new(s, nanos.rem_euclid(NANOS_PER_SECOND) as u32))
+            .ok_or_else(|| out_of_range(DURATION))
+    }
+
+    /// Creates a duration from a `chrono` time delta.
+    #[cfg(feature = "chrono")]
+    pub fn from_chrono(delta: &chrono::TimeDelta) -> Duration {
+        Duration {
+            seconds: delta.num_seconds(),
+            nanos: delta.subsec_nanos(),
+        }
+    }
+
+    /// Converts this duration to a `time` duration.
+    #[cfg(feature = "time")]
+    pub fn to_time(&self) -> error::Result<time::Duration> {
+        time::Duration::seconds(self.seconds)
+            .checked_add(time::Duration::nanoseconds(i64::from(self.nanos)))
+            .ok_or_else(|| out_of_range(DURATION))
+    }
+
+    /// Creates a duration from a `time` duration.
+    #[cfg(feature = "time")]
+    pub fn from_time(duration: &time::Duration) -> Duration {
+        Duration {
+            seconds: duration.whole_seconds(),
+            nanos: duration.subsec_nanoseconds(),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let t = self.normalized();
+        let (year, month, day) = civil_from_days(t.seconds.div_euclid(86_400));
+        let second_of_day = t.seconds.rem_euclid(86_400);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            second_of_day / 3600,
+            second_of_day / 60 % 60,
+            second_of_day % 60
+        )?;
+        write_fraction(f, t.nanos)?;
+        f.write_str("Z")
+    }
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.normalized();
+        if d.seconds < 0 || d.nanos < 0 {
+            f.write_str("-")?;
+        }
+        write!(f, "{}", d.seconds.unsigned_abs())?;
+        write_fraction(f, d.nanos.abs())?;
+        f.write_str("s")
+    }
+}
+
+/// Writes fractions of a second with 0, 3, 6 or 9 digits, as the JSON mapping prescribes.
+fn write_fraction(f: &mut fmt::Formatter, nanos: i32) -> fmt::Result {
+    if nanos == 0 {
+        Ok(())
+    } else if nanos % 1_000_000 == 0 {
+        write!(f, ".{:03}", nanos / 1_000_000)
+    } else if nanos % 1_000 == 0 {
+        write!(f, ".{:06}", nanos / 1_000)
+    } else {
+        write!(f, ".{:09}", nanos)
+    }
+}
+
+/// Parses an optional fraction of a second with up to 9 digits, returning the rest of the string.
+fn parse_fraction(s: &str) -> Option<(i32, &str)> {
+    match s.strip_prefix('.') {
+        Some(s) => {
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            if end == 0 || end > 9 {
+                return None;
+            }
+            let nanos = parse_digits(&s[..end])? * 10i64.pow(9 - end as u32);
+            Some((nanos as i32, &s[end..]))
+        }
+        None => Some((0, s)),
+    }
+}
+
+fn parse_digits(s: &str) -> Option<i64> {
+    if s.is_empty() || s.len() > 12 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        None
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The number of days since the Unix epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date in the proleptic Gregorian calendar of a number of days since the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+fn get_time(message: &value::Message) -> error::Result<(i64, i32)> {
+    let seconds = match message.fields.get(&1) {
+        None | Some(&value::Field::Singular(None)) => 0,
+        Some(&value::Field::Singular(Some(value::Value::I64(v)))) => v,
+        Some(_) => return Err(bad_field_value("seconds")),
+    };
+    let nanos = match message.fields.get(&2) {
+        None | Some(&value::Field::Singular(None)) => 0,
+        Some(&value::Field::Singular(Some(value::Value::I32(v)))) => v,
+        Some(_) => return Err(bad_field_value("nanos")),
+    };
+    Ok((seconds, nanos))
+}
+
+fn set_time(message: &mut value::Message, seconds: i64, nanos: i32) {
+    let seconds = value::Field::Singular(Some(value::Value::I64(seconds)));
+    let nanos = value::Field::Singular(Some(value::Value::I32(nanos)));
+    message.fields.insert(1, seconds);
+    message.fields.insert(2, nanos);
+}
+
+fn bad_field_value(name: &str) -> error::Error {
+    error::Error::BadFieldValue {
+        field: name.to_owned(),
+    }
+}
+
+fn out_of_range(name: &str) -> error::Error {
+    error::Error::OutOfRange {
+        name: name.to_owned(),
+    }
+}
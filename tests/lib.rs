@@ -1,11 +1,37 @@
+#[cfg(feature = "arrow")]
+extern crate arrow_array;
+#[cfg(feature = "arrow")]
+extern crate arrow_schema;
+#[cfg(feature = "arena")]
+extern crate bumpalo;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "parquet")]
+extern crate parquet;
+#[cfg(feature = "postgres")]
+extern crate postgres_types;
 extern crate protobuf;
 extern crate serde;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+extern crate serde_path_to_error;
 extern crate serde_value;
+#[cfg(feature = "time")]
+extern crate time;
+#[cfg(feature = "reflection")]
+extern crate tonic_reflection;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "uuid")]
+extern crate uuid;
 
 extern crate serde_protobuf;
 
 use std::collections;
 use std::fs;
+use std::sync::Arc;
 
 use serde_protobuf::de;
 use serde_protobuf::descriptor;
@@ -148,13 +174,17 @@ macro_rules! assert_subset {
     }};
 }
 
+fn descriptors() -> descriptor::Descriptors {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+    descriptor::Descriptors::from_proto(&proto)
+}
+
 macro_rules! roundtrip {
     ($t:ty, $v:ident, $s:stmt) => {{
         use serde::de::Deserialize;
 
-        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
-        let proto = protobuf::parse_from_reader(&mut file).unwrap();
-        let descriptors = descriptor::Descriptors::from_proto(&proto);
+        let descriptors = descriptors();
 
         let mut $v = <$t>::new();
         $s;
@@ -286,6 +316,41 @@ fn roundtrip_recursive() {
     )
 }
 
+#[test]
+fn deserialize_emit_defaults() {
+    use serde::de::Deserialize;
+
+    let descriptors = descriptors();
+    let name = ".protobuf_unittest.TestAllTypes";
+
+    let input = protobuf::CodedInputStream::from_bytes(&[]);
+    let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input).unwrap();
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_subset!(
+        value!(map {
+            (str: "optional_int32") => (none),
+            (str: "optional_nested_enum") => (none)
+        }),
+        v
+    );
+
+    let input = protobuf::CodedInputStream::from_bytes(&[]);
+    let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input)
+        .unwrap()
+        .with_options(de::Options::new().emit_defaults(true));
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_subset!(
+        value!(map {
+            (str: "optional_int32") => (some i32: 0),
+            (str: "optional_string") => (some str: ""),
+            (str: "optional_nested_enum") => (some str: "FOO"),
+            (str: "optional_nested_message") => (none),
+            (str: "default_int32") => (some i32: 41)
+        }),
+        v
+    );
+}
+
 macro_rules! check_roundtrip_singular {
     ($id:ident, $field:ident, $setter:ident, $v:expr, $($p:tt)+) => {
         #[test]
@@ -465,3 +530,7658 @@ check_roundtrip_repeated!(
     [vec![1, 2, 3], vec![2, 3, 4]],
     byte_buf
 );
+
+#[test]
+fn pooled_decode_reuses_messages() {
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_nested_message().push_default().set_bb(2);
+    v.mut_repeated_nested_message().push_default().set_bb(3);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut pool = value::MessagePool::new();
+    for _ in 0..2 {
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut m = pool.get(message);
+        m.merge_from_pooled(&descriptors, message, &mut input, &mut pool)
+            .unwrap();
+
+        match m.fields[&48] {
+            value::Field::Repeated(ref r) => assert_eq!(2, r.len()),
+            ref f => panic!("unexpected field {:?}", f),
+        }
+
+        pool.recycle(&descriptors, message, m);
+        // The outer message and its three nested messages are all retained.
+        assert_eq!(4, pool.len());
+    }
+}
+
+#[test]
+fn deep_size_of_counts_nested_data() {
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let decode = |v: &protobuf_unittest::unittest::TestAllTypes| {
+        let bytes = v.write_to_bytes().unwrap();
+        let mut m = value::Message::new(message);
+        m.merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+        m
+    };
+
+    let empty = decode(&protobuf_unittest::unittest::TestAllTypes::new());
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("x".repeat(1000));
+    let with_string = decode(&v);
+    assert!(with_string.deep_size_of() >= empty.deep_size_of() + 1000);
+
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_bytes().push(vec![0; 1000]);
+    let with_nested = decode(&v);
+    assert!(with_nested.deep_size_of() >= with_string.deep_size_of() + 1000);
+}
+
+#[test]
+fn encode_roundtrip() {
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_int32(-1);
+    v.set_optional_sint64(-2);
+    v.set_optional_fixed32(3);
+    v.set_optional_double(4.5);
+    v.set_optional_bool(true);
+    v.set_optional_string("hello".to_owned());
+    v.set_optional_nested_enum(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAZ);
+    v.mut_optional_nested_message().set_bb(6);
+    v.mut_repeated_nested_message().push_default().set_bb(7);
+    v.mut_repeated_nested_message().push_default().set_bb(8);
+    v.mut_repeated_bytes().push(vec![9, 10]);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut m = value::Message::new(message);
+    m.merge_from(
+        &descriptors,
+        message,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+
+    let size = m.compute_size(&descriptors, message).unwrap();
+    let encoded = m.write_to_bytes(&descriptors, message).unwrap();
+    assert_eq!(size as usize, encoded.len());
+    assert_eq!(size, m.cached_size());
+
+    let decoded: protobuf_unittest::unittest::TestAllTypes =
+        protobuf::parse_from_bytes(&encoded).unwrap();
+    assert_eq!(v.get_optional_int32(), decoded.get_optional_int32());
+    assert_eq!(v.get_optional_sint64(), decoded.get_optional_sint64());
+    assert_eq!(v.get_optional_fixed32(), decoded.get_optional_fixed32());
+    assert_eq!(v.get_optional_double(), decoded.get_optional_double());
+    assert_eq!(v.get_optional_bool(), decoded.get_optional_bool());
+    assert_eq!(v.get_optional_string(), decoded.get_optional_string());
+    assert_eq!(
+        v.get_optional_nested_enum(),
+        decoded.get_optional_nested_enum()
+    );
+    assert_eq!(
+        v.get_optional_nested_message(),
+        decoded.get_optional_nested_message()
+    );
+    assert_eq!(
+        v.get_repeated_nested_message(),
+        decoded.get_repeated_nested_message()
+    );
+    assert_eq!(v.get_repeated_bytes(), decoded.get_repeated_bytes());
+}
+
+#[test]
+fn encode_skip_defaults() {
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let skip = value::EncodeOptions::new().skip_defaults(true);
+
+    let mut m = value::Message::new(message);
+    assert!(!m.write_to_bytes(&descriptors, message).unwrap().is_empty());
+    assert!(m
+        .write_to_bytes_with_options(&descriptors, message, &skip)
+        .unwrap()
+        .is_empty());
+
+    m.fields
+        .insert(1, value::Field::Singular(Some(value::Value::I32(0))));
+    assert!(m
+        .write_to_bytes_with_options(&descriptors, message, &skip)
+        .unwrap()
+        .is_empty());
+
+    m.fields
+        .insert(1, value::Field::Singular(Some(value::Value::I32(5))));
+    let size = m
+        .compute_size_with_options(&descriptors, message, &skip)
+        .unwrap();
+    let bytes = m
+        .write_to_bytes_with_options(&descriptors, message, &skip)
+        .unwrap();
+    assert_eq!(vec![8, 5], bytes);
+    assert_eq!(2, size);
+}
+
+#[test]
+fn encode_bad_field_value() {
+    use serde_protobuf::{error, value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut m = value::Message::new(message);
+    m.fields.insert(
+        1,
+        value::Field::Singular(Some(value::Value::String("nope".to_owned()))),
+    );
+
+    match m.write_to_bytes(&descriptors, message) {
+        Err(error::Error::BadFieldValue { field }) => assert_eq!("optional_int32", field),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn encode_into_buf() {
+    use bytes::BufMut;
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("hello".to_owned());
+    v.mut_optional_nested_message().set_bb(1);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut m = value::Message::new(message);
+    m.merge_from(
+        &descriptors,
+        message,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+    let expected = m.write_to_bytes(&descriptors, message).unwrap();
+
+    let buf = m.write_to_bytes_mut(&descriptors, message).unwrap();
+    assert_eq!(&expected[..], &buf[..]);
+
+    let mut buf = bytes::BytesMut::from(&b"prefix"[..]);
+    m.write_to_buf(&descriptors, message, &mut buf).unwrap();
+    assert_eq!(b"prefix", &buf[..6]);
+    assert_eq!(&expected[..], &buf[6..]);
+
+    // A buffer whose spare capacity is split in chunks that are too small to encode into
+    let mut head = [0; 3];
+    let mut tail = vec![0; expected.len()];
+    {
+        let mut chain = (&mut head[..]).chain_mut(&mut tail[..]);
+        m.write_to_buf(&descriptors, message, &mut chain).unwrap();
+    }
+    assert_eq!(
+        &expected[..],
+        &[&head[..], &tail[..expected.len() - 3]].concat()[..]
+    );
+
+    // The encode options apply, e.g. to leave out the declared defaults filled in by
+    // `Message::new`
+    let skip = value::EncodeOptions::new().skip_defaults(true);
+    let expected = m
+        .write_to_bytes_with_options(&descriptors, message, &skip)
+        .unwrap();
+    assert!(expected.len() < m.write_to_bytes(&descriptors, message).unwrap().len());
+    let buf = m
+        .write_to_bytes_mut_with_options(&descriptors, message, &skip)
+        .unwrap();
+    assert_eq!(&expected[..], &buf[..]);
+    let mut buf = bytes::BytesMut::with_capacity(expected.len());
+    m.write_to_buf_with_options(&descriptors, message, &skip, &mut buf)
+        .unwrap();
+    assert_eq!(&expected[..], &buf[..]);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn decode_from_chunked_bufs() {
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("hello".to_owned());
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_int32().push(7);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut expected = value::Message::new(message);
+    expected
+        .merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+
+    let chunks = bytes
+        .chunks(3)
+        .map(bytes::Bytes::copy_from_slice)
+        .collect::<collections::VecDeque<_>>();
+    let mut chunked = value::Message::new(message);
+    chunked
+        .merge_from_bufs(&descriptors, message, chunks)
+        .unwrap();
+    assert_eq!(format!("{:?}", expected), format!("{:?}", chunked));
+
+    let mut single = value::Message::new(message);
+    single
+        .merge_from_buf(&descriptors, message, &bytes[..])
+        .unwrap();
+    assert_eq!(format!("{:?}", expected), format!("{:?}", single));
+}
+
+#[test]
+fn decode_status_details() {
+    use protobuf::Message;
+    use serde_protobuf::{status, value};
+
+    let descriptors = descriptors();
+
+    let mut detail = protobuf_unittest::unittest::TestAllTypes::new();
+    detail.set_optional_int32(42);
+    let detail = detail.write_to_bytes().unwrap();
+
+    let mut any = protobuf::well_known_types::Any::new();
+    any.set_type_url("type.googleapis.com/protobuf_unittest.TestAllTypes".to_owned());
+    any.set_value(detail.clone());
+    let mut unknown_any = protobuf::well_known_types::Any::new();
+    unknown_any.set_type_url("type.googleapis.com/google.rpc.BadRequest".to_owned());
+    unknown_any.set_value(vec![1, 2, 3]);
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        output.write_int32(1, 3).unwrap();
+        output.write_string(2, "invalid argument").unwrap();
+        output.write_message(3, &any).unwrap();
+        output.write_message(3, &unknown_any).unwrap();
+        output.flush().unwrap();
+    }
+
+    let status = status::Status::decode(&descriptors, &bytes).unwrap();
+    assert_eq!(3, status.code);
+    assert_eq!("invalid argument", status.message);
+    assert_eq!(2, status.details.len());
+
+    assert_eq!(
+        ".protobuf_unittest.TestAllTypes",
+        status.details[0].type_name()
+    );
+    assert_eq!(detail, status.details[0].value);
+    match status.details[0].message.as_ref().unwrap().fields[&1] {
+        value::Field::Singular(Some(value::Value::I32(v))) => assert_eq!(42, v),
+        ref f => panic!("unexpected field {:?}", f),
+    }
+
+    assert_eq!(vec![1, 2, 3], status.details[1].value);
+    assert!(status.details[1].message.is_none());
+}
+
+fn google_type_descriptors() -> descriptor::Descriptors {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+
+    let mut descriptors = descriptor::Descriptors::new();
+
+    let mut money = descriptor::MessageDescriptor::new(".google.type.Money");
+    let string = InternalFieldType::String;
+    money.add_field(FieldDescriptor::new(
+        "currency_code",
+        1,
+        FieldLabel::Optional,
+        string,
+        None,
+    ));
+    money.add_field(FieldDescriptor::new(
+        "units",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int64,
+        None,
+    ));
+    money.add_field(FieldDescriptor::new(
+        "nanos",
+        3,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    descriptors.add_message(money);
+
+    let mut date = descriptor::MessageDescriptor::new(".google.type.Date");
+    date.add_field(FieldDescriptor::new(
+        "year",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    date.add_field(FieldDescriptor::new(
+        "month",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    date.add_field(FieldDescriptor::new(
+        "day",
+        3,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    descriptors.add_message(date);
+
+    let mut invoice = descriptor::MessageDescriptor::new(".test.Invoice");
+    let money = InternalFieldType::UnresolvedMessage(".google.type.Money".to_owned());
+    let date = InternalFieldType::UnresolvedMessage(".google.type.Date".to_owned());
+    invoice.add_field(FieldDescriptor::new(
+        "total",
+        1,
+        FieldLabel::Optional,
+        money,
+        None,
+    ));
+    invoice.add_field(FieldDescriptor::new(
+        "due",
+        2,
+        FieldLabel::Optional,
+        date,
+        None,
+    ));
+    descriptors.add_message(invoice);
+
+    descriptors.resolve_refs();
+    descriptors
+}
+
+#[test]
+fn google_type_conversions() {
+    use serde::de::Deserialize;
+    use serde_protobuf::{error, google_type, value};
+
+    let descriptors = google_type_descriptors();
+    let money_descriptor = descriptors.message_by_name(google_type::MONEY).unwrap();
+    let date_descriptor = descriptors.message_by_name(google_type::DATE).unwrap();
+    let invoice_descriptor = descriptors.message_by_name(".test.Invoice").unwrap();
+
+    let money = google_type::Money {
+        currency_code: "USD".to_owned(),
+        units: -12,
+        nanos: -340_000_000,
+    };
+    assert_eq!("-12.34 USD", money.to_string());
+    let message = money.to_message(money_descriptor);
+    assert_eq!(money, google_type::Money::from_message(&message).unwrap());
+
+    // Amounts with nanos out of range or with the opposite sign of the units are invalid
+    for &(units, nanos) in &[(1, -5), (-1, 5), (0, 1_000_000_000), (0, -1_000_000_000)] {
+        let invalid = google_type::Money {
+            currency_code: "USD".to_owned(),
+            units,
+            nanos,
+        };
+        match invalid.amount() {
+            Err(error::Error::OutOfRange { .. }) => (),
+            other => panic!("unexpected amount of {:?}: {:?}", invalid, other),
+        }
+        let message = invalid.to_message(money_descriptor);
+        assert!(google_type::Money::from_message(&message).is_err());
+    }
+
+    let date = google_type::Date {
+        year: 2019,
+        month: 2,
+        day: 28,
+    };
+    let mut invoice = value::Message::new(invoice_descriptor);
+    invoice.fields.insert(
+        1,
+        value::Field::Singular(Some(value::Value::Message(Arc::new(message)))),
+    );
+    invoice.fields.insert(
+        2,
+        value::Field::Singular(Some(value::Value::Message(Arc::new(
+            date.to_message(date_descriptor),
+        )))),
+    );
+    let bytes = invoice
+        .write_to_bytes(&descriptors, invoice_descriptor)
+        .unwrap();
+
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, invoice_descriptor, input)
+        .with_options(de::Options::new().google_types(true));
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_subset!(
+        value!(map {
+            (str: "total") => (some str: "-12.34 USD"),
+            (str: "due") => (some str: "2019-02-28")
+        }),
+        v
+    );
+}
+
+#[test]
+fn field_mask_values() {
+    use serde::de::Deserialize;
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::{error, field_mask, value};
+
+    let mut descriptors = google_type_descriptors();
+    let mut mask = descriptor::MessageDescriptor::new(field_mask::FIELD_MASK);
+    mask.add_field(FieldDescriptor::new(
+        "paths",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::String,
+        None,
+    ));
+    descriptors.add_message(mask);
+    let mut request = descriptor::MessageDescriptor::new(".test.UpdateInvoiceRequest");
+    let mask = InternalFieldType::UnresolvedMessage(field_mask::FIELD_MASK.to_owned());
+    request.add_field(FieldDescriptor::new(
+        "update_mask",
+        1,
+        FieldLabel::Optional,
+        mask,
+        None,
+    ));
+    descriptors.add_message(request);
+    descriptors.resolve_refs();
+
+    let request = descriptors
+        .message_by_name(".test.UpdateInvoiceRequest")
+        .unwrap();
+    let invoice = descriptors.message_by_name(".test.Invoice").unwrap();
+
+    let mut v = protobuf::well_known_types::FieldMask::new();
+    v.mut_paths().push("total.units".to_owned());
+    v.mut_paths().push("due".to_owned());
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        output.write_message(1, &v).unwrap();
+        output.flush().unwrap();
+    }
+
+    let mut m = value::Message::new(request);
+    m.merge_from(
+        &descriptors,
+        request,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+    let mask = match m.fields[&1] {
+        value::Field::Singular(Some(value::Value::FieldMask(ref mask))) => mask.clone(),
+        ref f => panic!("unexpected field {:?}", f),
+    };
+    assert_eq!(field_mask::FieldMask::new(vec!["total.units", "due"]), mask);
+    assert_eq!(bytes, m.write_to_bytes(&descriptors, request).unwrap());
+
+    mask.validate(&descriptors, invoice).unwrap();
+    match field_mask::FieldMask::new(vec!["total.bogus"]).validate(&descriptors, invoice) {
+        Err(error::Error::BadFieldMaskPath { path }) => assert_eq!("total.bogus", path),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(field_mask::FieldMask::new(vec!["due.year.x"])
+        .validate(&descriptors, invoice)
+        .is_err());
+
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, request, input);
+    let v = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_subset!(
+        value!(map {
+            (str: "update_mask") => (some seq [(str: "total.units"), (str: "due")])
+        }),
+        v
+    );
+}
+
+fn well_known_descriptors() -> descriptor::Descriptors {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::well_known;
+
+    let mut descriptors = descriptor::Descriptors::new();
+    for name in &[well_known::TIMESTAMP, well_known::DURATION] {
+        let mut m = descriptor::MessageDescriptor::new(*name);
+        m.add_field(FieldDescriptor::new(
+            "seconds",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::Int64,
+            None,
+        ));
+        m.add_field(FieldDescriptor::new(
+            "nanos",
+            2,
+            FieldLabel::Optional,
+            InternalFieldType::Int32,
+            None,
+        ));
+        descriptors.add_message(m);
+    }
+
+    let mut event = descriptor::MessageDescriptor::new(".test.Event");
+    let timestamp = InternalFieldType::UnresolvedMessage(well_known::TIMESTAMP.to_owned());
+    let duration = InternalFieldType::UnresolvedMessage(well_known::DURATION.to_owned());
+    event.add_field(FieldDescriptor::new(
+        "at",
+        1,
+        FieldLabel::Optional,
+        timestamp,
+        None,
+    ));
+    event.add_field(FieldDescriptor::new(
+        "took",
+        2,
+        FieldLabel::Repeated,
+        duration,
+        None,
+    ));
+    descriptors.add_message(event);
+
+    descriptors.resolve_refs();
+    descriptors
+}
+
+#[test]
+fn timestamp_duration_normalization() {
+    use serde_protobuf::well_known::{Duration, TimePolicy, Timestamp};
+
+    let t = Timestamp {
+        seconds: 10,
+        nanos: -1,
+    };
+    assert!(!t.is_valid());
+    assert_eq!(
+        Timestamp {
+            seconds: 9,
+            nanos: 999_999_999
+        },
+        t.normalized()
+    );
+    assert!(t.checked(TimePolicy::Reject).is_err());
+    assert_eq!(t, t.checked(TimePolicy::Unchecked).unwrap());
+
+    let t = Timestamp {
+        seconds: i64::MAX,
+        nanos: 0,
+    };
+    assert_eq!(253_402_300_799, t.clamped().seconds);
+
+    let d = Duration {
+        seconds: 1,
+        nanos: -500_000_000,
+    };
+    assert!(!d.is_valid());
+    assert_eq!(
+        Duration {
+            seconds: 0,
+            nanos: 500_000_000
+        },
+        d.normalized()
+    );
+    let d = Duration {
+        seconds: -1,
+        nanos: -1_500_000_000,
+    };
+    assert_eq!(
+        Duration {
+            seconds: -2,
+            nanos: -500_000_000
+        },
+        d.clamped()
+    );
+    assert!(d.clamped().is_valid());
+}
+
+#[test]
+fn deserialize_time_policy() {
+    use serde::de::Deserialize;
+    use serde_protobuf::value;
+    use serde_protobuf::well_known::{self, Duration, TimePolicy, Timestamp};
+
+    let descriptors = well_known_descriptors();
+    let event = descriptors.message_by_name(".test.Event").unwrap();
+    let timestamp = descriptors.message_by_name(well_known::TIMESTAMP).unwrap();
+    let duration = descriptors.message_by_name(well_known::DURATION).unwrap();
+
+    let mut m = value::Message::new(event);
+    let at = Timestamp {
+        seconds: -100_000_000_000,
+        nanos: 0,
+    };
+    let took = Duration {
+        seconds: 1,
+        nanos: 2_000_000_000,
+    };
+    m.fields.insert(
+        1,
+        value::Field::Singular(Some(value::Value::Message(Arc::new(
+            at.to_message(timestamp),
+        )))),
+    );
+    m.fields.insert(
+        2,
+        value::Field::Repeated(
+            vec![value::Value::Message(Arc::new(took.to_message(duration)))]
+                .into_iter()
+                .collect(),
+        ),
+    );
+    let bytes = m.write_to_bytes(&descriptors, event).unwrap();
+
+    let deserialize = |policy| {
+        let input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut deserializer = de::Deserializer::new(&descriptors, event, input)
+            .with_options(de::Options::new().time_policy(policy));
+        serde_value::Value::deserialize(&mut deserializer)
+    };
+
+    assert!(deserialize(TimePolicy::Unchecked).is_ok());
+    assert!(deserialize(TimePolicy::Reject).is_err());
+    assert_subset!(
+        value!(map {
+            (str: "at") => (some map {
+                (str: "seconds") => (some i64: -62_135_596_800),
+                (str: "nanos") => (some i32: 0)
+            }),
+            (str: "took") => (seq [(map {
+                (str: "seconds") => (some i64: 3),
+                (str: "nanos") => (some i32: 0)
+            })])
+        }),
+        deserialize(TimePolicy::Clamp).unwrap()
+    );
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_conversions() {
+    use serde_protobuf::google_type::{Date, TimeOfDay};
+    use serde_protobuf::well_known::{Duration, Timestamp};
+
+    let t = Timestamp {
+        seconds: -1,
+        nanos: 500_000_000,
+    };
+    let dt = t.to_chrono().unwrap();
+    assert_eq!("1969-12-31 23:59:59.500 UTC", dt.to_string());
+    assert_eq!(t, Timestamp::from_chrono(&dt));
+
+    let d = Duration {
+        seconds: -1,
+        nanos: -500_000_000,
+    };
+    let delta = d.to_chrono().unwrap();
+    assert_eq!(-1500, delta.num_milliseconds());
+    assert_eq!(d, Duration::from_chrono(&delta));
+
+    let date = Date {
+        year: 2020,
+        month: 2,
+        day: 29,
+    };
+    assert_eq!(date, Date::from_chrono(&date.to_chrono().unwrap()));
+    assert!(Date { day: 30, ..date }.to_chrono().is_err());
+
+    let time = TimeOfDay {
+        hours: 13,
+        minutes: 45,
+        seconds: 30,
+        nanos: 5,
+    };
+    assert_eq!(time, TimeOfDay::from_chrono(&time.to_chrono().unwrap()));
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn time_conversions() {
+    use serde_protobuf::google_type::{Date, TimeOfDay};
+    use serde_protobuf::well_known::{Duration, Timestamp};
+
+    let t = Timestamp {
+        seconds: -1,
+        nanos: 500_000_000,
+    };
+    let dt = t.to_time().unwrap();
+    assert_eq!(-500_000_000, dt.unix_timestamp_nanos());
+    assert_eq!(t, Timestamp::from_time(&dt));
+
+    let d = Duration {
+        seconds: -1,
+        nanos: -500_000_000,
+    };
+    let duration = d.to_time().unwrap();
+    assert_eq!(-1500, duration.whole_milliseconds());
+    assert_eq!(d, Duration::from_time(&duration));
+
+    let date = Date {
+        year: 2020,
+        month: 2,
+        day: 29,
+    };
+    assert_eq!(date, Date::from_time(&date.to_time().unwrap()));
+    assert!(Date { month: 13, ..date }.to_time().is_err());
+
+    let time = TimeOfDay {
+        hours: 13,
+        minutes: 45,
+        seconds: 30,
+        nanos: 5,
+    };
+    assert_eq!(time, TimeOfDay::from_time(&time.to_time().unwrap()));
+}
+
+#[cfg(feature = "uuid")]
+#[test]
+fn deserialize_uuid_fields() {
+    use protobuf::Message;
+    use serde::de::Deserialize;
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value;
+
+    let mut options = protobuf::descriptor::FieldOptions::new();
+    options.mut_unknown_fields().add_varint(50_000, 1);
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut m = descriptor::MessageDescriptor::new(".test.Ids");
+    m.add_field(
+        FieldDescriptor::new(
+            "id",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::Bytes,
+            None,
+        )
+        .with_options(options),
+    );
+    m.add_field(FieldDescriptor::new(
+        "owner_uuid",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Bytes,
+        None,
+    ));
+    descriptors.add_message(m);
+    descriptors.resolve_refs();
+    let ids = descriptors.message_by_name(".test.Ids").unwrap();
+
+    let id = uuid::Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+    let id_value = value::Value::from_uuid(id);
+    assert_eq!(Some(id), id_value.to_uuid());
+    assert_eq!(None, value::Value::Bytes(vec![1, 2, 3]).to_uuid());
+
+    let mut m = value::Message::new(ids);
+    m.fields
+        .insert(1, value::Field::Singular(Some(id_value.clone())));
+    m.fields.insert(2, value::Field::Singular(Some(id_value)));
+    let bytes = m.write_to_bytes(&descriptors, ids).unwrap();
+
+    let deserialize = |rule| {
+        let input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut deserializer = de::Deserializer::new(&descriptors, ids, input)
+            .with_options(de::Options::new().uuid_rule(rule));
+        serde_value::Value::deserialize(&mut deserializer).unwrap()
+    };
+
+    assert_subset!(
+        value!(map {
+            (str: "id") => (some str: "67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            (str: "owner_uuid") => (some bytes: id.as_bytes())
+        }),
+        deserialize(de::UuidRule::CustomOption(50_000))
+    );
+    assert_subset!(
+        value!(map {
+            (str: "id") => (some bytes: id.as_bytes()),
+            (str: "owner_uuid") => (some str: "67e55044-10b1-426f-9247-bb680e5fe0c8")
+        }),
+        deserialize(de::UuidRule::NameSuffix("_uuid".to_owned()))
+    );
+}
+
+#[cfg(feature = "arrow")]
+#[test]
+fn arrow_record_batch_export() {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Int32Type, Int64Type};
+    use arrow_array::Array;
+    use serde_protobuf::arrow;
+    use serde_protobuf::descriptor::{
+        EnumDescriptor, EnumValueDescriptor, FieldDescriptor, FieldLabel, InternalFieldType,
+    };
+    use serde_protobuf::value;
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut color = EnumDescriptor::new(".test.Color");
+    color.add_value(EnumValueDescriptor::new("RED", 0));
+    color.add_value(EnumValueDescriptor::new("GREEN", 1));
+    descriptors.add_enum(color);
+    let mut author = descriptor::MessageDescriptor::new(".test.Author");
+    author.add_field(FieldDescriptor::new(
+        "name",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    descriptors.add_message(author);
+    let mut row = descriptor::MessageDescriptor::new(".test.Row");
+    row.add_field(FieldDescriptor::new(
+        "id",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::Int64,
+        None,
+    ));
+    row.add_field(FieldDescriptor::new(
+        "scores",
+        2,
+        FieldLabel::Repeated,
+        InternalFieldType::Int32,
+        None,
+    ));
+    row.add_field(FieldDescriptor::new(
+        "color",
+        3,
+        FieldLabel::Optional,
+        InternalFieldType::UnresolvedEnum(".test.Color".to_owned()),
+        None,
+    ));
+    row.add_field(FieldDescriptor::new(
+        "author",
+        4,
+        FieldLabel::Optional,
+        InternalFieldType::UnresolvedMessage(".test.Author".to_owned()),
+        None,
+    ));
+    row.add_field(FieldDescriptor::new(
+        "replies",
+        5,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Row".to_owned()),
+        None,
+    ));
+    descriptors.add_message(row);
+    descriptors.resolve_refs();
+    let row = descriptors.message_by_name(".test.Row").unwrap();
+    let author = descriptors.message_by_name(".test.Author").unwrap();
+
+    let mut first = value::Message::new(row);
+    first
+        .fields
+        .insert(1, value::Field::Singular(Some(value::Value::I64(7))));
+    first.fields.insert(
+        2,
+        value::Field::Repeated(
+            vec![value::Value::I32(1), value::Value::I32(2)]
+                .into_iter()
+                .collect(),
+        ),
+    );
+    first
+        .fields
+        .insert(3, value::Field::Singular(Some(value::Value::Enum(1))));
+    let mut a = value::Message::new(author);
+    a.fields.insert(
+        1,
+        value::Field::Singular(Some(value::Value::String("ann".to_owned()))),
+    );
+    first.fields.insert(
+        4,
+        value::Field::Singular(Some(value::Value::Message(Arc::new(a)))),
+    );
+    let second = value::Message::new(row);
+
+    let batch = arrow::to_record_batch(&descriptors, row, vec![&first, &second]).unwrap();
+    let names: Vec<_> = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.name().clone())
+        .collect();
+    assert_eq!(vec!["id", "scores", "color", "author.name"], names);
+    assert_eq!(2, batch.num_rows());
+
+    let ids = batch.column(0).as_primitive::<Int64Type>();
+    assert_eq!(7, ids.value(0));
+    assert!(ids.is_null(1));
+    let scores = batch.column(1).as_list::<i32>();
+    assert_eq!(
+        &[1, 2],
+        scores
+            .value(0)
+            .as_primitive::<Int32Type>()
+            .values()
+            .as_ref()
+    );
+    assert_eq!(0, scores.value(1).len());
+    assert_eq!("GREEN", batch.column(2).as_string::<i32>().value(0));
+    assert_eq!("ann", batch.column(3).as_string::<i32>().value(0));
+    assert!(batch.column(3).is_null(1));
+
+    let mut builder = arrow::RecordBatchBuilder::new(&descriptors, row);
+    let mut bad = value::Message::new(row);
+    bad.fields
+        .insert(1, value::Field::Singular(Some(value::Value::Bool(true))));
+    assert!(builder.append(&bad).is_err());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn json_schema_generation() {
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestRequired")
+        .unwrap();
+    let schema = descriptor::to_json_schema(&descriptors, message);
+    assert_eq!(
+        "https://json-schema.org/draft/2020-12/schema",
+        schema["$schema"]
+    );
+    assert_eq!("#/$defs/protobuf_unittest.TestRequired", schema["$ref"]);
+    let def = &schema["$defs"]["protobuf_unittest.TestRequired"];
+    assert_eq!(serde_json::json!(["a", "b", "c"]), def["required"]);
+    assert_eq!(
+        serde_json::json!({ "type": "integer", "format": "int32" }),
+        def["properties"]["dummy2"]
+    );
+
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let schema = descriptor::to_json_schema(&descriptors, message);
+    let def = &schema["$defs"]["protobuf_unittest.TestAllTypes"];
+    assert!(def.get("required").is_none());
+    let properties = &def["properties"];
+    assert_eq!(
+        serde_json::json!({ "type": ["string", "integer"], "format": "int64" }),
+        properties["optionalInt64"]
+    );
+    assert_eq!(
+        serde_json::json!({ "type": "string", "contentEncoding": "base64" }),
+        properties["optionalBytes"]
+    );
+    assert_eq!(
+        serde_json::json!({ "type": "string", "enum": ["FOO", "BAR", "BAZ", "NEG"] }),
+        properties["optionalNestedEnum"]
+    );
+    assert_eq!(
+        "#/$defs/protobuf_unittest.TestAllTypes.NestedMessage",
+        properties["repeatedNestedMessage"]["items"]["$ref"]
+    );
+    assert!(
+        schema["$defs"]["protobuf_unittest.TestAllTypes.NestedMessage"]["properties"]["bb"]
+            .is_object()
+    );
+}
+
+#[test]
+fn service_descriptors() {
+    let descriptors = descriptors();
+
+    let service = descriptors
+        .service_by_name(".protobuf_unittest.TestService")
+        .unwrap();
+    let method = service.method_by_name("Foo").unwrap();
+    assert_eq!(".protobuf_unittest.FooRequest", method.input_type_name());
+    assert_eq!(
+        ".protobuf_unittest.FooResponse",
+        method.output_type(&descriptors).unwrap().name()
+    );
+    assert!(!method.client_streaming() && !method.server_streaming());
+}
+
+#[test]
+fn http_rule_from_method_options() {
+    use protobuf::Message;
+    use serde_protobuf::http::{self, HttpRule};
+
+    let rule = HttpRule {
+        method: "GET".to_owned(),
+        path: "/v1/{name=shelves/*}/books/{book.id}".to_owned(),
+        additional_bindings: vec![HttpRule {
+            method: "HEAD".to_owned(),
+            path: "/v1/books".to_owned(),
+            body: "*".to_owned(),
+            ..HttpRule::default()
+        }],
+        ..HttpRule::default()
+    };
+    let mut options = protobuf::descriptor::MethodOptions::new();
+    options
+        .mut_unknown_fields()
+        .add_length_delimited(http::HTTP_EXTENSION, rule.write_to_bytes().unwrap());
+    let method = descriptor::MethodDescriptor::new("GetBook", ".test.Req", ".test.Resp")
+        .with_options(options);
+
+    let decoded = HttpRule::from_method(&method).unwrap().unwrap();
+    assert_eq!(rule, decoded);
+    assert_eq!(vec!["name", "book.id"], decoded.path_variables());
+    assert_eq!(2, decoded.bindings().len());
+    assert_eq!(
+        None,
+        HttpRule::from_method(&descriptor::MethodDescriptor::new("A", ".B", ".C")).unwrap()
+    );
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn openapi_generation() {
+    use protobuf::Message;
+    use serde_protobuf::http::{self, HttpRule};
+
+    let mut descriptors = descriptors();
+
+    let rule = HttpRule {
+        method: "GET".to_owned(),
+        path: "/v1/things/{optional_int32=*}".to_owned(),
+        ..HttpRule::default()
+    };
+    let mut options = protobuf::descriptor::MethodOptions::new();
+    options
+        .mut_unknown_fields()
+        .add_length_delimited(http::HTTP_EXTENSION, rule.write_to_bytes().unwrap());
+    let mut service = descriptor::ServiceDescriptor::new(".test.Things");
+    service.add_method(
+        descriptor::MethodDescriptor::new(
+            "GetThing",
+            ".protobuf_unittest.TestAllTypes",
+            ".protobuf_unittest.TestAllTypes",
+        )
+        .with_options(options),
+    );
+    descriptors.add_service(service);
+
+    let api = descriptor::to_openapi(&descriptors, "Test", "1.0").unwrap();
+    assert_eq!("3.1.0", api["openapi"]);
+
+    let foo = &api["paths"]["/protobuf_unittest.TestService/Foo"]["post"];
+    assert_eq!("TestService_Foo", foo["operationId"]);
+    assert_eq!(
+        "#/components/schemas/protobuf_unittest.FooRequest",
+        foo["requestBody"]["content"]["application/json"]["schema"]["$ref"]
+    );
+
+    let get = &api["paths"]["/v1/things/{optional_int32}"]["get"];
+    assert!(get.get("requestBody").is_none());
+    let parameters = get["parameters"].as_array().unwrap();
+    assert_eq!("optional_int32", parameters[0]["name"]);
+    assert_eq!("path", parameters[0]["in"]);
+    let query: Vec<_> = parameters[1..]
+        .iter()
+        .map(|p| p["name"].as_str().unwrap())
+        .collect();
+    assert!(query.contains(&"optionalInt64"));
+    assert!(query.contains(&"repeatedString"));
+    assert!(!query.contains(&"optionalInt32"));
+    assert!(!query.contains(&"optionalNestedMessage"));
+    assert!(api["components"]["schemas"]["protobuf_unittest.TestAllTypes"].is_object());
+}
+
+#[test]
+fn timestamp_duration_json_forms() {
+    use serde_protobuf::well_known::{Duration, Timestamp};
+
+    let t = Timestamp::parse("1972-01-01T10:00:20.021Z").unwrap();
+    assert_eq!(
+        Timestamp {
+            seconds: 63_108_020,
+            nanos: 21_000_000
+        },
+        t
+    );
+    assert_eq!("1972-01-01T10:00:20.021Z", t.to_string());
+    assert_eq!(
+        t,
+        Timestamp::parse("1972-01-01T11:30:20.021+01:30").unwrap()
+    );
+    assert_eq!(
+        "1970-01-01T00:00:00Z",
+        Timestamp {
+            seconds: 0,
+            nanos: 0
+        }
+        .to_string()
+    );
+    assert_eq!(None, Timestamp::parse("1972-13-01T10:00:20Z"));
+    assert_eq!(None, Timestamp::parse("1972-01-01 10:00:20Z"));
+
+    let d = Duration::parse("-1.5s").unwrap();
+    assert_eq!(
+        Duration {
+            seconds: -1,
+            nanos: -500_000_000
+        },
+        d
+    );
+    assert_eq!("-1.500s", d.to_string());
+    assert_eq!("3s", Duration::parse("3s").unwrap().to_string());
+    assert_eq!(None, Duration::parse("3"));
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn json_mapping_roundtrip() {
+    use serde_protobuf::{error, json, value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let input = serde_json::json!({
+        "optionalInt32": 42,
+        "optional_int64": "-7",
+        "optionalFloat": 0.1,
+        "optionalDouble": "NaN",
+        "optionalBytes": "aGVsbG8_",
+        "optionalNestedEnum": "BAZ",
+        "optionalNestedMessage": { "bb": 3 },
+        "repeatedString": ["a", "b"],
+        "optionalString": null,
+    });
+    let m = json::from_json(&descriptors, message, &input).unwrap();
+    match m.fields.get(&15) {
+        Some(value::Field::Singular(Some(value::Value::Bytes(b)))) => {
+            assert_eq!(b"hello?".to_vec(), *b)
+        }
+        _ => panic!("bytes not set"),
+    }
+
+    let output = json::to_json(&descriptors, message, &m).unwrap();
+    assert_eq!(
+        serde_json::json!({
+            "optionalInt32": 42,
+            "optionalInt64": "-7",
+            "optionalFloat": 0.1,
+            "optionalDouble": "NaN",
+            "optionalBytes": "aGVsbG8/",
+            "optionalNestedMessage": { "bb": 3 },
+            "optionalNestedEnum": "BAZ",
+            "repeatedString": ["a", "b"],
+            "optionalString": null,
+        }),
+        output
+    );
+    match m.fields.get(&14) {
+        Some(field) => assert!(field.is_null()),
+        None => panic!("string not cleared"),
+    }
+    assert_eq!(
+        output,
+        json::to_json(
+            &descriptors,
+            message,
+            &json::from_json(&descriptors, message, &output).unwrap()
+        )
+        .unwrap()
+    );
+
+    match json::from_json(&descriptors, message, &serde_json::json!({ "nope": 1 })) {
+        Err(error::Error::UnknownField { ref name }) if name == "nope" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(json::from_json(
+        &descriptors,
+        message,
+        &serde_json::json!({ "optionalInt32": 1.5 })
+    )
+    .is_err());
+
+    let descriptors = well_known_descriptors();
+    let event = descriptors.message_by_name(".test.Event").unwrap();
+    let input = serde_json::json!({
+        "at": "2017-01-15T01:30:15.010Z",
+        "took": ["1.500s", "-0.000000001s"],
+    });
+    let m = json::from_json(&descriptors, event, &input).unwrap();
+    assert_eq!(input, json::to_json(&descriptors, event, &m).unwrap());
+    assert!(json::from_json(
+        &descriptors,
+        event,
+        &serde_json::json!({ "at": "yesterday" })
+    )
+    .is_err());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn json_keeps_defaults_with_presence() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use serde_protobuf::json;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let mut descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // A proto2 field that is set to its default value is still present, but the declared
+    // defaults that `Message::new` fills in are not written
+    let mut m = Message::new(message);
+    m.fields.insert(1, Field::Singular(Some(Value::I32(0))));
+    m.fields
+        .insert(14, Field::Singular(Some(Value::String(String::new()))));
+    assert_eq!(
+        serde_json::json!({ "optionalInt32": 0, "optionalString": "" }),
+        json::to_json(&descriptors, message, &m).unwrap()
+    );
+
+    // message Counter {
+    //   int32 count = 1;
+    // }
+    let mut field = FieldDescriptorProto::new();
+    field.set_name("count".to_owned());
+    field.set_number(1);
+    field.set_label(Label::LABEL_OPTIONAL);
+    field.set_field_type(Type::TYPE_INT32);
+    let mut counter = DescriptorProto::new();
+    counter.set_name("Counter".to_owned());
+    counter.mut_field().push(field);
+    let mut file = FileDescriptorProto::new();
+    file.set_name("counter.proto".to_owned());
+    file.set_package("counter".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.set_message_type(vec![counter].into());
+    descriptors.add_file_proto(&file);
+    let counter = descriptors.message_by_name(".counter.Counter").unwrap();
+    assert!(!counter.field_by_name("count").unwrap().has_presence());
+
+    // A plain proto3 field without presence is omitted when it holds its default value
+    let mut m = Message::new(counter);
+    m.fields.insert(1, Field::Singular(Some(Value::I32(0))));
+    assert_eq!(
+        serde_json::json!({}),
+        json::to_json(&descriptors, counter, &m).unwrap()
+    );
+    m.fields.insert(1, Field::Singular(Some(Value::I32(3))));
+    assert_eq!(
+        serde_json::json!({ "count": 3 }),
+        json::to_json(&descriptors, counter, &m).unwrap()
+    );
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn grpc_json_transcoding() {
+    use protobuf::Message;
+    use serde_protobuf::http::{self, HttpRule, Transcoder};
+    use serde_protobuf::{json, value};
+
+    let mut descriptors = descriptors();
+
+    let rule = HttpRule {
+        method: "GET".to_owned(),
+        path: "/v1/things/{optional_string=shelves/*}/{optional_nested_message.bb}".to_owned(),
+        response_body: "repeated_string".to_owned(),
+        additional_bindings: vec![HttpRule {
+            method: "POST".to_owned(),
+            path: "/v1/things:create".to_owned(),
+            body: "*".to_owned(),
+            ..HttpRule::default()
+        }],
+        ..HttpRule::default()
+    };
+    let mut options = protobuf::descriptor::MethodOptions::new();
+    options
+        .mut_unknown_fields()
+        .add_length_delimited(http::HTTP_EXTENSION, rule.write_to_bytes().unwrap());
+    let mut service = descriptor::ServiceDescriptor::new(".test.Things");
+    service.add_method(
+        descriptor::MethodDescriptor::new(
+            "GetThing",
+            ".protobuf_unittest.TestAllTypes",
+            ".protobuf_unittest.TestAllTypes",
+        )
+        .with_options(options),
+    );
+    descriptors.add_service(service);
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let transcoder = Transcoder::new(&descriptors).unwrap();
+    let request = transcoder
+        .transcode_request(
+            "GET",
+            "/v1/things/shelves/my%20shelf/7",
+            "optionalInt64=12&repeatedString=a&repeated_string=b+c",
+            None,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!("GetThing", request.method.name());
+    assert_eq!(
+        serde_json::json!({
+            "optionalInt64": "12",
+            "optionalString": "shelves/my shelf",
+            "optionalNestedMessage": { "bb": 7 },
+            "repeatedString": ["a", "b c"],
+        }),
+        json::to_json(&descriptors, message, &request.message).unwrap()
+    );
+    assert_eq!(
+        serde_json::json!(["a", "b c"]),
+        request
+            .response_to_json(&descriptors, &request.message)
+            .unwrap()
+    );
+
+    let body = serde_json::json!({ "optionalInt32": 5 });
+    let request = transcoder
+        .transcode_request("post", "/v1/things:create", "optionalInt32=6", Some(&body))
+        .unwrap()
+        .unwrap();
+    match request.message.fields.get(&1) {
+        Some(value::Field::Singular(Some(value::Value::I32(5)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    assert_eq!(
+        serde_json::json!({ "optionalInt32": 5 }),
+        request
+            .response_to_json(&descriptors, &request.message)
+            .unwrap()
+    );
+
+    assert!(transcoder
+        .transcode_request("GET", "/v1/things/7", "", None)
+        .unwrap()
+        .is_none());
+    assert!(transcoder
+        .transcode_request("DELETE", "/v1/things:create", "", None)
+        .unwrap()
+        .is_none());
+    assert!(transcoder
+        .transcode_request("GET", "/v1/things/shelves/a/x", "", None)
+        .is_err());
+}
+
+#[test]
+fn message_by_type_url() {
+    let descriptors = descriptors();
+
+    let message = descriptors
+        .message_by_type_url("type.googleapis.com/protobuf_unittest.TestAllTypes")
+        .unwrap();
+    assert_eq!(".protobuf_unittest.TestAllTypes", message.name());
+    assert_eq!(
+        "type.googleapis.com/protobuf_unittest.TestAllTypes",
+        message.type_url()
+    );
+    for url in &[
+        "example.com/types/protobuf_unittest.TestAllTypes",
+        "protobuf_unittest.TestAllTypes",
+        ".protobuf_unittest.TestAllTypes",
+    ] {
+        assert_eq!(
+            Some(message.name()),
+            descriptors.message_by_type_url(url).map(|m| m.name())
+        );
+    }
+    assert!(descriptors
+        .message_by_type_url("type.googleapis.com/protobuf_unittest.Nope")
+        .is_none());
+}
+
+#[test]
+fn late_binding_descriptor_resolution() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::error;
+    use serde_protobuf::value::{self, DecodeOptions, Field, Value};
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+    outer.add_field(FieldDescriptor::new(
+        "inner",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::UnresolvedMessage(".test.Inner".to_owned()),
+        None,
+    ));
+    descriptors.add_message(outer);
+    descriptors.resolve_refs();
+    assert_eq!(vec![".test.Inner"], descriptors.unresolved_refs());
+
+    // inner { a: 5 }, inner { a: 6 }
+    let bytes = [0x0a, 0x02, 0x08, 0x05, 0x0a, 0x02, 0x08, 0x06];
+    let decode = |descriptors: &descriptor::Descriptors, options: &DecodeOptions| {
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+        let mut m = value::Message::new(outer);
+        m.merge_from_with_options(
+            descriptors,
+            outer,
+            options,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .map(|()| m)
+    };
+
+    match decode(&descriptors, &DecodeOptions::new()) {
+        Err(error::Error::UnknownMessage { ref name }) if name == ".test.Inner" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let options = DecodeOptions::new().unresolved_messages_as_bytes(true);
+    let m = decode(&descriptors, &options).unwrap();
+    match m.fields.get(&1) {
+        Some(Field::Singular(Some(Value::Bytes(b)))) => assert_eq!(vec![8, 5, 8, 6], *b),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    let outer = descriptors.message_by_name(".test.Outer").unwrap();
+    assert_eq!(
+        vec![0x0a, 0x04, 0x08, 0x05, 0x08, 0x06],
+        m.write_to_bytes(&descriptors, outer).unwrap()
+    );
+
+    let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+    inner.add_field(FieldDescriptor::new(
+        "a",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    descriptors.add_message(inner);
+    assert!(descriptors.unresolved_refs().is_empty());
+    descriptors.resolve_refs();
+    let m = decode(&descriptors, &DecodeOptions::new()).unwrap();
+    match m.fields.get(&1) {
+        Some(Field::Singular(Some(Value::Message(inner)))) => match inner.fields.get(&1) {
+            Some(Field::Singular(Some(Value::I32(6)))) => (),
+            other => panic!("unexpected field: {:?}", other),
+        },
+        other => panic!("unexpected field: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_stats() {
+    use serde::de::Deserialize;
+    use serde_protobuf::value::{self, DecodeStats};
+    use std::sync::{Arc, Mutex};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    // optional_int32 = 7, optional_string = "hi", optional_nested_message = { bb: 1 },
+    // field 999 = 1
+    let bytes = [
+        0x08, 0x07, 0x72, 0x02, b'h', b'i', 0x92, 0x01, 0x02, 0x08, 0x01, 0xb8, 0x3e, 0x01,
+    ];
+    let expected = DecodeStats {
+        bytes_read: 14,
+        fields_decoded: 4,
+        unknown_fields: 1,
+        max_depth: 2,
+        allocations: 2,
+    };
+
+    let mut m = value::Message::new(message);
+    let stats = m
+        .merge_from_with_stats(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    assert_eq!(expected, stats);
+
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let sink = reported.clone();
+    let options = de::Options::new().on_stats(move |stats| sink.lock().unwrap().push(*stats));
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer =
+        de::Deserializer::new(&descriptors, message, input).with_options(options);
+    serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(vec![expected], *reported.lock().unwrap());
+}
+
+#[test]
+fn unknown_field_policy() {
+    use protobuf::stream::wire_format::WireType;
+    use serde::de::Deserialize;
+    use serde_protobuf::error;
+    use serde_protobuf::value::{self, DecodeOptions, UnknownFieldCallback, UnknownFieldPolicy};
+    use std::sync::{Arc, Mutex};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    // optional_int32 = 7, field 999 = 1, field 1000 = "ab"
+    let bytes = [0x08, 0x07, 0xb8, 0x3e, 0x01, 0xc2, 0x3e, 0x02, b'a', b'b'];
+
+    let decode = |policy| {
+        let mut m = value::Message::new(message);
+        m.merge_from_with_options(
+            &descriptors,
+            message,
+            &DecodeOptions::new().unknown_fields(policy),
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .map(|()| m)
+    };
+
+    let preserved = decode(UnknownFieldPolicy::Preserve).unwrap();
+    assert_eq!(2, preserved.unknown.iter().count());
+    let dropped = decode(UnknownFieldPolicy::Drop).unwrap();
+    assert_eq!(0, dropped.unknown.iter().count());
+    assert_eq!(
+        bytes[..2].to_vec(),
+        dropped
+            .write_to_bytes_with_options(
+                &descriptors,
+                message,
+                &value::EncodeOptions::new().skip_defaults(true)
+            )
+            .unwrap()
+    );
+    match decode(UnknownFieldPolicy::Error) {
+        Err(error::Error::UnknownFieldNumber { number: 999 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    let callback = UnknownFieldCallback::new(move |number, wire_type, value| {
+        sink.lock()
+            .unwrap()
+            .push((number, wire_type, value.to_vec()))
+    });
+    let audited = decode(UnknownFieldPolicy::Callback(callback)).unwrap();
+    assert_eq!(2, audited.unknown.iter().count());
+    assert_eq!(
+        vec![
+            (999, WireType::WireTypeVarint, vec![0x01]),
+            (1000, WireType::WireTypeLengthDelimited, b"ab".to_vec()),
+        ],
+        *seen.lock().unwrap()
+    );
+
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, message, input)
+        .with_options(de::Options::new().unknown_fields(UnknownFieldPolicy::Error));
+    assert!(serde_value::Value::deserialize(&mut deserializer).is_err());
+}
+
+#[test]
+fn canonical_encoding() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value::{self, EncodeOptions, Field, Value};
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut options = protobuf::descriptor::MessageOptions::new();
+    options.set_map_entry(true);
+    let mut entry =
+        descriptor::MessageDescriptor::new(".test.Holder.EntriesEntry").with_options(options);
+    entry.add_field(FieldDescriptor::new(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    entry.add_field(FieldDescriptor::new(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    let mut holder = descriptor::MessageDescriptor::new(".test.Holder");
+    holder.add_field(FieldDescriptor::new(
+        "entries",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Holder.EntriesEntry".to_owned()),
+        None,
+    ));
+    descriptors.add_message(entry);
+    descriptors.add_message(holder);
+    descriptors.resolve_refs();
+    let entry = descriptors
+        .message_by_name(".test.Holder.EntriesEntry")
+        .unwrap();
+    let holder = descriptors.message_by_name(".test.Holder").unwrap();
+
+    let entries = ["b", "a"].iter().enumerate().map(|(i, k)| {
+        let mut m = value::Message::new(entry);
+        m.fields
+            .insert(1, Field::Singular(Some(Value::String(k.to_string()))));
+        m.fields
+            .insert(2, Field::Singular(Some(Value::I32(i as i32))));
+        Value::Message(Arc::new(m))
+    });
+    let mut m = value::Message::new(holder);
+    m.fields.insert(1, Field::Repeated(entries.collect()));
+    m.unknown.add_varint(9, 3);
+    m.unknown.add_varint(5, 1);
+    m.unknown.add_fixed32(5, 2);
+    m.unknown.add_varint(7, 2);
+
+    let plain = m.write_to_bytes(&descriptors, holder).unwrap();
+    assert_eq!(
+        vec![
+            0x0a, 0x05, 0x0a, 0x01, b'b', 0x10, 0x00, // entries { key: "b" value: 0 }
+            0x0a, 0x05, 0x0a, 0x01, b'a', 0x10, 0x01, // entries { key: "a" value: 1 }
+            0x2d, 0x02, 0x00, 0x00, 0x00, 0x28, 0x01, // field 5 = fixed32 2, varint 1
+            0x38, 0x02, 0x48, 0x03, // field 7 = 2, field 9 = 3
+        ],
+        plain
+    );
+
+    let canonical = m
+        .write_to_bytes_with_options(&descriptors, holder, &EncodeOptions::new().canonical(true))
+        .unwrap();
+    assert_eq!(&[0x0a, 0x05, 0x0a, 0x01, b'a'], &canonical[..5]);
+    assert_eq!(&[0x0a, 0x05, 0x0a, 0x01, b'b'], &canonical[7..12]);
+    assert_eq!(plain[14..], canonical[14..]);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_decode_encode_events() {
+    use serde_protobuf::value;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span;
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Recorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, attributes: &span::Attributes) -> span::Id {
+            attributes.record(&mut Recorder(self.0.clone()));
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &tracing::Event) {
+            event.record(&mut Recorder(self.0.clone()));
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    // optional_int32 = 7, repeated_int32 = [1], field 999 = 1
+    let bytes = [0x08, 0x07, 0xf8, 0x01, 0x01, 0xb8, 0x3e, 0x01];
+
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    tracing::subscriber::with_default(Recorder(recorded.clone()), || {
+        let mut m = value::Message::new(message);
+        m.merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+        m.write_to_bytes(&descriptors, message).unwrap();
+    });
+
+    let recorded = recorded.lock().unwrap().join(" ");
+    assert!(recorded.starts_with("message=\".protobuf_unittest.TestAllTypes\""));
+    assert!(recorded.contains("message=decoded message bytes=8 "));
+    assert!(recorded.contains("unknown_fields=1"));
+    assert!(recorded.contains("message=encoded message"));
+}
+
+#[cfg(feature = "rust_decimal")]
+#[test]
+fn google_type_rust_decimal() {
+    use serde_protobuf::google_type;
+
+    let money = google_type::Money {
+        currency_code: "EUR".to_owned(),
+        units: 1,
+        nanos: 500_000_000,
+    };
+    assert_eq!("1.5", money.to_decimal().unwrap().normalize().to_string());
+
+    let decimal = google_type::Decimal {
+        value: "2.25".to_owned(),
+    };
+    assert_eq!("2.25", decimal.to_decimal().unwrap().to_string());
+}
+
+#[cfg(feature = "arena")]
+#[test]
+fn arena_decode_matches_owned_decode() {
+    use protobuf::Message;
+    use serde_protobuf::{arena, value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("hello".to_owned());
+    v.set_optional_bytes(vec![1, 2, 3]);
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_string().push("a".to_owned());
+    v.mut_repeated_string().push("b".to_owned());
+    v.mut_repeated_int32().push(7);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut owned = value::Message::new(message);
+    owned
+        .merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+
+    let bump = bumpalo::Bump::new();
+    let mut borrowed = arena::Message::new_in(message, &bump);
+    borrowed
+        .merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+
+    match borrowed.field(14) {
+        Some(&arena::Field::Singular(Some(arena::Value::String(s)))) => assert_eq!("hello", s),
+        f => panic!("unexpected field {:?}", f),
+    }
+    assert_eq!(
+        format!("{:?}", owned),
+        format!("{:?}", borrowed.to_message())
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_decode_preserves_order() {
+    use protobuf::Message;
+    use serde_protobuf::{parallel, value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut data = Vec::new();
+    for i in 0..100 {
+        let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+        v.set_optional_int32(i);
+        v.write_length_delimited_to_vec(&mut data).unwrap();
+    }
+
+    let messages = parallel::decode_length_delimited(&descriptors, message, &data).unwrap();
+    assert_eq!(100, messages.len());
+    for (i, m) in messages.into_iter().enumerate() {
+        match m.unwrap().fields.get(&1) {
+            Some(&value::Field::Singular(Some(value::Value::I32(v)))) => assert_eq!(i as i32, v),
+            f => panic!("unexpected field {:?}", f),
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_decode_truncated_framing() {
+    use serde_protobuf::parallel;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    assert!(parallel::decode_length_delimited(&descriptors, message, &[2, 8, 1, 5, 8]).is_err());
+}
+
+macro_rules! check_roundtrip_packed {
+    ($id:ident, $field:ident, $mut_getter:ident, [$($v:expr),+], $p:tt) => {
+        #[test]
+        fn $id() {
+            let v = roundtrip!(protobuf_unittest::unittest::TestPackedTypes, v, {
+                $(
+                    v.$mut_getter().push($v);
+                )+
+            });
+            assert_subset!(value!(map {
+                (str: stringify!($field)) => (seq [$(($p: $v)),+])
+            }), v)
+        }
+    }
+}
+
+check_roundtrip_packed!(
+    roundtrip_packed_fixed32,
+    packed_fixed32,
+    mut_packed_fixed32,
+    [42, 21, 0, u32::max_value()],
+    u32
+);
+check_roundtrip_packed!(
+    roundtrip_packed_fixed64,
+    packed_fixed64,
+    mut_packed_fixed64,
+    [42, 21, 0, u64::max_value()],
+    u64
+);
+check_roundtrip_packed!(
+    roundtrip_packed_sfixed32,
+    packed_sfixed32,
+    mut_packed_sfixed32,
+    [42, -21, 0, i32::min_value()],
+    i32
+);
+check_roundtrip_packed!(
+    roundtrip_packed_sfixed64,
+    packed_sfixed64,
+    mut_packed_sfixed64,
+    [42, -21, 0, i64::min_value()],
+    i64
+);
+check_roundtrip_packed!(
+    roundtrip_packed_float,
+    packed_float,
+    mut_packed_float,
+    [0.4, -0.0, 1.0],
+    f32
+);
+check_roundtrip_packed!(
+    roundtrip_packed_double,
+    packed_double,
+    mut_packed_double,
+    [0.4, -0.0, 1.0],
+    f64
+);
+
+#[test]
+fn roundtrip_packed_large_run() {
+    let values: Vec<u64> = (0..1000).collect();
+    let v = roundtrip!(protobuf_unittest::unittest::TestPackedTypes, v, {
+        v.mut_packed_fixed64().extend(values.iter().cloned());
+    });
+
+    let mut expected = collections::BTreeMap::new();
+    expected.insert(
+        value!(str: "packed_fixed64"),
+        serde_value::Value::Seq(values.into_iter().map(serde_value::Value::U64).collect()),
+    );
+    assert_subset!(serde_value::Value::Map(expected), v)
+}
+
+#[test]
+fn packed_fixed_truncated_run() {
+    use serde::de::Deserialize;
+
+    let descriptors = descriptors();
+
+    // packed_fixed32 (field 96) with a 6 byte payload, which isn't a multiple of 4
+    let bytes = [0x82, 0x06, 6, 1, 0, 0, 0, 2, 0];
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::for_named_message(
+        &descriptors,
+        ".protobuf_unittest.TestPackedTypes",
+        input,
+    )
+    .unwrap();
+    assert!(serde_value::Value::deserialize(&mut deserializer).is_err());
+}
+
+check_roundtrip_packed!(
+    roundtrip_packed_int32,
+    packed_int32,
+    mut_packed_int32,
+    [42, -21, 0, i32::max_value()],
+    i32
+);
+check_roundtrip_packed!(
+    roundtrip_packed_sint64,
+    packed_sint64,
+    mut_packed_sint64,
+    [42, -21, 0, i64::min_value()],
+    i64
+);
+check_roundtrip_packed!(
+    roundtrip_packed_bool,
+    packed_bool,
+    mut_packed_bool,
+    [true, false, true],
+    bool
+);
+
+#[test]
+fn packed_fixed_run_is_presized() {
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestPackedTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestPackedTypes::new();
+    v.mut_packed_fixed32().extend(0..10);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut m = value::Message::new(message);
+    m.merge_from(
+        &descriptors,
+        message,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+
+    match m.fields[&96] {
+        value::Field::Repeated(ref r) => {
+            assert_eq!(10, r.len());
+            assert_eq!(10, r.capacity());
+        }
+        ref f => panic!("unexpected field {:?}", f),
+    }
+}
+
+#[test]
+fn packed_run_reserve_is_bounded_by_input() {
+    use protobuf::stream::wire_format::WireType;
+    use serde_protobuf::value::Field;
+
+    let descriptors = descriptors();
+    let field = descriptors
+        .message_by_name(".protobuf_unittest.TestPackedTypes")
+        .unwrap()
+        .field_by_name("packed_int32")
+        .unwrap();
+
+    // A packed run that claims 60000 bytes, in a message that only has 3 more bytes
+    let bytes = [0xe0, 0xd4, 0x03, 0x01, 0x02, 0x03];
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    input.push_limit(bytes.len() as u64).unwrap();
+    let mut values = Field::new(field);
+    assert!(values
+        .merge_from(
+            &descriptors,
+            field,
+            &mut input,
+            WireType::WireTypeLengthDelimited
+        )
+        .is_err());
+    match values {
+        Field::Repeated(ref r) => assert!(r.capacity() < 16),
+        ref f => panic!("unexpected field {:?}", f),
+    }
+}
+
+#[test]
+fn packed_fields_are_encoded_packed() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use protobuf::Message;
+    use serde_protobuf::value::{Field, TypedValues, Value};
+
+    let mut descriptors = descriptors();
+
+    // Re-encoding keeps the wire form of packed and unpacked proto2 fields
+    let mut packed = protobuf_unittest::unittest::TestPackedTypes::new();
+    packed.set_packed_int32(vec![1, -2, 300]);
+    packed.set_packed_sint64(vec![-5]);
+    packed.set_packed_double(vec![1.5, 2.5]);
+    packed.set_packed_enum(vec![protobuf_unittest::unittest::ForeignEnum::FOREIGN_BAR]);
+    let mut unpacked = protobuf_unittest::unittest::TestUnpackedTypes::new();
+    unpacked.set_unpacked_int32(vec![1, -2, 300]);
+    for &(name, ref bytes) in &[
+        (
+            ".protobuf_unittest.TestPackedTypes",
+            packed.write_to_bytes().unwrap(),
+        ),
+        (
+            ".protobuf_unittest.TestUnpackedTypes",
+            unpacked.write_to_bytes().unwrap(),
+        ),
+    ] {
+        let descriptor = descriptors.message_by_name(name).unwrap();
+        let message = descriptors.decode(name, bytes).unwrap();
+        assert_eq!(
+            *bytes,
+            message.write_to_bytes(&descriptors, descriptor).unwrap()
+        );
+    }
+
+    // Fixed-width runs are written the same from typed storage, and values of the wrong type
+    // are rejected
+    let mut fixed = protobuf_unittest::unittest::TestPackedTypes::new();
+    fixed.set_packed_fixed32(vec![1, 0xdead_beef]);
+    fixed.set_packed_sfixed64(vec![-2]);
+    fixed.set_packed_float(vec![0.5, -1.0]);
+    fixed.set_packed_double(vec![1.5]);
+    let bytes = fixed.write_to_bytes().unwrap();
+    let name = ".protobuf_unittest.TestPackedTypes";
+    let descriptor = descriptors.message_by_name(name).unwrap();
+    let mut message = serde_protobuf::value::Message::new(descriptor);
+    message
+        .merge_from_with_options(
+            &descriptors,
+            descriptor,
+            &serde_protobuf::value::DecodeOptions::new().typed_repeated_scalars(true),
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    match message.fields.get(&96) {
+        Some(Field::RepeatedTyped(TypedValues::U32(_))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    assert_eq!(
+        bytes,
+        message.write_to_bytes(&descriptors, descriptor).unwrap()
+    );
+    let mut untyped = message.clone();
+    untyped.fields.get_mut(&96).unwrap().make_untyped();
+    assert_eq!(
+        bytes,
+        untyped.write_to_bytes(&descriptors, descriptor).unwrap()
+    );
+    for field in [
+        Field::RepeatedTyped(TypedValues::I32(vec![1])),
+        Field::Repeated(vec![Value::I32(1)]),
+    ] {
+        message.fields.insert(96, field);
+        assert!(message.write_to_bytes(&descriptors, descriptor).is_err());
+    }
+
+    // message Samples {
+    //   repeated int32 values = 1;
+    //   repeated int32 unpacked = 2 [packed = false];
+    //   repeated string names = 3;
+    // }
+    let mut samples = DescriptorProto::new();
+    samples.set_name("Samples".to_owned());
+    for &(name, number, field_type) in &[
+        ("values", 1, Type::TYPE_INT32),
+        ("unpacked", 2, Type::TYPE_INT32),
+        ("names", 3, Type::TYPE_STRING),
+    ] {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(number);
+        field.set_label(Label::LABEL_REPEATED);
+        field.set_field_type(field_type);
+        if name == "unpacked" {
+            field.mut_options().set_packed(false);
+        }
+        samples.mut_field().push(field);
+    }
+    let mut file = FileDescriptorProto::new();
+    file.set_name("samples.proto".to_owned());
+    file.set_package("samples".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.set_message_type(vec![samples].into());
+    descriptors.add_file_proto(&file);
+    let samples = descriptors.message_by_name(".samples.Samples").unwrap();
+
+    // Repeated scalar fields are packed by default in proto3
+    let packed = |name| samples.field_by_name(name).unwrap().is_packed();
+    assert!(packed("values"));
+    assert!(!packed("unpacked"));
+    assert!(!packed("names"));
+
+    let mut message = serde_protobuf::value::Message::new(samples);
+    let ints = |vs: &[i32]| Field::Repeated(vs.iter().map(|&v| Value::I32(v)).collect());
+    message.fields.insert(1, ints(&[1, 2]));
+    message.fields.insert(2, ints(&[3, 4]));
+    message
+        .fields
+        .insert(3, Field::Repeated(vec![Value::String("a".to_owned())]));
+    assert_eq!(
+        vec![0x0a, 0x02, 0x01, 0x02, 0x10, 0x03, 0x10, 0x04, 0x1a, 0x01, b'a'],
+        message.write_to_bytes(&descriptors, samples).unwrap()
+    );
+    assert_eq!(11, message.compute_size(&descriptors, samples).unwrap());
+}
+
+#[test]
+fn descriptor_snapshot_roundtrip() {
+    let descriptors = descriptors();
+
+    let snapshot = descriptors.to_snapshot().unwrap();
+    let loaded = descriptor::Descriptors::from_snapshot(&snapshot).unwrap();
+    assert_eq!(format!("{:?}", descriptors), format!("{:?}", loaded));
+    assert_eq!(snapshot, loaded.to_snapshot().unwrap());
+
+    let message = loaded
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    match message
+        .field_by_name("optional_nested_message")
+        .unwrap()
+        .field_type(&loaded)
+    {
+        descriptor::FieldType::Message(m) => {
+            assert_eq!(".protobuf_unittest.TestAllTypes.NestedMessage", m.name())
+        }
+        other => panic!("unexpected field type: {:?}", other),
+    }
+
+    assert!(descriptor::Descriptors::from_snapshot(&snapshot[..snapshot.len() - 1]).is_err());
+    assert!(descriptor::Descriptors::from_snapshot(b"not a snapshot").is_err());
+}
+
+#[test]
+fn shared_descriptors_hot_reload() {
+    use std::thread;
+
+    let shared = descriptor::SharedDescriptors::new(descriptor::Descriptors::new());
+    let before = shared.load();
+
+    let previous = shared.store(descriptors());
+    assert!(previous
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .is_none());
+
+    let readers = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let descriptors = shared.load();
+                    let message = descriptors
+                        .message_by_name(".protobuf_unittest.TestAllTypes")
+                        .unwrap();
+                    let mut m = serde_protobuf::value::Message::new(message);
+                    m.merge_from(
+                        &descriptors,
+                        message,
+                        &mut protobuf::CodedInputStream::from_bytes(&[8, 1]),
+                    )
+                    .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..10 {
+        shared.update(|descriptors| {
+            let mut descriptors = descriptors.clone();
+            descriptors.add_message(descriptor::MessageDescriptor::new(format!(".test.M{}", i)));
+            descriptors
+        });
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let current = shared.load();
+    assert!((0..10).all(|i| current.message_by_name(&format!(".test.M{}", i)).is_some()));
+    assert!(before.message_by_name(".test.M0").is_none());
+}
+
+#[test]
+fn typed_message_bridges() {
+    use serde_protobuf::error;
+    use serde_protobuf::value::{self, Field, Value};
+    use serde_value::Value as V;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let map = |entries: Vec<(&str, V)>| {
+        V::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (V::String(k.to_owned()), v))
+                .collect(),
+        )
+    };
+    let typed = map(vec![
+        ("optional_int32", V::I32(5)),
+        ("optional_string", V::String("hello".to_owned())),
+        ("optional_nested_enum", V::String("BAZ".to_owned())),
+        ("optional_nested_message", map(vec![("bb", V::U8(7))])),
+        ("repeated_int64", V::Seq(vec![V::I64(-1), V::U64(2)])),
+        ("optional_float", V::Option(None)),
+    ]);
+
+    let m = value::Message::from_typed(&descriptors, message, &typed).unwrap();
+    match m.fields.get(&1) {
+        Some(Field::Singular(Some(Value::I32(5)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match m.fields.get(&21) {
+        Some(Field::Singular(Some(Value::Enum(3)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match m.fields.get(&32) {
+        Some(Field::Repeated(vs)) => match vs[..] {
+            [Value::I64(-1), Value::I64(2)] => (),
+            ref other => panic!("unexpected values: {:?}", other),
+        },
+        other => panic!("unexpected field: {:?}", other),
+    }
+
+    let roundtrip: collections::BTreeMap<String, V> = m.to_typed(&descriptors, message).unwrap();
+    assert_eq!(
+        V::Option(Some(Box::new(V::I32(5)))),
+        roundtrip["optional_int32"]
+    );
+    assert_eq!(
+        V::Option(Some(Box::new(V::String("BAZ".to_owned())))),
+        roundtrip["optional_nested_enum"]
+    );
+    assert_eq!(
+        V::Seq(vec![V::I64(-1), V::I64(2)]),
+        roundtrip["repeated_int64"]
+    );
+    assert_eq!(V::Option(None), roundtrip["optional_float"]);
+
+    let too_big = map(vec![("optional_int32", V::I64(1 << 40))]);
+    match value::Message::from_typed(&descriptors, message, &too_big) {
+        Err(error::Error::BadFieldValue { ref field }) if field == "optional_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let unknown = map(vec![("nope", V::Bool(true))]);
+    match value::Message::from_typed(&descriptors, message, &unknown) {
+        Err(error::Error::UnknownField { ref name }) if name == "nope" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn message_cursor() {
+    use serde_protobuf::cursor::{Cursor, CursorMut};
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut message = Message::new(descriptor);
+
+    {
+        let mut nested = CursorMut::new(&descriptors, descriptor, &mut message)
+            .field("repeated_nested_message")
+            .unwrap();
+        let entry = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+            .unwrap();
+        nested
+            .push(Value::Message(Arc::new(Message::new(entry))))
+            .unwrap();
+        nested
+            .push(Value::Message(Arc::new(Message::new(entry))))
+            .unwrap();
+        assert!(nested.push(Value::I32(1)).is_err());
+        let mut bb = nested.index(1).unwrap().field("bb").unwrap();
+        bb.set(Value::I32(42)).unwrap();
+        match bb.set(Value::I64(42)) {
+            Err(error::Error::TypeMismatch {
+                ref field,
+                ref expected,
+                actual: "i64",
+            }) if field == "bb" && expected == "int32" => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut e = CursorMut::new(&descriptors, descriptor, &mut message)
+            .field("optional_nested_enum")
+            .unwrap();
+        e.set_enum_name("BAR").unwrap();
+        assert!(e.set_enum_name("NOPE").is_err());
+    }
+
+    let cursor = Cursor::new(&descriptors, descriptor, &message);
+    let nested = cursor.field("repeated_nested_message").unwrap();
+    assert_eq!(2, nested.len());
+    assert_eq!(None, nested.index(0).unwrap().field("bb").unwrap().as_i32());
+    assert_eq!(
+        Some(42),
+        nested.index(1).unwrap().field("bb").unwrap().as_i32()
+    );
+    assert_eq!(
+        Some("BAR"),
+        cursor.field("optional_nested_enum").unwrap().as_enum_name()
+    );
+    // Reading through an absent message
+    let absent = cursor.field("optional_foreign_message").unwrap();
+    assert_eq!(None, absent.field("c").unwrap().as_i32());
+
+    match nested.index(2) {
+        Err(error::Error::IndexOutOfBounds {
+            ref field,
+            index: 2,
+        }) if field == "repeated_nested_message" => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match cursor.field("nope") {
+        Err(error::Error::UnknownField { ref name }) if name == "nope" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn repeated_field_helpers() {
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Field, Value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let repeated = message.field_by_name("repeated_int32").unwrap();
+    let singular = message.field_by_name("optional_int32").unwrap();
+
+    let ints = |field: &Field| -> Vec<i32> {
+        match field {
+            Field::Repeated(vs) => vs
+                .iter()
+                .map(|v| match *v {
+                    Value::I32(v) => v,
+                    ref other => panic!("unexpected value: {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected field: {:?}", other),
+        }
+    };
+
+    let mut field = Field::new(repeated);
+    for v in &[3, 1, 4, 1, 5] {
+        field.push(&descriptors, repeated, Value::I32(*v)).unwrap();
+    }
+    match field.push(&descriptors, repeated, Value::I64(9)) {
+        Err(error::Error::TypeMismatch { ref field, .. }) if field == "repeated_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    field
+        .insert(&descriptors, repeated, 0, Value::I32(9))
+        .unwrap();
+    match field.insert(&descriptors, repeated, 7, Value::I32(0)) {
+        Err(error::Error::IndexOutOfBounds { index: 7, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(vec![9, 3, 1, 4, 1, 5], ints(&field));
+
+    match field.remove(1) {
+        Some(Value::I32(3)) => (),
+        other => panic!("unexpected value: {:?}", other),
+    }
+    assert!(field.remove(5).is_none());
+    field.retain(|v| match *v {
+        Value::I32(v) => v != 1,
+        _ => true,
+    });
+    assert_eq!(vec![9, 4, 5], ints(&field));
+    field.sort_by_key(|v| match *v {
+        Value::I32(v) => v,
+        _ => 0,
+    });
+    assert_eq!(vec![4, 5, 9], ints(&field));
+    field.truncate(1);
+    assert_eq!(vec![4], ints(&field));
+    assert_eq!(1, field.len());
+
+    let mut field = Field::new(singular);
+    assert!(field.push(&descriptors, singular, Value::I32(1)).is_err());
+    assert!(field.is_empty());
+}
+
+#[test]
+fn dynamic_message_macro() {
+    use serde_protobuf::dynamic_message;
+    use serde_protobuf::error;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let name = "hello".to_owned();
+    let m = dynamic_message!(&descriptors, "protobuf_unittest.TestAllTypes" {
+        optional_int32: 2 + 3,
+        optional_uint64: 7,
+        optional_string: name.as_str(),
+        optional_bytes: b"\x01\x02",
+        optional_nested_enum: "BAR",
+        optional_nested_message: { bb: -1 },
+        repeated_int32: [1, -2, 3],
+        repeated_nested_message: [{ bb: 1 }, {}],
+        optional_float: None::<f32>,
+    })
+    .unwrap();
+
+    let mut expected = value::Message::new(descriptor);
+    expected
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&[
+                0x08, 0x05, // optional_int32
+                0x20, 0x07, // optional_uint64
+                0x72, 0x05, b'h', b'e', b'l', b'l', b'o', // optional_string
+                0x7a, 0x02, 0x01, 0x02, // optional_bytes
+                0xa8, 0x01, 0x02, // optional_nested_enum
+                0x92, 0x01, 0x0b, 0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0x01, // optional_nested_message
+                0xf8, 0x01, 0x01, 0xf8, 0x01, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0x01, 0xf8, 0x01, 0x03, // repeated_int32
+                0x82, 0x03, 0x02, 0x08, 0x01, 0x82, 0x03, 0x00, // repeated_nested_message
+            ]),
+        )
+        .unwrap();
+    let options = value::EncodeOptions::new().skip_defaults(true);
+    assert_eq!(
+        expected
+            .write_to_bytes_with_options(&descriptors, descriptor, &options)
+            .unwrap(),
+        m.write_to_bytes_with_options(&descriptors, descriptor, &options)
+            .unwrap()
+    );
+
+    match dynamic_message!(&descriptors, ".protobuf_unittest.TestAllTypes" { optional_int32: 1u64 << 40 })
+    {
+        Err(error::Error::BadFieldValue { ref field }) if field == "optional_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match dynamic_message!(&descriptors, "protobuf_unittest.Nope" {}) {
+        Err(error::Error::UnknownMessage { ref name }) if name == ".protobuf_unittest.Nope" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn roundtrip_check() {
+    use serde_protobuf::dynamic_message;
+    use serde_protobuf::roundtrip;
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let m = dynamic_message!(&descriptors, "protobuf_unittest.TestAllTypes" {
+        optional_int64: -5,
+        optional_double: std::f64::NAN,
+        optional_nested_message: { bb: 3 },
+        repeated_string: ["a", "b"],
+        repeated_nested_enum: ["FOO", "BAZ"],
+    })
+    .unwrap();
+    let mut bytes = m.write_to_bytes(&descriptors, descriptor).unwrap();
+    // An unknown varint field 1000
+    bytes.extend_from_slice(&[0xc0, 0x3e, 0x01]);
+
+    let report = roundtrip::check_roundtrip(&descriptors, descriptor, &bytes).unwrap();
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!("round trip ok", report.to_string());
+    assert!(!report.encoded.is_empty());
+
+    // Truncated input is rejected up front
+    assert!(roundtrip::check_roundtrip(&descriptors, descriptor, &[0x08]).is_err());
+
+    let report = roundtrip::RoundtripReport {
+        encoded: Vec::new(),
+        mismatches: vec![roundtrip::Mismatch {
+            path: "a.b[2]".to_owned(),
+            original: "I32(1)".to_owned(),
+            roundtripped: "absent".to_owned(),
+        }],
+    };
+    assert_eq!(
+        "1 round trip mismatches:\n  a.b[2]: I32(1) != absent",
+        report.to_string()
+    );
+}
+
+#[test]
+fn conformance_adapter() {
+    use serde_protobuf::conformance::{self, ConformanceResponse};
+
+    let descriptors = descriptors();
+
+    let request = |payload: &[u8], format: i32, message_type: &str| {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            output.write_bytes(1, payload).unwrap();
+            output.write_int32(3, format).unwrap();
+            output.write_string(4, message_type).unwrap();
+            output.flush().unwrap();
+        }
+        let mut framed = (bytes.len() as u32).to_le_bytes().to_vec();
+        framed.extend(bytes);
+        framed
+    };
+
+    let mut input = Vec::new();
+    input.extend(request(&[], 1, "conformance.FailureSet"));
+    input.extend(request(&[8, 42], 1, "protobuf_unittest.TestAllTypes"));
+    input.extend(request(&[8], 1, "protobuf_unittest.TestAllTypes"));
+    input.extend(request(&[8, 42], 4, "protobuf_unittest.TestAllTypes"));
+    let mut output = Vec::new();
+    let count = conformance::serve(&descriptors, &mut &input[..], &mut output).unwrap();
+    assert_eq!(4, count);
+
+    let mut responses = Vec::new();
+    let mut rest = &output[..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let mut input = protobuf::CodedInputStream::from_bytes(&rest[4..4 + len]);
+        let (number, _) = input.read_tag_unpack().unwrap();
+        responses.push((number, input.read_bytes().unwrap()));
+        rest = &rest[4 + len..];
+    }
+    assert_eq!((3, vec![]), responses[0]);
+    assert_eq!(3, responses[1].0);
+    assert_eq!(vec![8, 42], &responses[1].1[..2]);
+    assert_eq!(1, responses[2].0);
+    assert_eq!(5, responses[3].0);
+
+    let parsed = conformance::ConformanceRequest::parse(&request(&[8, 1], 2, "a.B")[4..]).unwrap();
+    assert_eq!(
+        conformance::WireFormat::Json,
+        parsed.requested_output_format
+    );
+    assert_eq!("a.B", parsed.message_type);
+    assert_eq!(
+        ConformanceResponse::RuntimeError("unknown message type: .a.B".to_owned()),
+        conformance::handle(&descriptors, &parsed)
+    );
+}
+
+#[test]
+fn streaming_repeated_iter() {
+    use serde_protobuf::error;
+    use serde_protobuf::stream;
+    use serde_protobuf::value;
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested = descriptors
+        .message_by_name(".protobuf_unittest.NestedTestAllTypes")
+        .unwrap();
+
+    let int32s = |descriptor, path, bytes| {
+        stream::decode_repeated_iter(&descriptors, descriptor, path, bytes)
+            .unwrap()
+            .map(|v| match v.unwrap() {
+                value::Value::I32(v) => v,
+                v => panic!("unexpected value: {:?}", v),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // repeated_int32: 1, packed [2, 3], then optional_int32 and 4
+    let payload = [0xf8, 0x01, 1, 0xfa, 0x01, 2, 2, 3, 8, 9, 0xf8, 0x01, 4];
+    assert_eq!(
+        vec![1, 2, 3, 4],
+        int32s(all_types, "repeated_int32", &payload[..])
+    );
+
+    // Two occurrences of payload, which are merged when decoding
+    let mut bytes = vec![0x12, payload.len() as u8];
+    bytes.extend(&payload);
+    bytes.extend(&[0x12, 3, 0xf8, 0x01, 5]);
+    assert_eq!(
+        vec![1, 2, 3, 4, 5],
+        int32s(nested, "payload.repeated_int32", &bytes[..])
+    );
+
+    // repeated_nested_message: {bb: 7}, {bb: 8}
+    let bytes = [0x82, 0x03, 2, 8, 7, 0x82, 0x03, 2, 8, 8];
+    let messages =
+        stream::decode_repeated_iter(&descriptors, all_types, "repeated_nested_message", &bytes)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+    assert_eq!(2, messages.len());
+    match messages[1] {
+        value::Value::Message(ref m) => match m.fields.get(&1) {
+            Some(value::Field::Singular(Some(value::Value::I32(8)))) => (),
+            f => panic!("unexpected field: {:?}", f),
+        },
+        ref v => panic!("unexpected value: {:?}", v),
+    }
+
+    assert!(stream::decode_repeated_iter(&descriptors, all_types, "optional_int32", &[]).is_err());
+    assert!(stream::decode_repeated_iter(&descriptors, all_types, "nope", &[]).is_err());
+    let mut truncated = stream::decode_repeated_iter(
+        &descriptors,
+        all_types,
+        "repeated_int32",
+        &[0xfa, 0x01, 5, 1],
+    )
+    .unwrap();
+    match truncated.next() {
+        Some(Err(error::Error::UnexpectedEof { position: 0 })) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(truncated.next().is_none());
+
+    // Errors in later or nested fields are at their position in the whole input
+    let errors = |path, bytes: &[u8]| {
+        stream::decode_repeated_iter(&descriptors, nested, path, bytes)
+            .unwrap()
+            .find_map(Result::err)
+    };
+    match errors(
+        "payload.repeated_int32",
+        &[0x12, 3, 0xf8, 0x01, 5, 0x12, 3, 0xf8, 0x01],
+    ) {
+        Some(error::Error::UnexpectedEof { position: 5 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match errors("payload.repeated_int32", &[0x12, 3, 0xfd, 0x01, 0]) {
+        Some(error::Error::BadWireType { position: 4, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn bytes_stream_encoding() {
+    use serde_protobuf::value::{self, BytesStream, EncodeOptions, Field, Value};
+    use std::io;
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let options = EncodeOptions::new().skip_defaults(true);
+
+    let content: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    let mut message = value::Message::new(descriptor);
+    let stream = BytesStream::new(content.len() as u32, io::Cursor::new(content.clone()));
+    message.fields.insert(
+        15,
+        Field::Singular(Some(Value::BytesStream(stream.clone()))),
+    );
+
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::new(&mut bytes);
+        message
+            .write_to_with_options(&descriptors, descriptor, &options, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    let mut decoded = value::Message::new(descriptor);
+    decoded
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    match decoded.fields.get(&15) {
+        Some(Field::Singular(Some(Value::Bytes(b)))) => assert!(*b == content),
+        f => panic!("unexpected field: {:?}", f),
+    }
+
+    // The reader is shared between clones and consumed by the first encoding
+    assert!(stream.read_to_vec().is_err());
+
+    message.fields.insert(
+        15,
+        Field::Singular(Some(Value::BytesStream(BytesStream::new(
+            10,
+            io::Cursor::new(vec![1, 2, 3]),
+        )))),
+    );
+    assert!(message
+        .write_to_bytes_with_options(&descriptors, descriptor, &options)
+        .is_err());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_decoding() {
+    use serde_protobuf::arena;
+    use serde_protobuf::mmap::MappedFile;
+    use std::io::Write;
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // Two records: optional_string = "hello", then optional_bytes = [1, 2, 3]
+    let path = std::env::temp_dir().join(format!("serde-protobuf-mmap-{}", std::process::id()));
+    fs::File::create(&path)
+        .unwrap()
+        .write_all(&[
+            7, 0x72, 5, b'h', b'e', b'l', b'l', b'o', 5, 0x7a, 3, 1, 2, 3,
+        ])
+        .unwrap();
+
+    let mapped = MappedFile::open(&path).unwrap();
+    let bump = bumpalo::Bump::new();
+    let messages = mapped
+        .decode_records(&descriptors, descriptor, &bump)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(2, messages.len());
+
+    let bytes = mapped.bytes().as_ptr_range();
+    match messages[0].field(14) {
+        Some(&arena::Field::Singular(Some(arena::Value::String(s)))) => {
+            assert_eq!("hello", s);
+            // Borrowed from the map rather than copied
+            assert!(bytes.contains(&s.as_ptr()));
+        }
+        f => panic!("unexpected field: {:?}", f),
+    }
+    match messages[1].field(15) {
+        Some(&arena::Field::Singular(Some(arena::Value::Bytes(b)))) => assert_eq!(&[1, 2, 3], b),
+        f => panic!("unexpected field: {:?}", f),
+    }
+
+    assert!(mapped
+        .decode_message(&descriptors, descriptor, &bump)
+        .is_err());
+    drop(messages);
+    drop(mapped);
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn retained_source_bytes() {
+    use serde_protobuf::value::{self, DecodeOptions, Field, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let options = DecodeOptions::new();
+
+    // optional_int32 = 1, optional_nested_message = {bb: 7}, optional_foreign_message twice
+    let input = bytes::Bytes::from_static(&[8, 1, 0x92, 1, 2, 8, 7, 0x9a, 1, 0, 0x9a, 1, 0]);
+    let mut message = value::Message::new(descriptor);
+    message
+        .merge_from_bytes(&descriptors, descriptor, &options, &input)
+        .unwrap();
+    assert_eq!(Some(&input), message.source_bytes());
+
+    match message.fields.get(&18) {
+        Some(Field::Singular(Some(Value::Message(m)))) => {
+            assert_eq!(&[8, 7], &m.source_bytes().unwrap()[..]);
+        }
+        f => panic!("unexpected field: {:?}", f),
+    }
+    match message.fields.get(&19) {
+        Some(Field::Singular(Some(Value::Message(m)))) => assert!(m.source_bytes().is_none()),
+        f => panic!("unexpected field: {:?}", f),
+    }
+
+    let mut plain = value::Message::new(descriptor);
+    plain
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&input),
+        )
+        .unwrap();
+    assert!(plain.source_bytes().is_none());
+
+    // Bytes merged into a message that already has fields don't describe it exactly
+    let mut set = value::Message::new(descriptor);
+    set.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+    set.merge_from_bytes(
+        &descriptors,
+        descriptor,
+        &options,
+        &bytes::Bytes::from_static(&[0x10, 3]),
+    )
+    .unwrap();
+    assert!(set.source_bytes().is_none());
+    let encoded = set
+        .write_to_bytes_with_options(
+            &descriptors,
+            descriptor,
+            &value::EncodeOptions::new()
+                .pass_through_source_bytes(true)
+                .skip_defaults(true),
+        )
+        .unwrap();
+    assert_eq!(vec![8, 5, 0x10, 3], encoded);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn partial_reencode_passes_through_source_bytes() {
+    use serde_protobuf::cursor::CursorMut;
+    use serde_protobuf::value::{self, DecodeOptions, EncodeOptions, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let options = EncodeOptions::new().pass_through_source_bytes(true);
+
+    // optional_nested_message = {bb: 0} (a default, kept verbatim), optional_foreign_message = {c: 1}
+    let input = bytes::Bytes::from_static(&[0x92, 1, 2, 8, 0, 0x9a, 1, 2, 8, 1]);
+    let mut message = value::Message::new(descriptor);
+    message
+        .merge_from_bytes(&descriptors, descriptor, &DecodeOptions::new(), &input)
+        .unwrap();
+
+    // Untouched messages are copied as they are, even where the fields would encode differently
+    let encoded = message
+        .write_to_bytes_with_options(&descriptors, descriptor, &options)
+        .unwrap();
+    assert_eq!(&input[..], &encoded[..]);
+
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("optional_foreign_message")
+        .unwrap()
+        .field("c")
+        .unwrap()
+        .set(Value::I32(2))
+        .unwrap();
+    assert!(message.source_bytes().is_none());
+    let encoded = message
+        .write_to_bytes_with_options(&descriptors, descriptor, &options.skip_defaults(true))
+        .unwrap();
+    assert_eq!(vec![0x92, 1, 2, 8, 0, 0x9a, 1, 2, 8, 2], encoded);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn pass_through_notices_nested_modifications() {
+    use serde_protobuf::field_mask::FieldMask;
+    use serde_protobuf::value::{
+        self, DecodeOptions, EncodeOptions, Field, TruncationStrategy, Value,
+    };
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let options = EncodeOptions::new().pass_through_source_bytes(true);
+
+    // optional_int32 = 1, optional_nested_message = {bb: 7}
+    let input = bytes::Bytes::from_static(&[8, 1, 0x92, 1, 2, 8, 7]);
+    let decode = || {
+        let mut message = value::Message::new(descriptor);
+        message
+            .merge_from_bytes(&descriptors, descriptor, &DecodeOptions::new(), &input)
+            .unwrap();
+        message
+    };
+    let encode = |message: &value::Message| {
+        message
+            .write_to_bytes_with_options(
+                &descriptors,
+                descriptor,
+                &options.clone().skip_defaults(true),
+            )
+            .unwrap()
+    };
+
+    // An update of a nested field
+    let mut message = decode();
+    let mut update = value::Message::new(descriptor);
+    let nested = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+    let mut bb = value::Message::new(nested);
+    bb.fields.insert(1, Field::Singular(Some(Value::I32(9))));
+    update
+        .fields
+        .insert(18, Field::Singular(Some(Value::Message(Arc::new(bb)))));
+    message
+        .apply_update(
+            &descriptors,
+            descriptor,
+            &update,
+            &FieldMask::new(vec!["optional_nested_message.bb"]),
+        )
+        .unwrap();
+    assert_eq!(vec![8, 1, 0x92, 1, 2, 8, 9], encode(&message));
+
+    // Clearing a nested field by priority
+    let mut message = decode();
+    let size = message.compute_size(&descriptors, descriptor).unwrap();
+    message
+        .truncate_to(
+            &descriptors,
+            descriptor,
+            size - 1,
+            &TruncationStrategy::Priority(FieldMask::new(vec!["optional_nested_message.bb"])),
+        )
+        .unwrap();
+    assert_eq!(vec![8, 1, 0x92, 1, 0], encode(&message));
+
+    // A direct edit of the fields of a nested message, which stays stale after the changes are
+    // cleared
+    let mut message = decode();
+    match message.fields.get_mut(&18) {
+        Some(Field::Singular(Some(Value::Message(m)))) => {
+            let m = Arc::make_mut(m);
+            m.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+            m.mark_changed(1);
+        }
+        f => panic!("unexpected field: {:?}", f),
+    }
+    message.clear_changes();
+    assert_eq!(vec![8, 1, 0x92, 1, 2, 8, 5], encode(&message));
+
+    let mut message = decode();
+    match message.fields.get_mut(&18) {
+        Some(Field::Singular(Some(Value::Message(m)))) => {
+            let m = Arc::make_mut(m);
+            m.fields.insert(1, Field::Singular(Some(Value::I32(6))));
+            m.clear_source_bytes();
+        }
+        f => panic!("unexpected field: {:?}", f),
+    }
+    assert_eq!(vec![8, 1, 0x92, 1, 2, 8, 6], encode(&message));
+
+    // Merging more input into a decoded message
+    let mut message = decode();
+    message
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&[8, 2]),
+        )
+        .unwrap();
+    assert!(message.source_bytes().is_none());
+    assert_eq!(vec![8, 2, 0x92, 1, 2, 8, 7], encode(&message));
+}
+
+#[test]
+fn changed_field_tracking() {
+    use serde_protobuf::cursor::CursorMut;
+    use serde_protobuf::value::{self, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // optional_nested_message = {bb: 1}, repeated_nested_message = [{bb: 2}]
+    let mut message = value::Message::new(descriptor);
+    message
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&[0x92, 1, 2, 8, 1, 0x82, 3, 2, 8, 2]),
+        )
+        .unwrap();
+    assert!(!message.has_changes());
+
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("optional_nested_message")
+        .unwrap()
+        .field("bb")
+        .unwrap()
+        .set(Value::I32(3))
+        .unwrap();
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("repeated_nested_message")
+        .unwrap()
+        .index(0)
+        .unwrap()
+        .field("bb")
+        .unwrap()
+        .set(Value::I32(4))
+        .unwrap();
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("optional_foreign_message")
+        .unwrap()
+        .field("c")
+        .unwrap()
+        .set(Value::I32(5))
+        .unwrap();
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("repeated_int32")
+        .unwrap()
+        .push(Value::I32(6))
+        .unwrap();
+    // Reading through a cursor isn't a change
+    CursorMut::new(&descriptors, descriptor, &mut message)
+        .field("optional_int64")
+        .unwrap();
+    message.mark_changed(1);
+
+    assert!(message.has_changes());
+    assert_eq!(
+        vec![
+            "optional_int32",
+            "optional_nested_message.bb",
+            "optional_foreign_message",
+            "repeated_int32",
+            "repeated_nested_message",
+        ],
+        message.changed_paths(&descriptors, descriptor).paths
+    );
+
+    message.clear_changes();
+    assert!(!message.has_changes());
+    assert!(message
+        .changed_paths(&descriptors, descriptor)
+        .paths
+        .is_empty());
+}
+
+#[test]
+fn deserialize_error_paths() {
+    let descriptors = descriptors();
+
+    let path_of_error = |bytes: &[u8]| {
+        let input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut deserializer = de::Deserializer::for_named_message(
+            &descriptors,
+            ".protobuf_unittest.NestedTestAllTypes",
+            input,
+        )
+        .unwrap();
+        let result: Result<serde_value::Value, _> =
+            serde_path_to_error::deserialize(&mut deserializer);
+        result.unwrap_err().path().to_string()
+    };
+
+    // payload.repeated_nested_enum = [FOO, 99]
+    assert_eq!(
+        "payload.repeated_nested_enum[1]",
+        path_of_error(&[0x12, 6, 0x98, 3, 1, 0x98, 3, 99])
+    );
+    // child.payload.optional_nested_enum = 99
+    assert_eq!(
+        "child.payload.optional_nested_enum",
+        path_of_error(&[0x0a, 5, 0x12, 3, 0xa8, 1, 99])
+    );
+}
+
+#[test]
+fn deserialize_map_fields_into_maps() {
+    use serde::de::Deserialize;
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut options = protobuf::descriptor::MessageOptions::new();
+    options.set_map_entry(true);
+    let mut entry =
+        descriptor::MessageDescriptor::new(".test.Holder.EntriesEntry").with_options(options);
+    entry.add_field(FieldDescriptor::new(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    entry.add_field(FieldDescriptor::new(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    let mut holder = descriptor::MessageDescriptor::new(".test.Holder");
+    holder.add_field(FieldDescriptor::new(
+        "entries",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Holder.EntriesEntry".to_owned()),
+        None,
+    ));
+    descriptors.add_message(entry);
+    descriptors.add_message(holder);
+    descriptors.resolve_refs();
+    let holder = descriptors.message_by_name(".test.Holder").unwrap();
+
+    // entries { key: "b" }, entries { key: "a" value: 1 }
+    let bytes = [0x0a, 3, 0x0a, 1, b'b', 0x0a, 5, 0x0a, 1, b'a', 0x10, 1];
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, holder, input);
+    let typed: collections::BTreeMap<String, collections::HashMap<String, i32>> =
+        Deserialize::deserialize(&mut deserializer).unwrap();
+    let entries = &typed["entries"];
+    assert_eq!(2, entries.len());
+    assert_eq!(0, entries["b"]);
+    assert_eq!(1, entries["a"]);
+
+    // Self-describing targets still see a sequence of entry messages
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, holder, input);
+    match serde_value::Value::deserialize(&mut deserializer).unwrap() {
+        serde_value::Value::Map(m) => match m[&serde_value::Value::String("entries".to_owned())] {
+            serde_value::Value::Seq(ref entries) => assert_eq!(2, entries.len()),
+            ref v => panic!("unexpected value: {:?}", v),
+        },
+        v => panic!("unexpected value: {:?}", v),
+    }
+}
+
+#[test]
+fn deserialize_enums_by_name() {
+    use serde::de::{self as serde_de, Deserialize, VariantAccess};
+
+    #[derive(Debug, PartialEq)]
+    enum NestedEnum {
+        Foo,
+        Bar,
+        Unknown,
+        Index(u32),
+    }
+
+    // What `#[derive(Deserialize)]` would generate, reduced to the enum fields of TestAllTypes
+    #[derive(Debug, Default)]
+    struct Enums {
+        optional_nested_enum: Option<NestedEnum>,
+        repeated_nested_enum: Vec<NestedEnum>,
+    }
+
+    struct NestedEnumVisitor;
+    struct EnumsVisitor;
+
+    impl<'de> serde_de::Visitor<'de> for NestedEnumVisitor {
+        type Value = NestedEnum;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a NestedEnum variant")
+        }
+
+        fn visit_enum<A>(self, data: A) -> Result<NestedEnum, A::Error>
+        where
+            A: serde_de::EnumAccess<'de>,
+        {
+            let (variant, access) = data.variant::<serde_value::Value>()?;
+            access.unit_variant()?;
+            match variant {
+                serde_value::Value::String(ref s) if s == "FOO" => Ok(NestedEnum::Foo),
+                serde_value::Value::String(ref s) if s == "BAR" => Ok(NestedEnum::Bar),
+                serde_value::Value::String(ref s) if s == "UNKNOWN" => Ok(NestedEnum::Unknown),
+                serde_value::Value::U32(n) => Ok(NestedEnum::Index(n)),
+                v => Err(serde_de::Error::custom(format!(
+                    "unexpected variant {:?}",
+                    v
+                ))),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for NestedEnum {
+        fn deserialize<D>(deserializer: D) -> Result<NestedEnum, D::Error>
+        where
+            D: serde_de::Deserializer<'de>,
+        {
+            deserializer.deserialize_enum("NestedEnum", &["FOO", "BAR"], NestedEnumVisitor)
+        }
+    }
+
+    impl<'de> serde_de::Visitor<'de> for EnumsVisitor {
+        type Value = Enums;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a TestAllTypes message")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Enums, A::Error>
+        where
+            A: serde_de::MapAccess<'de>,
+        {
+            let mut enums = Enums::default();
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "optional_nested_enum" => enums.optional_nested_enum = map.next_value()?,
+                    "repeated_nested_enum" => enums.repeated_nested_enum = map.next_value()?,
+                    _ => {
+                        map.next_value::<serde_de::IgnoredAny>()?;
+                    }
+                }
+            }
+            Ok(enums)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Enums {
+        fn deserialize<D>(deserializer: D) -> Result<Enums, D::Error>
+        where
+            D: serde_de::Deserializer<'de>,
+        {
+            deserializer.deserialize_struct("Enums", &[], EnumsVisitor)
+        }
+    }
+
+    let descriptors = descriptors();
+    let name = ".protobuf_unittest.TestAllTypes";
+
+    // optional_nested_enum = BAR, repeated_nested_enum = [FOO, 99]
+    let bytes = [0xa8, 1, 2, 0x98, 3, 1, 0x98, 3, 99];
+    let deserialize = |options: de::Options| {
+        let input = protobuf::CodedInputStream::from_bytes(&bytes);
+        let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input)
+            .unwrap()
+            .with_options(options);
+        Enums::deserialize(&mut deserializer)
+    };
+
+    assert!(deserialize(de::Options::new()).is_err());
+    let enums = deserialize(
+        de::Options::new()
+            .unknown_enum_values(de::UnknownEnumValues::Variant("UNKNOWN".to_owned())),
+    )
+    .unwrap();
+    assert_eq!(Some(NestedEnum::Bar), enums.optional_nested_enum);
+    assert_eq!(
+        vec![NestedEnum::Foo, NestedEnum::Unknown],
+        enums.repeated_nested_enum
+    );
+    let enums =
+        deserialize(de::Options::new().unknown_enum_values(de::UnknownEnumValues::Number)).unwrap();
+    assert_eq!(
+        vec![NestedEnum::Foo, NestedEnum::Index(99)],
+        enums.repeated_nested_enum
+    );
+
+    // Self-describing targets see the number of unknown values
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::for_named_message(&descriptors, name, input)
+        .unwrap()
+        .with_options(de::Options::new().unknown_enum_values(de::UnknownEnumValues::Number));
+    let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    assert_eq!(
+        Some(&value!(seq [(str: "FOO"), (i32: 99)])),
+        match value {
+            serde_value::Value::Map(ref m) => m.get(&value!(str: "repeated_nested_enum")),
+            _ => None,
+        }
+    );
+}
+
+#[test]
+fn flatten_wrappers_and_proto3_optionals() {
+    use serde::de::Deserialize;
+    use serde_protobuf::descriptor::{
+        FieldDescriptor, FieldLabel, InternalFieldType, OneofDescriptor,
+    };
+
+    let unittest = descriptors();
+    let test_oneof = unittest
+        .message_by_name(".protobuf_unittest.TestOneof")
+        .unwrap();
+    assert_eq!("foo", test_oneof.oneofs()[0].name());
+    assert!(!test_oneof.is_synthetic_oneof(0));
+    assert_eq!(
+        vec!["foo_int", "foo_string", "foo_message"],
+        test_oneof
+            .oneof_fields(0)
+            .map(|f| f.name())
+            .collect::<Vec<_>>()
+    );
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut wrapper = descriptor::MessageDescriptor::new(".google.protobuf.Int32Value");
+    wrapper.add_field(FieldDescriptor::new(
+        "value",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    let mut optionals = descriptor::MessageDescriptor::new(".test.Optionals");
+    optionals.add_oneof(OneofDescriptor::new("_limit"));
+    optionals.add_field(FieldDescriptor::new(
+        "count",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::UnresolvedMessage(".google.protobuf.Int32Value".to_owned()),
+        None,
+    ));
+    optionals.add_field(
+        FieldDescriptor::new(
+            "limit",
+            2,
+            FieldLabel::Optional,
+            InternalFieldType::Int32,
+            None,
+        )
+        .with_oneof_index(0)
+        .with_proto3_optional(true),
+    );
+    descriptors.add_message(wrapper);
+    descriptors.add_message(optionals);
+    descriptors.resolve_refs();
+    let optionals = descriptors.message_by_name(".test.Optionals").unwrap();
+    assert!(optionals.is_synthetic_oneof(0));
+
+    let deserialize = |bytes: &[u8]| {
+        let input = protobuf::CodedInputStream::from_bytes(bytes);
+        let mut deserializer = de::Deserializer::new(&descriptors, optionals, input).with_options(
+            de::Options::new()
+                .flatten_wrappers(true)
+                .emit_defaults(true),
+        );
+        let typed: collections::BTreeMap<String, Option<i32>> =
+            Deserialize::deserialize(&mut deserializer).unwrap();
+        (typed["count"], typed["limit"])
+    };
+
+    assert_eq!((None, None), deserialize(&[]));
+    // count { value: 5 }, limit = 0
+    assert_eq!(
+        (Some(5), Some(0)),
+        deserialize(&[0x0a, 2, 0x08, 5, 0x10, 0])
+    );
+    // count {}
+    assert_eq!((Some(0), None), deserialize(&[0x0a, 0]));
+
+    let snapshot = descriptors.to_snapshot().unwrap();
+    let restored = descriptor::Descriptors::from_snapshot(&snapshot).unwrap();
+    let restored = restored.message_by_name(".test.Optionals").unwrap();
+    assert_eq!("_limit", restored.oneofs()[0].name());
+    assert!(restored.is_synthetic_oneof(0));
+}
+
+#[test]
+fn oneof_representations() {
+    use serde::de::{self as serde_de, Deserialize, VariantAccess};
+
+    #[derive(Debug, PartialEq)]
+    enum Foo {
+        FooInt(i32),
+        FooString(String),
+    }
+
+    struct FooVisitor;
+
+    impl<'de> serde_de::Visitor<'de> for FooVisitor {
+        type Value = Foo;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a foo oneof")
+        }
+
+        fn visit_enum<A>(self, data: A) -> Result<Foo, A::Error>
+        where
+            A: serde_de::EnumAccess<'de>,
+        {
+            let (variant, access) = data.variant::<String>()?;
+            match variant.as_str() {
+                "foo_int" => access.newtype_variant().map(Foo::FooInt),
+                "foo_string" => access.newtype_variant().map(Foo::FooString),
+                v => Err(serde_de::Error::custom(format!("unexpected variant {}", v))),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Foo {
+        fn deserialize<D>(deserializer: D) -> Result<Foo, D::Error>
+        where
+            D: serde_de::Deserializer<'de>,
+        {
+            deserializer.deserialize_enum("Foo", &["foo_int", "foo_string"], FooVisitor)
+        }
+    }
+
+    let descriptors = descriptors();
+    let name = ".protobuf_unittest.TestOneof";
+    let deserializer = |bytes, representation| {
+        de::Deserializer::for_named_message(
+            &descriptors,
+            name,
+            protobuf::CodedInputStream::from_bytes(bytes),
+        )
+        .unwrap()
+        .with_options(de::Options::new().oneofs(representation))
+    };
+
+    // foo_int = 5
+    let mut d = deserializer(&[0x08, 5], de::OneofRepresentation::ExternallyTagged);
+    let typed: collections::BTreeMap<String, Option<Foo>> =
+        Deserialize::deserialize(&mut d).unwrap();
+    assert_eq!(1, typed.len());
+    assert_eq!(Some(Foo::FooInt(5)), typed["foo"]);
+
+    let mut d = deserializer(&[], de::OneofRepresentation::ExternallyTagged);
+    let typed: collections::BTreeMap<String, Option<Foo>> =
+        Deserialize::deserialize(&mut d).unwrap();
+    assert_eq!(None, typed["foo"]);
+
+    // foo_string = "x"
+    let mut d = deserializer(
+        &[0x12, 1, b'x'],
+        de::OneofRepresentation::AdjacentlyTagged {
+            tag: "kind".to_owned(),
+            content: "value".to_owned(),
+        },
+    );
+    assert_eq!(
+        value!(map {
+            (str: "foo") => (some map {
+                (str: "kind") => (str: "foo_string"),
+                (str: "value") => (str: "x")
+            })
+        }),
+        serde_value::Value::deserialize(&mut d).unwrap()
+    );
+
+    let mut d = deserializer(&[0x12, 1, b'x'], de::OneofRepresentation::Flattened);
+    match serde_value::Value::deserialize(&mut d).unwrap() {
+        serde_value::Value::Map(m) => assert!(m.contains_key(&value!(str: "foo_string"))),
+        v => panic!("unexpected value: {:?}", v),
+    }
+}
+
+#[test]
+fn field_numbers_as_keys() {
+    use serde::de::Deserialize;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // optional_int32 = 42, optional_nested_message { bb: 7 }
+    let bytes = [8, 42, 0x92, 1, 2, 8, 7];
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer = de::Deserializer::new(&descriptors, message, input)
+        .with_options(de::Options::new().field_numbers_as_keys(true));
+    let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+    let fields = match value {
+        serde_value::Value::Map(ref m) => m,
+        ref v => panic!("unexpected value: {:?}", v),
+    };
+    assert_eq!(value!(some i32: 42), fields[&value!(str: "1")]);
+    assert_eq!(
+        value!(some map { (str: "1") => (some i32: 7) }),
+        fields[&value!(str: "18")]
+    );
+    assert!(!fields.contains_key(&value!(str: "optional_int32")));
+
+    let m = Message::from_typed(&descriptors, message, &value).unwrap();
+    match m.fields.get(&1) {
+        Some(Field::Singular(Some(Value::I32(42)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match m.fields.get(&18) {
+        Some(Field::Singular(Some(Value::Message(n)))) => match n.fields.get(&1) {
+            Some(Field::Singular(Some(Value::I32(7)))) => (),
+            other => panic!("unexpected field: {:?}", other),
+        },
+        other => panic!("unexpected field: {:?}", other),
+    }
+}
+
+#[test]
+fn wire_anomalies_and_strict_decoding() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{DecodeOptions, Field, Message, Value};
+
+    let descriptors = descriptors();
+    let decode = |name: &str, bytes: &[u8], strict: bool| {
+        let descriptor = descriptors.message_by_name(name).unwrap();
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message
+            .merge_from_with_options(
+                &descriptors,
+                descriptor,
+                &DecodeOptions::new().strict(strict),
+                &mut input,
+            )
+            .map(|()| message)
+    };
+    let all_types = ".protobuf_unittest.TestAllTypes";
+
+    match decode(all_types, &[0x00, 1], false) {
+        Err(Error::BadFieldNumber {
+            number: 0,
+            position: Some(0),
+        }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match decode(all_types, &[0x0e, 1], false) {
+        Err(Error::UndefinedWireType {
+            wire_type: 6,
+            position: 0,
+        }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // optional_int32 = 5, with a padded value and a padded tag
+    for bytes in &[&[0x08, 0x85, 0x00][..], &[0x88, 0x00, 5][..]] {
+        match decode(all_types, bytes, false).unwrap().fields.get(&1) {
+            Some(Field::Singular(Some(Value::I32(5)))) => (),
+            other => panic!("unexpected field: {:?}", other),
+        }
+        match decode(all_types, bytes, true) {
+            Err(Error::NonMinimalVarint { .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    assert!(decode(all_types, &[0x08, 5, 0x28, 0x95, 0x01], true).is_ok());
+
+    // payload { optional_string: <10 bytes> }, but only two bytes remain in payload
+    let bytes = [0x12, 4, 0x72, 10, b'a', b'b'];
+    let nested = ".protobuf_unittest.NestedTestAllTypes";
+    match decode(nested, &bytes, false) {
+        Err(Error::UnexpectedEof { position: 2 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match decode(nested, &bytes, true) {
+        Err(Error::LengthOutOfBounds {
+            length: 10,
+            remaining: 2,
+            position: 3,
+        }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn duplicate_field_policies() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{DecodeOptions, DuplicateFieldPolicy, Field, Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let decode = |bytes: &[u8], policy| {
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message
+            .merge_from_with_options(
+                &descriptors,
+                descriptor,
+                &DecodeOptions::new().duplicate_fields(policy),
+                &mut input,
+            )
+            .map(|()| message)
+    };
+
+    // optional_int32 = 1, optional_int32 = 2, optional_int32 = 3
+    let bytes = [8, 1, 8, 2, 8, 3];
+    let message = decode(&bytes, DuplicateFieldPolicy::Overwrite).unwrap();
+    match message.fields.get(&1) {
+        Some(Field::Singular(Some(Value::I32(3)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    assert!(message.duplicates().is_empty());
+
+    match decode(&bytes, DuplicateFieldPolicy::Error) {
+        Err(Error::DuplicateField { ref name }) if name == "optional_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let message = decode(&bytes, DuplicateFieldPolicy::Collect).unwrap();
+    match message.fields.get(&1) {
+        Some(Field::Singular(Some(Value::I32(3)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match message.duplicates() {
+        [(1, Value::I32(1)), (1, Value::I32(2))] => (),
+        other => panic!("unexpected duplicates: {:?}", other),
+    }
+
+    // Occurrences of optional_nested_message are merged, and repeated_int32 = [1, 1] is fine
+    let bytes = [0x92, 1, 2, 8, 1, 0x92, 1, 0, 0xf8, 1, 1, 0xf8, 1, 1];
+    assert!(decode(&bytes, DuplicateFieldPolicy::Error).is_ok());
+
+    // The declared default of default_int32 is not an earlier occurrence of it
+    let bytes = [0xe8, 3, 5];
+    let message = decode(&bytes, DuplicateFieldPolicy::Error).unwrap();
+    match message.fields.get(&61) {
+        Some(Field::Singular(Some(Value::I32(5)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    let message = decode(&bytes, DuplicateFieldPolicy::Collect).unwrap();
+    assert!(message.duplicates().is_empty());
+    let bytes = [0xe8, 3, 5, 0xe8, 3, 6];
+    match decode(&bytes, DuplicateFieldPolicy::Error) {
+        Err(Error::DuplicateField { ref name }) if name == "default_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // bb is set in both occurrences of optional_nested_message
+    let bytes = [0x92, 1, 2, 8, 1, 0x92, 1, 2, 8, 2];
+    match decode(&bytes, DuplicateFieldPolicy::Error) {
+        Err(Error::DuplicateField { ref name }) if name == "bb" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn semantic_equality() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value::Message;
+
+    let decode = |descriptors: &descriptor::Descriptors, descriptor, bytes: &[u8]| {
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message
+            .merge_from(descriptors, descriptor, &mut input)
+            .unwrap();
+        message
+    };
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // unknown field 1000, repeated_int32 = [], optional_nested_message {}
+    let a = decode(
+        &descriptors,
+        descriptor,
+        &[0xc0, 0x3e, 1, 0xfa, 1, 0, 0x92, 1, 0],
+    );
+    let b = decode(&descriptors, descriptor, &[0x92, 1, 0]);
+    assert!(a.semantic_eq(&descriptors, descriptor, &b));
+    assert!(b.semantic_eq(&descriptors, descriptor, &a));
+
+    // An empty message is not equal to an absent one, and proto2 fields that are set to their
+    // default value are not equal to absent ones
+    let c = decode(&descriptors, descriptor, &[]);
+    let d = decode(&descriptors, descriptor, &[8, 0, 0x92, 1, 0]);
+    let e = decode(&descriptors, descriptor, &[0x92, 1, 2, 8, 0]);
+    assert!(!a.semantic_eq(&descriptors, descriptor, &c));
+    assert!(!a.semantic_eq(&descriptors, descriptor, &d));
+    assert!(!a.semantic_eq(&descriptors, descriptor, &e));
+
+    // The declared defaults that `Message::new` fills in equal absent fields
+    assert!(Message::new(descriptor).semantic_eq(&descriptors, descriptor, &Message::default()));
+
+    // message Counter {
+    //   int32 count = 1;
+    // }
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut field = FieldDescriptorProto::new();
+    field.set_name("count".to_owned());
+    field.set_number(1);
+    field.set_label(Label::LABEL_OPTIONAL);
+    field.set_field_type(Type::TYPE_INT32);
+    let mut counter = DescriptorProto::new();
+    counter.set_name("Counter".to_owned());
+    counter.mut_field().push(field);
+    let mut file = FileDescriptorProto::new();
+    file.set_name("counter.proto".to_owned());
+    file.set_package("counter".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.set_message_type(vec![counter].into());
+    descriptors.add_file_proto(&file);
+    let counter = descriptors.message_by_name(".counter.Counter").unwrap();
+
+    // In proto3, count = 0 is the same as no count, but count = 1 is not
+    let a = decode(&descriptors, counter, &[8, 0]);
+    let b = decode(&descriptors, counter, &[]);
+    let c = decode(&descriptors, counter, &[8, 1]);
+    assert!(a.semantic_eq(&descriptors, counter, &b));
+    assert!(!c.semantic_eq(&descriptors, counter, &b));
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut options = protobuf::descriptor::MessageOptions::new();
+    options.set_map_entry(true);
+    let mut entry =
+        descriptor::MessageDescriptor::new(".test.Holder.EntriesEntry").with_options(options);
+    entry.add_field(FieldDescriptor::new(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    entry.add_field(FieldDescriptor::new(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    let mut holder = descriptor::MessageDescriptor::new(".test.Holder");
+    holder.add_field(FieldDescriptor::new(
+        "entries",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Holder.EntriesEntry".to_owned()),
+        None,
+    ));
+    descriptors.add_message(entry);
+    descriptors.add_message(holder);
+    descriptors.resolve_refs();
+    let holder = descriptors.message_by_name(".test.Holder").unwrap();
+
+    // entries { key: "a" value: 1 }, entries { key: "b" }, entries { key: "a" value: 2 }
+    let a = decode(
+        &descriptors,
+        holder,
+        &[
+            0x0a, 5, 0x0a, 1, b'a', 0x10, 1, 0x0a, 3, 0x0a, 1, b'b', 0x0a, 5, 0x0a, 1, b'a', 0x10,
+            2,
+        ],
+    );
+    // entries { key: "b" value: 0 }, entries { key: "a" value: 2 }
+    let b = decode(
+        &descriptors,
+        holder,
+        &[
+            0x0a, 5, 0x0a, 1, b'b', 0x10, 0, 0x0a, 5, 0x0a, 1, b'a', 0x10, 2,
+        ],
+    );
+    // entries { key: "a" value: 1 }, entries { key: "b" }
+    let c = decode(
+        &descriptors,
+        holder,
+        &[0x0a, 5, 0x0a, 1, b'a', 0x10, 1, 0x0a, 3, 0x0a, 1, b'b'],
+    );
+    assert!(a.semantic_eq(&descriptors, holder, &b));
+    assert!(!a.semantic_eq(&descriptors, holder, &c));
+}
+
+#[test]
+fn sort_and_dedup_repeated_messages_by_field() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let field = descriptor.field_by_name("repeated_nested_message").unwrap();
+    let keys = |message: &Message| match message.fields.get(&48) {
+        Some(Field::Repeated(vs)) => vs
+            .iter()
+            .map(|v| match *v {
+                Value::Message(ref m) => match m.fields.get(&1) {
+                    Some(&Field::Singular(Some(Value::I32(bb)))) => Some(bb),
+                    _ => None,
+                },
+                ref v => panic!("unexpected value: {:?}", v),
+            })
+            .collect::<Vec<_>>(),
+        f => panic!("unexpected field: {:?}", f),
+    };
+
+    // repeated_nested_message = [{ bb: 2 }, { bb: 1 }, {}, { bb: 1 }]
+    let bytes = [
+        0x82, 3, 2, 8, 2, 0x82, 3, 2, 8, 1, 0x82, 3, 0, 0x82, 3, 2, 8, 1,
+    ];
+    let mut message = Message::new(descriptor);
+    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+    message
+        .merge_from(&descriptors, descriptor, &mut input)
+        .unwrap();
+
+    let mut sorted = message.clone();
+    let repeated = sorted.fields.get_mut(&48).unwrap();
+    repeated.sort_by_field(&descriptors, field, "bb").unwrap();
+    assert_eq!(vec![None, Some(1), Some(1), Some(2)], keys(&sorted));
+
+    let repeated = message.fields.get_mut(&48).unwrap();
+    repeated.dedup_by_field(&descriptors, field, "bb").unwrap();
+    assert_eq!(vec![Some(2), None, Some(1)], keys(&message));
+
+    let repeated = message.fields.get_mut(&48).unwrap();
+    match repeated.sort_by_field(&descriptors, field, "cc") {
+        Err(Error::UnknownField { ref name }) if name == "cc" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let field = descriptor.field_by_name("repeated_int32").unwrap();
+    match repeated.dedup_by_field(&descriptors, field, "bb") {
+        Err(Error::BadFieldValue { ref field }) if field == "repeated_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn field_codecs_transform_selected_fields() {
+    use serde_protobuf::descriptor::{FieldDescriptor, MessageDescriptor};
+    use serde_protobuf::error;
+    use serde_protobuf::value::{DecodeOptions, EncodeOptions, Field, FieldCodec, Message, Value};
+
+    // Reverses the content of optional_string and optional_bytes
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl FieldCodec for Reverse {
+        fn applies_to(&self, message: &MessageDescriptor, field: &FieldDescriptor) -> bool {
+            message.name() == ".protobuf_unittest.TestAllTypes"
+                && (field.name() == "optional_string" || field.name() == "optional_bytes")
+        }
+
+        fn encode(
+            &self,
+            _: &MessageDescriptor,
+            _: &FieldDescriptor,
+            content: &[u8],
+        ) -> error::Result<Vec<u8>> {
+            Ok(content.iter().rev().cloned().collect())
+        }
+
+        fn decode(
+            &self,
+            _: &MessageDescriptor,
+            _: &FieldDescriptor,
+            mut content: Vec<u8>,
+        ) -> error::Result<Vec<u8>> {
+            content.reverse();
+            Ok(content)
+        }
+    }
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let codec: Arc<dyn FieldCodec> = Arc::new(Reverse);
+
+    // optional_string = "abc", optional_bytes = [1, 2], optional_cord = "abc"
+    let mut message = Message::default();
+    message
+        .fields
+        .insert(14, Field::Singular(Some(Value::String("abc".to_owned()))));
+    message
+        .fields
+        .insert(15, Field::Singular(Some(Value::Bytes(vec![1, 2]))));
+    message
+        .fields
+        .insert(25, Field::Singular(Some(Value::String("abc".to_owned()))));
+
+    let options = EncodeOptions::new().field_codec(codec.clone());
+    let bytes = message
+        .write_to_bytes_with_options(&descriptors, descriptor, &options)
+        .unwrap();
+    assert_eq!(
+        vec![0x72, 3, b'c', b'b', b'a', 0x7a, 2, 2, 1, 0xca, 1, 3, b'a', b'b', b'c'],
+        bytes
+    );
+
+    let mut decoded = Message::new(descriptor);
+    decoded
+        .merge_from_with_options(
+            &descriptors,
+            descriptor,
+            &DecodeOptions::new().field_codec(codec),
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    assert!(decoded.semantic_eq(&descriptors, descriptor, &message));
+
+    let mut raw = Message::new(descriptor);
+    raw.merge_from(
+        &descriptors,
+        descriptor,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+    match raw.fields.get(&15) {
+        Some(Field::Singular(Some(Value::Bytes(b)))) => assert_eq!(vec![2, 1], *b),
+        f => panic!("unexpected field: {:?}", f),
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_bytes_fields() {
+    use serde_protobuf::compression::{Algorithm, Compression};
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{DecodeOptions, EncodeOptions, Field, Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let decode = |compression: Compression, bytes: &[u8]| {
+        let mut message = Message::new(descriptor);
+        let options = DecodeOptions::new().field_codec(Arc::new(compression));
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        message
+            .merge_from_with_options(&descriptors, descriptor, &options, &mut input)
+            .map(|()| message)
+    };
+
+    for &algorithm in &[Algorithm::Gzip, Algorithm::Zstd] {
+        let compression = Compression::new()
+            .field(
+                ".protobuf_unittest.TestAllTypes",
+                "optional_bytes",
+                algorithm,
+            )
+            .field(
+                ".protobuf_unittest.TestAllTypes",
+                "repeated_bytes",
+                algorithm,
+            );
+
+        // optional_bytes = 1000 zeros, repeated_bytes = ["a", "b"]
+        let mut message = Message::default();
+        message
+            .fields
+            .insert(15, Field::Singular(Some(Value::Bytes(vec![0; 1000]))));
+        message.fields.insert(
+            45,
+            Field::Repeated(
+                vec![Value::Bytes(b"a".to_vec()), Value::Bytes(b"b".to_vec())]
+                    .into_iter()
+                    .collect(),
+            ),
+        );
+        let options = EncodeOptions::new().field_codec(Arc::new(compression.clone()));
+        let bytes = message
+            .write_to_bytes_with_options(&descriptors, descriptor, &options)
+            .unwrap();
+        assert!(bytes.len() < 200, "{:?}: {} bytes", algorithm, bytes.len());
+
+        let decoded = decode(compression.clone(), &bytes).unwrap();
+        assert!(decoded.semantic_eq(&descriptors, descriptor, &message));
+
+        // Content that changed in place since a size was computed is compressed again
+        message
+            .compute_size_with_options(&descriptors, descriptor, &options)
+            .unwrap();
+        if let Some(&mut Field::Singular(Some(Value::Bytes(ref mut content)))) =
+            message.fields.get_mut(&15)
+        {
+            content[0] = 1;
+        }
+        let bytes = message
+            .write_to_bytes_with_options(&descriptors, descriptor, &options)
+            .unwrap();
+        let decoded = decode(compression.clone(), &bytes).unwrap();
+        assert!(decoded.semantic_eq(&descriptors, descriptor, &message));
+
+        match decode(compression.max_decompressed_size(999), &bytes) {
+            Err(Error::DecompressedTooLarge { ref field, limit }) => {
+                assert_eq!("optional_bytes", field);
+                assert_eq!(999, limit);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn decode_by_message_name() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{Field, Value};
+
+    let descriptors = descriptors();
+
+    for name in &[
+        ".protobuf_unittest.TestAllTypes",
+        "protobuf_unittest.TestAllTypes",
+    ] {
+        let message = descriptors.decode(name, &[8, 42]).unwrap();
+        match message.fields.get(&1) {
+            Some(Field::Singular(Some(Value::I32(42)))) => (),
+            f => panic!("unexpected field: {:?}", f),
+        }
+    }
+
+    match descriptors.decode("protobuf_unittest.TestNothing", &[]) {
+        Err(Error::UnknownMessageType {
+            ref name,
+            ref available,
+        }) => {
+            assert_eq!("protobuf_unittest.TestNothing", name);
+            assert!(available.contains(&".protobuf_unittest.TestAllTypes".to_owned()));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_many_records() {
+    use serde_protobuf::value::{DecodeOptions, Decoder, Field, UnknownFieldPolicy, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // optional_int32 = i, optional_nested_message { bb: i }, unknown field 1000 = i
+    let records = (1..=3u8)
+        .map(|i| vec![8, i, 0x92, 1, 2, 8, i, 0xc0, 0x3e, i])
+        .collect::<Vec<_>>();
+    let mut decoder = Decoder::new(&descriptors, descriptor)
+        .with_options(DecodeOptions::new().unknown_fields(UnknownFieldPolicy::Drop));
+    let mut values = Vec::new();
+    let mut messages = decoder.decode_many(&records);
+    while let Some(message) = messages.next() {
+        let message = message.unwrap();
+        assert!(message.unknown.iter().next().is_none());
+        match (message.fields.get(&1), message.fields.get(&18)) {
+            (
+                Some(&Field::Singular(Some(Value::I32(v)))),
+                Some(Field::Singular(Some(Value::Message(nested)))),
+            ) => match nested.fields.get(&1) {
+                Some(&Field::Singular(Some(Value::I32(bb)))) if bb == v => values.push(v),
+                f => panic!("unexpected nested field: {:?}", f),
+            },
+            f => panic!("unexpected fields: {:?}", f),
+        }
+        messages.recycle(message);
+    }
+    assert_eq!(vec![1, 2, 3], values);
+
+    // Recycled messages don't carry over fields to the next record
+    let message = decoder.decode(&[0x92, 1, 0]).unwrap();
+    match message.fields.get(&1) {
+        Some(Field::Singular(None)) => (),
+        f => panic!("unexpected field: {:?}", f),
+    }
+    assert!(decoder.decode(&[0x92]).is_err());
+}
+
+#[test]
+fn profile_messages() {
+    use serde_protobuf::profile::{FieldProfile, Profiler};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut profiler = Profiler::new(&descriptors, descriptor);
+    // optional_int32 = 125, repeated_string = ["a", "bcd"], repeated_nested_message = [{ bb: 7 }]
+    profiler
+        .add_bytes(&[
+            8, 0x7d, 0xe2, 2, 1, b'a', 0xe2, 2, 3, b'b', b'c', b'd', 0x82, 3, 2, 8, 7,
+        ])
+        .unwrap();
+    // optional_int32 = 4, repeated_nested_message = [{ bb: 1, 1000: 1 }, {}]
+    profiler
+        .add_bytes(&[8, 4, 0x82, 3, 5, 8, 1, 0xc0, 0x3e, 1, 0x82, 3, 0])
+        .unwrap();
+    let report = profiler.into_report();
+
+    assert_eq!(2, report.messages);
+    let int32 = &report.fields["optional_int32"];
+    assert_eq!(1.0, int32.presence_rate());
+    assert_eq!((Some(4.0), Some(125.0)), (int32.min, int32.max));
+    assert_eq!(
+        FieldProfile {
+            messages: 2,
+            present: 1,
+            values: 2,
+            min: None,
+            max: None,
+            min_len: Some(1),
+            max_len: Some(3),
+        },
+        report.fields["repeated_string"]
+    );
+    let bb = &report.fields["repeated_nested_message.bb"];
+    assert_eq!((3, 2, 2), (bb.messages, bb.present, bb.values));
+    assert_eq!(0.0, report.fields["optional_int64"].presence_rate());
+    assert!(!report.fields.contains_key("optional_nested_message.bb"));
+    assert_eq!(
+        vec![("repeated_nested_message.1000".to_owned(), 1)],
+        report.undeclared.into_iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn infer_descriptors_from_payloads() {
+    use protobuf::descriptor::FieldDescriptorProto_Label as Label;
+    use protobuf::descriptor::FieldDescriptorProto_Type as Type;
+    use serde_protobuf::infer::SchemaInference;
+    use serde_protobuf::value::{Field, Value};
+
+    let mut inference = SchemaInference::new();
+    // 1: 150, 2: "hi", 3: { 1: 1, 2: "x\n" }, 3: { 1: 2 }, 4: -1 (as 10 bytes), 5: fixed32 7
+    inference
+        .add(&[
+            8, 0x96, 1, 0x12, 2, b'h', b'i', 0x1a, 6, 8, 1, 0x12, 2, b'x', b'\n', 0x1a, 2, 8, 2,
+            0x20, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1, 0x2d, 7, 0, 0, 0,
+        ])
+        .unwrap();
+    // 1: 1 << 40, 2: [0xff], 6: "a"
+    inference
+        .add(&[
+            8, 0x80, 0x80, 0x80, 0x80, 0x80, 0x20, 0x12, 1, 0xff, 0x32, 1, b'a',
+        ])
+        .unwrap();
+    // Broken payloads don't add anything
+    assert!(inference.add(&[0x38]).is_err());
+
+    let proto = inference.descriptor_proto(".legacy.Feed");
+    assert_eq!("Feed", proto.get_name());
+    let fields = proto
+        .get_field()
+        .iter()
+        .map(|f| (f.get_name(), f.get_label(), f.get_field_type()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            ("field_1", Label::LABEL_OPTIONAL, Type::TYPE_INT64),
+            ("field_2", Label::LABEL_OPTIONAL, Type::TYPE_BYTES),
+            ("field_3", Label::LABEL_REPEATED, Type::TYPE_MESSAGE),
+            ("field_4", Label::LABEL_OPTIONAL, Type::TYPE_INT32),
+            ("field_5", Label::LABEL_OPTIONAL, Type::TYPE_FIXED32),
+            ("field_6", Label::LABEL_OPTIONAL, Type::TYPE_STRING),
+        ],
+        fields
+    );
+    assert_eq!(".legacy.Feed.Field3", proto.get_field()[2].get_type_name());
+
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_message_proto(".legacy", &proto);
+    descriptors.resolve_refs();
+    let message = descriptors
+        .decode(".legacy.Feed", &[0x1a, 4, 0x12, 2, b'o', b'k'])
+        .unwrap();
+    match message.fields.get(&3) {
+        Some(Field::Repeated(vs)) => match vs[0] {
+            Value::Message(ref m) => match m.fields.get(&2) {
+                Some(Field::Singular(Some(Value::String(s)))) => assert_eq!("ok", s),
+                f => panic!("unexpected field: {:?}", f),
+            },
+            ref v => panic!("unexpected value: {:?}", v),
+        },
+        f => panic!("unexpected field: {:?}", f),
+    }
+}
+
+#[test]
+fn truncate_messages_to_size() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::field_mask::FieldMask;
+    use serde_protobuf::value::{Message, TruncationReport, TruncationStrategy};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // optional_int32 = 5, optional_string = 100 x "x", optional_nested_message { bb: 1 },
+    // repeated_int32 = [1, 2, 3], unknown field 1000 = 1
+    let mut bytes = vec![8, 5, 0x72, 100];
+    bytes.extend(vec![b'x'; 100]);
+    bytes.extend(&[
+        0x92, 1, 2, 8, 1, 0xf8, 1, 1, 0xf8, 1, 2, 0xf8, 1, 3, 0xc0, 0x3e, 1,
+    ]);
+    let mut original = Message::default();
+    original
+        .merge_from(
+            &descriptors,
+            descriptor,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    assert_eq!(
+        121,
+        original.compute_size(&descriptors, descriptor).unwrap()
+    );
+
+    let mut message = original.clone();
+    let report = message
+        .truncate_to(
+            &descriptors,
+            descriptor,
+            10,
+            &TruncationStrategy::LargestFirst,
+        )
+        .unwrap();
+    assert_eq!(
+        TruncationReport {
+            cleared: vec![
+                "optional_string".to_owned(),
+                "optional_nested_message".to_owned()
+            ],
+            trimmed: vec![("repeated_int32".to_owned(), 2)],
+            size: 8,
+        },
+        report
+    );
+    assert_eq!(
+        8,
+        message
+            .write_to_bytes(&descriptors, descriptor)
+            .unwrap()
+            .len()
+    );
+    assert!(message.fields.get(&14).unwrap().is_empty());
+    assert_eq!(1, message.fields.get(&31).unwrap().len());
+
+    // Unknown fields are never removed
+    let mut message = original.clone();
+    match message.truncate_to(
+        &descriptors,
+        descriptor,
+        2,
+        &TruncationStrategy::LargestFirst,
+    ) {
+        Err(Error::MessageTooLarge { size: 3, limit: 2 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let mask = FieldMask::new(vec![
+        "optional_nested_message.bb",
+        "repeated_int32",
+        "optional_string",
+        "optional_int32",
+    ]);
+    let mut message = original.clone();
+    let report = message
+        .truncate_to(
+            &descriptors,
+            descriptor,
+            115,
+            &TruncationStrategy::Priority(mask.clone()),
+        )
+        .unwrap();
+    assert_eq!(
+        vec![
+            "optional_nested_message.bb".to_owned(),
+            "repeated_int32".to_owned()
+        ],
+        report.cleared
+    );
+    assert_eq!(110, report.size);
+    assert_eq!(
+        FieldMask::new(vec!["optional_nested_message.bb", "repeated_int32"]),
+        message.changed_paths(&descriptors, descriptor)
+    );
+
+    let mut message = original.clone();
+    let mask = FieldMask::new(vec!["no_such_field"]);
+    match message.truncate_to(
+        &descriptors,
+        descriptor,
+        0,
+        &TruncationStrategy::Priority(mask),
+    ) {
+        Err(Error::BadFieldMaskPath { ref path }) if path == "no_such_field" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn split_and_join_by_repeated_field() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    // optional_int32 = 5 (2 bytes), repeated_string = 5 x 8 characters (11 bytes each)
+    let mut original = Message::default();
+    original
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(5))));
+    original.fields.insert(
+        44,
+        Field::Repeated(
+            (0..5)
+                .map(|i| Value::String(format!("element{}", i)))
+                .collect(),
+        ),
+    );
+    assert_eq!(57, original.compute_size(&descriptors, descriptor).unwrap());
+
+    let messages = original
+        .split_by_field(&descriptors, descriptor, "repeated_string", 25)
+        .unwrap();
+    assert_eq!(
+        vec![2, 2, 1],
+        messages
+            .iter()
+            .map(|m| m.fields[&44].len())
+            .collect::<Vec<_>>()
+    );
+    for message in &messages {
+        assert!(message.compute_size(&descriptors, descriptor).unwrap() <= 25);
+        assert!(message.fields.contains_key(&1));
+        assert!(message.has_changes());
+    }
+
+    let joined = Message::join_by_field(descriptor, "repeated_string", messages).unwrap();
+    assert!(joined.semantic_eq(&descriptors, descriptor, &original));
+    match joined.fields[&44] {
+        Field::Repeated(ref vs) => match vs[4] {
+            Value::String(ref s) => assert_eq!("element4", s),
+            ref v => panic!("unexpected value: {:?}", v),
+        },
+        ref f => panic!("unexpected field: {:?}", f),
+    }
+
+    let messages = original
+        .split_by_field(&descriptors, descriptor, "repeated_string", 57)
+        .unwrap();
+    assert_eq!(1, messages.len());
+    assert!(!messages[0].has_changes());
+
+    match original.split_by_field(&descriptors, descriptor, "repeated_string", 10) {
+        Err(Error::MessageTooLarge {
+            size: 13,
+            limit: 10,
+        }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match original.split_by_field(&descriptors, descriptor, "optional_int32", 10) {
+        Err(Error::BadFieldValue { ref field }) if field == "optional_int32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match Message::join_by_field(descriptor, "no_such_field", Vec::new()) {
+        Err(Error::UnknownField { ref name }) if name == "no_such_field" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn convert_to_and_from_generated_types() {
+    use serde_protobuf::error::Error;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_int32(5);
+    v.mut_optional_nested_message().set_bb(1);
+    v.mut_repeated_string().push("a".to_owned());
+
+    let mut message = Message::from_generated(&descriptors, descriptor, &v).unwrap();
+    match message.fields[&1] {
+        Field::Singular(Some(Value::I32(5))) => (),
+        ref f => panic!("unexpected field: {:?}", f),
+    }
+    message
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(6))));
+
+    let generated: protobuf_unittest::unittest::TestAllTypes = message
+        .clone()
+        .into_generated(&descriptors, descriptor)
+        .unwrap();
+    assert_eq!(6, generated.get_optional_int32());
+    assert_eq!(1, generated.get_optional_nested_message().get_bb());
+    assert_eq!(&["a".to_owned()], generated.get_repeated_string());
+
+    match message
+        .into_generated::<protobuf_unittest::unittest::ForeignMessage>(&descriptors, descriptor)
+    {
+        Err(Error::MessageTypeMismatch {
+            ref expected,
+            ref actual,
+        }) => {
+            assert_eq!(".protobuf_unittest.ForeignMessage", expected);
+            assert_eq!(".protobuf_unittest.TestAllTypes", actual);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn descriptors_from_generated_code() {
+    use protobuf_unittest::{unittest, unittest_import, unittest_import_public};
+
+    let descriptors = descriptor::Descriptors::from_generated(vec![
+        unittest::file_descriptor_proto(),
+        unittest_import::file_descriptor_proto(),
+        unittest_import_public::file_descriptor_proto(),
+        unittest_import::file_descriptor_proto(),
+    ]);
+    assert!(descriptors.unresolved_refs().is_empty());
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    assert_eq!(
+        7,
+        descriptor
+            .field_by_name("optional_fixed32")
+            .unwrap()
+            .number()
+    );
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest_import.ImportMessage")
+        .is_some());
+
+    let bytes = fs::read("testdata/descriptors.pb").unwrap();
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_encoded_file_set_proto(&bytes).unwrap();
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .is_some());
+    assert!(descriptors.add_encoded_file_set_proto(&[0xff]).is_err());
+}
+
+#[test]
+fn load_descriptor_sets_from_directories() {
+    use protobuf::Message;
+    use serde_protobuf::error::Error;
+
+    let dir = std::env::temp_dir().join(format!("serde-protobuf-loader-{}", std::process::id()));
+    let bytes = fs::read("testdata/descriptors.pb").unwrap();
+    fs::create_dir_all(dir.join("a")).unwrap();
+    fs::create_dir_all(dir.join("b/c")).unwrap();
+    // The same files in several sets are only loaded once
+    fs::write(dir.join("a/unittest.binpb"), &bytes).unwrap();
+    fs::write(dir.join("b/c/unittest.desc"), &bytes).unwrap();
+    fs::write(dir.join("b/README"), b"not a descriptor set").unwrap();
+    // Links that form a cycle are only walked once
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&dir, dir.join("b/c/loop")).unwrap();
+
+    let descriptors = descriptor::Descriptors::from_dir(&dir).unwrap();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    assert_eq!(
+        7,
+        descriptor
+            .field_by_name("optional_fixed32")
+            .unwrap()
+            .number()
+    );
+
+    // A different file that defines the same type conflicts
+    let mut file_set: protobuf::descriptor::FileDescriptorSet =
+        protobuf::parse_from_bytes(&bytes).unwrap();
+    let mut file = protobuf::descriptor::FileDescriptorProto::new();
+    file.set_name("copy.proto".to_owned());
+    file.set_package("protobuf_unittest".to_owned());
+    file.mut_message_type().push(
+        file_set
+            .get_file()
+            .iter()
+            .flat_map(|f| f.get_message_type())
+            .find(|m| m.get_name() == "TestAllTypes")
+            .unwrap()
+            .clone(),
+    );
+    file_set.mut_file().clear();
+    file_set.mut_file().push(file);
+    fs::write(dir.join("b/copy.pb"), file_set.write_to_bytes().unwrap()).unwrap();
+
+    let result = descriptor::DescriptorLoader::new().dir(&dir).load();
+    fs::remove_dir_all(&dir).unwrap();
+    match result {
+        Err(Error::DescriptorConflict {
+            ref name,
+            ref first,
+            ref second,
+        }) => {
+            assert_eq!(".protobuf_unittest.TestAllTypes", name);
+            assert!(first.ends_with("unittest.binpb"));
+            assert!(second.ends_with("copy.pb"));
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn merge_descriptors_with_conflicts() {
+    use serde_protobuf::error::Error;
+
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto: protobuf::descriptor::FileDescriptorSet =
+        protobuf::parse_from_reader(&mut file).unwrap();
+    let mut descriptors = descriptor::Descriptors::from_proto(&proto);
+    descriptors.resolve_refs();
+    assert_eq!(
+        Some("google/protobuf/unittest.proto"),
+        descriptors.file_of_type(".protobuf_unittest.TestAllTypes")
+    );
+
+    // Identical definitions merge cleanly
+    descriptors.add_file_set_proto_checked(&proto).unwrap();
+
+    // A stale definition of a type is reported along with a new type that isn't added
+    let mut stale = protobuf::descriptor::FileDescriptorProto::new();
+    stale.set_name("stale.proto".to_owned());
+    stale.set_package("protobuf_unittest".to_owned());
+    let mut message = protobuf::descriptor::DescriptorProto::new();
+    message.set_name("ForeignMessage".to_owned());
+    let mut field = protobuf::descriptor::FieldDescriptorProto::new();
+    field.set_name("c".to_owned());
+    field.set_number(1);
+    field.set_label(protobuf::descriptor::FieldDescriptorProto_Label::LABEL_OPTIONAL);
+    field.set_field_type(protobuf::descriptor::FieldDescriptorProto_Type::TYPE_STRING);
+    message.mut_field().push(field);
+    stale.mut_message_type().push(message);
+    let mut added = protobuf::descriptor::DescriptorProto::new();
+    added.set_name("Added".to_owned());
+    let mut field = protobuf::descriptor::FieldDescriptorProto::new();
+    field.set_name("foreign".to_owned());
+    field.set_number(1);
+    field.set_label(protobuf::descriptor::FieldDescriptorProto_Label::LABEL_OPTIONAL);
+    field.set_field_type(protobuf::descriptor::FieldDescriptorProto_Type::TYPE_MESSAGE);
+    field.set_type_name(".protobuf_unittest.ForeignMessage".to_owned());
+    added.mut_field().push(field);
+    stale.mut_message_type().push(added);
+    let mut stale_set = protobuf::descriptor::FileDescriptorSet::new();
+    stale_set.mut_file().push(stale.clone());
+
+    match descriptors.add_file_set_proto_checked(&stale_set) {
+        Err(Error::TypeConflicts { ref conflicts }) => {
+            assert_eq!(
+                vec![descriptor::TypeConflict {
+                    name: ".protobuf_unittest.ForeignMessage".to_owned(),
+                    first_file: Some("google/protobuf/unittest.proto".to_owned()),
+                    second_file: Some("stale.proto".to_owned()),
+                    differences: vec!["field 1 (c): type int32 != string".to_owned()],
+                }],
+                *conflicts
+            );
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest.Added")
+        .is_none());
+
+    // Without the stale type, the new type is added and refers to the existing one
+    stale.mut_message_type().remove(0);
+    let mut other = descriptor::Descriptors::new();
+    other.add_file_proto(&stale);
+    other.add_file_set_proto(&proto);
+    other.resolve_refs();
+    descriptors.merge(&other).unwrap();
+    descriptors.resolve_refs();
+    let added = descriptors
+        .message_by_name(".protobuf_unittest.Added")
+        .unwrap();
+    match added
+        .field_by_name("foreign")
+        .unwrap()
+        .field_type(&descriptors)
+    {
+        descriptor::FieldType::Message(m) => {
+            assert_eq!(".protobuf_unittest.ForeignMessage", m.name())
+        }
+        t => panic!("unexpected type: {:?}", t),
+    }
+    assert_eq!(
+        Some("stale.proto"),
+        descriptors.file_of_type(".protobuf_unittest.Added")
+    );
+}
+
+#[test]
+fn filter_descriptors_by_package() {
+    let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+    let proto = protobuf::parse_from_reader(&mut file).unwrap();
+
+    let descriptors =
+        descriptor::Descriptors::from_proto_packages(&proto, &["protobuf_unittest_import."]);
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest_import.ImportMessage")
+        .is_some());
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .is_none());
+
+    // Imported files are loaded as well
+    let descriptors = descriptor::Descriptors::from_proto_packages(&proto, &["protobuf_unittest."]);
+    assert!(descriptors.unresolved_refs().is_empty());
+
+    let mut descriptors = descriptor::Descriptors::from_proto(&proto);
+    descriptors.resolve_refs();
+    descriptors.retain_packages(&["protobuf_unittest.TestAllTypes"]);
+    descriptors.resolve_refs();
+    assert!(descriptors.unresolved_refs().is_empty());
+    for name in &[
+        ".protobuf_unittest.TestAllTypes",
+        ".protobuf_unittest.TestAllTypes.NestedMessage",
+        ".protobuf_unittest.ForeignMessage",
+        ".protobuf_unittest_import.ImportMessage",
+    ] {
+        assert!(descriptors.message_by_name(name).is_some(), "{}", name);
+    }
+    assert!(descriptors
+        .enum_by_name(".protobuf_unittest.TestAllTypes.NestedEnum")
+        .is_some());
+    assert!(descriptors
+        .message_by_name(".protobuf_unittest.TestRequired")
+        .is_none());
+    assert!(descriptors
+        .service_by_name(".protobuf_unittest.TestService")
+        .is_none());
+    assert_eq!(
+        Some("google/protobuf/unittest.proto"),
+        descriptors.file_of_type(".protobuf_unittest.TestAllTypes")
+    );
+}
+
+#[test]
+fn resolve_relative_type_names_along_imports() {
+    use protobuf::descriptor::FieldDescriptorProto_Label as Label;
+    use protobuf::descriptor::FieldDescriptorProto_Type as Type;
+    use protobuf::descriptor::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto};
+    use serde_protobuf::value::Message;
+
+    fn file(name: &str, package: &str, messages: Vec<DescriptorProto>) -> FileDescriptorProto {
+        let mut file = FileDescriptorProto::new();
+        file.set_name(name.to_owned());
+        file.set_package(package.to_owned());
+        file.set_message_type(messages.into());
+        file
+    }
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        let mut message = DescriptorProto::new();
+        message.set_name(name.to_owned());
+        message.set_field(fields.into());
+        message
+    }
+    fn field(name: &str, number: i32, type_name: &str) -> FieldDescriptorProto {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(number);
+        field.set_label(Label::LABEL_OPTIONAL);
+        field.set_type_name(type_name.to_owned());
+        field
+    }
+
+    let base = file("base.proto", "base", vec![message("Base", vec![])]);
+    // Re-exports base.proto
+    let mut reexport = file("reexport.proto", "reexport", vec![]);
+    reexport.mut_dependency().push("base.proto".to_owned());
+    reexport.mut_public_dependency().push(0);
+    let hidden = file("hidden.proto", "hidden", vec![message("Hidden", vec![])]);
+
+    let mut weak = field("weak", 4, ".weak.Weak");
+    weak.set_field_type(Type::TYPE_MESSAGE);
+    weak.mut_options().set_weak(true);
+    let mut hidden_field = field("hidden", 3, "hidden.Hidden");
+    hidden_field.set_field_type(Type::TYPE_MESSAGE);
+    let mut outer = message(
+        "Outer",
+        vec![
+            field("inner", 1, "Inner"),
+            field("base", 2, "base.Base"),
+            hidden_field,
+            weak,
+        ],
+    );
+    outer.mut_nested_type().push(message("Inner", vec![]));
+    let mut main = file("main.proto", "app", vec![outer]);
+    main.mut_dependency().push("reexport.proto".to_owned());
+    // A weak import that is missing from the set
+    main.mut_dependency().push("weak.proto".to_owned());
+    main.mut_weak_dependency().push(1);
+
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.set_file(vec![base, reexport, hidden, main].into());
+    let descriptors = descriptor::Descriptors::from_proto(&set);
+    let outer = descriptors.message_by_name(".app.Outer").unwrap();
+
+    let type_of = |name: &str| match outer.field_by_name(name).unwrap().field_type(&descriptors) {
+        descriptor::FieldType::Message(m) => m.name().to_owned(),
+        descriptor::FieldType::UnresolvedMessage(n) => format!("unresolved {}", n),
+        t => panic!("unexpected type: {:?}", t),
+    };
+    assert_eq!(".app.Outer.Inner", type_of("inner"));
+    assert_eq!(".base.Base", type_of("base"));
+    // Not imported
+    assert_eq!("unresolved hidden.Hidden", type_of("hidden"));
+    assert_eq!(vec!["hidden.Hidden"], descriptors.unresolved_refs());
+
+    // Weak fields of missing types are decoded as unknown fields
+    let mut message = Message::new(outer);
+    message
+        .merge_from(
+            &descriptors,
+            outer,
+            &mut protobuf::CodedInputStream::from_bytes(&[0x22, 0]),
+        )
+        .unwrap();
+    assert!(message.unknown.get(4).is_some());
+    assert!(message.fields[&4].is_empty());
+}
+
+#[test]
+fn source_locations_and_comments() {
+    use protobuf::descriptor::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+        FileDescriptorProto, SourceCodeInfo_Location,
+    };
+
+    fn location(path: Vec<i32>, span: Vec<i32>, leading: &str) -> SourceCodeInfo_Location {
+        let mut location = SourceCodeInfo_Location::new();
+        location.set_path(path);
+        location.set_span(span);
+        if !leading.is_empty() {
+            location.set_leading_comments(leading.to_owned());
+        }
+        location
+    }
+
+    let mut field = FieldDescriptorProto::new();
+    field.set_name("id".to_owned());
+    field.set_number(1);
+    let mut nested = DescriptorProto::new();
+    nested.set_name("Nested".to_owned());
+    nested.mut_field().push(field);
+    let mut message = DescriptorProto::new();
+    message.set_name("Message".to_owned());
+    message.mut_nested_type().push(nested);
+    let mut value = EnumValueDescriptorProto::new();
+    value.set_name("RED".to_owned());
+    let mut e = EnumDescriptorProto::new();
+    e.set_name("Color".to_owned());
+    e.mut_value().push(value);
+
+    let mut file = FileDescriptorProto::new();
+    file.set_name("app.proto".to_owned());
+    file.set_package("app".to_owned());
+    file.mut_message_type().push(message);
+    file.mut_enum_type().push(e);
+    let locations = file.mut_source_code_info().mut_location();
+    locations.push(location(vec![], vec![0, 0, 12, 0], ""));
+    locations.push(location(vec![4, 0], vec![2, 0, 6, 1], " A message.\n"));
+    locations.push(location(
+        vec![4, 0, 3, 0, 2, 0],
+        vec![4, 4, 18],
+        " The ID.\n",
+    ));
+    // The type of the field, not the field itself
+    locations.push(location(vec![4, 0, 3, 0, 2, 0, 5], vec![4, 4, 9], ""));
+    let mut red = location(vec![5, 0, 2, 0], vec![9, 2, 10], "");
+    red.set_trailing_comments(" The color red.\n".to_owned());
+    red.mut_leading_detached_comments()
+        .push(" Detached.\n".to_owned());
+    locations.push(red);
+
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.mut_file().push(file);
+    let descriptors = descriptor::Descriptors::from_proto(&set);
+
+    let message = descriptors.source_location(".app.Message").unwrap();
+    assert_eq!("app.proto", message.file);
+    assert_eq!(
+        (2, 0, 6, 1),
+        (
+            message.start_line,
+            message.start_column,
+            message.end_line,
+            message.end_column
+        )
+    );
+    assert_eq!(Some(" A message.\n"), message.leading_comments.as_deref());
+
+    let field = descriptors
+        .source_location(".app.Message.Nested.id")
+        .unwrap();
+    assert_eq!(
+        (4, 4, 4, 18),
+        (
+            field.start_line,
+            field.start_column,
+            field.end_line,
+            field.end_column
+        )
+    );
+    assert_eq!(Some(" The ID.\n"), field.leading_comments.as_deref());
+
+    let red = descriptors.source_location(".app.Color.RED").unwrap();
+    assert_eq!(None, red.leading_comments);
+    assert_eq!(Some(" The color red.\n"), red.trailing_comments.as_deref());
+    assert_eq!(vec![" Detached.\n"], red.leading_detached_comments);
+    assert!(descriptors.source_location(".app.Color").is_none());
+
+    // Source locations survive snapshots, merging and filtering
+    let snapshot =
+        descriptor::Descriptors::from_snapshot(&descriptors.to_snapshot().unwrap()).unwrap();
+    assert_eq!(Some(red), snapshot.source_location(".app.Color.RED"));
+    let mut merged = descriptor::Descriptors::new();
+    merged.merge(&descriptors).unwrap();
+    assert_eq!(
+        Some(field),
+        merged.source_location(".app.Message.Nested.id")
+    );
+    merged.retain_packages(&["app.Color"]);
+    assert!(merged.source_location(".app.Color.RED").is_some());
+    assert!(merged.source_location(".app.Message.Nested.id").is_none());
+}
+
+#[test]
+fn report_deprecated_fields() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value::{
+        DecodeOptions, DeprecatedFieldCallback, DeprecatedFieldPolicy, EncodeOptions, Message,
+    };
+    use std::sync::{Arc, Mutex};
+
+    let mut deprecated = protobuf::descriptor::FieldOptions::new();
+    deprecated.set_deprecated(true);
+    let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+    inner.add_field(
+        FieldDescriptor::new(
+            "old",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::String,
+            None,
+        )
+        .with_options(deprecated.clone()),
+    );
+    let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+    outer.add_field(FieldDescriptor::new(
+        "inner",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Inner".to_owned()),
+        None,
+    ));
+    outer.add_field(
+        FieldDescriptor::new(
+            "legacy",
+            2,
+            FieldLabel::Repeated,
+            InternalFieldType::Int32,
+            None,
+        )
+        .with_options(deprecated),
+    );
+    let mut descriptors = descriptor::Descriptors::new();
+    descriptors.add_message(inner);
+    descriptors.add_message(outer);
+    descriptors.resolve_refs();
+    let outer = descriptors.message_by_name(".test.Outer").unwrap();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    let policy =
+        DeprecatedFieldPolicy::Callback(DeprecatedFieldCallback::new(move |path, field| {
+            sink.lock().unwrap().push((path.to_owned(), field.number()))
+        }));
+
+    // legacy = 1, legacy = 2, inner { old = "a" }, inner {}
+    let bytes = [
+        0x10, 0x01, 0x10, 0x02, 0x0a, 0x03, 0x0a, 0x01, b'a', 0x0a, 0x00,
+    ];
+    let mut message = Message::new(outer);
+    message
+        .merge_from_with_options(
+            &descriptors,
+            outer,
+            &DecodeOptions::new().deprecated_fields(policy.clone()),
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    // Once per message, after its nested messages
+    assert_eq!(
+        vec![("inner.old".to_owned(), 1), ("legacy".to_owned(), 2)],
+        *seen.lock().unwrap()
+    );
+
+    seen.lock().unwrap().clear();
+    message
+        .write_to_bytes_with_options(
+            &descriptors,
+            outer,
+            &EncodeOptions::new().deprecated_fields(policy),
+        )
+        .unwrap();
+    assert_eq!(
+        vec![("inner.old".to_owned(), 1), ("legacy".to_owned(), 2)],
+        *seen.lock().unwrap()
+    );
+
+    // Not looked for by default
+    seen.lock().unwrap().clear();
+    message.write_to_bytes(&descriptors, outer).unwrap();
+    assert!(seen.lock().unwrap().is_empty());
+}
+
+#[test]
+fn list_types_of_registry() {
+    let descriptors = descriptors();
+
+    assert!(descriptors
+        .messages()
+        .iter()
+        .any(|m| m.name() == ".protobuf_unittest.TestAllTypes.NestedMessage"));
+    assert!(descriptors
+        .enums()
+        .iter()
+        .any(|e| e.name() == ".protobuf_unittest.ForeignEnum"));
+    assert!(descriptors
+        .files()
+        .contains(&"google/protobuf/unittest.proto"));
+
+    let packages = ["protobuf_unittest_import."];
+    assert!(descriptors
+        .messages_in_packages(&packages)
+        .all(|m| m.name().starts_with(".protobuf_unittest_import.")));
+    assert!(descriptors
+        .enums_in_packages(&packages)
+        .any(|e| e.name() == ".protobuf_unittest_import.ImportEnum"));
+    assert_eq!(0, descriptors.services_in_packages(&packages).count());
+    assert!(descriptors
+        .services_in_packages(&["protobuf_unittest."])
+        .any(|s| s.name() == ".protobuf_unittest.TestService"));
+    assert_eq!(
+        vec![
+            "google/protobuf/unittest_import.proto",
+            "google/protobuf/unittest_import_public.proto"
+        ],
+        descriptors.files_in_packages(&packages)
+    );
+}
+
+#[test]
+fn export_file_descriptor_protos() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+
+    let mut descriptors = descriptors();
+    descriptors.resolve_refs();
+
+    let files = descriptors.file_descriptor_protos();
+    let unittest = files
+        .iter()
+        .find(|f| f.get_name() == "google/protobuf/unittest.proto")
+        .unwrap();
+    assert_eq!("protobuf_unittest", unittest.get_package());
+    assert!(unittest
+        .get_dependency()
+        .iter()
+        .any(|d| d == "google/protobuf/unittest_import.proto"));
+    let all_types = unittest
+        .get_message_type()
+        .iter()
+        .find(|m| m.get_name() == "TestAllTypes")
+        .unwrap();
+    assert!(all_types
+        .get_nested_type()
+        .iter()
+        .any(|m| m.get_name() == "NestedMessage"));
+    assert!(all_types
+        .get_enum_type()
+        .iter()
+        .any(|e| e.get_name() == "NestedEnum"));
+
+    // The exported files describe the same types
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.set_file(files.into());
+    let mut exported = descriptor::Descriptors::from_proto(&set);
+    exported.resolve_refs();
+    assert!(exported.unresolved_refs().is_empty());
+    assert_eq!(descriptors.messages().len(), exported.messages().len());
+    assert_eq!(descriptors.enums().len(), exported.enums().len());
+    assert_eq!(descriptors.services().len(), exported.services().len());
+    assert!(descriptors.conflicts(&exported).is_empty());
+    assert!(descriptors.file_descriptor_proto("missing.proto").is_none());
+
+    // The syntax of proto3 files is kept, also through snapshots
+    let mut field = FieldDescriptorProto::new();
+    field.set_name("count".to_owned());
+    field.set_number(1);
+    field.set_label(Label::LABEL_OPTIONAL);
+    field.set_field_type(Type::TYPE_INT32);
+    let mut counter = DescriptorProto::new();
+    counter.set_name("Counter".to_owned());
+    counter.mut_field().push(field);
+    let mut file = FileDescriptorProto::new();
+    file.set_name("counter.proto".to_owned());
+    file.set_package("counter".to_owned());
+    file.set_syntax("proto3".to_owned());
+    file.set_message_type(vec![counter].into());
+    descriptors.add_file_proto(&file);
+    assert_eq!(Some("proto3"), descriptors.syntax_of_file("counter.proto"));
+    assert_eq!(
+        None,
+        descriptors.syntax_of_file("google/protobuf/unittest.proto")
+    );
+    let loaded =
+        descriptor::Descriptors::from_snapshot(&descriptors.to_snapshot().unwrap()).unwrap();
+    assert_eq!(
+        "proto3",
+        loaded
+            .file_descriptor_proto("counter.proto")
+            .unwrap()
+            .get_syntax()
+    );
+}
+
+#[cfg(feature = "reflection")]
+#[test]
+fn serve_reflection_requests() {
+    use serde_protobuf::reflection::ReflectionService;
+    use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+    use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+    use tonic_reflection::pb::v1::ServerReflectionRequest;
+
+    let descriptors = descriptors();
+    let service = ReflectionService::new(&descriptors).unwrap();
+
+    let respond = |request| {
+        let response = service.respond(&ServerReflectionRequest {
+            host: "localhost".to_owned(),
+            message_request: Some(request),
+        });
+        assert_eq!("localhost", response.valid_host);
+        response.message_response.unwrap()
+    };
+    let file_names = |response| match response {
+        MessageResponse::FileDescriptorResponse(files) => files
+            .file_descriptor_proto
+            .iter()
+            .map(|bytes| {
+                let file: protobuf::descriptor::FileDescriptorProto =
+                    protobuf::Message::parse_from_bytes(bytes).unwrap();
+                file.get_name().to_owned()
+            })
+            .collect::<Vec<_>>(),
+        other => panic!("unexpected response: {:?}", other),
+    };
+
+    match respond(MessageRequest::ListServices(String::new())) {
+        MessageResponse::ListServicesResponse(list) => assert!(list
+            .service
+            .iter()
+            .any(|s| s.name == "protobuf_unittest.TestService")),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // The file comes first, followed by its imports
+    let files = file_names(respond(MessageRequest::FileContainingSymbol(
+        "protobuf_unittest.TestService.Foo".to_owned(),
+    )));
+    assert_eq!("google/protobuf/unittest.proto", files[0]);
+    assert!(files.contains(&"google/protobuf/unittest_import_public.proto".to_owned()));
+    assert_eq!(
+        vec!["google/protobuf/unittest_import_public.proto"],
+        file_names(respond(MessageRequest::FileByFilename(
+            "google/protobuf/unittest_import_public.proto".to_owned()
+        )))
+    );
+
+    match respond(MessageRequest::FileContainingSymbol(
+        "protobuf_unittest.Missing".to_owned(),
+    )) {
+        MessageResponse::ErrorResponse(e) => assert_eq!(5, e.error_code),
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn reinterpret_unknown_fields_after_upgrade() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let registry = |upgraded: bool| {
+        let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+        let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+        outer.add_field(FieldDescriptor::new(
+            "inner",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::UnresolvedMessage(".test.Inner".to_owned()),
+            None,
+        ));
+        if upgraded {
+            inner.add_field(FieldDescriptor::new(
+                "name",
+                1,
+                FieldLabel::Optional,
+                InternalFieldType::String,
+                None,
+            ));
+            outer.add_field(FieldDescriptor::new(
+                "counts",
+                2,
+                FieldLabel::Repeated,
+                InternalFieldType::Int32,
+                None,
+            ));
+        }
+        let mut descriptors = descriptor::Descriptors::new();
+        descriptors.add_message(inner);
+        descriptors.add_message(outer);
+        descriptors.resolve_refs();
+        descriptors
+    };
+    let (old, new) = (registry(false), registry(true));
+
+    // inner { name = "x" }, counts = 5, counts = 6
+    let bytes = [0x0a, 0x03, 0x0a, 0x01, b'x', 0x10, 0x05, 0x10, 0x06];
+    let outer = old.message_by_name(".test.Outer").unwrap();
+    let mut message = Message::new(outer);
+    message
+        .merge_from(
+            &old,
+            outer,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    assert!(message.unknown.get(2).is_some());
+
+    let outer = new.message_by_name(".test.Outer").unwrap();
+    assert_eq!(2, message.reinterpret_unknown(&new, outer).unwrap());
+    assert!(message.unknown.get(2).is_none());
+    match message.fields[&2] {
+        Field::Repeated(ref values) => match values[..] {
+            [Value::I32(5), Value::I32(6)] => (),
+            ref other => panic!("unexpected values: {:?}", other),
+        },
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+    match message.fields[&1] {
+        Field::Singular(Some(Value::Message(ref inner))) => {
+            assert!(inner.unknown.get(1).is_none());
+            match inner.fields[&1] {
+                Field::Singular(Some(Value::String(ref name))) => assert_eq!("x", name),
+                ref other => panic!("unexpected field: {:?}", other),
+            }
+        }
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+    assert_eq!(bytes.to_vec(), message.write_to_bytes(&new, outer).unwrap());
+    // Nothing left to promote
+    assert_eq!(0, message.reinterpret_unknown(&new, outer).unwrap());
+}
+
+#[test]
+fn project_messages_onto_older_descriptors() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::value::{Message, RemovedFieldPolicy};
+
+    let registry = |upgraded: bool| {
+        let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+        let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+        outer.add_field(FieldDescriptor::new(
+            "inner",
+            1,
+            FieldLabel::Repeated,
+            InternalFieldType::UnresolvedMessage(".test.Inner".to_owned()),
+            None,
+        ));
+        inner.add_field(FieldDescriptor::new(
+            "id",
+            1,
+            FieldLabel::Optional,
+            InternalFieldType::Int32,
+            None,
+        ));
+        if upgraded {
+            inner.add_field(FieldDescriptor::new(
+                "name",
+                2,
+                FieldLabel::Optional,
+                InternalFieldType::String,
+                None,
+            ));
+            outer.add_field(FieldDescriptor::new(
+                "flag",
+                2,
+                FieldLabel::Optional,
+                InternalFieldType::Bool,
+                None,
+            ));
+        }
+        let mut descriptors = descriptor::Descriptors::new();
+        descriptors.add_message(inner);
+        descriptors.add_message(outer);
+        descriptors.resolve_refs();
+        descriptors
+    };
+    let (old, new) = (registry(false), registry(true));
+    let (old_outer, outer) = (
+        old.message_by_name(".test.Outer").unwrap(),
+        new.message_by_name(".test.Outer").unwrap(),
+    );
+
+    // inner { id = 1, name = "x" }, inner { id = 2 }, flag = true
+    let bytes = [
+        0x0a, 0x05, 0x08, 0x01, 0x12, 0x01, b'x', 0x0a, 0x02, 0x08, 0x02, 0x10, 0x01,
+    ];
+    let decode = || {
+        let mut message = Message::new(outer);
+        message
+            .merge_from(
+                &new,
+                outer,
+                &mut protobuf::CodedInputStream::from_bytes(&bytes),
+            )
+            .unwrap();
+        message
+    };
+
+    let mut dropped = decode();
+    let removed = dropped
+        .project_onto(&new, outer, &old, old_outer, RemovedFieldPolicy::Drop)
+        .unwrap();
+    assert_eq!(vec!["inner.name", "flag"], removed);
+    assert!(dropped.unknown.get(2).is_none());
+    // inner { id = 1 }, inner { id = 2 }
+    assert_eq!(
+        vec![0x0a, 0x02, 0x08, 0x01, 0x0a, 0x02, 0x08, 0x02],
+        dropped.write_to_bytes(&old, old_outer).unwrap()
+    );
+    // Repeated message fields are reported as a whole
+    assert_eq!(
+        vec!["inner", "flag"],
+        dropped.changed_paths(&new, outer).paths
+    );
+
+    let mut kept = decode();
+    kept.project_onto(&new, outer, &old, old_outer, RemovedFieldPolicy::Unknown)
+        .unwrap();
+    assert!(kept.unknown.get(2).is_some());
+    assert_eq!(
+        bytes.to_vec(),
+        kept.write_to_bytes(&old, old_outer).unwrap()
+    );
+}
+
+#[test]
+fn remap_messages_between_sibling_types() {
+    use serde_protobuf::cursor::{Cursor, CursorMut};
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::remap::FieldMapping;
+    use serde_protobuf::value::{Message, Value};
+
+    let field = |name: &str, number, label, field_type| {
+        FieldDescriptor::new(name, number, label, field_type, None)
+    };
+    let message = |name: &str| InternalFieldType::UnresolvedMessage(name.to_owned());
+    let (optional, repeated) = (FieldLabel::Optional, FieldLabel::Repeated);
+
+    let mut source = descriptor::Descriptors::new();
+    let mut customer = descriptor::MessageDescriptor::new(".a.Customer");
+    customer.add_field(field("id", 1, optional, InternalFieldType::Int64));
+    customer.add_field(field("name", 2, optional, InternalFieldType::String));
+    let mut status = descriptor::EnumDescriptor::new(".a.Status");
+    status.add_value(descriptor::EnumValueDescriptor::new("NEW", 0));
+    status.add_value(descriptor::EnumValueDescriptor::new("SHIPPED", 1));
+    let mut order = descriptor::MessageDescriptor::new(".a.Order");
+    order.add_field(field("customer", 1, optional, message(".a.Customer")));
+    order.add_field(field(
+        "status",
+        2,
+        optional,
+        InternalFieldType::UnresolvedEnum(".a.Status".to_owned()),
+    ));
+    order.add_field(field("total_cents", 3, optional, InternalFieldType::UInt32));
+    order.add_field(field("tags", 4, repeated, InternalFieldType::String));
+    source.add_message(customer);
+    source.add_enum(status);
+    source.add_message(order);
+    source.resolve_refs();
+
+    let mut target = descriptor::Descriptors::new();
+    let mut customer = descriptor::MessageDescriptor::new(".b.Customer");
+    customer.add_field(field("name", 1, optional, InternalFieldType::Bytes));
+    let mut order = descriptor::MessageDescriptor::new(".b.Order");
+    order.add_field(field("client_id", 7, optional, InternalFieldType::String));
+    order.add_field(field("customer", 1, optional, message(".b.Customer")));
+    order.add_field(field("status", 2, optional, InternalFieldType::String));
+    order.add_field(field("total_cents", 3, optional, InternalFieldType::Int64));
+    order.add_field(field("tags", 4, repeated, InternalFieldType::String));
+    target.add_message(customer);
+    target.add_message(order);
+    target.resolve_refs();
+
+    let (source_order, target_order) = (
+        source.message_by_name(".a.Order").unwrap(),
+        target.message_by_name(".b.Order").unwrap(),
+    );
+    let mut message = Message::new(source_order);
+    {
+        let mut set = |path: &[&str], value| {
+            let mut cursor = CursorMut::new(&source, source_order, &mut message);
+            for name in path {
+                cursor = cursor.field(name).unwrap();
+            }
+            cursor.set(value).unwrap();
+        };
+        set(&["customer", "id"], Value::I64(42));
+        set(&["customer", "name"], Value::String("Ada".to_owned()));
+        set(&["status"], Value::Enum(1));
+        set(&["total_cents"], Value::U32(1999));
+    }
+    CursorMut::new(&source, source_order, &mut message)
+        .field("tags")
+        .unwrap()
+        .push(Value::String("gift".to_owned()))
+        .unwrap();
+
+    let converted = FieldMapping::new()
+        .field("customer.id", "client_id")
+        .apply(&source, source_order, &message, &target, target_order)
+        .unwrap();
+    let cursor = Cursor::new(&target, target_order, &converted);
+    assert_eq!(Some("42"), cursor.field("client_id").unwrap().as_str());
+    assert_eq!(
+        Some(&b"Ada"[..]),
+        cursor
+            .field("customer")
+            .unwrap()
+            .field("name")
+            .unwrap()
+            .as_bytes()
+    );
+    assert_eq!(Some("SHIPPED"), cursor.field("status").unwrap().as_str());
+    assert_eq!(Some(1999), cursor.field("total_cents").unwrap().as_i64());
+    assert_eq!(
+        Some("gift"),
+        cursor.field("tags").unwrap().index(0).unwrap().as_str()
+    );
+
+    // A number can't become a message, and paths must exist
+    match FieldMapping::new().field("total_cents", "customer").apply(
+        &source,
+        source_order,
+        &message,
+        &target,
+        target_order,
+    ) {
+        Err(serde_protobuf::Error::FieldConversion { from, to }) => {
+            assert_eq!(("total_cents", "customer"), (from.as_str(), to.as_str()))
+        }
+        other => panic!("expected a conversion error, got {:?}", other),
+    }
+    match FieldMapping::new()
+        .field("customer.email", "client_id")
+        .apply(&source, source_order, &message, &target, target_order)
+    {
+        Err(serde_protobuf::Error::UnknownField { name }) => assert_eq!("customer.email", name),
+        other => panic!("expected an unknown field error, got {:?}", other),
+    }
+}
+
+#[test]
+fn decode_masked_fields() {
+    use protobuf::Message;
+    use serde_protobuf::field_mask::{FieldMask, FieldMaskTree};
+    use serde_protobuf::value::{Field, Value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_int32(1);
+    v.set_optional_int64(2);
+    v.mut_optional_nested_message().set_bb(3);
+    v.mut_optional_foreign_message().set_c(4);
+    v.mut_repeated_int32().push(5);
+    let mut bytes = v.write_to_bytes().unwrap();
+    // An unknown field 999 with the varint 1
+    bytes.extend_from_slice(&[0xb8, 0x3e, 0x01]);
+
+    let mask = FieldMask::new(vec![
+        "optional_int32",
+        "optional_nested_message.bb",
+        "optional_foreign_message",
+        "optional_foreign_message.c",
+    ]);
+    let tree = FieldMaskTree::compile(&descriptors, message, &mask).unwrap();
+    assert!(tree.field(19).unwrap().is_empty());
+    assert!(FieldMask::new(vec!["optional_int32.bb"])
+        .compile(&descriptors, message)
+        .is_err());
+
+    // The tree is reused for several messages
+    for _ in 0..2 {
+        let mut m = serde_protobuf::value::Message::new(message);
+        m.merge_from_masked(
+            &descriptors,
+            message,
+            &tree,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+
+        match (&m.fields[&1], &m.fields[&2], &m.fields[&31]) {
+            (Field::Singular(Some(Value::I32(1))), Field::Singular(None), Field::Repeated(r))
+                if r.is_empty() => {}
+            other => panic!("unexpected fields {:?}", other),
+        }
+        for &(number, expected) in &[(18, 3), (19, 4)] {
+            match m.fields[&number] {
+                Field::Singular(Some(Value::Message(ref nested))) => match nested.fields[&1] {
+                    Field::Singular(Some(Value::I32(v))) => assert_eq!(expected, v),
+                    ref f => panic!("unexpected field {:?}", f),
+                },
+                ref f => panic!("unexpected field {:?}", f),
+            }
+        }
+        assert!(m.unknown.get(999).is_none());
+    }
+}
+
+#[test]
+fn deserialize_masked_fields() {
+    use protobuf::Message;
+    use serde::Deserialize;
+    use serde_protobuf::field_mask::FieldMask;
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_int32(1);
+    v.set_optional_int64(2);
+    v.mut_optional_nested_message().set_bb(3);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mask = FieldMask::new(vec!["optional_int32"])
+        .compile(&descriptors, message)
+        .unwrap();
+    let input = protobuf::CodedInputStream::from_bytes(&bytes);
+    let mut deserializer =
+        de::Deserializer::new(&descriptors, message, input).with_field_mask(&mask);
+    let fields = match serde_value::Value::deserialize(&mut deserializer).unwrap() {
+        serde_value::Value::Map(fields) => fields,
+        other => panic!("expected a map, got {:?}", other),
+    };
+    let field = |name: &str| &fields[&serde_value::Value::String(name.to_owned())];
+
+    assert_eq!(value!(some i32: 1), *field("optional_int32"));
+    assert_eq!(value!(none), *field("optional_int64"));
+    assert_eq!(value!(none), *field("optional_nested_message"));
+}
+
+#[test]
+fn mask_repeated_elements_and_map_entries() {
+    use serde_protobuf::descriptor::{FieldDescriptor, FieldLabel, InternalFieldType};
+    use serde_protobuf::field_mask::FieldMask;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let mut descriptors = descriptor::Descriptors::new();
+    let mut item = descriptor::MessageDescriptor::new(".test.Item");
+    item.add_field(FieldDescriptor::new(
+        "price",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::Int32,
+        None,
+    ));
+    item.add_field(FieldDescriptor::new(
+        "name",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    let mut options = protobuf::descriptor::MessageOptions::new();
+    options.set_map_entry(true);
+    let mut entry =
+        descriptor::MessageDescriptor::new(".test.Holder.LabelsEntry").with_options(options);
+    entry.add_field(FieldDescriptor::new(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+        None,
+    ));
+    entry.add_field(FieldDescriptor::new(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::UnresolvedMessage(".test.Item".to_owned()),
+        None,
+    ));
+    let mut holder = descriptor::MessageDescriptor::new(".test.Holder");
+    holder.add_field(FieldDescriptor::new(
+        "items",
+        1,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Item".to_owned()),
+        None,
+    ));
+    holder.add_field(FieldDescriptor::new(
+        "labels",
+        2,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".test.Holder.LabelsEntry".to_owned()),
+        None,
+    ));
+    descriptors.add_message(item);
+    descriptors.add_message(entry);
+    descriptors.add_message(holder);
+    descriptors.resolve_refs();
+    let item = descriptors.message_by_name(".test.Item").unwrap();
+    let entry = descriptors
+        .message_by_name(".test.Holder.LabelsEntry")
+        .unwrap();
+    let holder = descriptors.message_by_name(".test.Holder").unwrap();
+
+    let new_item = |price, name: &str| {
+        let mut m = Message::new(item);
+        m.fields.insert(1, Field::Singular(Some(Value::I32(price))));
+        m.fields
+            .insert(2, Field::Singular(Some(Value::String(name.to_owned()))));
+        Value::Message(Arc::new(m))
+    };
+    let mut message = Message::new(holder);
+    for (price, name) in &[(1, "a"), (2, "b"), (3, "c")] {
+        let value = new_item(*price, name);
+        message
+            .fields
+            .get_mut(&1)
+            .unwrap()
+            .push(&descriptors, holder.field_by_number(1).unwrap(), value)
+            .unwrap();
+    }
+    for (key, price) in &[("env", 4), ("team", 5)] {
+        let mut m = Message::new(entry);
+        m.fields
+            .insert(1, Field::Singular(Some(Value::String((*key).to_owned()))));
+        m.fields
+            .insert(2, Field::Singular(Some(new_item(*price, key))));
+        message
+            .fields
+            .get_mut(&2)
+            .unwrap()
+            .push(
+                &descriptors,
+                holder.field_by_number(2).unwrap(),
+                Value::Message(Arc::new(m)),
+            )
+            .unwrap();
+    }
+    let bytes = message.write_to_bytes(&descriptors, holder).unwrap();
+
+    assert!(FieldMask::new(vec!["items.x"])
+        .validate(&descriptors, holder)
+        .is_err());
+    assert!(FieldMask::new(vec!["items.price"])
+        .validate(&descriptors, holder)
+        .is_err());
+    assert!(FieldMask::new(vec!["items.0", "labels.env.name"])
+        .validate(&descriptors, holder)
+        .is_ok());
+    let mask = FieldMask::new(vec!["items.1.price", "labels.env"])
+        .compile(&descriptors, holder)
+        .unwrap();
+
+    let mut decoded = Message::new(holder);
+    decoded
+        .merge_from_masked(
+            &descriptors,
+            holder,
+            &mask,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    let mut retained = message.clone();
+    retained.retain_masked(&descriptors, holder, &mask);
+
+    for m in &[decoded, retained] {
+        match m.fields[&1] {
+            Field::Repeated(ref items) => match items[..] {
+                [Value::Message(ref item)] => match (&item.fields[&1], &item.fields[&2]) {
+                    (Field::Singular(Some(Value::I32(2))), Field::Singular(None)) => {}
+                    ref other => panic!("unexpected item {:?}", other),
+                },
+                ref other => panic!("unexpected items {:?}", other),
+            },
+            ref other => panic!("unexpected field {:?}", other),
+        }
+        match m.fields[&2] {
+            Field::Repeated(ref labels) => assert_eq!(1, labels.len()),
+            ref other => panic!("unexpected field {:?}", other),
+        }
+    }
+
+    // Element paths also work for clearing by priority
+    let size = message.compute_size(&descriptors, holder).unwrap();
+    let report = message
+        .truncate_to(
+            &descriptors,
+            holder,
+            size - 1,
+            &serde_protobuf::value::TruncationStrategy::Priority(FieldMask::new(vec![
+                "labels.team",
+            ])),
+        )
+        .unwrap();
+    assert_eq!(vec!["labels.team"], report.cleared);
+    match message.fields[&2] {
+        Field::Repeated(ref labels) => assert_eq!(1, labels.len()),
+        ref other => panic!("unexpected field {:?}", other),
+    }
+}
+
+#[test]
+fn decode_typed_repeated_scalars() {
+    use protobuf::Message;
+    use serde_protobuf::value::{self, DecodeOptions, Field, TypedValues, Value};
+
+    let mut descriptors = descriptors();
+    descriptors.resolve_refs();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.mut_repeated_int32().extend_from_slice(&[1, -2, 3]);
+    v.mut_repeated_double().push(0.5);
+    v.mut_repeated_string().push("a".to_owned());
+    v.mut_repeated_nested_enum()
+        .push(protobuf_unittest::unittest::TestAllTypes_NestedEnum::BAR);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let mut m = value::Message::new(message);
+    m.merge_from_with_options(
+        &descriptors,
+        message,
+        &DecodeOptions::new().typed_repeated_scalars(true),
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+
+    let field = |name: &str| &m.fields[&message.field_by_name(name).unwrap().number()];
+    match *field("repeated_int32") {
+        Field::RepeatedTyped(TypedValues::I32(ref vs)) => assert_eq!(&[1, -2, 3], &vs[..]),
+        ref other => panic!("unexpected field {:?}", other),
+    }
+    match *field("repeated_double") {
+        Field::RepeatedTyped(TypedValues::F64(ref vs)) => assert_eq!(&[0.5], &vs[..]),
+        ref other => panic!("unexpected field {:?}", other),
+    }
+    // Strings and enums keep their values
+    assert!(matches!(*field("repeated_string"), Field::Repeated(_)));
+    assert!(matches!(*field("repeated_nested_enum"), Field::Repeated(_)));
+
+    match *field("repeated_int32").values() {
+        [Value::I32(1), Value::I32(-2), Value::I32(3)] => (),
+        ref other => panic!("unexpected values {:?}", other),
+    }
+    let mut untyped = value::Message::new(message);
+    untyped
+        .merge_from(
+            &descriptors,
+            message,
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    assert_eq!(
+        untyped.write_to_bytes(&descriptors, message).unwrap(),
+        m.write_to_bytes(&descriptors, message).unwrap()
+    );
+
+    let mut f = field("repeated_int32").clone();
+    f.make_untyped();
+    assert!(matches!(f, Field::Repeated(ref vs) if vs.len() == 3));
+    f.make_typed(
+        &descriptors,
+        message.field_by_name("repeated_int32").unwrap(),
+    );
+    assert!(matches!(f, Field::RepeatedTyped(TypedValues::I32(ref vs)) if vs == &[1, -2, 3]));
+
+    // Packed runs are read into typed storage as well
+    let packed = descriptors
+        .message_by_name(".protobuf_unittest.TestPackedTypes")
+        .unwrap();
+    let mut v = protobuf_unittest::unittest::TestPackedTypes::new();
+    v.mut_packed_sint64().extend_from_slice(&[-1, 1 << 40]);
+    v.mut_packed_fixed32().extend_from_slice(&[7, 8]);
+    let bytes = v.write_to_bytes().unwrap();
+    let mut m = value::Message::new(packed);
+    m.merge_from_with_options(
+        &descriptors,
+        packed,
+        &DecodeOptions::new().typed_repeated_scalars(true),
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+    match m.fields[&packed.field_by_name("packed_sint64").unwrap().number()] {
+        Field::RepeatedTyped(TypedValues::I64(ref vs)) => assert_eq!(&[-1, 1 << 40], &vs[..]),
+        ref other => panic!("unexpected field {:?}", other),
+    }
+    match m.fields[&packed.field_by_name("packed_fixed32").unwrap().number()] {
+        Field::RepeatedTyped(TypedValues::U32(ref vs)) => assert_eq!(&[7, 8], &vs[..]),
+        ref other => panic!("unexpected field {:?}", other),
+    }
+}
+
+#[test]
+fn share_messages_copy_on_write() {
+    use serde_protobuf::value::{self, Field, Value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_type = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+    let nested_number = message
+        .field_by_name("optional_nested_message")
+        .unwrap()
+        .number();
+    let bb = nested_type.field_by_name("bb").unwrap().number();
+
+    let mut nested = value::Message::new(nested_type);
+    nested
+        .fields
+        .insert(bb, Field::Singular(Some(Value::I32(1))));
+    let mut original = value::Message::new(message);
+    original.fields.insert(
+        nested_number,
+        Field::Singular(Some(Value::Message(Arc::new(nested)))),
+    );
+
+    fn nested_of(m: &value::Message, number: i32) -> &Arc<value::Message> {
+        match m.fields[&number] {
+            Field::Singular(Some(Value::Message(ref n))) => n,
+            ref other => panic!("unexpected field {:?}", other),
+        }
+    }
+
+    // Cloning shares the nested message
+    let mut copy = original.clone();
+    assert!(Arc::ptr_eq(
+        nested_of(&original, nested_number),
+        nested_of(&copy, nested_number)
+    ));
+
+    // Modifying it in the clone copies it and leaves the original alone
+    match copy.fields.get_mut(&nested_number) {
+        Some(Field::Singular(Some(Value::Message(n)))) => {
+            Arc::make_mut(n)
+                .fields
+                .insert(bb, Field::Singular(Some(Value::I32(2))));
+        }
+        other => panic!("unexpected field {:?}", other),
+    }
+    assert!(!Arc::ptr_eq(
+        nested_of(&original, nested_number),
+        nested_of(&copy, nested_number)
+    ));
+    match (
+        &nested_of(&original, nested_number).fields[&bb],
+        &nested_of(&copy, nested_number).fields[&bb],
+    ) {
+        (Field::Singular(Some(Value::I32(1))), Field::Singular(Some(Value::I32(2)))) => (),
+        other => panic!("unexpected fields {:?}", other),
+    }
+}
+
+#[test]
+fn encode_clones_sharing_messages_with_different_options() {
+    use serde_protobuf::value::{self, EncodeOptions, Field, Value};
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_type = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+
+    let mut nested = value::Message::new(nested_type);
+    nested
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(0))));
+    let mut a = value::Message::default();
+    a.fields
+        .insert(18, Field::Singular(Some(Value::Message(Arc::new(nested)))));
+    let b = a.clone();
+
+    // The sizes computed for one clone aren't affected by sizing the other one
+    let skip = EncodeOptions::new().skip_defaults(true);
+    assert_eq!(
+        3,
+        a.compute_size_with_options(&descriptors, message, &skip)
+            .unwrap()
+    );
+    assert_eq!(5, b.compute_size(&descriptors, message).unwrap());
+    let mut bytes = Vec::new();
+    {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        a.write_to_with_cached_sizes(&descriptors, message, &skip, &mut output)
+            .unwrap();
+        output.flush().unwrap();
+    }
+    assert_eq!(vec![146, 1, 0], bytes);
+    assert_eq!(
+        vec![146, 1, 2, 8, 0],
+        b.write_to_bytes(&descriptors, message).unwrap()
+    );
+}
+
+#[test]
+fn share_messages_between_threads() {
+    use std::thread;
+
+    use protobuf::Message;
+    use serde_protobuf::value;
+
+    let mut descriptors = descriptors();
+    descriptors.resolve_refs();
+    let descriptors = Arc::new(descriptors);
+
+    let mut v = protobuf_unittest::unittest::TestAllTypes::new();
+    v.set_optional_string("shared".to_owned());
+    v.mut_repeated_nested_message().push_default().set_bb(7);
+    let bytes = v.write_to_bytes().unwrap();
+
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut m = value::Message::new(message);
+    m.merge_from(
+        &descriptors,
+        message,
+        &mut protobuf::CodedInputStream::from_bytes(&bytes),
+    )
+    .unwrap();
+    let expected = m.write_to_bytes(&descriptors, message).unwrap();
+    let m = Arc::new(m);
+
+    let workers = (0..4)
+        .map(|_| {
+            let descriptors = descriptors.clone();
+            let m = m.clone();
+            thread::spawn(move || {
+                let message = descriptors
+                    .message_by_name(".protobuf_unittest.TestAllTypes")
+                    .unwrap();
+                m.write_to_bytes(&descriptors, message).unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        assert_eq!(expected, worker.join().unwrap());
+    }
+}
+
+#[test]
+fn structured_errors() {
+    use serde_protobuf::error;
+    use serde_protobuf::value::{self, Field, Value};
+
+    let mut descriptors = descriptors();
+    descriptors.resolve_refs();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestRequiredForeign")
+        .unwrap();
+    let required = descriptors
+        .message_by_name(".protobuf_unittest.TestRequired")
+        .unwrap();
+
+    let mut r = value::Message::new(required);
+    for name in &["a", "b", "c"] {
+        let number = required.field_by_name(name).unwrap().number();
+        r.fields
+            .insert(number, Field::Singular(Some(Value::I32(1))));
+    }
+    let mut m = value::Message::new(message);
+    m.fields.insert(
+        message.field_by_name("repeated_message").unwrap().number(),
+        Field::Repeated(
+            vec![Value::Message(Arc::new(r.clone()))]
+                .into_iter()
+                .collect(),
+        ),
+    );
+    m.check_required_fields(&descriptors, message).unwrap();
+
+    r.fields
+        .remove(&required.field_by_name("b").unwrap().number());
+    m.fields.insert(
+        message.field_by_name("optional_message").unwrap().number(),
+        Field::Singular(Some(Value::Message(Arc::new(r)))),
+    );
+    let e = m.check_required_fields(&descriptors, message).unwrap_err();
+    assert_eq!("missing_field", e.code());
+    match e {
+        error::Error::MissingField { ref path } => assert_eq!("optional_message.b", path),
+        other => panic!("unexpected error: {:?}", other),
+    }
+
+    assert_eq!("end_of_stream", error::Error::EndOfStream.code());
+}
+
+#[cfg(feature = "miette")]
+#[test]
+fn diagnostics() {
+    use miette::Diagnostic as _;
+    use serde_protobuf::diagnostic::Diagnostic;
+    use serde_protobuf::error;
+
+    let descriptors = descriptors();
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let diagnostic = Diagnostic::new(error::Error::UnknownField {
+        name: "optional_int3".to_owned(),
+    })
+    .with_message(descriptor);
+    assert_eq!("unknown_field", diagnostic.code().unwrap().to_string());
+    assert_eq!(
+        "did you mean field `optional_int32`?",
+        diagnostic.help().unwrap().to_string()
+    );
+    let diagnostic = Diagnostic::new(error::Error::UnknownField {
+        name: "memo".to_owned(),
+    })
+    .with_message(descriptor);
+    assert!(diagnostic.help().is_none());
+
+    // The padded varint at byte 35 is labeled in the hex dump
+    let mut input = vec![0u8; 40];
+    input[35] = 0x85;
+    let diagnostic =
+        Diagnostic::new(error::Error::NonMinimalVarint { position: 35 }).with_input(&input);
+    let label = diagnostic.labels().unwrap().next().unwrap();
+    let span = diagnostic
+        .source_code()
+        .unwrap()
+        .read_span(label.inner(), 0, 0)
+        .unwrap();
+    assert_eq!(b"85", span.data());
+    assert_eq!(2, span.line());
+
+    // Common decoding errors are labeled at their position
+    let label = |input: &[u8]| {
+        let mut message = serde_protobuf::value::Message::new(descriptor);
+        let error = message
+            .merge_from(
+                &descriptors,
+                descriptor,
+                &mut protobuf::CodedInputStream::from_bytes(input),
+            )
+            .unwrap_err();
+        let diagnostic = Diagnostic::new(error).with_input(input);
+        let label = diagnostic.labels().unwrap().next().unwrap();
+        let span = diagnostic
+            .source_code()
+            .unwrap()
+            .read_span(label.inner(), 0, 0)
+            .unwrap();
+        (
+            label.label().unwrap().to_owned(),
+            String::from_utf8(span.data().to_vec()).unwrap(),
+        )
+    };
+    // optional_int32 = 5, then optional_string with the wrong wire type
+    assert_eq!(
+        (
+            "this value has the wrong wire type".to_owned(),
+            "01".to_owned()
+        ),
+        label(&[0x08, 5, 0x70, 1])
+    );
+    // optional_int32 = 5, then optional_int64 without a value
+    assert_eq!(
+        ("this field is cut off".to_owned(), "10".to_owned()),
+        label(&[0x08, 5, 0x10])
+    );
+    assert_eq!(
+        (
+            "this tag has an invalid field number".to_owned(),
+            "00".to_owned()
+        ),
+        label(&[0x08, 5, 0x00, 1])
+    );
+
+    #[cfg(feature = "anyhow")]
+    {
+        let error = Diagnostic::new(error::Error::UnexpectedEof { position: 2 })
+            .with_input(&[0x08, 5, 0x10])
+            .into_anyhow();
+        let printed = format!("{:?}", error);
+        assert!(printed.contains("this field is cut off"), "{}", printed);
+        assert!(printed.contains("unexpected end of input in the field at byte 2"));
+        assert!(error.downcast_ref::<Diagnostic>().is_some());
+    }
+}
+
+#[test]
+fn decoding_arbitrary_input_never_panics() {
+    use serde::Deserialize;
+    use serde_protobuf::value::{DecodeOptions, Message};
+
+    let mut descriptors = descriptors();
+    descriptors.resolve_refs();
+
+    // Valid messages to mutate, with scalars, strings, nested messages and packed runs
+    let mut all_types = protobuf_unittest::unittest::TestAllTypes::new();
+    all_types.set_optional_int32(-5);
+    all_types.set_optional_string("hello".to_owned());
+    all_types.mut_optional_nested_message().set_bb(7);
+    all_types
+        .mut_repeated_int64()
+        .extend_from_slice(&[1, -1, 1 << 40]);
+    let mut packed = protobuf_unittest::unittest::TestPackedTypes::new();
+    packed.mut_packed_int32().extend_from_slice(&[1, -1, 300]);
+    packed.mut_packed_sint64().extend_from_slice(&[-2, 1 << 50]);
+    packed.mut_packed_double().extend_from_slice(&[1.5, -0.0]);
+    packed.mut_packed_bool().push(true);
+    let samples = [
+        (
+            ".protobuf_unittest.TestAllTypes",
+            protobuf::Message::write_to_bytes(&all_types).unwrap(),
+        ),
+        (
+            ".protobuf_unittest.TestPackedTypes",
+            protobuf::Message::write_to_bytes(&packed).unwrap(),
+        ),
+    ];
+
+    // xorshift64*, so that failures are reproducible; set FUZZ_ITERATIONS for longer runs
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    };
+    let iterations = std::env::var("FUZZ_ITERATIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+
+    let messages = descriptors.messages();
+    for i in 0..iterations {
+        // Alternately random bytes for a random message type, and a mutated sample
+        let (descriptor, input) = if i % 2 == 0 {
+            let len = next() % 64;
+            let input = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+            (&messages[next() as usize % messages.len()], input)
+        } else {
+            let (name, ref sample) = samples[next() as usize % samples.len()];
+            let mut input = sample.clone();
+            for _ in 0..1 + next() % 4 {
+                let at = next() as usize % input.len();
+                match next() % 3 {
+                    0 => input[at] = next() as u8,
+                    1 => input.insert(at, next() as u8),
+                    _ => input.truncate(at.max(1)),
+                }
+            }
+            (descriptors.message_by_name(name).unwrap(), input)
+        };
+
+        for options in &[
+            DecodeOptions::new(),
+            DecodeOptions::new()
+                .strict(true)
+                .typed_repeated_scalars(true),
+        ] {
+            let mut message = Message::new(descriptor);
+            let mut stream = protobuf::CodedInputStream::from_bytes(&input);
+            let _ = message.merge_from_with_options(&descriptors, descriptor, options, &mut stream);
+        }
+        let stream = protobuf::CodedInputStream::from_bytes(&input);
+        let mut deserializer = de::Deserializer::new(&descriptors, descriptor, stream);
+        let _ = serde_value::Value::deserialize(&mut deserializer);
+        #[cfg(feature = "arena")]
+        {
+            let bump = bumpalo::Bump::new();
+            let mut message = serde_protobuf::arena::Message::new_in(descriptor, &bump);
+            let _ = message.merge_from_slice(&descriptors, descriptor, &input);
+            let mut message = serde_protobuf::arena::Message::new_in(descriptor, &bump);
+            let _ = message.merge_from(
+                &descriptors,
+                descriptor,
+                &mut protobuf::CodedInputStream::new(&mut &input[..]),
+            );
+        }
+    }
+
+    // Deeply nested messages, built inside out with every byte reversed, fail instead of
+    // overflowing the stack
+    let nested = |depth: usize| {
+        let mut reversed = Vec::new();
+        for _ in 1..depth {
+            let mut len = reversed.len() as u64;
+            let mut varint = Vec::new();
+            loop {
+                varint.push(len as u8 & 0x7f | if len >= 0x80 { 0x80 } else { 0 });
+                len >>= 7;
+                if len == 0 {
+                    break;
+                }
+            }
+            reversed.extend(varint.into_iter().rev());
+            reversed.push(0x0a);
+        }
+        reversed.reverse();
+        reversed
+    };
+    let descriptor = descriptors
+        .message_by_name(".protobuf_unittest.TestRecursiveMessage")
+        .unwrap();
+    let decode = |input: &[u8], options: &DecodeOptions| {
+        let mut message = Message::new(descriptor);
+        let mut stream = protobuf::CodedInputStream::from_bytes(input);
+        message.merge_from_with_options(&descriptors, descriptor, options, &mut stream)
+    };
+    let limit = serde_protobuf::value::DEFAULT_RECURSION_LIMIT as usize;
+    let shallow = nested(limit);
+    decode(&shallow, &DecodeOptions::new()).unwrap();
+    match decode(&nested(limit + 1), &DecodeOptions::new()) {
+        Err(serde_protobuf::error::Error::RecursionLimitExceeded { limit: 100, .. }) => (),
+        r => panic!("unexpected result {:?}", r),
+    }
+    decode(
+        &nested(limit + 1),
+        &DecodeOptions::new().recursion_limit(200),
+    )
+    .unwrap();
+    let input = nested(100_000);
+    assert!(decode(&input, &DecodeOptions::new()).is_err());
+    let stream = protobuf::CodedInputStream::from_bytes(&input);
+    let mut deserializer = de::Deserializer::new(&descriptors, descriptor, stream);
+    assert!(serde_value::Value::deserialize(&mut deserializer).is_err());
+    #[cfg(feature = "arena")]
+    {
+        let bump = bumpalo::Bump::new();
+        let mut message = serde_protobuf::arena::Message::new_in(descriptor, &bump);
+        message
+            .merge_from_slice(&descriptors, descriptor, &shallow)
+            .unwrap();
+        let mut message = serde_protobuf::arena::Message::new_in(descriptor, &bump);
+        assert!(message
+            .merge_from_slice(&descriptors, descriptor, &input)
+            .is_err());
+    }
+}
+
+#[test]
+fn group_fields_are_skipped() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use serde_protobuf::error;
+    use serde_protobuf::value::{DecodeOptions, Field, Message, UnknownFieldPolicy, Value};
+
+    let field = |name: &str, number: i32, field_type: Type| {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(number);
+        field.set_label(Label::LABEL_OPTIONAL);
+        field.set_field_type(field_type);
+        field
+    };
+    let mut message = DescriptorProto::new();
+    message.set_name("Legacy".to_owned());
+    message.set_field(
+        vec![
+            field("data", 1, Type::TYPE_GROUP),
+            field("n", 3, Type::TYPE_INT32),
+        ]
+        .into(),
+    );
+    let mut file = FileDescriptorProto::new();
+    file.set_name("legacy.proto".to_owned());
+    file.set_package("app".to_owned());
+    file.set_message_type(vec![message].into());
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.set_file(vec![file].into());
+    let descriptors = descriptor::Descriptors::from_proto(&set);
+    let legacy = descriptors.message_by_name(".app.Legacy").unwrap();
+
+    // data { 2: 5 }, n: 7
+    let bytes = [0x0b, 0x10, 5, 0x0c, 0x18, 7];
+    let decode = |options: &DecodeOptions| {
+        let mut message = Message::new(legacy);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        message
+            .merge_from_with_options(&descriptors, legacy, options, &mut input)
+            .map(|()| message)
+    };
+    let message = decode(&DecodeOptions::new()).unwrap();
+    assert!(message.fields[&1].is_empty());
+    match message.fields.get(&3) {
+        Some(Field::Singular(Some(Value::I32(7)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match decode(&DecodeOptions::new().unknown_fields(UnknownFieldPolicy::Error)) {
+        Err(error::Error::UnknownFieldNumber { number: 1 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // Group values can't be encoded
+    let mut message = message;
+    message
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    match message.write_to_bytes(&descriptors, legacy) {
+        Err(error::Error::BadFieldValue { ref field }) => assert_eq!("data", field),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn extreme_field_numbers() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let decode = |tag: u64| {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            output.write_raw_varint64(tag).unwrap();
+            output.write_raw_varint64(1).unwrap();
+            output.flush().unwrap();
+        }
+        let mut message = Message::new(all_types);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        message
+            .merge_from(&descriptors, all_types, &mut input)
+            .map(|()| message)
+    };
+
+    // The largest field number is kept as an unknown field, and larger ones are rejected
+    let max = (1 << 29) - 1;
+    let message = decode(max << 3).unwrap();
+    assert!(message.unknown.get(max as u32).is_some());
+    for &number in &[max + 1, u64::from(u32::MAX), u64::MAX >> 3] {
+        match decode(number << 3) {
+            Err(error::Error::BadFieldNumber {
+                number: n,
+                position: Some(0),
+            }) => assert_eq!(number, n),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Fields of descriptors with invalid numbers aren't encoded
+    let mut message = DescriptorProto::new();
+    message.set_name("Invalid".to_owned());
+    for (name, number) in &[("zero", 0), ("negative", -1), ("too_large", 1 << 29)] {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name((*name).to_owned());
+        field.set_number(*number);
+        field.set_label(Label::LABEL_OPTIONAL);
+        field.set_field_type(Type::TYPE_INT32);
+        message.mut_field().push(field);
+    }
+    let mut file = FileDescriptorProto::new();
+    file.set_name("invalid.proto".to_owned());
+    file.set_message_type(vec![message].into());
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.set_file(vec![file].into());
+    let descriptors = descriptor::Descriptors::from_proto(&set);
+    let invalid = descriptors.message_by_name(".Invalid").unwrap();
+    for &(number, reported) in &[(0, 0), (-1, u64::from(u32::MAX)), (1 << 29, 1 << 29)] {
+        let mut message = Message::new(invalid);
+        message
+            .fields
+            .insert(number, Field::Singular(Some(Value::I32(1))));
+        match message.write_to_bytes(&descriptors, invalid) {
+            Err(error::Error::BadFieldNumber {
+                number,
+                position: None,
+            }) => assert_eq!(reported, number),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Neither are unknown fields with invalid numbers
+    let mut message = Message::new(invalid);
+    message.unknown.add_varint(0, 1);
+    match message.write_to_bytes(&descriptors, invalid) {
+        Err(error::Error::BadFieldNumber {
+            number: 0,
+            position: None,
+        }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_envelopes() {
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label as Label,
+        FieldDescriptorProto_Type as Type, FileDescriptorProto,
+    };
+    use serde_protobuf::envelope::Envelope;
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Field, Value};
+
+    let mut descriptors = descriptors();
+
+    // message Event { int64 timestamp = 1; string kind = 2; bytes body = 3; }
+    let mut event = DescriptorProto::new();
+    event.set_name("Event".to_owned());
+    for &(name, number, field_type) in &[
+        ("timestamp", 1, Type::TYPE_INT64),
+        ("kind", 2, Type::TYPE_STRING),
+        ("body", 3, Type::TYPE_BYTES),
+    ] {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.to_owned());
+        field.set_number(number);
+        field.set_label(Label::LABEL_OPTIONAL);
+        field.set_field_type(field_type);
+        event.mut_field().push(field);
+    }
+    let mut file = FileDescriptorProto::new();
+    file.set_name("events.proto".to_owned());
+    file.set_package("events".to_owned());
+    file.set_message_type(vec![event].into());
+    descriptors.add_file_proto(&file);
+    let event = descriptors.message_by_name(".events.Event").unwrap();
+
+    let encode = |kind: &str, body: &[u8]| {
+        let mut bytes = Vec::new();
+        {
+            let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+            output.write_int64(1, 1_600_000_000).unwrap();
+            output.write_string(2, kind).unwrap();
+            output.write_bytes(3, body).unwrap();
+            output.flush().unwrap();
+        }
+        bytes
+    };
+    let envelope = Envelope::new().type_name_field("kind").payload_number(3);
+
+    // optional_int32: 5
+    let bytes = encode("protobuf_unittest.TestAllTypes", &[0x08, 5]);
+    let decoded = envelope.decode(&descriptors, event, &bytes).unwrap();
+    assert_eq!(
+        Some(".protobuf_unittest.TestAllTypes"),
+        decoded.payload_type.as_deref()
+    );
+    match decoded.payload.unwrap().fields.get(&1) {
+        Some(Field::Singular(Some(Value::I32(5)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match decoded.envelope.fields.get(&1) {
+        Some(Field::Singular(Some(Value::I64(1_600_000_000)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+
+    // Envelopes without a type name have no payload
+    let decoded = envelope
+        .decode(&descriptors, event, &encode("", &[]))
+        .unwrap();
+    assert!(decoded.payload.is_none());
+
+    let bytes = encode("events.Missing", &[]);
+    match envelope.decode(&descriptors, event, &bytes) {
+        Err(error::Error::UnknownMessageType { ref name, .. }) => {
+            assert_eq!("events.Missing", name)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    // The type name must be in a string field
+    match Envelope::new()
+        .type_name_number(1)
+        .decode(&descriptors, event, &bytes)
+    {
+        Err(error::Error::TypeMismatch { ref field, .. }) => assert_eq!("timestamp", field),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn custom_values_of_type_handlers() {
+    use serde_protobuf::error;
+    use serde_protobuf::value::{
+        CustomValue, DecodeOptions, EncodeOptions, Field, Message, TypeHandler, TypeHandlers, Value,
+    };
+
+    #[derive(Debug, PartialEq)]
+    struct Cents(i32);
+
+    #[derive(Debug)]
+    struct CentsHandler;
+
+    impl TypeHandler for CentsHandler {
+        fn decode(
+            &self,
+            _: &descriptor::Descriptors,
+            _: &descriptor::MessageDescriptor,
+            message: Message,
+        ) -> error::Result<CustomValue> {
+            match message.fields.get(&1) {
+                Some(&Field::Singular(Some(Value::I32(c)))) => Ok(CustomValue::new(Cents(c))),
+                _ => Ok(CustomValue::new(Cents(0))),
+            }
+        }
+
+        fn encode(
+            &self,
+            _: &descriptor::Descriptors,
+            message_type: &descriptor::MessageDescriptor,
+            value: &CustomValue,
+        ) -> error::Result<Message> {
+            let mut message = Message::new(message_type);
+            let Cents(c) = *value.downcast_ref::<Cents>().unwrap();
+            message
+                .fields
+                .insert(1, Field::Singular(Some(Value::I32(c))));
+            Ok(message)
+        }
+    }
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut handlers = TypeHandlers::new();
+    handlers.register(".protobuf_unittest.ForeignMessage", Arc::new(CentsHandler));
+    let handlers = Arc::new(handlers);
+
+    // optional_int32: 1, optional_foreign_message { c: 42 }
+    let bytes = [0x08, 1, 0x9a, 0x01, 2, 0x08, 42];
+    let mut message = Message::new(all_types);
+    message
+        .merge_from_with_options(
+            &descriptors,
+            all_types,
+            &DecodeOptions::new().type_handlers(handlers.clone()),
+            &mut protobuf::CodedInputStream::from_bytes(&bytes),
+        )
+        .unwrap();
+    match message.fields.get(&19) {
+        Some(Field::Singular(Some(Value::Custom(v)))) => {
+            assert_eq!(Some(&Cents(42)), v.downcast_ref::<Cents>())
+        }
+        other => panic!("unexpected field: {:?}", other),
+    }
+
+    let mut output = Vec::new();
+    {
+        let mut stream = protobuf::CodedOutputStream::vec(&mut output);
+        message
+            .write_to_with_options(
+                &descriptors,
+                all_types,
+                &EncodeOptions::new()
+                    .skip_defaults(true)
+                    .type_handlers(handlers),
+                &mut stream,
+            )
+            .unwrap();
+        stream.flush().unwrap();
+    }
+    assert_eq!(&bytes[..], &output[..]);
+
+    // Custom values can't be written without their handler
+    match message.write_to_bytes(&descriptors, all_types) {
+        Err(error::Error::TypeMismatch { ref field, .. }) => {
+            assert_eq!("optional_foreign_message", field)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn scalar_representations() {
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Field, Message, Value};
+    use serde_value::Value as V;
+
+    // Renders `optional_fixed64` as hex and the values of NestedEnum in lower case
+    #[derive(Debug)]
+    struct HexAndLowercase;
+
+    impl de::ScalarRepresentation for HexAndLowercase {
+        fn applies_to(
+            &self,
+            field: &descriptor::FieldDescriptor,
+            field_type: &descriptor::FieldType,
+        ) -> bool {
+            match *field_type {
+                descriptor::FieldType::Enum(e) => e.name().ends_with(".NestedEnum"),
+                _ => field.name() == "optional_fixed64",
+            }
+        }
+
+        fn represent(
+            &self,
+            _: &descriptor::FieldDescriptor,
+            field_type: &descriptor::FieldType,
+            value: Value,
+        ) -> error::Result<Value> {
+            Ok(match (field_type, value) {
+                (&descriptor::FieldType::Enum(e), Value::Enum(n)) => {
+                    Value::String(e.value_by_number(n).unwrap().name().to_lowercase())
+                }
+                (_, Value::U64(v)) => Value::String(format!("{:x}", v)),
+                (_, v) => v,
+            })
+        }
+
+        fn parse(
+            &self,
+            field: &descriptor::FieldDescriptor,
+            field_type: &descriptor::FieldType,
+            representation: Value,
+        ) -> error::Result<Value> {
+            let bad = || error::Error::BadFieldValue {
+                field: field.name().to_owned(),
+            };
+            match (field_type, representation) {
+                (&descriptor::FieldType::Enum(e), Value::String(s)) => e
+                    .value_by_name(&s.to_uppercase())
+                    .map(|v| Value::Enum(v.number()))
+                    .ok_or_else(bad),
+                (_, Value::String(s)) => u64::from_str_radix(&s, 16)
+                    .map(Value::U64)
+                    .map_err(|_| bad()),
+                _ => Err(bad()),
+            }
+        }
+    }
+
+    let descriptors = descriptors();
+    let message = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let options = de::Options::new().scalar_representation(Arc::new(HexAndLowercase));
+
+    let mut m = Message::new(message);
+    m.fields
+        .insert(8, Field::Singular(Some(Value::U64(0xdead_beef))));
+    m.fields.insert(21, Field::Singular(Some(Value::Enum(3))));
+    m.fields.insert(
+        51,
+        Field::Repeated(vec![Value::Enum(1), Value::Enum(2)].into_iter().collect()),
+    );
+    m.fields.insert(9, Field::Singular(Some(Value::I32(-7))));
+
+    let typed: collections::BTreeMap<String, V> = m
+        .to_typed_with_options(&descriptors, message, &options)
+        .unwrap();
+    assert_eq!(
+        V::Option(Some(Box::new(V::String("deadbeef".to_owned())))),
+        typed["optional_fixed64"]
+    );
+    assert_eq!(
+        V::Option(Some(Box::new(V::String("baz".to_owned())))),
+        typed["optional_nested_enum"]
+    );
+    assert_eq!(
+        V::Seq(vec![
+            V::String("foo".to_owned()),
+            V::String("bar".to_owned())
+        ]),
+        typed["repeated_nested_enum"]
+    );
+    // Other fields keep their usual representation
+    assert_eq!(
+        V::Option(Some(Box::new(V::I32(-7)))),
+        typed["optional_sfixed32"]
+    );
+
+    let parsed = Message::from_typed_with_options(&descriptors, message, &options, &typed).unwrap();
+    match parsed.fields.get(&8) {
+        Some(Field::Singular(Some(Value::U64(0xdead_beef)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match parsed.fields.get(&21) {
+        Some(Field::Singular(Some(Value::Enum(3)))) => (),
+        other => panic!("unexpected field: {:?}", other),
+    }
+    match parsed.fields.get(&51) {
+        Some(Field::Repeated(vs)) => match vs[..] {
+            [Value::Enum(1), Value::Enum(2)] => (),
+            ref other => panic!("unexpected values: {:?}", other),
+        },
+        other => panic!("unexpected field: {:?}", other),
+    }
+
+    // Without the representation, the hex string isn't a valid fixed64
+    match Message::from_typed(&descriptors, message, &typed) {
+        Err(error::Error::BadFieldValue { ref field }) if field == "optional_fixed64" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn delimited_export() {
+    use serde_protobuf::delimited::{RepeatedFields, Table};
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_type = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+
+    let nested = |bb| {
+        let mut m = Message::new(nested_type);
+        m.fields.insert(1, Field::Singular(Some(Value::I32(bb))));
+        Value::Message(Arc::new(m))
+    };
+    let mut message = Message::new(all_types);
+    message.fields.insert(
+        14,
+        Field::Singular(Some(Value::String("a,\"b\"".to_owned()))),
+    );
+    message.fields.insert(
+        31,
+        Field::Repeated(
+            vec![Value::I32(1), Value::I32(2), Value::I32(3)]
+                .into_iter()
+                .collect(),
+        ),
+    );
+    message.fields.insert(
+        48,
+        Field::Repeated(vec![nested(7), nested(8)].into_iter().collect()),
+    );
+
+    let cell = |table: &Table, row: &[String], column: &str| {
+        let index = table.columns().iter().position(|c| c == column).unwrap();
+        row[index].clone()
+    };
+
+    // Every combination of the elements of the two repeated fields gets a row
+    let table = Table::new(&descriptors, all_types);
+    let rows = table.rows(&message).unwrap();
+    assert_eq!(6, rows.len());
+    let pairs = rows
+        .iter()
+        .map(|r| {
+            (
+                cell(&table, r, "repeated_int32"),
+                cell(&table, r, "repeated_nested_message.bb"),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(("1".to_owned(), "7".to_owned()), pairs[0]);
+    assert_eq!(("3".to_owned(), "8".to_owned()), pairs[5]);
+    assert_eq!("", cell(&table, &rows[0], "optional_int32"));
+
+    let table =
+        Table::new(&descriptors, all_types).repeated_fields(RepeatedFields::Join(";".to_owned()));
+    let rows = table.rows(&message).unwrap();
+    assert_eq!(1, rows.len());
+    assert_eq!("1;2;3", cell(&table, &rows[0], "repeated_int32"));
+    assert_eq!("7;8", cell(&table, &rows[0], "repeated_nested_message.bb"));
+
+    let table = Table::new(&descriptors, all_types).repeated_fields(RepeatedFields::Index(2));
+    let columns = table.columns();
+    assert!(!columns.contains(&"repeated_int32.2".to_owned()));
+    let rows = table.rows(&message).unwrap();
+    assert_eq!(columns.len(), rows[0].len());
+    assert_eq!("2", cell(&table, &rows[0], "repeated_int32.1"));
+    assert_eq!("8", cell(&table, &rows[0], "repeated_nested_message.1.bb"));
+
+    let mut output = Vec::new();
+    assert_eq!(1, table.write_rows(&mut output, &message).unwrap());
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(",\"a,\"\"b\"\"\","));
+    let tsv = Table::new(&descriptors, all_types).delimiter(b'\t');
+    let mut output = Vec::new();
+    tsv.write_header(&mut output).unwrap();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .starts_with("optional_int32\toptional_int64\t"));
+}
+
+#[test]
+fn example_messages() {
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_all_types = descriptors
+        .message_by_name(".protobuf_unittest.NestedTestAllTypes")
+        .unwrap();
+
+    let example = Message::example(&descriptors, all_types, 1);
+    let field = |number| format!("{:?}", example.fields[&number]);
+    assert_eq!("Singular(Some(I32(1)))", field(1));
+    assert_eq!("Singular(Some(String(\"optional_string\")))", field(14));
+    assert_eq!("Singular(Some(Enum(1)))", field(21));
+    // Declared defaults are used
+    assert_eq!("Singular(Some(I32(41)))", field(61));
+    assert_eq!(1, example.fields[&31].len());
+    match example.fields[&18] {
+        Field::Singular(Some(Value::Message(ref m))) => assert!(!m.fields[&1].is_empty()),
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+    // Only the first field of the oneof is set
+    assert!(!example.fields[&111].is_empty());
+    assert!(example.fields[&112].is_empty());
+    assert!(example.fields[&113].is_empty());
+    example.write_to_bytes(&descriptors, all_types).unwrap();
+
+    // The depth bounds recursive types
+    let example = Message::example(&descriptors, nested_all_types, 2);
+    let child = |m: &Message| match m.fields[&1] {
+        Field::Singular(Some(Value::Message(ref c))) => Some(c.clone()),
+        _ => None,
+    };
+    let grandchild = child(&child(&example).unwrap()).unwrap();
+    assert!(child(&grandchild).is_none());
+}
+
+#[test]
+fn predicate_evaluation() {
+    use serde_protobuf::error;
+    use serde_protobuf::predicate::Predicate;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_type = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+
+    let mut nested = Message::new(nested_type);
+    nested
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(7))));
+    let mut message = Message::new(all_types);
+    message
+        .fields
+        .insert(12, Field::Singular(Some(Value::F64(-2.5))));
+    message
+        .fields
+        .insert(15, Field::Singular(Some(Value::Bytes(b"abc".to_vec()))));
+    message
+        .fields
+        .insert(18, Field::Singular(Some(Value::Message(Arc::new(nested)))));
+
+    let evaluate = |source: &str| {
+        Predicate::compile(&descriptors, all_types, source)
+            .unwrap()
+            .evaluate(all_types, &message)
+            .unwrap()
+    };
+    assert!(evaluate(
+        "optional_nested_message.bb > 5 && optional_double < -2"
+    ));
+    assert!(evaluate(
+        "has(optional_nested_message) && !has(optional_int32)"
+    ));
+    // Absent fields have their default value
+    assert!(evaluate("optional_int32 == 0 && optional_string == \"\""));
+    assert!(evaluate("optional_bytes == \"abc\" || optional_bool"));
+    assert!(!evaluate("!(optional_nested_message.bb >= 7)"));
+    assert!(evaluate("optional_nested_enum == \"FOO\""));
+
+    let compile = |source: &str| Predicate::compile(&descriptors, all_types, source);
+    match compile("optional_int32 > 1 && nope == 1") {
+        Err(error::Error::BadExpression { offset: 22, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match compile("optional_string > 1") {
+        Err(error::Error::BadExpression { offset: 16, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(compile("repeated_int32 == 1").is_err());
+    assert!(compile("optional_int32").is_err());
+    assert!(compile("optional_nested_enum == \"QUX\"").is_err());
+    assert!(compile("(optional_bool").is_err());
+
+    // Messages of another type, or with values of the wrong kind, are rejected
+    let predicate = compile("optional_bool").unwrap();
+    match predicate.evaluate(nested_type, &message) {
+        Err(error::Error::MessageTypeMismatch { ref expected, .. })
+            if expected == ".protobuf_unittest.TestAllTypes" => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let mut wrong = Message::new(all_types);
+    wrong
+        .fields
+        .insert(13, Field::Singular(Some(Value::I32(1))));
+    match predicate.evaluate(all_types, &wrong) {
+        Err(error::Error::BadFieldValue { ref field }) if field == "optional_bool" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn projection_of_messages() {
+    use serde_protobuf::error;
+    use serde_protobuf::projection::Projection;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let nested_all_types = descriptors
+        .message_by_name(".protobuf_unittest.NestedTestAllTypes")
+        .unwrap();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut payload = Message::new(all_types);
+    payload
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    payload
+        .fields
+        .insert(2, Field::Singular(Some(Value::I64(2))));
+    payload.fields.insert(
+        31,
+        Field::Repeated(vec![Value::I32(3), Value::I32(4)].into_iter().collect()),
+    );
+    let mut child = Message::new(nested_all_types);
+    child.fields.insert(
+        2,
+        Field::Singular(Some(Value::Message(Arc::new(payload.clone())))),
+    );
+    let mut message = Message::new(nested_all_types);
+    message
+        .fields
+        .insert(1, Field::Singular(Some(Value::Message(Arc::new(child)))));
+    message
+        .fields
+        .insert(2, Field::Singular(Some(Value::Message(Arc::new(payload)))));
+
+    let projection = Projection::parse(
+        &descriptors,
+        nested_all_types,
+        "payload { optional_int32, repeated_int32 } child { payload { optional_int64 } }",
+    )
+    .unwrap();
+    let projected = projection.project(&descriptors, nested_all_types, &message);
+    let payload = |m: &Message| match m.fields[&2] {
+        Field::Singular(Some(Value::Message(ref p))) => p.clone(),
+        ref other => panic!("unexpected payload: {:?}", other),
+    };
+    let top = payload(&projected);
+    assert!(!top.fields[&1].is_empty());
+    assert!(top.fields[&2].is_empty());
+    assert_eq!(2, top.fields[&31].len());
+    let nested = match projected.fields[&1] {
+        Field::Singular(Some(Value::Message(ref c))) => payload(c),
+        ref other => panic!("unexpected child: {:?}", other),
+    };
+    assert!(nested.fields[&1].is_empty());
+    assert!(!nested.fields[&2].is_empty());
+
+    // Projecting, decoding and encoding select the same fields
+    let mut retained = message.clone();
+    retained.retain_masked(&descriptors, nested_all_types, projection.tree());
+    let encoded = projection
+        .encode(&descriptors, nested_all_types, &message)
+        .unwrap();
+    assert_eq!(
+        retained
+            .write_to_bytes(&descriptors, nested_all_types)
+            .unwrap(),
+        encoded
+    );
+    let bytes = message
+        .write_to_bytes(&descriptors, nested_all_types)
+        .unwrap();
+    let decoded = projection
+        .decode(&descriptors, nested_all_types, &bytes)
+        .unwrap();
+    assert_eq!(
+        encoded,
+        decoded
+            .write_to_bytes(&descriptors, nested_all_types)
+            .unwrap()
+    );
+
+    for selection in &[
+        "payload {",
+        "payload { }",
+        "{ payload }",
+        "payload }",
+        "nope",
+    ] {
+        match Projection::parse(&descriptors, nested_all_types, selection) {
+            Err(error::Error::BadFieldMaskPath { .. }) => (),
+            other => panic!("unexpected result for {:?}: {:?}", selection, other),
+        }
+    }
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn json_lines_conversion() {
+    use std::io;
+
+    use serde_protobuf::error;
+    use serde_protobuf::json_lines;
+    use serde_protobuf::value::{Field, LengthDelimitedRecords, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let input = concat!(
+        "{\"optionalInt32\": 1, \"repeatedString\": [\"a\", \"b\"]}\n",
+        "{\"optionalInt32\": \n",
+        "  \r\n",
+        "{\"noSuchField\": 1}\n",
+        "{\"optional_int64\": \"-5\"}",
+    );
+    let records =
+        json_lines::records(&descriptors, all_types, input.as_bytes()).collect::<Vec<_>>();
+    assert_eq!(4, records.len());
+    let mut message = Message::new(all_types);
+    message
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    message.fields.insert(
+        44,
+        Field::Repeated(
+            vec![Value::String("a".to_owned()), Value::String("b".to_owned())]
+                .into_iter()
+                .collect(),
+        ),
+    );
+    assert_eq!(
+        message.write_to_bytes(&descriptors, all_types).unwrap(),
+        *records[0].as_ref().unwrap()
+    );
+    assert_eq!(2, records[1].as_ref().unwrap_err().line);
+    match records[2] {
+        Err(json_lines::RecordError {
+            line: 4,
+            error: error::Error::UnknownField { ref name },
+        }) if name == "noSuchField" => (),
+        ref other => panic!("unexpected record: {:?}", other),
+    }
+    assert!(records[3].is_ok());
+
+    let mut output = Vec::new();
+    let rejected =
+        json_lines::to_length_delimited(&descriptors, all_types, input.as_bytes(), &mut output)
+            .unwrap();
+    assert_eq!(
+        vec![2, 4],
+        rejected.iter().map(|e| e.line).collect::<Vec<_>>()
+    );
+    let written = LengthDelimitedRecords::new(&output)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(2, written.len());
+    assert_eq!(&records[3].as_ref().unwrap()[..], written[1]);
+
+    let mut exported = Vec::new();
+    let count =
+        json_lines::from_length_delimited(&descriptors, all_types, &output[..], &mut exported)
+            .unwrap();
+    assert_eq!(2, count);
+    let exported = String::from_utf8(exported).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(2, lines.len());
+    assert!(lines[1].contains("\"optionalInt64\":\"-5\""));
+    // Truncated records are errors
+    assert!(json_lines::from_length_delimited(
+        &descriptors,
+        all_types,
+        &output[..output.len() - 1],
+        io::sink()
+    )
+    .is_err());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn avro_conversion() {
+    use serde_protobuf::avro;
+    use serde_protobuf::error;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let nested_all_types = descriptors
+        .message_by_name(".protobuf_unittest.NestedTestAllTypes")
+        .unwrap();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let schema = avro::schema(&descriptors, nested_all_types);
+    assert_eq!("protobuf_unittest.NestedTestAllTypes", schema["name"]);
+    // The recursive field refers to the record by name
+    assert_eq!(
+        serde_json::json!({
+            "name": "child",
+            "type": ["null", "protobuf_unittest.NestedTestAllTypes"],
+            "default": null,
+        }),
+        schema["fields"][0]
+    );
+    let schema = avro::schema(&descriptors, all_types);
+    let field = |name: &str| {
+        schema["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f["name"] == name)
+            .cloned()
+    };
+    assert_eq!(
+        serde_json::json!({"type": "array", "items": "int"}),
+        field("repeated_int32").unwrap()["type"]
+    );
+    assert_eq!("long", field("optional_uint64").unwrap()["type"][1]);
+    assert_eq!(
+        serde_json::json!(["FOO", "BAR", "BAZ", "NEG"]),
+        field("optional_nested_enum").unwrap()["type"][1]["symbols"]
+    );
+    assert_eq!(None, field("optionalgroup"));
+
+    let mut payload = Message::new(all_types);
+    payload
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(-5))));
+    payload
+        .fields
+        .insert(4, Field::Singular(Some(Value::U64(u64::MAX))));
+    payload
+        .fields
+        .insert(14, Field::Singular(Some(Value::String("héllo".to_owned()))));
+    payload
+        .fields
+        .insert(21, Field::Singular(Some(Value::Enum(-1))));
+    payload.fields.insert(
+        31,
+        Field::Repeated(vec![Value::I32(1), Value::I32(2)].into_iter().collect()),
+    );
+    let mut message = Message::new(nested_all_types);
+    message
+        .fields
+        .insert(2, Field::Singular(Some(Value::Message(Arc::new(payload)))));
+
+    let datum = avro::to_datum(&descriptors, nested_all_types, &message).unwrap();
+    let decoded = avro::from_datum(&descriptors, nested_all_types, &datum).unwrap();
+    assert!(decoded.fields[&1].is_empty());
+    let payload = match decoded.fields[&2] {
+        Field::Singular(Some(Value::Message(ref m))) => m,
+        ref other => panic!("unexpected payload: {:?}", other),
+    };
+    let field = |number| format!("{:?}", payload.fields[&number]);
+    assert_eq!("Singular(Some(I32(-5)))", field(1));
+    assert_eq!(format!("Singular(Some(U64({})))", u64::MAX), field(4));
+    assert_eq!("Singular(Some(String(\"héllo\")))", field(14));
+    assert_eq!("Singular(Some(Enum(-1)))", field(21));
+    assert_eq!("Singular(None)", field(2));
+    assert_eq!(2, payload.fields[&31].len());
+
+    match avro::from_datum(&descriptors, nested_all_types, &datum[..datum.len() - 1]) {
+        Err(error::Error::EndOfStream) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let mut unknown = Message::new(all_types);
+    unknown
+        .fields
+        .insert(21, Field::Singular(Some(Value::Enum(7))));
+    match avro::to_datum(&descriptors, all_types, &unknown) {
+        Err(error::Error::UnknownEnumValue { value: 7 }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[cfg(any(feature = "rmp-serde", feature = "ciborium"))]
+fn binary_formats_message(descriptors: &descriptor::Descriptors) -> serde_protobuf::value::Message {
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let mut message = Message::new(all_types);
+    message
+        .fields
+        .insert(2, Field::Singular(Some(Value::I64(i64::MIN))));
+    message
+        .fields
+        .insert(4, Field::Singular(Some(Value::U64(u64::MAX))));
+    message
+        .fields
+        .insert(15, Field::Singular(Some(Value::Bytes(vec![0, 255, 7]))));
+    message
+        .fields
+        .insert(21, Field::Singular(Some(Value::Enum(2))));
+    message
+}
+
+#[cfg(feature = "rmp-serde")]
+#[test]
+fn msgpack_conversion() {
+    use serde_protobuf::msgpack;
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let message = binary_formats_message(&descriptors);
+
+    let output = msgpack::to_vec(&descriptors, all_types, &message).unwrap();
+    // The bytes field is a MessagePack binary
+    assert!(output.windows(5).any(|w| w == [0xc4, 3, 0, 255, 7]));
+    let decoded = msgpack::from_slice(&descriptors, all_types, &output).unwrap();
+    for number in &[2, 4, 15, 21] {
+        assert_eq!(
+            format!("{:?}", message.fields[number]),
+            format!("{:?}", decoded.fields[number])
+        );
+    }
+}
+
+#[cfg(feature = "ciborium")]
+#[test]
+fn cbor_conversion() {
+    use serde_protobuf::cbor;
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let message = binary_formats_message(&descriptors);
+
+    let output = cbor::to_vec(&descriptors, all_types, &message).unwrap();
+    // The bytes field is a CBOR byte string
+    assert!(output.windows(4).any(|w| w == [0x43, 0, 255, 7]));
+    let decoded = cbor::from_reader(&descriptors, all_types, &output[..]).unwrap();
+    for number in &[2, 4, 15, 21] {
+        assert_eq!(
+            format!("{:?}", message.fields[number]),
+            format!("{:?}", decoded.fields[number])
+        );
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[test]
+fn sql_row_mapping() {
+    use postgres_types::{FromSql, ToSql, Type};
+    use serde_protobuf::error;
+    use serde_protobuf::sql::{self, SqlValue};
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let columns = sql::columns(&descriptors, all_types);
+    assert_eq!("optional_int32", columns[0]);
+    assert!(!columns.contains(&"optional_nested_message"));
+    assert!(!columns.contains(&"repeated_int32"));
+
+    let mut message = Message::new(all_types);
+    message
+        .fields
+        .insert(3, Field::Singular(Some(Value::U32(u32::MAX))));
+    message
+        .fields
+        .insert(14, Field::Singular(Some(Value::String("a".to_owned()))));
+    message
+        .fields
+        .insert(21, Field::Singular(Some(Value::Enum(2))));
+    let params = sql::to_params(&descriptors, all_types, &message).unwrap();
+    assert_eq!(columns.len(), params.len());
+    let param = |name| params[columns.iter().position(|c| *c == name).unwrap()].clone();
+    assert_eq!(SqlValue::Null, param("optional_int32"));
+    assert_eq!(
+        SqlValue::Int8(i64::from(u32::MAX)),
+        param("optional_uint32")
+    );
+    assert_eq!(
+        SqlValue::Text("BAR".to_owned()),
+        param("optional_nested_enum")
+    );
+
+    let row = vec![
+        ("id", SqlValue::Int8(1)),
+        ("optional_uint32", SqlValue::Int8(7)),
+        ("optional_double", SqlValue::Float4(0.5)),
+        ("optional_nested_enum", SqlValue::Text("BAZ".to_owned())),
+        ("optional_string", SqlValue::Null),
+    ];
+    let decoded = sql::from_row(&descriptors, all_types, row).unwrap();
+    assert_eq!(
+        "Singular(Some(U32(7)))",
+        format!("{:?}", decoded.fields[&3])
+    );
+    assert_eq!(
+        "Singular(Some(F64(0.5)))",
+        format!("{:?}", decoded.fields[&12])
+    );
+    assert_eq!(
+        "Singular(Some(Enum(3)))",
+        format!("{:?}", decoded.fields[&21])
+    );
+    assert_eq!("Singular(None)", format!("{:?}", decoded.fields[&14]));
+
+    let row = vec![("optional_uint32", SqlValue::Int8(-1))];
+    match sql::from_row(&descriptors, all_types, row) {
+        Err(error::Error::BadFieldValue { ref field }) if field == "optional_uint32" => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    assert!(<SqlValue as ToSql>::accepts(&Type::INT8));
+    assert!(!<SqlValue as ToSql>::accepts(&Type::JSON));
+    assert_eq!(
+        SqlValue::Int8(42),
+        SqlValue::from_sql(&Type::INT8, &[0, 0, 0, 0, 0, 0, 0, 42]).unwrap()
+    );
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_export() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use serde_protobuf::parquet::Writer;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested_type = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+        .unwrap();
+
+    let nested = |bb| {
+        let mut m = Message::new(nested_type);
+        m.fields.insert(1, Field::Singular(Some(Value::I32(bb))));
+        Value::Message(Arc::new(m))
+    };
+    let mut first = Message::new(all_types);
+    first.fields.insert(1, Field::Singular(Some(Value::I32(5))));
+    first.fields.insert(18, Field::Singular(Some(nested(6))));
+    first
+        .fields
+        .insert(21, Field::Singular(Some(Value::Enum(3))));
+    first.fields.insert(
+        31,
+        Field::Repeated(vec![Value::I32(1), Value::I32(2)].into_iter().collect()),
+    );
+    first.fields.insert(
+        48,
+        Field::Repeated(vec![nested(7), nested(8)].into_iter().collect()),
+    );
+    let second = Message::new(all_types);
+
+    let path = std::env::temp_dir().join(format!("serde-protobuf-{}.parquet", std::process::id()));
+    let mut writer =
+        Writer::new(&descriptors, all_types, fs::File::create(&path).unwrap()).unwrap();
+    let written = writer
+        .write_stream(vec![Ok(first), Ok(second.clone()), Ok(second)], 2)
+        .unwrap();
+    assert_eq!(3, written);
+    writer.finish().unwrap();
+
+    let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(2, reader.metadata().num_row_groups());
+    assert_eq!(3, reader.metadata().file_metadata().num_rows());
+    let rows = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect::<Vec<_>>();
+    let column = |row: &parquet::record::Row, name: &str| {
+        row.get_column_iter()
+            .find(|(n, _)| n.as_str() == name)
+            .map(|(_, v)| v.to_string())
+            .unwrap()
+    };
+    assert_eq!("5", column(&rows[0], "optional_int32"));
+    assert_eq!("{bb: 6}", column(&rows[0], "optional_nested_message"));
+    assert_eq!("\"BAZ\"", column(&rows[0], "optional_nested_enum"));
+    assert_eq!("[1, 2]", column(&rows[0], "repeated_int32"));
+    assert_eq!(
+        "[{bb: 7}, {bb: 8}]",
+        column(&rows[0], "repeated_nested_message")
+    );
+    assert_eq!("null", column(&rows[1], "optional_int32"));
+    assert_eq!("[]", column(&rows[1], "repeated_int32"));
+}
+
+#[test]
+fn null_values() {
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+
+    let mut message = Message::default();
+    message
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    message.fields.insert(2, Field::Singular(Some(Value::Null)));
+    message
+        .fields
+        .insert(18, Field::Singular(Some(Value::Null)));
+
+    // Cleared fields are distinct from absent ones, but have no values
+    assert!(message.fields[&2].is_null());
+    assert!(message.fields[&2].is_empty());
+    assert!(!message.fields.contains_key(&3));
+    assert!(!message.fields[&1].is_null());
+
+    // Cleared fields are encoded like absent ones
+    let mut expected = Message::default();
+    expected
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    assert_eq!(
+        expected.write_to_bytes(&descriptors, all_types).unwrap(),
+        message.write_to_bytes(&descriptors, all_types).unwrap()
+    );
+    assert_eq!(
+        expected.compute_size(&descriptors, all_types).unwrap(),
+        message.compute_size(&descriptors, all_types).unwrap()
+    );
+
+    // Cleared fields are exported like absent ones
+    let table = serde_protobuf::delimited::Table::new(&descriptors, all_types);
+    assert_eq!(
+        table.rows(&expected).unwrap(),
+        table.rows(&message).unwrap()
+    );
+    #[cfg(feature = "arrow")]
+    {
+        let batch = |m: &Message| {
+            let mut builder =
+                serde_protobuf::arrow::RecordBatchBuilder::new(&descriptors, all_types);
+            builder.append(m).unwrap();
+            format!("{:?}", builder.finish().unwrap())
+        };
+        assert_eq!(batch(&expected), batch(&message));
+    }
+    #[cfg(feature = "parquet")]
+    {
+        let mut writer =
+            serde_protobuf::parquet::Writer::new(&descriptors, all_types, Vec::new()).unwrap();
+        writer.write_row_group(&[message.clone()]).unwrap();
+        writer.finish().unwrap();
+    }
+    #[cfg(feature = "serde_json")]
+    assert_eq!(
+        serde_protobuf::avro::to_datum(&descriptors, all_types, &expected).unwrap(),
+        serde_protobuf::avro::to_datum(&descriptors, all_types, &message).unwrap()
+    );
+    #[cfg(feature = "postgres")]
+    assert_eq!(
+        serde_protobuf::sql::to_params(&descriptors, all_types, &expected).unwrap(),
+        serde_protobuf::sql::to_params(&descriptors, all_types, &message).unwrap()
+    );
+    // Formats based on serde see them like fields that are present but unset
+    #[cfg(any(feature = "rmp-serde", feature = "ciborium"))]
+    {
+        let mut unset = expected.clone();
+        unset.fields.insert(2, Field::Singular(None));
+        unset.fields.insert(18, Field::Singular(None));
+        #[cfg(feature = "rmp-serde")]
+        assert_eq!(
+            serde_protobuf::msgpack::to_vec(&descriptors, all_types, &unset).unwrap(),
+            serde_protobuf::msgpack::to_vec(&descriptors, all_types, &message).unwrap()
+        );
+        #[cfg(feature = "ciborium")]
+        assert_eq!(
+            serde_protobuf::cbor::to_vec(&descriptors, all_types, &unset).unwrap(),
+            serde_protobuf::cbor::to_vec(&descriptors, all_types, &message).unwrap()
+        );
+    }
+
+    // Cleared fields are deserialized like absent ones
+    let typed: collections::BTreeMap<String, Option<i64>> =
+        message.to_typed(&descriptors, all_types).unwrap();
+    assert_eq!(Some(&Some(1)), typed.get("optional_int32"));
+    assert_eq!(Some(&None), typed.get("optional_int64"));
+    assert_eq!(Some(&None), typed.get("optional_nested_message"));
+
+    // Cleared fields are null in JSON
+    #[cfg(feature = "serde_json")]
+    {
+        let json = serde_protobuf::json::to_json(&descriptors, all_types, &message).unwrap();
+        let object = json.as_object().unwrap();
+        assert_eq!(Some(&serde_json::Value::Null), object.get("optionalInt64"));
+        assert_eq!(
+            Some(&serde_json::Value::Null),
+            object.get("optionalNestedMessage")
+        );
+    }
+}
+
+#[test]
+fn update_with_mask() {
+    use serde_protobuf::error;
+    use serde_protobuf::field_mask::FieldMask;
+    use serde_protobuf::value::{Field, Message, Value};
+
+    let descriptors = descriptors();
+    let all_types = descriptors
+        .message_by_name(".protobuf_unittest.TestAllTypes")
+        .unwrap();
+    let nested = |bb| {
+        let mut m = Message::default();
+        m.fields.insert(1, Field::Singular(Some(Value::I32(bb))));
+        Field::Singular(Some(Value::Message(Arc::new(m))))
+    };
+    let field = |m: &Message, number| format!("{:?}", m.fields.get(&number));
+
+    let mut resource = Message::default();
+    resource
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(1))));
+    resource
+        .fields
+        .insert(2, Field::Singular(Some(Value::I64(2))));
+    resource
+        .fields
+        .insert(3, Field::Singular(Some(Value::U32(3))));
+    resource.fields.insert(18, nested(4));
+    resource
+        .fields
+        .insert(111, Field::Singular(Some(Value::U32(5))));
+
+    let mut update = Message::default();
+    update
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(10))));
+    update.fields.insert(2, Field::Singular(Some(Value::Null)));
+    update
+        .fields
+        .insert(3, Field::Singular(Some(Value::U32(30))));
+    update.fields.insert(18, nested(40));
+    update.fields.insert(
+        112,
+        Field::Singular(Some(Value::Message(Arc::new(Message::default())))),
+    );
+
+    // Masked paths are replaced or cleared, and other fields are kept
+    let mut updated = resource.clone();
+    let mask = FieldMask::new(vec!["optional_int32", "optional_int64", "oneof_uint32"]);
+    updated
+        .apply_update(&descriptors, all_types, &update, &mask)
+        .unwrap();
+    assert_eq!("Some(Singular(Some(I32(10))))", field(&updated, 1));
+    assert_eq!("Some(Singular(None))", field(&updated, 2));
+    assert_eq!("Some(Singular(None))", field(&updated, 111));
+    assert_eq!(field(&resource, 3), field(&updated, 3));
+    assert_eq!(field(&resource, 18), field(&updated, 18));
+    assert_eq!(
+        vec!["optional_int32", "optional_int64", "oneof_uint32"],
+        updated.changed_paths(&descriptors, all_types).paths
+    );
+
+    // Paths lead into nested messages, and setting a oneof member clears the others
+    let mut updated = resource.clone();
+    let mask = FieldMask::new(vec!["optional_nested_message.bb", "oneof_nested_message"]);
+    updated
+        .apply_update(&descriptors, all_types, &update, &mask)
+        .unwrap();
+    match updated.fields[&18] {
+        Field::Singular(Some(Value::Message(ref m))) => {
+            assert_eq!("Some(Singular(Some(I32(40))))", field(m, 1))
+        }
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+    assert!(updated.fields[&111].is_empty());
+    assert!(!updated.fields[&112].is_empty());
+    assert_eq!(
+        vec![
+            "optional_nested_message.bb",
+            "oneof_uint32",
+            "oneof_nested_message"
+        ],
+        updated.changed_paths(&descriptors, all_types).paths
+    );
+
+    // Setting a field of an absent oneof member creates the member and clears the others
+    let mut member_update = Message::default();
+    member_update.fields.insert(112, nested(50));
+    let mut updated = resource.clone();
+    updated
+        .apply_update(
+            &descriptors,
+            all_types,
+            &member_update,
+            &FieldMask::new(vec!["oneof_nested_message.bb"]),
+        )
+        .unwrap();
+    assert!(updated.fields[&111].is_empty());
+    match updated.fields[&112] {
+        Field::Singular(Some(Value::Message(ref m))) => {
+            assert_eq!("Some(Singular(Some(I32(50))))", field(m, 1))
+        }
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+
+    // Created messages have all the fields of their type
+    let nested_all_types = descriptors
+        .message_by_name(".protobuf_unittest.NestedTestAllTypes")
+        .unwrap();
+    let mut payload = Message::default();
+    payload
+        .fields
+        .insert(1, Field::Singular(Some(Value::I32(7))));
+    let mut tree_update = Message::default();
+    tree_update
+        .fields
+        .insert(2, Field::Singular(Some(Value::Message(Arc::new(payload)))));
+    let mut tree = Message::new(nested_all_types);
+    tree.apply_update(
+        &descriptors,
+        nested_all_types,
+        &tree_update,
+        &FieldMask::new(vec!["payload.optional_int32"]),
+    )
+    .unwrap();
+    match tree.fields[&2] {
+        Field::Singular(Some(Value::Message(ref m))) => {
+            assert_eq!(all_types.fields().len(), m.fields.len());
+            assert_eq!("Some(Singular(Some(I32(7))))", field(m, 1));
+        }
+        ref other => panic!("unexpected field: {:?}", other),
+    }
+
+    // An empty mask stands for the fields of the update, and `*` replaces everything
+    let mut updated = resource.clone();
+    updated
+        .apply_update(&descriptors, all_types, &update, &FieldMask::default())
+        .unwrap();
+    assert_eq!("Some(Singular(None))", field(&updated, 2));
+    assert_eq!("Some(Singular(Some(U32(30))))", field(&updated, 3));
+    let mut updated = resource.clone();
+    updated
+        .apply_update(&descriptors, all_types, &update, &FieldMask::new(vec!["*"]))
+        .unwrap();
+    assert_eq!(
+        update.write_to_bytes(&descriptors, all_types).unwrap(),
+        updated.write_to_bytes(&descriptors, all_types).unwrap()
+    );
+
+    for path in &["repeated_int32.0", "optional_int32.bb", "*"] {
+        let mask = FieldMask::new(vec![*path, "optional_int64"]);
+        match resource
+            .clone()
+            .apply_update(&descriptors, all_types, &update, &mask)
+        {
+            Err(error::Error::BadFieldMaskPath { .. }) => (),
+            other => panic!("unexpected result for {}: {:?}", path, other),
+        }
+    }
+}